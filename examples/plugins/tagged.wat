@@ -0,0 +1,63 @@
+;; A knock plugin for `plugin` steps, to start your own from: the datagram
+;; is "knock:" and the step's argument, and only a reply starting with
+;; "ok" is accepted.
+;;
+;;   async_port_knocker -H example.com -s 7000:plugin:examples/plugins/tagged.wat:s3cret
+;;
+;; The knocker takes the text as it is; `wat2wasm tagged.wat` turns it
+;; into the tagged.wasm a plugin is usually shipped as.
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 0) "knock:")
+  ;; The next free byte; below 1024 are the constants
+  (global $next (mut i32) (i32.const 1024))
+
+  (func $alloc (export "knock_alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $next))
+    (global.set $next (i32.add (local.get $ptr) (local.get $len)))
+    (if (i32.gt_u (global.get $next) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (drop (memory.grow (i32.add (i32.shr_u (local.get $len) (i32.const 16)) (i32.const 1))))))
+    (local.get $ptr))
+
+  ;; Where the argument starts in the request: past "\narg=", the last
+  ;; line, or at its end without one
+  (func $arg (param $ptr i32) (param $len i32) (result i32)
+    (local $i i32)
+    (local.set $i (local.get $ptr))
+    (block $done
+      (loop $scan
+        (br_if $done
+          (i32.gt_u (i32.add (local.get $i) (i32.const 5))
+                    (i32.add (local.get $ptr) (local.get $len))))
+        ;; "arg=" read as a little-endian word
+        (if (i32.and (i32.eq (i32.load8_u (local.get $i)) (i32.const 10))
+                     (i32.eq (i32.load (i32.add (local.get $i) (i32.const 1)))
+                             (i32.const 0x3d677261)))
+          (then (return (i32.add (local.get $i) (i32.const 5)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $scan)))
+    (i32.add (local.get $ptr) (local.get $len)))
+
+  ;; The response: flags (1, wait for a reply), "knock:", the argument
+  (func (export "knock_build") (param $ptr i32) (param $len i32) (result i64)
+    (local $arg i32)
+    (local $arg_len i32)
+    (local $out i32)
+    (local.set $arg (call $arg (local.get $ptr) (local.get $len)))
+    (local.set $arg_len
+      (i32.sub (i32.add (local.get $ptr) (local.get $len)) (local.get $arg)))
+    (local.set $out (call $alloc (i32.add (local.get $arg_len) (i32.const 7))))
+    (i32.store8 (local.get $out) (i32.const 1))
+    (memory.copy (i32.add (local.get $out) (i32.const 1)) (i32.const 0) (i32.const 6))
+    (memory.copy (i32.add (local.get $out) (i32.const 7)) (local.get $arg) (local.get $arg_len))
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+      (i64.extend_i32_u (i32.add (local.get $arg_len) (i32.const 7)))))
+
+  ;; A reply is accepted when it starts with "ok"
+  (func (export "knock_check") (param $ptr i32) (param $len i32) (result i32)
+    (if (i32.lt_u (local.get $len) (i32.const 2))
+      (then (return (i32.const 0))))
+    (i32.eq (i32.load16_u (local.get $ptr)) (i32.const 0x6b6f))))
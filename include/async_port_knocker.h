@@ -0,0 +1,174 @@
+#ifndef ASYNC_PORT_KNOCKER_H
+#define ASYNC_PORT_KNOCKER_H
+
+/* C interface of async_port_knocker (cargo feature `ffi`); regenerate with
+   `cbindgen --config cbindgen.toml --output include/async_port_knocker.h`. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The call succeeded.
+#define PK_OK 0
+
+// A pointer was NULL or a value out of range.
+#define PK_ERR_INVALID_ARGUMENT -1
+
+// The library panicked; the call had no effect beyond what it did before.
+#define PK_ERR_PANIC -2
+
+#define PK_PROTOCOL_TCP 0
+
+#define PK_PROTOCOL_UDP 1
+
+#define PK_PROTOCOL_ICMP 2
+
+#define PK_PROTOCOL_SCTP 3
+
+// Stops a run from another thread; see [`pk_run_with_cancel`].
+typedef struct PkCancel PkCancel;
+
+// A run's settings, built up with the `pk_config_*` functions.
+typedef struct PkConfig PkConfig;
+
+// One knock of a [`PkReport`].
+typedef struct PkKnock {
+  // Port, or the payload size for ICMP.
+  uint16_t port;
+  // One of the `PK_PROTOCOL_*` constants.
+  int protocol;
+  bool succeeded;
+  // The target answered, rather than the knock only being sent.
+  bool acknowledged;
+  size_t attempts;
+  // Latency of the attempt that got through, or -1.
+  int64_t latency_ms;
+  // Time spent on the knock, retries included.
+  uint64_t elapsed_ms;
+} PkKnock;
+
+// What [`pk_run`] did; free it with [`pk_report_free`].
+typedef struct PkReport {
+  // `knock_count` knocks, in sequence order.
+  struct PkKnock *knocks;
+  size_t knock_count;
+  uint64_t duration_ms;
+  // The run was cancelled before every knock was sent.
+  bool interrupted;
+} PkReport;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Create a config with the command-line defaults and no host or ports.
+// Returns NULL only if allocation failed.
+struct PkConfig *pk_config_new(void);
+
+// Free a config; NULL is ignored.
+//
+// # Safety
+// `config` must come from [`pk_config_new`] and not be used afterwards.
+void pk_config_free(struct PkConfig *config);
+
+// Set the host name or IP to knock.
+//
+// # Safety
+// `config` must be a live config and `host` a NUL-terminated string.
+int pk_config_set_host(struct PkConfig *config, const char *host);
+
+// Append a port to the knock sequence.
+//
+// # Safety
+// `config` must be a live config.
+int pk_config_add_port(struct PkConfig *config, uint16_t port);
+
+// Set the protocol of every knock, one of the `PK_PROTOCOL_*` constants.
+//
+// # Safety
+// `config` must be a live config.
+int pk_config_set_protocol(struct PkConfig *config, int protocol);
+
+// Set the timeout of one attempt, in milliseconds.
+//
+// # Safety
+// `config` must be a live config.
+int pk_config_set_timeout_ms(struct PkConfig *config, uint64_t ms);
+
+// Set the number of attempts per knock, the first included; 0 is
+// rejected when the run starts.
+//
+// # Safety
+// `config` must be a live config.
+int pk_config_set_attempts(struct PkConfig *config, uint32_t attempts);
+
+// Deprecated name of `pk_config_set_attempts`, which it is the same as.
+//
+// # Safety
+// `config` must be a live config.
+int pk_config_set_retries(struct PkConfig *config, uint32_t retries);
+
+// Set the delay between knocks, in milliseconds.
+//
+// # Safety
+// `config` must be a live config.
+int pk_config_set_delay_ms(struct PkConfig *config, uint64_t ms);
+
+// Set the payload of UDP knocks; the bytes are copied.
+//
+// # Safety
+// `config` must be a live config and `data` point to `len` readable
+// bytes (it may be NULL when `len` is 0).
+int pk_config_set_payload(struct PkConfig *config, const uint8_t *data, size_t len);
+
+// Send the knocks and wait for them. On success `*report` receives the
+// report; on failure it is set to NULL. A run in which some knocks did not
+// get through fails with the binary's exit code 7.
+//
+// # Safety
+// `config` must be a live config and `report` a valid pointer.
+int pk_run(const struct PkConfig *config, struct PkReport **report);
+
+// Create a handle that cancels the runs it is passed to.
+struct PkCancel *pk_cancel_new(void);
+
+// Cancel the runs using `cancel`, also ones started later; may be called
+// from any thread.
+//
+// # Safety
+// `cancel` must be a live handle.
+int pk_cancel(const struct PkCancel *cancel);
+
+// Free a cancel handle once no run uses it; NULL is ignored.
+//
+// # Safety
+// `cancel` must come from [`pk_cancel_new`] and not be used afterwards.
+void pk_cancel_free(struct PkCancel *cancel);
+
+// [`pk_run`] that stops early, with a report marked `interrupted`, once
+// `cancel` is cancelled. `cancel` may be NULL.
+//
+// # Safety
+// `config` must be a live config, `cancel` NULL or a live handle and
+// `report` a valid pointer.
+int pk_run_with_cancel(const struct PkConfig *config,
+                       const struct PkCancel *cancel,
+                       struct PkReport **report);
+
+// Free a report from [`pk_run`]; NULL is ignored.
+//
+// # Safety
+// `report` must come from [`pk_run`] and not be used afterwards.
+void pk_report_free(struct PkReport *report);
+
+// The message of the last failed call on this thread, or NULL if none
+// failed yet. It stays valid until the next failing call on the thread.
+const char *pk_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ASYNC_PORT_KNOCKER_H */
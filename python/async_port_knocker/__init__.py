@@ -0,0 +1,15 @@
+"""Python bindings for async_port_knocker (pyo3).
+
+Build and install them into the current environment with
+
+    cd python && maturin develop --release
+
+``PortKnocker().knock(...)`` runs with the GIL released; Ctrl-C
+(KeyboardInterrupt) while waiting for it cancels the run and is then
+re-raised. Knocks that did not get through are marked in the returned
+report; other failures raise KnockError, with the report as ``report``.
+"""
+
+from ._native import KnockError, KnockReport, KnockResult, PortKnocker
+
+__all__ = ["KnockError", "KnockReport", "KnockResult", "PortKnocker"]
@@ -0,0 +1,68 @@
+import _thread
+import socket
+import threading
+import time
+
+import pytest
+
+from async_port_knocker import KnockError, PortKnocker
+
+
+@pytest.fixture
+def knocker():
+    return PortKnocker()
+
+
+@pytest.fixture
+def listener():
+    sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    sock.bind(("127.0.0.1", 0))
+    sock.listen(8)
+    yield sock
+    sock.close()
+
+
+def test_loopback_knock(knocker, listener):
+    port = listener.getsockname()[1]
+    report = knocker.knock("127.0.0.1", [port, port], attempts=2)
+    assert report.succeeded
+    assert [k.port for k in report.knocks] == [port, port]
+    assert all(k.protocol == "tcp" and k.attempts == 1 for k in report.knocks)
+    assert all(k.latency_ms is not None for k in report.knocks)
+
+
+def test_knocks_that_fail_are_marked_in_the_report(knocker, listener):
+    # A listener whose backlog is full: connects to it time out
+    full = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    full.bind(("127.0.0.1", 0))
+    full.listen(0)
+    queued = socket.create_connection(full.getsockname())
+    try:
+        ports = [listener.getsockname()[1], full.getsockname()[1]]
+        report = knocker.knock("127.0.0.1", ports, timeout_ms=200)
+    finally:
+        queued.close()
+        full.close()
+    assert not report.succeeded
+    assert [k.succeeded for k in report.knocks] == [True, False]
+    assert report.knocks[0].error is None
+    assert report.knocks[1].error
+
+
+def test_errors_carry_the_exit_code(knocker):
+    with pytest.raises(KnockError) as error:
+        knocker.knock("", [7000])
+    assert error.value.code == 2
+    assert error.value.report is None
+    assert "no host" in str(error.value)
+    with pytest.raises(ValueError):
+        knocker.knock("127.0.0.1", [7000], protocol="carrier-pigeon")
+
+
+def test_keyboard_interrupt_cancels_the_run(knocker, listener):
+    port = listener.getsockname()[1]
+    threading.Timer(0.2, _thread.interrupt_main).start()
+    started = time.monotonic()
+    with pytest.raises(KeyboardInterrupt):
+        knocker.knock("127.0.0.1", [port, port], delay_ms=10_000)
+    assert time.monotonic() - started < 5
@@ -93,7 +93,7 @@ def run_knocker(
     timeout_ms: int = 500,
     delay_ms: int = 0,
     concurrency: int = 1,
-    retries: int = 1,
+    attempts: int = 1,
     backoff_ms: int = 100,
     payload_hex: Optional[str] = None,
     extra_args: Optional[List[str]] = None,
@@ -113,8 +113,8 @@ def run_knocker(
         str(delay_ms),
         "--concurrency",
         str(concurrency),
-        "-r",
-        str(retries),
+        "--attempts",
+        str(attempts),
         "-b",
         str(backoff_ms),
     ]
@@ -300,7 +300,7 @@ def test_tcp_success_local(bin_path: str) -> Tuple[bool, str]:
             protocol="tcp",
             sequence=[srv.port],
             timeout_ms=800,
-            retries=1,
+            attempts=1,
         )
         ok = res.code == 0 and f"TCP 127.0.0.1:{srv.port} OK" in res.out
         return expect(ok, f"stdout: {res.out.strip()} stderr: {res.err.strip()}")
@@ -317,7 +317,7 @@ def test_tcp_err_refused(bin_path: str) -> Tuple[bool, str]:
         protocol="tcp",
         sequence=[port],
         timeout_ms=500,
-        retries=1,
+        attempts=1,
     )
     ok = res.code == 0 and "OK" not in res.out
     return expect(ok, f"stdout: {res.out.strip()} stderr: {res.err.strip()}")
@@ -333,7 +333,8 @@ def test_udp_success_local_echo(bin_path: str) -> Tuple[bool, str]:
             protocol="udp",
             sequence=[srv.port],
             timeout_ms=700,
-            retries=1,
+            attempts=1,
+            extra_args=["--expect-reply"],
         )
         ok = (
             res.code == 0
@@ -353,7 +354,7 @@ def test_public_tcp_google_443(bin_path: str) -> Tuple[bool, str]:
         protocol="tcp",
         sequence=[443],
         timeout_ms=1500,
-        retries=1,
+        attempts=1,
     )
     ok = res.code == 0 and "OK" in res.out
     return expect(ok, f"stdout: {res.out.strip()} stderr: {res.err.strip()}")
@@ -369,8 +370,9 @@ def test_public_udp_dns_query(bin_path: str) -> Tuple[bool, str]:
         protocol="udp",
         sequence=[53],
         timeout_ms=1500,
-        retries=1,
+        attempts=1,
         payload_hex=payload_hex,
+        extra_args=["--expect-reply"],
     )
     # Expect a reply from DNS resolver
     ok = res.code == 0 and "UDP 8.8.8.8:53 received " in res.out
@@ -384,7 +386,7 @@ def test_invalid_payload_hex(bin_path: str) -> Tuple[bool, str]:
         protocol="udp",
         sequence=[9],
         timeout_ms=300,
-        retries=1,
+        attempts=1,
         payload_hex="xyz",  # invalid hex
     )
     ok = res.code != 0
@@ -400,7 +402,7 @@ def test_dns_resolution_error(bin_path: str) -> Tuple[bool, str]:
         protocol="tcp",
         sequence=[80],
         timeout_ms=500,
-        retries=1,
+        attempts=1,
     )
     ok = res.code != 0 and "Error:" in res.err
     return expect(ok, f"code={res.code} stdout: {res.out.strip()} "
@@ -425,8 +427,9 @@ def test_concurrency_udp_timeout(bin_path: str) -> Tuple[bool, str]:
         protocol="udp",
         sequence=seq,
         timeout_ms=to_ms,
-        retries=1,
+        attempts=1,
         concurrency=1,
+        extra_args=["--expect-reply"],
     )
     dur_seq = res_seq.duration_s
 
@@ -436,8 +439,9 @@ def test_concurrency_udp_timeout(bin_path: str) -> Tuple[bool, str]:
         protocol="udp",
         sequence=seq,
         timeout_ms=to_ms,
-        retries=1,
+        attempts=1,
         concurrency=2,
+        extra_args=["--expect-reply"],
     )
     dur_par = res_par.duration_s
 
@@ -466,7 +470,7 @@ def test_retries_behavior_udp(bin_path: str) -> Tuple[bool, str]:
     srv = SilentUdpServer()
     srv.start()
     try:
-        retries = 3
+        attempts = 3
         backoff_ms = 150
         to_ms = 300
 
@@ -476,11 +480,12 @@ def test_retries_behavior_udp(bin_path: str) -> Tuple[bool, str]:
             protocol="udp",
             sequence=[srv.port],
             timeout_ms=to_ms,
-            retries=retries,
+            attempts=attempts,
             backoff_ms=backoff_ms,
+            extra_args=["--expect-reply"],
         )
-        # Minimal expected duration: retries * timeout + (retries-1) * backoff
-        min_expected = (retries * to_ms + (retries - 1) * backoff_ms) / 1000.0
+        # Minimal expected duration: attempts * timeout + (attempts-1) * backoff
+        min_expected = (attempts * to_ms + (attempts - 1) * backoff_ms) / 1000.0
         ok = res.duration_s >= (min_expected * 0.9)  # allow some slack
         msg = (f"duration={res.duration_s:.3f}s min_expected={min_expected:.3f}s "
                f"stdout={res.out.strip()} stderr={res.err.strip()}")
@@ -489,6 +494,33 @@ def test_retries_behavior_udp(bin_path: str) -> Tuple[bool, str]:
         srv.stop()
 
 
+def test_udp_fire_and_forget_default(bin_path: str) -> Tuple[bool, str]:
+    # Without --expect-reply a successful send is a delivered knock, so a
+    # silent server must not cost the timeout or any attempts.
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    sock.bind(("127.0.0.1", 0))
+    port = sock.getsockname()[1]
+    try:
+        res = run_knocker(
+            bin_path,
+            host="127.0.0.1",
+            protocol="udp",
+            sequence=[port],
+            timeout_ms=2000,
+            attempts=3,
+        )
+        ok = (
+            res.code == 0
+            and f"UDP 127.0.0.1:{port} SENT " in res.out
+            and res.duration_s < 1.5
+        )
+        msg = (f"duration={res.duration_s:.3f}s "
+               f"stdout={res.out.strip()} stderr={res.err.strip()}")
+        return expect(ok, msg)
+    finally:
+        sock.close()
+
+
 # ---------------------------------------------------------------------------
 # Runner
 # ---------------------------------------------------------------------------
@@ -500,6 +532,8 @@ def main():
         TestCase("TCP local refused", lambda: test_tcp_err_refused(bin_path)),
         TestCase("UDP local echo success",
                  lambda: test_udp_success_local_echo(bin_path)),
+        TestCase("UDP fire-and-forget default",
+                 lambda: test_udp_fire_and_forget_default(bin_path)),
         TestCase("Public TCP google:443",
                  lambda: test_public_tcp_google_443(bin_path)),
         TestCase("Public UDP DNS query 8.8.8.8:53",
@@ -508,7 +542,7 @@ def main():
         TestCase("DNS resolution error", lambda: test_dns_resolution_error(bin_path)),
         TestCase("Concurrency UDP timing",
                  lambda: test_concurrency_udp_timeout(bin_path)),
-        TestCase("UDP retries/backoff timing",
+        TestCase("UDP attempts/backoff timing",
                  lambda: test_retries_behavior_udp(bin_path)),
     ]
 
@@ -0,0 +1,71 @@
+use crate::{
+    config::{KnockConfig, KnockOpts},
+    errors::AppError,
+    outcome::{KnockOutcome, KnockReport},
+    CancellationToken,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+
+/// Blocking version of [`crate::run`] for synchronous programs, on a
+/// current-thread runtime of its own. Unlike the async version it leaves
+/// Ctrl-C to the application.
+pub fn run(config: KnockConfig) -> Result<KnockReport, AppError> {
+    run_with_cancel(config, CancellationToken::new())
+}
+
+/// Blocking version of [`crate::run_with_cancel`]; cancel `cancel` from
+/// another thread to stop the run.
+pub fn run_with_cancel(
+    config: KnockConfig,
+    cancel: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    block_on(crate::run_with_cancel(config, cancel))?
+}
+
+/// Blocking version of [`crate::knock_tcp`].
+pub fn knock_tcp(target: SocketAddr, opts: &KnockOpts) -> Result<KnockOutcome, AppError> {
+    block_on(crate::knock_tcp(target, opts))?
+}
+
+/// Blocking version of [`crate::knock_udp`].
+pub fn knock_udp(
+    target: SocketAddr,
+    payload: Option<&[u8]>,
+    opts: &KnockOpts,
+) -> Result<KnockOutcome, AppError> {
+    block_on(crate::knock_udp(target, payload, opts))?
+}
+
+/// Drive `future` to completion on this thread, with `runtime-tokio` on a
+/// fresh runtime. Blocking a runtime thread would stall its other tasks
+/// (and Tokio panics on nested runtimes), so a call from inside Tokio is
+/// an error instead.
+fn block_on<F: Future>(future: F) -> Result<F::Output, AppError> {
+    crate::rt::block_on(future)?.ok_or_else(|| {
+        AppError::Runtime(
+            "blocking API called from inside a Tokio runtime; use the async functions there".into(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn knocks_without_a_runtime() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        let outcome = knock_tcp(target, &KnockOpts::default()).unwrap();
+        assert!(outcome.succeeded);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_block_a_runtime() {
+        let target = "127.0.0.1:7000".parse().unwrap();
+        let err = knock_udp(target, None, &KnockOpts::default()).unwrap_err();
+        assert!(matches!(err, AppError::Runtime(_)), "{err}");
+    }
+}
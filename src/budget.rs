@@ -0,0 +1,135 @@
+//! A retry budget shared by every knock of a run (`--max-attempts-total`).
+//!
+//! Per-knock retries multiply: ten knocks of five attempts are fifty
+//! attempts against a host the first few already showed to be flaky. The
+//! budget caps the attempts of the whole run. Every attempt draws from it;
+//! once it is spent a knock still gets its first attempt but no retry, or
+//! is not sent at all, as [`BudgetExhausted`] says.
+
+use crate::protocol::BudgetExhausted;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Attempts left to the knocks of a run, drawn from by all of them at once.
+#[derive(Debug)]
+pub struct RetryBudget {
+    total: usize,
+    remaining: AtomicUsize,
+    skipped: AtomicUsize,
+    exhausted: BudgetExhausted,
+}
+
+impl RetryBudget {
+    /// A budget of `total` attempts, handling the knocks after it is spent
+    /// as `exhausted` says.
+    pub fn new(total: usize, exhausted: BudgetExhausted) -> Self {
+        Self {
+            total,
+            remaining: AtomicUsize::new(total),
+            skipped: AtomicUsize::new(0),
+            exhausted,
+        }
+    }
+
+    /// Take one attempt from the budget; `false`, taking nothing, once it
+    /// is spent. Knocks drawing at the same time never take the same one.
+    pub fn take(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Whether every attempt has been taken.
+    pub fn is_spent(&self) -> bool {
+        self.remaining.load(Ordering::Acquire) == 0
+    }
+
+    /// Whether a knock about to be sent is skipped instead: the budget is
+    /// spent and knocks are not sent once it is. Counts the ones skipped.
+    pub fn skips_knock(&self) -> bool {
+        let skip = self.exhausted == BudgetExhausted::Skip && self.is_spent();
+        if skip {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        skip
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// How the budget was used so far.
+    pub fn report(&self) -> BudgetReport {
+        BudgetReport {
+            total: self.total,
+            spent: self.total - self.remaining.load(Ordering::Acquire),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How a run used its retry budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BudgetReport {
+    pub total: usize,
+    /// Attempts taken from it; the first attempts made once it was spent
+    /// are not among them.
+    pub spent: usize,
+    /// Knocks not sent because it was spent.
+    pub skipped: usize,
+}
+
+/// `7/10 attempts spent`, and the knocks skipped if any.
+impl fmt::Display for BudgetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} attempts spent", self.spent, self.total)?;
+        if self.skipped > 0 {
+            write!(f, ", {} knock(s) skipped", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+
+    #[test]
+    fn concurrent_knocks_never_overdraw_the_budget() {
+        // Two knocks drawing the last attempt at once: one gets it
+        for _ in 0..100 {
+            let budget = Arc::new(RetryBudget::new(1, BudgetExhausted::Single));
+            let barrier = Arc::new(Barrier::new(2));
+            let draws: Vec<_> = (0..2)
+                .map(|_| {
+                    let (budget, barrier) = (budget.clone(), barrier.clone());
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        budget.take()
+                    })
+                })
+                .collect();
+            let taken = draws.into_iter().map(|d| d.join().unwrap());
+            assert_eq!(taken.filter(|&taken| taken).count(), 1);
+            assert!(budget.is_spent());
+        }
+
+        // Many drawing far more than there is take exactly all of it
+        let budget = Arc::new(RetryBudget::new(1000, BudgetExhausted::Skip));
+        let draws: Vec<_> = (0..8)
+            .map(|_| {
+                let budget = budget.clone();
+                std::thread::spawn(move || (0..500).filter(|_| budget.take()).count())
+            })
+            .collect();
+        let taken: usize = draws.into_iter().map(|d| d.join().unwrap()).sum();
+        assert_eq!(taken, 1000);
+        assert!(budget.skips_knock());
+        assert_eq!(
+            budget.report().to_string(),
+            "1000/1000 attempts spent, 1 knock(s) skipped"
+        );
+    }
+}
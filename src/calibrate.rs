@@ -0,0 +1,90 @@
+//! Picking the knock timeout from the latency to the target
+//! (`--auto-timeout`).
+//!
+//! A fixed timeout is either too short for a distant host or far longer
+//! than a near one needs. Before the sequence a few TCP connects to a
+//! calibration port measure the round trip, a refusal counting as much as
+//! an accepted connection; without such a port, the DNS lookup and the
+//! first knock are timed instead. The timeout is then a few times the
+//! slowest usual round trip.
+
+use crate::ratelimit::RateLimiter;
+use crate::rt::{self, Instant, TcpStream};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Connects made to the calibration port.
+pub const PROBES: usize = 5;
+/// The shortest timeout calibration picks, however near the host.
+pub const MIN_TIMEOUT: Duration = Duration::from_millis(100);
+/// How many times the 95th-percentile round trip a knock is given.
+const FACTOR: u32 = 3;
+
+/// The timeout for round trips like `samples`: three times their 95th
+/// percentile, and at least [`MIN_TIMEOUT`]. `None` without a sample.
+pub fn timeout_for(samples: &[Duration]) -> Option<Duration> {
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    // Nearest rank: the smallest sample at least 95% of them reach
+    let rank = (sorted.len() * 95).div_ceil(100);
+    let p95 = *sorted.get(rank.checked_sub(1)?)?;
+    Some((p95 * FACTOR).max(MIN_TIMEOUT))
+}
+
+/// Time [`PROBES`] connects to `addr`, each given `timeout` and waiting
+/// for a token of `rate` first. A connect that gets no answer in time adds
+/// no sample, and the probing stops once `cancel` fires.
+pub async fn probe(
+    addr: SocketAddr,
+    timeout: Duration,
+    rate: Option<&RateLimiter>,
+    cancel: &CancellationToken,
+) -> Vec<Duration> {
+    let mut samples = Vec::with_capacity(PROBES);
+    for _ in 0..PROBES {
+        if let Some(rate) = rate {
+            if !rate.acquire().await {
+                break;
+            }
+        }
+        let started = Instant::now();
+        let answered = tokio::select! {
+            connect = rt::timeout(timeout, TcpStream::connect(addr)) => match connect {
+                Ok(Ok(_)) => true,
+                Ok(Err(e)) => e.kind() == ErrorKind::ConnectionRefused,
+                Err(_) => false,
+            },
+            _ = cancel.cancelled() => break,
+        };
+        if answered {
+            samples.push(started.elapsed());
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_is_three_times_the_95th_percentile() {
+        let ms = |ms: &[u64]| {
+            ms.iter()
+                .map(|&ms| Duration::from_millis(ms))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timeout_for(&[]), None);
+        // Of a few samples the slowest is the 95th percentile
+        let samples = ms(&[40, 90, 50, 45, 60]);
+        assert_eq!(timeout_for(&samples), Some(Duration::from_millis(270)));
+        // Of twenty, the one outlier is left out
+        let mut samples = ms(&[50; 19]);
+        samples.push(Duration::from_secs(2));
+        assert_eq!(timeout_for(&samples), Some(Duration::from_millis(150)));
+        // A host next door still gets the floor
+        assert_eq!(timeout_for(&ms(&[1, 2])), Some(MIN_TIMEOUT));
+    }
+}
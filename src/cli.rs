@@ -1,52 +1,2568 @@
-use clap::{Parser, ValueEnum};
-use std::sync::Arc;
+use crate::dns::ResolvePin;
+use crate::duration;
+use crate::generate::SequenceSpec;
+use crate::jump::JumpHost;
+use crate::observer::{AttemptInfo, KnockObserver};
+use crate::outcome::FailureTolerance;
+use crate::packet::TcpFlags;
+use crate::pattern::ReplyPattern;
+use crate::plan::{KnockPlan, KnockStep, TtlWalk};
+pub use crate::protocol::{
+    BackoffStrategy, BudgetExhausted, Protocol, ResolveStrategy, TcpClose, TotpAlgorithm,
+};
+use crate::secret::SecretSource;
+use crate::securedns::SecureDns;
+use crate::server::{KnockServer, ListenStep, ServerConfig};
+use crate::sntp::{self, NtpServer};
+use crate::socks::Socks5Proxy;
+use crate::ssh_config::SshHost;
+use crate::stun::StunServer;
+use crate::udp::{MulticastInterface, SourcePortPolicy};
+use crate::uri::KnockUri;
+use crate::{AppError, KnockConfig, KnockEvent, KnockReport, StdoutObserver, Timestamps};
+use bytes::Bytes;
+use clap::{ArgGroup, Parser, ValueEnum};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 /// Async TCP/UDP Port Knocker Scanner CLI
 #[derive(Parser)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    group = ArgGroup::new("replies").args(["expect_reply", "lockstep"]).multiple(true),
+    group = ArgGroup::new("verifier").args(["plan", "verify"]).multiple(true),
+    group = ArgGroup::new("soak_limit")
+        .args(["soak", "soak_duration"])
+        .conflicts_with_all(["plan", "schedule", "hosts_file", "export_knockd", "dry_run", "confirm", "resume", "state_file", "skip_if_recent"]),
+    after_help = "Run `async_port_knocker listen --help` to listen for knocks instead, or \
+                  `async_port_knocker self-test` to try the knocker out on loopback, or \
+                  `async_port_knocker gen-sequence` to draw a new sequence, or \
+                  `async_port_knocker history` to look through the runs of --history-db"
+)]
 pub struct Cli {
-    /// Target host (IP or hostname) to knock on
-    #[arg(short = 'H', long)]
-    pub host: String,
+    /// Target host (IP or hostname) to knock on, without a port; IPv6 may
+    /// be bracketed, and link-local IPv6 needs an interface, e.g.
+    /// "fe80::1%eth0"
+    #[arg(short = 'H', long, value_parser = parse_host, required_unless_present_any = ["plan", "hosts_file", "srv", "uri", "ssh_host"])]
+    pub host: Option<String>,
 
-    /// Protocol to use for knocks: tcp or udp
-    #[arg(short, long, value_enum, default_value_t = Protocol::Tcp)]
+    /// The whole knock as one knock:// URI, e.g.
+    /// "knock://example.com/7000,8000:udp,9000?timeout=500ms&verify=22":
+    /// the host, the sequence as its path and long options as query
+    /// parameters (see `uri::PARAMS`); options also given as flags keep
+    /// the flag's value
+    #[arg(long, value_name = "URI", value_parser = KnockUri::parse, conflicts_with_all = ["plan", "hosts_file"])]
+    pub uri: Option<KnockUri>,
+
+    /// Knock the host of this Host entry of ~/.ssh/config (or
+    /// --ssh-config): its HostName, with its Port (22 without one) to
+    /// --verify. Knock options come from `#knock-NAME VALUE` comments or
+    /// `SetEnv KNOCK_NAME=VALUE` in the entry, e.g.
+    /// "#knock-sequence 7000,8000,9000", as the options of --uri do;
+    /// options also given as flags keep the flag's value
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["plan", "hosts_file", "uri", "srv"])]
+    pub ssh_host: Option<String>,
+
+    /// The ssh_config file --ssh-host looks in [default: ~/.ssh/config]
+    #[arg(long, value_name = "PATH", requires = "ssh_host")]
+    pub ssh_config: Option<PathBuf>,
+
+    /// Knock every resolved address of the host instead of only the first;
+    /// HTTP and TLS steps still go to the first
+    #[arg(long, conflicts_with = "proxy_socks5")]
+    pub all_ips: bool,
+
+    /// Which resolved addresses to use and in which order: all, prefer-v4,
+    /// prefer-v6, only-v4 or only-v6 [default: all]. Or, like curl's
+    /// --resolve, HOST:ADDR to send knocks for HOST to ADDR without
+    /// resolving it, e.g. example.com:203.0.113.7; repeatable
+    #[arg(long, value_name = "STRATEGY|HOST:ADDR", value_parser = parse_resolve, conflicts_with = "proxy_socks5")]
+    pub resolve: Vec<ResolveArg>,
+
+    /// Resolve the host through this DNS server (IP, port 53 by default)
+    /// instead of the system resolver. Needs the `custom-dns` feature
+    #[arg(long, value_name = "ADDR", value_parser = parse_dns_server, conflicts_with = "proxy_socks5")]
+    pub dns_server: Option<SocketAddr>,
+
+    /// Knock the targets of this name's DNS SRV records instead of a host,
+    /// e.g. _knock._udp.example.com: by priority and weight, each in turn
+    /// until the knocks succeed. A _tcp record's port is verified open
+    /// unless --verify is given. Asks --dns-server, or the first
+    /// nameserver of /etc/resolv.conf. Needs the `custom-dns` feature
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["host", "plan", "hosts_file", "doh_url", "dot"])]
+    pub srv: Option<String>,
+
+    /// Resolve the host over DNS-over-HTTPS at this URL instead, e.g.
+    /// https://1.1.1.1/dns-query, so its name is not sent in cleartext;
+    /// --resolve pins still win. Needs the `secure-dns` feature
+    #[arg(long, value_name = "URL", value_parser = SecureDns::parse_doh_url, conflicts_with_all = ["proxy_socks5", "dns_server"])]
+    pub doh_url: Option<SecureDns>,
+
+    /// Resolve the host over DNS-over-TLS with this server, HOST[:PORT]
+    /// (853 by default), e.g. 1.1.1.1. Needs the `secure-dns` feature
+    #[arg(long, value_name = "HOST[:PORT]", value_parser = SecureDns::parse_dot, conflicts_with_all = ["proxy_socks5", "dns_server", "doh_url"])]
+    pub dot: Option<SecureDns>,
+
+    /// When a knock gets no route or times out on every retry, resolve the
+    /// host again; if its address changed, resend that knock once to the
+    /// new address and send the rest there too
+    #[arg(long, conflicts_with_all = ["all_ips", "proxy_socks5"])]
+    pub reresolve_on_failure: bool,
+
+    /// Send no DNS query at all: the host must be an IP address or pinned
+    /// with --resolve HOST:ADDR, as must a proxy, jump host, STUN or SNTP
+    /// server, and verifying connects go to the pinned address too
+    #[arg(long, conflicts_with_all = ["srv", "sequence_from_txt", "dns_server", "doh_url", "dot"])]
+    pub no_dns: bool,
+
+    /// Stop at the first knock that does not get through and exit with its
+    /// error, instead of sending the rest and reporting every failure
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Let the run succeed with up to N knocks failed, still listed in the
+    /// output; with more it fails as usual. With --hosts-file this applies
+    /// to each host
+    #[arg(long, value_name = "N", conflicts_with_all = ["fail_fast", "lockstep", "max_failure_percent"])]
+    pub max_failures: Option<usize>,
+
+    /// Like --max-failures, as a percentage (0-100) of the run's knocks
+    #[arg(long, value_name = "P", value_parser = parse_percent, conflicts_with_all = ["fail_fast", "lockstep"])]
+    pub max_failure_percent: Option<f64>,
+
+    /// Once N steps in a row got no answer (timed out or unreachable, a
+    /// refusal does not count), take the host for down and skip the rest
+    /// of the sequence
+    #[arg(long, value_name = "N", default_value_t = crate::config::DEFAULT_BREAK_AFTER)]
+    pub break_after: usize,
+
+    /// Send every step however many in a row fail to reach the host
+    #[arg(long, conflicts_with = "break_after")]
+    pub no_circuit_breaker: bool,
+
+    /// Send each knock only once the one before got through and, for UDP,
+    /// was answered (implies --expect-reply); stop the sequence at the
+    /// first reply that does not come within the step's timeout
+    #[arg(long, conflicts_with_all = ["unordered", "broadcast", "spoof_source"])]
+    pub lockstep: bool,
+
+    /// Protocol to use for knocks: tcp, udp, icmp (echo requests; needs the
+    /// `raw` feature), sctp (Linux only) or both (TCP then UDP on each port)
+    #[arg(short, long, value_parser = parse_protocol, default_value = "tcp")]
     pub protocol: Protocol,
 
-    /// Comma-separated port sequence (e.g. "7000,8000,9000")
-    #[arg(short, long, value_parser = parse_port, value_delimiter = ',')]
-    pub sequence: Vec<u16>,
+    /// How TCP knocks are sent: full connect, or a bare SYN (needs the `raw` feature)
+    #[arg(long, value_enum, default_value_t = TcpMode::Connect)]
+    pub tcp_mode: TcpMode,
+
+    /// Send crafted TCP segments with these flags instead of connecting,
+    /// e.g. "fin", "syn,ack", "xmas", "null" (needs the `raw` feature)
+    #[arg(long, value_parser = parse_tcp_flags)]
+    pub tcp_flags: Option<TcpFlags>,
+
+    /// Send TCP and UDP knocks as crafted packets from this source address
+    /// (needs the `raw` feature and CAP_NET_RAW); replies cannot come back,
+    /// so --expect-reply is turned off
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "i_understand_spoofing",
+        conflicts_with = "proxy_socks5"
+    )]
+    pub spoof_source: Option<IpAddr>,
+
+    /// Acknowledge that --spoof-source sends packets claiming to come from
+    /// an address that is not yours
+    #[arg(long, requires = "spoof_source")]
+    pub i_understand_spoofing: bool,
+
+    /// Count a refused TCP connection as a failed knock (default: delivered)
+    #[arg(long)]
+    pub refused_is_failure: bool,
+
+    /// Comma-separated port sequence (e.g. "7000,8000,9000").
+    /// With --protocol icmp each number is the echo payload size in bytes
+    /// instead of a port. A step can pick its own knock type, e.g.
+    /// "8080:http:/knock/abc123" for an HTTP GET of that path, or
+    /// "443:tls[:SNI]" for a TLS ClientHello, and the local port it is
+    /// sent from, e.g. "7000<40001", the IP TTL of its packets, e.g.
+    /// "7000~63", and its own number of attempts in place of --retries,
+    /// e.g. "9000*5". Ports in parentheses form a group
+    /// sent together, and the next step waits until all of it is done,
+    /// e.g. "(7000,8000),9000".
+    #[arg(short, long, value_parser = parse_plan)]
+    pub sequence: Vec<KnockPlan>,
+
+    /// Derive the port sequence from this shared secret and the current
+    /// time, TOTP-style, instead of giving --sequence. The secret is read
+    /// from file:PATH, env:VAR, keyring:SERVICE/USER or prompt:
+    #[arg(
+        long,
+        alias = "totp-secret-file",
+        value_name = "SOURCE",
+        value_parser = Unechoed(SecretSource::from_str),
+        conflicts_with = "sequence",
+        requires = "totp_knocks"
+    )]
+    pub totp_secret: Option<SecretSource>,
+
+    /// Number of knocks in a TOTP-derived sequence
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), requires = "totp_secret")]
+    pub totp_knocks: Option<u16>,
+
+    /// TOTP time step, e.g. "30s", "2m" or "1h"
+    #[arg(long, value_name = "DURATION", value_parser = parse_totp_step, default_value = "30s")]
+    pub totp_step: u64,
+
+    /// Lowest port a TOTP-derived knock can use
+    #[arg(long, default_value_t = 10000)]
+    pub totp_port_base: u16,
+
+    /// Number of ports above --totp-port-base TOTP-derived knocks spread over
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..), default_value_t = 50000)]
+    pub totp_port_range: u16,
+
+    /// HMAC algorithm for TOTP-derived ports
+    #[arg(long, value_enum, default_value_t = TotpAlgorithm::Sha1)]
+    pub totp_algorithm: TotpAlgorithm,
+
+    /// Derive the port sequence from a passphrase and the host name
+    /// (HKDF-SHA256, see `passphrase::derive_ports`) instead of giving
+    /// --sequence. The passphrase is read from SOURCE (file:PATH, env:VAR,
+    /// keyring:SERVICE/USER), or asked for on the terminal when none is
+    /// given; never from the command line
+    #[arg(
+        long,
+        value_name = "SOURCE",
+        value_parser = Unechoed(SecretSource::from_str),
+        num_args = 0..=1,
+        default_missing_value = "prompt:",
+        conflicts_with_all = ["sequence", "totp_secret"],
+        requires = "derived_knocks"
+    )]
+    pub ports_from_secret: Option<SecretSource>,
+
+    /// Number of knocks in a passphrase-derived sequence
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), requires = "ports_from_secret")]
+    pub derived_knocks: Option<u16>,
+
+    /// Lowest port a passphrase-derived knock can use
+    #[arg(long, default_value_t = 10000)]
+    pub derived_port_base: u16,
+
+    /// Number of ports above --derived-port-base passphrase-derived knocks
+    /// spread over
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..), default_value_t = 50000)]
+    pub derived_port_range: u16,
+
+    /// Fetch the port sequence from the DNS TXT record of this name,
+    /// written as --sequence takes it, instead of giving --sequence; a
+    /// record of several strings is read as one. Asked of --dns-server, or
+    /// the first nameserver of /etc/resolv.conf, when the run starts; the
+    /// ports are only shown by --dry-run. Needs the `custom-dns` feature
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["sequence", "totp_secret", "ports_from_secret"]
+    )]
+    pub sequence_from_txt: Option<String>,
+
+    /// Key the --sequence-from-txt record is sealed with: the record then
+    /// holds, in base64, the sequence sealed with AES-256-GCM as
+    /// --encrypt-key seals payloads. The key (32 raw bytes or 64 hex
+    /// digits) is read from file:PATH, env:VAR, keyring:SERVICE/USER or
+    /// prompt:. Needs the `crypto` feature
+    #[arg(long, value_name = "SOURCE", value_parser = Unechoed(SecretSource::from_str), requires = "sequence_from_txt")]
+    pub txt_key: Option<SecretSource>,
+
+    /// Work the port sequence out with the rhai script at PATH when the
+    /// run starts, instead of giving --sequence: its plan(ctx) gets the
+    /// host, the time and the --script-secret secrets and returns the
+    /// steps, each a port, a --sequence entry or a map of port, protocol,
+    /// payload, delay, timeout and attempts (see `script`). The steps are
+    /// only shown by --dry-run. Needs the `scripting` feature
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["sequence", "totp_secret", "ports_from_secret", "sequence_from_txt"]
+    )]
+    pub script: Option<PathBuf>,
+
+    /// A secret the --script gets as ctx.secrets.NAME, read from SOURCE
+    /// (file:PATH, env:VAR, keyring:SERVICE/USER or prompt:); repeat for
+    /// more
+    #[arg(long, value_name = "NAME=SOURCE", value_parser = Unechoed(parse_script_secret), requires = "script")]
+    pub script_secret: Vec<(String, SecretSource)>,
+
+    /// Tell the sequence by the lengths of UDP datagrams instead of by
+    /// ports: one knock per length (e.g. "100,237,61"), all to
+    /// --length-port, each that many random bytes. Timing and retries
+    /// work as for any knock
+    #[arg(
+        long,
+        value_name = "LENGTHS",
+        value_delimiter = ',',
+        value_parser = parse_knock_length,
+        requires = "length_port",
+        conflicts_with_all = ["sequence", "totp_secret", "ports_from_secret", "sequence_from_txt", "script"]
+    )]
+    pub length_sequence: Vec<usize>,
+
+    /// Tell this code of 0s and 1s (e.g. "10110") by the gaps between
+    /// identical knocks to --timing-port instead of by ports: a gap of one
+    /// --timing-unit for a 0 and of three for a 1, one knock more than
+    /// there are bits, sent on the strict timing schedule
+    #[arg(
+        long,
+        value_name = "BITS",
+        value_parser = crate::config::TimingCode::parse_bits,
+        requires = "timing_port",
+        conflicts_with_all = ["sequence", "totp_secret", "ports_from_secret", "sequence_from_txt", "script", "length_sequence", "unordered", "decoys"]
+    )]
+    // Spelled out so clap takes the whole code as one value
+    pub timing_code: Option<::std::vec::Vec<bool>>,
+
+    /// Gap of a 0 bit of the --timing-code; a 1 bit's is three times it
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay, default_value = "200ms", requires = "timing_code")]
+    pub timing_unit: Duration,
+
+    /// The port every --timing-code knock goes to
+    #[arg(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..), requires = "timing_code")]
+    pub timing_port: Option<u16>,
+
+    /// Protocol of the --timing-code knocks: udp or tcp
+    #[arg(long, value_name = "PROTO", value_parser = parse_protocol, default_value = "udp", requires = "timing_code")]
+    pub timing_protocol: Protocol,
+
+    /// The port every --length-sequence datagram goes to
+    #[arg(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..), requires = "length_sequence")]
+    pub length_port: Option<u16>,
+
+    /// Also derive the source port of every knock, from the range
+    /// FIRST-LAST (default 32768-60999), with the TOTP secret or
+    /// passphrase, for servers that check where knocks come from
+    #[arg(
+        long,
+        value_name = "RANGE",
+        num_args = 0..=1,
+        default_missing_value = "32768-60999",
+        value_parser = parse_source_ports,
+        conflicts_with = "sequence"
+    )]
+    pub derive_source_ports: Option<RangeInclusive<u16>>,
+
+    /// Before knocking, ask an SNTP server how far off the local clock is,
+    /// and warn when it is off by more than --max-clock-skew: TOTP ports
+    /// and signed, SPA or fwknop payloads carry the time. A server that
+    /// does not answer only draws a warning
+    #[arg(long)]
+    pub check_clock: bool,
+
+    /// SNTP server --check-clock asks, HOST[:PORT]
+    #[arg(long, value_name = "HOST[:PORT]", value_parser = NtpServer::parse, default_value = sntp::DEFAULT_SERVER, requires = "check_clock")]
+    pub ntp_server: NtpServer,
+
+    /// Clock skew --check-clock allows either way, e.g. "5s" or "1m"
+    #[arg(long, value_name = "DURATION", value_parser = parse_totp_step, default_value = "5s", requires = "check_clock")]
+    pub max_clock_skew: u64,
+
+    /// Fail without knocking, naming the skew, when the clock is off by
+    /// more than --max-clock-skew
+    #[arg(long, requires = "check_clock")]
+    pub strict_clock: bool,
 
     /// Timeout per knock in milliseconds
     #[arg(short, long, default_value_t = 500)]
     pub timeout: u64,
 
-    /// Inter-knock base delay in milliseconds
-    #[arg(long, default_value_t = 0)]
-    pub delay: u64,
+    /// Before knocking, measure the round trip to the host and use three
+    /// times its 95th percentile (at least 100ms) as the timeout of every
+    /// step without its own; --timeout bounds the measuring and is kept
+    /// when nothing could be measured
+    #[arg(long)]
+    pub auto_timeout: bool,
+
+    /// TCP port the --auto-timeout probes connect to (a refusal answers as
+    /// well as a listener); without one, the DNS lookup and the first knock
+    /// are timed instead
+    #[arg(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..), requires = "auto_timeout")]
+    pub calibration_port: Option<u16>,
+
+    /// Connect to this TCP port of the host before knocking, and when it
+    /// is already open knock nothing and exit successfully (the post-hook
+    /// still runs); some servers close a port knocked open again
+    #[arg(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..), conflicts_with_all = ["proxy_socks5", "jump"])]
+    pub skip_if_open: Option<u16>,
+
+    /// Milliseconds the --skip-if-open connect may take before the port
+    /// counts as closed
+    #[arg(long, value_name = "MS", default_value_t = crate::verify::DEFAULT_OPEN_PROBE_TIMEOUT, requires = "skip_if_open")]
+    pub skip_if_open_timeout: u64,
+
+    /// Inter-knock base delay, plus up to as much random jitter: the gap
+    /// between one knock going out and the next, even with several in
+    /// flight; not applied before the first knock. Milliseconds, or with a
+    /// unit: 250us, 1.5ms, 2s. A sub-millisecond part is kept to within
+    /// about 100µs on Linux by spinning through the last 2ms
+    #[arg(long, value_name = "DELAY", default_value = "0", value_parser = parse_delay)]
+    pub delay: Duration,
 
-    /// Max concurrent knocks
+    /// Pause before the first knock in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub initial_delay: u64,
+
+    /// Fire knock N exactly N times --delay after the first (no jitter),
+    /// however long the knocks before it took; a knock whose slot has
+    /// already passed is skipped and fails the run
+    #[arg(long, requires = "delay")]
+    pub strict_timing: bool,
+
+    /// Max concurrent knocks with --unordered, or within a group of the
+    /// sequence (default there: the whole group at once)
     #[arg(long, default_value_t = 1)]
     pub concurrency: usize,
 
+    /// Let knocks overlap, --concurrency at a time, so they may arrive out
+    /// of order (for scanning; a knock sequence needs them in order)
+    #[arg(long)]
+    pub unordered: bool,
+
+    /// Mix N decoy knocks to random high ports in before and between the
+    /// real ones, sent with the same protocol, timing and payload
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "lockstep"
+    )]
+    pub decoys: usize,
+
+    /// Ports or ranges no decoy goes to, comma-separated (e.g.
+    /// "22,8000-8100"); the sequence's own ports are always left out
+    #[arg(long, value_name = "PORTS", value_parser = parse_port_range, value_delimiter = ',', requires = "decoys")]
+    pub decoy_exclude: Vec<RangeInclusive<u16>>,
+
+    /// Let decoys follow the last real knock too; some servers take extra
+    /// packets after a sequence as the start of a new one
+    #[arg(long, requires = "decoys")]
+    pub decoys_after: bool,
+
     /// Optional UDP payload as hex (e.g. "deadbeef")
-    #[arg(long, value_parser = parse_hex_payload)]
-    pub payload: Option<Arc<Vec<u8>>>,
+    #[arg(long, value_parser = parse_hex_payload, conflicts_with = "payload_text")]
+    pub payload: Option<Bytes>,
+
+    /// Optional UDP payload as text; with --stun-server, "{public_ip}" in
+    /// it becomes the public address
+    #[arg(long, value_parser = parse_text_payload)]
+    pub payload_text: Option<Bytes>,
 
-    /// Number of retries per knock
-    #[arg(short = 'r', long, default_value_t = 1)]
-    pub retries: usize,
+    /// Optional payload written on each TCP knock connection, as hex
+    #[arg(long, value_parser = parse_hex_payload, conflicts_with = "tcp_payload_text")]
+    pub tcp_payload: Option<Bytes>,
 
-    /// Backoff between retries in milliseconds
+    /// Optional payload written on each TCP knock connection, as text;
+    /// with --stun-server, "{public_ip}" in it becomes the public address
+    #[arg(long, value_parser = parse_text_payload)]
+    pub tcp_payload_text: Option<Bytes>,
+
+    /// Wait for this many response bytes on the TCP connection before closing
+    #[arg(long, default_value_t = 0)]
+    pub tcp_expect: usize,
+
+    /// With --protocol icmp, wait for each echo reply instead of fire-and-forget
+    #[arg(long)]
+    pub icmp_reply: bool,
+
+    /// How to close TCP knock connections: graceful FIN, or an abortive RST
+    #[arg(long, value_enum, default_value_t = TcpClose::Fin)]
+    pub tcp_close: TcpClose,
+
+    /// Wait for a reply to each UDP knock; by default a successful send
+    /// counts as delivered
+    #[arg(long)]
+    pub expect_reply: bool,
+
+    /// How long to wait for a UDP reply in milliseconds; the send keeps
+    /// --timeout (defaults to --timeout)
+    #[arg(long, value_name = "MS", requires = "replies")]
+    pub recv_timeout: Option<u64>,
+
+    /// Only accept UDP replies matching this pattern: `hex:PREFIX`, or a glob
+    /// over the reply bytes (`*` any run, `?` one byte); others are ignored
+    #[arg(long, value_name = "PATTERN", value_parser = ReplyPattern::parse, requires = "replies")]
+    pub expect_pattern: Option<ReplyPattern>,
+
+    /// Accept UDP replies from this port on the target instead of the
+    /// knocked one; replies from anywhere else are ignored
+    #[arg(long, value_name = "PORT", value_parser = parse_port, requires = "replies")]
+    pub reply_port: Option<u16>,
+
+    /// Count an ICMP port-unreachable on a UDP knock as a failed knock
+    /// (default: delivered, since the datagram reached the host)
+    #[arg(long)]
+    pub strict_udp: bool,
+
+    /// Send UDP knocks with the don't-fragment bit set, so a datagram too
+    /// big for the path fails here with its size instead of vanishing on
+    /// the way (Linux only)
+    #[arg(long)]
+    pub dont_fragment: bool,
+
+    /// Allow UDP knocks to a broadcast address such as 192.168.1.255,
+    /// reaching every host on the segment; replies are not waited for
+    #[arg(long)]
+    pub broadcast: bool,
+
+    /// Send UDP knocks to a multicast group (224.0.0.0/4, ff00::/8) from
+    /// the interface with this address; IPv6 needs its zone, e.g.
+    /// fe80::1%eth0. Replies count from any member of the group
+    #[arg(long, value_name = "ADDR", value_parser = MulticastInterface::parse)]
+    pub multicast_if: Option<MulticastInterface>,
+
+    /// Hop limit of UDP knocks to a multicast group (default 1, the local
+    /// segment)
+    #[arg(long, value_name = "N")]
+    pub multicast_ttl: Option<u8>,
+
+    /// Set this firewall mark (SO_MARK, decimal or 0x hex) on the TCP and
+    /// UDP knock sockets, for policy routing out a particular link (Linux
+    /// only, needs CAP_NET_ADMIN)
+    #[arg(long, value_name = "N", value_parser = parse_fwmark, conflicts_with_all = ["proxy_socks5", "jump"])]
+    pub fwmark: Option<u32>,
+
+    /// Send the knocks with IP TTLs (IPv6 hop limits) that start at BASE
+    /// and change by STEP from one knock to the next, e.g. "64,-4" for 64,
+    /// 60, 56; a step's own TTL ("7000~63") is kept. Plain TCP and UDP
+    /// knocks only
+    #[arg(long, value_name = "BASE,STEP", value_parser = TtlWalk::parse, conflicts_with_all = ["proxy_socks5", "jump"])]
+    pub ttl_walk: Option<TtlWalk>,
+
+    /// Warn about knocks sent with a TTL below this, which may expire on
+    /// the way to the host
+    #[arg(long, value_name = "N", default_value_t = crate::config::DEFAULT_MIN_TTL, value_parser = clap::value_parser!(u8).range(1..))]
+    pub min_ttl: u8,
+
+    /// Send the knocks from inside this network namespace, as `ip netns
+    /// exec NAME` would; hooks and --verify stay where they are (Linux
+    /// only, needs CAP_SYS_ADMIN)
+    #[arg(long, value_name = "NAME")]
+    pub netns: Option<String>,
+
+    /// Fail instead of warning when a UDP payload is larger than an
+    /// unfragmented datagram carries on a common link (1472 bytes over
+    /// IPv4, 1232 over IPv6)
+    #[arg(long)]
+    pub strict: bool,
+
+    /// How UDP knocks pick their source port: os (the kernel picks, the
+    /// default), random (from 32768-60999), random:FIRST-LAST, or
+    /// range:FIRST-LAST (the first free one, counting up; a single port
+    /// pins it)
+    #[arg(long, value_name = "POLICY", value_parser = SourcePortPolicy::parse)]
+    pub source_port_policy: Option<SourcePortPolicy>,
+
+    /// Send UDP knocks from a random port of this range, "FIRST-LAST" or a
+    /// single port; the same as --source-port-policy random:RANGE
+    #[arg(long, value_name = "RANGE", value_parser = parse_source_ports, conflicts_with = "source_port_policy")]
+    pub source_ports: Option<RangeInclusive<u16>>,
+
+    /// SNI for TLS knock steps (defaults to the target host name)
+    #[arg(long, value_parser = parse_sni)]
+    pub sni: Option<String>,
+
+    /// Send a DNS A query for this name as the UDP payload (fresh query ID
+    /// per knock); an explicit --payload takes precedence
+    #[arg(long, value_name = "NAME", value_parser = parse_dns_name)]
+    pub payload_dns: Option<String>,
+
+    /// Send a single HMAC-signed Single Packet Authorization datagram as the
+    /// knock (UDP, one port); the key comes from --spa-key or
+    /// $KNOCK_SPA_KEY, never from the command line
+    #[arg(long, requires = "spa_client_id", conflicts_with_all = ["payload", "payload_dns"])]
+    pub spa: bool,
+
+    /// Where the SPA key comes from: file:PATH, env:VAR,
+    /// keyring:SERVICE/USER or prompt: (a trailing newline is ignored)
+    #[arg(long, alias = "spa-key-file", value_name = "SOURCE", value_parser = Unechoed(SecretSource::from_str), requires = "spa")]
+    pub spa_key: Option<SecretSource>,
+
+    /// Client ID carried in the SPA packet (1-255 bytes)
+    #[arg(long, value_name = "NAME", value_parser = parse_spa_client_id, requires = "spa")]
+    pub spa_client_id: Option<String>,
+
+    /// Send an fwknop-compatible SPA packet as the knock (UDP, one port;
+    /// fwknopd listens on 62201 by default). Needs the `fwknop` feature
+    #[arg(long, requires = "fwknop_access", conflicts_with_all = ["payload", "payload_dns", "spa"])]
+    pub fwknop: bool,
+
+    /// Access to request from fwknopd, e.g. "tcp/22" or "tcp/22,udp/53"
+    #[arg(long, value_name = "PROTO/PORT", value_parser = parse_fwknop_access, requires = "fwknop")]
+    pub fwknop_access: Option<String>,
+
+    /// Address fwknopd should open access for (default 0.0.0.0: the
+    /// packet's source address)
+    #[arg(long, value_name = "IP", requires = "fwknop")]
+    pub fwknop_allow_ip: Option<IpAddr>,
+
+    /// Username in the fwknop SPA message (default $USER)
+    #[arg(long, value_name = "NAME", requires = "fwknop")]
+    pub fwknop_user: Option<String>,
+
+    /// Where the fwknop Rijndael key comes from, as for --spa-key (else
+    /// $KNOCK_FWKNOP_KEY)
+    #[arg(
+        long,
+        alias = "fwknop-key-file",
+        value_name = "SOURCE",
+        value_parser = Unechoed(SecretSource::from_str),
+        requires = "fwknop"
+    )]
+    pub fwknop_key: Option<SecretSource>,
+
+    /// Where the fwknop HMAC-SHA256 key comes from (else
+    /// $KNOCK_FWKNOP_HMAC_KEY; without either the packet carries no HMAC)
+    #[arg(
+        long,
+        alias = "fwknop-hmac-key-file",
+        value_name = "SOURCE",
+        value_parser = Unechoed(SecretSource::from_str),
+        requires = "fwknop"
+    )]
+    pub fwknop_hmac_key: Option<SecretSource>,
+
+    /// Before knocking, ask this STUN server (HOST[:PORT], port 3478 by
+    /// default) for the public address this host knocks from, behind NAT
+    /// or CGNAT: fwknop asks access for it unless --fwknop-allow-ip is
+    /// given, and "{public_ip}" in payloads becomes it. A server that
+    /// does not answer draws a warning and the local address is used
+    #[arg(long, value_name = "HOST[:PORT]", value_parser = StunServer::parse, conflicts_with_all = ["proxy_socks5", "jump"])]
+    pub stun_server: Option<StunServer>,
+
+    /// Fail without knocking when the STUN server cannot tell the public
+    /// address, instead of using the local one
+    #[arg(long, requires = "stun_server")]
+    pub require_public_ip: bool,
+
+    /// Send TCP knocks through a SOCKS5 proxy, given as [USER:PASS@]HOST:PORT;
+    /// the target host name is resolved by the proxy
+    #[arg(long, value_name = "PROXY", value_parser = Socks5Proxy::parse, conflicts_with_all = ["tcp_mode", "tcp_flags"])]
+    pub proxy_socks5: Option<Socks5Proxy>,
+
+    /// Send TCP knocks through an SSH jump host, given as
+    /// [USER@]HOST[:PORT]: each knock connection is a channel the bastion
+    /// forwards (like ssh -W), authenticated by ssh-agent or ~/.ssh/config,
+    /// and the bastion resolves the target. UDP knocks are not supported.
+    /// Needs the `ssh` feature
+    #[arg(long, value_name = "BASTION", value_parser = JumpHost::parse, conflicts_with_all = ["proxy_socks5", "tcp_mode", "tcp_flags", "all_ips", "resolve", "dns_server", "doh_url", "dot", "reresolve_on_failure"])]
+    pub jump: Option<JumpHost>,
+
+    /// Attempts per knock, the first included
+    #[arg(short = 'a', long, value_name = "N", default_value_t = 1)]
+    pub attempts: usize,
+
+    /// Deprecated: retries after the first attempt, the same as
+    /// --attempts N+1
+    #[arg(short = 'r', long, value_name = "N", conflicts_with = "attempts")]
+    pub retries: Option<usize>,
+
+    /// Keep retrying each knock until it gets through, --knock-deadline
+    /// passes or the run is cancelled
+    #[arg(long, conflicts_with_all = ["attempts", "retries"])]
+    pub retry_forever: bool,
+
+    /// Backoff between retries in milliseconds; the first one with an
+    /// exponential strategy
     #[arg(short = 'b', long, default_value_t = 100)]
     pub backoff: u64,
+
+    /// How the backoff grows between retries: constant, exponential
+    /// (doubling) or jitter (exponential, randomized below it)
+    #[arg(long, value_enum, default_value_t = BackoffStrategy::Constant)]
+    pub backoff_strategy: BackoffStrategy,
+
+    /// Cap on an exponential backoff in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 10_000)]
+    pub backoff_max: u64,
+
+    /// Overall time limit of each knock in milliseconds: no retry is started
+    /// (or backoff slept) that would begin past it
+    #[arg(long, value_name = "MS")]
+    pub knock_deadline: Option<u64>,
+
+    /// The server's window in milliseconds (knockd's seq_timeout): when
+    /// retries and backoff keep a pass sending past it from its first
+    /// packet, give the pass up and start the sequence over
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub window: Option<u64>,
+
+    /// Wait this long in milliseconds before starting over (defaults to
+    /// --window)
+    #[arg(long, value_name = "MS", requires = "window")]
+    pub window_cooldown: Option<u64>,
+
+    /// Start over at most this many times before failing
+    #[arg(long, value_name = "N", default_value_t = 3, requires = "window")]
+    pub window_restarts: usize,
+
+    /// Knock only while holding a lock on the host, so another --lock run
+    /// against it cannot interleave its packets; fails naming the other
+    /// run's PID when it holds the lock
+    #[arg(long)]
+    pub lock: bool,
+
+    /// Wait up to this many milliseconds for the other run to finish
+    /// before failing
+    #[arg(long, value_name = "MS", default_value_t = 0, requires = "lock")]
+    pub lock_timeout: u64,
+
+    /// Send at most N packets or connection attempts per second across all
+    /// knocks, retries included (0 means unlimited)
+    #[arg(long, value_name = "N")]
+    pub rate: Option<u32>,
+
+    /// Cap the attempts of all knocks together at N, retries included, so
+    /// a flaky host does not cost every knock its full --attempts
+    #[arg(long, value_name = "N")]
+    pub max_attempts_total: Option<usize>,
+
+    /// What knocks get once --max-attempts-total is spent: a single
+    /// attempt each, or none (skip)
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = BudgetExhausted::Single, requires = "max_attempts_total")]
+    pub budget_exhausted: BudgetExhausted,
+
+    /// Pad every UDP payload with random bytes to this many bytes, after
+    /// any signing or encryption (at most 1232, which fits one datagram
+    /// on any IPv4 or IPv6 path)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..=crate::udp::MAX_PADDED_LEN as i64))]
+    pub pad_to: Option<u16>,
+
+    /// Wrap each UDP payload in a signed, timestamped frame (port, Unix
+    /// millis, nonce, payload, HMAC-SHA256) keyed by this secret (file:PATH,
+    /// env:VAR, keyring:SERVICE/USER or prompt:), so the server can reject
+    /// replays
+    #[arg(long, alias = "sign-key-file", value_name = "SOURCE", value_parser = Unechoed(SecretSource::from_str), conflicts_with_all = ["spa", "fwknop"])]
+    pub sign_key: Option<SecretSource>,
+
+    /// Encrypt each UDP payload with AES-256-GCM under this 32-byte key
+    /// (raw or hex, from file:PATH, env:VAR, keyring:SERVICE/USER or
+    /// prompt:), sending nonce | ciphertext | tag. Needs the `crypto`
+    /// feature
+    #[arg(long, alias = "encrypt-key-file", value_name = "SOURCE", value_parser = Unechoed(SecretSource::from_str))]
+    pub encrypt_key: Option<SecretSource>,
+
+    /// Record the knock traffic sent (and UDP replies received) to a pcap file
+    #[arg(long, value_name = "FILE")]
+    pub pcap: Option<PathBuf>,
+
+    /// Write each UDP reply to its own file in this directory (created
+    /// when missing), named by knock index, port and time; at most 64 KiB
+    /// a reply and 16 MiB a run
+    #[arg(long, value_name = "DIR", requires = "replies")]
+    pub save_replies: Option<PathBuf>,
+
+    /// Print a hex dump of every payload sent and reply received to stderr
+    #[arg(long)]
+    pub hexdump: bool,
+
+    /// Bytes dumped of each payload with --hexdump; the rest is counted
+    #[arg(long, value_name = "BYTES", default_value_t = crate::hexdump::DEFAULT_LIMIT, requires = "hexdump")]
+    pub hexdump_limit: usize,
+
+    /// Prefix every output line with when it was printed: milliseconds
+    /// since the run started, local wall-clock time, or an RFC 3339
+    /// timestamp with the offset; the summary also shows when the run
+    /// started and ended
+    #[arg(long, value_name = "FORMAT", value_enum)]
+    pub timestamps: Option<Timestamps>,
+
+    /// Run the stages of this TOML plan file one after another, each with
+    /// its own host, sequence and settings over the ones given here. Needs
+    /// the `plan-file` feature
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["host", "sequence", "totp_secret", "ports_from_secret", "sequence_from_txt", "script", "length_sequence", "timing_code", "export_knockd", "schedule"])]
+    pub plan: Option<PathBuf>,
+
+    /// Knock every host in this file, one per line (blank lines and `#`
+    /// comments skipped), with the same sequence and settings
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["host", "plan", "schedule", "export_knockd"])]
+    pub hosts_file: Option<PathBuf>,
+
+    /// Hosts of --hosts-file knocked at once; each host's knocks stay in
+    /// order
+    #[arg(long, value_name = "N", default_value_t = crate::fleet::DEFAULT_HOST_CONCURRENCY, requires = "hosts_file")]
+    pub host_concurrency: usize,
+
+    /// Let --hosts-file succeed with up to N hosts failed, still listed in
+    /// the output
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "hosts_file",
+        conflicts_with = "max_failed_host_percent"
+    )]
+    pub max_failed_hosts: Option<usize>,
+
+    /// Like --max-failed-hosts, as a percentage (0-100) of the hosts
+    #[arg(long, value_name = "P", value_parser = parse_percent, requires = "hosts_file")]
+    pub max_failed_host_percent: Option<f64>,
+
+    /// Go on with the next stage of --plan when one fails, instead of
+    /// stopping there
+    #[arg(long, requires = "plan")]
+    pub continue_on_failure: bool,
+
+    /// After knocking, connect to this TCP port of the host to check the
+    /// knocks opened it, and fail the run when they did not
+    #[arg(long, value_name = "PORT", value_parser = parse_port, conflicts_with = "plan")]
+    pub verify: Option<u16>,
+
+    /// Another sequence to knock when the one before does not --verify,
+    /// e.g. the old one while the host's sequence is being rotated; repeat
+    /// for more rungs, tried in order
+    #[arg(
+        long,
+        value_name = "SEQUENCE",
+        value_parser = parse_plan,
+        requires = "verify",
+        conflicts_with_all = ["totp_secret", "ports_from_secret"]
+    )]
+    pub ladder: Vec<KnockPlan>,
+
+    /// Milliseconds to wait before knocking the next --ladder rung
+    #[arg(long, value_name = "MS", default_value_t = 2000, requires = "ladder")]
+    pub ladder_cooldown: u64,
+
+    /// Most milliseconds the --ladder rungs may take together, knocks,
+    /// verifying connects and cooldowns included, before the run fails
+    #[arg(long, value_name = "MS", default_value_t = 60000, value_parser = clap::value_parser!(u64).range(1..), requires = "verify")]
+    pub ladder_max_time: u64,
+
+    /// After a --plan stage's or --verify's connect, read what the service
+    /// sends first and fail unless it starts with this, e.g. "SSH-2.0";
+    /// for stages whose `verify` names no banner of its own
+    #[arg(long, value_name = "TEXT", requires = "verifier", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    pub verify_banner: Option<String>,
+
+    /// Most bytes read looking for --verify-banner [default: 256]
+    #[arg(long, value_name = "N", requires = "verify_banner")]
+    pub verify_banner_bytes: Option<usize>,
+
+    /// Milliseconds to wait for --verify-banner after connecting
+    /// [default: 2000]
+    #[arg(long, value_name = "MS", requires = "verify_banner")]
+    pub verify_banner_timeout: Option<u64>,
+
+    /// Accept --verify-banner anywhere in what is read, not only first
+    #[arg(long, requires = "verify_banner")]
+    pub verify_banner_contains: bool,
+
+    /// Let a service that sends nothing (one waiting for the client to
+    /// speak first) still verify, reported as connected but no banner
+    #[arg(long, requires = "verify_banner")]
+    pub verify_banner_optional: bool,
+
+    /// Print the resolved plan, including TOTP-derived ports, and exit
+    /// without sending anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Resolve, build, sign and encrypt every payload and draw every
+    /// delay before the first packet, then send exactly that; with
+    /// --dry-run the warmed knocks are listed
+    #[arg(long)]
+    pub warmup: bool,
+
+    /// Hold the first knock of a warmed-up run until this time, as Unix
+    /// seconds (fractions allowed) or UTC, e.g. 2026-10-16T12:00:00.250Z
+    #[arg(long, value_name = "TIME", value_parser = crate::warmup::parse_start_at, requires = "warmup")]
+    pub start_at: Option<SystemTime>,
+
+    /// Hold the first knock of a warmed-up run until Enter is pressed
+    #[arg(long, requires = "warmup", conflicts_with = "start_at")]
+    pub start_on_key: bool,
+
+    /// Print a knockd.conf section that opens for this sequence, with a
+    /// seq_timeout covering the slowest run, and exit without sending
+    /// anything
+    #[arg(long, conflicts_with_all = ["dry_run", "confirm"])]
+    pub export_knockd: bool,
+
+    /// Print the JSON Schema of --plan files, for editors and CI
+    /// validators, and exit. Needs the `plan-file` feature
+    #[arg(long, exclusive = true)]
+    pub print_config_schema: bool,
+
+    /// Keep running and knock at every fire time of this cron expression
+    /// (minute hour day-of-month month day-of-week, local time), e.g.
+    /// "55 8 * * 1-5"; fire times passing during a run are skipped. Needs
+    /// the `schedule` feature
+    #[arg(long, value_name = "CRON", conflicts_with_all = ["dry_run", "confirm", "export_knockd"])]
+    pub schedule: Option<String>,
+
+    /// Soak-test the knocks: run the whole sequence, and --verify when
+    /// given, this many times, --soak-interval apart, then print the
+    /// success rate, the failures by step and by kind of error and the
+    /// latency percentiles. Ctrl-C ends the soak early and still prints
+    /// them
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub soak: Option<u64>,
+
+    /// Soak-test the knocks for this long instead of a number of times,
+    /// e.g. "30m", "1h" or "1d"
+    #[arg(long, value_name = "DURATION", value_parser = parse_age)]
+    pub soak_duration: Option<Duration>,
+
+    /// Wait between the end of one soak iteration and the start of the
+    /// next, e.g. "500ms" or "5s"
+    #[arg(long, value_name = "DELAY", value_parser = parse_delay, default_value = "1s", requires = "soak_limit")]
+    pub soak_interval: Duration,
+
+    /// Also write the soak's statistics and every iteration as JSON to
+    /// PATH, or to stdout for "-"
+    #[arg(long, value_name = "PATH", requires = "soak_limit")]
+    pub soak_json: Option<PathBuf>,
+
+    /// Also write every soak iteration as a CSV row to PATH, or to stdout
+    /// for "-"
+    #[arg(long, value_name = "PATH", requires = "soak_limit")]
+    pub soak_csv: Option<PathBuf>,
+
+    /// Record each successful run's host and sequence (hashed) in this
+    /// file, and forget them when a run or its --plan verify fails
+    /// [default with --skip-if-recent or --resume:
+    /// $XDG_STATE_HOME/async_port_knocker/state]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["schedule", "hosts_file", "export_knockd"])]
+    pub state_file: Option<PathBuf>,
+
+    /// Exit at once, successfully, when the state file shows the same host
+    /// and sequence (or --plan file) knocked successfully less than this
+    /// long ago, e.g. "10m", "90s" or "1h"
+    #[arg(long, value_name = "AGE", value_parser = parse_age, conflicts_with_all = ["schedule", "hosts_file", "export_knockd"])]
+    pub skip_if_recent: Option<Duration>,
+
+    /// Note in the state file which knock the run last delivered, and when
+    /// the last run on the host with the same sequence was cut short
+    /// within --resume-window, carry on after its last delivered knock
+    /// instead of from the start
+    #[arg(long, conflicts_with_all = ["plan", "schedule", "hosts_file", "export_knockd", "totp_secret", "ports_from_secret", "sequence_from_txt", "script", "decoys"])]
+    pub resume: bool,
+
+    /// How recently an interrupted run must have delivered its last knock
+    /// to be resumed, e.g. "10s" or "2m" [default: 10s, the sequence
+    /// timeout of `listen`]
+    #[arg(long, value_name = "AGE", value_parser = parse_age, requires = "resume")]
+    pub resume_window: Option<Duration>,
+
+    /// Record the run in this SQLite database: its plan (secrets left
+    /// out), each knock as it ends and the exit status; see
+    /// `async_port_knocker history`. Needs the `history` feature
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["dry_run", "schedule", "plan", "hosts_file", "export_knockd"])]
+    pub history_db: Option<PathBuf>,
+
+    /// Serve Prometheus metrics of the --schedule runs' knocks at
+    /// http://ADDR/metrics, e.g. 127.0.0.1:9109, for as long as the
+    /// schedule runs. Needs the `metrics` feature
+    #[arg(long, value_name = "ADDR", requires = "schedule")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Take commands for the --schedule run on a Unix socket at this path,
+    /// one per line: `status` (the last run's report as JSON), `knock` (run
+    /// now), `reload` (rebuild the configuration from the command line,
+    /// forgetting cached addresses) and `stop`. The socket is mode 0600
+    #[arg(long, value_name = "PATH", requires = "schedule")]
+    pub control_socket: Option<PathBuf>,
+
+    /// Also send the knock events to the local syslog daemon, at a level
+    /// per event (failures as err, retried attempts as warning, ...).
+    /// Needs the `syslog` feature
+    #[arg(long)]
+    pub log_syslog: bool,
+
+    /// Send the knock events only to syslog and print none of them
+    #[arg(long, requires = "log_syslog")]
+    pub syslog_only: bool,
+
+    /// Syslog facility: user, daemon, auth or local0-local7
+    #[arg(
+        long,
+        value_name = "FACILITY",
+        default_value = "user",
+        requires = "log_syslog"
+    )]
+    pub syslog_facility: String,
+
+    /// Syslog message format: rfc3164 or rfc5424 (with the knock as
+    /// structured data)
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "rfc3164",
+        requires = "log_syslog"
+    )]
+    pub syslog_format: String,
+
+    /// Datagram socket of the syslog daemon
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "/dev/log",
+        requires = "log_syslog"
+    )]
+    pub syslog_socket: PathBuf,
+
+    /// Show a desktop notification when the run ends, naming the host and
+    /// any --plan port verified; a --schedule only notifies when its runs
+    /// turn from succeeding to failing or back. Needs the `notify` feature
+    #[arg(long)]
+    pub notify_desktop: bool,
+
+    /// Show the resolved plan and ask for confirmation before sending
+    #[arg(long)]
+    pub confirm: bool,
+
+    /// Answer yes to the --confirm prompt (for scripted use)
+    #[arg(short = 'y', long, requires = "confirm")]
+    pub yes: bool,
+
+    /// Run this shell command before each run, with KNOCK_HOST,
+    /// KNOCK_SEQUENCE and KNOCK_PROTOCOL set; the run stops if it fails
+    #[arg(long, value_name = "CMD")]
+    pub pre_hook: Option<String>,
+
+    /// Run this shell command after each run, also told how it went in
+    /// KNOCK_RESULT, KNOCK_EXIT_CODE, KNOCK_ERROR, KNOCK_FAILED_PORT(S) and
+    /// KNOCK_DURATION_MS; a failure is only reported
+    #[arg(long, value_name = "CMD")]
+    pub post_hook: Option<String>,
+
+    /// Run this shell command after each run that failed, before the
+    /// --post-hook, told the same
+    #[arg(long, value_name = "CMD")]
+    pub failure_hook: Option<String>,
+
+    /// Kill a hook still running after this long, e.g. "30s" or "2m"
+    #[arg(long, value_name = "AGE", value_parser = parse_age, default_value = "30s")]
+    pub hook_timeout: Duration,
+
+    /// Fail a run that succeeded when its --post-hook fails (exit code 1)
+    #[arg(long)]
+    pub strict_hooks: bool,
+}
+
+/// A `--resolve` value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveArg {
+    Strategy(ResolveStrategy),
+    Pin(ResolvePin),
+}
+
+impl Cli {
+    /// The last `--resolve` strategy given, `all` without one.
+    pub fn resolve_strategy(&self) -> ResolveStrategy {
+        let strategy = self.resolve.iter().rev().find_map(|arg| match arg {
+            ResolveArg::Strategy(strategy) => Some(*strategy),
+            ResolveArg::Pin(_) => None,
+        });
+        strategy.unwrap_or(ResolveStrategy::All)
+    }
+
+    /// The hosts `--resolve` pins to addresses, in the order given.
+    pub fn resolve_pins(&self) -> Vec<ResolvePin> {
+        let pins = self.resolve.iter().filter_map(|arg| match arg {
+            ResolveArg::Pin(pin) => Some(pin.clone()),
+            ResolveArg::Strategy(_) => None,
+        });
+        pins.collect()
+    }
+
+    /// Flags for crafted raw TCP knocks, if any raw TCP mode is selected.
+    pub fn raw_tcp_flags(&self) -> Option<TcpFlags> {
+        match (self.tcp_flags, self.tcp_mode) {
+            (Some(flags), _) => Some(flags),
+            (None, TcpMode::Syn) => Some(TcpFlags::SYN),
+            (None, TcpMode::Connect) => None,
+        }
+    }
+}
+
+/// How a TCP knock reaches the wire
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum TcpMode {
+    /// Regular connect() with a full three-way handshake
+    Connect,
+    /// Single crafted SYN over a raw socket, no handshake
+    Syn,
+}
+
+/// Listen for a knock sequence and run a command for each source that
+/// completes it
+#[derive(Parser)]
+#[command(name = "async_port_knocker listen", version)]
+pub struct ListenCli {
+    /// Comma-separated ports of the sequence, in order, each optionally
+    /// with its protocol (e.g. "7000,8000/udp,9000")
+    #[arg(
+        short,
+        long,
+        value_parser = parse_listen_step,
+        value_delimiter = ',',
+        required_unless_present = "one_time_file"
+    )]
+    pub sequence: Vec<ListenStep>,
+
+    /// Accept each sequence of this file once, one per line in --sequence
+    /// form; a used line is commented out so a replayed knock does nothing
+    #[arg(long, value_name = "PATH", conflicts_with = "sequence")]
+    pub one_time_file: Option<PathBuf>,
+
+    /// Protocol of the ports given without one: tcp or udp
+    #[arg(short, long, value_parser = parse_protocol, default_value = "tcp")]
+    pub protocol: Protocol,
+
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    pub bind: IpAddr,
+
+    /// Time in milliseconds from a source's first knock by which it has to
+    /// complete the sequence
+    #[arg(long, alias = "window", default_value_t = 10_000)]
+    pub seq_timeout: u64,
+
+    /// Sources followed through the sequence at once; beyond that the one
+    /// heard from least recently is forgotten
+    #[arg(long, default_value_t = crate::server::DEFAULT_MAX_SOURCES)]
+    pub max_sources: usize,
+
+    /// Ignore a source after this many out-of-order knocks
+    #[arg(long, value_name = "KNOCKS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub ban_after: Option<u32>,
+
+    /// How long a source stays ignored, in seconds
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 60,
+        requires = "ban_after"
+    )]
+    pub ban_time: u64,
+
+    /// Only count UDP knocks signed with this key (as sent with --sign-key;
+    /// file:PATH, env:VAR, keyring:SERVICE/USER or prompt:); the command
+    /// gets what the client signed along in KNOCK_CLIENT_ID
+    #[arg(long, alias = "sign-key-file", value_name = "SOURCE", value_parser = Unechoed(SecretSource::from_str))]
+    pub sign_key: Option<SecretSource>,
+
+    /// Reject signed knocks whose timestamp is further than this many
+    /// seconds from the local clock
+    #[arg(long, value_name = "SECS", default_value_t = 30, requires = "sign_key")]
+    pub max_skew: u64,
+
+    /// Nonces of signed knocks remembered to reject replays
+    #[arg(long, value_name = "NONCES", default_value_t = crate::server::DEFAULT_REPLAY_CACHE, requires = "sign_key")]
+    pub replay_cache: usize,
+
+    /// Shell command run for each completed sequence, with %IP% replaced
+    /// by the source address (e.g. "nft add element inet filter allow { %IP% }")
+    #[arg(long)]
+    pub command: Option<String>,
+
+    /// Kill the command when it runs longer than this, e.g. "30s" or "2m"
+    #[arg(long, value_name = "AGE", value_parser = parse_age, default_value = "30s")]
+    pub command_timeout: Duration,
+}
+
+/// Listen the way the binary does, printing each completed sequence, until
+/// Ctrl-C or SIGTERM.
+pub async fn listen(cli: ListenCli) -> Result<(), AppError> {
+    let one_time_file = cli.one_time_file.clone();
+    let mut server = KnockServer::bind(ServerConfig::from(cli)).await?;
+    println!("Listening for ports {:?}", server.ports());
+    loop {
+        let done = tokio::select! {
+            signal = crate::shutdown::shutdown_signal() => {
+                return Err(AppError::Interrupted(signal?));
+            }
+            done = server.next_completed() => done,
+        };
+        let Some(done) = done else {
+            return Ok(());
+        };
+        let command = match &done.command {
+            None => String::new(),
+            Some(Ok(())) => ", command ran".to_string(),
+            Some(Err(e)) => format!(", command {e}"),
+        };
+        let client = match &done.client {
+            Some(client) => format!(" as '{client}'"),
+            None => String::new(),
+        };
+        println!(
+            "{}{client} completed the sequence in {}ms{command}",
+            done.source,
+            done.elapsed.as_millis()
+        );
+        let path = one_time_file.as_deref().unwrap_or(Path::new(""));
+        match done.one_time {
+            None => {}
+            Some(Ok(used)) => {
+                println!(
+                    "Used one-time sequence on line {} of {}, {} left",
+                    used.line,
+                    path.display(),
+                    used.remaining
+                );
+                if used.remaining == 0 {
+                    eprintln!(
+                        "Warning: no one-time sequences left in {}; no knock opens this host \
+                         until more are added and the listener restarted",
+                        path.display()
+                    );
+                }
+            }
+            Some(Err(e)) => {
+                eprintln!("Warning: one-time sequence not crossed off, command not run: {e}")
+            }
+        }
+    }
+}
+
+/// Generate a random knock sequence and print it for this tool and for
+/// knockd
+#[derive(Parser)]
+#[command(name = "async_port_knocker gen-sequence", version)]
+pub struct GenSequenceCli {
+    /// Number of ports in the sequence
+    #[arg(long, default_value_t = 5)]
+    pub length: usize,
+
+    /// Lowest port to draw
+    #[arg(long, default_value_t = 10000)]
+    pub min: u16,
+
+    /// Highest port to draw
+    #[arg(long, default_value_t = 60000)]
+    pub max: u16,
+
+    /// Protocols each port is drawn from, comma-separated: tcp, udp
+    #[arg(long, value_parser = parse_protocol, value_delimiter = ',', default_value = "tcp")]
+    pub protocol_mix: Vec<Protocol>,
+
+    /// Ports never drawn, comma-separated
+    #[arg(long, value_parser = parse_port, value_delimiter = ',', default_value = "22,80,443")]
+    pub exclude: Vec<u16>,
+
+    /// Draw the same sequence for the same seed, e.g. for documentation;
+    /// never for a sequence that guards a host
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+/// Print a generated sequence the way the binary does.
+pub fn gen_sequence(cli: GenSequenceCli) -> Result<(), AppError> {
+    let spec = SequenceSpec {
+        length: cli.length,
+        min: cli.min,
+        max: cli.max,
+        protocols: cli.protocol_mix,
+        exclude: cli.exclude,
+        seed: cli.seed,
+    };
+    let sequence = crate::generate::generate(&spec).map_err(AppError::InvalidConfig)?;
+    println!("--sequence {}", crate::generate::knocker_syntax(&sequence));
+    println!("{}", crate::generate::knockd_syntax(&sequence));
+    Ok(())
+}
+
+/// Knock listeners on loopback and check they saw the sequence, to try
+/// the knocker out before relying on it
+#[derive(Parser)]
+#[command(name = "async_port_knocker self-test", version)]
+pub struct SelfTestCli {}
+
+/// Run the self-test the way the binary does, printing PASS or FAIL for
+/// each check; fails when any check does.
+pub async fn self_test(_cli: SelfTestCli) -> Result<(), AppError> {
+    let report = crate::selftest::self_test().await?;
+    for check in &report.checks {
+        println!("{check}");
+    }
+    match report.passed() {
+        true => {
+            println!("PASS");
+            Ok(())
+        }
+        false => {
+            println!("FAIL");
+            Err(AppError::Runtime("self-test failed".into()))
+        }
+    }
+}
+
+/// List the local interfaces, their addresses and which hold the default
+/// routes, to pick an address for options like --multicast-if
+#[derive(Parser)]
+#[command(name = "async_port_knocker interfaces", version)]
+pub struct InterfacesCli {
+    /// Print a JSON array instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Print the local interfaces as a table, or as JSON with `--json`.
+pub fn interfaces(cli: InterfacesCli) -> Result<(), AppError> {
+    let interfaces = crate::interfaces::list()
+        .map_err(|e| AppError::Runtime(format!("cannot list the local interfaces: {e}")))?;
+    match cli.json {
+        true => println!("{}", crate::interfaces::json(&interfaces)),
+        false => print!("{}", crate::interfaces::table(&interfaces)),
+    }
+    Ok(())
+}
+
+/// List, show and prune the runs recorded with --history-db
+#[derive(Parser)]
+#[command(name = "async_port_knocker history", version)]
+pub struct HistoryCli {
+    /// The database the runs were recorded in with --history-db
+    #[arg(long, value_name = "PATH")]
+    pub history_db: PathBuf,
+
+    #[command(subcommand)]
+    pub command: HistoryCommand,
+}
+
+#[derive(clap::Subcommand)]
+pub enum HistoryCommand {
+    /// The most recent runs, newest first
+    List {
+        /// How many runs to list
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// One run: how it ended, its plan and each of its knocks
+    Show { run_id: i64 },
+    /// Delete the runs started longer ago than --older-than, knocks and all
+    Prune {
+        /// e.g. "90d" or "12h"
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        older_than: Duration,
+    },
+}
+
+/// Print the runs of a history database, or prune them.
+#[cfg(feature = "history")]
+pub fn history(cli: HistoryCli) -> Result<(), AppError> {
+    use crate::history::History;
+
+    let history = History::open(&cli.history_db).map_err(AppError::History)?;
+    let time = |t: SystemTime| {
+        chrono::DateTime::<chrono::Local>::from(t)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    match cli.command {
+        HistoryCommand::List { limit } => {
+            let runs = history.list(limit).map_err(AppError::History)?;
+            println!(
+                "{:>6}  {:<19}  {:<11}  {:>6}  HOST",
+                "ID", "STARTED", "RESULT", "KNOCKS"
+            );
+            for run in runs {
+                println!(
+                    "{:>6}  {}  {:<11}  {:>6}  {}",
+                    run.id,
+                    time(run.started_at),
+                    run.status(),
+                    format!("{}/{}", run.succeeded, run.steps),
+                    run.host
+                );
+            }
+        }
+        HistoryCommand::Show { run_id } => {
+            let Some((run, steps)) = history.show(run_id).map_err(AppError::History)? else {
+                return Err(AppError::History(format!("no run {run_id}")));
+            };
+            println!("Run:       {}", run.id);
+            println!("Host:      {}", run.host);
+            println!("Started:   {}", time(run.started_at));
+            match run.finished_at {
+                Some(at) => println!("Finished:  {}", time(at)),
+                None => println!("Finished:  never; the process ended before the run did"),
+            }
+            match (run.exit_code, &run.error) {
+                (Some(code), Some(error)) => {
+                    println!("Result:    {} (exit {code}): {error}", run.status())
+                }
+                (Some(code), None) => println!("Result:    {} (exit {code})", run.status()),
+                (None, _) => println!("Result:    {}", run.status()),
+            }
+            println!("Plan:");
+            for line in run.config.lines() {
+                println!("  {line}");
+            }
+            println!("Knocks:    {}/{} succeeded", run.succeeded, run.steps);
+            for step in steps {
+                let result = match (step.succeeded, step.latency, &step.error) {
+                    (true, Some(latency), _) => {
+                        format!("OK {:.1}ms", latency.as_secs_f64() * 1000.0)
+                    }
+                    (true, None, _) => "OK".to_string(),
+                    (false, _, Some(error)) => format!("FAILED: {error}"),
+                    (false, _, None) => "FAILED".to_string(),
+                };
+                println!(
+                    "  #{} {} {}/{} to {} after {} attempt(s): {result}",
+                    step.step,
+                    time(step.at),
+                    step.port,
+                    step.protocol,
+                    step.addr.as_deref().unwrap_or("-"),
+                    step.attempts
+                );
+            }
+        }
+        HistoryCommand::Prune { older_than } => {
+            let before = SystemTime::now() - older_than;
+            let pruned = history.prune(before).map_err(AppError::History)?;
+            println!("Pruned {pruned} run(s) started before {}", time(before));
+        }
+    }
+    Ok(())
+}
+
+/// Without the `history` feature there is no database to look through.
+#[cfg(not(feature = "history"))]
+pub fn history(_cli: HistoryCli) -> Result<(), AppError> {
+    Err(AppError::InvalidConfig(
+        "`history` requires building with `--features history`".into(),
+    ))
+}
+
+/// Parse the knocker's command line, filling in what a `--uri` or the
+/// `--ssh-host` entry gives for each option not passed as a flag itself.
+pub fn parse_args<I, T>(args: I) -> Result<Cli, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    use clap::parser::ValueSource;
+    use clap::{CommandFactory, FromArgMatches};
+
+    let mut args: Vec<std::ffi::OsString> = args.into_iter().map(Into::into).collect();
+    let matches = Cli::command().try_get_matches_from(&args)?;
+    let cli = Cli::from_arg_matches(&matches)?;
+    let from = match (&cli.uri, &cli.ssh_host) {
+        (Some(uri), _) => uri.args(),
+        (None, Some(name)) => {
+            let invalid =
+                |e: String| Cli::command().error(clap::error::ErrorKind::ValueValidation, e);
+            let path = match &cli.ssh_config {
+                Some(path) => path.clone(),
+                None => crate::ssh_config::user_config().ok_or_else(|| {
+                    invalid("no home directory for ~/.ssh/config; give --ssh-config".into())
+                })?,
+            };
+            SshHost::lookup(&path, name)
+                .map_err(|e| invalid(format!("--ssh-host {name}: {e}")))?
+                .args()
+        }
+        (None, None) => return Ok(cli),
+    };
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let extra: Vec<String> = from
+        .into_iter()
+        .filter(|arg| {
+            let option = arg[2..].split('=').next().unwrap_or_default();
+            match option {
+                "host" => !given("host") && !given("srv"),
+                "sequence" => ![
+                    "sequence",
+                    "totp_secret",
+                    "ports_from_secret",
+                    "sequence_from_txt",
+                    "script",
+                    "length_sequence",
+                    "timing_code",
+                ]
+                .into_iter()
+                .any(given),
+                _ => !given(&option.replace('-', "_")),
+            }
+        })
+        .collect();
+    args.extend(extra.into_iter().map(Into::into));
+    Cli::try_parse_from(args)
+}
+
+/// Run the knocks the command line describes the way the binary does:
+/// progress printed to stdout, stopped by Ctrl-C or SIGTERM like [`crate::run`].
+pub async fn run(cli: Cli) -> Result<KnockReport, AppError> {
+    run_observed(cli, None).await
+}
+
+/// As [`run`], with `also` told of the knocks too.
+async fn run_observed(
+    cli: Cli,
+    also: Option<Arc<dyn KnockObserver + Send + Sync>>,
+) -> Result<KnockReport, AppError> {
+    if cli.retries.is_some() {
+        eprintln!("Warning: --retries is deprecated; --retries N is --attempts N+1");
+    }
+    let mut output = knock_output(&cli, Arc::new(printer(&cli)))?;
+    if let Some(also) = also {
+        output = Arc::new(Tee(output, also));
+    }
+    let notifier = Notifier::new(&cli, &output)?;
+    let host = cli.host.clone().or(cli.srv.clone()).unwrap_or_default();
+    warn_unused_pins(&cli, &[&host]);
+    let history_db = cli.history_db.clone();
+    let mut config = KnockConfig::from(cli);
+    let history = match history_db {
+        Some(path) => Some(HistoryLog::begin(&path, &host, &config)?),
+        None => None,
+    };
+    if let Some(history) = &history {
+        output = Arc::new(Tee(output, history.clone()));
+    }
+    config.observer = Some(output);
+    let result = crate::run(config).await;
+    notifier
+        .finished(&host, result.as_ref().map(|_| None))
+        .await;
+    if let Some(history) = history {
+        history.finished(&result);
+    }
+    result
+}
+
+/// The run recorded in the `--history-db` database: begun before any
+/// knock, each knock as it ends, and how the run ended. A write that
+/// fails warns once and the knocks carry on.
+struct HistoryLog {
+    #[cfg(feature = "history")]
+    history: Mutex<crate::history::History>,
+    #[cfg(feature = "history")]
+    path: PathBuf,
+    #[cfg(feature = "history")]
+    run: i64,
+    #[cfg(feature = "history")]
+    steps: std::sync::atomic::AtomicUsize,
+    #[cfg(feature = "history")]
+    warned: AtomicBool,
+}
+
+impl HistoryLog {
+    /// Record the start of a run knocking `host` as `config` says.
+    #[cfg(feature = "history")]
+    fn begin(path: &Path, host: &str, config: &KnockConfig) -> Result<Arc<Self>, AppError> {
+        let history = crate::history::History::open(path).map_err(AppError::History)?;
+        // The run resolves the host; each knock records the address it went to
+        let plan: String = crate::confirm::describe_plan(config, &[])
+            .lines()
+            .filter(|line| !line.starts_with("Addresses:"))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let run = history
+            .begin(host, &plan, SystemTime::now())
+            .map_err(AppError::History)?;
+        Ok(Arc::new(Self {
+            history: Mutex::new(history),
+            path: path.to_path_buf(),
+            run,
+            steps: Default::default(),
+            warned: AtomicBool::new(false),
+        }))
+    }
+
+    /// Without the `history` feature `--history-db` is an error.
+    #[cfg(not(feature = "history"))]
+    fn begin(_path: &Path, _host: &str, _config: &KnockConfig) -> Result<Arc<Self>, AppError> {
+        Err(AppError::InvalidConfig(
+            "--history-db requires building with `--features history`".into(),
+        ))
+    }
+
+    /// Record how the run ended, with the exit code the binary gives it.
+    fn finished(&self, result: &Result<KnockReport, AppError>) {
+        #[cfg(feature = "history")]
+        {
+            let (code, error) = match result {
+                Ok(_) => (0, None),
+                Err(e) => (e.exit_code(), Some(e.to_string())),
+            };
+            let history = self.history.lock().unwrap();
+            if let Err(e) = history.finish(self.run, SystemTime::now(), code, error.as_deref()) {
+                self.warn(&e);
+            }
+        }
+        #[cfg(not(feature = "history"))]
+        let _ = result;
+    }
+
+    #[cfg(feature = "history")]
+    fn warn(&self, e: &str) {
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "Warning: cannot record the run in history database {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+impl KnockObserver for HistoryLog {
+    #[cfg(feature = "history")]
+    fn on_result(&self, outcome: &crate::KnockOutcome) {
+        let history = self.history.lock().unwrap();
+        let step = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Err(e) = history.record_step(self.run, step, outcome, SystemTime::now()) {
+            self.warn(&e);
+        }
+    }
+}
+
+/// The binary's own output, stamped as `--timestamps` says.
+fn printer(cli: &Cli) -> StdoutObserver {
+    match cli.timestamps {
+        Some(format) => StdoutObserver::timestamped(format),
+        None => StdoutObserver::default(),
+    }
+}
+
+/// Where the knock events of a run go: to `printer`, to syslog with
+/// `--log-syslog`, or only to syslog with `--syslog-only`.
+fn knock_output(
+    cli: &Cli,
+    printer: Arc<dyn KnockObserver + Send + Sync>,
+) -> Result<Arc<dyn KnockObserver + Send + Sync>, AppError> {
+    if !cli.log_syslog {
+        return Ok(printer);
+    }
+    let syslog = syslog_observer(cli)?;
+    match cli.syslog_only {
+        true => Ok(syslog),
+        false => Ok(Arc::new(Tee(printer, syslog))),
+    }
+}
+
+#[cfg(all(feature = "syslog", unix))]
+fn syslog_observer(cli: &Cli) -> Result<Arc<dyn KnockObserver + Send + Sync>, AppError> {
+    let facility = cli
+        .syslog_facility
+        .parse()
+        .map_err(AppError::InvalidConfig)?;
+    let format = cli.syslog_format.parse().map_err(AppError::InvalidConfig)?;
+    Ok(Arc::new(crate::syslog::SyslogObserver::new(
+        &cli.syslog_socket,
+        facility,
+        format,
+    )))
+}
+
+/// Without the `syslog` feature, or off Unix, `--log-syslog` is an error.
+#[cfg(not(all(feature = "syslog", unix)))]
+fn syslog_observer(_cli: &Cli) -> Result<Arc<dyn KnockObserver + Send + Sync>, AppError> {
+    Err(AppError::InvalidConfig(
+        "--log-syslog requires building with `--features syslog` on Unix".into(),
+    ))
+}
+
+/// Every hook passed to both observers in turn.
+struct Tee(
+    Arc<dyn KnockObserver + Send + Sync>,
+    Arc<dyn KnockObserver + Send + Sync>,
+);
+
+impl KnockObserver for Tee {
+    fn on_attempt(&self, info: &AttemptInfo) {
+        self.0.on_attempt(info);
+        self.1.on_attempt(info);
+    }
+
+    fn on_result(&self, outcome: &crate::KnockOutcome) {
+        self.0.on_result(outcome);
+        self.1.on_result(outcome);
+    }
+
+    fn on_event(&self, event: &KnockEvent) {
+        self.0.on_event(event);
+        self.1.on_event(event);
+    }
+}
+
+/// Print the knockd section for the knocks the command line describes.
+pub fn export_knockd(cli: Cli) -> Result<(), AppError> {
+    let section = crate::knockd::export_section(&KnockConfig::from(cli))?;
+    print!("{section}");
+    Ok(())
+}
+
+/// Knock at every fire time of `--schedule` the way the binary does,
+/// logging each run, until Ctrl-C or SIGTERM.
+#[cfg(feature = "schedule")]
+pub async fn run_scheduled(cli: Cli) -> Result<(), AppError> {
+    use crate::schedule::{CronSchedule, ScheduleEvent};
+
+    let expr = cli.schedule.clone().unwrap_or_default();
+    let schedule = CronSchedule::parse(&expr).map_err(AppError::InvalidConfig)?;
+    let metrics_listen = cli.metrics_listen;
+    let control_socket = cli.control_socket.clone();
+    let output = knock_output(&cli, Arc::new(printer(&cli)))?;
+    let notifier = Notifier::new(&cli, &output)?;
+    let host = cli.host.clone().unwrap_or_default();
+    warn_unused_pins(&cli, &[&host]);
+    let mut config = KnockConfig::from(cli);
+    config.observer = Some(output);
+    let (cancel, abort) = (
+        tokio_util::sync::CancellationToken::new(),
+        tokio_util::sync::CancellationToken::new(),
+    );
+    let shutdown = crate::cancel_on_shutdown(cancel.clone(), abort)?;
+    let metrics = match metrics_listen {
+        Some(addr) => Some(serve_metrics(addr, &mut config, cancel.clone()).await?),
+        None => None,
+    };
+    let (commands, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let control = match control_socket {
+        Some(path) => Some(serve_control(&path, &mut config, commands, cancel.clone()).await?),
+        None => None,
+    };
+    let mut systemd = Systemd::from_env();
+    let time = |t: &chrono::DateTime<chrono::Local>| t.format("%Y-%m-%d %H:%M:%S %:z").to_string();
+    let stop = cancel.clone();
+    let run = crate::schedule::run_on_schedule_controlled;
+    let result = run(
+        config,
+        &schedule,
+        cancel,
+        &mut received,
+        |event| match event {
+            ScheduleEvent::Waiting { next } => println!("Next knock at {}", time(&next)),
+            ScheduleEvent::Missed { count } => eprintln!(
+                "Warning: skipped {count} fire time(s) of '{schedule}' that passed during the run"
+            ),
+            ScheduleEvent::Reloaded => println!("Configuration reloaded"),
+            ScheduleEvent::Ran {
+                at,
+                result: Ok(report),
+            } => {
+                let status = format!(
+                    "Knock at {}: all {} knocks got through",
+                    time(&at),
+                    report.steps.len()
+                );
+                println!("{status}");
+                systemd.ran(&status, true);
+                notifier.changed(&host, Ok(None));
+            }
+            ScheduleEvent::Ran { at, result: Err(e) } => {
+                let status = format!("Knock at {} failed: {e}", time(&at));
+                eprintln!("{status}");
+                systemd.ran(&status, false);
+                notifier.changed(&host, Err(&e));
+            }
+        },
+    )
+    .await;
+    // The metrics endpoint and control socket go with the schedule
+    systemd.stopping();
+    stop.cancel();
+    for server in [metrics, control].into_iter().flatten() {
+        let _ = server.await;
+    }
+    result?;
+    match shutdown.signal() {
+        Some(signal) => Err(AppError::Interrupted(signal)),
+        None => Ok(()),
+    }
+}
+
+/// Start answering scrapes at `addr` and have `config`'s knocks counted
+/// for them, besides sent to its observer.
+#[cfg(all(feature = "schedule", feature = "metrics"))]
+async fn serve_metrics(
+    addr: SocketAddr,
+    config: &mut KnockConfig,
+    cancel: tokio_util::sync::CancellationToken,
+) -> Result<tokio::task::JoinHandle<()>, AppError> {
+    let metrics = Arc::new(crate::metrics::Metrics::new());
+    let server = crate::metrics::serve(addr, metrics.clone(), cancel).await?;
+    println!("Serving metrics at http://{addr}/metrics");
+    let output = config
+        .observer
+        .take()
+        .unwrap_or_else(|| Arc::new(StdoutObserver::default()));
+    config.observer = Some(Arc::new(Tee(output, metrics)));
+    Ok(server)
+}
+
+/// Without the `metrics` feature `--metrics-listen` is an error.
+#[cfg(all(feature = "schedule", not(feature = "metrics")))]
+async fn serve_metrics(
+    _addr: SocketAddr,
+    _config: &mut KnockConfig,
+    _cancel: tokio_util::sync::CancellationToken,
+) -> Result<tokio::task::JoinHandle<()>, AppError> {
+    Err(AppError::InvalidConfig(
+        "--metrics-listen requires building with `--features metrics`".into(),
+    ))
+}
+
+/// Start taking commands on a socket at `path`, handing the ones for the
+/// schedule to `commands` and answering `status` from `config`'s reports.
+#[cfg(all(feature = "schedule", unix))]
+async fn serve_control(
+    path: &std::path::Path,
+    config: &mut KnockConfig,
+    commands: tokio::sync::mpsc::UnboundedSender<crate::schedule::ScheduleCommand>,
+    cancel: tokio_util::sync::CancellationToken,
+) -> Result<tokio::task::JoinHandle<()>, AppError> {
+    use crate::control::{ControlCommand, LastReport};
+    use crate::schedule::ScheduleCommand;
+
+    let last = Arc::new(LastReport::default());
+    let output = config
+        .observer
+        .take()
+        .unwrap_or_else(|| Arc::new(StdoutObserver::default()));
+    let observer: Arc<dyn KnockObserver + Send + Sync> = Arc::new(Tee(output, last.clone()));
+    config.observer = Some(observer.clone());
+    let send = move |command, done: &str| match commands.send(command) {
+        Ok(()) => format!("ok: {done}"),
+        Err(_) => "error: the schedule has stopped".to_string(),
+    };
+    let stop = cancel.clone();
+    let respond = move |command| match command {
+        ControlCommand::Status => last.json(),
+        ControlCommand::Knock => send(ScheduleCommand::KnockNow, "knocking now"),
+        ControlCommand::Reload => match reloaded_config(&observer) {
+            Ok(config) => send(ScheduleCommand::Reload(Box::new(config)), "reloaded"),
+            Err(e) => format!("error: {e}"),
+        },
+        ControlCommand::Stop => {
+            stop.cancel();
+            "ok: stopping".to_string()
+        }
+    };
+    let server = crate::control::serve(path, respond, cancel).await?;
+    println!("Taking commands on {}", path.display());
+    Ok(server)
+}
+
+/// The configuration the command line gives now, knocks reported to
+/// `observer` as before.
+#[cfg(all(feature = "schedule", unix))]
+fn reloaded_config(
+    observer: &Arc<dyn KnockObserver + Send + Sync>,
+) -> Result<KnockConfig, AppError> {
+    let cli = parse_args(std::env::args_os()).map_err(|e| {
+        let message = e.to_string();
+        AppError::InvalidConfig(message.lines().next().unwrap_or_default().to_string())
+    })?;
+    let mut config = KnockConfig::from(cli);
+    config.validate()?;
+    config.observer = Some(observer.clone());
+    Ok(config)
+}
+
+/// Off Unix `--control-socket` is an error.
+#[cfg(all(feature = "schedule", not(unix)))]
+async fn serve_control(
+    _path: &std::path::Path,
+    _config: &mut KnockConfig,
+    _commands: tokio::sync::mpsc::UnboundedSender<crate::schedule::ScheduleCommand>,
+    _cancel: tokio_util::sync::CancellationToken,
+) -> Result<tokio::task::JoinHandle<()>, AppError> {
+    Err(AppError::InvalidConfig(
+        "--control-socket is only available on Unix".into(),
+    ))
+}
+
+/// Without the `schedule` feature `--schedule` is an error.
+#[cfg(not(feature = "schedule"))]
+pub async fn run_scheduled(_cli: Cli) -> Result<(), AppError> {
+    Err(AppError::InvalidConfig(
+        "--schedule requires building with `--features schedule`".into(),
+    ))
+}
+
+/// Soak-test the knocks the command line describes, printing a line per
+/// iteration and the statistics at the end, also when Ctrl-C or SIGTERM
+/// ends the soak early.
+pub async fn run_soak(cli: Cli) -> Result<(), AppError> {
+    use crate::soak::{Soak, SoakLimit};
+
+    let limit = match (cli.soak, cli.soak_duration) {
+        (_, Some(duration)) => SoakLimit::Duration(duration),
+        (iterations, None) => {
+            SoakLimit::Iterations(usize::try_from(iterations.unwrap_or(1)).unwrap_or(usize::MAX))
+        }
+    };
+    let soak = Soak {
+        limit,
+        interval: cli.soak_interval,
+    };
+    // Opened first, so a path that cannot be written fails before the soak
+    let json = cli.soak_json.as_deref().map(Output::create).transpose()?;
+    let csv = cli.soak_csv.as_deref().map(Output::create).transpose()?;
+    let host = cli.host.clone().or(cli.srv.clone()).unwrap_or_default();
+    warn_unused_pins(&cli, &[&host]);
+    let config = KnockConfig::from(cli);
+    let (cancel, abort) = (
+        tokio_util::sync::CancellationToken::new(),
+        tokio_util::sync::CancellationToken::new(),
+    );
+    let shutdown = crate::cancel_on_shutdown(cancel.clone(), abort)?;
+    let report = crate::soak::run_soak(config, &soak, cancel, |iteration| {
+        let (number, millis) = (iteration.number, iteration.latency.as_millis());
+        match &iteration.failure {
+            None => println!("Iteration {number}: ok in {millis}ms"),
+            Some(failure) => println!(
+                "Iteration {number}: failed at {} in {millis}ms: {}",
+                failure.place(),
+                failure.error
+            ),
+        }
+    })
+    .await?;
+    print!("{}", report.summary());
+    if let Some(output) = json {
+        output.write(&format!("{}\n", report.json()))?;
+    }
+    if let Some(output) = csv {
+        output.write(&report.csv())?;
+    }
+    match shutdown.signal() {
+        Some(signal) => Err(AppError::Interrupted(signal)),
+        None => Ok(()),
+    }
+}
+
+/// Where `--soak-json` or `--soak-csv` goes: a file, or stdout for `-`.
+enum Output {
+    Stdout,
+    File(PathBuf, std::fs::File),
+}
+
+impl Output {
+    fn create(path: &Path) -> Result<Self, AppError> {
+        if path == Path::new("-") {
+            return Ok(Output::Stdout);
+        }
+        std::fs::File::create(path)
+            .map(|file| Output::File(path.to_path_buf(), file))
+            .map_err(|e| Output::error(path, e))
+    }
+
+    fn write(self, text: &str) -> Result<(), AppError> {
+        use std::io::Write;
+        match self {
+            Output::Stdout => {
+                print!("{text}");
+                Ok(())
+            }
+            Output::File(path, mut file) => file
+                .write_all(text.as_bytes())
+                .map_err(|e| Output::error(&path, e)),
+        }
+    }
+
+    fn error(path: &Path, e: std::io::Error) -> AppError {
+        AppError::Io(std::io::Error::new(
+            e.kind(),
+            format!("cannot write {}: {e}", path.display()),
+        ))
+    }
+}
+
+/// Run the stages of `--plan` the way the binary does, announcing each
+/// stage before its knocks.
+#[cfg(feature = "plan-file")]
+pub async fn run_plan(cli: Cli) -> Result<(), AppError> {
+    use crate::workflow::{BannerCheck, Verify, Workflow, WorkflowEvent};
+
+    let mut workflow = Workflow::load(cli.plan.as_deref().unwrap_or(Path::new("")))?;
+    if let Some(banner) = &cli.verify_banner {
+        let verifies = workflow.stages.iter_mut().filter_map(|s| s.verify.as_mut());
+        for verify in verifies.filter(|v| v.banner.is_none()) {
+            verify.banner = Some(banner.clone());
+            verify.banner_contains = cli.verify_banner_contains;
+            verify.banner_optional = cli.verify_banner_optional;
+            if let Some(bytes) = cli.verify_banner_bytes {
+                verify.banner_bytes = bytes;
+            }
+            if let Some(timeout) = cli.verify_banner_timeout {
+                verify.banner_timeout = timeout;
+            }
+        }
+    }
+    let stage_hosts: Vec<&str> = workflow.stages.iter().map(|s| s.host.as_str()).collect();
+    warn_unused_pins(&cli, &stage_hosts);
+    let continue_on_failure = cli.continue_on_failure;
+    let output = knock_output(&cli, Arc::new(printer(&cli)))?;
+    let notifier = Notifier::new(&cli, &output)?;
+    let (mut host, mut verified) = (String::new(), None);
+    let mut base = KnockConfig::from(cli);
+    base.observer = Some(output);
+    let result =
+        crate::workflow::run_workflow(&workflow, &base, continue_on_failure, |event| match event {
+            WorkflowEvent::Started {
+                index,
+                count,
+                stage,
+            } => {
+                let verify = match &stage.verify {
+                    Some(Verify {
+                        port,
+                        banner: Some(banner),
+                        ..
+                    }) => format!(", then verify port {port} is open and says \"{banner}\""),
+                    Some(verify) => format!(", then verify port {} is open", verify.port),
+                    None => String::new(),
+                };
+                println!(
+                    "Stage {}/{count} {}: {}{verify}",
+                    index + 1,
+                    stage.label(index),
+                    stage.host
+                );
+                host.clone_from(&stage.host);
+            }
+            WorkflowEvent::Verified { port, banner, .. } => {
+                match banner {
+                    BannerCheck::NotChecked => println!("Verified: port {port} is open"),
+                    BannerCheck::Matched(text) => println!("Verified: port {port} is open: {text}"),
+                    BannerCheck::Silent => {
+                        println!("Verified: port {port} is open, connected but no banner")
+                    }
+                }
+                verified = Some(port);
+            }
+            WorkflowEvent::Failed { index, error } => {
+                eprintln!("Stage {} failed: {error}", index + 1)
+            }
+        })
+        .await;
+    notifier
+        .finished(&host, result.as_ref().map(|_| verified))
+        .await;
+    result
+}
+
+/// Without the `plan-file` feature `--plan` is an error.
+#[cfg(not(feature = "plan-file"))]
+pub async fn run_plan(_cli: Cli) -> Result<(), AppError> {
+    Err(AppError::InvalidConfig(
+        "--plan requires building with `--features plan-file`".into(),
+    ))
 }
 
-/// Supported knock protocols
-#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
-pub enum Protocol {
-    Tcp,
-    Udp,
+/// Print the JSON Schema of plan files.
+#[cfg(feature = "plan-file")]
+pub fn print_config_schema() -> Result<(), AppError> {
+    println!("{}", crate::workflow::Workflow::json_schema());
+    Ok(())
+}
+
+/// Without the `plan-file` feature there are no plan files to describe.
+#[cfg(not(feature = "plan-file"))]
+pub fn print_config_schema() -> Result<(), AppError> {
+    Err(AppError::InvalidConfig(
+        "--print-config-schema requires building with `--features plan-file`".into(),
+    ))
+}
+
+/// Knock as [`run`] or [`run_plan`] do, unless the state file shows the
+/// same knocks succeeded within `--skip-if-recent`, then note how the run
+/// went there. With `--resume` the run notes its progress there as it
+/// goes, and carries on where an interrupted one stopped. A state file
+/// that cannot be read or written only costs a warning; the knocks matter
+/// more.
+pub async fn run_with_state(mut cli: Cli) -> Result<(), AppError> {
+    use crate::state::{self, StateFile};
+
+    let knock = |cli: Cli, progress: Option<ResumeProgress>| async move {
+        match (cli.plan.is_some(), progress) {
+            (true, _) => run_plan(cli).await,
+            (false, None) => run(cli).await.map(|_| ()),
+            (false, Some(progress)) => run_observed(cli, Some(Arc::new(progress)))
+                .await
+                .map(|_| ()),
+        }
+    };
+    let Some(path) = cli.state_file.clone().or_else(state::default_path) else {
+        eprintln!("Warning: no state directory (XDG_STATE_HOME or HOME); give --state-file");
+        return knock(cli, None).await;
+    };
+    let load = |path: &Path| {
+        StateFile::load(path).unwrap_or_else(|e| {
+            eprintln!(
+                "Warning: cannot read state file {}: {e}; starting it over",
+                path.display()
+            );
+            StateFile::empty(path)
+        })
+    };
+    let key = state_key(&cli);
+    if let (Some(max), Some(age)) = (cli.skip_if_recent, load(&path).age(&key)) {
+        if age < max {
+            println!(
+                "Recently knocked {} {}s ago (--skip-if-recent {}s); not knocking again",
+                key.host,
+                age.as_secs(),
+                max.as_secs()
+            );
+            return Ok(());
+        }
+    }
+
+    let mut progress = None;
+    if cli.resume {
+        let steps: Vec<KnockStep> = cli
+            .sequence
+            .iter()
+            .flat_map(|plan| plan.0.clone())
+            .collect();
+        let window = cli
+            .resume_window
+            .unwrap_or(crate::server::DEFAULT_SEQ_TIMEOUT);
+        let from = resume_from(load(&path).progress(&key.host), &key, steps.len(), window);
+        if from > 0 {
+            let mut rest = steps[from..].to_vec();
+            rest[0].with_previous = false;
+            cli.sequence = vec![KnockPlan(rest)];
+        }
+        if !cli.dry_run {
+            progress = Some(ResumeProgress::new(&path, &key, &steps, cli.protocol, from));
+        }
+    }
+
+    let dry_run = cli.dry_run;
+    let result = knock(cli, progress).await;
+    if dry_run {
+        return result;
+    }
+    // Read again, keeping what other runs wrote meanwhile
+    let mut state = load(&path);
+    match &result {
+        Ok(()) => state.record(&key, SystemTime::now()),
+        Err(_) => state.forget(&key),
+    }
+    if let Err(e) = state.save() {
+        eprintln!("Warning: cannot write state file {}: {e}", path.display());
+    }
+    result
+}
+
+/// Where a `--resume` run of the `len` knocks of `key` starts: after the
+/// last knock `progress` says was delivered, when it is of the same
+/// sequence and within `window`, else at the start, saying why.
+fn resume_from(
+    progress: Option<crate::state::Progress>,
+    key: &crate::state::StateKey,
+    len: usize,
+    window: Duration,
+) -> usize {
+    let Some(progress) = progress else {
+        return 0;
+    };
+    let host = &key.host;
+    let age = SystemTime::now()
+        .duration_since(progress.at)
+        .unwrap_or_default();
+    let next = progress.last_delivered + 1;
+    if progress.key != *key {
+        eprintln!(
+            "Not resuming: the interrupted run on {host} knocked another sequence; knocking \
+             this one from the start"
+        );
+        0
+    } else if age > window {
+        eprintln!(
+            "Not resuming: the interrupted run on {host} delivered its last knock {}s ago, \
+             longer than --resume-window {}s; knocking the sequence from the start",
+            age.as_secs(),
+            window.as_secs()
+        );
+        0
+    } else if next >= len {
+        eprintln!(
+            "Not resuming: the interrupted run on {host} delivered every knock; knocking the \
+             sequence from the start"
+        );
+        0
+    } else {
+        let delivered = match next {
+            1 => "knock 1 was".to_string(),
+            _ => format!("knocks 1-{next} were"),
+        };
+        println!(
+            "Resuming {host} at knock {} of {len}: {delivered} delivered {}s ago",
+            next + 1,
+            age.as_secs()
+        );
+        next
+    }
+}
+
+/// Notes in the state file, as a `--resume` run goes, the last knock of
+/// the sequence delivered with all before it. Resuming in the middle of a
+/// group would split it, so only whole groups count.
+struct ResumeProgress {
+    path: PathBuf,
+    key: crate::state::StateKey,
+    steps: Vec<KnockStep>,
+    /// Per step, the outcomes it still waits for: two for a knock over
+    /// both TCP and UDP; and how many steps are noted as delivered.
+    pending: Mutex<(Vec<usize>, usize)>,
+    warned: AtomicBool,
+}
+
+impl ResumeProgress {
+    /// Progress of knocking `steps`, the first `from` of them delivered by
+    /// an earlier run.
+    fn new(
+        path: &Path,
+        key: &crate::state::StateKey,
+        steps: &[KnockStep],
+        protocol: Protocol,
+        from: usize,
+    ) -> Self {
+        let pending = steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| match step.protocol.unwrap_or(protocol) {
+                _ if i < from => 0,
+                Protocol::Both => 2,
+                _ => 1,
+            })
+            .collect();
+        Self {
+            path: path.to_path_buf(),
+            key: key.clone(),
+            steps: steps.to_vec(),
+            pending: Mutex::new((pending, from)),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Steps delivered from the start, up to the end of the last whole
+    /// group among them.
+    fn delivered(&self, pending: &[usize]) -> usize {
+        let mut delivered = pending.iter().take_while(|&&left| left == 0).count();
+        while delivered > 0 && delivered < self.steps.len() && self.steps[delivered].with_previous {
+            delivered -= 1;
+        }
+        delivered
+    }
+}
+
+impl KnockObserver for ResumeProgress {
+    fn on_result(&self, outcome: &crate::KnockOutcome) {
+        if !outcome.succeeded {
+            return;
+        }
+        let mut guard = self.pending.lock().unwrap();
+        let (pending, noted) = &mut *guard;
+        let step =
+            (0..pending.len()).find(|&i| pending[i] > 0 && self.steps[i].port == outcome.port);
+        let Some(step) = step else {
+            return;
+        };
+        pending[step] -= 1;
+        let delivered = self.delivered(pending);
+        if delivered <= *noted {
+            return;
+        }
+        *noted = delivered;
+        let mut state = crate::state::StateFile::load(&self.path)
+            .unwrap_or_else(|_| crate::state::StateFile::empty(&self.path));
+        state.record_progress(&self.key, delivered - 1, SystemTime::now());
+        if let Err(e) = state.save() {
+            if !self.warned.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "Warning: cannot note the run's progress in state file {}: {e}",
+                    self.path.display()
+                );
+            }
+        }
+    }
+}
+
+/// What the state file keeps this command line's run under: the host and
+/// how its sequence is given, or the plan file and its contents.
+fn state_key(cli: &Cli) -> crate::state::StateKey {
+    if let Some(plan) = &cli.plan {
+        let text = std::fs::read_to_string(plan).unwrap_or_default();
+        return crate::state::StateKey::new(&plan.display().to_string(), &text);
+    }
+    let sequence: Vec<String> = cli.sequence.iter().map(ToString::to_string).collect();
+    let mut sequence = format!(
+        "{}|{}|{:?}|{:?}",
+        sequence.join(","),
+        cli.protocol,
+        cli.totp_secret.as_ref().map(ToString::to_string),
+        cli.ports_from_secret.as_ref().map(ToString::to_string)
+    );
+    // Only then, so the keys of earlier state files still match
+    if let Some(name) = &cli.sequence_from_txt {
+        sequence.push_str(&format!("|txt:{name}"));
+    }
+    if let Some(script) = &cli.script {
+        sequence.push_str(&format!("|script:{}", script.display()));
+    }
+    if let Some(port) = cli.length_port {
+        let lengths: Vec<String> = cli.length_sequence.iter().map(usize::to_string).collect();
+        sequence.push_str(&format!("|lengths:{}@{port}", lengths.join(",")));
+    }
+    if let (Some(bits), Some(port)) = (&cli.timing_code, cli.timing_port) {
+        let bits: String = bits.iter().map(|&b| if b { '1' } else { '0' }).collect();
+        sequence.push_str(&format!("|timing:{bits}@{port}"));
+    }
+    crate::state::StateKey::new(cli.host.as_deref().unwrap_or_default(), &sequence)
+}
+
+/// Knock every host of `--hosts-file` the way the binary does, then list
+/// how each host did.
+pub async fn run_hosts(cli: Cli) -> Result<(), AppError> {
+    let hosts = crate::fleet::load_hosts(cli.hosts_file.as_deref().unwrap_or(Path::new("")))?;
+    let concurrency = cli.host_concurrency;
+    let tolerance = cli
+        .max_failed_hosts
+        .map(FailureTolerance::Count)
+        .or(cli.max_failed_host_percent.map(FailureTolerance::Percent));
+    warn_unused_pins(&cli, &hosts.iter().map(String::as_str).collect::<Vec<_>>());
+    let observer = knock_output(&cli, Arc::new(FleetObserver(printer(&cli))))?;
+    let notifier = Notifier::new(&cli, &observer)?;
+    let base = KnockConfig::from(cli);
+    let report = crate::fleet::run_fleet(
+        &base,
+        &hosts,
+        concurrency,
+        |_| Some(observer.clone()),
+        |done| match &done.result {
+            Ok(report) => println!(
+                "{}: {}/{} knocks succeeded",
+                done.host,
+                report.steps.iter().filter(|o| o.succeeded).count(),
+                report.steps.len()
+            ),
+            Err(e) => eprintln!("{}: FAILED: {e}", done.host),
+        },
+    )
+    .await?;
+    let failed = report.failed();
+    println!(
+        "Hosts: {}/{} succeeded",
+        report.hosts.len() - failed.len(),
+        report.hosts.len()
+    );
+    if let Some(tolerance) = tolerance.filter(|_| !failed.is_empty()) {
+        let within = match report.tolerated(Some(tolerance)) {
+            true => "tolerated",
+            false => "not tolerated",
+        };
+        println!(
+            "Failed hosts {within}: {} ({} of {}, at most {tolerance})",
+            failed.join(", "),
+            failed.len(),
+            report.hosts.len()
+        );
+    }
+    let hosts = format!("{} hosts", report.hosts.len());
+    let result = report.into_result(tolerance).map(|_| ());
+    notifier
+        .finished(&hosts, result.as_ref().map(|_| None))
+        .await;
+    result
+}
+
+/// Warn about `--resolve` pins for none of `hosts`, most likely typos.
+fn warn_unused_pins(cli: &Cli, hosts: &[&str]) {
+    // SRV targets are only known once the records are in
+    if cli.srv.is_some() {
+        return;
+    }
+    for pin in crate::dns::unused_pins(&cli.resolve_pins(), hosts) {
+        eprintln!(
+            "Warning: --resolve {}:{} is not used; no knock goes to {}",
+            pin.host, pin.addr, pin.host
+        );
+    }
+}
+
+/// `--notify-desktop`: a desktop notification when a run ends.
+struct Notifier {
+    #[cfg(feature = "notify")]
+    desktop: Option<Arc<crate::notify::DesktopNotifier>>,
+}
+
+impl Notifier {
+    /// Notices about the notifications go to `output`. Without the
+    /// `notify` feature `--notify-desktop` is an error.
+    fn new(cli: &Cli, output: &Arc<dyn KnockObserver + Send + Sync>) -> Result<Self, AppError> {
+        #[cfg(feature = "notify")]
+        return Ok(Self {
+            desktop: cli.notify_desktop.then(|| {
+                Arc::new(crate::notify::DesktopNotifier::new().with_observer(output.clone()))
+            }),
+        });
+        #[cfg(not(feature = "notify"))]
+        let _ = output;
+        #[cfg(not(feature = "notify"))]
+        match cli.notify_desktop {
+            true => Err(AppError::InvalidConfig(
+                "--notify-desktop requires building with `--features notify`".into(),
+            )),
+            false => Ok(Self {}),
+        }
+    }
+
+    /// Notify that the run knocking `host` ended with `result`, the port
+    /// verified open if any.
+    async fn finished(&self, host: &str, result: Result<Option<u16>, &AppError>) {
+        #[cfg(feature = "notify")]
+        if let Some(desktop) = &self.desktop {
+            desktop.show(&Self::notification(host, result)).await;
+        }
+        #[cfg(not(feature = "notify"))]
+        let _ = (host, result);
+    }
+
+    /// As [`finished`](Self::finished) for one of a series of runs,
+    /// notifying only when they turn from succeeding to failing or back.
+    /// The notification is shown on a task of its own, so the schedule
+    /// does not wait for it.
+    #[cfg(feature = "schedule")]
+    fn changed(&self, host: &str, result: Result<Option<u16>, &AppError>) {
+        #[cfg(feature = "notify")]
+        if let Some(desktop) = &self.desktop {
+            let (desktop, notification) = (desktop.clone(), Self::notification(host, result));
+            tokio::spawn(async move { desktop.on_change(&notification).await });
+        }
+        #[cfg(not(feature = "notify"))]
+        let _ = (host, result);
+    }
+
+    #[cfg(feature = "notify")]
+    fn notification(
+        host: &str,
+        result: Result<Option<u16>, &AppError>,
+    ) -> crate::notify::Notification {
+        match result {
+            Ok(verified) => crate::notify::Notification::succeeded(host, verified),
+            Err(e) => crate::notify::Notification::failed(host, &e.to_string()),
+        }
+    }
+}
+
+/// `sd_notify` messages for a `--schedule` run started by systemd, which
+/// sets `$NOTIFY_SOCKET`; without it, or off Unix, nothing is sent.
+#[cfg(feature = "schedule")]
+struct Systemd {
+    #[cfg(unix)]
+    notify: Option<crate::sdnotify::SdNotify>,
+    ready: bool,
+}
+
+#[cfg(feature = "schedule")]
+impl Systemd {
+    fn from_env() -> Self {
+        #[cfg(unix)]
+        let notify = match crate::sdnotify::SdNotify::from_env() {
+            Some(Ok(notify)) => Some(notify),
+            Some(Err(e)) => {
+                eprintln!("Warning: cannot notify systemd through $NOTIFY_SOCKET: {e}");
+                None
+            }
+            None => None,
+        };
+        Self {
+            #[cfg(unix)]
+            notify,
+            ready: false,
+        }
+    }
+
+    /// After a run ending as `status` says: ready once the first succeeds,
+    /// then a watchdog ping for each one that does.
+    fn ran(&mut self, status: &str, succeeded: bool) {
+        #[cfg(unix)]
+        if let Some(notify) = &self.notify {
+            // Lost messages are systemd's to notice, not a reason to stop
+            let _ = match (succeeded, self.ready) {
+                (true, false) => notify.ready(status),
+                (true, true) => notify.watchdog(status),
+                (false, _) => notify.status(status),
+            };
+        }
+        #[cfg(not(unix))]
+        let _ = status;
+        self.ready |= succeeded;
+    }
+
+    /// Shutting down, whatever stopped the schedule.
+    fn stopping(&self) {
+        #[cfg(unix)]
+        if let Some(notify) = &self.notify {
+            let _ = notify.stopping();
+        }
+    }
+}
+
+/// Each host's knocks as [`StdoutObserver`] prints them, without its
+/// summary: [`run_hosts`] prints one line per host instead.
+struct FleetObserver(StdoutObserver);
+
+impl KnockObserver for FleetObserver {
+    fn on_attempt(&self, info: &AttemptInfo) {
+        self.0.on_attempt(info);
+    }
+
+    fn on_event(&self, event: &KnockEvent) {
+        if !matches!(event, KnockEvent::Finished { .. }) {
+            self.0.on_event(event);
+        }
+    }
+}
+
+/// Parse a TOTP time step in whole seconds: a [`duration`], seconds when
+/// bare.
+pub fn parse_totp_step(s: &str) -> Result<u64, String> {
+    let step = duration::parse(s, Some(Duration::from_secs(1)))?;
+    match (step.as_secs(), step.subsec_nanos()) {
+        (0, _) => Err(format!("'{s}' is not a positive duration")),
+        (secs, 0) => Ok(secs),
+        _ => Err(format!("'{s}' is not a whole number of seconds")),
+    }
+}
+
+/// Parse a `--skip-if-recent` age: a [`duration`], seconds when bare.
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    match duration::parse(s, Some(Duration::from_secs(1)))? {
+        Duration::ZERO => Err(format!("'{s}' is not a positive age")),
+        age => Ok(age),
+    }
+}
+
+/// Parse a `--delay`: a [`duration`], milliseconds when bare.
+pub fn parse_delay(s: &str) -> Result<Duration, String> {
+    duration::parse(s, Some(Duration::from_millis(1)))
+}
+
+/// Parse a `--fwmark`, decimal or hex with `0x` as `ip rule` takes it.
+pub fn parse_fwmark(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("'{s}' is not a firewall mark (0 to 4294967295, or 0x hex)"))
 }
 
 /// Parse a comma‐free single port argument into u16.
@@ -55,16 +2571,166 @@ pub fn parse_port(s: &str) -> Result<u16, String> {
         .map_err(|_| format!("'{s}' is not a valid port"))
 }
 
-/// Decode a hex payload string into an Arc‐wrapped Vec<u8>.
-pub fn parse_hex_payload(s: &str) -> Result<Arc<Vec<u8>>, String> {
+/// Parse one sequence entry (`PORT` or `PORT:KIND[:ARG]`).
+pub fn parse_step(s: &str) -> Result<KnockStep, String> {
+    KnockStep::parse(s)
+}
+
+/// Parse a comma-separated sequence, groups in parentheses included.
+pub fn parse_plan(s: &str) -> Result<KnockPlan, String> {
+    KnockPlan::parse(s)
+}
+
+/// Parse one listened-for port (`PORT[/PROTO]`).
+pub fn parse_listen_step(s: &str) -> Result<ListenStep, String> {
+    ListenStep::parse(s)
+}
+
+/// Validate a TLS server name.
+pub fn parse_sni(s: &str) -> Result<String, String> {
+    crate::tls::validate_sni(s).map(|_| s.to_string())
+}
+
+/// Validate the target host, including any IPv6 `%interface` zone, and
+/// strip the brackets of an IPv6 literal; see [`crate::scope::parse_host`].
+pub fn parse_host(s: &str) -> Result<String, String> {
+    crate::scope::parse_host(s)
+}
+
+/// Validate a name for DNS-shaped payloads.
+pub fn parse_dns_name(s: &str) -> Result<String, String> {
+    crate::dns::validate_name(s).map(|_| s.to_string())
+}
+
+/// A `--max-failure-percent` or `--max-failed-host-percent` value: 0-100.
+pub fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.trim().trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("'{s}' is not a percentage of 0-100")),
+    }
+}
+
+/// A `--length-sequence` length: 1 to 65507 bytes, what one UDP datagram
+/// carries at most.
+pub fn parse_knock_length(s: &str) -> Result<usize, String> {
+    match s.trim().parse::<usize>() {
+        Ok(len) if (1..=crate::udp::MAX_PAYLOAD_LEN).contains(&len) => Ok(len),
+        _ => Err(format!(
+            "'{s}' is not a datagram length of 1-{} bytes",
+            crate::udp::MAX_PAYLOAD_LEN
+        )),
+    }
+}
+
+/// Value parser for options naming a secret source: `parse`, with errors
+/// that leave the value out rather than clap's `invalid value '...'`, as a
+/// mistyped value is likely the secret itself.
+#[derive(Clone)]
+pub struct Unechoed<F>(pub F);
+
+impl<T, F> clap::builder::TypedValueParser for Unechoed<F>
+where
+    T: Clone + Send + Sync + 'static,
+    F: Fn(&str) -> Result<T, String> + Clone + Send + Sync + 'static,
+{
+    type Value = T;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<T, clap::Error> {
+        let parsed = value
+            .to_str()
+            .ok_or_else(|| "not UTF-8".to_string())
+            .and_then(&self.0);
+        parsed.map_err(|e| {
+            let arg = arg.map_or_else(|| "...".to_string(), |a| a.to_string());
+            clap::Error::raw(
+                clap::error::ErrorKind::InvalidValue,
+                format!("invalid value for '{arg}': {e}\n"),
+            )
+            .with_cmd(cmd)
+        })
+    }
+}
+
+/// A `--script-secret` value: `NAME=SOURCE`, the name of letters, digits
+/// and `_`.
+pub fn parse_script_secret(s: &str) -> Result<(String, SecretSource), String> {
+    let (name, source) = s
+        .split_once('=')
+        .ok_or_else(|| "expected NAME=SOURCE".to_string())?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "'{name}' is not a secret name; use letters, digits and _"
+        ));
+    }
+    Ok((name.to_string(), source.parse()?))
+}
+
+/// A DNS server address: `IP:PORT`, or a bare IP for port 53.
+pub fn parse_dns_server(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("'{s}' is not a DNS server address (expected IP or IP:PORT)"))
+}
+
+/// A `--resolve` value: a strategy name, or `HOST:ADDR` pinning a host.
+pub fn parse_resolve(s: &str) -> Result<ResolveArg, String> {
+    match s.contains(':') {
+        true => s.parse().map(ResolveArg::Pin),
+        false => s.parse().map(ResolveArg::Strategy),
+    }
+}
+
+/// A port, or a range of them (`8000-8100`).
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    crate::udp::parse_port_range(s)
+}
+
+/// A UDP source port range.
+pub fn parse_source_ports(s: &str) -> Result<RangeInclusive<u16>, String> {
+    crate::udp::parse_port_range(s)
+}
+
+/// Validate an SPA client ID.
+pub fn parse_spa_client_id(s: &str) -> Result<String, String> {
+    crate::spa::validate_client_id(s).map(|_| s.to_string())
+}
+
+/// Validate an fwknop access request: comma-separated `tcp/PORT` or
+/// `udp/PORT` items.
+pub fn parse_fwknop_access(s: &str) -> Result<String, String> {
+    crate::spa::validate_fwknop_access(s).map(|_| s.to_string())
+}
+
+/// Parse a knock protocol, rejecting ones this platform cannot send.
+pub fn parse_protocol(s: &str) -> Result<Protocol, String> {
+    s.parse()
+}
+
+/// Parse a TCP flag combination for crafted knocks.
+pub fn parse_tcp_flags(s: &str) -> Result<TcpFlags, String> {
+    TcpFlags::parse(s)
+}
+
+/// Decode a hex payload string.
+pub fn parse_hex_payload(s: &str) -> Result<Bytes, String> {
     hex::decode(s)
-        .map(Arc::new)
+        .map(Bytes::from)
         .map_err(|e| format!("invalid hex payload: {e}"))
 }
 
+/// Take a text payload verbatim as its UTF-8 bytes.
+pub fn parse_text_payload(s: &str) -> Result<Bytes, String> {
+    Ok(Bytes::copy_from_slice(s.as_bytes()))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse_port;
+    use super::*;
 
     #[test]
     fn valid_port() {
@@ -75,4 +2741,227 @@ mod tests {
     fn invalid_port() {
         assert!(parse_port("foo").is_err());
     }
+
+    #[test]
+    fn host_forms() {
+        // Accepted, as reports show them; the resolver gets the ASCII name
+        for (input, host) in [
+            ("knock.example", "knock.example"),
+            ("knock.example.", "knock.example."),
+            ("bücher.example", "bücher.example"),
+            ("203.0.113.7", "203.0.113.7"),
+            ("2001:db8::1", "2001:db8::1"),
+            ("[2001:db8::1]", "2001:db8::1"),
+            ("::1", "::1"),
+            ("[::1]", "::1"),
+            // Read whole as an address: a port after IPv6 needs brackets
+            ("::1:7000", "::1:7000"),
+            ("fe80::1%1", "fe80::1%1"),
+            ("[fe80::1%1]", "fe80::1%1"),
+        ] {
+            assert_eq!(parse_host(input).as_deref(), Ok(host), "{input}");
+        }
+
+        // Refused, with what is wrong
+        for (input, error) in [
+            ("knock.example:7000", "has a port"),
+            ("203.0.113.7:7000", "has a port"),
+            ("[2001:db8::1]:7000", "has a port"),
+            ("[::1]:7000", "has a port"),
+            ("knock.example:", "without a port"),
+            ("knock.example:http", "is not a port"),
+            ("[::1]:99999", "is not a port"),
+            ("2001:db8::zz", "needs brackets"),
+            ("[::1", "closing ']'"),
+            ("::1]", "without its '['"),
+            ("[::1]x", "after ']'"),
+            ("[203.0.113.7]", "not an IPv6 address"),
+            ("[knock.example]", "not an IPv6 address"),
+            ("knock.example%eth0", "only IPv6 takes a %interface"),
+            ("fe80::1", "needs an interface"),
+            ("[fe80::1]", "needs an interface"),
+            ("", "no host given"),
+            ("[]", "not an IPv6 address"),
+            ("knock.ex ample", "label 'ex ample'"),
+            ("knock..example", "empty label"),
+            ("10.0.0.256", "not a valid IPv4 address"),
+        ] {
+            let message = parse_host(input).unwrap_err();
+            assert!(message.contains(error), "{input}: {message}");
+        }
+    }
+
+    #[test]
+    fn fwknop_access_items() {
+        assert!(parse_fwknop_access("tcp/22").is_ok());
+        assert!(parse_fwknop_access("tcp/22,udp/53").is_ok());
+        assert!(parse_fwknop_access("tcp").is_err());
+        assert!(parse_fwknop_access("icmp/1").is_err());
+        assert!(parse_fwknop_access("tcp/0").is_err());
+    }
+
+    #[test]
+    fn failure_tolerances() {
+        assert_eq!(parse_percent("25"), Ok(25.0));
+        assert_eq!(parse_percent("12.5%"), Ok(12.5));
+        assert!(parse_percent("101").is_err());
+        assert!(parse_percent("-1").is_err());
+        assert!(parse_percent("NaN").is_err());
+
+        let cli = parse_args(["apk", "-H", "h", "-s", "7000", "--max-failures", "1"]).unwrap();
+        let config = KnockConfig::from(cli);
+        assert_eq!(config.failure_tolerance, Some(FailureTolerance::Count(1)));
+        assert!(parse_args([
+            "apk",
+            "-H",
+            "h",
+            "-s",
+            "7000",
+            "--max-failures",
+            "1",
+            "--fail-fast"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn totp_step_units() {
+        assert_eq!(parse_totp_step("30"), Ok(30));
+        assert_eq!(parse_totp_step("30s"), Ok(30));
+        assert_eq!(parse_totp_step("2m"), Ok(120));
+        assert_eq!(parse_totp_step("1h"), Ok(3600));
+        assert!(parse_totp_step("0s").is_err());
+        assert!(parse_totp_step("1.5s").is_err());
+        assert!(parse_totp_step("s").is_err());
+    }
+
+    #[test]
+    fn ages_also_come_in_days() {
+        assert_eq!(parse_age("90d"), Ok(Duration::from_secs(90 * 86_400)));
+        assert_eq!(parse_age("10m"), Ok(Duration::from_secs(600)));
+        assert!(parse_age("0d").is_err());
+        assert!(parse_age("d").is_err());
+        assert_eq!(parse_age("1.5d"), Ok(Duration::from_secs(36 * 3600)));
+    }
+
+    #[test]
+    fn delay_units() {
+        let micros = |us| Ok(Duration::from_micros(us));
+        assert_eq!(parse_delay("100"), micros(100_000));
+        assert_eq!(parse_delay("250us"), micros(250));
+        assert_eq!(parse_delay("250µs"), micros(250));
+        assert_eq!(parse_delay("1.5ms"), micros(1500));
+        assert_eq!(parse_delay("2s"), micros(2_000_000));
+        assert!(parse_delay("ms").is_err());
+        assert!(parse_delay("-1").is_err());
+        // The same grammar as the rest, minutes included
+        assert_eq!(parse_delay("1m"), micros(60_000_000));
+    }
+
+    #[test]
+    fn protocol_names() {
+        assert_eq!(parse_protocol("UDP").unwrap(), Protocol::Udp);
+        assert!(parse_protocol("quic").is_err());
+        assert_eq!(
+            parse_protocol("sctp").is_ok(),
+            cfg!(target_os = "linux"),
+            "sctp only parses on Linux"
+        );
+    }
+
+    #[test]
+    fn secrets_are_named_by_source() {
+        let cli = Cli::try_parse_from([
+            "knock",
+            "--host",
+            "knock.example",
+            "--ports-from-secret",
+            "--derived-knocks",
+            "3",
+            "--sign-key",
+            "env:KNOCK_SIGN_KEY",
+        ])
+        .unwrap();
+        assert_eq!(cli.ports_from_secret, Some(SecretSource::Prompt));
+        assert_eq!(
+            cli.sign_key,
+            Some(SecretSource::Env("KNOCK_SIGN_KEY".into()))
+        );
+
+        // The old flag name still parses, but no longer takes a bare path
+        let err = Cli::try_parse_from([
+            "knock",
+            "--host",
+            "knock.example",
+            "--sequence",
+            "7000",
+            "--sign-key-file",
+            "/etc/knock.key",
+        ])
+        .err()
+        .unwrap();
+        let err = err.to_string();
+        assert!(err.contains("file:PATH"), "{err}");
+        assert!(!err.contains("/etc/knock.key"), "{err}");
+    }
+
+    #[test]
+    fn resuming_needs_the_same_recent_sequence() {
+        use crate::state::{Progress, StateKey};
+
+        let key = StateKey::new("gw.example", "7000,8000,9000");
+        let progress = |key: &StateKey, last_delivered, ago| {
+            Some(Progress {
+                key: key.clone(),
+                last_delivered,
+                at: SystemTime::now() - Duration::from_secs(ago),
+            })
+        };
+        let window = Duration::from_secs(10);
+        assert_eq!(resume_from(None, &key, 3, window), 0);
+        assert_eq!(resume_from(progress(&key, 0, 2), &key, 3, window), 1);
+        assert_eq!(resume_from(progress(&key, 1, 2), &key, 3, window), 2);
+        // Expired, another sequence, or nothing left to knock
+        assert_eq!(resume_from(progress(&key, 1, 30), &key, 3, window), 0);
+        let other = StateKey::new("gw.example", "7000,8000");
+        assert_eq!(resume_from(progress(&other, 0, 2), &key, 3, window), 0);
+        assert_eq!(resume_from(progress(&key, 2, 2), &key, 3, window), 0);
+    }
+
+    #[test]
+    fn resume_progress_counts_whole_groups() {
+        use crate::state::{StateFile, StateKey};
+
+        let dir = std::env::temp_dir().join(format!("apk-resume-{}", std::process::id()));
+        let path = dir.join("state");
+        let _ = std::fs::remove_dir_all(&dir);
+        let key = StateKey::new("gw.example", "7000,(8000,8001),9000");
+        let steps = KnockPlan::parse("7000,(8000,8001),9000").unwrap().0;
+        let progress = ResumeProgress::new(&path, &key, &steps, Protocol::Udp, 0);
+        let deliver = |port, succeeded| {
+            let mut outcome = crate::KnockOutcome::failed(port, Protocol::Udp, "");
+            outcome.succeeded = succeeded;
+            progress.on_result(&outcome);
+            StateFile::load(&path)
+                .unwrap()
+                .progress("gw.example")
+                .map(|p| p.last_delivered)
+        };
+        assert_eq!(deliver(7000, false), None);
+        assert_eq!(deliver(7000, true), Some(0));
+        // Half a group is not resumed from
+        assert_eq!(deliver(8001, true), Some(0));
+        assert_eq!(deliver(8000, true), Some(2));
+        assert_eq!(deliver(9000, true), Some(3));
+
+        // A resumed run carries on from where the last one got
+        let both = ResumeProgress::new(&path, &key, &steps, Protocol::Both, 3);
+        let outcome = crate::KnockOutcome::failed(9000, Protocol::Tcp, "");
+        both.on_result(&crate::KnockOutcome {
+            succeeded: true,
+            ..outcome
+        });
+        assert_eq!(both.pending.lock().unwrap().0, [0, 0, 0, 1]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,2628 @@
+use crate::budget::RetryBudget;
+#[cfg(feature = "cli")]
+use crate::cli::Cli;
+use crate::dns::{DnsCache, ResolutionPolicy, ResolvePin};
+use crate::hooks::Hooks;
+use crate::jump::JumpHost;
+use crate::ladder::Ladder;
+use crate::observer::KnockObserver;
+use crate::outcome::FailureTolerance;
+use crate::packet::TcpFlags;
+use crate::pattern::ReplyPattern;
+use crate::plan::{KnockPlan, KnockStep, TtlWalk};
+use crate::protocol::{
+    BackoffStrategy, BudgetExhausted, Protocol, ResolveStrategy, TcpClose, TotpAlgorithm,
+};
+use crate::ratelimit::RateLimiter;
+use crate::retry::{Attempts, BackoffPolicy};
+use crate::secret::SecretSource;
+use crate::securedns::SecureDns;
+use crate::sntp::NtpServer;
+use crate::socks::Socks5Proxy;
+use crate::stun::StunServer;
+use crate::tcp::TcpOpts;
+use crate::transport::KnockTransport;
+use crate::udp::{MulticastInterface, SourcePortPolicy, UdpOpts};
+#[cfg(feature = "cli")]
+use crate::verify::Verify;
+use crate::AppError;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Steps in a row that may fail to reach the host before the rest are
+/// skipped, unless the circuit breaker is turned off.
+pub const DEFAULT_BREAK_AFTER: usize = 3;
+
+/// Knocks sent with a lower TTL are warned about unless `--min-ttl` says
+/// otherwise; fewer hops than this rarely reach a host off the local site.
+pub const DEFAULT_MIN_TTL: u8 = 8;
+
+/// Everything one run of [`crate::run`] needs, independent of the command
+/// line. Build one with [`KnockConfig::builder`], or convert a parsed
+/// [`Cli`] with `From`.
+///
+/// Deliberately not `Debug`, like [`Socks5Proxy`], which may hold a password
+/// (and observers and transports have no `Debug` either).
+///
+/// With the `serde` feature it serializes field by field, durations in
+/// milliseconds and payloads in hex, leaving out the DNS cache, observer
+/// and transports; fields missing when deserializing take their defaults,
+/// and the result is not checked until [`validate`](Self::validate).
+#[derive(Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct KnockConfig {
+    /// Target host name or IP; link-local IPv6 needs a `%interface` zone.
+    pub host: String,
+    /// Knock every resolved address instead of sticking to one.
+    pub all_ips: bool,
+    /// Which resolved addresses are used, and in which order.
+    pub resolve: ResolveStrategy,
+    /// Host names pinned to addresses, used instead of resolving them.
+    pub resolve_pins: Vec<ResolvePin>,
+    /// DNS server to resolve the host through instead of the system
+    /// resolver (needs the `custom-dns` feature).
+    pub dns_server: Option<SocketAddr>,
+    /// Encrypted DNS server to resolve the host through instead (needs the
+    /// `secure-dns` feature).
+    pub secure_dns: Option<SecureDns>,
+    /// DNS SRV name, e.g. `_knock._udp.example.com`, whose targets are
+    /// knocked instead of `host`, in turn until one run succeeds (needs
+    /// the `custom-dns` feature; see [`crate::srv`]).
+    pub srv: Option<String>,
+    /// When a run resolves the host again rather than reusing
+    /// `dns_cache`.
+    pub resolution: ResolutionPolicy,
+    /// Addresses from earlier runs; shared by clones of the config.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub dns_cache: Arc<DnsCache>,
+    /// Resolve the host again after a knock fails to reach it, and move
+    /// the rest of the sequence to a new address (see
+    /// [`KnockOutcome::unreachable`](crate::KnockOutcome::unreachable)).
+    pub reresolve_on_failure: bool,
+    /// Send no DNS query: the host must be an IP address or pinned, and
+    /// so must the proxy, jump host, STUN and SNTP servers
+    /// ([`AppError::DnsForbidden`] otherwise).
+    pub no_dns: bool,
+    /// End the run at the first failed knock with its error, instead of
+    /// sending the rest and failing with [`AppError::Partial`].
+    pub fail_fast: bool,
+    /// Failed knocks the run gets away with: as many or fewer and it
+    /// succeeds, the failures still in its report; `None` tolerates none.
+    pub failure_tolerance: Option<FailureTolerance>,
+    /// Open the circuit breaker once this many steps in a row failed
+    /// without reaching the host (see
+    /// [`KnockOutcome::unreachable`](crate::KnockOutcome::unreachable)),
+    /// skipping the rest with [`AppError::CircuitOpen`]; a refusal does
+    /// not count. `None` sends every step however many fail.
+    pub break_after: Option<usize>,
+    /// Send each knock only once the one before got through and, for UDP,
+    /// was answered within its timeout by a reply datagram, not a port
+    /// unreachable (`expect_reply` is implied); the first that is not ends
+    /// the run, with [`AppError::Lockstep`] for a missing reply. Knocks go
+    /// one at a time.
+    pub lockstep: bool,
+    pub protocol: Protocol,
+    /// Send crafted TCP segments with these flags instead of connecting
+    /// (needs the `raw` feature).
+    pub tcp_flags: Option<TcpFlags>,
+    /// Send TCP and UDP knocks as crafted packets from this source address
+    /// (needs the `raw` feature and CAP_NET_RAW). No reply can come back,
+    /// so `expect_reply` is turned off.
+    pub spoof_source: Option<IpAddr>,
+    /// Count a refused TCP connection as a failed knock.
+    pub refused_is_failure: bool,
+    pub sequence: KnockPlan,
+    /// Derive the sequence from a shared secret instead of `sequence`.
+    pub totp: Option<TotpConfig>,
+    /// Derive the sequence from a passphrase and the host instead of
+    /// `sequence`; see [`crate::passphrase`].
+    pub passphrase_ports: Option<PassphrasePorts>,
+    /// Fetch the sequence from a DNS TXT record instead of `sequence`;
+    /// see [`crate::txt`].
+    pub sequence_from_txt: Option<TxtSequence>,
+    /// Work the sequence out with a script when the run starts instead of
+    /// `sequence`; see [`crate::script`].
+    pub script: Option<ScriptPlan>,
+    /// Tell the sequence by the lengths of UDP datagrams to one port
+    /// instead of by ports.
+    pub length_sequence: Option<LengthSequence>,
+    /// Tell a short code by the gaps between identical knocks to one port
+    /// instead of by ports; the knocks are sent under strict timing.
+    pub timing_code: Option<TimingCode>,
+    /// Also derive the local port of every knock, from this range, with
+    /// the TOTP secret or passphrase; see [`crate::totp::derive_source_ports`]
+    /// and [`crate::passphrase::derive_source_ports`].
+    pub derive_source_ports: Option<RangeInclusive<u16>>,
+    /// Check the local clock against an SNTP server before building
+    /// anything that depends on the time.
+    pub check_clock: Option<ClockCheck>,
+    /// Verify the knocks opened the host, trying the ladder's other
+    /// sequences in turn when not; see [`crate::ladder`].
+    pub ladder: Option<Ladder>,
+    /// Timeout per knock attempt in milliseconds.
+    pub timeout: u64,
+    /// Measure the latency to the host before the sequence and use a
+    /// timeout calibrated from it (see [`crate::calibrate`]) instead of
+    /// `timeout` for every step without one of its own; `timeout` stays
+    /// the limit of the calibration itself and the fallback when nothing
+    /// could be measured.
+    pub auto_timeout: bool,
+    /// TCP port the calibration connects to; without one, the DNS lookup
+    /// and the first knock are timed.
+    pub calibration_port: Option<u16>,
+    /// TCP port connected to before the knocks, on the addresses resolved
+    /// for them; when it is already open the run knocks nothing, as
+    /// knocking again closes it on some servers.
+    pub skip_if_open: Option<u16>,
+    /// How long that connect may take, in milliseconds.
+    pub open_probe_timeout: u64,
+    /// Base delay between one knock going out and the next, however many
+    /// are in flight, plus up to as much jitter. Retries are spaced by the
+    /// backoff instead. A delay with a sub-millisecond part is kept to
+    /// within about 100µs, finer than the runtime's millisecond timer.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::millis"))]
+    pub delay: Duration,
+    /// Delay before the first knock in milliseconds, without jitter.
+    pub initial_delay: u64,
+    /// Send each knock at a fixed time slot counted from the start of the
+    /// sequence (see [`KnockConfig::schedule`]), without jitter, instead of
+    /// waiting the delay after the previous knock.
+    pub strict_timing: bool,
+    /// Knocks in flight at once; only with `unordered`. They still go out
+    /// `delay` apart. Within a group of the sequence, 1 sends the whole
+    /// group at once.
+    pub concurrency: usize,
+    /// Let knocks overlap, `concurrency` at a time, so they may reach the
+    /// host out of order. Off by default: steps run strictly one by one.
+    pub unordered: bool,
+    /// Decoy knocks to random ports mixed in before and between the real
+    /// ones (see [`KnockPlan::with_decoys`]).
+    pub decoys: usize,
+    /// Ports no decoy knock goes to, besides those of the sequence.
+    pub decoy_exclude: Vec<RangeInclusive<u16>>,
+    /// Let decoys follow the last real knock too.
+    pub decoys_after: bool,
+    /// Attempts per knock, the first included.
+    pub attempts: Attempts,
+    /// Backoff between retries in milliseconds; the first one with an
+    /// exponential strategy.
+    pub backoff: u64,
+    pub backoff_strategy: BackoffStrategy,
+    /// Cap on an exponential backoff in milliseconds.
+    pub backoff_max: u64,
+    /// Overall time limit of one knock, retries included, in milliseconds.
+    pub knock_deadline: Option<u64>,
+    /// The server's window in milliseconds, like knockd's `seq_timeout`: a
+    /// pass of the sequence still sending once this long has gone by since
+    /// its first packet is given up and the sequence started over.
+    pub window: Option<u64>,
+    /// Wait between a pass given up and the next, in milliseconds; the
+    /// window itself when unset, so the server has forgotten the pass.
+    pub window_cooldown: Option<u64>,
+    /// Passes started over at most before the run fails with
+    /// [`AppError::WindowOverrun`].
+    pub window_restarts: usize,
+    /// Knock only while holding the host's lock (see [`crate::lock`]), so
+    /// no other locked run interleaves its packets.
+    pub lock: bool,
+    /// Wait this long in milliseconds for another run to let go of the
+    /// lock before failing with [`AppError::Locked`]; 0 fails at once.
+    pub lock_timeout: u64,
+    /// Where the lock files go; [`lock::lock_dir`](crate::lock::lock_dir)
+    /// when `None`.
+    pub lock_dir: Option<PathBuf>,
+    /// Sends and connects per second across all knocks, retries included;
+    /// `None` or 0 is unlimited.
+    pub rate: Option<u32>,
+    /// Attempts all the knocks of the run may make together, passes
+    /// started over included (see [`crate::budget`]); `None` is no cap.
+    pub max_attempts_total: Option<usize>,
+    /// What the knocks get once `max_attempts_total` is spent.
+    pub budget_exhausted: BudgetExhausted,
+    /// UDP payload.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::hex_opt"))]
+    pub payload: Option<Bytes>,
+    /// Send a DNS A query for this name as the UDP payload.
+    pub payload_dns: Option<String>,
+    /// Bytes written on each TCP knock connection.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::hex_opt"))]
+    pub tcp_payload: Option<Bytes>,
+    /// Response bytes to wait for before closing a TCP knock.
+    pub tcp_expect: usize,
+    pub tcp_close: TcpClose,
+    /// Wait for each ICMP echo reply.
+    pub icmp_reply: bool,
+    /// Wait for a reply to each UDP knock.
+    pub expect_reply: bool,
+    /// UDP reply wait in milliseconds; `timeout` when unset.
+    pub recv_timeout: Option<u64>,
+    /// Only accept UDP replies matching this pattern.
+    pub expect_pattern: Option<ReplyPattern>,
+    /// Port UDP replies come from, when not the knocked one.
+    pub reply_port: Option<u16>,
+    /// Count an ICMP port-unreachable on a UDP knock as a failure.
+    pub strict_udp: bool,
+    /// How UDP knocks pick the local port they are sent from.
+    pub source_port_policy: SourcePortPolicy,
+    /// Send UDP knocks with the don't-fragment bit, so one too big for the
+    /// path fails with [`AppError::Oversized`] (Linux only).
+    pub dont_fragment: bool,
+    /// Allow UDP knocks to broadcast addresses (IPv4 only). Replies from
+    /// every host on the segment tell nothing about one knock, so
+    /// `expect_reply` is turned off.
+    pub broadcast: bool,
+    /// Interface UDP knocks to a multicast group leave from.
+    pub multicast_if: Option<MulticastInterface>,
+    /// Hop limit of UDP knocks to a multicast group.
+    pub multicast_ttl: Option<u8>,
+    /// Firewall mark (SO_MARK) of the TCP and UDP knock sockets, for
+    /// policy routing (Linux only; needs CAP_NET_ADMIN).
+    pub fwmark: Option<u32>,
+    /// IP TTLs the knocks are sent with, changing from one to the next; a
+    /// step's own TTL is kept.
+    pub ttl_walk: Option<TtlWalk>,
+    /// Knocks sent with a TTL below this are warned about.
+    pub min_ttl: u8,
+    /// Network namespace under `/var/run/netns` the knocks are sent from
+    /// (Linux only; needs CAP_SYS_ADMIN).
+    pub netns: Option<String>,
+    /// Fail, instead of warning, when a UDP payload is too big for an
+    /// unfragmented datagram on a common link.
+    pub strict: bool,
+    /// Default SNI for TLS steps.
+    pub sni: Option<String>,
+    pub spa: Option<SpaSettings>,
+    pub fwknop: Option<FwknopSettings>,
+    /// Learn the public address from a STUN server, for fwknop's allow IP
+    /// and `{public_ip}` in payloads; see [`crate::stun`].
+    pub public_ip: Option<PublicIp>,
+    pub proxy_socks5: Option<Socks5Proxy>,
+    /// SSH bastion TCP knocks are forwarded through (needs the `ssh`
+    /// feature).
+    pub jump: Option<JumpHost>,
+    /// Pad every UDP payload to this many bytes.
+    pub pad_to: Option<u16>,
+    /// Key for signed, timestamped UDP payloads.
+    pub sign_key: Option<SecretSource>,
+    /// Key for AES-256-GCM UDP payloads (needs the `crypto` feature).
+    pub encrypt_key: Option<SecretSource>,
+    /// Record the knock traffic to this pcap file.
+    pub pcap: Option<PathBuf>,
+    /// Write every UDP reply to a file in this directory; see
+    /// [`crate::replies`].
+    pub save_replies: Option<PathBuf>,
+    /// Dump every payload sent and reply received as hex, up to this many
+    /// bytes each; see [`crate::hexdump`].
+    pub hexdump: Option<usize>,
+    /// Build every payload and wait before the first packet; see
+    /// [`crate::warmup`].
+    pub warmup: bool,
+    /// After warming up, hold the first knock until this time.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::unix_secs_opt"))]
+    pub start_at: Option<SystemTime>,
+    /// After warming up, hold the first knock until Enter is pressed.
+    pub start_on_key: bool,
+    /// Print the plan and return without sending.
+    pub dry_run: bool,
+    /// Print the plan and ask on stdin before sending.
+    pub confirm: bool,
+    /// Answer yes to the confirmation prompt.
+    pub assume_yes: bool,
+    /// Commands run before and after the run; see [`crate::hooks`].
+    pub hooks: Hooks,
+    /// Hooks called for every attempt and every knock's outcome.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub observer: Option<Arc<dyn KnockObserver + Send + Sync>>,
+    /// Send plain steps of these protocols through custom transports
+    /// instead of the built-in knocks.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transports: HashMap<Protocol, Arc<dyn KnockTransport + Send + Sync>>,
+    /// Send the steps on these ports through custom transports, whatever
+    /// the protocol or the step's knock type.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub step_transports: HashMap<u16, Arc<dyn KnockTransport + Send + Sync>>,
+}
+
+/// TOTP-style port derivation from a shared secret.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TotpConfig {
+    pub secret: SecretSource,
+    pub knocks: u16,
+    /// Time step in seconds.
+    pub step: u64,
+    pub port_base: u16,
+    pub port_range: u16,
+    pub algorithm: TotpAlgorithm,
+}
+
+/// Checking the local clock before time-based knocks; see
+/// [`crate::sntp`]. A server that cannot be asked only ever warns.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClockCheck {
+    pub server: NtpServer,
+    /// Skew allowed either way in seconds.
+    pub max_skew: u64,
+    /// Fail the run with [`AppError::ClockSkew`] on more skew than that,
+    /// instead of warning.
+    pub strict: bool,
+}
+
+/// Learning the public address before knocking; see [`crate::stun`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PublicIp {
+    pub server: StunServer,
+    /// Fail the run with [`AppError::PublicIp`] when the server cannot
+    /// tell, instead of warning and using the local address.
+    pub required: bool,
+}
+
+/// Port derivation from a shared passphrase.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PassphrasePorts {
+    /// Where the passphrase comes from; [`SecretSource::Prompt`] asks for
+    /// it when the run starts.
+    pub source: SecretSource,
+    pub knocks: u16,
+    pub port_base: u16,
+    pub port_range: u16,
+}
+
+/// The sequence published in a DNS TXT record.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TxtSequence {
+    /// Name whose TXT record holds the sequence.
+    pub name: String,
+    /// Key the record is sealed with, as [`crate::crypto::load_key`] reads
+    /// it (needs the `crypto` feature); a plain record when unset.
+    pub key: Option<SecretSource>,
+}
+
+/// The sequence a script works out.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScriptPlan {
+    /// The rhai script whose `plan(ctx)` returns the steps.
+    pub path: PathBuf,
+    /// Secrets the script gets as `ctx.secrets.NAME`, read when the run
+    /// starts.
+    pub secrets: Vec<(String, SecretSource)>,
+}
+
+/// A sequence told by the lengths of UDP datagrams all sent to one port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LengthSequence {
+    /// Port every datagram goes to.
+    pub port: u16,
+    /// Payload length of each knock, in order.
+    pub lengths: Vec<usize>,
+}
+
+impl LengthSequence {
+    /// One UDP step to `port` per length, its payload that many random
+    /// bytes, drawn afresh every call.
+    pub fn plan(&self, rng: &mut impl rand::RngCore) -> KnockPlan {
+        self.lengths
+            .iter()
+            .map(|&len| {
+                let mut payload = vec![0; len];
+                rng.fill_bytes(&mut payload);
+                KnockStep {
+                    protocol: Some(Protocol::Udp),
+                    payload: Some(payload.into()),
+                    ..KnockStep::new(self.port)
+                }
+            })
+            .collect()
+    }
+}
+
+/// A short code told by the gaps between identical knocks to one port: a
+/// 0 bit is a gap of one `unit`, a 1 bit of [`TimingCode::ONE_UNITS`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimingCode {
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "bits_text", deserialize_with = "bits_parsed")
+    )]
+    pub bits: Vec<bool>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::millis"))]
+    pub unit: Duration,
+    pub port: u16,
+    /// TCP or UDP.
+    pub protocol: Protocol,
+}
+
+impl TimingCode {
+    /// Units of the gap a 1 bit stands for.
+    pub const ONE_UNITS: u32 = 3;
+
+    /// Most bits a code may have.
+    pub const MAX_BITS: usize = 64;
+
+    /// Parse a code of `0`s and `1`s.
+    pub fn parse_bits(s: &str) -> Result<Vec<bool>, String> {
+        if s.is_empty() || s.len() > Self::MAX_BITS {
+            return Err(format!("a timing code has 1-{} bits", Self::MAX_BITS));
+        }
+        s.chars()
+            .map(|c| match c {
+                '0' => Ok(false),
+                '1' => Ok(true),
+                other => Err(format!("'{other}' is not a bit of a timing code")),
+            })
+            .collect()
+    }
+
+    /// The gap that tells `bit`.
+    pub fn gap(&self, bit: bool) -> Duration {
+        match bit {
+            true => self.unit * Self::ONE_UNITS,
+            false => self.unit,
+        }
+    }
+
+    /// One knock more than there are bits, each after the gap of its bit.
+    /// A knock is tried once, as a retry would be a knock of its own.
+    pub fn plan(&self) -> KnockPlan {
+        let first = std::iter::once(None);
+        first
+            .chain(self.bits.iter().map(|&bit| Some(self.gap(bit))))
+            .map(|gap| KnockStep {
+                protocol: Some(self.protocol),
+                pre_delay: gap,
+                attempts: Some(1),
+                ..KnockStep::new(self.port)
+            })
+            .collect()
+    }
+}
+
+/// The code as it is given, e.g. `10110`.
+impl std::fmt::Display for TimingCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bits: String = self
+            .bits
+            .iter()
+            .map(|&b| if b { '1' } else { '0' })
+            .collect();
+        f.write_str(&bits)
+    }
+}
+
+/// The bits of a timing code serialized as it is given, e.g. `"10110"`.
+#[cfg(feature = "serde")]
+fn bits_text<S: serde::Serializer>(bits: &[bool], serializer: S) -> Result<S::Ok, S::Error> {
+    let bits: String = bits.iter().map(|&b| if b { '1' } else { '0' }).collect();
+    serializer.serialize_str(&bits)
+}
+
+#[cfg(feature = "serde")]
+fn bits_parsed<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<bool>, D::Error> {
+    let bits = <String as serde::Deserialize>::deserialize(deserializer)?;
+    TimingCode::parse_bits(&bits).map_err(serde::de::Error::custom)
+}
+
+/// Single Packet Authorization with the crate's own packet format.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpaSettings {
+    /// Where the key comes from; `$KNOCK_SPA_KEY` when unset.
+    pub key: Option<SecretSource>,
+    pub client_id: String,
+}
+
+/// fwknop-compatible SPA (needs the `fwknop` feature).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FwknopSettings {
+    /// Access to request, e.g. `tcp/22`.
+    pub access: String,
+    /// Address to open access for; the packet's source when unset.
+    pub allow_ip: Option<IpAddr>,
+    /// Username in the message; `$USER` when unset.
+    pub user: Option<String>,
+    /// Where the Rijndael key comes from; `$KNOCK_FWKNOP_KEY` when unset.
+    pub key: Option<SecretSource>,
+    /// Where the HMAC key comes from; `$KNOCK_FWKNOP_HMAC_KEY` when unset.
+    pub hmac_key: Option<SecretSource>,
+}
+
+/// How one knock is sent by [`crate::knock_tcp`] or [`crate::knock_udp`]:
+/// timing, retries and the per-protocol behavior.
+#[derive(Clone)]
+pub struct KnockOpts {
+    /// Per-attempt timeout in milliseconds.
+    pub timeout: u64,
+    /// Number of attempts.
+    pub attempts: Attempts,
+    /// Wait between attempts.
+    pub backoff: BackoffPolicy,
+    /// Start no attempt or backoff that would run past this much time
+    /// since the knock began.
+    pub deadline: Option<Duration>,
+    /// Limiter every attempt takes a token from first, shared by all the
+    /// knocks of a run.
+    pub rate: Option<Arc<RateLimiter>>,
+    /// Budget every attempt draws from, shared by all the knocks of a run.
+    pub budget: Option<Arc<RetryBudget>>,
+    /// Dump every payload sent and reply received, up to this many bytes
+    /// each.
+    pub hexdump: Option<usize>,
+    pub tcp: TcpOpts,
+    pub udp: UdpOpts,
+}
+
+/// Same timing and behavior as a run with the command-line defaults.
+impl Default for KnockOpts {
+    fn default() -> Self {
+        KnockConfig::default().knock_opts()
+    }
+}
+
+/// Same defaults as the command line, with no host or sequence.
+impl Default for KnockConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            all_ips: false,
+            resolve: ResolveStrategy::All,
+            resolve_pins: Vec::new(),
+            dns_server: None,
+            secure_dns: None,
+            srv: None,
+            resolution: ResolutionPolicy::Once,
+            dns_cache: Arc::default(),
+            reresolve_on_failure: false,
+            no_dns: false,
+            fail_fast: false,
+            failure_tolerance: None,
+            break_after: Some(DEFAULT_BREAK_AFTER),
+            lockstep: false,
+            protocol: Protocol::Tcp,
+            tcp_flags: None,
+            spoof_source: None,
+            refused_is_failure: false,
+            sequence: KnockPlan::default(),
+            totp: None,
+            passphrase_ports: None,
+            sequence_from_txt: None,
+            script: None,
+            length_sequence: None,
+            timing_code: None,
+            derive_source_ports: None,
+            check_clock: None,
+            ladder: None,
+            timeout: 500,
+            auto_timeout: false,
+            calibration_port: None,
+            skip_if_open: None,
+            open_probe_timeout: crate::verify::DEFAULT_OPEN_PROBE_TIMEOUT,
+            delay: Duration::ZERO,
+            initial_delay: 0,
+            strict_timing: false,
+            concurrency: 1,
+            unordered: false,
+            decoys: 0,
+            decoy_exclude: Vec::new(),
+            decoys_after: false,
+            attempts: Attempts::Finite(1),
+            backoff: 100,
+            backoff_strategy: BackoffStrategy::Constant,
+            backoff_max: 10_000,
+            knock_deadline: None,
+            window: None,
+            window_cooldown: None,
+            window_restarts: 3,
+            lock: false,
+            lock_timeout: 0,
+            lock_dir: None,
+            rate: None,
+            max_attempts_total: None,
+            budget_exhausted: BudgetExhausted::Single,
+            payload: None,
+            payload_dns: None,
+            tcp_payload: None,
+            tcp_expect: 0,
+            tcp_close: TcpClose::Fin,
+            icmp_reply: false,
+            expect_reply: false,
+            recv_timeout: None,
+            expect_pattern: None,
+            strict_udp: false,
+            dont_fragment: false,
+            broadcast: false,
+            multicast_if: None,
+            multicast_ttl: None,
+            fwmark: None,
+            ttl_walk: None,
+            min_ttl: DEFAULT_MIN_TTL,
+            netns: None,
+            strict: false,
+            source_port_policy: SourcePortPolicy::Os,
+            reply_port: None,
+            sni: None,
+            spa: None,
+            fwknop: None,
+            public_ip: None,
+            proxy_socks5: None,
+            jump: None,
+            pad_to: None,
+            sign_key: None,
+            encrypt_key: None,
+            pcap: None,
+            save_replies: None,
+            hexdump: None,
+            warmup: false,
+            start_at: None,
+            start_on_key: false,
+            dry_run: false,
+            confirm: false,
+            assume_yes: false,
+            hooks: Hooks::default(),
+            observer: None,
+            transports: HashMap::new(),
+            step_transports: HashMap::new(),
+        }
+    }
+}
+
+impl KnockConfig {
+    /// Start from the command-line defaults.
+    pub fn builder() -> KnockConfigBuilder {
+        KnockConfigBuilder::default()
+    }
+
+    /// Knocks run at once: one, so the sequence arrives in order, unless
+    /// the run is `unordered`.
+    pub fn knocks_in_flight(&self) -> usize {
+        if self.unordered {
+            self.concurrency
+        } else {
+            1
+        }
+    }
+
+    /// When each knock is due under strict timing, counted from the start
+    /// of the sequence: the initial delay for the first, then the delay
+    /// (or the step's own) after the previous slot, or the same slot for
+    /// the rest of a group.
+    pub fn schedule(&self) -> Vec<Duration> {
+        let mut at = Duration::ZERO;
+        self.sequence
+            .iter()
+            .enumerate()
+            .map(|(index, step)| {
+                at += step.pre_delay.unwrap_or(if index == 0 {
+                    Duration::from_millis(self.initial_delay)
+                } else if step.with_previous {
+                    Duration::ZERO
+                } else {
+                    self.delay
+                });
+                at
+            })
+            .collect()
+    }
+
+    /// The wait between retries the backoff settings describe.
+    pub fn backoff_policy(&self) -> BackoffPolicy {
+        let base = Duration::from_millis(self.backoff);
+        let max = Duration::from_millis(self.backoff_max);
+        match self.backoff_strategy {
+            BackoffStrategy::Constant => BackoffPolicy::Constant(base),
+            BackoffStrategy::Exponential => BackoffPolicy::Exponential {
+                base,
+                factor: 2,
+                max,
+            },
+            BackoffStrategy::Jitter => BackoffPolicy::ExponentialWithJitter {
+                base,
+                factor: 2,
+                max,
+            },
+        }
+    }
+
+    /// A fresh limiter for [`rate`](Self::rate), unless unlimited.
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate.filter(|&rate| rate > 0).map(RateLimiter::new)
+    }
+
+    /// The options every TCP and UDP knock of this run is sent with.
+    pub fn knock_opts(&self) -> KnockOpts {
+        KnockOpts {
+            timeout: self.timeout,
+            attempts: self.attempts,
+            backoff: self.backoff_policy(),
+            deadline: self.knock_deadline.map(Duration::from_millis),
+            rate: self.rate_limiter().map(Arc::new),
+            budget: self
+                .max_attempts_total
+                .map(|total| Arc::new(RetryBudget::new(total, self.budget_exhausted))),
+            hexdump: self.hexdump,
+            tcp: TcpOpts {
+                refused_is_failure: self.refused_is_failure,
+                payload: self.tcp_payload.clone(),
+                expect: self.tcp_expect,
+                close: self.tcp_close,
+                proxy: self.proxy_socks5.clone(),
+                jump: self.jump.clone(),
+                bind_port: None,
+                fwmark: self.fwmark,
+                ttl: None,
+            },
+            udp: UdpOpts {
+                expect_reply: self.expect_reply,
+                recv_timeout: self.recv_timeout.unwrap_or(self.timeout),
+                pattern: self.expect_pattern.clone(),
+                strict: self.strict_udp,
+                reply_only: self.lockstep,
+                source_port: self.source_port_policy.clone(),
+                bind_port: None,
+                reply_port: self.reply_port,
+                dont_fragment: self.dont_fragment,
+                broadcast: self.broadcast,
+                multicast_if: self.multicast_if,
+                multicast_ttl: self.multicast_ttl,
+                fwmark: self.fwmark,
+                ttl: None,
+            },
+        }
+    }
+
+    /// Check the settings that do not depend on the build or the network;
+    /// these are the rules the command-line parser enforces for `Cli`.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let invalid = |msg: String| Err(AppError::InvalidConfig(msg));
+        match &self.srv {
+            Some(_) if !self.host.is_empty() => {
+                return invalid("give either a host or an SRV name, not both".into());
+            }
+            Some(name) => crate::dns::validate_name(name).map_err(AppError::InvalidConfig)?,
+            None => {
+                crate::scope::parse_host(&self.host).map_err(AppError::InvalidConfig)?;
+            }
+        }
+        if self.sequence.is_empty()
+            && self.totp.is_none()
+            && self.passphrase_ports.is_none()
+            && self.sequence_from_txt.is_none()
+            && self.script.is_none()
+            && self.length_sequence.is_none()
+            && self.timing_code.is_none()
+        {
+            return invalid("no knock sequence given".into());
+        }
+        if self.concurrency == 0 {
+            return invalid("concurrency must be at least 1".into());
+        }
+        if self.attempts == Attempts::Finite(0) {
+            return invalid("a knock needs at least 1 attempt".into());
+        }
+        if self.backoff_strategy != BackoffStrategy::Constant && self.backoff_max < self.backoff {
+            return invalid("the backoff cap must be at least the backoff".into());
+        }
+        if let Some(totp) = &self.totp {
+            if !self.sequence.is_empty() {
+                return invalid("give either a sequence or TOTP derivation, not both".into());
+            }
+            if totp.knocks == 0 || totp.step == 0 || totp.port_range == 0 {
+                return invalid("TOTP knocks, step and port range must be positive".into());
+            }
+        }
+        if let Some(derived) = &self.passphrase_ports {
+            if !self.sequence.is_empty() || self.totp.is_some() {
+                return invalid(
+                    "give either a sequence, TOTP or passphrase derivation, not several".into(),
+                );
+            }
+            if derived.knocks == 0 || derived.port_range == 0 {
+                return invalid("derived knocks and port range must be positive".into());
+            }
+        }
+        if let Some(txt) = &self.sequence_from_txt {
+            if !self.sequence.is_empty() || self.totp.is_some() || self.passphrase_ports.is_some() {
+                return invalid(
+                    "give either a sequence or a TXT record to fetch it from, not both".into(),
+                );
+            }
+            crate::dns::validate_name(&txt.name).map_err(AppError::InvalidConfig)?;
+        }
+        if let Some(script) = &self.script {
+            if !self.sequence.is_empty()
+                || self.totp.is_some()
+                || self.passphrase_ports.is_some()
+                || self.sequence_from_txt.is_some()
+            {
+                return invalid(
+                    "give either a sequence or a script to work it out, not both".into(),
+                );
+            }
+            for (i, (name, _)) in script.secrets.iter().enumerate() {
+                if script.secrets[..i].iter().any(|(other, _)| other == name) {
+                    return invalid(format!("script secret {name} is given twice"));
+                }
+            }
+        }
+        if let Some(lengths) = &self.length_sequence {
+            if !self.sequence.is_empty()
+                || self.totp.is_some()
+                || self.passphrase_ports.is_some()
+                || self.sequence_from_txt.is_some()
+                || self.script.is_some()
+            {
+                return invalid(
+                    "give either a sequence of ports or one of lengths, not both".into(),
+                );
+            }
+            if lengths.port == 0 || lengths.lengths.is_empty() {
+                return invalid("a length sequence needs a port and at least one length".into());
+            }
+            if let Some(&len) = lengths
+                .lengths
+                .iter()
+                .find(|&&len| len == 0 || len > crate::udp::MAX_PAYLOAD_LEN)
+            {
+                return invalid(format!(
+                    "knock length {len} is not 1-{} bytes",
+                    crate::udp::MAX_PAYLOAD_LEN
+                ));
+            }
+            if self.spa.is_some()
+                || self.fwknop.is_some()
+                || self.sign_key.is_some()
+                || self.encrypt_key.is_some()
+                || self.pad_to.is_some()
+            {
+                return invalid(
+                    "SPA, fwknop, signing, encryption and padding change the datagram lengths a \
+                     length sequence is told by"
+                        .into(),
+                );
+            }
+        }
+        if let Some(code) = &self.timing_code {
+            if !self.sequence.is_empty()
+                || self.totp.is_some()
+                || self.passphrase_ports.is_some()
+                || self.sequence_from_txt.is_some()
+                || self.script.is_some()
+                || self.length_sequence.is_some()
+            {
+                return invalid("give either a sequence or a timing code, not both".into());
+            }
+            if code.bits.is_empty() || code.bits.len() > TimingCode::MAX_BITS {
+                return invalid(format!("a timing code has 1-{} bits", TimingCode::MAX_BITS));
+            }
+            if code.port == 0 || code.unit < Duration::from_millis(1) {
+                return invalid("a timing code needs a port and a unit of at least 1ms".into());
+            }
+            if !matches!(code.protocol, Protocol::Tcp | Protocol::Udp) {
+                return invalid(format!(
+                    "a timing code is sent over tcp or udp, not {}",
+                    code.protocol
+                ));
+            }
+            if self.unordered || self.decoys > 0 {
+                return invalid(
+                    "overlapping or decoy knocks would blur the gaps of a timing code".into(),
+                );
+            }
+        }
+        if let Some(ladder) = &self.ladder {
+            if !ladder.rungs.is_empty() && self.sequence.is_empty() {
+                return invalid("ladder rungs stand in for a sequence given, not derived".into());
+            }
+            if ladder.rungs.iter().any(|rung| rung.is_empty()) {
+                return invalid("a ladder rung has no knocks".into());
+            }
+            if ladder.verify.port == 0 || ladder.verify.timeout == 0 {
+                return invalid("the verifying connect needs a port and a timeout".into());
+            }
+            if ladder.max_time.is_zero() {
+                return invalid("the ladder needs time to knock".into());
+            }
+            let verify = &ladder.verify;
+            if let Some(banner) = &verify.banner {
+                if banner.is_empty() || verify.banner_bytes < banner.len() {
+                    return invalid(format!(
+                        "banner \"{banner}\" must be non-empty and fit in banner_bytes ({})",
+                        verify.banner_bytes
+                    ));
+                }
+            }
+        }
+        if let Some(ports) = &self.derive_source_ports {
+            if self.totp.is_none() && self.passphrase_ports.is_none() {
+                return invalid("derived source ports need a TOTP or passphrase derivation".into());
+            }
+            if ports.is_empty() || *ports.start() == 0 {
+                return invalid("source ports must be a non-empty range of 1-65535".into());
+            }
+        }
+        if self.check_clock.as_ref().is_some_and(|c| c.max_skew == 0) {
+            return invalid("the clock skew allowed must be at least 1s".into());
+        }
+        if self.max_attempts_total == Some(0) {
+            return invalid("the attempt budget must allow at least 1 attempt".into());
+        }
+        if let Some(tolerance) = self.failure_tolerance {
+            if self.fail_fast || self.lockstep {
+                return invalid(
+                    "failures cannot be tolerated when the first one ends the run \
+                     (--fail-fast, --lockstep)"
+                        .into(),
+                );
+            }
+            if let FailureTolerance::Percent(percent) = tolerance {
+                if !(0.0..=100.0).contains(&percent) {
+                    return invalid(format!("{percent}% of failures is not 0-100%"));
+                }
+            }
+        }
+        let hooks = [&self.hooks.pre, &self.hooks.post, &self.hooks.failure];
+        if hooks
+            .iter()
+            .any(|h| h.as_ref().is_some_and(|h| h.trim().is_empty()))
+        {
+            return invalid("a hook command must not be empty".into());
+        }
+        if self.hooks.timeout.is_zero() {
+            return invalid("hook timeout must be at least 1s".into());
+        }
+        if self.break_after == Some(0) {
+            return invalid("the circuit breaker needs at least 1 failed step".into());
+        }
+        if self.calibration_port.is_some() && !self.auto_timeout {
+            return invalid("a calibration port needs auto_timeout".into());
+        }
+        if self.calibration_port == Some(0) {
+            return invalid("calibration port must be 1-65535".into());
+        }
+        if let Some(port) = self.skip_if_open {
+            if port == 0 {
+                return invalid("the port checked for being open must be 1-65535".into());
+            }
+            if self.open_probe_timeout == 0 {
+                return invalid("the open-port check needs a timeout".into());
+            }
+            if self.proxy_socks5.is_some() || self.jump.is_some() {
+                return invalid(
+                    "the open-port check connects to the host directly, which a SOCKS5 proxy \
+                     or jump host run does not"
+                        .into(),
+                );
+            }
+        }
+        if (self.recv_timeout.is_some()
+            || self.expect_pattern.is_some()
+            || self.reply_port.is_some()
+            || self.save_replies.is_some())
+            && !self.expect_reply
+            && !self.lockstep
+        {
+            return invalid(
+                "a reply timeout, pattern, port or directory needs expect_reply".into(),
+            );
+        }
+        if self.lockstep && (self.unordered || self.broadcast || self.spoof_source.is_some()) {
+            return invalid(
+                "lockstep knocks wait for each reply in turn; they cannot be unordered, \
+                 broadcast or from a spoofed source"
+                    .into(),
+            );
+        }
+        if self.sequence.has_groups() && (self.unordered || self.lockstep) {
+            return invalid(
+                "a sequence with groups waits for each group in turn; it cannot be unordered \
+                 or lockstep"
+                    .into(),
+            );
+        }
+        if self.decoys == 0 && (!self.decoy_exclude.is_empty() || self.decoys_after) {
+            return invalid("decoy exclusions or placement need decoys".into());
+        }
+        if self.decoys > 0 && (self.lockstep || self.protocol == Protocol::Icmp) {
+            return invalid(
+                "decoy knocks get no reply and have no port over ICMP; they cannot be \
+                 lockstep or ICMP"
+                    .into(),
+            );
+        }
+        if (self.start_at.is_some() || self.start_on_key) && !self.warmup {
+            return invalid("a start time or key press needs warmup".into());
+        }
+        if self.start_at.is_some() && self.start_on_key {
+            return invalid("start at a time or on a key press, not both".into());
+        }
+        if self.window == Some(0) {
+            return invalid("the window must be at least 1ms".into());
+        }
+        if self.window_cooldown.is_some() && self.window.is_none() {
+            return invalid("a window cooldown needs a window".into());
+        }
+        if self.reply_port == Some(0) {
+            return invalid("reply port must be 1-65535".into());
+        }
+        if self.strict_timing && self.delay.is_zero() {
+            return invalid("strict timing needs a delay between knocks".into());
+        }
+        if let Some(ports) = self.source_port_policy.ports() {
+            if ports.is_empty() || *ports.start() == 0 {
+                return invalid("source ports must be a non-empty range of 1-65535".into());
+            }
+        }
+        if let Some(size) = self.pad_to {
+            if size == 0 || usize::from(size) > crate::udp::MAX_PADDED_LEN {
+                return invalid(format!("pad size must be 1-{}", crate::udp::MAX_PADDED_LEN));
+            }
+        }
+        if self.proxy_socks5.is_some()
+            && (self.all_ips || self.tcp_flags.is_some() || self.spoof_source.is_some())
+        {
+            return invalid(
+                "a SOCKS5 proxy cannot be combined with all_ips, raw TCP or a spoofed source"
+                    .into(),
+            );
+        }
+        if self.reresolve_on_failure && (self.all_ips || self.proxy_socks5.is_some()) {
+            return invalid(
+                "re-resolving on failure needs a single address, without all_ips or a proxy".into(),
+            );
+        }
+        if self.srv.is_some() && self.secure_dns.is_some() {
+            return invalid(
+                "SRV records are looked up over plain DNS; --srv cannot be combined with \
+                 DNS-over-HTTPS or DNS-over-TLS"
+                    .into(),
+            );
+        }
+        if self.dns_server.is_some() && self.secure_dns.is_some() {
+            return invalid("resolve through a DNS server or a secure one, not both".into());
+        }
+        if self.proxy_socks5.is_some()
+            && (self.resolve != ResolveStrategy::All
+                || self.dns_server.is_some()
+                || self.secure_dns.is_some()
+                || !self.resolve_pins.is_empty())
+        {
+            return invalid(
+                "a SOCKS5 proxy resolves the host itself; drop the resolve strategy, pins and \
+                 DNS server"
+                    .into(),
+            );
+        }
+        if self.jump.is_some() {
+            if self.proxy_socks5.is_some() {
+                return invalid("a jump host and a SOCKS5 proxy cannot be combined".into());
+            }
+            if self.all_ips
+                || self.tcp_flags.is_some()
+                || self.spoof_source.is_some()
+                || self.reresolve_on_failure
+            {
+                return invalid(
+                    "an SSH jump host cannot be combined with all_ips, raw TCP, a spoofed source \
+                     or re-resolving on failure"
+                        .into(),
+                );
+            }
+            if self.resolve != ResolveStrategy::All
+                || self.dns_server.is_some()
+                || self.secure_dns.is_some()
+                || !self.resolve_pins.is_empty()
+            {
+                return invalid(
+                    "an SSH jump host resolves the host itself; drop the resolve strategy, pins \
+                     and DNS server"
+                        .into(),
+                );
+            }
+            if self.tcp_close == TcpClose::Rst {
+                return invalid(
+                    "an SSH jump host closes forwarded connections itself; RST close is not \
+                     available"
+                        .into(),
+                );
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(name) = &self.netns {
+            crate::netns::check_name(name).map_err(AppError::InvalidConfig)?;
+        }
+        if self.ttl_walk.is_some() && (self.proxy_socks5.is_some() || self.jump.is_some()) {
+            return invalid(
+                "a TTL walk is set on the knock sockets themselves; it cannot be combined with \
+                 a SOCKS5 proxy or jump host"
+                    .into(),
+            );
+        }
+        // Derived sequences are only checked once worked out
+        if let Some(walk) = self.ttl_walk {
+            self.sequence
+                .clone()
+                .with_ttl_walk(walk)
+                .map_err(AppError::InvalidConfig)?;
+        }
+        if self.min_ttl == 0 {
+            return invalid("the minimum TTL must be at least 1".into());
+        }
+        if self.fwmark.is_some() && (self.proxy_socks5.is_some() || self.jump.is_some()) {
+            return invalid(
+                "a firewall mark is set on the knock sockets themselves; it cannot be combined \
+                 with a SOCKS5 proxy or jump host"
+                    .into(),
+            );
+        }
+        if self.public_ip.is_some() && (self.proxy_socks5.is_some() || self.jump.is_some()) {
+            return invalid(
+                "STUN finds the address this host knocks from, not that of a SOCKS5 proxy or \
+                 jump host"
+                    .into(),
+            );
+        }
+        if self.broadcast && self.spoof_source.is_some() {
+            return invalid("broadcast knocks cannot be sent from a spoofed source".into());
+        }
+        if self.no_dns {
+            self.check_no_dns()?;
+        }
+        if let Some(spa) = &self.spa {
+            crate::spa::validate_client_id(&spa.client_id).map_err(AppError::InvalidConfig)?;
+        }
+        if let Some(fwknop) = &self.fwknop {
+            crate::spa::validate_fwknop_access(&fwknop.access).map_err(AppError::InvalidConfig)?;
+        }
+        let spa_modes = usize::from(self.spa.is_some()) + usize::from(self.fwknop.is_some());
+        if spa_modes > 1 {
+            return invalid("SPA and fwknop cannot both be used".into());
+        }
+        if spa_modes > 0 && (self.payload.is_some() || self.payload_dns.is_some()) {
+            return invalid("an SPA packet replaces the payload".into());
+        }
+        if spa_modes > 0 && self.sign_key.is_some() {
+            return invalid("SPA packets are signed already".into());
+        }
+        Ok(())
+    }
+
+    /// That nothing of the run needs a DNS query, for `no_dns`.
+    fn check_no_dns(&self) -> Result<(), AppError> {
+        use crate::dns::require_literal;
+
+        if self.srv.is_some()
+            || self.sequence_from_txt.is_some()
+            || self.dns_server.is_some()
+            || self.secure_dns.is_some()
+        {
+            return Err(AppError::InvalidConfig(
+                "no DNS queries are sent; SRV and TXT records and DNS servers cannot be used"
+                    .into(),
+            ));
+        }
+        // A proxy or jump host is handed the name itself, pins or not
+        match (&self.proxy_socks5, &self.jump) {
+            (None, None) => crate::dns::without_dns(&self.host, &self.resolve_pins).map(drop)?,
+            _ => require_literal("host", &self.host)?,
+        }
+        if let Some(proxy) = &self.proxy_socks5 {
+            let host = proxy.addr.rsplit_once(':').map_or("", |(host, _)| host);
+            require_literal("SOCKS5 proxy", host)?;
+        }
+        if let Some(jump) = &self.jump {
+            require_literal("jump host", &jump.host)?;
+        }
+        if let Some(public_ip) = &self.public_ip {
+            require_literal("STUN server", &public_ip.server.host)?;
+        }
+        if let Some(check) = &self.check_clock {
+            require_literal("SNTP server", &check.server.host)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builder for [`KnockConfig`]; unset fields keep the command-line
+/// defaults and [`build`](Self::build) runs [`KnockConfig::validate`].
+#[derive(Clone, Default)]
+pub struct KnockConfigBuilder {
+    config: KnockConfig,
+}
+
+impl KnockConfigBuilder {
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.config.host = host.into();
+        self
+    }
+
+    /// Plain knocks on these ports with the run-wide protocol.
+    pub fn sequence(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.config.sequence = ports.into_iter().map(KnockStep::new).collect();
+        self
+    }
+
+    /// Knock steps, including per-step HTTP or TLS knocks and overrides.
+    pub fn steps(mut self, steps: impl IntoIterator<Item = KnockStep>) -> Self {
+        self.config.sequence = steps.into_iter().collect();
+        self
+    }
+
+    pub fn plan(mut self, plan: KnockPlan) -> Self {
+        self.config.sequence = plan;
+        self
+    }
+
+    pub fn totp(mut self, totp: TotpConfig) -> Self {
+        self.config.totp = Some(totp);
+        self
+    }
+
+    pub fn passphrase_ports(mut self, derived: PassphrasePorts) -> Self {
+        self.config.passphrase_ports = Some(derived);
+        self
+    }
+
+    /// Fetch the sequence from a DNS TXT record when the run starts.
+    pub fn sequence_from_txt(mut self, txt: TxtSequence) -> Self {
+        self.config.sequence_from_txt = Some(txt);
+        self
+    }
+
+    /// Work the sequence out with a script when the run starts.
+    pub fn script(mut self, script: ScriptPlan) -> Self {
+        self.config.script = Some(script);
+        self
+    }
+
+    /// Tell `code` by the gaps between identical knocks to its port.
+    pub fn timing_code(mut self, code: TimingCode) -> Self {
+        self.config.timing_code = Some(code);
+        self
+    }
+
+    /// Knock `port` with UDP datagrams of these payload lengths in turn,
+    /// filled with random bytes, instead of a sequence of ports.
+    pub fn length_sequence(mut self, port: u16, lengths: impl Into<Vec<usize>>) -> Self {
+        self.config.length_sequence = Some(LengthSequence {
+            port,
+            lengths: lengths.into(),
+        });
+        self
+    }
+
+    /// Verify the knocks, falling back on the ladder's rungs.
+    pub fn ladder(mut self, ladder: Ladder) -> Self {
+        self.config.ladder = Some(ladder);
+        self
+    }
+
+    /// Derive the local port of every knock from `ports` too, with the
+    /// TOTP secret or passphrase the sequence is derived with.
+    pub fn derive_source_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.config.derive_source_ports = Some(ports);
+        self
+    }
+
+    /// Check the local clock against an SNTP server before knocking.
+    pub fn check_clock(mut self, check: ClockCheck) -> Self {
+        self.config.check_clock = Some(check);
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = protocol;
+        self
+    }
+
+    pub fn all_ips(mut self, all_ips: bool) -> Self {
+        self.config.all_ips = all_ips;
+        self
+    }
+
+    pub fn resolve(mut self, strategy: ResolveStrategy) -> Self {
+        self.config.resolve = strategy;
+        self
+    }
+
+    /// Send the knocks for `pin.host` to `pin.addr` without resolving it;
+    /// pins add up, so a host may be pinned to several addresses.
+    pub fn resolve_pin(mut self, pin: ResolvePin) -> Self {
+        self.config.resolve_pins.push(pin);
+        self
+    }
+
+    pub fn dns_server(mut self, server: SocketAddr) -> Self {
+        self.config.dns_server = Some(server);
+        self
+    }
+
+    /// Resolve the host over DNS-over-HTTPS or DNS-over-TLS.
+    pub fn secure_dns(mut self, server: SecureDns) -> Self {
+        self.config.secure_dns = Some(server);
+        self
+    }
+
+    /// Knock the targets of this SRV name's records instead of a host,
+    /// in turn until one run succeeds.
+    pub fn srv(mut self, name: impl Into<String>) -> Self {
+        self.config.srv = Some(name.into());
+        self
+    }
+
+    pub fn resolution(mut self, policy: ResolutionPolicy) -> Self {
+        self.config.resolution = policy;
+        self
+    }
+
+    /// Follow the host to a new address when a knock cannot reach it.
+    pub fn reresolve_on_failure(mut self, reresolve: bool) -> Self {
+        self.config.reresolve_on_failure = reresolve;
+        self
+    }
+
+    /// Refuse every DNS query; see [`KnockConfig::no_dns`].
+    pub fn no_dns(mut self, no_dns: bool) -> Self {
+        self.config.no_dns = no_dns;
+        self
+    }
+
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.config.fail_fast = fail_fast;
+        self
+    }
+
+    /// Let the run succeed with up to `max` failed knocks.
+    pub fn max_failures(mut self, max: usize) -> Self {
+        self.config.failure_tolerance = Some(FailureTolerance::Count(max));
+        self
+    }
+
+    /// Let the run succeed with up to `percent` (0-100) of its knocks
+    /// failed.
+    pub fn max_failure_percent(mut self, percent: f64) -> Self {
+        self.config.failure_tolerance = Some(FailureTolerance::Percent(percent));
+        self
+    }
+
+    /// Skip the rest of the sequence once `break_after` steps in a row
+    /// could not reach the host; `None` turns the breaker off.
+    pub fn circuit_breaker(mut self, break_after: Option<usize>) -> Self {
+        self.config.break_after = break_after;
+        self
+    }
+
+    /// Wait for each knock to be answered before the next one.
+    pub fn lockstep(mut self, lockstep: bool) -> Self {
+        self.config.lockstep = lockstep;
+        self
+    }
+
+    pub fn tcp_flags(mut self, flags: TcpFlags) -> Self {
+        self.config.tcp_flags = Some(flags);
+        self
+    }
+
+    pub fn spoof_source(mut self, source: IpAddr) -> Self {
+        self.config.spoof_source = Some(source);
+        self
+    }
+
+    pub fn refused_is_failure(mut self, refused_is_failure: bool) -> Self {
+        self.config.refused_is_failure = refused_is_failure;
+        self
+    }
+
+    /// Per-attempt timeout in milliseconds.
+    pub fn timeout(mut self, ms: u64) -> Self {
+        self.config.timeout = ms;
+        self
+    }
+
+    /// Calibrate the timeout from the latency to the host, measured with
+    /// connects to `calibration_port` or else from the first knock.
+    pub fn auto_timeout(mut self, calibration_port: Option<u16>) -> Self {
+        self.config.auto_timeout = true;
+        self.config.calibration_port = calibration_port;
+        self
+    }
+
+    /// Connect to `port` before the knocks and knock nothing when it is
+    /// already open.
+    pub fn skip_if_open(mut self, port: u16) -> Self {
+        self.config.skip_if_open = Some(port);
+        self
+    }
+
+    /// How long the connect of [`skip_if_open`](Self::skip_if_open) may
+    /// take, in milliseconds.
+    pub fn open_probe_timeout(mut self, ms: u64) -> Self {
+        self.config.open_probe_timeout = ms;
+        self
+    }
+
+    /// Base inter-knock delay in milliseconds.
+    pub fn delay(mut self, ms: u64) -> Self {
+        self.config.delay = Duration::from_millis(ms);
+        self
+    }
+
+    /// Base inter-knock delay, down to the microsecond.
+    pub fn delay_precise(mut self, delay: Duration) -> Self {
+        self.config.delay = delay;
+        self
+    }
+
+    /// Pause before the first knock in milliseconds.
+    pub fn initial_delay(mut self, ms: u64) -> Self {
+        self.config.initial_delay = ms;
+        self
+    }
+
+    /// Fire every knock at its slot of [`KnockConfig::schedule`].
+    pub fn strict_timing(mut self, strict: bool) -> Self {
+        self.config.strict_timing = strict;
+        self
+    }
+
+    /// Knocks in flight at once with [`unordered`](Self::unordered).
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.config.concurrency = concurrency;
+        self
+    }
+
+    /// Run knocks concurrently, giving up the order in which they arrive.
+    pub fn unordered(mut self, unordered: bool) -> Self {
+        self.config.unordered = unordered;
+        self
+    }
+
+    /// Mix `count` decoy knocks into the sequence.
+    pub fn decoys(mut self, count: usize) -> Self {
+        self.config.decoys = count;
+        self
+    }
+
+    pub fn decoy_exclude(mut self, ports: impl IntoIterator<Item = RangeInclusive<u16>>) -> Self {
+        self.config.decoy_exclude = ports.into_iter().collect();
+        self
+    }
+
+    pub fn decoys_after(mut self, after: bool) -> Self {
+        self.config.decoys_after = after;
+        self
+    }
+
+    /// Attempts per knock, the first included.
+    pub fn attempts(mut self, attempts: usize) -> Self {
+        self.config.attempts = Attempts::Finite(attempts);
+        self
+    }
+
+    /// Keep retrying each knock until it gets through, the knock deadline
+    /// passes or the run is cancelled.
+    pub fn retry_forever(mut self) -> Self {
+        self.config.attempts = Attempts::Unlimited;
+        self
+    }
+
+    /// Backoff between retries in milliseconds.
+    pub fn backoff(mut self, ms: u64) -> Self {
+        self.config.backoff = ms;
+        self
+    }
+
+    pub fn backoff_strategy(mut self, strategy: BackoffStrategy) -> Self {
+        self.config.backoff_strategy = strategy;
+        self
+    }
+
+    /// Cap on an exponential backoff in milliseconds.
+    pub fn backoff_max(mut self, ms: u64) -> Self {
+        self.config.backoff_max = ms;
+        self
+    }
+
+    /// Overall time limit of one knock, retries included, in milliseconds.
+    pub fn knock_deadline(mut self, ms: u64) -> Self {
+        self.config.knock_deadline = Some(ms);
+        self
+    }
+
+    /// Start the sequence over when a pass runs past `ms` after its first
+    /// packet.
+    pub fn window(mut self, ms: u64) -> Self {
+        self.config.window = Some(ms);
+        self
+    }
+
+    pub fn window_cooldown(mut self, ms: u64) -> Self {
+        self.config.window_cooldown = Some(ms);
+        self
+    }
+
+    pub fn window_restarts(mut self, restarts: usize) -> Self {
+        self.config.window_restarts = restarts;
+        self
+    }
+
+    /// Hold the host's lock while knocking, waiting up to `timeout_ms`
+    /// for it.
+    pub fn lock(mut self, timeout_ms: u64) -> Self {
+        self.config.lock = true;
+        self.config.lock_timeout = timeout_ms;
+        self
+    }
+
+    /// Keep the lock files in `dir` instead of the runtime directory; only
+    /// runs using the same directory exclude each other.
+    pub fn lock_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.lock_dir = Some(dir.into());
+        self
+    }
+
+    /// Cap sends and connects at `per_second` for the whole run; 0 is
+    /// unlimited.
+    pub fn rate(mut self, per_second: u32) -> Self {
+        self.config.rate = Some(per_second);
+        self
+    }
+
+    /// Cap the attempts of the whole run at `total`, after which knocks
+    /// are sent as `exhausted` says.
+    pub fn max_attempts_total(mut self, total: usize, exhausted: BudgetExhausted) -> Self {
+        self.config.max_attempts_total = Some(total);
+        self.config.budget_exhausted = exhausted;
+        self
+    }
+
+    pub fn payload(mut self, payload: impl Into<Bytes>) -> Self {
+        self.config.payload = Some(payload.into());
+        self
+    }
+
+    pub fn payload_dns(mut self, name: impl Into<String>) -> Self {
+        self.config.payload_dns = Some(name.into());
+        self
+    }
+
+    pub fn tcp_payload(mut self, payload: impl Into<Bytes>) -> Self {
+        self.config.tcp_payload = Some(payload.into());
+        self
+    }
+
+    pub fn tcp_expect(mut self, bytes: usize) -> Self {
+        self.config.tcp_expect = bytes;
+        self
+    }
+
+    pub fn tcp_close(mut self, close: TcpClose) -> Self {
+        self.config.tcp_close = close;
+        self
+    }
+
+    pub fn icmp_reply(mut self, icmp_reply: bool) -> Self {
+        self.config.icmp_reply = icmp_reply;
+        self
+    }
+
+    pub fn expect_reply(mut self, expect_reply: bool) -> Self {
+        self.config.expect_reply = expect_reply;
+        self
+    }
+
+    /// UDP reply wait in milliseconds.
+    pub fn recv_timeout(mut self, ms: u64) -> Self {
+        self.config.recv_timeout = Some(ms);
+        self
+    }
+
+    pub fn expect_pattern(mut self, pattern: ReplyPattern) -> Self {
+        self.config.expect_pattern = Some(pattern);
+        self
+    }
+
+    /// Accept UDP replies from `port` on the target rather than from the
+    /// knocked port.
+    pub fn reply_port(mut self, port: u16) -> Self {
+        self.config.reply_port = Some(port);
+        self
+    }
+
+    pub fn strict_udp(mut self, strict: bool) -> Self {
+        self.config.strict_udp = strict;
+        self
+    }
+
+    pub fn dont_fragment(mut self, dont_fragment: bool) -> Self {
+        self.config.dont_fragment = dont_fragment;
+        self
+    }
+
+    /// Allow UDP knocks to broadcast addresses.
+    pub fn broadcast(mut self, broadcast: bool) -> Self {
+        self.config.broadcast = broadcast;
+        self
+    }
+
+    /// Send UDP knocks to a multicast group from this interface.
+    pub fn multicast_if(mut self, interface: MulticastInterface) -> Self {
+        self.config.multicast_if = Some(interface);
+        self
+    }
+
+    /// Hop limit of UDP knocks to a multicast group.
+    pub fn multicast_ttl(mut self, ttl: u8) -> Self {
+        self.config.multicast_ttl = Some(ttl);
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict = strict;
+        self
+    }
+
+    /// How UDP knocks pick their local port; the kernel does by default.
+    pub fn source_port_policy(mut self, policy: SourcePortPolicy) -> Self {
+        self.config.source_port_policy = policy;
+        self
+    }
+
+    /// Send UDP knocks from a random port of `ports`.
+    pub fn source_ports(self, ports: RangeInclusive<u16>) -> Self {
+        self.source_port_policy(SourcePortPolicy::Random(ports))
+    }
+
+    pub fn sni(mut self, sni: impl Into<String>) -> Self {
+        self.config.sni = Some(sni.into());
+        self
+    }
+
+    pub fn spa(mut self, spa: SpaSettings) -> Self {
+        self.config.spa = Some(spa);
+        self
+    }
+
+    pub fn fwknop(mut self, fwknop: FwknopSettings) -> Self {
+        self.config.fwknop = Some(fwknop);
+        self
+    }
+
+    /// Learn the public address from a STUN server before knocking.
+    pub fn public_ip(mut self, public_ip: PublicIp) -> Self {
+        self.config.public_ip = Some(public_ip);
+        self
+    }
+
+    pub fn proxy_socks5(mut self, proxy: Socks5Proxy) -> Self {
+        self.config.proxy_socks5 = Some(proxy);
+        self
+    }
+
+    /// Mark the TCP and UDP knock sockets with SO_MARK, for policy
+    /// routing (Linux only).
+    pub fn fwmark(mut self, mark: u32) -> Self {
+        self.config.fwmark = Some(mark);
+        self
+    }
+
+    /// Send the knocks with TTLs starting at `walk.base` and changing by
+    /// `walk.step` from one to the next.
+    pub fn ttl_walk(mut self, walk: TtlWalk) -> Self {
+        self.config.ttl_walk = Some(walk);
+        self
+    }
+
+    /// Warn about knocks sent with a TTL below `ttl`.
+    pub fn min_ttl(mut self, ttl: u8) -> Self {
+        self.config.min_ttl = ttl;
+        self
+    }
+
+    /// Send the knocks from inside the network namespace `ip netns` knows
+    /// by this name (Linux only).
+    pub fn netns(mut self, name: impl Into<String>) -> Self {
+        self.config.netns = Some(name.into());
+        self
+    }
+
+    /// Forward TCP knocks through an SSH bastion instead of connecting
+    /// directly.
+    pub fn jump(mut self, jump: JumpHost) -> Self {
+        self.config.jump = Some(jump);
+        self
+    }
+
+    pub fn pad_to(mut self, size: u16) -> Self {
+        self.config.pad_to = Some(size);
+        self
+    }
+
+    pub fn sign_key(mut self, source: SecretSource) -> Self {
+        self.config.sign_key = Some(source);
+        self
+    }
+
+    pub fn encrypt_key(mut self, source: SecretSource) -> Self {
+        self.config.encrypt_key = Some(source);
+        self
+    }
+
+    pub fn pcap(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.pcap = Some(path.into());
+        self
+    }
+
+    /// Write UDP replies to files in `dir`, created when missing.
+    pub fn save_replies(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.save_replies = Some(dir.into());
+        self
+    }
+
+    /// Dump the bytes sent and received, the first `limit` of each.
+    pub fn hexdump(mut self, limit: usize) -> Self {
+        self.config.hexdump = Some(limit);
+        self
+    }
+
+    /// Prepare every knock before the first is sent.
+    pub fn warmup(mut self, warmup: bool) -> Self {
+        self.config.warmup = warmup;
+        self
+    }
+
+    /// Start a warmed-up run at `at`.
+    pub fn start_at(mut self, at: SystemTime) -> Self {
+        self.config.start_at = Some(at);
+        self
+    }
+
+    /// Start a warmed-up run once Enter is pressed.
+    pub fn start_on_key(mut self, start_on_key: bool) -> Self {
+        self.config.start_on_key = start_on_key;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// Ask on stdin before sending, or only print the plan with `assume_yes`.
+    pub fn confirm(mut self, confirm: bool, assume_yes: bool) -> Self {
+        self.config.confirm = confirm;
+        self.config.assume_yes = assume_yes;
+        self
+    }
+
+    /// Run the commands of `hooks` around the run.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.config.hooks = hooks;
+        self
+    }
+
+    /// Call `observer` for every attempt and outcome; [`StdoutObserver`]
+    /// prints the binary's log lines.
+    ///
+    /// [`StdoutObserver`]: crate::StdoutObserver
+    pub fn observer(mut self, observer: Arc<dyn KnockObserver + Send + Sync>) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// Send plain `protocol` steps through `transport`.
+    pub fn transport(
+        mut self,
+        protocol: Protocol,
+        transport: Arc<dyn KnockTransport + Send + Sync>,
+    ) -> Self {
+        self.config.transports.insert(protocol, transport);
+        self
+    }
+
+    /// Send the step on `port` through `transport`.
+    pub fn step_transport(
+        mut self,
+        port: u16,
+        transport: Arc<dyn KnockTransport + Send + Sync>,
+    ) -> Self {
+        self.config.step_transports.insert(port, transport);
+        self
+    }
+
+    pub fn build(mut self) -> Result<KnockConfig, AppError> {
+        // Brackets around an IPv6 host are only for the reader
+        if self.config.srv.is_none() {
+            self.config.host =
+                crate::scope::parse_host(&self.config.host).map_err(AppError::InvalidConfig)?;
+        }
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(feature = "cli")]
+impl From<Cli> for KnockConfig {
+    fn from(cli: Cli) -> Self {
+        let tcp_flags = cli.raw_tcp_flags();
+        let (resolve, resolve_pins) = (cli.resolve_strategy(), cli.resolve_pins());
+        let check_clock = cli.check_clock.then(|| ClockCheck {
+            server: cli.ntp_server.clone(),
+            max_skew: cli.max_clock_skew,
+            strict: cli.strict_clock,
+        });
+        let totp = cli
+            .totp_secret
+            .zip(cli.totp_knocks)
+            .map(|(secret, knocks)| TotpConfig {
+                secret,
+                knocks,
+                step: cli.totp_step,
+                port_base: cli.totp_port_base,
+                port_range: cli.totp_port_range,
+                algorithm: cli.totp_algorithm,
+            });
+        let ladder = cli.verify.map(|port| Ladder {
+            rungs: cli.ladder,
+            verify: Verify {
+                banner: cli.verify_banner.clone(),
+                banner_bytes: cli
+                    .verify_banner_bytes
+                    .unwrap_or(crate::verify::DEFAULT_BANNER_BYTES),
+                banner_timeout: cli
+                    .verify_banner_timeout
+                    .unwrap_or(crate::verify::DEFAULT_BANNER_TIMEOUT),
+                banner_contains: cli.verify_banner_contains,
+                banner_optional: cli.verify_banner_optional,
+                ..Verify::new(port)
+            },
+            cooldown: Duration::from_millis(cli.ladder_cooldown),
+            max_time: Duration::from_millis(cli.ladder_max_time),
+        });
+        let passphrase_ports = cli.ports_from_secret.map(|source| PassphrasePorts {
+            source,
+            knocks: cli.derived_knocks.unwrap_or(0),
+            port_base: cli.derived_port_base,
+            port_range: cli.derived_port_range,
+        });
+        let sequence_from_txt = cli.sequence_from_txt.map(|name| TxtSequence {
+            name,
+            key: cli.txt_key,
+        });
+        let script = cli.script.map(|path| ScriptPlan {
+            path,
+            secrets: cli.script_secret,
+        });
+        let spa = cli
+            .spa_client_id
+            .filter(|_| cli.spa)
+            .map(|client_id| SpaSettings {
+                key: cli.spa_key,
+                client_id,
+            });
+        let public_ip = cli.stun_server.map(|server| PublicIp {
+            server,
+            required: cli.require_public_ip,
+        });
+        let fwknop = cli
+            .fwknop_access
+            .filter(|_| cli.fwknop)
+            .map(|access| FwknopSettings {
+                access,
+                allow_ip: cli.fwknop_allow_ip,
+                user: cli.fwknop_user,
+                key: cli.fwknop_key,
+                hmac_key: cli.fwknop_hmac_key,
+            });
+        Self {
+            host: cli.host.unwrap_or_default(),
+            all_ips: cli.all_ips,
+            resolve,
+            resolve_pins,
+            dns_server: cli.dns_server,
+            secure_dns: cli.doh_url.or(cli.dot),
+            srv: cli.srv,
+            resolution: ResolutionPolicy::Once,
+            dns_cache: Arc::default(),
+            reresolve_on_failure: cli.reresolve_on_failure,
+            no_dns: cli.no_dns,
+            fail_fast: cli.fail_fast,
+            failure_tolerance: cli
+                .max_failures
+                .map(FailureTolerance::Count)
+                .or(cli.max_failure_percent.map(FailureTolerance::Percent)),
+            break_after: (!cli.no_circuit_breaker).then_some(cli.break_after),
+            lockstep: cli.lockstep,
+            protocol: cli.protocol,
+            tcp_flags,
+            spoof_source: cli.spoof_source,
+            refused_is_failure: cli.refused_is_failure,
+            sequence: KnockPlan(cli.sequence.into_iter().flat_map(|plan| plan.0).collect())
+                .expand_both(cli.protocol),
+            totp,
+            passphrase_ports,
+            sequence_from_txt,
+            script,
+            length_sequence: cli.length_port.map(|port| LengthSequence {
+                port,
+                lengths: cli.length_sequence,
+            }),
+            timing_code: cli.timing_code.map(|bits| TimingCode {
+                bits,
+                unit: cli.timing_unit,
+                port: cli.timing_port.unwrap_or(0),
+                protocol: cli.timing_protocol,
+            }),
+            derive_source_ports: cli.derive_source_ports,
+            check_clock,
+            ladder,
+            timeout: cli.timeout,
+            auto_timeout: cli.auto_timeout,
+            calibration_port: cli.calibration_port,
+            skip_if_open: cli.skip_if_open,
+            open_probe_timeout: cli.skip_if_open_timeout,
+            delay: cli.delay,
+            initial_delay: cli.initial_delay,
+            strict_timing: cli.strict_timing,
+            concurrency: cli.concurrency,
+            unordered: cli.unordered,
+            decoys: cli.decoys,
+            decoy_exclude: cli.decoy_exclude,
+            decoys_after: cli.decoys_after,
+            attempts: match (cli.retry_forever, cli.retries) {
+                (true, _) => Attempts::Unlimited,
+                (false, Some(retries)) => Attempts::Finite(retries.saturating_add(1)),
+                (false, None) => Attempts::Finite(cli.attempts),
+            },
+            backoff: cli.backoff,
+            backoff_strategy: cli.backoff_strategy,
+            backoff_max: cli.backoff_max,
+            knock_deadline: cli.knock_deadline,
+            window: cli.window,
+            window_cooldown: cli.window_cooldown,
+            window_restarts: cli.window_restarts,
+            lock: cli.lock,
+            lock_timeout: cli.lock_timeout,
+            lock_dir: None,
+            rate: cli.rate,
+            max_attempts_total: cli.max_attempts_total,
+            budget_exhausted: cli.budget_exhausted,
+            payload: cli.payload.or(cli.payload_text),
+            payload_dns: cli.payload_dns,
+            tcp_payload: cli.tcp_payload.or(cli.tcp_payload_text),
+            tcp_expect: cli.tcp_expect,
+            tcp_close: cli.tcp_close,
+            icmp_reply: cli.icmp_reply,
+            expect_reply: cli.expect_reply,
+            recv_timeout: cli.recv_timeout,
+            expect_pattern: cli.expect_pattern,
+            strict_udp: cli.strict_udp,
+            dont_fragment: cli.dont_fragment,
+            broadcast: cli.broadcast,
+            multicast_if: cli.multicast_if,
+            multicast_ttl: cli.multicast_ttl,
+            fwmark: cli.fwmark,
+            ttl_walk: cli.ttl_walk,
+            min_ttl: cli.min_ttl,
+            netns: cli.netns,
+            strict: cli.strict,
+            source_port_policy: match (cli.source_port_policy, cli.source_ports) {
+                (Some(policy), _) => policy,
+                (None, Some(ports)) => SourcePortPolicy::Random(ports),
+                (None, None) => SourcePortPolicy::Os,
+            },
+            reply_port: cli.reply_port,
+            sni: cli.sni,
+            spa,
+            fwknop,
+            public_ip,
+            proxy_socks5: cli.proxy_socks5,
+            jump: cli.jump,
+            pad_to: cli.pad_to,
+            sign_key: cli.sign_key,
+            encrypt_key: cli.encrypt_key,
+            pcap: cli.pcap,
+            save_replies: cli.save_replies,
+            hexdump: cli.hexdump.then_some(cli.hexdump_limit),
+            warmup: cli.warmup,
+            start_at: cli.start_at,
+            start_on_key: cli.start_on_key,
+            dry_run: cli.dry_run,
+            confirm: cli.confirm,
+            assume_yes: cli.yes,
+            hooks: Hooks {
+                pre: cli.pre_hook,
+                post: cli.post_hook,
+                failure: cli.failure_hook,
+                timeout: cli.hook_timeout,
+                strict: cli.strict_hooks,
+            },
+            observer: None,
+            transports: HashMap::new(),
+            step_transports: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "cli")]
+    use clap::Parser;
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn builder_defaults_match_the_cli() {
+        let built = KnockConfig::builder()
+            .host("example.com")
+            .sequence([7000, 8000])
+            .build()
+            .unwrap();
+        let parsed = KnockConfig::from(Cli::parse_from([
+            "knock",
+            "-H",
+            "example.com",
+            "-s",
+            "7000,8000",
+        ]));
+        assert_eq!(built.sequence, parsed.sequence);
+        assert_eq!(
+            (
+                built.timeout,
+                built.attempts,
+                built.backoff,
+                built.concurrency
+            ),
+            (
+                parsed.timeout,
+                parsed.attempts,
+                parsed.backoff,
+                parsed.concurrency
+            )
+        );
+        assert_eq!(built.protocol, parsed.protocol);
+        assert_eq!(built.min_ttl, parsed.min_ttl);
+        assert!(parsed.validate().is_ok());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn attempt_flags() {
+        let parse = |args: &[&str]| {
+            let base = ["knock", "-H", "example.com", "-s", "7000"];
+            KnockConfig::from(Cli::parse_from(base.iter().chain(args)))
+        };
+        assert_eq!(parse(&[]).attempts, Attempts::Finite(1));
+        assert_eq!(parse(&["--attempts", "3"]).attempts, Attempts::Finite(3));
+        // The old flag counts retries after the first attempt
+        assert_eq!(parse(&["--retries", "2"]).attempts, Attempts::Finite(3));
+        assert_eq!(parse(&["--retries", "0"]).attempts, Attempts::Finite(1));
+        assert_eq!(parse(&["--retry-forever"]).attempts, Attempts::Unlimited);
+        assert!(parse(&["--retry-forever"]).validate().is_ok());
+        assert!(matches!(
+            parse(&["--attempts", "0"]).validate(),
+            Err(AppError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn builder_rejects_incomplete_configs() {
+        let invalid = |builder: KnockConfigBuilder| {
+            matches!(builder.build(), Err(AppError::InvalidConfig(_)))
+        };
+        assert!(invalid(KnockConfig::builder().sequence([7000])));
+        assert!(invalid(KnockConfig::builder().host("h")));
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .sequence([7000])
+                .concurrency(0)
+        ));
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .sequence([7000])
+                .recv_timeout(100)
+        ));
+        assert!(invalid(
+            KnockConfig::builder().host("fe80::1").sequence([7000])
+        ));
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("knock.example:7000")
+                .sequence([7000])
+        ));
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .sequence([7000])
+                .derive_source_ports(40000..=40999)
+        ));
+        let bracketed = KnockConfig::builder().host("[::1]").sequence([7000]);
+        assert_eq!(bracketed.build().unwrap().host, "::1");
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .sequence([7000])
+                .backoff(500)
+                .backoff_strategy(BackoffStrategy::Jitter)
+                .backoff_max(100)
+        ));
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .sequence([7000])
+                .fwmark(2)
+                .proxy_socks5(Socks5Proxy::parse("127.0.0.1:1080").unwrap())
+        ));
+        // A TTL walk has to stay within 1-255 over the whole sequence
+        let walked = |walk| {
+            KnockConfig::builder()
+                .host("h")
+                .sequence([7000, 8000, 9000])
+                .ttl_walk(TtlWalk::parse(walk).unwrap())
+        };
+        assert!(walked("9,-4").build().is_ok());
+        assert!(invalid(walked("8,-4")));
+        assert!(invalid(
+            walked("64,-4").proxy_socks5(Socks5Proxy::parse("127.0.0.1:1080").unwrap())
+        ));
+        // A TXT record stands in for the sequence, not beside it
+        let txt = TxtSequence {
+            name: "_seq.example.com".into(),
+            key: None,
+        };
+        let fetched = KnockConfig::builder()
+            .host("h")
+            .sequence_from_txt(txt.clone());
+        assert!(fetched.build().is_ok());
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .sequence([7000])
+                .sequence_from_txt(txt)
+        ));
+        // As does a script, whose secrets have a name each
+        let secret = |name: &str| (name.to_string(), SecretSource::Env("KNOCK".into()));
+        let script = |secrets| ScriptPlan {
+            path: "knock.rhai".into(),
+            secrets,
+        };
+        let scripted = KnockConfig::builder().host("h");
+        assert!(scripted
+            .clone()
+            .script(script(vec![secret("a"), secret("b")]))
+            .build()
+            .is_ok());
+        assert!(invalid(
+            scripted
+                .clone()
+                .script(script(vec![secret("a"), secret("a")]))
+        ));
+        assert!(invalid(
+            scripted.sequence([7000]).script(script(Vec::new()))
+        ));
+        // So do the lengths of datagrams, which nothing may change
+        let lengths = KnockConfig::builder()
+            .host("h")
+            .length_sequence(40000, [100, 237]);
+        assert!(lengths.clone().build().is_ok());
+        assert!(invalid(lengths.clone().sequence([7000])));
+        assert!(invalid(lengths.clone().pad_to(512)));
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .length_sequence(40000, [100, 70000])
+        ));
+        // And a timing code, whose gaps nothing may blur
+        let code = TimingCode {
+            bits: TimingCode::parse_bits("10110").unwrap(),
+            unit: Duration::from_millis(200),
+            port: 40000,
+            protocol: Protocol::Udp,
+        };
+        let timed = KnockConfig::builder().host("h").timing_code(code.clone());
+        assert!(timed.clone().build().is_ok());
+        assert!(invalid(timed.clone().sequence([7000])));
+        assert!(invalid(timed.clone().unordered(true)));
+        assert!(invalid(KnockConfig::builder().host("h").timing_code(
+            TimingCode {
+                protocol: Protocol::Icmp,
+                ..code
+            }
+        )));
+        // An SRV name stands in for the host
+        let srv = KnockConfig::builder()
+            .srv("_knock._udp.example.com")
+            .sequence([7000]);
+        assert!(srv.build().is_ok());
+        assert!(invalid(
+            KnockConfig::builder()
+                .host("h")
+                .srv("_knock._udp.example.com")
+                .sequence([7000])
+        ));
+    }
+
+    #[test]
+    fn timing_code_gaps_tell_its_bits() {
+        let code = TimingCode {
+            bits: TimingCode::parse_bits("10110").unwrap(),
+            unit: Duration::from_millis(200),
+            port: 40000,
+            protocol: Protocol::Udp,
+        };
+        assert_eq!(code.to_string(), "10110");
+        let plan = code.plan();
+        assert_eq!(plan.len(), 6);
+        assert!(plan
+            .iter()
+            .all(|s| s.port == 40000 && s.attempts == Some(1)));
+        let gaps: Vec<_> = plan
+            .iter()
+            .map(|s| s.pre_delay.map(|d| d.as_millis()))
+            .collect();
+        assert_eq!(
+            gaps,
+            [None, Some(600), Some(200), Some(600), Some(600), Some(200)]
+        );
+        let config = KnockConfig {
+            sequence: plan,
+            ..KnockConfig::default()
+        };
+        let slots: Vec<_> = config.schedule().iter().map(Duration::as_millis).collect();
+        assert_eq!(slots, [0, 600, 800, 1400, 2000, 2200]);
+        assert!(TimingCode::parse_bits("").is_err());
+        assert!(TimingCode::parse_bits("102").is_err());
+        assert!(TimingCode::parse_bits(&"1".repeat(65)).is_err());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn backoff_flags_select_the_policy() {
+        let config = KnockConfig::from(Cli::parse_from([
+            "knock",
+            "-H",
+            "h",
+            "-s",
+            "7000",
+            "-b",
+            "200",
+            "--backoff-strategy",
+            "exponential",
+            "--backoff-max",
+            "1500",
+        ]));
+        assert_eq!(
+            config.knock_opts().backoff,
+            BackoffPolicy::Exponential {
+                base: Duration::from_millis(200),
+                factor: 2,
+                max: Duration::from_millis(1500),
+            }
+        );
+        let config = KnockConfig::from(Cli::parse_from(["knock", "-H", "h", "-s", "7000"]));
+        assert_eq!(
+            config.backoff_policy(),
+            BackoffPolicy::Constant(Duration::from_millis(100))
+        );
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn cli_modes_become_settings() {
+        let config = KnockConfig::from(Cli::parse_from([
+            "knock",
+            "-H",
+            "h",
+            "-s",
+            "7000",
+            "-p",
+            "udp",
+            "--spa",
+            "--spa-client-id",
+            "laptop",
+            "--tcp-payload-text",
+            "hi",
+        ]));
+        assert_eq!(config.spa.unwrap().client_id, "laptop");
+        assert!(config.fwknop.is_none());
+        assert_eq!(config.tcp_payload.as_deref(), Some(&b"hi"[..]));
+
+        let marked =
+            |mark| Cli::try_parse_from(["knock", "-H", "h", "-s", "7000", "--fwmark", mark]);
+        assert_eq!(
+            KnockConfig::from(marked("0x1f").ok().unwrap()).fwmark,
+            Some(31)
+        );
+        assert_eq!(
+            KnockConfig::from(marked("31").ok().unwrap()).fwmark,
+            Some(31)
+        );
+        assert!(marked("-1").is_err());
+        assert!(marked("0x100000000").is_err());
+
+        let srv = Cli::parse_from(["knock", "--srv", "_knock._udp.example.com", "-s", "7000"]);
+        let config = KnockConfig::from(srv);
+        assert_eq!(config.srv.as_deref(), Some("_knock._udp.example.com"));
+        assert!(config.host.is_empty());
+        let both = [
+            "knock",
+            "-H",
+            "h",
+            "--srv",
+            "_knock._udp.example.com",
+            "-s",
+            "7000",
+        ];
+        assert!(Cli::try_parse_from(both).is_err());
+
+        // Lengths stand in for the sequence, to one port
+        let lengths = |extra: &[&str]| {
+            let args = ["knock", "-H", "h", "--length-sequence", "100,237,61"];
+            Cli::try_parse_from(args.iter().chain(extra))
+        };
+        let config = KnockConfig::from(lengths(&["--length-port", "40000"]).ok().unwrap());
+        assert_eq!(
+            config.length_sequence,
+            Some(LengthSequence {
+                port: 40000,
+                lengths: vec![100, 237, 61]
+            })
+        );
+        assert!(config.validate().is_ok());
+        assert!(lengths(&[]).is_err());
+        assert!(lengths(&["--length-port", "40000", "-s", "7000"]).is_err());
+        assert!(lengths(&["--length-port", "0"]).is_err());
+        assert!(Cli::try_parse_from([
+            "knock",
+            "-H",
+            "h",
+            "--length-sequence",
+            "0",
+            "--length-port",
+            "1"
+        ])
+        .is_err());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn flags_win_over_the_uri() {
+        let uri = "knock://example.com/7000,8000:udp?timeout=2s&verify=22&dry-run";
+        let parse = |args: &[&str]| {
+            let args = ["knock", "--uri", uri]
+                .into_iter()
+                .chain(args.iter().copied());
+            KnockConfig::from(crate::cli::parse_args(args).unwrap())
+        };
+        let config = parse(&[]);
+        assert_eq!(config.host, "example.com");
+        assert_eq!(config.sequence.to_string(), "7000,8000/udp");
+        assert_eq!(config.timeout, 2000);
+        assert!(config.dry_run);
+        assert_eq!(config.ladder.unwrap().verify.port, 22);
+
+        let config = parse(&["-H", "other.example", "-t", "900", "-s", "9000"]);
+        assert_eq!(config.host, "other.example");
+        assert_eq!(config.sequence.to_string(), "9000");
+        assert_eq!(config.timeout, 900);
+        assert!(config.dry_run);
+
+        let uri_and_plan = ["knock", "--uri", uri, "--plan", "plan.toml"];
+        assert!(crate::cli::parse_args(uri_and_plan).is_err());
+        assert!(crate::cli::parse_args(["knock", "--uri", "knock://h/7000?hooks=x"]).is_err());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn flags_win_over_the_ssh_config_entry() {
+        let ssh_config = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/ssh/config");
+        let parse = |args: &[&str]| {
+            let args = ["knock", "--ssh-config", ssh_config]
+                .into_iter()
+                .chain(args.iter().copied());
+            crate::cli::parse_args(args).map(KnockConfig::from)
+        };
+        let config = parse(&["--ssh-host", "bastion"]).unwrap();
+        assert_eq!(config.host, "203.0.113.7");
+        assert_eq!(config.sequence.to_string(), "7000,8000,9000");
+        assert_eq!(config.protocol, Protocol::Udp);
+        assert_eq!(config.timeout, 2000);
+        assert!(config.dry_run);
+        assert_eq!(config.ladder.unwrap().verify.port, 2222);
+
+        let args = ["--ssh-host", "bastion", "-H", "198.51.100.1", "-s", "1234"];
+        let config = parse(&[&args[..], &["-t", "900", "--verify", "443"]].concat()).unwrap();
+        assert_eq!(config.host, "198.51.100.1");
+        assert_eq!(config.sequence.to_string(), "1234");
+        assert_eq!(config.protocol, Protocol::Udp);
+        assert_eq!(config.timeout, 900);
+        assert!(config.dry_run);
+        assert_eq!(config.ladder.unwrap().verify.port, 443);
+
+        let config = parse(&["--ssh-host", "db"]).unwrap();
+        assert_eq!(config.host, "10.0.0.5");
+        assert_eq!(config.ladder.unwrap().verify.port, 5432);
+
+        let error = |args: &[&str]| parse(args).err().unwrap().to_string();
+        assert!(error(&["--ssh-host", "broken"]).contains("'hooks' is not a knock option"));
+        assert!(error(&["--ssh-host", "db", "--uri", "knock://h/7000"]).contains("cannot be used"));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn spoofing_needs_its_acknowledgement() {
+        let args = [
+            "knock",
+            "-H",
+            "h",
+            "-s",
+            "7000",
+            "--spoof-source",
+            "192.0.2.1",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+        let acknowledged = args.iter().chain(&["--i-understand-spoofing"]);
+        let config = KnockConfig::from(Cli::parse_from(acknowledged));
+        assert_eq!(config.spoof_source, Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn resolve_strategy_flag() {
+        let args = ["knock", "-H", "h", "-s", "7000", "--resolve", "prefer-v6"];
+        let config = KnockConfig::from(Cli::parse_from(args));
+        assert_eq!(config.resolve, ResolveStrategy::PreferV6);
+        let proxied = KnockConfig::builder()
+            .host("h")
+            .sequence([7000])
+            .resolve(ResolveStrategy::OnlyV4)
+            .proxy_socks5(Socks5Proxy::parse("127.0.0.1:1080").unwrap())
+            .build();
+        assert!(matches!(proxied, Err(AppError::InvalidConfig(_))));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn stun_flags() {
+        let args = [
+            "knock",
+            "-H",
+            "h",
+            "-s",
+            "7000",
+            "--stun-server",
+            "stun.example.net",
+            "--require-public-ip",
+            "--payload-text",
+            "from {public_ip}",
+        ];
+        let config = KnockConfig::from(Cli::parse_from(args));
+        let public_ip = config.public_ip.unwrap();
+        assert_eq!(public_ip.server.to_string(), "stun.example.net");
+        assert!(public_ip.required);
+        assert_eq!(config.payload.as_deref(), Some(&b"from {public_ip}"[..]));
+        assert!(
+            Cli::try_parse_from(["knock", "-H", "h", "-s", "1", "--require-public-ip"]).is_err()
+        );
+
+        let proxied = KnockConfig::builder()
+            .host("h")
+            .sequence([7000])
+            .public_ip(PublicIp {
+                server: StunServer::parse("stun.example.net:3479").unwrap(),
+                required: false,
+            })
+            .proxy_socks5(Socks5Proxy::parse("127.0.0.1:1080").unwrap())
+            .build();
+        assert!(matches!(proxied, Err(AppError::InvalidConfig(_))));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn resolve_flag_takes_strategies_and_pins() {
+        let args = [
+            "knock",
+            "-H",
+            "h",
+            "-s",
+            "7000",
+            "--resolve",
+            "h:203.0.113.7",
+            "--resolve",
+            "only-v6",
+            "--resolve",
+            "h:[2001:db8::7]",
+        ];
+        let config = KnockConfig::from(Cli::parse_from(args));
+        assert_eq!(config.resolve, ResolveStrategy::OnlyV6);
+        let addrs: Vec<_> = config.resolve_pins.iter().map(|p| p.addr).collect();
+        assert_eq!(
+            addrs,
+            [
+                "203.0.113.7".parse::<std::net::IpAddr>().unwrap(),
+                "2001:db8::7".parse().unwrap()
+            ]
+        );
+        assert!(
+            Cli::try_parse_from(["knock", "-H", "h", "-s", "1", "--resolve", "h:nope"]).is_err()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_round_trips_through_json_and_toml() {
+        use std::time::UNIX_EPOCH;
+
+        let mut config = KnockConfig::builder()
+            .host("knock.example")
+            .plan("(7000,8000/udp),9000*3".parse().unwrap())
+            .protocol(Protocol::Udp)
+            .delay_precise(Duration::from_micros(1500))
+            .payload(&b"knock"[..])
+            .retry_forever()
+            .build()
+            .unwrap();
+        config.resolve_pins = vec!["knock.example:[2001:db8::7]".parse::<ResolvePin>().unwrap()];
+        config.resolution = ResolutionPolicy::Ttl(Duration::from_secs(30));
+        config.source_port_policy = SourcePortPolicy::Range(40000..=40100);
+        config.failure_tolerance = Some(FailureTolerance::Percent(12.5));
+        config.sign_key = Some("env:KNOCK_KEY".parse().unwrap());
+        config.proxy_socks5 = Some(Socks5Proxy::parse("user:hunter2@proxy.example:1080").unwrap());
+        config.start_at = Some(UNIX_EPOCH + Duration::from_millis(1_792_137_600_250));
+        config.timing_code = Some(TimingCode {
+            port: 7000,
+            bits: vec![true, false, true],
+            unit: Duration::from_millis(50),
+            protocol: Protocol::Tcp,
+        });
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains(r#""delay":1.5"#), "{json}");
+        assert!(json.contains(r#""payload":"6b6e6f636b""#), "{json}");
+        assert!(
+            json.contains(r#""sequence":"(7000,8000/udp),9000?attempts=3""#),
+            "{json}"
+        );
+        assert!(json.contains(r#""bits":"101""#), "{json}");
+        assert!(
+            !json.contains("hunter2"),
+            "the proxy password is not written: {json}"
+        );
+
+        let from_json: KnockConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&from_json).unwrap(), json);
+        let toml = toml::to_string(&config).unwrap();
+        let from_toml: KnockConfig = toml::from_str(&toml).unwrap();
+        assert_eq!(serde_json::to_string(&from_toml).unwrap(), json, "{toml}");
+
+        assert_eq!(from_toml.sequence, config.sequence);
+        assert_eq!(from_toml.delay, Duration::from_micros(1500));
+        assert_eq!(from_toml.payload.as_deref(), Some(&b"knock"[..]));
+        assert_eq!(from_toml.attempts, Attempts::Unlimited);
+        assert_eq!(from_toml.start_at, config.start_at);
+        assert_eq!(from_toml.sign_key, config.sign_key);
+
+        // Anything left out takes the builder's default.
+        let sparse: KnockConfig = toml::from_str("host = \"h\"\nsequence = \"7000\"").unwrap();
+        assert_eq!(sparse.timeout, KnockConfig::default().timeout);
+        assert_eq!(sparse.attempts, KnockConfig::default().attempts);
+        assert!(toml::from_str::<KnockConfig>("host = \"h\"\npayload = \"xyz\"").is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{config::KnockConfig, plan::KnockStep, retry::Attempts, AppError};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Render the resolved knock plan shown before asking for confirmation.
+pub fn describe_plan(config: &KnockConfig, addrs: &[SocketAddr]) -> String {
+    let mut out = String::new();
+    let targets: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+    // Passphrase-derived, fetched and scripted ports are only shown for a
+    // dry run
+    let hidden = match (
+        &config.passphrase_ports,
+        &config.sequence_from_txt,
+        &config.script,
+    ) {
+        _ if config.dry_run => None,
+        (Some(_), _, _) => Some("derived from the passphrase".to_string()),
+        (None, Some(txt), _) => Some(format!("from the TXT record of {}", txt.name)),
+        (None, None, Some(script)) => Some(format!("from script {}", script.path.display())),
+        (None, None, None) => None,
+    };
+    // A length sequence's steps show their lengths, not their random bytes
+    let label = |step: &KnockStep| match (&config.length_sequence, &step.payload) {
+        (Some(_), Some(payload)) => format!("{}/udp ({} bytes)", step.port, payload.len()),
+        _ => step.to_string(),
+    };
+    let ports: Vec<String> = match hidden {
+        Some(source) => vec![format!("{} {source}", config.sequence.len())],
+        // A group sent together shows in parentheses
+        None => config
+            .sequence
+            .groups()
+            .map(|group| {
+                let steps: Vec<String> = group
+                    .iter()
+                    .map(|step| match step.decoy {
+                        true => format!("{step} (decoy)"),
+                        false => label(step),
+                    })
+                    .collect();
+                match steps.len() {
+                    1 => steps.join(""),
+                    _ => format!("({})", steps.join(", ")),
+                }
+            })
+            .collect(),
+    };
+    let payload = match (&config.payload, &config.payload_dns) {
+        (Some(p), _) => p.len(),
+        (None, Some(name)) => crate::dns::build_query(0, name, crate::dns::TYPE_A).len(),
+        (None, None) => 0,
+    };
+
+    out.push_str(&format!("Host:      {}\n", config.host));
+    match (&config.proxy_socks5, &config.jump) {
+        (Some(proxy), _) => {
+            out.push_str(&format!("Proxy:     socks5 {} (remote DNS)\n", proxy.addr))
+        }
+        (None, Some(jump)) => out.push_str(&format!("Jump host: ssh {jump} (remote DNS)\n")),
+        (None, None) => {
+            let pinned = crate::dns::pinned(&config.resolve_pins, &config.host, config.resolve);
+            let pinned = if pinned.is_some() { " (pinned)" } else { "" };
+            out.push_str(&format!("Addresses: {}{pinned}\n", targets.join(", ")))
+        }
+    }
+    let protocol = format!("{:?}", config.protocol).to_lowercase();
+    out.push_str(&format!("Protocol:  {protocol}\n"));
+    if let Some(name) = &config.netns {
+        out.push_str(&format!("Netns:     {name}\n"));
+    }
+    if let Some(mark) = config.fwmark {
+        out.push_str(&format!(
+            "Fwmark:    {mark} ({mark:#x}) on TCP and UDP sockets\n"
+        ));
+    }
+    if let Some(port) = config.skip_if_open {
+        out.push_str(&format!(
+            "Skip if:   port {port} is already open (connect within {}ms)\n",
+            config.open_probe_timeout
+        ));
+    }
+    out.push_str(&format!("Ports:     {}\n", ports.join(" -> ")));
+    if let Some(code) = &config.timing_code {
+        let slots: Vec<String> = config
+            .schedule()
+            .iter()
+            .map(|at| format!("+{}ms", at.as_millis()))
+            .collect();
+        out.push_str(&format!(
+            "Schedule:  {} (code {code}: a 0 is {}ms, a 1 {}ms)\n",
+            slots.join(", "),
+            code.gap(false).as_millis(),
+            code.gap(true).as_millis()
+        ));
+    }
+    if let Some(public_ip) = &config.public_ip {
+        let fallback = match public_ip.required {
+            true => "required",
+            false => "else the local address",
+        };
+        out.push_str(&format!(
+            "Public IP: from STUN server {} ({fallback})\n",
+            public_ip.server
+        ));
+    }
+    match (&config.spa, &config.fwknop) {
+        (Some(spa), _) => out.push_str(&format!(
+            "Payload:   SPA packet for client '{}'\n",
+            spa.client_id
+        )),
+        (_, Some(fwknop)) => out.push_str(&format!(
+            "Payload:   fwknop SPA packet requesting {}\n",
+            fwknop.access
+        )),
+        _ if config.length_sequence.is_some() => {
+            out.push_str("Payload:   random bytes, as many as each knock's length\n")
+        }
+        _ if config.protocol == crate::protocol::Protocol::Udp => {
+            out.push_str(&format!("Payload:   {payload} bytes\n"))
+        }
+        _ => {}
+    }
+    match max_duration(config) {
+        Some(duration) => out.push_str(&format!("Duration:  up to {}ms\n", duration.as_millis())),
+        None => out.push_str("Duration:  until every knock gets through or the run is cancelled\n"),
+    }
+    out
+}
+
+/// Worst-case wall time of the whole sequence: every knock uses all its
+/// retries and the maximum jitter, with knocks run one at a time, or
+/// `concurrency` at a time when unordered, each round as slow as its
+/// slowest step, plus the wait for every attempt's turn under `rate`.
+/// `None` when a knock retries forever with no knock deadline to end it.
+pub fn max_duration(config: &KnockConfig) -> Option<Duration> {
+    let backoff = config.backoff_policy();
+    let attempts = |step: &KnockStep| step.attempts.map_or(config.attempts, Attempts::Finite);
+    let per_knock = |(index, step): (usize, &KnockStep)| {
+        let delay = match step.pre_delay {
+            Some(delay) => delay.as_millis() as u64,
+            None if index == 0 => config.initial_delay,
+            // Rounded up to whole milliseconds like the rest
+            None => (2 * config.delay).as_micros().div_ceil(1000) as u64,
+        };
+        let timeout = step
+            .timeout
+            .map_or(config.timeout, |t| t.as_millis() as u64);
+        // The last attempt may start just before the deadline
+        let deadline = config.knock_deadline.map(|d| d + timeout);
+        let knock = match attempts(step).finite() {
+            Some(attempts) => {
+                let backoff: u64 = (1..attempts)
+                    .map(|attempt| backoff.max_delay_for(attempt).as_millis() as u64)
+                    .sum();
+                let knock = attempts as u64 * timeout + backoff;
+                deadline.map_or(knock, |d| knock.min(d))
+            }
+            None => deadline?,
+        };
+        Some(delay + knock)
+    };
+    let slowest = config
+        .sequence
+        .iter()
+        .enumerate()
+        .map(per_knock)
+        .collect::<Option<Vec<u64>>>()?
+        .into_iter()
+        .max()
+        .unwrap_or(0);
+    let rounds = config
+        .sequence
+        .len()
+        .div_ceil(config.knocks_in_flight().max(1)) as u64;
+    let rate_wait = match config.rate {
+        Some(rate) if rate > 0 => {
+            // Endless attempts wait for their turns within the deadline
+            let attempts: u64 = config
+                .sequence
+                .iter()
+                .map(|step| attempts(step).finite().unwrap_or(1) as u64)
+                .sum();
+            attempts * 1000 / u64::from(rate)
+        }
+        _ => 0,
+    };
+    Some(Duration::from_millis(rounds * slowest + rate_wait))
+}
+
+/// Ask for an explicit `y` on stdin. The run has already shown the plan
+/// as a [`KnockEvent::Plan`](crate::KnockEvent::Plan).
+///
+/// With `assume_yes` no prompt is shown. A non-interactive stdin is an
+/// error rather than a silent hang.
+pub async fn confirm_plan(assume_yes: bool) -> Result<(), AppError> {
+    if assume_yes {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(AppError::Confirm(
+            "stdin is not a terminal; pass --yes to skip the prompt".into(),
+        ));
+    }
+
+    let answer = crate::rt::spawn_blocking(|| {
+        print!("Send these knocks? [y/N] ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        Ok::<_, io::Error>(line)
+    })
+    .await??;
+
+    if is_yes(&answer) {
+        Ok(())
+    } else {
+        Err(AppError::Confirm("aborted, no knocks sent".into()))
+    }
+}
+
+/// Only an explicit `y`/`yes` confirms; anything else (including EOF) aborts.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_explicit_yes_confirms() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes(""));
+        assert!(!is_yes("n\n"));
+        assert!(!is_yes("yep"));
+    }
+
+    #[test]
+    fn duration_accounts_for_attempts_and_concurrency() {
+        let config = KnockConfig::builder()
+            .host("h")
+            .sequence([1, 2, 3])
+            .timeout(100)
+            .attempts(2)
+            .backoff(50)
+            .delay(10)
+            .concurrency(2)
+            .unordered(true)
+            .build()
+            .unwrap();
+        // per knock: 2*10 + 2*100 + 1*50 = 270ms, two rounds
+        assert_eq!(max_duration(&config), Some(Duration::from_millis(540)));
+
+        // In order the knocks take three rounds whatever the concurrency
+        let ordered = KnockConfig {
+            unordered: false,
+            ..config.clone()
+        };
+        assert_eq!(max_duration(&ordered), Some(Duration::from_millis(810)));
+
+        // A slow step sets the pace of every round
+        let mut slow = config.clone();
+        slow.sequence.0[2] = KnockStep::parse("3?timeout=200&attempts=1&delay=0").unwrap();
+        assert_eq!(max_duration(&slow), Some(Duration::from_millis(540)));
+        slow.sequence.0[2] = KnockStep::parse("3?timeout=600").unwrap();
+        // 2*10 + 2*600 + 50 = 1270ms
+        assert_eq!(max_duration(&slow), Some(Duration::from_millis(2540)));
+
+        // The first knock waits the initial delay instead: 1000 + 250ms
+        let mut first = config.clone();
+        first.initial_delay = 1000;
+        assert_eq!(max_duration(&first), Some(Duration::from_millis(2500)));
+
+        // Exponential backoff: 50 + 100 between three attempts
+        let mut exponential = config.clone();
+        exponential.attempts = Attempts::Finite(3);
+        exponential.backoff_strategy = crate::protocol::BackoffStrategy::Exponential;
+        // per knock: 2*10 + 3*100 + 150 = 470ms
+        assert_eq!(max_duration(&exponential), Some(Duration::from_millis(940)));
+
+        // A knock deadline bounds the attempts: 2*10 + 120 + 100 = 240ms
+        exponential.knock_deadline = Some(120);
+        assert_eq!(max_duration(&exponential), Some(Duration::from_millis(480)));
+
+        // Six attempts at 4 a second wait up to 1500ms for their turns
+        let mut limited = config.clone();
+        limited.rate = Some(4);
+        assert_eq!(max_duration(&limited), Some(Duration::from_millis(2040)));
+
+        // Retrying forever is only bounded by a deadline: 2*10 + 300 + 100
+        let mut forever = config.clone();
+        forever.attempts = Attempts::Unlimited;
+        assert_eq!(max_duration(&forever), None);
+        forever.knock_deadline = Some(300);
+        assert_eq!(max_duration(&forever), Some(Duration::from_millis(840)));
+    }
+}
@@ -0,0 +1,198 @@
+//! A control socket for long-running knocking (`--control-socket`).
+//!
+//! A Unix domain socket, readable and writable by its owner only, taking
+//! one command per line and answering each with one line:
+//!
+//! - `status`: the last run's report as JSON, `null` before the first.
+//! - `knock`: run the sequence now, out of schedule.
+//! - `reload`: build the configuration afresh.
+//! - `stop`: shut down the way SIGTERM does.
+//!
+//! What each does is up to the caller of [`serve`]; it only parses the
+//! commands and carries the answers. A socket file left behind by a run
+//! that crashed is replaced, one a live process still listens on is not.
+
+use crate::events::KnockEvent;
+use crate::observer::KnockObserver;
+use crate::{AppError, KnockReport};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// A command read from the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    Status,
+    Knock,
+    Reload,
+    Stop,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "status" => Ok(ControlCommand::Status),
+            "knock" => Ok(ControlCommand::Knock),
+            "reload" => Ok(ControlCommand::Reload),
+            "stop" => Ok(ControlCommand::Stop),
+            _ => Err(format!(
+                "unknown command '{}' (expected status, knock, reload or stop)",
+                s.trim()
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ControlCommand::Status => "status",
+            ControlCommand::Knock => "knock",
+            ControlCommand::Reload => "reload",
+            ControlCommand::Stop => "stop",
+        })
+    }
+}
+
+/// Listen on a socket at `path` and answer every command line with what
+/// `respond` makes of it, an unknown one with `error: ...`, until `cancel`
+/// is cancelled. The socket file is made mode 0600 and removed once done.
+pub async fn serve<F>(
+    path: &Path,
+    respond: F,
+    cancel: CancellationToken,
+) -> Result<JoinHandle<()>, AppError>
+where
+    F: Fn(ControlCommand) -> String + Send + Sync + 'static,
+{
+    let listener = bind(path).map_err(|e| control_error(path, e))?;
+    let (path, respond) = (path.to_path_buf(), Arc::new(respond));
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                _ = cancel.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(_) => continue,
+                },
+            };
+            let (respond, cancel) = (respond.clone(), cancel.clone());
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = cancel.cancelled() => {}
+                    _ = answer(stream, &*respond) => {}
+                }
+            });
+        }
+        remove(&path);
+    }))
+}
+
+/// Bind a socket at `path`, replacing a stale one.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            // Nobody answers on a socket left by a process that crashed
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        ErrorKind::AddrInUse,
+                        "another process is listening on it",
+                    ))
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "a file that is not a socket is in the way",
+            ))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+        remove(path);
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+/// Answer the command lines of one connection until it closes.
+async fn answer(stream: UnixStream, respond: &(dyn Fn(ControlCommand) -> String + Sync)) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut answer = match line.parse() {
+            Ok(command) => respond(command),
+            Err(e) => format!("error: {e}"),
+        };
+        answer.push('\n');
+        if write.write_all(answer.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn control_error(path: &Path, e: io::Error) -> AppError {
+    AppError::Runtime(format!("cannot listen on {}: {e}", path.display()))
+}
+
+/// An observer keeping the report of the last run, for `status`.
+#[derive(Debug, Default)]
+pub struct LastReport(Mutex<Option<KnockReport>>);
+
+impl LastReport {
+    /// The report as [`report_json`] has it, `null` before the first run.
+    pub fn json(&self) -> String {
+        match &*self.0.lock().unwrap() {
+            Some(report) => report_json(report),
+            None => "null".to_string(),
+        }
+    }
+}
+
+impl KnockObserver for LastReport {
+    fn on_event(&self, event: &KnockEvent) {
+        if let KnockEvent::Finished { report } = event {
+            *self.0.lock().unwrap() = Some((**report).clone());
+        }
+    }
+}
+
+/// A run's report as one line of JSON, as it serializes, and whether it
+/// succeeded, e.g.
+/// `{"host":"example.com","address":"192.0.2.7","started_at":1792137600.25,"steps":[{"port":7000,"protocol":"tcp",...}],"duration_ms":412,...,"succeeded":true}`.
+pub fn report_json(report: &KnockReport) -> String {
+    #[derive(serde::Serialize)]
+    struct Status<'a> {
+        #[serde(flatten)]
+        report: &'a KnockReport,
+        succeeded: bool,
+    }
+    let status = Status {
+        report,
+        succeeded: report.succeeded(),
+    };
+    serde_json::to_string(&status).expect("a report serializes")
+}
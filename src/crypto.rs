@@ -0,0 +1,160 @@
+use crate::secret::SecretSource;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::sync::atomic::{AtomicU64, Ordering};
+use zeroize::Zeroizing;
+
+/// Length of the shared key.
+pub const KEY_LEN: usize = 32;
+/// Length of the nonce leading every encrypted payload.
+pub const NONCE_LEN: usize = 12;
+/// Length of the GCM tag closing every encrypted payload.
+pub const TAG_LEN: usize = 16;
+
+/// AES-256-GCM encryption of knock payloads under one shared key.
+///
+/// Each encrypted payload goes on the wire as
+///
+/// ```text
+/// nonce (12) | ciphertext (len of plaintext) | tag (16)
+/// ```
+///
+/// with no associated data; a receiver splits off the nonce and decrypts
+/// the rest with the key. Nonces start from a random value per run and
+/// count up from there, so they never repeat within a run.
+pub struct PayloadCipher {
+    cipher: Aes256Gcm,
+    nonce_base: [u8; NONCE_LEN],
+    counter: AtomicU64,
+}
+
+impl PayloadCipher {
+    /// Cipher whose nonces start at a random point.
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        Self::with_nonce_base(key, rand::random())
+    }
+
+    fn with_nonce_base(key: &[u8; KEY_LEN], nonce_base: [u8; NONCE_LEN]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+            nonce_base,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Encrypt `plaintext` under a fresh nonce, returning the wire layout.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("AES-GCM encryption cannot fail for knock-sized payloads");
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    /// Add the call count to the low 64 bits of the base.
+    fn next_nonce(&self) -> [u8; NONCE_LEN] {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut nonce = self.nonce_base;
+        let mut low = [0u8; 8];
+        low.copy_from_slice(&nonce[4..]);
+        nonce[4..].copy_from_slice(&u64::from_be_bytes(low).wrapping_add(n).to_be_bytes());
+        nonce
+    }
+}
+
+/// Decrypt what [`PayloadCipher::seal`] sealed under `key`; `None` when it
+/// is too short to be sealed or does not authenticate.
+pub fn open(key: &[u8; KEY_LEN], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    Aes256Gcm::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), rest)
+        .ok()
+}
+
+/// Read a key holding either the 32 raw key bytes or 64 hex digits. A
+/// trailing newline is not part of the key.
+pub async fn load_key(source: &SecretSource) -> Result<Zeroizing<[u8; KEY_LEN]>, String> {
+    let raw = source.read_raw("encryption key").await?;
+    let mut data = &raw[..];
+    if data.len() != KEY_LEN {
+        while let [rest @ .., b'\n' | b'\r'] = data {
+            data = rest;
+        }
+    }
+    let hex_key;
+    let key = match data.len() {
+        KEY_LEN => data,
+        n if n == 2 * KEY_LEN => {
+            hex_key =
+                Zeroizing::new(hex::decode(data).map_err(|e| format!("invalid hex key: {e}"))?);
+            &hex_key[..]
+        }
+        n => {
+            return Err(format!(
+                "key must be {KEY_LEN} raw bytes or {} hex digits, got {n} bytes",
+                2 * KEY_LEN
+            ))
+        }
+    };
+    let mut out = Zeroizing::new([0u8; KEY_LEN]);
+    out.copy_from_slice(key);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    #[test]
+    fn round_trip() {
+        let cipher = PayloadCipher::new(&KEY);
+        for plaintext in [&b""[..], b"open sesame", &[0xab; 1200]] {
+            let sealed = cipher.seal(plaintext);
+            assert_eq!(sealed.len(), NONCE_LEN + plaintext.len() + TAG_LEN);
+            assert_eq!(open(&KEY, &sealed).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let mut sealed = PayloadCipher::new(&KEY).seal(b"open sesame");
+        sealed[NONCE_LEN] ^= 1;
+        assert!(open(&KEY, &sealed).is_none());
+        assert!(open(&KEY, &sealed[..NONCE_LEN + TAG_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn nonces_never_repeat() {
+        // Start next to the wrap of the low 64 bits
+        let mut base = [0xff; NONCE_LEN];
+        base[..4].copy_from_slice(&[1, 2, 3, 4]);
+        let cipher = PayloadCipher::with_nonce_base(&KEY, base);
+        let nonces: Vec<Vec<u8>> = (0..4)
+            .map(|_| cipher.seal(b"knock")[..NONCE_LEN].to_vec())
+            .collect();
+        assert_eq!(nonces[0], base);
+        for (i, a) in nonces.iter().enumerate() {
+            assert_eq!(&a[..4], &[1, 2, 3, 4]);
+            assert!(nonces[i + 1..].iter().all(|b| a != b));
+        }
+    }
+
+    #[tokio::test]
+    async fn key_file_formats() {
+        let path = std::env::temp_dir().join(format!("gcm-key-{}", std::process::id()));
+        let source = SecretSource::File(path.clone());
+        std::fs::write(&path, KEY).unwrap();
+        assert_eq!(*load_key(&source).await.unwrap(), KEY);
+        std::fs::write(&path, format!("{}\n", hex::encode(KEY))).unwrap();
+        assert_eq!(*load_key(&source).await.unwrap(), KEY);
+        std::fs::write(&path, "short\n").unwrap();
+        assert!(load_key(&source).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
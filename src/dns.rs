@@ -0,0 +1,800 @@
+use crate::rt::{self, Instant};
+use crate::{protocol::ResolveStrategy, scope, AppError};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "custom-dns")]
+use {
+    crate::srv::SrvRecord,
+    hickory_resolver::{
+        config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts},
+        error::{ResolveError, ResolveErrorKind},
+        proto::{
+            error::ProtoErrorKind,
+            op::ResponseCode,
+            rr::{RData, RecordType},
+        },
+        TokioAsyncResolver,
+    },
+};
+
+/// DNS record type A.
+pub const TYPE_A: u16 = 1;
+/// How long a custom DNS server gets to answer one query.
+#[cfg(feature = "custom-dns")]
+const QUERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+/// Queries sent to a custom DNS server before giving up.
+#[cfg(feature = "custom-dns")]
+const QUERY_TRIES: usize = 2;
+/// DNS class IN.
+const CLASS_IN: u16 = 1;
+
+/// Resolve `host` to the addresses knocks are sent to, with port 0,
+/// filtered and ordered by `strategy`.
+///
+/// IP literals, with or without brackets, are taken as is; a zoned IPv6
+/// literal keeps its scope ID so it reaches the sockets. Names are looked
+/// up as [`scope::ascii_host`] spells them. No address left after filtering
+/// is [`AppError::NoDns`].
+pub async fn resolve_target(
+    host: &str,
+    strategy: ResolveStrategy,
+) -> Result<Vec<SocketAddr>, AppError> {
+    let mut addrs: Vec<SocketAddr> = match literal(host) {
+        Some(addr) => vec![addr],
+        None => {
+            let fail = |source| AppError::Resolve {
+                host: host.to_string(),
+                server: None,
+                source,
+            };
+            let name = scope::ascii_host(host)
+                .map_err(|e| fail(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+            let addrs = rt::lookup_host(&name, 0).await.map_err(fail)?;
+            addrs
+        }
+    };
+    apply_strategy(&mut addrs, strategy);
+    if addrs.is_empty() {
+        return Err(AppError::NoDns);
+    }
+    Ok(addrs)
+}
+
+/// [`resolve_target`] asking the DNS server at `server` for A and AAAA
+/// records instead of the system resolver, e.g. for names only a
+/// split-horizon resolver knows.
+///
+/// The queries go through hickory-resolver: over UDP, again over TCP when
+/// the answer is too big for a datagram, each tried twice. No answer in
+/// time, SERVFAIL and every other error code are [`AppError::Resolve`]
+/// naming `server`.
+#[cfg(feature = "custom-dns")]
+pub async fn resolve_via(
+    server: SocketAddr,
+    host: &str,
+    strategy: ResolveStrategy,
+) -> Result<Vec<SocketAddr>, AppError> {
+    let mut addrs = match literal(host) {
+        Some(addr) => vec![addr],
+        None => {
+            let fail = |source| AppError::Resolve {
+                host: host.to_string(),
+                server: Some(server),
+                source,
+            };
+            let invalid = |e| fail(io::Error::new(io::ErrorKind::InvalidInput, e));
+            let name = scope::ascii_host(host).map_err(invalid)?;
+            validate_name(&name).map_err(invalid)?;
+            let ip_strategy = match strategy {
+                ResolveStrategy::OnlyV4 => LookupIpStrategy::Ipv4Only,
+                ResolveStrategy::OnlyV6 => LookupIpStrategy::Ipv6Only,
+                _ => LookupIpStrategy::Ipv4AndIpv6,
+            };
+            match resolver(server, ip_strategy).lookup_ip(fqdn(&name)).await {
+                Ok(lookup) => lookup.iter().map(|ip| SocketAddr::new(ip, 0)).collect(),
+                Err(e) => match lookup_error(e) {
+                    Some(e) => return Err(fail(e)),
+                    None => Vec::new(),
+                },
+            }
+        }
+    };
+    apply_strategy(&mut addrs, strategy);
+    if addrs.is_empty() {
+        return Err(AppError::NoDns);
+    }
+    Ok(addrs)
+}
+
+/// The SRV records of `name`, e.g. `_knock._udp.example.com`, asked of
+/// the DNS server at `server`, in the order of the answer. Failures are
+/// [`AppError::Resolve`] naming `server`, as for [`resolve_via`]; a name
+/// with no SRV records is one too.
+#[cfg(feature = "custom-dns")]
+pub async fn lookup_srv(server: SocketAddr, name: &str) -> Result<Vec<SrvRecord>, AppError> {
+    let srv = |data: &RData| match data {
+        RData::SRV(srv) => Some(SrvRecord {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            target: match srv.target().is_root() {
+                true => ".".into(),
+                false => srv.target().to_ascii().trim_end_matches('.').to_string(),
+            },
+        }),
+        _ => None, // CNAMEs and the like
+    };
+    lookup(server, name, RecordType::SRV, srv, "no SRV records").await
+}
+
+/// The TXT records of `name` asked of the DNS server at `server`, each
+/// the concatenation of its strings (RFC 7208 section 3.3). Failures are
+/// [`AppError::Resolve`] as for [`lookup_srv`].
+#[cfg(feature = "custom-dns")]
+pub async fn lookup_txt(server: SocketAddr, name: &str) -> Result<Vec<Vec<u8>>, AppError> {
+    let txt = |data: &RData| match data {
+        RData::TXT(txt) => Some(txt.txt_data().concat()),
+        _ => None, // CNAMEs and the like
+    };
+    lookup(server, name, RecordType::TXT, txt, "no TXT records").await
+}
+
+/// Ask `server` for the `rtype` records of `name`, each read with `read`;
+/// an answer without any is an error reading `none`.
+#[cfg(feature = "custom-dns")]
+async fn lookup<T>(
+    server: SocketAddr,
+    name: &str,
+    rtype: RecordType,
+    read: impl Fn(&RData) -> Option<T>,
+    none: &str,
+) -> Result<Vec<T>, AppError> {
+    let fail = |source| AppError::Resolve {
+        host: name.to_string(),
+        server: Some(server),
+        source,
+    };
+    validate_name(name).map_err(|e| fail(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+    let resolver = resolver(server, LookupIpStrategy::default());
+    let records: Vec<T> = match resolver.lookup(fqdn(name), rtype).await {
+        Ok(lookup) => lookup
+            .record_iter()
+            .filter_map(|record| record.data().and_then(&read))
+            .collect(),
+        Err(e) => match lookup_error(e) {
+            Some(e) => return Err(fail(e)),
+            None => Vec::new(),
+        },
+    };
+    if records.is_empty() {
+        return Err(fail(io::Error::new(io::ErrorKind::NotFound, none)));
+    }
+    Ok(records)
+}
+
+/// The first nameserver of `/etc/resolv.conf`, on port 53, for lookups
+/// the system resolver does not offer.
+pub(crate) fn system_nameserver(what: &str) -> Result<SocketAddr, AppError> {
+    let none = |why: String| {
+        AppError::InvalidConfig(format!(
+            "no DNS server to ask for {what} ({why}); give one with --dns-server"
+        ))
+    };
+    let conf = std::fs::read_to_string("/etc/resolv.conf")
+        .map_err(|e| none(format!("cannot read /etc/resolv.conf: {e}")))?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|rest| rest.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| none("/etc/resolv.conf names none".into()))
+}
+
+/// A resolver asking nothing but the DNS server at `server`.
+#[cfg(feature = "custom-dns")]
+fn resolver(server: SocketAddr, ip_strategy: LookupIpStrategy) -> TokioAsyncResolver {
+    // UDP and TCP both, so a truncated answer is asked for again over TCP
+    let servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+    let mut opts = ResolverOpts::default();
+    opts.ip_strategy = ip_strategy;
+    opts.timeout = QUERY_TIMEOUT;
+    opts.attempts = QUERY_TRIES;
+    opts.use_hosts_file = false;
+    TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, Vec::new(), servers), opts)
+}
+
+/// `name` fully qualified, so no search domain is tried first.
+#[cfg(feature = "custom-dns")]
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+/// Why a lookup failed; `None` for an answer that merely has no records.
+#[cfg(feature = "custom-dns")]
+fn lookup_error(e: ResolveError) -> Option<io::Error> {
+    use io::ErrorKind;
+
+    Some(match e.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => match *response_code {
+            ResponseCode::NoError => return None,
+            ResponseCode::NXDomain => io::Error::new(ErrorKind::NotFound, "NXDOMAIN"),
+            ResponseCode::ServFail => io::Error::other("SERVFAIL"),
+            ResponseCode::Refused => io::Error::new(ErrorKind::PermissionDenied, "REFUSED"),
+            code => io::Error::other(format!("DNS error code {}", u16::from(code))),
+        },
+        ResolveErrorKind::Timeout => io::Error::new(ErrorKind::TimedOut, no_answer()),
+        ResolveErrorKind::Proto(e) if matches!(e.kind(), ProtoErrorKind::Timeout) => {
+            io::Error::new(ErrorKind::TimedOut, no_answer())
+        }
+        ResolveErrorKind::Io(e) => io::Error::new(e.kind(), e.to_string()),
+        _ => io::Error::other(e.to_string()),
+    })
+}
+
+#[cfg(feature = "custom-dns")]
+fn no_answer() -> String {
+    format!("no answer after {QUERY_TRIES} queries")
+}
+
+/// What `host` is knocked at without asking DNS, as `--no-dns` has it:
+/// the host itself when it is an IP literal, or else the first address it
+/// is pinned to. Any other name is [`AppError::DnsForbidden`].
+pub fn without_dns(host: &str, pins: &[ResolvePin]) -> Result<String, AppError> {
+    if literal(host).is_some() {
+        return Ok(host.to_string());
+    }
+    match pins.iter().find(|pin| pin.matches(host)) {
+        Some(pin) => Ok(pin.addr.to_string()),
+        None => Err(AppError::DnsForbidden {
+            what: "host",
+            name: host.to_string(),
+        }),
+    }
+}
+
+/// [`AppError::DnsForbidden`] unless `name`, the `what` of the run, is an
+/// IP literal needing no lookup.
+pub fn require_literal(what: &'static str, name: &str) -> Result<(), AppError> {
+    match literal(name) {
+        Some(_) => Ok(()),
+        None => Err(AppError::DnsForbidden {
+            what,
+            name: name.to_string(),
+        }),
+    }
+}
+
+/// An IP literal, with or without brackets, or a zoned IPv6 literal.
+pub(crate) fn literal(host: &str) -> Option<SocketAddr> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    match scope::parse_scoped(unbracketed).ok().flatten() {
+        Some(addr) => Some(SocketAddr::V6(addr)),
+        None => unbracketed
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, 0)),
+    }
+}
+
+/// Drop or reorder addresses by family; the sort is stable, so the
+/// resolver's order is kept within a family.
+pub(crate) fn apply_strategy(addrs: &mut Vec<SocketAddr>, strategy: ResolveStrategy) {
+    match strategy {
+        ResolveStrategy::All => {}
+        ResolveStrategy::PreferV4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+        ResolveStrategy::PreferV6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+        ResolveStrategy::OnlyV4 => addrs.retain(SocketAddr::is_ipv4),
+        ResolveStrategy::OnlyV6 => addrs.retain(SocketAddr::is_ipv6),
+    }
+}
+
+/// A host name pinned to an address, as curl's `--resolve` does: knocks
+/// to the host go to the address without asking any resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvePin {
+    pub host: String,
+    pub addr: IpAddr,
+}
+
+/// `HOST:ADDR`, e.g. `example.com:203.0.113.7`; an IPv6 address may be
+/// bracketed, e.g. `example.com:[2001:db8::7]`.
+impl std::str::FromStr for ResolvePin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some((host, addr)) = s.split_once(':') else {
+            return Err(format!("'{s}' is not HOST:ADDR"));
+        };
+        if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+            return Err(format!("'{s}' does not start with a host name"));
+        }
+        scope::ascii_host(host)?;
+        let unbracketed = addr
+            .strip_prefix('[')
+            .and_then(|a| a.strip_suffix(']'))
+            .unwrap_or(addr);
+        let addr = unbracketed
+            .parse()
+            .map_err(|_| format!("'{addr}' in '{s}' is not an IP address"))?;
+        Ok(Self {
+            host: host.to_string(),
+            addr,
+        })
+    }
+}
+
+/// The pin as [`FromStr`](std::str::FromStr) takes it.
+impl std::fmt::Display for ResolvePin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.addr {
+            IpAddr::V6(addr) => write!(f, "{}:[{addr}]", self.host),
+            IpAddr::V4(addr) => write!(f, "{}:{addr}", self.host),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(ResolvePin);
+
+impl ResolvePin {
+    /// Whether the pin is for `host`, compared as the resolver would see
+    /// both names.
+    pub fn matches(&self, host: &str) -> bool {
+        let key = |h: &str| scope::ascii_host(h).map(|a| a.to_ascii_lowercase());
+        matches!((key(&self.host), key(host)), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+/// The addresses `host` is pinned to by `pins`, in the order given and
+/// filtered and ordered by `strategy` like resolved ones; `None` when no
+/// pin is for `host`.
+pub fn pinned(
+    pins: &[ResolvePin],
+    host: &str,
+    strategy: ResolveStrategy,
+) -> Option<Result<Vec<SocketAddr>, AppError>> {
+    let mut addrs: Vec<SocketAddr> = pins
+        .iter()
+        .filter(|pin| pin.matches(host))
+        .map(|pin| SocketAddr::new(pin.addr, 0))
+        .collect();
+    if addrs.is_empty() {
+        return None;
+    }
+    apply_strategy(&mut addrs, strategy);
+    Some(match addrs.is_empty() {
+        true => Err(AppError::NoDns),
+        false => Ok(addrs),
+    })
+}
+
+/// The pins for none of `hosts`, most likely typos.
+pub fn unused_pins<'a>(pins: &'a [ResolvePin], hosts: &[&str]) -> Vec<&'a ResolvePin> {
+    pins.iter()
+        .filter(|pin| !hosts.iter().any(|host| pin.matches(host)))
+        .collect()
+}
+
+/// When a run looks the host up again instead of reusing the addresses of
+/// an earlier run of the same config (see [`DnsCache`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum ResolutionPolicy {
+    /// Resolve on the first run and keep those addresses.
+    #[default]
+    Once,
+    /// Resolve again before every run.
+    EveryRound,
+    /// Keep the addresses for this long, then resolve again.
+    Ttl(#[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::millis"))] Duration),
+}
+
+/// The addresses a host resolved to, kept between runs according to a
+/// [`ResolutionPolicy`]. Clones of a config share one cache, so every run
+/// of it is a round.
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    entry: Mutex<Option<CacheEntry>>,
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    host: String,
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+}
+
+/// What [`DnsCache::lookup`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    pub addrs: Vec<SocketAddr>,
+    /// The cached addresses, when the host was resolved again and no longer
+    /// resolves to them.
+    pub changed_from: Option<Vec<SocketAddr>>,
+}
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The addresses of `host`: the cached ones while `policy` allows,
+    /// otherwise fresh ones from `resolve`, which are cached in turn. A
+    /// failed lookup keeps the old entry for the next attempt.
+    pub async fn lookup<F, Fut>(
+        &self,
+        host: &str,
+        policy: ResolutionPolicy,
+        resolve: F,
+    ) -> Result<Lookup, AppError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<SocketAddr>, AppError>>,
+    {
+        // A different host, e.g. after editing the config, starts afresh
+        let cached = self
+            .entry
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|e| e.host == host);
+        if let Some(entry) = &cached {
+            let fresh = match policy {
+                ResolutionPolicy::Once => true,
+                ResolutionPolicy::EveryRound => false,
+                ResolutionPolicy::Ttl(ttl) => entry.resolved_at.elapsed() < ttl,
+            };
+            if fresh {
+                return Ok(Lookup {
+                    addrs: entry.addrs.clone(),
+                    changed_from: None,
+                });
+            }
+        }
+
+        let addrs = resolve().await?;
+        *self.entry.lock().unwrap() = Some(CacheEntry {
+            host: host.to_string(),
+            addrs: addrs.clone(),
+            resolved_at: Instant::now(),
+        });
+        let changed_from = cached.map(|e| e.addrs).filter(|old| *old != addrs);
+        Ok(Lookup {
+            addrs,
+            changed_from,
+        })
+    }
+}
+
+/// Check that `name` can be encoded as a DNS question name.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    if trimmed.is_empty() || trimmed.len() > 253 {
+        return Err(format!("DNS name '{name}' must be 1-253 characters"));
+    }
+    for label in trimmed.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("DNS name '{name}' has an invalid label '{label}'"));
+        }
+        if !label.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(format!(
+                "DNS name '{name}' has a non-printable label '{label}'"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Build a standard recursive query with a single question for `name`.
+/// `name` must have passed [`validate_name`].
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut pkt = Vec::with_capacity(18 + name.len());
+    pkt.extend_from_slice(&id.to_be_bytes());
+    pkt.extend_from_slice(&0x0100u16.to_be_bytes()); // RD
+    pkt.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    pkt.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // AN/NS/ARCOUNT
+    for label in name.strip_suffix('.').unwrap_or(name).split('.') {
+        pkt.push(label.len() as u8);
+        pkt.extend_from_slice(label.as_bytes());
+    }
+    pkt.push(0);
+    pkt.extend_from_slice(&qtype.to_be_bytes());
+    pkt.extend_from_slice(&CLASS_IN.to_be_bytes());
+    pkt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn literals_resolve_to_themselves() {
+        let all = ResolveStrategy::All;
+        let addrs = resolve_target("127.0.0.1", all).await.unwrap();
+        assert_eq!(addrs, ["127.0.0.1:0".parse::<SocketAddr>().unwrap()]);
+        let v6: SocketAddr = "[::1]:0".parse().unwrap();
+        assert_eq!(resolve_target("::1", all).await.unwrap(), [v6]);
+        assert_eq!(resolve_target("[::1]", all).await.unwrap(), [v6]);
+    }
+
+    #[test]
+    fn strategy_filters_and_orders() {
+        let addrs: Vec<SocketAddr> = ["[::1]:0", "10.0.0.1:0", "[::2]:0", "10.0.0.2:0"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let apply = |strategy| {
+            let mut addrs = addrs.clone();
+            apply_strategy(&mut addrs, strategy);
+            addrs
+        };
+        assert_eq!(apply(ResolveStrategy::All), addrs);
+        assert_eq!(
+            apply(ResolveStrategy::PreferV4),
+            [addrs[1], addrs[3], addrs[0], addrs[2]]
+        );
+        assert_eq!(
+            apply(ResolveStrategy::PreferV6),
+            [addrs[0], addrs[2], addrs[1], addrs[3]]
+        );
+        assert_eq!(apply(ResolveStrategy::OnlyV4), [addrs[1], addrs[3]]);
+        assert_eq!(apply(ResolveStrategy::OnlyV6), [addrs[0], addrs[2]]);
+    }
+
+    #[test]
+    fn pins_parse_and_match_like_curl_resolve() {
+        let pin: ResolvePin = "example.com:203.0.113.7".parse().unwrap();
+        assert_eq!(pin.addr, IpAddr::from([203, 0, 113, 7]));
+        assert!(pin.matches("EXAMPLE.com."));
+        assert!(!pin.matches("www.example.com"));
+        for v6 in ["h.example:2001:db8::7", "h.example:[2001:db8::7]"] {
+            let pin: ResolvePin = v6.parse().unwrap();
+            assert_eq!(pin.addr, "2001:db8::7".parse::<IpAddr>().unwrap());
+        }
+        let idn: ResolvePin = "bücher.example:10.0.0.1".parse().unwrap();
+        assert!(idn.matches("xn--bcher-kva.example"));
+        assert!("example.com".parse::<ResolvePin>().is_err());
+        assert!("example.com:443:10.0.0.1".parse::<ResolvePin>().is_err());
+        assert!("10.0.0.1:10.0.0.2".parse::<ResolvePin>().is_err());
+    }
+
+    #[test]
+    fn pinned_hosts_skip_the_resolver() {
+        let pins: Vec<ResolvePin> = ["a.example:10.0.0.1", "b.example:10.0.0.2", "a.example:::1"]
+            .iter()
+            .map(|p| p.parse().unwrap())
+            .collect();
+        let addr = |a: &str| SocketAddr::new(a.parse().unwrap(), 0);
+        let all = ResolveStrategy::All;
+        assert_eq!(
+            pinned(&pins, "a.example", all).unwrap().unwrap(),
+            [addr("10.0.0.1"), addr("::1")]
+        );
+        let v6 = pinned(&pins, "a.example", ResolveStrategy::OnlyV6);
+        assert_eq!(v6.unwrap().unwrap(), [addr("::1")]);
+        let none = pinned(&pins, "b.example", ResolveStrategy::OnlyV6);
+        assert!(matches!(none, Some(Err(AppError::NoDns))));
+        assert!(pinned(&pins, "c.example", all).is_none());
+        assert_eq!(unused_pins(&pins, &["a.example"]), [&pins[1]]);
+    }
+
+    #[tokio::test]
+    async fn filtering_out_every_address_is_no_dns() {
+        let addrs = resolve_target("::1", ResolveStrategy::OnlyV4).await;
+        assert!(matches!(addrs, Err(AppError::NoDns)));
+    }
+
+    #[tokio::test]
+    async fn unresolvable_name_is_a_resolve_error() {
+        let err = resolve_target("no-such-host.invalid", ResolveStrategy::All)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, AppError::Resolve { ref host, .. } if host == "no-such-host.invalid")
+        );
+        assert_eq!(err.exit_code(), 3);
+    }
+
+    #[test]
+    fn a_query_wire_format() {
+        let pkt = build_query(0xbeef, "example.com", TYPE_A);
+        let expected = [
+            0xbe, 0xef, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, // qname
+            0x00, 0x01, 0x00, 0x01, // A, IN
+        ];
+        assert_eq!(pkt, expected);
+        assert_eq!(build_query(0xbeef, "example.com.", TYPE_A), expected);
+    }
+
+    #[test]
+    fn name_validation() {
+        assert!(validate_name("example.com").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a..b").is_err());
+        assert!(validate_name(&"x".repeat(64)).is_err());
+        assert!(validate_name("bad name.com").is_err());
+    }
+
+    /// A resolver that answers with `addr`.
+    fn answer(addr: &str) -> impl Future<Output = Result<Vec<SocketAddr>, AppError>> {
+        std::future::ready(Ok(vec![addr.parse().unwrap()]))
+    }
+
+    async fn unreachable() -> Result<Vec<SocketAddr>, AppError> {
+        panic!("the cached addresses should have been used")
+    }
+
+    #[tokio::test]
+    async fn once_keeps_the_first_answer() {
+        let cache = DnsCache::new();
+        let first = cache
+            .lookup("h", ResolutionPolicy::Once, || answer("10.0.0.1:0"))
+            .await
+            .unwrap();
+        assert_eq!(first.changed_from, None);
+        let again = cache.lookup("h", ResolutionPolicy::Once, unreachable).await;
+        assert_eq!(again.unwrap().addrs, first.addrs);
+        // Another host is not served from the cache
+        let other = cache.lookup("other", ResolutionPolicy::Once, || answer("10.0.0.9:0"));
+        assert_eq!(other.await.unwrap().addrs[0].to_string(), "10.0.0.9:0");
+    }
+
+    #[tokio::test]
+    async fn every_round_reports_a_change() {
+        let cache = DnsCache::new();
+        let policy = ResolutionPolicy::EveryRound;
+        cache
+            .lookup("h", policy, || answer("10.0.0.1:0"))
+            .await
+            .unwrap();
+        let same = cache.lookup("h", policy, || answer("10.0.0.1:0")).await;
+        assert_eq!(same.unwrap().changed_from, None);
+        let moved = cache
+            .lookup("h", policy, || answer("10.0.0.2:0"))
+            .await
+            .unwrap();
+        assert_eq!(moved.addrs, ["10.0.0.2:0".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(
+            moved.changed_from,
+            Some(vec!["10.0.0.1:0".parse().unwrap()])
+        );
+
+        // A failed lookup keeps the entry to compare the next answer with
+        let failed = cache
+            .lookup("h", policy, || async { Err(AppError::NoDns) })
+            .await;
+        assert!(failed.is_err());
+        let back = cache
+            .lookup("h", policy, || answer("10.0.0.1:0"))
+            .await
+            .unwrap();
+        assert_eq!(back.changed_from, Some(vec!["10.0.0.2:0".parse().unwrap()]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_expires() {
+        let cache = DnsCache::new();
+        let policy = ResolutionPolicy::Ttl(Duration::from_secs(60));
+        cache
+            .lookup("h", policy, || answer("10.0.0.1:0"))
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(59)).await;
+        cache.lookup("h", policy, unreachable).await.unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let expired = cache.lookup("h", policy, || answer("10.0.0.2:0")).await;
+        assert!(expired.unwrap().changed_from.is_some());
+    }
+
+    /// Answer `query` with `rcode` and, for A queries, each of `v4`.
+    #[cfg(feature = "custom-dns")]
+    fn response(query: &[u8], rcode: u8, v4: &[[u8; 4]]) -> Vec<u8> {
+        let mut pkt = query.to_vec();
+        pkt[2] |= 0x80;
+        pkt[3] |= rcode;
+        let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+        let v4 = if qtype == TYPE_A { v4 } else { &[] };
+        pkt[6..8].copy_from_slice(&(v4.len() as u16).to_be_bytes());
+        for ip in v4 {
+            pkt.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            pkt.extend_from_slice(ip);
+        }
+        pkt
+    }
+
+    /// A DNS server on localhost answering every query with `rcode` and
+    /// `v4`, or never answering with `None`.
+    #[cfg(feature = "custom-dns")]
+    async fn fake_server(answer: Option<(u8, Vec<[u8; 4]>)>) -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                if let Some((rcode, v4)) = &answer {
+                    let _ = socket.send_to(&response(&buf[..n], *rcode, v4), peer).await;
+                }
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "custom-dns")]
+    #[tokio::test]
+    async fn custom_server_answers() {
+        let server = fake_server(Some((0, vec![[10, 1, 2, 3]]))).await;
+        let addrs = resolve_via(server, "knock.internal", ResolveStrategy::All)
+            .await
+            .unwrap();
+        assert_eq!(addrs, ["10.1.2.3:0".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[cfg(feature = "custom-dns")]
+    #[tokio::test]
+    async fn truncated_answers_are_asked_for_over_tcp() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Over UDP only that the answer does not fit, over TCP the answer
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = tokio::net::TcpListener::bind(server).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = udp.recv_from(&mut buf).await {
+                let mut pkt = response(&buf[..n], 0, &[]);
+                pkt[2] |= 0x02;
+                let _ = udp.send_to(&pkt, peer).await;
+            }
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let mut query = vec![0u8; usize::from(stream.read_u16().await.unwrap())];
+            stream.read_exact(&mut query).await.unwrap();
+            let pkt = response(&query, 0, &[[10, 9, 8, 7]]);
+            stream.write_u16(pkt.len() as u16).await.unwrap();
+            stream.write_all(&pkt).await.unwrap();
+        });
+        let addrs = resolve_via(server, "knock.internal", ResolveStrategy::OnlyV4)
+            .await
+            .unwrap();
+        assert_eq!(addrs, ["10.9.8.7:0".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[cfg(feature = "custom-dns")]
+    #[tokio::test]
+    async fn servfail_names_the_server() {
+        let server = fake_server(Some((2, Vec::new()))).await;
+        let err = resolve_via(server, "knock.internal", ResolveStrategy::All)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Resolve { server: Some(s), .. } if s == server));
+        assert_eq!(
+            err.to_string(),
+            format!("could not resolve knock.internal via {server}: SERVFAIL")
+        );
+    }
+
+    #[cfg(feature = "custom-dns")]
+    #[tokio::test(start_paused = true)]
+    async fn silent_server_times_out() {
+        let server = fake_server(None).await;
+        let err = resolve_via(server, "knock.internal", ResolveStrategy::All)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::Resolve { server: Some(_), ref source, .. }
+                if source.kind() == io::ErrorKind::TimedOut
+        ));
+    }
+}
@@ -0,0 +1,50 @@
+//! The one duration grammar of the command line and knock URIs.
+//!
+//! A number with a unit as humantime reads it: `250us`, `1.5ms`, `2s`,
+//! `1m`, `90d`, or several summed like `1m30s`. Each option decides what a
+//! bare number means, if anything, and the range it takes.
+
+use std::time::Duration;
+
+/// Parse `s`, a bare number counting `bare_unit`s, or refused without a
+/// unit when there is none.
+pub fn parse(s: &str, bare_unit: Option<Duration>) -> Result<Duration, String> {
+    let s = s.trim();
+    if let (Some(unit), Ok(n)) = (bare_unit, s.parse::<f64>()) {
+        return match n >= 0.0 && (n * unit.as_secs_f64()) < u64::MAX as f64 {
+            true => Ok(unit.mul_f64(n)),
+            false => Err(format!("'{s}' is not a duration")),
+        };
+    }
+    humantime::parse_duration(s).map_err(|e| {
+        let example = match bare_unit {
+            Some(_) => "250us, 1.5ms, 2s, 1m30s or 1h",
+            None => "500ms, 2s or 1m",
+        };
+        format!("'{s}' is not a duration like {example} ({e})")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_fractions_and_bare_numbers() {
+        let ms = Some(Duration::from_millis(1));
+        assert_eq!(parse("250us", ms), Ok(Duration::from_micros(250)));
+        assert_eq!(parse("250µs", ms), Ok(Duration::from_micros(250)));
+        assert_eq!(parse("1.5ms", ms), Ok(Duration::from_micros(1500)));
+        assert_eq!(parse("1m30s", ms), Ok(Duration::from_secs(90)));
+        assert_eq!(parse("90d", ms), Ok(Duration::from_secs(90 * 86_400)));
+        assert_eq!(parse("100", ms), Ok(Duration::from_millis(100)));
+        assert_eq!(parse("2.5", ms), Ok(Duration::from_micros(2500)));
+        let secs = Some(Duration::from_secs(1));
+        assert_eq!(parse("30", secs), Ok(Duration::from_secs(30)));
+
+        assert!(parse("100", None).is_err());
+        for bad in ["", "ms", "-1", "5 parsecs", "1e400"] {
+            assert!(parse(bad, ms).is_err(), "{bad}");
+        }
+    }
+}
@@ -1,3 +1,10 @@
+use crate::hooks::HookPoint;
+use crate::outcome::KnockFailure;
+use crate::protocol::Protocol;
+use crate::securedns::SecureDns;
+use crate::shutdown::ShutdownSignal;
+use std::io;
+use std::net::SocketAddr;
 use thiserror::Error;
 
 /// Top-level application errors
@@ -6,6 +13,393 @@ pub enum AppError {
     #[error("network I/O error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+
     #[error("no DNS records found for target")]
     NoDns,
+
+    #[error("could not resolve {host}{}: {source}", via(.server))]
+    Resolve {
+        host: String,
+        /// The DNS server asked, when not the system resolver.
+        server: Option<SocketAddr>,
+        source: std::io::Error,
+    },
+
+    /// A name that would have to be looked up while `--no-dns` forbids
+    /// every DNS query: what it names, and the name.
+    #[error("{what} {name} is not an IP address, and --no-dns forbids looking it up")]
+    DnsForbidden { what: &'static str, name: String },
+
+    /// A plugin of a `plugin` step that cannot be used.
+    #[error("{0}")]
+    Plugin(String),
+
+    #[error("could not resolve {host} over {server}: {reason}")]
+    SecureResolve {
+        host: String,
+        server: SecureDns,
+        reason: String,
+    },
+
+    #[error("could not bind a local socket on {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+
+    /// An OS error no retry can fix, with what to check.
+    #[error("{source}: {hint}")]
+    LocalFailure {
+        source: std::io::Error,
+        hint: &'static str,
+    },
+
+    /// A datagram the kernel refused as too big for the path (EMSGSIZE).
+    #[error(
+        "{size}-byte datagram is too large to send{}; shrink the payload or --pad-to",
+        mtu.map(|mtu| format!(" over a path MTU of {mtu}")).unwrap_or_default()
+    )]
+    Oversized { size: usize, mtu: Option<u32> },
+
+    /// A UDP knock sent to a broadcast address without `--broadcast`.
+    #[error("{addr} is a broadcast address; pass --broadcast to knock every host on the segment")]
+    Broadcast { addr: SocketAddr },
+
+    #[error("no free source port in {first}-{last} after {attempts} attempt(s)")]
+    SourcePorts {
+        first: u16,
+        last: u16,
+        attempts: usize,
+    },
+
+    #[error("{protocol} knock on port {port} failed after {attempts} attempt(s): {last_error}")]
+    KnockFailed {
+        port: u16,
+        protocol: Protocol,
+        attempts: usize,
+        last_error: String,
+    },
+
+    #[error("knock on port {port} timed out after {attempts} attempt(s)")]
+    Timeout { port: u16, attempts: usize },
+
+    /// Every pass of the sequence ran past the window.
+    #[error("the sequence ran past the {window}ms window on all {passes} pass(es)")]
+    WindowOverrun { window: u64, passes: usize },
+
+    /// Another run holds the host's lock (`--lock`), after waiting
+    /// `waited` milliseconds for it.
+    #[error("another run{} is knocking {host}{}; not knocking over it", holder(*pid), after(*waited))]
+    Locked {
+        host: String,
+        pid: Option<u32>,
+        waited: u64,
+    },
+
+    /// The local clock is further off the SNTP server's than allowed
+    /// (`--strict-clock`); `skew` is its lead in milliseconds.
+    #[error("local clock is {} {server}, more than the {max}s allowed; time-based knocks would be rejected", crate::sntp::describe_skew(*skew))]
+    ClockSkew { server: String, skew: i64, max: u64 },
+
+    /// The STUN server could not tell the public address, and
+    /// `--require-public-ip` leaves no falling back to the local one.
+    #[error("could not learn the public address from STUN server {server}: {reason}")]
+    PublicIp { server: String, reason: String },
+
+    /// The circuit breaker opened: `failed` steps in a row could not reach
+    /// the host, and the `skipped` after them were not sent.
+    #[error("{host} looks unreachable: {failed} steps in a row got no answer; circuit open, the remaining {skipped} step(s) skipped")]
+    CircuitOpen {
+        host: String,
+        failed: usize,
+        skipped: usize,
+    },
+
+    /// A lockstep knock that got no reply in time; the rest of the
+    /// sequence was not sent.
+    #[error("no reply to the knock on port {port} within {waited}ms; sequence stopped")]
+    Lockstep { port: u16, waited: u64 },
+
+    /// No rung of a knock ladder verified, or the ladder ran out of time
+    /// after `tried` of its `rungs`.
+    #[error("{}: {reason}", ladder_failed(*tried, *rungs))]
+    Ladder {
+        tried: usize,
+        rungs: usize,
+        reason: String,
+    },
+
+    #[error("{} of {} knocks failed: {}", failed.len(), failed.len() + succeeded, list(failed))]
+    Partial {
+        failed: Vec<KnockFailure>,
+        succeeded: usize,
+    },
+
+    #[error("{} of {total} hosts failed: {}", failed.len(), failed.join(", "))]
+    Hosts { failed: Vec<String>, total: usize },
+
+    #[error("confirmation failed: {0}")]
+    Confirm(String),
+
+    /// A hook command failed: the pre-hook, which stops the run, or with
+    /// strict hooks the post-hook after a run that succeeded.
+    #[error("{point}-hook failed: {reason}")]
+    Hook { point: HookPoint, reason: String },
+
+    #[error("raw socket error: {0}")]
+    RawSocket(String),
+
+    #[error("SOCKS5 proxy error: {0}")]
+    Proxy(String),
+
+    #[error("SSH jump host error: {0}")]
+    Jump(String),
+
+    #[error("SPA error: {0}")]
+    Spa(String),
+
+    #[error("reply capture error: {0}")]
+    Replies(String),
+
+    #[error("history database error: {0}")]
+    History(String),
+
+    #[error("payload error: {0}")]
+    Payload(String),
+
+    #[error("TOTP sequence error: {0}")]
+    Totp(String),
+
+    #[error("passphrase sequence error: {0}")]
+    Passphrase(String),
+
+    #[error("TXT sequence error: {0}")]
+    TxtSequence(String),
+
+    /// A `--script` that could not be read or run, or whose plan is no
+    /// knock sequence.
+    #[error("script error: {0}")]
+    Script(String),
+
+    #[error("payload signing error: {0}")]
+    Sign(String),
+
+    #[error("payload encryption error: {0}")]
+    Crypto(String),
+
+    #[error("runtime error: {0}")]
+    Runtime(String),
+
+    #[error("interrupted by {0}")]
+    Interrupted(ShutdownSignal),
+}
+
+impl AppError {
+    /// Process exit code for the binary, one per class of failure:
+    /// 2 bad configuration or key material, 3 name resolution, 4 local
+    /// sockets, 5 a knock that did not get through or did not verify, 6 a
+    /// knock that timed out, 7 some knocks of a run failing, 8 some hosts of a hosts file
+    /// failing and 9 all of them, 128 plus the signal number for
+    /// a run stopped by a signal, 1 anything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::InvalidConfig(_)
+            | AppError::Spa(_)
+            | AppError::Payload(_)
+            | AppError::Totp(_)
+            | AppError::Passphrase(_)
+            | AppError::TxtSequence(_)
+            | AppError::Script(_)
+            | AppError::Sign(_)
+            | AppError::Crypto(_)
+            | AppError::DnsForbidden { .. }
+            | AppError::Plugin(_) => 2,
+            AppError::NoDns | AppError::Resolve { .. } | AppError::SecureResolve { .. } => 3,
+            AppError::Bind { .. }
+            | AppError::LocalFailure { .. }
+            | AppError::SourcePorts { .. }
+            | AppError::Oversized { .. }
+            | AppError::Broadcast { .. }
+            | AppError::RawSocket(_) => 4,
+            AppError::KnockFailed { .. } | AppError::Ladder { .. } => 5,
+            AppError::Timeout { .. }
+            | AppError::Lockstep { .. }
+            | AppError::CircuitOpen { .. }
+            | AppError::WindowOverrun { .. } => 6,
+            AppError::Partial { .. } => 7,
+            AppError::Hosts { failed, total } if failed.len() < *total => 8,
+            AppError::Hosts { .. } => 9,
+            AppError::Interrupted(signal) => signal.exit_code(),
+            AppError::Io(_)
+            | AppError::Confirm(_)
+            | AppError::Hook { .. }
+            | AppError::Proxy(_)
+            | AppError::Jump(_)
+            | AppError::Replies(_)
+            | AppError::History(_)
+            | AppError::Locked { .. }
+            | AppError::ClockSkew { .. }
+            | AppError::PublicIp { .. }
+            | AppError::Runtime(_) => 1,
+        }
+    }
+
+    /// A [`ErrorClass::Fatal`] OS error, with a hint at its likely cause.
+    pub fn local_failure(source: io::Error) -> Self {
+        let hint = if is_msgsize(&source) {
+            "the datagram is larger than the path allows; shrink the payload or --pad-to"
+        } else if source.kind() == io::ErrorKind::AddrNotAvailable {
+            "no local address can reach the target; check the interface and routes"
+        } else {
+            "refused by this host; check local firewall rules (e.g. an OUTPUT drop) \
+             and that the process may send there"
+        };
+        AppError::LocalFailure { source, hint }
+    }
+
+    /// How the OS error behind this one, if any, bears on the knock.
+    pub fn class(&self) -> Option<ErrorClass> {
+        match self {
+            AppError::LocalFailure { .. }
+            | AppError::Oversized { .. }
+            | AppError::Broadcast { .. } => Some(ErrorClass::Fatal),
+            AppError::Io(e) => Some(ErrorClass::of(e)),
+            _ => None,
+        }
+    }
+}
+
+/// How an OS socket error bears on a knock, and so whether to retry it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ErrorClass {
+    /// No retry will change it: the local host forbids the send (EACCES,
+    /// EPERM), has no address to send from, or the knock itself is wrong
+    /// (EMSGSIZE).
+    Fatal,
+    /// May clear up, e.g. a route that comes back: worth another attempt.
+    Retryable,
+    /// The target host answered (a refusal), so the knock reached it.
+    Delivered,
+}
+
+impl ErrorClass {
+    /// Classify an error from a connect, send or receive.
+    pub fn of(e: &io::Error) -> Self {
+        if is_msgsize(e) {
+            return ErrorClass::Fatal;
+        }
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => ErrorClass::Delivered,
+            io::ErrorKind::PermissionDenied | io::ErrorKind::AddrNotAvailable => ErrorClass::Fatal,
+            _ => ErrorClass::Retryable,
+        }
+    }
+}
+
+/// EMSGSIZE, which has no `io::ErrorKind` of its own.
+pub(crate) fn is_msgsize(e: &io::Error) -> bool {
+    let emsgsize = if cfg!(windows) {
+        10040 // WSAEMSGSIZE
+    } else if cfg!(any(target_os = "linux", target_os = "android")) {
+        90
+    } else {
+        40
+    };
+    e.raw_os_error() == Some(emsgsize)
+}
+
+fn via(server: &Option<SocketAddr>) -> String {
+    server.map(|s| format!(" via {s}")).unwrap_or_default()
+}
+
+fn holder(pid: Option<u32>) -> String {
+    pid.map(|pid| format!(" (PID {pid})")).unwrap_or_default()
+}
+
+fn after(waited: u64) -> String {
+    match waited {
+        0 => String::new(),
+        waited => format!(" still after {waited}ms"),
+    }
+}
+
+fn ladder_failed(tried: usize, rungs: usize) -> String {
+    match (tried, rungs) {
+        (_, 1) => "the knocks did not verify".into(),
+        (tried, rungs) if tried == rungs => format!("none of the {rungs} ladder rungs verified"),
+        (tried, rungs) => format!("no ladder rung verified ({tried} of {rungs} tried)"),
+    }
+}
+
+fn list(failed: &[KnockFailure]) -> String {
+    let failed: Vec<String> = failed.iter().map(ToString::to_string).collect();
+    failed.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knock_errors_name_the_port() {
+        let err = AppError::KnockFailed {
+            port: 7000,
+            protocol: Protocol::Tcp,
+            attempts: 3,
+            last_error: "connection reset".into(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "tcp knock on port 7000 failed after 3 attempt(s): connection reset"
+        );
+        assert_eq!(err.exit_code(), 5);
+        let err = AppError::Timeout {
+            port: 8000,
+            attempts: 2,
+        };
+        assert_eq!(
+            err.to_string(),
+            "knock on port 8000 timed out after 2 attempt(s)"
+        );
+        assert_ne!(err.exit_code(), AppError::NoDns.exit_code());
+    }
+
+    #[test]
+    fn os_errors_are_classified() {
+        use io::ErrorKind::*;
+        for (kind, class) in [
+            (ConnectionRefused, ErrorClass::Delivered),
+            (PermissionDenied, ErrorClass::Fatal),
+            (AddrNotAvailable, ErrorClass::Fatal),
+            (NetworkUnreachable, ErrorClass::Retryable),
+            (HostUnreachable, ErrorClass::Retryable),
+            (ConnectionReset, ErrorClass::Retryable),
+            (TimedOut, ErrorClass::Retryable),
+        ] {
+            assert_eq!(ErrorClass::of(&io::Error::from(kind)), class, "{kind:?}");
+        }
+        #[cfg(target_os = "linux")]
+        for (errno, class) in [
+            (libc::EACCES, ErrorClass::Fatal),
+            (libc::EPERM, ErrorClass::Fatal),
+            (libc::ENETUNREACH, ErrorClass::Retryable),
+            (libc::EHOSTUNREACH, ErrorClass::Retryable),
+            (libc::EMSGSIZE, ErrorClass::Fatal),
+        ] {
+            let e = io::Error::from_raw_os_error(errno);
+            assert_eq!(ErrorClass::of(&e), class, "errno {errno}");
+        }
+
+        let err = AppError::local_failure(io::Error::from(PermissionDenied));
+        assert_eq!(err.class(), Some(ErrorClass::Fatal));
+        assert!(err.to_string().contains("firewall"), "{err}");
+        assert_eq!(err.exit_code(), 4);
+    }
 }
@@ -0,0 +1,223 @@
+use crate::hexdump::Direction;
+use crate::observer::{AttemptInfo, AttemptResult, KnockObserver};
+use crate::outcome::{KnockOutcome, KnockReport};
+use crate::plan::StepKind;
+use crate::protocol::Protocol;
+use futures::channel::mpsc::UnboundedSender;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Progress of a run, emitted as it happens by
+/// [`run_with_events`](crate::run_with_events) and handed to
+/// [`KnockObserver::on_event`]. Every line the binary prints comes from one
+/// of these.
+#[derive(Debug, Clone)]
+pub enum KnockEvent {
+    /// The host name resolved to these addresses (none with a proxy).
+    Resolved {
+        host: String,
+        addrs: Vec<SocketAddr>,
+    },
+    /// The host was resolved again and its addresses changed since an
+    /// earlier run of the same config.
+    AddressesChanged {
+        host: String,
+        previous: Vec<SocketAddr>,
+        addrs: Vec<SocketAddr>,
+    },
+    /// Several addresses resolved and the sequence sticks to this one.
+    AddressChosen { host: String, addr: SocketAddr },
+    /// The knocks about to be sent, as a readable listing; emitted for
+    /// `dry_run` and `confirm`, before anything is sent.
+    Plan { text: String },
+    /// A knock is about to be sent.
+    KnockStarted { target: KnockTarget },
+    /// One attempt of a knock failed; another may follow.
+    AttemptFailed {
+        target: KnockTarget,
+        attempt: usize,
+        error: String,
+    },
+    /// A knock got through; `detail` says how, e.g. `OK` or `SENT 16 bytes`.
+    KnockSucceeded {
+        target: KnockTarget,
+        attempt: usize,
+        latency: Duration,
+        detail: String,
+    },
+    /// The reply to a knock was written to `path` (`--save-replies`);
+    /// `truncated` when it was longer than what was kept.
+    ReplySaved {
+        target: KnockTarget,
+        path: PathBuf,
+        bytes: usize,
+        truncated: bool,
+    },
+    /// Bytes a knock sent or received, dumped with `--hexdump`; `len` is
+    /// the full length, `dump` the [`crate::hexdump`] of the bytes within
+    /// the limit.
+    Hexdump {
+        target: KnockTarget,
+        direction: Direction,
+        attempt: usize,
+        len: usize,
+        dump: String,
+    },
+    /// A knock used up its attempts without getting through.
+    KnockFailed {
+        target: KnockTarget,
+        attempts: usize,
+    },
+    /// Something worth knowing that is not a failure, such as an ignored
+    /// reply.
+    Notice {
+        target: Option<KnockTarget>,
+        message: String,
+    },
+    /// The run is over, also when it was interrupted. Always the last
+    /// event of a run that got past setup; a setup error ends the stream
+    /// without it.
+    Finished { report: Box<KnockReport> },
+}
+
+/// The knock an event is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnockTarget {
+    /// Host name, or the address when knocking every resolved address.
+    pub host: String,
+    /// Port, or the echo payload size for ICMP.
+    pub port: u16,
+    pub protocol: Protocol,
+    /// Per-step knock type, for HTTP and TLS steps.
+    pub step: Option<StepKind>,
+}
+
+impl KnockTarget {
+    pub fn new(host: &str, port: u16, protocol: Protocol) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            protocol,
+            step: None,
+        }
+    }
+}
+
+/// The prefix of every log line about this knock, e.g. `TCP example.com:7000`.
+impl fmt::Display for KnockTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (host, port) = (&self.host, self.port);
+        match (&self.step, self.protocol) {
+            (Some(StepKind::Http { path }), _) => write!(f, "HTTP {host}:{port}{path}"),
+            (Some(StepKind::Tls { .. }), _) => write!(f, "TLS {host}:{port}"),
+            (Some(StepKind::Quic), _) => write!(f, "QUIC {host}:{port}"),
+            (Some(StepKind::Plugin { .. }), _) => write!(f, "PLUGIN {host}:{port}"),
+            (None, Protocol::Icmp) => write!(f, "ICMP {host} size {port}"),
+            (None, protocol) => {
+                let name = format!("{protocol:?}").to_uppercase();
+                write!(f, "{name} {host}:{port}")
+            }
+        }
+    }
+}
+
+/// Where the knock code sends its events and observer hooks; a run
+/// without a listener or observer uses the default sink, which drops them.
+#[derive(Clone, Default)]
+pub(crate) struct EventSink {
+    tx: Option<UnboundedSender<KnockEvent>>,
+    observer: Option<Arc<dyn KnockObserver + Send + Sync>>,
+}
+
+impl EventSink {
+    #[cfg(any(test, feature = "runtime-tokio"))]
+    pub fn new(tx: UnboundedSender<KnockEvent>) -> Self {
+        Self {
+            tx: Some(tx),
+            observer: None,
+        }
+    }
+
+    pub fn with_observer(mut self, observer: Option<Arc<dyn KnockObserver + Send + Sync>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Hand an event to the observer, then send it; a listener that went
+    /// away is not an error.
+    pub fn emit(&self, event: KnockEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+        if let Some(tx) = &self.tx {
+            let _ = tx.unbounded_send(event);
+        }
+    }
+
+    pub fn notice(&self, target: Option<&KnockTarget>, message: impl Into<String>) {
+        self.emit(KnockEvent::Notice {
+            target: target.cloned(),
+            message: message.into(),
+        });
+    }
+
+    /// Report one attempt to the observer, then as an `AttemptFailed` or
+    /// `KnockSucceeded` event.
+    pub fn attempt(&self, info: AttemptInfo) {
+        if let Some(observer) = &self.observer {
+            observer.on_attempt(&info);
+        }
+        let AttemptInfo {
+            target,
+            attempt,
+            result,
+        } = info;
+        self.emit(match result {
+            AttemptResult::Delivered { latency, detail } => KnockEvent::KnockSucceeded {
+                target,
+                attempt,
+                latency,
+                detail,
+            },
+            AttemptResult::Failed { error } => KnockEvent::AttemptFailed {
+                target,
+                attempt,
+                error,
+            },
+        });
+    }
+
+    /// Report the final outcome of a knock to the observer, and emit
+    /// `KnockFailed` when it did not get through.
+    pub fn result(&self, target: KnockTarget, outcome: &KnockOutcome) {
+        if let Some(observer) = &self.observer {
+            observer.on_result(outcome);
+        }
+        if !outcome.succeeded {
+            self.emit(KnockEvent::KnockFailed {
+                target,
+                attempts: outcome.attempts,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_labels() {
+        let mut target = KnockTarget::new("example.com", 7000, Protocol::Udp);
+        assert_eq!(target.to_string(), "UDP example.com:7000");
+        target.protocol = Protocol::Icmp;
+        assert_eq!(target.to_string(), "ICMP example.com size 7000");
+        target.step = Some(StepKind::Http {
+            path: "/knock".into(),
+        });
+        assert_eq!(target.to_string(), "HTTP example.com:7000/knock");
+    }
+}
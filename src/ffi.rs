@@ -0,0 +1,517 @@
+//! C interface for embedding the knocker in programs written in other
+//! languages; `include/async_port_knocker.h` declares it.
+//!
+//! Functions return [`PK_OK`] or an error code: [`PK_ERR_INVALID_ARGUMENT`]
+//! for a bad argument, [`PK_ERR_PANIC`] for a bug caught before it could
+//! unwind into the caller, and otherwise the binary's exit code for the
+//! error ([`AppError::exit_code`]). [`pk_last_error_message`] describes the
+//! last failure on the calling thread.
+//!
+//! Runs use [`blocking::run`](crate::blocking::run), so they must not be
+//! started from inside a Tokio runtime.
+
+use crate::{
+    blocking, protocol::Protocol, retry::Attempts, AppError, CancellationToken, KnockConfig,
+    KnockStep,
+};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+/// The call succeeded.
+pub const PK_OK: c_int = 0;
+/// A pointer was NULL or a value out of range.
+pub const PK_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The library panicked; the call had no effect beyond what it did before.
+pub const PK_ERR_PANIC: c_int = -2;
+
+pub const PK_PROTOCOL_TCP: c_int = 0;
+pub const PK_PROTOCOL_UDP: c_int = 1;
+pub const PK_PROTOCOL_ICMP: c_int = 2;
+pub const PK_PROTOCOL_SCTP: c_int = 3;
+
+/// A run's settings, built up with the `pk_config_*` functions.
+pub struct PkConfig {
+    config: KnockConfig,
+}
+
+/// Stops a run from another thread; see [`pk_run_with_cancel`].
+pub struct PkCancel {
+    token: CancellationToken,
+}
+
+/// One knock of a [`PkReport`].
+#[repr(C)]
+pub struct PkKnock {
+    /// Port, or the payload size for ICMP.
+    pub port: u16,
+    /// One of the `PK_PROTOCOL_*` constants.
+    pub protocol: c_int,
+    pub succeeded: bool,
+    /// The target answered, rather than the knock only being sent.
+    pub acknowledged: bool,
+    pub attempts: usize,
+    /// Latency of the attempt that got through, or -1.
+    pub latency_ms: i64,
+    /// Time spent on the knock, retries included.
+    pub elapsed_ms: u64,
+}
+
+/// What [`pk_run`] did; free it with [`pk_report_free`].
+#[repr(C)]
+pub struct PkReport {
+    /// `knock_count` knocks, in sequence order.
+    pub knocks: *mut PkKnock,
+    pub knock_count: usize,
+    pub duration_ms: u64,
+    /// The run was cancelled before every knock was sent.
+    pub interrupted: bool,
+}
+
+type Failure = (c_int, String);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Create a config with the command-line defaults and no host or ports.
+/// Returns NULL only if allocation failed.
+#[no_mangle]
+pub extern "C" fn pk_config_new() -> *mut PkConfig {
+    catch_unwind(|| {
+        Box::into_raw(Box::new(PkConfig {
+            config: KnockConfig::default(),
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Free a config; NULL is ignored.
+///
+/// # Safety
+/// `config` must come from [`pk_config_new`] and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_free(config: *mut PkConfig) {
+    if !config.is_null() {
+        drop(Box::from_raw(config));
+    }
+}
+
+/// Set the host name or IP to knock.
+///
+/// # Safety
+/// `config` must be a live config and `host` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_set_host(config: *mut PkConfig, host: *const c_char) -> c_int {
+    call(|| {
+        let config = config_mut(config)?;
+        if host.is_null() {
+            return Err(invalid("host is NULL"));
+        }
+        let host = CStr::from_ptr(host)
+            .to_str()
+            .map_err(|_| invalid("host is not UTF-8"))?;
+        config.host = host.to_string();
+        Ok(())
+    })
+}
+
+/// Append a port to the knock sequence.
+///
+/// # Safety
+/// `config` must be a live config.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_add_port(config: *mut PkConfig, port: u16) -> c_int {
+    call(|| {
+        config_mut(config)?.sequence.0.push(KnockStep::new(port));
+        Ok(())
+    })
+}
+
+/// Set the protocol of every knock, one of the `PK_PROTOCOL_*` constants.
+///
+/// # Safety
+/// `config` must be a live config.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_set_protocol(config: *mut PkConfig, protocol: c_int) -> c_int {
+    call(|| {
+        let config = config_mut(config)?;
+        config.protocol = match protocol {
+            PK_PROTOCOL_TCP => Protocol::Tcp,
+            PK_PROTOCOL_UDP => Protocol::Udp,
+            PK_PROTOCOL_ICMP => Protocol::Icmp,
+            PK_PROTOCOL_SCTP => Protocol::Sctp,
+            _ => return Err(invalid(format!("unknown protocol {protocol}"))),
+        };
+        Ok(())
+    })
+}
+
+/// Set the timeout of one attempt, in milliseconds.
+///
+/// # Safety
+/// `config` must be a live config.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_set_timeout_ms(config: *mut PkConfig, ms: u64) -> c_int {
+    call(|| {
+        config_mut(config)?.timeout = ms;
+        Ok(())
+    })
+}
+
+/// Set the number of attempts per knock, the first included; 0 is
+/// rejected when the run starts.
+///
+/// # Safety
+/// `config` must be a live config.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_set_attempts(config: *mut PkConfig, attempts: u32) -> c_int {
+    call(|| {
+        config_mut(config)?.attempts = Attempts::Finite(attempts as usize);
+        Ok(())
+    })
+}
+
+/// Deprecated name of `pk_config_set_attempts`, which it is the same as.
+///
+/// # Safety
+/// `config` must be a live config.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_set_retries(config: *mut PkConfig, retries: u32) -> c_int {
+    pk_config_set_attempts(config, retries)
+}
+
+/// Set the delay between knocks, in milliseconds.
+///
+/// # Safety
+/// `config` must be a live config.
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_set_delay_ms(config: *mut PkConfig, ms: u64) -> c_int {
+    call(|| {
+        config_mut(config)?.delay = Duration::from_millis(ms);
+        Ok(())
+    })
+}
+
+/// Set the payload of UDP knocks; the bytes are copied.
+///
+/// # Safety
+/// `config` must be a live config and `data` point to `len` readable
+/// bytes (it may be NULL when `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn pk_config_set_payload(
+    config: *mut PkConfig,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    call(|| {
+        let config = config_mut(config)?;
+        let payload = match (data.is_null(), len) {
+            (_, 0) => Vec::new(),
+            (true, _) => return Err(invalid("payload is NULL")),
+            (false, len) => std::slice::from_raw_parts(data, len).to_vec(),
+        };
+        config.payload = Some(payload.into());
+        Ok(())
+    })
+}
+
+/// Send the knocks and wait for them. On success `*report` receives the
+/// report; on failure it is set to NULL. A run in which some knocks did not
+/// get through fails with the binary's exit code 7.
+///
+/// # Safety
+/// `config` must be a live config and `report` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn pk_run(config: *const PkConfig, report: *mut *mut PkReport) -> c_int {
+    pk_run_with_cancel(config, ptr::null(), report)
+}
+
+/// Create a handle that cancels the runs it is passed to.
+#[no_mangle]
+pub extern "C" fn pk_cancel_new() -> *mut PkCancel {
+    catch_unwind(|| {
+        Box::into_raw(Box::new(PkCancel {
+            token: CancellationToken::new(),
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Cancel the runs using `cancel`, also ones started later; may be called
+/// from any thread.
+///
+/// # Safety
+/// `cancel` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn pk_cancel(cancel: *const PkCancel) -> c_int {
+    call(|| {
+        cancel
+            .as_ref()
+            .ok_or_else(|| invalid("cancel is NULL"))?
+            .token
+            .cancel();
+        Ok(())
+    })
+}
+
+/// Free a cancel handle once no run uses it; NULL is ignored.
+///
+/// # Safety
+/// `cancel` must come from [`pk_cancel_new`] and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pk_cancel_free(cancel: *mut PkCancel) {
+    if !cancel.is_null() {
+        drop(Box::from_raw(cancel));
+    }
+}
+
+/// [`pk_run`] that stops early, with a report marked `interrupted`, once
+/// `cancel` is cancelled. `cancel` may be NULL.
+///
+/// # Safety
+/// `config` must be a live config, `cancel` NULL or a live handle and
+/// `report` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn pk_run_with_cancel(
+    config: *const PkConfig,
+    cancel: *const PkCancel,
+    report: *mut *mut PkReport,
+) -> c_int {
+    call(|| {
+        if report.is_null() {
+            return Err(invalid("report is NULL"));
+        }
+        *report = ptr::null_mut();
+        let config = config
+            .as_ref()
+            .ok_or_else(|| invalid("config is NULL"))?
+            .config
+            .clone();
+        let cancel = cancel
+            .as_ref()
+            .map_or_else(CancellationToken::new, |c| c.token.clone());
+        let run = blocking::run_with_cancel(config, cancel).map_err(app_error)?;
+        let knocks: Box<[PkKnock]> = run
+            .steps
+            .iter()
+            .map(|o| PkKnock {
+                port: o.port,
+                protocol: protocol_code(o.protocol),
+                succeeded: o.succeeded,
+                acknowledged: o.acknowledged,
+                attempts: o.attempts,
+                latency_ms: o.latency.map_or(-1, |l| l.as_millis() as i64),
+                elapsed_ms: o.elapsed.as_millis() as u64,
+            })
+            .collect();
+        let knock_count = knocks.len();
+        *report = Box::into_raw(Box::new(PkReport {
+            knocks: Box::into_raw(knocks).cast(),
+            knock_count,
+            duration_ms: run.duration.as_millis() as u64,
+            interrupted: run.interrupted,
+        }));
+        Ok(())
+    })
+}
+
+/// Free a report from [`pk_run`]; NULL is ignored.
+///
+/// # Safety
+/// `report` must come from [`pk_run`] and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pk_report_free(report: *mut PkReport) {
+    if report.is_null() {
+        return;
+    }
+    let report = Box::from_raw(report);
+    let knocks = ptr::slice_from_raw_parts_mut(report.knocks, report.knock_count);
+    drop(Box::from_raw(knocks));
+}
+
+/// The message of the last failed call on this thread, or NULL if none
+/// failed yet. It stays valid until the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn pk_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+/// Run `f`, turning its failure or panic into an error code and message.
+fn call(f: impl FnOnce() -> Result<(), Failure>) -> c_int {
+    let result = catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".into());
+        Err((PK_ERR_PANIC, format!("panic: {message}")))
+    });
+    match result {
+        Ok(()) => PK_OK,
+        Err((code, message)) => {
+            let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+            code
+        }
+    }
+}
+
+/// # Safety
+/// `config` must be NULL or a live config.
+unsafe fn config_mut<'a>(config: *mut PkConfig) -> Result<&'a mut KnockConfig, Failure> {
+    config
+        .as_mut()
+        .map(|c| &mut c.config)
+        .ok_or_else(|| invalid("config is NULL"))
+}
+
+fn invalid(message: impl Into<String>) -> Failure {
+    (PK_ERR_INVALID_ARGUMENT, message.into())
+}
+
+fn app_error(e: AppError) -> Failure {
+    (e.exit_code(), e.to_string())
+}
+
+fn protocol_code(protocol: Protocol) -> c_int {
+    match protocol {
+        Protocol::Tcp => PK_PROTOCOL_TCP,
+        Protocol::Udp => PK_PROTOCOL_UDP,
+        Protocol::Icmp => PK_PROTOCOL_ICMP,
+        Protocol::Sctp => PK_PROTOCOL_SCTP,
+        Protocol::Both => unreachable!("both is expanded into tcp and udp knocks"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn last_error() -> String {
+        let message = pk_last_error_message();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn run_through_the_c_interface() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        unsafe {
+            let config = pk_config_new();
+            assert_eq!(pk_config_set_host(config, c"127.0.0.1".as_ptr()), PK_OK);
+            assert_eq!(pk_config_add_port(config, port), PK_OK);
+            assert_eq!(pk_config_set_attempts(config, 2), PK_OK);
+
+            let mut report = ptr::null_mut();
+            assert_eq!(pk_run(config, &mut report), PK_OK);
+            let knocks = std::slice::from_raw_parts((*report).knocks, (*report).knock_count);
+            assert_eq!(knocks.len(), 1);
+            assert_eq!(
+                (knocks[0].port, knocks[0].protocol),
+                (port, PK_PROTOCOL_TCP)
+            );
+            assert!(knocks[0].succeeded && knocks[0].latency_ms >= 0);
+            pk_report_free(report);
+            pk_config_free(config);
+        }
+    }
+
+    #[test]
+    fn errors_are_codes_with_a_message() {
+        unsafe {
+            let config = pk_config_new();
+            let mut report = ptr::null_mut();
+            // No host yet: the binary's exit code for bad configuration
+            assert_eq!(pk_run(config, &mut report), 2);
+            assert!(report.is_null());
+            assert!(last_error().contains("no host"), "{}", last_error());
+
+            assert_eq!(pk_config_set_protocol(config, 9), PK_ERR_INVALID_ARGUMENT);
+            assert_eq!(last_error(), "unknown protocol 9");
+            assert_eq!(
+                pk_config_add_port(ptr::null_mut(), 7000),
+                PK_ERR_INVALID_ARGUMENT
+            );
+            pk_config_free(config);
+        }
+    }
+
+    #[test]
+    fn cancelled_run_reports_the_interruption() {
+        unsafe {
+            let config = pk_config_new();
+            pk_config_set_host(config, c"127.0.0.1".as_ptr());
+            pk_config_add_port(config, 7000);
+            pk_config_set_delay_ms(config, 10_000);
+            let cancel = pk_cancel_new();
+            assert_eq!(pk_cancel(cancel), PK_OK);
+
+            let mut report = ptr::null_mut();
+            assert_eq!(pk_run_with_cancel(config, cancel, &mut report), PK_OK);
+            assert!((*report).interrupted);
+            assert_eq!((*report).knock_count, 0);
+            pk_report_free(report);
+            pk_cancel_free(cancel);
+            pk_config_free(config);
+        }
+    }
+
+    #[test]
+    fn panics_do_not_unwind_into_the_caller() {
+        assert_eq!(call(|| panic!("boom")), PK_ERR_PANIC);
+        assert_eq!(last_error(), "panic: boom");
+    }
+
+    /// The checked-in header declares exactly the functions exported here;
+    /// regenerate it with cbindgen (see `cbindgen.toml`) after a change.
+    #[test]
+    fn header_matches_the_exports() {
+        let names = |source: &str, marker: &str| -> BTreeSet<String> {
+            source
+                .lines()
+                .filter_map(|line| {
+                    let rest = &line[line.find(marker)? + marker.len()..];
+                    let name = rest.trim_start_matches('*');
+                    Some(name[..name.find('(')?].to_string())
+                })
+                .filter(|name| name.starts_with("pk_"))
+                .collect()
+        };
+        let exported = names(include_str!("ffi.rs"), "extern \"C\" fn ");
+        let header = include_str!("../include/async_port_knocker.h");
+        // Declarations may span lines; comments and directives are skipped
+        let code: Vec<&str> = header
+            .lines()
+            .filter(|line| !line.trim_start().starts_with(['/', '#', '}']))
+            .collect();
+        let declared: BTreeSet<String> = code
+            .join(" ")
+            .split(';')
+            .filter_map(|decl| {
+                let name = &decl[..decl.find('(')?];
+                Some(name.rsplit([' ', '*']).next()?.to_string())
+            })
+            .collect();
+        assert!(exported.contains("pk_run"), "{exported:?}");
+        assert_eq!(exported, declared);
+        for constant in [
+            "PK_OK",
+            "PK_ERR_INVALID_ARGUMENT",
+            "PK_ERR_PANIC",
+            "PK_PROTOCOL_UDP",
+        ] {
+            assert!(
+                header.contains(&format!("#define {constant} ")),
+                "{constant}"
+            );
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Knocking the same sequence on many hosts.
+//!
+//! [`run_fleet`] knocks every host of a hosts file ([`parse_hosts`]) with
+//! the settings of one [`KnockConfig`], a few hosts at a time. Each host
+//! still gets its sequence strictly in order, and a host that cannot be
+//! resolved or knocked fails on its own without stopping the others.
+
+use crate::observer::KnockObserver;
+use crate::outcome::FailureTolerance;
+use crate::{AppError, KnockConfig, KnockReport};
+use futures::{stream, StreamExt};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Hosts knocked at once when nothing else is asked for.
+pub const DEFAULT_HOST_CONCURRENCY: usize = 4;
+
+/// How knocking one host went.
+#[derive(Debug)]
+pub struct HostResult {
+    pub host: String,
+    pub result: Result<KnockReport, AppError>,
+}
+
+/// Every host's result, in hosts-file order.
+#[derive(Debug)]
+pub struct FleetReport {
+    pub hosts: Vec<HostResult>,
+}
+
+impl FleetReport {
+    /// The hosts whose run failed, in hosts-file order.
+    pub fn failed(&self) -> Vec<&str> {
+        self.hosts
+            .iter()
+            .filter(|h| h.result.is_err())
+            .map(|h| h.host.as_str())
+            .collect()
+    }
+
+    /// Whether the hosts that failed, if any, are within `tolerance`
+    /// (`--max-failed-hosts`).
+    pub fn tolerated(&self, tolerance: Option<FailureTolerance>) -> bool {
+        let failed = self.failed().len();
+        tolerance.is_some_and(|tolerance| tolerance.allows(failed, self.hosts.len()))
+    }
+
+    /// `Ok` when every host got through, or the hosts that did not are
+    /// [`tolerated`](Self::tolerated), [`AppError::Hosts`] otherwise.
+    pub fn into_result(self, tolerance: Option<FailureTolerance>) -> Result<Self, AppError> {
+        let failed: Vec<String> = self.failed().into_iter().map(str::to_string).collect();
+        match failed.is_empty() || self.tolerated(tolerance) {
+            true => Ok(self),
+            false => Err(AppError::Hosts {
+                failed,
+                total: self.hosts.len(),
+            }),
+        }
+    }
+}
+
+/// Read a hosts file: one host per line, as `--host` takes it; blank lines
+/// and everything after a `#` are skipped.
+pub fn parse_hosts(text: &str) -> Result<Vec<String>, String> {
+    let mut hosts = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let host =
+            crate::scope::parse_host(line).map_err(|e| format!("line {}: {e}", number + 1))?;
+        hosts.push(host);
+    }
+    if hosts.is_empty() {
+        return Err("no hosts listed".into());
+    }
+    Ok(hosts)
+}
+
+/// Read and parse a hosts file.
+pub fn load_hosts(path: &Path) -> Result<Vec<String>, AppError> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        AppError::InvalidConfig(format!("cannot read hosts file {}: {e}", path.display()))
+    })?;
+    parse_hosts(&text)
+        .map_err(|e| AppError::InvalidConfig(format!("hosts file {}: {e}", path.display())))
+}
+
+/// Knock `hosts` with the settings of `base`, at most `concurrency` at a
+/// time, each through [`crate::run`]. `observer` gives the observer of
+/// each host's run, to tell their output apart, and `on_done` hears of
+/// each host as it finishes.
+///
+/// Failed hosts are in the report; only a signal ends the fleet early,
+/// with [`AppError::Interrupted`]. As with [`crate::run`], the signals
+/// stay caught once it returns.
+pub async fn run_fleet(
+    base: &KnockConfig,
+    hosts: &[String],
+    concurrency: usize,
+    observer: impl Fn(&str) -> Option<Arc<dyn KnockObserver + Send + Sync>>,
+    mut on_done: impl FnMut(&HostResult),
+) -> Result<FleetReport, AppError> {
+    if concurrency == 0 {
+        return Err(AppError::InvalidConfig(
+            "host concurrency must be at least 1".into(),
+        ));
+    }
+    let runs = hosts.iter().enumerate().map(|(index, host)| {
+        let mut config = base.clone();
+        config.host = host.clone();
+        if let Some(observer) = observer(host) {
+            config.observer = Some(observer);
+        }
+        async move {
+            let result = crate::run(config).await;
+            (
+                index,
+                HostResult {
+                    host: host.clone(),
+                    result,
+                },
+            )
+        }
+    });
+    let mut done = stream::iter(runs).buffer_unordered(concurrency);
+    let mut results = Vec::with_capacity(hosts.len());
+    while let Some((index, host)) = done.next().await {
+        on_done(&host);
+        if let Err(AppError::Interrupted(signal)) = host.result {
+            return Err(AppError::Interrupted(signal));
+        }
+        results.push((index, host));
+    }
+    results.sort_by_key(|(index, _)| *index);
+    Ok(FleetReport {
+        hosts: results.into_iter().map(|(_, host)| host).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn hosts_files_skip_comments_and_blank_lines() {
+        let text = "# edge boxes\nedge1.example\n\n  203.0.113.7  # lab\n[2001:db8::1]\n";
+        assert_eq!(
+            parse_hosts(text).unwrap(),
+            ["edge1.example", "203.0.113.7", "2001:db8::1"]
+        );
+        assert!(parse_hosts("# nothing\n\n").is_err());
+        let error = parse_hosts("ok.example\nbad host\n").unwrap_err();
+        assert!(error.starts_with("line 2:"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn one_bad_host_does_not_stop_the_others() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let base = KnockConfig::builder()
+            .host("placeholder.invalid")
+            .sequence([port, port])
+            .timeout(300)
+            .build()
+            .unwrap();
+        let hosts: Vec<String> = ["127.0.0.1", "no-such-host.invalid", "127.0.0.1"]
+            .map(String::from)
+            .to_vec();
+        let mut finished = 0;
+        let report = run_fleet(&base, &hosts, 2, |_| None, |_| finished += 1)
+            .await
+            .unwrap();
+        assert_eq!(finished, 3);
+        let hosts: Vec<&str> = report.hosts.iter().map(|h| h.host.as_str()).collect();
+        assert_eq!(hosts, ["127.0.0.1", "no-such-host.invalid", "127.0.0.1"]);
+        assert_eq!(report.failed(), ["no-such-host.invalid"]);
+        assert_eq!(report.hosts[0].result.as_ref().unwrap().steps.len(), 2);
+        assert!(report.tolerated(Some(FailureTolerance::Count(1))));
+        assert!(report.tolerated(Some(FailureTolerance::Percent(40.0))));
+        assert!(!report.tolerated(Some(FailureTolerance::Percent(30.0))));
+
+        let error = report.into_result(None).unwrap_err();
+        assert_eq!(error.exit_code(), 8);
+        assert_eq!(
+            error.to_string(),
+            "1 of 3 hosts failed: no-such-host.invalid"
+        );
+    }
+
+    #[tokio::test]
+    async fn hosts_run_in_parallel_up_to_the_limit() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Each host's run takes the initial delay, so 4 hosts two at a
+        // time take two delays
+        let base = KnockConfig::builder()
+            .host("placeholder.invalid")
+            .sequence([port])
+            .initial_delay(300)
+            .build()
+            .unwrap();
+        let hosts = vec!["127.0.0.1".to_string(); 4];
+        let started = tokio::time::Instant::now();
+        let report = run_fleet(&base, &hosts, 2, |_| None, |_| {}).await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(report.into_result(None).is_ok());
+        assert!(elapsed >= Duration::from_millis(600), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(900), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn all_hosts_failing_has_its_own_exit_code() {
+        let base = KnockConfig::builder()
+            .host("placeholder.invalid")
+            .sequence([7000])
+            .build()
+            .unwrap();
+        let hosts = vec!["a.invalid".to_string(), "b.invalid".to_string()];
+        let report = run_fleet(&base, &hosts, 2, |_| None, |_| {}).await.unwrap();
+        assert_eq!(report.into_result(None).unwrap_err().exit_code(), 9);
+    }
+}
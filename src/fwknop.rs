@@ -0,0 +1,192 @@
+use crate::secret::Secret;
+use aes::Aes256;
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use cbc::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// SPA protocol version written into every message.
+pub const PROTOCOL_VERSION: &str = "3.0.0";
+/// Environment variable holding the Rijndael key when no source is given.
+pub const KEY_ENV: &str = "KNOCK_FWKNOP_KEY";
+/// Environment variable holding the HMAC key when no source is given.
+pub const HMAC_KEY_ENV: &str = "KNOCK_FWKNOP_HMAC_KEY";
+/// Message type of a plain access request.
+const ACCESS_MSG: u8 = 1;
+/// Base64 of the `Salted__` header, left off the wire by fwknop.
+const B64_SALTED: &str = "U2FsdGVkX1";
+
+/// Keys and access request for fwknop-compatible SPA packets.
+///
+/// Deliberately not `Debug`, so the keys cannot end up in a log line.
+#[derive(Clone)]
+pub struct FwknopConfig {
+    /// Rijndael key, the `KEY` of the fwknopd access stanza.
+    pub key: Secret,
+    /// HMAC-SHA256 key, the stanza's `HMAC_KEY`; no HMAC is sent without it.
+    pub hmac_key: Option<Secret>,
+    pub user: String,
+    /// Address to open access for; `0.0.0.0` lets fwknopd use the source.
+    pub allow_ip: IpAddr,
+    /// Requested access such as `tcp/22`.
+    pub access: String,
+}
+
+impl FwknopConfig {
+    /// Build a packet with fresh random data, salt and timestamp.
+    pub fn packet(&self) -> Vec<u8> {
+        let rand_val = format!("{:016}", rand::random::<u64>() % 10u64.pow(16));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.build(&rand_val, now, rand::random()).into_bytes()
+    }
+
+    /// Encode, digest, encrypt and wrap one access message.
+    ///
+    /// The message is `rand:b64(user):timestamp:version:type:b64(ip,access)`,
+    /// encrypted together with its base64 SHA-256 digest. The HMAC covers
+    /// the base64 ciphertext including the `Salted__` header that is
+    /// stripped before sending.
+    fn build(&self, rand_val: &str, timestamp: u64, salt: [u8; 8]) -> String {
+        let access = format!("{},{}", self.allow_ip, self.access);
+        let encoded = encode_message(rand_val, &self.user, timestamp, &access);
+        let digest = b64(&Sha256::digest(encoded.as_bytes()));
+        let wrapped = b64(&encrypt(
+            &self.key,
+            salt,
+            format!("{encoded}:{digest}").as_bytes(),
+        ));
+
+        let mut spa = wrapped
+            .strip_prefix(B64_SALTED)
+            .unwrap_or(&wrapped)
+            .to_string();
+        if let Some(hmac_key) = &self.hmac_key {
+            spa.push_str(&b64(&crate::spa::hmac_sha256(
+                hmac_key,
+                &[wrapped.as_bytes()],
+            )));
+        }
+        spa
+    }
+}
+
+/// Colon-separated plaintext fields of an access message.
+fn encode_message(rand_val: &str, user: &str, timestamp: u64, access: &str) -> String {
+    format!(
+        "{rand_val}:{}:{timestamp}:{PROTOCOL_VERSION}:{ACCESS_MSG}:{}",
+        b64(user.as_bytes()),
+        b64(access.as_bytes())
+    )
+}
+
+/// AES-256-CBC in OpenSSL's salted format: `Salted__ | salt | ciphertext`.
+fn encrypt(key: &[u8], salt: [u8; 8], plaintext: &[u8]) -> Vec<u8> {
+    let (aes_key, iv) = derive_key_iv(key, &salt);
+    let ciphertext = cbc::Encryptor::<Aes256>::new(&aes_key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+    [b"Salted__".as_slice(), &salt, &ciphertext].concat()
+}
+
+/// OpenSSL's `EVP_BytesToKey` with MD5 and a single round: chain
+/// `MD5(prev | key | salt)` blocks into a 32-byte key and 16-byte IV.
+fn derive_key_iv(key: &[u8], salt: &[u8]) -> ([u8; 32], [u8; 16]) {
+    let mut out = Vec::with_capacity(48);
+    let mut prev = Vec::new();
+    while out.len() < 48 {
+        prev = Md5::new()
+            .chain_update(&prev)
+            .chain_update(key)
+            .chain_update(salt)
+            .finalize()
+            .to_vec();
+        out.extend_from_slice(&prev);
+    }
+    let mut aes_key = [0u8; 32];
+    let mut iv = [0u8; 16];
+    aes_key.copy_from_slice(&out[..32]);
+    iv.copy_from_slice(&out[32..48]);
+    (aes_key, iv)
+}
+
+/// Base64 without `=` padding, as fwknop writes every field.
+fn b64(data: &[u8]) -> String {
+    STANDARD_NO_PAD.encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbc::cipher::BlockDecryptMut;
+
+    fn config(hmac_key: Option<&[u8]>) -> FwknopConfig {
+        FwknopConfig {
+            key: Secret::from(&b"fwknoptest"[..]),
+            hmac_key: hmac_key.map(Secret::from),
+            user: "alice".into(),
+            allow_ip: "0.0.0.0".parse().unwrap(),
+            access: "tcp/22".into(),
+        }
+    }
+
+    /// Undo `build` the way fwknopd does, returning the plaintext.
+    fn decrypt(key: &[u8], spa: &str) -> String {
+        let data = STANDARD_NO_PAD
+            .decode(format!("{B64_SALTED}{spa}"))
+            .unwrap();
+        assert_eq!(&data[..8], b"Salted__");
+        let (aes_key, iv) = derive_key_iv(key, &data[8..16]);
+        let plain = cbc::Decryptor::<Aes256>::new(&aes_key.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&data[16..])
+            .unwrap();
+        String::from_utf8(plain).unwrap()
+    }
+
+    #[test]
+    fn key_derivation_matches_openssl() {
+        // openssl enc -aes-256-cbc -P -md md5 -S 0102030405060708 -pass pass:fwknoptest
+        let (key, iv) = derive_key_iv(b"fwknoptest", &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            hex::encode_upper(key),
+            "EBE462FE5BE052F4611BBB47B9E7C48BD8B947D85052411F55C16F0E4E04A5FF"
+        );
+        assert_eq!(hex::encode_upper(iv), "48926D083E8525F9424A6BDAEC95C20C");
+    }
+
+    #[test]
+    fn message_fields() {
+        assert_eq!(
+            encode_message("1234567890123456", "alice", 1_700_000_000, "0.0.0.0,tcp/22"),
+            "1234567890123456:YWxpY2U:1700000000:3.0.0:1:MC4wLjAuMCx0Y3AvMjI"
+        );
+    }
+
+    #[test]
+    fn packet_decrypts_to_message_and_digest() {
+        let cfg = config(None);
+        let spa = cfg.build("1234567890123456", 1_700_000_000, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(!spa.starts_with(B64_SALTED));
+        assert_eq!(
+            decrypt(&cfg.key, &spa),
+            "1234567890123456:YWxpY2U:1700000000:3.0.0:1:MC4wLjAuMCx0Y3AvMjI:\
+             1QrouJGK+1GLvZRTWssdHpW1pTFgqEkvM80ch10118o"
+        );
+    }
+
+    #[test]
+    fn hmac_covers_the_salted_ciphertext() {
+        let salt = [9u8; 8];
+        let plain = config(None).build("1234567890123456", 1, salt);
+        let signed = config(Some(b"hmac-key")).build("1234567890123456", 1, salt);
+
+        let (body, tag) = signed.split_at(plain.len());
+        assert_eq!(body, plain);
+        let expected =
+            crate::spa::hmac_sha256(b"hmac-key", &[format!("{B64_SALTED}{plain}").as_bytes()]);
+        assert_eq!(tag, b64(&expected));
+        assert_eq!(tag.len(), 43);
+    }
+}
@@ -0,0 +1,37 @@
+//! `--fwmark`: the firewall mark (SO_MARK) knock sockets carry, so that
+//! policy routing, e.g. `ip rule add fwmark 2 table wan2`, sends the
+//! knocks out a particular link. Linux only; setting a mark needs
+//! CAP_NET_ADMIN.
+
+use crate::AppError;
+
+/// Mark `socket` with `mark`, before it connects or sends anything.
+#[cfg(target_os = "linux")]
+pub(crate) fn set(socket: &impl std::os::fd::AsFd, mark: u32) -> Result<(), AppError> {
+    socket2::SockRef::from(socket)
+        .set_mark(mark)
+        .map_err(|source| match source.kind() {
+            std::io::ErrorKind::PermissionDenied => AppError::LocalFailure {
+                source,
+                hint: "setting --fwmark (SO_MARK) needs CAP_NET_ADMIN (or CAP_NET_RAW on \
+                       Linux 5.17 and later); run as root or `setcap cap_net_admin+ep` on \
+                       the binary",
+            },
+            _ => AppError::local_failure(source),
+        })
+}
+
+/// Elsewhere there is no SO_MARK to set.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set<S>(_socket: &S, _mark: u32) -> Result<(), AppError> {
+    Err(AppError::InvalidConfig(
+        "--fwmark is only supported on Linux".into(),
+    ))
+}
+
+/// Mark a throwaway socket, so a missing CAP_NET_ADMIN fails the run
+/// before its first knock instead of at every one.
+pub(crate) fn check(mark: u32) -> Result<(), AppError> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").map_err(AppError::local_failure)?;
+    set(&socket, mark)
+}
@@ -0,0 +1,93 @@
+//! Classic offset/hex/ASCII dumps of the bytes a knock sends and receives
+//! (`--hexdump`), for debugging payload mismatches without a packet
+//! capture.
+//!
+//! ```text
+//! 00000000  6f 70 65 6e 20 73 65 73  61 6d 65 0a              |open sesame.|
+//! ```
+
+use std::fmt::{self, Write};
+
+/// Bytes dumped of one payload when no other limit is given.
+pub const DEFAULT_LIMIT: usize = 256;
+
+/// Which way the dumped bytes went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        })
+    }
+}
+
+/// Dump `bytes`, 16 a line, with the offset, the bytes in hex and their
+/// printable ASCII (anything else shown as `.`). Only the first `limit`
+/// bytes are dumped, followed by a line counting the rest; nothing at all
+/// is `(empty)`. Every line ends with a newline.
+pub fn hexdump(bytes: &[u8], limit: usize) -> String {
+    if bytes.is_empty() {
+        return "(empty)\n".into();
+    }
+    let shown = &bytes[..bytes.len().min(limit)];
+    let mut out = String::new();
+    for (line, chunk) in shown.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", line * 16);
+        for i in 0..16 {
+            // An extra space between the two halves of the line
+            if i == 8 {
+                out.push(' ');
+            }
+            match chunk.get(i) {
+                Some(b) => {
+                    let _ = write!(out, " {b:02x}");
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(chunk.iter().map(|&b| match b {
+            0x20..=0x7e => char::from(b),
+            _ => '.',
+        }));
+        out.push_str("|\n");
+    }
+    if bytes.len() > shown.len() {
+        let _ = writeln!(out, "... {} more bytes", bytes.len() - shown.len());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_show_offset_hex_and_ascii() {
+        let mut bytes = b"open sesame\n".to_vec();
+        bytes.extend([0x00, 0xff, 0x7f, 0x80, b'!']);
+        assert_eq!(
+            hexdump(&bytes, DEFAULT_LIMIT),
+            "00000000  6f 70 65 6e 20 73 65 73  61 6d 65 0a 00 ff 7f 80  |open sesame.....|\n\
+             00000010  21                                                |!|\n"
+        );
+    }
+
+    #[test]
+    fn empty_and_truncated_payloads() {
+        assert_eq!(hexdump(&[], 16), "(empty)\n");
+        let dump = hexdump(&[b'a'; 40], 16);
+        assert_eq!(
+            dump,
+            "00000000  61 61 61 61 61 61 61 61  61 61 61 61 61 61 61 61  |aaaaaaaaaaaaaaaa|\n\
+             ... 24 more bytes\n"
+        );
+        assert_eq!(hexdump(b"ab", 0), "... 2 more bytes\n");
+    }
+}
@@ -0,0 +1,415 @@
+//! A SQLite database of the runs knocked (`--history-db`), to answer
+//! months later who knocked what, when, and whether it worked.
+//!
+//! Each run is a row of `runs`: when it started and ended, the host, its
+//! plan as [`crate::confirm::describe_plan`] renders it (payloads only by
+//! length, no proxy credentials, derived ports hidden) and how it ended:
+//! the process exit code and the error. Each knock is a row of `steps`,
+//! written in a transaction of its own as soon as the knock ends, so a run
+//! cut short by a signal or a crash keeps the knocks it got to. A run with
+//! no end time is one whose process died before it could say how it
+//! ended.
+//!
+//! The database holds the knocked ports, which the state file does not;
+//! it is created readable by its owner only. The schema is created on
+//! first use and migrated forward by [`MIGRATIONS`], tracked in
+//! `PRAGMA user_version`.
+
+use crate::outcome::KnockOutcome;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The schema, one migration per version: a database at version N has had
+/// the first N applied.
+pub const MIGRATIONS: &[&str] = &["
+    CREATE TABLE runs (
+        id          INTEGER PRIMARY KEY,
+        started_at  INTEGER NOT NULL,
+        finished_at INTEGER,
+        host        TEXT NOT NULL,
+        config      TEXT NOT NULL,
+        exit_code   INTEGER,
+        error       TEXT
+    );
+    CREATE TABLE steps (
+        run_id     INTEGER NOT NULL REFERENCES runs (id) ON DELETE CASCADE,
+        step       INTEGER NOT NULL,
+        at         INTEGER NOT NULL,
+        port       INTEGER NOT NULL,
+        protocol   TEXT NOT NULL,
+        addr       TEXT,
+        attempts   INTEGER NOT NULL,
+        succeeded  INTEGER NOT NULL,
+        latency_us INTEGER,
+        error      TEXT,
+        PRIMARY KEY (run_id, step)
+    );
+    CREATE INDEX runs_started_at ON runs (started_at);
+"];
+
+/// How long a write waits for another process holding the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One run, as recorded. Times are to the millisecond.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunRecord {
+    pub id: i64,
+    pub started_at: SystemTime,
+    /// `None` while the run goes on, or when its process died.
+    pub finished_at: Option<SystemTime>,
+    pub host: String,
+    /// The run's plan, secrets left out.
+    pub config: String,
+    /// Exit code of the binary for the run: 0 when it succeeded.
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    /// Knocks recorded, and how many of them got through.
+    pub steps: usize,
+    pub succeeded: usize,
+}
+
+impl RunRecord {
+    /// How the run ended, in a word.
+    pub fn status(&self) -> &'static str {
+        match (self.finished_at, self.exit_code) {
+            (None, _) => "unfinished",
+            (_, Some(0)) => "ok",
+            (_, Some(130 | 129 | 143)) => "interrupted",
+            _ => "failed",
+        }
+    }
+}
+
+/// One knock of a run, as recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepRecord {
+    /// Order the knock ended in, from 1.
+    pub step: usize,
+    pub at: SystemTime,
+    pub port: u16,
+    pub protocol: String,
+    pub addr: Option<String>,
+    pub attempts: usize,
+    pub succeeded: bool,
+    pub latency: Option<Duration>,
+    /// The last attempt's error, when the knock did not get through.
+    pub error: Option<String>,
+}
+
+/// An open history database.
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    /// Open the database at `path`, creating it and its schema, or
+    /// migrating an older schema, as needed.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let fail = |e: &dyn std::fmt::Display| format!("{}: {e}", path.display());
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| fail(&e))?;
+        }
+        create_private(path).map_err(|e| fail(&e))?;
+        let conn = Connection::open(path).map_err(|e| fail(&e))?;
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(|e| fail(&e))?;
+        conn.pragma_update(None, "foreign_keys", true)
+            .map_err(|e| fail(&e))?;
+        let mut history = Self { conn };
+        history.migrate().map_err(|e| fail(&e))?;
+        Ok(history)
+    }
+
+    /// Apply the migrations the database has not had, each in a
+    /// transaction with the version it brings the database to.
+    fn migrate(&mut self) -> Result<(), String> {
+        let version: usize = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        if version > MIGRATIONS.len() {
+            return Err(format!(
+                "the database is at schema version {version}, newer than this version knows \
+                 ({})",
+                MIGRATIONS.len()
+            ));
+        }
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction().map_err(|e| e.to_string())?;
+            tx.execute_batch(migration).map_err(|e| e.to_string())?;
+            tx.pragma_update(None, "user_version", index + 1)
+                .map_err(|e| e.to_string())?;
+            tx.commit().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Record that a run knocking `host` as `config` describes started at
+    /// `at`, and return its ID.
+    pub fn begin(&self, host: &str, config: &str, at: SystemTime) -> Result<i64, String> {
+        self.conn
+            .execute(
+                "INSERT INTO runs (started_at, host, config) VALUES (?1, ?2, ?3)",
+                params![unix_millis(at), host, config],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Record the knock of run `run` that ended `step`th, at `at`.
+    pub fn record_step(
+        &self,
+        run: i64,
+        step: usize,
+        outcome: &KnockOutcome,
+        at: SystemTime,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO steps (run_id, step, at, port, protocol, addr, attempts, \
+                 succeeded, latency_us, error) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    run,
+                    step,
+                    unix_millis(at),
+                    outcome.port,
+                    outcome.protocol.to_string(),
+                    outcome.addr.map(|a| a.to_string()),
+                    outcome.attempts,
+                    outcome.succeeded,
+                    outcome.latency.map(|l| l.as_micros() as i64),
+                    (!outcome.succeeded)
+                        .then(|| outcome.errors.last().map(|e| e.message.clone()))
+                        .flatten(),
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Record that run `run` ended at `at` with the binary's `exit_code`
+    /// and, when it failed, `error`.
+    pub fn finish(
+        &self,
+        run: i64,
+        at: SystemTime,
+        exit_code: i32,
+        error: Option<&str>,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE runs SET finished_at = ?2, exit_code = ?3, error = ?4 WHERE id = ?1",
+                params![run, unix_millis(at), exit_code, error],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// The `limit` most recent runs, newest first.
+    pub fn list(&self, limit: usize) -> Result<Vec<RunRecord>, String> {
+        let mut statement = self
+            .conn
+            .prepare(&format!(
+                "{RUN_COLUMNS} GROUP BY runs.id ORDER BY runs.started_at DESC, runs.id DESC \
+                 LIMIT ?1"
+            ))
+            .map_err(|e| e.to_string())?;
+        let runs = statement
+            .query_map(params![limit as i64], run_record)
+            .map_err(|e| e.to_string())?;
+        runs.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    /// Run `id` and its knocks in the order they ended; `None` when there
+    /// is no such run.
+    pub fn show(&self, id: i64) -> Result<Option<(RunRecord, Vec<StepRecord>)>, String> {
+        let run = self
+            .conn
+            .query_row(
+                &format!("{RUN_COLUMNS} WHERE runs.id = ?1 GROUP BY runs.id"),
+                params![id],
+                run_record,
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        let Some(run) = run else {
+            return Ok(None);
+        };
+        let mut statement = self
+            .conn
+            .prepare(
+                "SELECT step, at, port, protocol, addr, attempts, succeeded, latency_us, error \
+                 FROM steps WHERE run_id = ?1 ORDER BY step",
+            )
+            .map_err(|e| e.to_string())?;
+        let steps = statement
+            .query_map(params![id], |row| {
+                Ok(StepRecord {
+                    step: row.get(0)?,
+                    at: from_unix_millis(row.get(1)?),
+                    port: row.get(2)?,
+                    protocol: row.get(3)?,
+                    addr: row.get(4)?,
+                    attempts: row.get(5)?,
+                    succeeded: row.get(6)?,
+                    latency: row
+                        .get::<_, Option<i64>>(7)?
+                        .map(|us| Duration::from_micros(us as u64)),
+                    error: row.get(8)?,
+                })
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(Some((run, steps)))
+    }
+
+    /// Delete the runs started before `before`, with their knocks, and
+    /// return how many there were.
+    pub fn prune(&self, before: SystemTime) -> Result<usize, String> {
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|e| e.to_string())?;
+        let cutoff = unix_millis(before);
+        tx.execute(
+            "DELETE FROM steps WHERE run_id IN (SELECT id FROM runs WHERE started_at < ?1)",
+            params![cutoff],
+        )
+        .map_err(|e| e.to_string())?;
+        let pruned = tx
+            .execute("DELETE FROM runs WHERE started_at < ?1", params![cutoff])
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(pruned)
+    }
+}
+
+/// A run's columns, with its knocks counted, for [`run_record`].
+const RUN_COLUMNS: &str = "SELECT runs.id, runs.started_at, runs.finished_at, runs.host, \
+    runs.config, runs.exit_code, runs.error, COUNT(steps.step), \
+    COALESCE(SUM(steps.succeeded), 0) FROM runs LEFT JOIN steps ON steps.run_id = runs.id";
+
+fn run_record(row: &rusqlite::Row) -> rusqlite::Result<RunRecord> {
+    Ok(RunRecord {
+        id: row.get(0)?,
+        started_at: from_unix_millis(row.get(1)?),
+        finished_at: row.get::<_, Option<i64>>(2)?.map(from_unix_millis),
+        host: row.get(3)?,
+        config: row.get(4)?,
+        exit_code: row.get(5)?,
+        error: row.get(6)?,
+        steps: row.get(7)?,
+        succeeded: row.get(8)?,
+    })
+}
+
+/// Create `path` empty and readable by its owner only, unless it exists.
+fn create_private(path: &Path) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match options.open(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn unix_millis(at: SystemTime) -> i64 {
+    at.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+fn from_unix_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outcome::AttemptError;
+    use crate::protocol::Protocol;
+
+    fn outcome(port: u16, succeeded: bool) -> KnockOutcome {
+        let mut outcome = KnockOutcome::failed(port, Protocol::Udp, "timed out");
+        outcome.attempts = 2;
+        outcome.addr = Some("192.0.2.7:7000".parse().unwrap());
+        if succeeded {
+            outcome.succeeded = true;
+            outcome.errors = vec![AttemptError::new(1, "refused")];
+            outcome.latency = Some(Duration::from_micros(1500));
+        }
+        outcome
+    }
+
+    #[test]
+    fn runs_are_recorded_step_by_step() {
+        let dir = std::env::temp_dir().join(format!("history-test-{}", std::process::id()));
+        let path = dir.join("history.db");
+        let _ = std::fs::remove_dir_all(&dir);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        let history = History::open(&path).unwrap();
+        let old = history
+            .begin("old.example", "Host: old", at(1_000))
+            .unwrap();
+        history.finish(old, at(1_001), 0, None).unwrap();
+        let run = history
+            .begin("example.com", "Host: example.com", at(2_000))
+            .unwrap();
+        history
+            .record_step(run, 1, &outcome(7000, true), at(2_001))
+            .unwrap();
+        history
+            .record_step(run, 2, &outcome(8000, false), at(2_002))
+            .unwrap();
+        // A second process reads what the first wrote, before it ended
+        drop(history);
+        let history = History::open(&path).unwrap();
+        let (record, steps) = history.show(run).unwrap().unwrap();
+        assert_eq!(record.status(), "unfinished");
+        assert_eq!((record.steps, record.succeeded), (2, 1));
+        assert_eq!(steps[0].latency, Some(Duration::from_micros(1500)));
+        assert_eq!(steps[0].error, None);
+        assert_eq!(steps[1].error.as_deref(), Some("timed out"));
+        assert_eq!(steps[1].addr.as_deref(), Some("192.0.2.7:7000"));
+        assert_eq!(steps[1].at, at(2_002));
+
+        history
+            .finish(run, at(2_003), 7, Some("1 of 2 knocks failed"))
+            .unwrap();
+        let listed = history.list(10).unwrap();
+        let ids: Vec<i64> = listed.iter().map(|r| r.id).collect();
+        assert_eq!(ids, [run, old]);
+        assert_eq!(listed[0].status(), "failed");
+        assert_eq!(listed[1].status(), "ok");
+        assert_eq!(history.list(1).unwrap().len(), 1);
+
+        assert_eq!(history.prune(at(1_500)).unwrap(), 1);
+        assert!(history.show(old).unwrap().is_none());
+        assert_eq!(history.show(run).unwrap().unwrap().1.len(), 2);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn newer_schemas_are_refused() {
+        let dir = std::env::temp_dir().join(format!("history-newer-{}", std::process::id()));
+        let path = dir.join("history.db");
+        let _ = std::fs::remove_dir_all(&dir);
+        drop(History::open(&path).unwrap());
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(conn);
+        let err = History::open(&path).err().unwrap();
+        assert!(err.contains("newer than this version knows"), "{err}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,218 @@
+//! Commands run around a knock run (`--pre-hook`, `--post-hook`,
+//! `--failure-hook`).
+//!
+//! The pre-hook runs before anything is resolved or sent, e.g. to bring up
+//! the route the knocks go over; a pre-hook that fails or times out stops
+//! the run with [`AppError::Hook`]. Once the run is over the failure hook
+//! runs if it failed, then the post-hook either way. Their failures are
+//! only noted, unless the hooks are strict and the run itself succeeded.
+//!
+//! Each hook is a shell command (`sh -c`, `cmd /C` on Windows) sharing the
+//! knocker's stdout and stderr, told about the run through the `KNOCK_*`
+//! environment variables of [`run_env`] and [`result_env`], and killed
+//! once its timeout runs out. Running them needs the `runtime-tokio`
+//! feature.
+
+use crate::{AppError, KnockConfig, KnockReport};
+use std::fmt;
+use std::time::Duration;
+
+/// How long a hook may run before it is killed.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The commands run around a knock run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Hooks {
+    /// Run before the knocks; failing stops the run.
+    pub pre: Option<String>,
+    /// Run after the run, whatever its result.
+    pub post: Option<String>,
+    /// Run after a run that failed, before `post`.
+    pub failure: Option<String>,
+    /// How long each hook may run.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::millis"))]
+    pub timeout: Duration,
+    /// Fail a run that succeeded when its post-hook fails.
+    pub strict: bool,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            pre: None,
+            post: None,
+            failure: None,
+            timeout: DEFAULT_TIMEOUT,
+            strict: false,
+        }
+    }
+}
+
+impl Hooks {
+    /// Whether no hook is set.
+    pub fn is_empty(&self) -> bool {
+        self.pre.is_none() && self.post.is_none() && self.failure.is_none()
+    }
+}
+
+/// When in a run a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPoint {
+    Pre,
+    Post,
+    Failure,
+}
+
+impl HookPoint {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookPoint::Pre => "pre",
+            HookPoint::Post => "post",
+            HookPoint::Failure => "failure",
+        }
+    }
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What every hook is told of the run: `KNOCK_HOST` (the SRV name with
+/// `--srv`), `KNOCK_SEQUENCE` (as `--sequence` takes it) and
+/// `KNOCK_PROTOCOL`.
+pub fn run_env(config: &KnockConfig) -> Vec<(&'static str, String)> {
+    let host = config.srv.as_ref().unwrap_or(&config.host);
+    vec![
+        ("KNOCK_HOST", host.clone()),
+        ("KNOCK_SEQUENCE", config.sequence.to_string()),
+        ("KNOCK_PROTOCOL", config.protocol.name().to_string()),
+    ]
+}
+
+/// What the hooks after a run are also told of how it went:
+///
+/// - `KNOCK_RESULT`: `success`, `failure` or `interrupted`
+/// - `KNOCK_EXIT_CODE`: the code the binary exits with for it
+/// - `KNOCK_ERROR`: why it failed, empty when it did not
+/// - `KNOCK_FAILED_PORT`, `KNOCK_FAILED_PORTS`: the first port that failed
+///   and all of them, comma-separated, empty when none did or the run
+///   ended before knocking
+/// - `KNOCK_DURATION_MS`: how long it took, empty when it ended before
+///   knocking
+pub fn result_env(result: &Result<KnockReport, AppError>) -> Vec<(&'static str, String)> {
+    let (outcome, code, error, failed, duration) = match result {
+        Ok(report) => {
+            let outcome = match report.interrupted {
+                true => "interrupted",
+                false => "success",
+            };
+            let duration = report.duration.as_millis().to_string();
+            (outcome, 0, String::new(), Vec::new(), duration)
+        }
+        Err(e) => (
+            "failure",
+            e.exit_code(),
+            e.to_string(),
+            failed_ports(e),
+            String::new(),
+        ),
+    };
+    let ports: Vec<String> = failed.iter().map(u16::to_string).collect();
+    vec![
+        ("KNOCK_RESULT", outcome.to_string()),
+        ("KNOCK_EXIT_CODE", code.to_string()),
+        ("KNOCK_ERROR", error),
+        (
+            "KNOCK_FAILED_PORT",
+            ports.first().cloned().unwrap_or_default(),
+        ),
+        ("KNOCK_FAILED_PORTS", ports.join(",")),
+        ("KNOCK_DURATION_MS", duration),
+    ]
+}
+
+/// The ports of the knocks a failed run names, in sequence order.
+fn failed_ports(e: &AppError) -> Vec<u16> {
+    match e {
+        AppError::Partial { failed, .. } => failed.iter().map(|f| f.port).collect(),
+        AppError::KnockFailed { port, .. }
+        | AppError::Timeout { port, .. }
+        | AppError::Lockstep { port, .. } => vec![*port],
+        _ => Vec::new(),
+    }
+}
+
+/// Run `command` with `env` added to the environment, for at most
+/// `timeout`. Fails with why when it cannot be started, exits unsuccessfully
+/// or is killed for running too long.
+#[cfg(feature = "runtime-tokio")]
+pub async fn run_hook(
+    command: &str,
+    env: &[(&'static str, String)],
+    timeout: Duration,
+) -> Result<(), String> {
+    use std::process::Stdio;
+    use tokio::process::Command;
+
+    let (shell, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let mut child = Command::new(shell)
+        .args([flag, command])
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("could not start: {e}"))?;
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(Ok(status)) if status.success() => Ok(()),
+        Ok(Ok(status)) => Err(match status.code() {
+            Some(code) => format!("exited with status {code}"),
+            None => format!("{status}"),
+        }),
+        Ok(Err(e)) => Err(format!("could not be waited for: {e}")),
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(format!("killed after running {}s", timeout.as_secs_f64()))
+        }
+    }
+}
+
+#[cfg(all(test, unix, feature = "runtime-tokio"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hooks_see_the_run_and_are_cut_short() {
+        let dir = std::env::temp_dir().join(format!("apk-hooks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("env");
+        let env = [
+            ("KNOCK_HOST", "example.com".to_string()),
+            ("KNOCK_FAILED_PORT", "8000".to_string()),
+        ];
+        let command = format!(
+            "echo \"$KNOCK_HOST $KNOCK_FAILED_PORT\" > '{}'",
+            out.display()
+        );
+        run_hook(&command, &env, DEFAULT_TIMEOUT).await.unwrap();
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(written, "example.com 8000\n");
+
+        let err = run_hook("exit 3", &env, DEFAULT_TIMEOUT).await;
+        assert_eq!(err.unwrap_err(), "exited with status 3");
+        let started = std::time::Instant::now();
+        let err = run_hook("sleep 10", &env, Duration::from_millis(200)).await;
+        assert_eq!(err.unwrap_err(), "killed after running 0.2s");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
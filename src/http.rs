@@ -0,0 +1,143 @@
+use crate::rt::Instant;
+use crate::{
+    config::KnockOpts,
+    events::{EventSink, KnockTarget},
+    outcome::{AttemptLog, KnockOutcome},
+    plan::StepKind,
+    protocol::Protocol,
+    retry::{retry_with_backoff, sync_on_timeout, RetryDecision},
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/// Perform an HTTP GET knock: connect to `addr`, send a minimal request
+/// for `path` naming `host` and read the status line. Any HTTP response
+/// (even a 404) means the knock was delivered.
+pub(crate) async fn knock_http(
+    host: &str,
+    addr: SocketAddr,
+    path: &str,
+    opts: &KnockOpts,
+    events: &EventSink,
+) -> KnockOutcome {
+    let port = addr.port();
+    let request = build_request(host, port, path);
+    let started = Instant::now();
+    let log = AttemptLog::new(
+        events,
+        KnockTarget {
+            step: Some(StepKind::Http {
+                path: path.to_string(),
+            }),
+            ..KnockTarget::new(host, port, Protocol::Tcp)
+        },
+    );
+    let Ok(retry) = retry_with_backoff(
+        opts.attempts,
+        opts.timeout,
+        &opts.backoff,
+        opts.deadline,
+        opts.rate.as_deref(),
+        opts.budget.as_deref(),
+        |attempt| {
+            let request = &request;
+            let log = &log;
+            async move {
+                let start = Instant::now();
+                match get_status_line(addr, request, opts.tcp.fwmark).await {
+                    Ok(status) => {
+                        let elapsed = start.elapsed();
+                        log.succeeded(attempt, elapsed, status);
+                        RetryDecision::<_, Infallible>::Done(elapsed) // stop retrying
+                    }
+                    Err(e) => {
+                        log.push(attempt, e.to_string());
+                        RetryDecision::<_, Infallible>::Retry // retry
+                    }
+                }
+            }
+        },
+        sync_on_timeout(|attempt, _| {
+            log.timed_out(attempt, "timed out");
+        }),
+    )
+    .await;
+
+    let latency = retry.value;
+    KnockOutcome {
+        port,
+        protocol: Protocol::Tcp,
+        addr: Some(addr),
+        source_port: None,
+        fwmark: None,
+        ttl: None,
+        payload_len: None,
+        attempts_allowed: None,
+        attempts: retry.attempts,
+        succeeded: retry.succeeded(),
+        acknowledged: latency.is_some(),
+        latency,
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: log.into_errors(),
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Send the request and return the trimmed status line.
+async fn get_status_line(
+    addr: SocketAddr,
+    request: &[u8],
+    fwmark: Option<u32>,
+) -> std::io::Result<String> {
+    let mut stream = crate::tcp::dial(addr, fwmark).await?;
+    stream.write_all(request).await?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    if !line.starts_with("HTTP/") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "response is not HTTP",
+        ));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Build the GET request. `path` is validated at parse time, so it can be
+/// embedded verbatim.
+fn build_request(host: &str, port: u16, path: &str) -> Vec<u8> {
+    let host = crate::scope::ascii_host(host).unwrap_or(host.into());
+    let host = if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.into_owned()
+    };
+    let authority = if port == 80 {
+        host
+    } else {
+        format!("{host}:{port}")
+    };
+    format!(
+        "GET {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: async_port_knocker\r\nConnection: close\r\n\r\n"
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_has_host_header() {
+        let req = build_request("example.com", 8080, "/knock/abc");
+        let req = String::from_utf8(req).unwrap();
+        assert!(req.starts_with("GET /knock/abc HTTP/1.1\r\n"));
+        assert!(req.contains("\r\nHost: example.com:8080\r\n"));
+        assert!(req.ends_with("\r\n\r\n"));
+
+        let req6 = String::from_utf8(build_request("::1", 80, "/")).unwrap();
+        assert!(req6.contains("\r\nHost: [::1]\r\n"));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::rt::{Instant, UdpSocket};
+use crate::{
+    config::KnockOpts,
+    events::{EventSink, KnockTarget},
+    outcome::{AttemptLog, KnockOutcome},
+    packet,
+    protocol::Protocol,
+    retry::{retry_with_backoff, sync_on_timeout, RetryDecision},
+    AppError,
+};
+use rand::{rngs::ThreadRng, RngCore};
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// Send ICMP echo requests whose payload is `size` bytes long.
+///
+/// In ICMP mode each number of the sequence is a payload size, not a port.
+/// With `wait_reply` an attempt only succeeds once the matching echo reply
+/// arrives; otherwise a successful send completes the knock.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn knock_icmp(
+    host: &str,
+    target: IpAddr,
+    size: u16,
+    opts: &KnockOpts,
+    wait_reply: bool,
+    events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    let started = Instant::now();
+    let socket = open_icmp_socket(target)?;
+    let ident = ThreadRng::default().next_u32() as u16;
+    let dst = SocketAddr::new(target, 0);
+
+    let log = AttemptLog::new(events, KnockTarget::new(host, size, Protocol::Icmp));
+
+    let retry = retry_with_backoff(
+        opts.attempts,
+        opts.timeout,
+        &opts.backoff,
+        opts.deadline,
+        opts.rate.as_deref(),
+        opts.budget.as_deref(),
+        |attempt| {
+            let socket = &socket;
+            let log = &log;
+            async move {
+                let start = Instant::now();
+                let seq = attempt as u16;
+                let req = packet::icmp_echo_request(target.is_ipv6(), ident, seq, size.into());
+                if let Err(e) = socket.send_to(&req, dst).await {
+                    log.push(attempt, e.to_string());
+                    return RetryDecision::Retry; // retry
+                }
+                if wait_reply {
+                    if let Err(e) = wait_for_reply(socket, target.is_ipv6(), seq).await {
+                        return RetryDecision::Fatal(e);
+                    }
+                }
+                let elapsed = start.elapsed();
+                let what = if wait_reply {
+                    "echo reply"
+                } else {
+                    "echo sent"
+                };
+                log.succeeded(attempt, elapsed, what);
+                RetryDecision::Done(elapsed) // stop retrying
+            }
+        },
+        sync_on_timeout(|attempt, _| {
+            log.timed_out(attempt, "no echo reply");
+        }),
+    )
+    .await?;
+
+    let latency = retry.value;
+    Ok(KnockOutcome {
+        port: size,
+        protocol: Protocol::Icmp,
+        addr: Some(dst),
+        source_port: None,
+        fwmark: None,
+        ttl: None,
+        payload_len: None,
+        attempts_allowed: None,
+        attempts: retry.attempts,
+        succeeded: retry.succeeded(),
+        acknowledged: wait_reply && latency.is_some(),
+        latency,
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: log.into_errors(),
+        elapsed: started.elapsed(),
+    })
+}
+
+/// Read until the echo reply for `seq` arrives; the caller's timeout bounds it.
+async fn wait_for_reply(socket: &UdpSocket, v6: bool, seq: u16) -> Result<(), AppError> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, _) = socket.recv_from(&mut buf).await?;
+        if packet::is_echo_reply(&buf[..n], v6, seq) {
+            return Ok(());
+        }
+    }
+}
+
+/// Open an unprivileged ping socket if the kernel allows it
+/// (net.ipv4.ping_group_range), falling back to a raw ICMP socket.
+fn open_icmp_socket(target: IpAddr) -> Result<UdpSocket, AppError> {
+    let (domain, proto) = match target {
+        IpAddr::V4(_) => (Domain::IPV4, socket2::Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, socket2::Protocol::ICMPV6),
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(proto))
+        .or_else(|_| Socket::new(domain, Type::RAW, Some(proto)))
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => AppError::RawSocket(
+                "ICMP knocks need CAP_NET_RAW, or your group in net.ipv4.ping_group_range \
+                 (e.g. `sysctl net.ipv4.ping_group_range=\"0 2147483647\"`)"
+                    .into(),
+            ),
+            _ => AppError::Io(e),
+        })?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(std::net::UdpSocket::from(socket))?)
+}
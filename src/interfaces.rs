@@ -0,0 +1,343 @@
+//! The local network interfaces and the addresses knocks can leave from,
+//! for the `interfaces` subcommand and for checking an interface given on
+//! the command line before any knock goes out.
+//!
+//! Which interface holds the default route of each IP version is found by
+//! asking the kernel which address it would send to a documentation
+//! address from; no packet is sent.
+
+use std::fmt::Write;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+/// A local interface and its addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalInterface {
+    pub name: String,
+    /// The index IPv6 zones and IPV6_MULTICAST_IF take, when known.
+    pub index: Option<u32>,
+    /// Operationally up.
+    pub up: bool,
+    pub loopback: bool,
+    pub addrs: Vec<IpAddr>,
+    /// The IPv4 default route leaves from one of its addresses.
+    pub default_v4: bool,
+    /// The IPv6 default route leaves from one of its addresses.
+    pub default_v6: bool,
+}
+
+impl LocalInterface {
+    /// The address as given on the command line: link-local IPv6 ones
+    /// with their `%interface` zone, which they need to be used.
+    pub fn display_addr(&self, addr: IpAddr) -> String {
+        match addr {
+            IpAddr::V6(ip) if crate::scope::is_link_local(&ip) => format!("{ip}%{}", self.name),
+            _ => addr.to_string(),
+        }
+    }
+}
+
+/// Every interface with at least one address, in the order the system
+/// lists them.
+pub fn list() -> io::Result<Vec<LocalInterface>> {
+    let default_v4 = default_source(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    let default_v6 = default_source(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)));
+    let mut interfaces: Vec<LocalInterface> = Vec::new();
+    for entry in if_addrs::get_if_addrs()? {
+        let ip = entry.ip();
+        let position = interfaces.iter().position(|i| i.name == entry.name);
+        let interface = match position {
+            Some(position) => &mut interfaces[position],
+            None => {
+                interfaces.push(LocalInterface {
+                    name: entry.name.clone(),
+                    index: entry.index,
+                    up: entry.is_oper_up(),
+                    loopback: entry.is_loopback(),
+                    addrs: Vec::new(),
+                    default_v4: false,
+                    default_v6: false,
+                });
+                interfaces.last_mut().expect("just pushed")
+            }
+        };
+        // Some systems report an interface unknown or dormant on one
+        // address and up on the next
+        interface.up |= entry.is_oper_up();
+        interface.loopback &= entry.is_loopback();
+        interface.default_v4 |= default_v4 == Some(ip);
+        interface.default_v6 |= default_v6 == Some(ip);
+        interface.addrs.push(ip);
+    }
+    Ok(interfaces)
+}
+
+/// The local address a datagram to `remote` would leave from, `None`
+/// when nothing routes there.
+fn default_source(remote: IpAddr) -> Option<IpAddr> {
+    let local = match remote {
+        IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        IpAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let sock = UdpSocket::bind(local).ok()?;
+    // Connecting a UDP socket only picks the route
+    sock.connect((remote, 9)).ok()?;
+    Some(sock.local_addr().ok()?.ip())
+}
+
+/// A table of the interfaces, one line each.
+pub fn table(interfaces: &[LocalInterface]) -> String {
+    let rows: Vec<[String; 5]> = interfaces
+        .iter()
+        .map(|i| {
+            let addrs: Vec<String> = i.addrs.iter().map(|&a| i.display_addr(a)).collect();
+            [
+                i.name.clone(),
+                i.index.map_or("-".into(), |index| index.to_string()),
+                if i.up { "up" } else { "down" }.into(),
+                default_families(i).join(","),
+                addrs.join(", "),
+            ]
+        })
+        .collect();
+    let header = ["INTERFACE", "INDEX", "STATE", "DEFAULT", "ADDRESSES"];
+    let width = |column: usize| {
+        rows.iter()
+            .map(|row| row[column].len())
+            .chain([header[column].len()])
+            .max()
+            .unwrap_or(0)
+    };
+    let widths: Vec<usize> = (0..4).map(width).collect();
+    let mut out = String::new();
+    for row in std::iter::once(header.map(String::from)).chain(rows) {
+        let mut line = String::new();
+        for (column, cell) in row.iter().enumerate().take(4) {
+            let _ = write!(line, "{cell:<0$}  ", widths[column]);
+        }
+        line.push_str(&row[4]);
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// The interfaces as a JSON array, as they serialize, e.g.
+/// `[{"name":"eth0","index":2,"up":true,"loopback":false,"default_route":["ipv4"],"addresses":["192.0.2.5"]}]`.
+#[cfg(feature = "serde")]
+pub fn json(interfaces: &[LocalInterface]) -> String {
+    serde_json::to_string(interfaces).expect("interfaces serialize")
+}
+
+/// The interface as the `interfaces` subcommand lists it: the families
+/// whose default route leaves from it, and its addresses as given on the
+/// command line.
+#[cfg(feature = "serde")]
+impl serde::Serialize for LocalInterface {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let addresses: Vec<String> = self.addrs.iter().map(|&a| self.display_addr(a)).collect();
+        let mut interface = serializer.serialize_struct("LocalInterface", 6)?;
+        interface.serialize_field("name", &self.name)?;
+        interface.serialize_field("index", &self.index)?;
+        interface.serialize_field("up", &self.up)?;
+        interface.serialize_field("loopback", &self.loopback)?;
+        interface.serialize_field("default_route", &default_families(self))?;
+        interface.serialize_field("addresses", &addresses)?;
+        interface.end()
+    }
+}
+
+fn default_families(interface: &LocalInterface) -> Vec<&'static str> {
+    let mut families = Vec::new();
+    if interface.default_v4 {
+        families.push("ipv4");
+    }
+    if interface.default_v6 {
+        families.push("ipv6");
+    }
+    families
+}
+
+/// Check that `addr` belongs to a local interface, and to the one with
+/// `index` when given, suggesting the addresses of the same IP version
+/// the interfaces have when it does not. The unspecified address stands
+/// for the system's choice and always passes.
+pub fn check_address(
+    interfaces: &[LocalInterface],
+    addr: IpAddr,
+    index: Option<u32>,
+) -> Result<(), String> {
+    if addr.is_unspecified() {
+        return Ok(());
+    }
+    let owner = interfaces.iter().find(|i| {
+        i.addrs.contains(&addr) && index.is_none_or(|index| i.index.is_none_or(|i| i == index))
+    });
+    if owner.is_some() {
+        return Ok(());
+    }
+    if let (Some(index), Some(other)) = (index, interfaces.iter().find(|i| i.addrs.contains(&addr)))
+    {
+        return Err(format!(
+            "{addr} is on interface {}, not interface {index}; did you mean {}?",
+            other.name,
+            other.display_addr(addr)
+        ));
+    }
+    // Up interfaces first, then the addresses sharing the longest prefix
+    let mut candidates: Vec<(bool, u32, String)> = interfaces
+        .iter()
+        .flat_map(|i| i.addrs.iter().map(move |&a| (i, a)))
+        .filter(|(_, a)| a.is_ipv6() == addr.is_ipv6())
+        .map(|(i, a)| {
+            let named = format!("{} ({})", i.display_addr(a), i.name);
+            (!i.up, common_prefix(addr, a), named)
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    let mut message = format!("no local interface has the address {addr}");
+    let suggestions: Vec<String> = candidates.into_iter().take(3).map(|c| c.2).collect();
+    match suggestions.as_slice() {
+        [] => {}
+        [only] => {
+            let _ = write!(message, "; did you mean {only}?");
+        }
+        [rest @ .., last] => {
+            let _ = write!(message, "; did you mean {} or {last}?", rest.join(", "));
+        }
+    }
+    Err(message)
+}
+
+/// Bits two addresses of the same IP version have in common from the
+/// start.
+fn common_prefix(a: IpAddr, b: IpAddr) -> u32 {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (a.to_bits() ^ b.to_bits()).leading_zeros(),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (a.to_bits() ^ b.to_bits()).leading_zeros(),
+        _ => 0,
+    }
+}
+
+/// `; did you mean NAME?` naming the local interface closest to the
+/// mistyped `name`, or nothing when none is close or they cannot be
+/// listed.
+pub fn did_you_mean(name: &str) -> String {
+    let Ok(interfaces) = list() else {
+        return String::new();
+    };
+    closest_name(&interfaces, name)
+        .map(|found| format!("; did you mean {found}?"))
+        .unwrap_or_default()
+}
+
+/// The interface name at most a third of `name` away in edits, the
+/// closest first.
+fn closest_name<'a>(interfaces: &'a [LocalInterface], name: &str) -> Option<&'a str> {
+    interfaces
+        .iter()
+        .map(|i| (edit_distance(&i.name, name), i.name.as_str()))
+        .filter(|&(distance, _)| distance <= name.chars().count().div_ceil(3))
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, found)| found)
+}
+
+/// Levenshtein distance, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, index: u32, up: bool, addrs: &[&str]) -> LocalInterface {
+        LocalInterface {
+            name: name.into(),
+            index: Some(index),
+            up,
+            loopback: name == "lo",
+            addrs: addrs.iter().map(|a| a.parse().unwrap()).collect(),
+            default_v4: name == "eth0",
+            default_v6: false,
+        }
+    }
+
+    fn sample() -> Vec<LocalInterface> {
+        vec![
+            interface("lo", 1, true, &["127.0.0.1", "::1"]),
+            interface("eth0", 2, true, &["192.0.2.5", "fe80::5"]),
+            interface("wlan0", 3, false, &["192.0.2.9", "198.51.100.7"]),
+        ]
+    }
+
+    #[test]
+    fn addresses_are_checked_with_suggestions() {
+        let interfaces = sample();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(check_address(&interfaces, ip("192.0.2.5"), None), Ok(()));
+        assert_eq!(check_address(&interfaces, ip("0.0.0.0"), None), Ok(()));
+        assert_eq!(check_address(&interfaces, ip("fe80::5"), Some(2)), Ok(()));
+        assert_eq!(
+            check_address(&interfaces, ip("192.0.2.6"), None).unwrap_err(),
+            "no local interface has the address 192.0.2.6; did you mean \
+             192.0.2.5 (eth0), 127.0.0.1 (lo) or 192.0.2.9 (wlan0)?"
+        );
+        assert_eq!(
+            check_address(&interfaces, ip("fe80::5"), Some(3)).unwrap_err(),
+            "fe80::5 is on interface eth0, not interface 3; did you mean fe80::5%eth0?"
+        );
+        assert_eq!(
+            check_address(&interfaces[..1], ip("2001:db8::1"), None).unwrap_err(),
+            "no local interface has the address 2001:db8::1; did you mean ::1 (lo)?"
+        );
+    }
+
+    #[test]
+    fn names_close_to_a_typo_are_suggested() {
+        let interfaces = sample();
+        assert_eq!(closest_name(&interfaces, "eht0"), Some("eth0"));
+        assert_eq!(closest_name(&interfaces, "wlan1"), Some("wlan0"));
+        assert_eq!(closest_name(&interfaces, "docker0"), None);
+    }
+
+    #[test]
+    fn table_and_json_list_every_address() {
+        let interfaces = sample();
+        assert_eq!(
+            table(&interfaces[..2]),
+            "INTERFACE  INDEX  STATE  DEFAULT  ADDRESSES\n\
+             lo         1      up              127.0.0.1, ::1\n\
+             eth0       2      up     ipv4     192.0.2.5, fe80::5%eth0\n"
+        );
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            json(&interfaces[1..2]),
+            "[{\"name\":\"eth0\",\"index\":2,\"up\":true,\"loopback\":false,\
+             \"default_route\":[\"ipv4\"],\"addresses\":[\"192.0.2.5\",\"fe80::5%eth0\"]}]"
+        );
+    }
+
+    #[test]
+    fn loopback_is_listed() {
+        let interfaces = list().unwrap();
+        assert!(
+            interfaces
+                .iter()
+                .any(|i| i.loopback && i.addrs.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST))),
+            "{interfaces:?}"
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! TCP knocks through an SSH jump host.
+//!
+//! With a [`JumpHost`] set, each TCP knock connection is a `direct-tcpip`
+//! channel the bastion opens to the target, the way `ssh -W host:port`
+//! forwards one, and the bastion resolves the target's name. Connections
+//! are made by the system `ssh` client (`ssh` feature), so the usual
+//! `~/.ssh/config`, `known_hosts` and ssh-agent apply; it runs in batch
+//! mode and never prompts.
+//!
+//! A channel the bastion could not open is reported as the connect error
+//! a direct dial would have given: "connect failed: Connection refused"
+//! is a refused connect, which counts as a delivered knock, and
+//! "administratively prohibited" is a connect the local host does not
+//! permit. Anything that keeps the bastion itself from being used (no
+//! route, authentication, host key) is [`AppError::Jump`], which is not
+//! retried.
+
+use std::fmt;
+
+/// An SSH bastion TCP knocks are forwarded through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JumpHost {
+    /// Login name; `ssh`'s own default (the config file or `$USER`) when
+    /// unset.
+    pub user: Option<String>,
+    pub host: String,
+    /// SSH port; `ssh`'s own default (the config file or 22) when unset.
+    pub port: Option<u16>,
+    /// The `ssh` client to run.
+    pub program: String,
+}
+
+impl JumpHost {
+    /// Parse `[user@]host[:port]`; an IPv6 host with a port is bracketed.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (user, rest) = match s.rsplit_once('@') {
+            Some((user, rest)) if !user.is_empty() => (Some(user.to_string()), rest),
+            Some(_) => return Err(format!("'{s}' has an empty user before '@'")),
+            None => (None, s),
+        };
+        let port = |p: &str| {
+            p.parse::<u16>()
+                .ok()
+                .filter(|&p| p != 0)
+                .ok_or_else(|| format!("'{p}' in '{s}' is not an SSH port"))
+        };
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            let Some((host, after)) = bracketed.split_once(']') else {
+                return Err(format!("'{s}' is missing the closing ']'"));
+            };
+            match after.strip_prefix(':') {
+                Some(p) => (host, Some(port(p)?)),
+                None if after.is_empty() => (host, None),
+                None => return Err(format!("unexpected '{after}' after ']' in '{s}'")),
+            }
+        } else {
+            match rest.split_once(':') {
+                // A bare IPv6 address has no port
+                Some(_) if rest.parse::<std::net::Ipv6Addr>().is_ok() => (rest, None),
+                Some((host, p)) => (host, Some(port(p)?)),
+                None => (rest, None),
+            }
+        };
+        if host.is_empty() || host.starts_with('-') || host.contains(char::is_whitespace) {
+            return Err(format!("'{s}' does not name a jump host"));
+        }
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+            program: "ssh".into(),
+        })
+    }
+
+    /// The arguments that make `ssh` forward its stdio to `host:port`
+    /// through the bastion. `-vv` makes it log the channel's open
+    /// confirmation, which is how a forwarded connect is known to have
+    /// succeeded.
+    pub fn args(&self, host: &str, port: u16) -> Vec<String> {
+        let target = match host.contains(':') {
+            true => format!("[{host}]:{port}"),
+            false => format!("{host}:{port}"),
+        };
+        let mut args: Vec<String> = ["-vv", "-o", "BatchMode=yes", "-W"]
+            .map(String::from)
+            .into();
+        args.push(target);
+        if let Some(port) = self.port {
+            args.extend(["-p".into(), port.to_string()]);
+        }
+        if let Some(user) = &self.user {
+            args.extend(["-l".into(), user.clone()]);
+        }
+        args.extend(["--".into(), self.host.clone()]);
+        args
+    }
+}
+
+/// `[user@]host[:port]`, as parsed.
+impl fmt::Display for JumpHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{user}@")?;
+        }
+        match (self.port, self.host.contains(':')) {
+            (Some(port), true) => write!(f, "[{}]:{port}", self.host),
+            (Some(port), false) => write!(f, "{}:{port}", self.host),
+            (None, _) => f.write_str(&self.host),
+        }
+    }
+}
+
+/// Why a channel the bastion refused to open is not retried.
+pub(crate) const PROHIBITED_HINT: &str =
+    "the jump host does not allow forwarding there; check AllowTcpForwarding and PermitOpen \
+     in its sshd_config";
+
+#[cfg(feature = "ssh")]
+pub use forward::JumpStream;
+
+#[cfg(feature = "ssh")]
+mod forward {
+    use super::JumpHost;
+    use crate::AppError;
+    use std::io;
+    use std::pin::Pin;
+    use std::process::Stdio;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+    use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+    /// What a line `ssh -vv` logged says about the forwarded channel.
+    #[derive(Debug)]
+    pub(super) enum ChannelLine {
+        /// The bastion opened the channel: the target accepted the connect.
+        Confirmed,
+        /// The bastion could not open it, as the connect error it stands for.
+        Failed(io::Error),
+        /// Anything else.
+        Other,
+    }
+
+    /// Read a line of `ssh`'s log.
+    pub(super) fn channel_line(line: &str) -> ChannelLine {
+        if line.contains(": open confirm") {
+            return ChannelLine::Confirmed;
+        }
+        let Some((_, reason)) = line.split_once(": open failed: ") else {
+            return ChannelLine::Other;
+        };
+        let (kind, detail) = reason.split_once(": ").unwrap_or((reason, ""));
+        let error = match kind {
+            "administratively prohibited" => io::ErrorKind::PermissionDenied,
+            "connect failed" => connect_error(detail),
+            _ => io::ErrorKind::Other,
+        };
+        ChannelLine::Failed(io::Error::new(
+            error,
+            format!("jump host: {}", reason.trim()),
+        ))
+    }
+
+    /// The error kind of the far end's connect, from its `strerror` text.
+    fn connect_error(detail: &str) -> io::ErrorKind {
+        let detail = detail.to_ascii_lowercase();
+        let kinds = [
+            ("refused", io::ErrorKind::ConnectionRefused),
+            ("timed out", io::ErrorKind::TimedOut),
+            ("no route", io::ErrorKind::HostUnreachable),
+            ("network is unreachable", io::ErrorKind::NetworkUnreachable),
+            ("permission denied", io::ErrorKind::PermissionDenied),
+            ("not known", io::ErrorKind::NotFound),
+        ];
+        kinds
+            .into_iter()
+            .find(|(text, _)| detail.contains(text))
+            .map_or(io::ErrorKind::Other, |(_, kind)| kind)
+    }
+
+    /// Whether a line of `ssh`'s log is its own chatter rather than an error
+    /// worth repeating.
+    fn is_debug(line: &str) -> bool {
+        line.starts_with("debug") || line.starts_with("OpenSSH_") || line.is_empty()
+    }
+
+    /// A connection forwarded through the bastion: the stdio of the `ssh`
+    /// client carrying it, which is killed when the stream is dropped.
+    #[derive(Debug)]
+    pub struct JumpStream {
+        stdout: ChildStdout,
+        stdin: ChildStdin,
+        _child: Child,
+    }
+
+    impl JumpHost {
+        /// Have the bastion connect to `host:port`. The outer error is a
+        /// bastion that cannot be used; the inner result is the target
+        /// connect as a direct dial would have reported it.
+        pub async fn connect(
+            &self,
+            host: &str,
+            port: u16,
+        ) -> Result<io::Result<JumpStream>, AppError> {
+            let mut child = Command::new(&self.program)
+                .args(self.args(host, port))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| AppError::Jump(format!("cannot run {}: {e}", self.program)))?;
+            let (Some(stdin), Some(stdout), Some(stderr)) =
+                (child.stdin.take(), child.stdout.take(), child.stderr.take())
+            else {
+                unreachable!("all three streams are piped");
+            };
+            let mut lines = BufReader::new(stderr).lines();
+            let mut errors = Vec::new();
+            loop {
+                let line = lines
+                    .next_line()
+                    .await
+                    .map_err(|e| AppError::Jump(format!("reading {} output: {e}", self.program)))?;
+                let Some(line) = line else {
+                    // ssh exited without opening the channel
+                    let reason = errors.last().map_or("ssh exited", String::as_str);
+                    return Err(AppError::Jump(format!("{self}: {reason}")));
+                };
+                match channel_line(&line) {
+                    ChannelLine::Confirmed => break,
+                    ChannelLine::Failed(e) => return Ok(Err(e)),
+                    ChannelLine::Other if !is_debug(&line) => errors.push(line),
+                    ChannelLine::Other => {}
+                }
+            }
+            // Keep reading the log so ssh never blocks writing it
+            tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+            Ok(Ok(JumpStream {
+                stdout,
+                stdin,
+                _child: child,
+            }))
+        }
+    }
+
+    impl AsyncRead for JumpStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stdout).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for JumpStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.stdin).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stdin).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stdin).poll_shutdown(cx)
+        }
+    }
+}
+
+/// Without the `ssh` feature a jump host cannot be used.
+#[cfg(not(feature = "ssh"))]
+impl JumpHost {
+    pub async fn connect(
+        &self,
+        _host: &str,
+        _port: u16,
+    ) -> Result<std::io::Result<crate::rt::TcpStream>, crate::AppError> {
+        Err(crate::AppError::InvalidConfig(
+            "--jump requires building with `--features ssh`".into(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jump_hosts_parse_like_ssh_destinations() {
+        let jump = JumpHost::parse("ops@bastion.example:2222").unwrap();
+        assert_eq!(jump.user.as_deref(), Some("ops"));
+        assert_eq!(jump.host, "bastion.example");
+        assert_eq!(jump.port, Some(2222));
+        assert_eq!(jump.to_string(), "ops@bastion.example:2222");
+        let plain = JumpHost::parse("bastion").unwrap();
+        assert_eq!((plain.user, plain.port), (None, None));
+        assert_eq!(
+            JumpHost::parse("[2001:db8::1]:22").unwrap().host,
+            "2001:db8::1"
+        );
+        assert_eq!(JumpHost::parse("2001:db8::1").unwrap().port, None);
+        for bad in [
+            "",
+            "@bastion",
+            "bastion:ssh",
+            "bastion:0",
+            "-oProxyCommand=x",
+            "[::1",
+        ] {
+            assert!(JumpHost::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn ssh_forwards_stdio_to_the_target() {
+        let jump = JumpHost::parse("ops@bastion:2222").unwrap();
+        let args = jump.args("10.0.0.5", 7000);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        assert_eq!(
+            args,
+            [
+                "-vv",
+                "-o",
+                "BatchMode=yes",
+                "-W",
+                "10.0.0.5:7000",
+                "-p",
+                "2222",
+                "-l",
+                "ops",
+                "--",
+                "bastion"
+            ]
+        );
+        let v6 = JumpHost::parse("bastion").unwrap().args("2001:db8::5", 22);
+        assert!(v6.contains(&"[2001:db8::5]:22".to_string()));
+    }
+
+    #[cfg(feature = "ssh")]
+    #[test]
+    fn channel_failures_read_as_connect_errors() {
+        use super::forward::{channel_line, ChannelLine};
+        use std::io;
+        let kind = |line| match channel_line(line) {
+            ChannelLine::Failed(e) => Some(e.kind()),
+            _ => None,
+        };
+        assert_eq!(
+            kind("channel 0: open failed: connect failed: Connection refused"),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+        assert_eq!(
+            kind("channel 0: open failed: administratively prohibited: open failed"),
+            Some(io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(
+            kind("channel 2: open failed: connect failed: No route to host"),
+            Some(io::ErrorKind::HostUnreachable)
+        );
+        assert_eq!(
+            kind("channel 0: open failed: connect failed: Connection timed out"),
+            Some(io::ErrorKind::TimedOut)
+        );
+        assert!(matches!(
+            channel_line("debug2: channel 0: open confirm rwindow 2097152 rmax 32768"),
+            ChannelLine::Confirmed
+        ));
+        assert!(matches!(
+            channel_line("debug1: Authenticating to bastion:22 as 'ops'"),
+            ChannelLine::Other
+        ));
+    }
+
+    #[cfg(feature = "ssh")]
+    #[test]
+    fn classified_like_a_local_connect() {
+        use super::forward::{channel_line, ChannelLine};
+        use crate::errors::ErrorClass;
+        let error = |line| match channel_line(line) {
+            ChannelLine::Failed(e) => e,
+            other => panic!("{other:?}"),
+        };
+        let refused = error("channel 0: open failed: connect failed: Connection refused");
+        assert_eq!(ErrorClass::of(&refused), ErrorClass::Delivered);
+        let prohibited = error("channel 0: open failed: administratively prohibited: ");
+        assert_eq!(ErrorClass::of(&prohibited), ErrorClass::Fatal);
+    }
+
+    /// A stand-in for `ssh` that logs `stderr` and, if it confirms the
+    /// channel, echoes its stdin.
+    #[cfg(all(feature = "ssh", unix))]
+    fn fake_ssh(name: &str, stderr: &str) -> JumpHost {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("fake-ssh-{}-{name}", std::process::id()));
+        std::fs::write(
+            &path,
+            format!("#!/bin/sh\nprintf '{stderr}' >&2\nexec cat\n"),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        JumpHost {
+            program: path.to_string_lossy().into_owned(),
+            ..JumpHost::parse("bastion").unwrap()
+        }
+    }
+
+    #[cfg(all(feature = "ssh", unix))]
+    #[tokio::test]
+    async fn forwarded_connects_through_a_fake_ssh() {
+        use crate::AppError;
+        use std::io;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let open = fake_ssh(
+            "open",
+            "debug1: Authentication succeeded (publickey).\\n\
+             debug2: channel 0: open confirm rwindow 2097152 rmax 32768\\n",
+        );
+        let mut stream = open.connect("10.0.0.5", 22).await.unwrap().unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+
+        let refused = fake_ssh(
+            "refused",
+            "channel 0: open failed: connect failed: Connection refused\\n",
+        );
+        let error = refused.connect("10.0.0.5", 22).await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+
+        let auth = JumpHost {
+            program: "/bin/sh".into(),
+            ..JumpHost::parse("bastion").unwrap()
+        };
+        // sh rejects ssh's arguments and exits, as ssh would on a failed login
+        let error = auth.connect("10.0.0.5", 22).await.unwrap_err();
+        assert!(matches!(error, AppError::Jump(_)), "{error}");
+
+        for fake in [open, refused] {
+            std::fs::remove_file(fake.program).unwrap();
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! The knockd side of a knock sequence.
+//!
+//! [`export_section`] writes the `knockd.conf` section that opens for the
+//! sequence a [`KnockConfig`] sends, and [`parse_sequence`] reads the
+//! `sequence` line of such a section back.
+
+use crate::config::KnockConfig;
+use crate::packet::TcpFlags;
+use crate::plan::StepKind;
+use crate::protocol::Protocol;
+use crate::AppError;
+use std::fmt::Write;
+
+/// Name of the exported section.
+pub const SECTION: &str = "openCustom";
+
+/// Command of the exported section, to be edited: `%IP%` is the knocking
+/// address.
+pub const COMMAND_TEMPLATE: &str = "/sbin/iptables -I INPUT -s %IP% -p tcp --dport 22 -j ACCEPT";
+
+/// Format a sequence as knockd's `sequence =` line, e.g.
+/// `sequence = 7000:tcp,8000:udp`.
+pub fn sequence_line(sequence: &[(u16, Protocol)]) -> String {
+    let steps: Vec<String> = sequence
+        .iter()
+        .map(|(port, protocol)| format!("{port}:{protocol}"))
+        .collect();
+    format!("sequence = {}", steps.join(","))
+}
+
+/// Read the ports of a knockd `sequence` line, with or without the
+/// `sequence =` key; a port without a protocol is TCP, as in knockd.
+pub fn parse_sequence(line: &str) -> Result<Vec<(u16, Protocol)>, String> {
+    let value = match line.split_once('=') {
+        Some((key, value)) if key.trim().eq_ignore_ascii_case("sequence") => value,
+        Some((key, _)) => return Err(format!("'{}' is not a sequence line", key.trim())),
+        None => line,
+    };
+    value
+        .split(',')
+        .map(|step| {
+            let (port, protocol) = match step.trim().split_once(':') {
+                Some((port, protocol)) => (port, protocol.trim().to_ascii_lowercase()),
+                None => (step.trim(), "tcp".to_string()),
+            };
+            let port = port
+                .trim()
+                .parse::<u16>()
+                .map_err(|_| format!("'{port}' is not a valid port"))?;
+            match protocol.as_str() {
+                "tcp" => Ok((port, Protocol::Tcp)),
+                "udp" => Ok((port, Protocol::Udp)),
+                other => Err(format!("knockd has no protocol '{other}'")),
+            }
+        })
+        .collect()
+}
+
+/// Write the knockd section matching what `config` sends: its ports and
+/// their protocols, a `seq_timeout` covering the slowest run the delays,
+/// timeouts and attempts allow, the TCP flags of its TCP knocks, and
+/// [`COMMAND_TEMPLATE`].
+///
+/// knockd only hears TCP and UDP knocks at fixed ports, so ICMP and SCTP
+/// steps, TOTP-derived sequences and runs with no bound on their length
+/// are refused.
+pub fn export_section(config: &KnockConfig) -> Result<String, AppError> {
+    config.validate()?;
+    let invalid = |msg: &str| Err(AppError::InvalidConfig(msg.into()));
+    if config.totp.is_some() {
+        return invalid("a TOTP sequence changes every step; knockd cannot follow it");
+    }
+    if config.passphrase_ports.is_some() {
+        return invalid("a passphrase-derived sequence is only shown with --dry-run");
+    }
+    if config.script.is_some() {
+        return invalid("a scripted sequence is only worked out when the run starts");
+    }
+    if config.length_sequence.is_some() {
+        return invalid("knockd hears ports, not the lengths of datagrams");
+    }
+    if config.timing_code.is_some() {
+        return invalid("knockd hears ports, not the gaps between knocks");
+    }
+    let mut sequence = Vec::with_capacity(config.sequence.len());
+    for step in config.sequence.iter() {
+        // HTTP and TLS steps connect over TCP and QUIC and plugin steps
+        // send UDP, whatever the run's protocol
+        let protocol = match step.kind {
+            Some(StepKind::Quic | StepKind::Plugin { .. }) => Protocol::Udp,
+            Some(_) => Protocol::Tcp,
+            None => step.protocol.unwrap_or(config.protocol),
+        };
+        if !matches!(protocol, Protocol::Tcp | Protocol::Udp) {
+            return Err(AppError::InvalidConfig(format!(
+                "knockd cannot hear the {protocol} knock on port {}",
+                step.port
+            )));
+        }
+        sequence.push((step.port, protocol));
+    }
+    let Some(duration) = crate::confirm::max_duration(config) else {
+        return invalid("knocks retry without end; set --attempts or --knock-deadline");
+    };
+    let seq_timeout = duration.as_millis().div_ceil(1000).max(1);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "[{SECTION}]");
+    let _ = writeln!(out, "    {}", sequence_line(&sequence));
+    let _ = writeln!(out, "    seq_timeout = {seq_timeout}");
+    if sequence.iter().any(|&(_, p)| p == Protocol::Tcp) {
+        let flags = config.tcp_flags.unwrap_or(TcpFlags::SYN);
+        let _ = writeln!(out, "    tcpflags = {}", knockd_flags(flags));
+    }
+    let _ = writeln!(out, "    command = {COMMAND_TEMPLATE}");
+    Ok(out)
+}
+
+/// TCP flags as knockd lists them: `syn`, `fin,psh,urg`, and so on.
+fn knockd_flags(flags: TcpFlags) -> String {
+    flags.to_string().to_ascii_lowercase().replace('|', ",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::KnockPlan;
+
+    fn config(sequence: &str) -> KnockConfig {
+        KnockConfig::builder()
+            .host("knock.example")
+            .plan(sequence.parse::<KnockPlan>().unwrap())
+            .timeout(500)
+            .delay(100)
+            .attempts(2)
+            .build()
+            .unwrap()
+    }
+
+    fn line<'a>(section: &'a str, key: &str) -> &'a str {
+        section
+            .lines()
+            .find(|l| l.trim_start().starts_with(key))
+            .unwrap_or_else(|| panic!("no {key} in {section}"))
+            .trim()
+    }
+
+    #[test]
+    fn round_trips_mixed_sequences() {
+        let config = config("7000,8000/udp,9000:http:/k,9100/tcp");
+        let section = export_section(&config).unwrap();
+        assert!(section.starts_with("[openCustom]\n"), "{section}");
+        assert_eq!(
+            line(&section, "sequence"),
+            "sequence = 7000:tcp,8000:udp,9000:tcp,9100:tcp"
+        );
+        assert_eq!(line(&section, "tcpflags"), "tcpflags = syn");
+        assert!(line(&section, "command").contains("%IP%"));
+
+        let imported = parse_sequence(line(&section, "sequence")).unwrap();
+        let sent: Vec<(u16, Protocol)> = config
+            .sequence
+            .iter()
+            .map(|s| (s.port, s.protocol.unwrap_or(Protocol::Tcp)))
+            .collect();
+        assert_eq!(imported, sent);
+
+        // Steps of a UDP run keep their own protocols
+        let udp = KnockConfig {
+            protocol: Protocol::Udp,
+            ..config
+        };
+        let section = export_section(&udp).unwrap();
+        assert_eq!(
+            line(&section, "sequence"),
+            "sequence = 7000:udp,8000:udp,9000:tcp,9100:tcp"
+        );
+    }
+
+    #[test]
+    fn seq_timeout_covers_the_slowest_run() {
+        let config = config("7000,8000,9000");
+        let worst = crate::confirm::max_duration(&config).unwrap();
+        let section = export_section(&config).unwrap();
+        let seconds: u128 = line(&section, "seq_timeout")
+            .trim_start_matches("seq_timeout = ")
+            .parse()
+            .unwrap();
+        assert!(
+            seconds * 1000 >= worst.as_millis(),
+            "{seconds}s < {worst:?}"
+        );
+        assert!((seconds - 1) * 1000 < worst.as_millis());
+    }
+
+    #[test]
+    fn udp_only_sequences_have_no_tcp_flags() {
+        let section = export_section(&config("7000/udp,8000/udp")).unwrap();
+        assert!(!section.contains("tcpflags"), "{section}");
+    }
+
+    #[test]
+    fn refuses_what_knockd_cannot_hear() {
+        assert!(export_section(&config("7000,8000/icmp")).is_err());
+        let endless = KnockConfig {
+            attempts: crate::retry::Attempts::Unlimited,
+            ..config("7000")
+        };
+        assert!(export_section(&endless).is_err());
+    }
+
+    #[test]
+    fn parses_knockd_sequence_lines() {
+        assert_eq!(
+            parse_sequence("sequence    = 7000:tcp, 8000:UDP,9000").unwrap(),
+            [
+                (7000, Protocol::Tcp),
+                (8000, Protocol::Udp),
+                (9000, Protocol::Tcp)
+            ]
+        );
+        assert!(parse_sequence("seq_timeout = 5").is_err());
+        assert!(parse_sequence("7000:icmp").is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Knock ladders: other sequences to try when one does not open the host
+//! (`--ladder`, a plan stage's `ladders`).
+//!
+//! While a host's sequence is being rotated both the old and the new one
+//! may be the one it has. A ladder knocks the configured sequence, then
+//! checks with a [`Verify`] connect that it opened the host; when not, it
+//! waits out the cooldown and knocks the next rung, until one verifies or
+//! none is left. However many rungs there are, the whole climb is cut off
+//! once its `max_time` runs out, so the worst case stays bounded.
+//!
+//! A ladder with no rungs beyond the configured sequence only verifies it
+//! (`--verify` on its own).
+
+use crate::events::EventSink;
+use crate::plan::KnockPlan;
+use crate::verify::{self, BannerCheck, Verify};
+use crate::{rt, AppError, KnockConfig, KnockReport};
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// How long to wait before the next rung when the ladder gives no cooldown.
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(2);
+
+/// How long a whole climb may take when the ladder gives no limit.
+pub const DEFAULT_MAX_TIME: Duration = Duration::from_secs(60);
+
+/// The sequences to fall back on, and how a rung is known to have worked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ladder {
+    /// Sequences knocked in order after the configured one, each when the
+    /// one before did not verify.
+    pub rungs: Vec<KnockPlan>,
+    pub verify: Verify,
+    /// Wait between a rung that did not verify and the next.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::millis"))]
+    pub cooldown: Duration,
+    /// Most time all rungs may take together, cooldowns included.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::millis"))]
+    pub max_time: Duration,
+}
+
+impl Ladder {
+    /// Only verify the configured sequence, with the default timing.
+    pub fn new(verify: Verify) -> Self {
+        Self {
+            rungs: Vec::new(),
+            verify,
+            cooldown: DEFAULT_COOLDOWN,
+            max_time: DEFAULT_MAX_TIME,
+        }
+    }
+}
+
+/// Which rung of a ladder verified, in the run's report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LadderReport {
+    /// The rung, from 0 for the configured sequence.
+    pub rung: usize,
+    /// Rungs the ladder has, the configured sequence included.
+    pub rungs: usize,
+    /// The port verified open.
+    pub port: u16,
+    pub banner: BannerCheck,
+}
+
+/// What became of one rung: verified, or the run was cut short, or why
+/// it did not verify.
+enum Rung {
+    Done(Box<KnockReport>),
+    Failed(String),
+}
+
+/// Knock each rung of `config.ladder` through `run` until one verifies.
+/// A dry run shows the plan of every rung and verifies none.
+pub(crate) async fn climb<F, Fut>(
+    mut config: KnockConfig,
+    events: &EventSink,
+    cancel: &CancellationToken,
+    mut run: F,
+) -> Result<KnockReport, AppError>
+where
+    F: FnMut(KnockConfig) -> Fut,
+    Fut: Future<Output = Result<KnockReport, AppError>>,
+{
+    let Some(ladder) = config.ladder.take() else {
+        return run(config).await;
+    };
+    // Every rung is checked before the first one knocks
+    let first = std::mem::take(&mut config.sequence);
+    let configs = std::iter::once(first)
+        .chain(ladder.rungs.iter().cloned())
+        .map(|sequence| {
+            let mut rung = config.clone();
+            rung.sequence = sequence;
+            rung.validate().map(|_| rung)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = configs.len();
+    // Without DNS the verifying connect goes where the knocks went
+    let host = match config.no_dns {
+        true => crate::dns::without_dns(&config.host, &config.resolve_pins)?,
+        false => config.host,
+    };
+    if config.dry_run {
+        let mut first = None;
+        for (index, rung) in configs.into_iter().enumerate() {
+            if count > 1 {
+                events.notice(None, format!("Ladder rung {}/{count}:", index + 1));
+            }
+            let report = run(rung).await?;
+            first.get_or_insert(report);
+        }
+        return Ok(first.expect("a ladder has at least one rung"));
+    }
+
+    let deadline = rt::Instant::now() + ladder.max_time;
+    let out_of_time = |tried: usize| AppError::Ladder {
+        tried,
+        rungs: count,
+        reason: format!(
+            "the {}s the ladder may take ran out",
+            ladder.max_time.as_secs_f64()
+        ),
+    };
+    for (index, rung) in configs.into_iter().enumerate() {
+        if index > 0 {
+            events.notice(
+                None,
+                format!(
+                    "Trying ladder rung {}/{count}: {}",
+                    index + 1,
+                    rung.sequence
+                ),
+            );
+        }
+        let climbing = async {
+            let mut report = match run(rung).await {
+                Ok(report) if report.interrupted => return Ok(Rung::Done(Box::new(report))),
+                Ok(report) => report,
+                Err(e @ (AppError::Interrupted(_) | AppError::InvalidConfig(_))) => return Err(e),
+                Err(e) => return Ok(Rung::Failed(e.to_string())),
+            };
+            Ok(
+                match verify::check(&host, report.address, &ladder.verify).await {
+                    Ok(banner) => {
+                        report.ladder = Some(LadderReport {
+                            rung: index,
+                            rungs: count,
+                            port: ladder.verify.port,
+                            banner,
+                        });
+                        Rung::Done(Box::new(report))
+                    }
+                    Err(e) => Rung::Failed(e),
+                },
+            )
+        };
+        let remaining = deadline.saturating_duration_since(rt::Instant::now());
+        let reason = match rt::timeout(remaining, climbing).await {
+            Err(_) => return Err(out_of_time(index + 1)),
+            Ok(Err(e)) => return Err(e),
+            Ok(Ok(Rung::Done(report))) => {
+                if count > 1 && !report.interrupted {
+                    events.notice(
+                        None,
+                        format!(
+                            "Ladder rung {}/{count} verified: port {} of {host} is open",
+                            index + 1,
+                            ladder.verify.port
+                        ),
+                    );
+                }
+                return Ok(*report);
+            }
+            Ok(Ok(Rung::Failed(reason))) => reason,
+        };
+        if index + 1 == count {
+            return Err(AppError::Ladder {
+                tried: count,
+                rungs: count,
+                reason,
+            });
+        }
+        if deadline.saturating_duration_since(rt::Instant::now()) <= ladder.cooldown {
+            return Err(out_of_time(index + 1));
+        }
+        events.notice(
+            None,
+            format!(
+                "Ladder rung {}/{count} did not verify: {reason}; next rung in {}ms",
+                index + 1,
+                ladder.cooldown.as_millis()
+            ),
+        );
+        // A signal during the cooldown ends the run at the next rung
+        let _ = rt::timeout(ladder.cooldown, cancel.cancelled()).await;
+    }
+    unreachable!("the last rung returns")
+}
+
+#[cfg(all(test, feature = "runtime-tokio"))]
+mod tests {
+    use super::*;
+    use crate::plan::KnockStep;
+    use tokio::net::TcpListener;
+
+    fn rung(port: u16) -> KnockPlan {
+        KnockPlan(vec![KnockStep::new(port)])
+    }
+
+    #[tokio::test]
+    async fn the_rung_that_opens_the_host_is_reported() {
+        // Knocking the old sequence opens nothing; the new one opens
+        // `guarded`, free until then
+        let old = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let new = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let guarded = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let guarded_port = guarded.local_addr().unwrap().port();
+        drop(guarded);
+        let old_port = old.local_addr().unwrap().port();
+        let new_port = new.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _ = new.accept().await;
+            let open = TcpListener::bind(("127.0.0.1", guarded_port))
+                .await
+                .unwrap();
+            while open.accept().await.is_ok() {}
+        });
+        // The knock after the new port's gives the host time to open it
+        let mut new_sequence = rung(new_port);
+        new_sequence.0.push(KnockStep::new(old_port));
+        let ladder = Ladder {
+            rungs: vec![new_sequence],
+            cooldown: Duration::from_millis(50),
+            ..Ladder::new(Verify {
+                timeout: 300,
+                ..Verify::new(guarded_port)
+            })
+        };
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([old_port])
+            .delay(100)
+            .ladder(ladder)
+            .build()
+            .unwrap();
+        let report = crate::run(config).await.unwrap();
+        assert_eq!(report.steps[0].port, new_port);
+        let climbed = report.ladder.unwrap();
+        assert_eq!((climbed.rung, climbed.rungs), (1, 2));
+        assert_eq!(climbed.banner, BannerCheck::NotChecked);
+    }
+
+    #[tokio::test]
+    async fn a_ladder_that_never_verifies_is_cut_off() {
+        let knocked = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = knocked.local_addr().unwrap().port();
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+
+        let ladder = Ladder {
+            rungs: vec![rung(port); 2],
+            cooldown: Duration::from_millis(50),
+            ..Ladder::new(Verify::new(closed_port))
+        };
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([port])
+            .ladder(ladder.clone())
+            .build()
+            .unwrap();
+        let err = crate::run(config.clone()).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                AppError::Ladder {
+                    tried: 3,
+                    rungs: 3,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert!(err
+            .to_string()
+            .starts_with("none of the 3 ladder rungs verified: port"));
+        assert_eq!(err.exit_code(), 5);
+
+        // Many rungs, but no more time than the ladder is given
+        let mut config = config;
+        config.ladder = Some(Ladder {
+            rungs: vec![rung(port); 50],
+            max_time: Duration::from_millis(400),
+            ..ladder
+        });
+        let started = std::time::Instant::now();
+        let err = crate::run(config).await.unwrap_err();
+        assert!(matches!(err, AppError::Ladder { rungs: 51, .. }), "{err}");
+        assert!(err.to_string().contains("ran out"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
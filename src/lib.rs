@@ -1,91 +1,4801 @@
 // Declare all the modules that make up this library.
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod budget;
+pub mod calibrate;
+#[cfg(feature = "cli")]
 pub mod cli;
+pub mod config;
+pub mod confirm;
+#[cfg(all(unix, feature = "runtime-tokio", feature = "serde"))]
+pub mod control;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+pub mod dns;
+pub mod duration;
 pub mod errors;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fleet;
+#[cfg(feature = "fwknop")]
+mod fwknop;
+mod fwmark;
+pub mod generate;
+pub mod hexdump;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod hooks;
+mod http;
+#[cfg(feature = "raw")]
+mod icmp;
+pub mod interfaces;
+pub mod jump;
+pub mod knockd;
+pub mod ladder;
+pub mod lock;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod netns;
+#[cfg(feature = "notify")]
+pub mod notify;
+pub mod observer;
+pub mod outcome;
+mod pacing;
+pub mod packet;
+pub mod passphrase;
+pub mod pattern;
+pub mod pcap;
+pub mod plan;
+#[cfg(feature = "wasm-plugins")]
+pub mod plugin;
+pub mod protocol;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod ratelimit;
+#[cfg(feature = "raw")]
+mod raw;
+pub mod replies;
 pub mod retry;
+pub mod rt;
+#[cfg(feature = "schedule")]
+pub mod schedule;
+pub mod scope;
+pub mod script;
+#[cfg(target_os = "linux")]
+mod sctp;
+#[cfg(unix)]
+pub mod sdnotify;
+pub mod secret;
+pub mod securedns;
+#[cfg(any(test, feature = "test-util"))]
+pub mod selftest;
+#[cfg(feature = "serde")]
+mod serde_forms;
+#[cfg(feature = "runtime-tokio")]
+pub mod server;
+pub mod shutdown;
+pub mod signed;
+pub mod sntp;
+pub mod soak;
+pub mod socks;
+pub mod spa;
+pub mod srv;
+pub mod ssh_config;
+pub mod state;
+pub mod stun;
+#[cfg(all(feature = "syslog", unix))]
+pub mod syslog;
 pub mod tcp;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod tls;
+pub mod totp;
+pub mod transport;
+pub mod txt;
 pub mod udp;
+pub mod uri;
+pub mod verify;
+pub mod warmup;
+#[cfg(feature = "plan-file")]
+pub mod workflow;
 
 // Re-export the main run function and the Cli struct for the binary to use.
+pub use bytes::Bytes;
+#[cfg(feature = "cli")]
 pub use cli::Cli;
-pub use errors::AppError;
-pub use retry::retry_with_backoff;
+pub use config::{KnockConfig, KnockConfigBuilder, KnockOpts};
+pub use dns::{resolve_target, DnsCache, ResolutionPolicy, ResolvePin};
+pub use errors::{AppError, ErrorClass};
+pub use events::{KnockEvent, KnockTarget};
+pub use hooks::Hooks;
+pub use observer::{AttemptInfo, AttemptResult, KnockObserver, StdoutObserver, Timestamps};
+pub use outcome::{
+    AttemptError, FailureTolerance, GroupReport, KnockFailure, KnockOutcome, KnockReport,
+    LatencyStats, OpenProbe, WindowOverrun,
+};
+pub use protocol::{
+    BackoffStrategy, BudgetExhausted, Protocol, ResolveStrategy, TcpClose, TotpAlgorithm,
+};
+pub use ratelimit::RateLimiter;
+pub use retry::{
+    retry_with_backoff, sync_on_timeout, Attempts, BackoffPolicy, RetryDecision, RetryOutcome,
+};
+pub use secret::{Secret, SecretSource};
+pub use shutdown::ShutdownSignal;
+#[cfg(feature = "runtime-tokio")]
+pub use shutdown::{cancel_on_shutdown, ShutdownListener};
+pub use signed::verify_signed_knock;
+pub use tcp::{knock_tcp, TcpOpts};
+pub use tokio_util::sync::CancellationToken;
+pub use transport::{KnockTransport, TcpTransport, UdpTransport};
+pub use udp::{knock_udp, MulticastInterface, SourcePortPolicy, UdpOpts};
 
-use crate::{tcp::knock_tcp, udp::knock_udp};
-use futures::StreamExt;
+use crate::{
+    events::EventSink,
+    http::knock_http,
+    pcap::PcapWriter,
+    plan::{KnockPlan, KnockStep, StepKind},
+    replies::ReplySaver,
+    warmup::{WarmKnock, WarmPlan},
+};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use std::borrow::Cow;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::{net::lookup_host, signal};
+use std::time::{Instant, SystemTime};
+#[cfg(feature = "runtime-tokio")]
+use tokio::{signal, task::JoinHandle};
 
-/// The main application logic.
-/// This function is called by the binary's main function.
-pub async fn run(cli: Cli) -> Result<(), AppError> {
-    // Wrap host in Arc so tasks can share it cheaply
-    let host = Arc::new(cli.host);
+/// The main application logic: send the configured knocks and report how
+/// each went. Nothing is printed; use [`run_with_events`] or an observer
+/// ([`KnockConfigBuilder::observer`]) to follow along.
+///
+/// Ctrl-C, SIGTERM and SIGHUP (on Windows also Ctrl-Break and closing the
+/// console) stop the run with [`AppError::Interrupted`] once the knocks in
+/// flight are done, and a second one right away; use [`run_with_cancel`]
+/// to decide that yourself. Once every knock has been sent, any that did
+/// not get through fail the run with [`AppError::Partial`] listing them in sequence order; with
+/// `fail_fast` the first failure ends the run as its own error instead,
+/// and with `lockstep` the first knock not answered in time ends it with
+/// [`AppError::Lockstep`]. Once `break_after` steps in a row could not
+/// reach the host, the rest are skipped with [`AppError::CircuitOpen`].
+/// The full report still reaches observers and [`KnockEvent::Finished`].
+/// The configured [`Hooks`] run around all of it, and a pre-hook that
+/// fails stops the run with [`AppError::Hook`].
+///
+/// Signals are only caught with the `runtime-tokio` feature; without it
+/// nothing stops the run early.
+///
+/// # Process-wide side effect
+///
+/// Catching them installs handlers that outlive the run (see
+/// [`cancel_on_shutdown`]): after `run` returns, SIGINT, SIGTERM and SIGHUP
+/// no longer end the process. Programs that go on after a run, or handle
+/// signals themselves, should call [`run_with_cancel`] or
+/// [`run_with_abort`] instead, which touch no signal handlers.
+pub async fn run(config: KnockConfig) -> Result<KnockReport, AppError> {
+    let (cancel, abort) = (CancellationToken::new(), CancellationToken::new());
+    #[cfg(feature = "runtime-tokio")]
+    {
+        let shutdown = cancel_on_shutdown(cancel.clone(), abort.clone())?;
+        let result = run_with_abort(config, cancel, abort).await;
+        match shutdown.signal() {
+            Some(signal) => result.and(Err(AppError::Interrupted(signal))),
+            None => result,
+        }
+    }
+    #[cfg(not(feature = "runtime-tokio"))]
+    run_with_abort(config, cancel, abort).await
+}
+
+/// Like [`run`], but stopped by cancelling `cancel` instead of a signal.
+///
+/// Cancelling starts no more knocks. The ones in flight get up to twice
+/// the knock timeout to finish, then the run returns the report so far,
+/// marked as interrupted, with the knocks cut short in `aborted` and the
+/// rest in `not_started`.
+pub async fn run_with_cancel(
+    config: KnockConfig,
+    cancel: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    run_with_abort(config, cancel, CancellationToken::new()).await
+}
+
+/// Like [`run_with_cancel`], with `abort` to drop the knocks in flight
+/// without waiting for them, e.g. on a second Ctrl-C.
+pub async fn run_with_abort(
+    config: KnockConfig,
+    cancel: CancellationToken,
+    abort: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    run_inner(config, EventSink::default(), cancel, abort).await
+}
+
+/// Cancel `cancel` on the first Ctrl-C. Abort the returned task to stop
+/// listening.
+#[cfg(feature = "runtime-tokio")]
+pub fn cancel_on_ctrl_c(cancel: CancellationToken) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            cancel.cancel();
+        }
+    })
+}
+
+/// Start a run on the Tokio runtime and stream its progress.
+///
+/// The stream ends after [`KnockEvent::Finished`], which is sent even when
+/// the run is cancelled through `cancel` or aborted through the handle
+/// (which drops the knocks in flight); only notices of hooks that failed
+/// after the run may follow it. The
+/// same events reach [`KnockObserver::on_event`], which is how the binary
+/// prints them.
+#[cfg(feature = "runtime-tokio")]
+pub fn run_with_events(
+    config: KnockConfig,
+    cancel: CancellationToken,
+) -> (
+    impl futures::Stream<Item = KnockEvent>,
+    JoinHandle<Result<KnockReport, AppError>>,
+) {
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    let abort = CancellationToken::new();
+    let handle = tokio::spawn(run_inner(config, EventSink::new(tx), cancel, abort));
+    (rx, handle)
+}
+
+/// Run the knocks between the configured hooks; a dry run runs none.
+async fn run_inner(
+    config: KnockConfig,
+    events: EventSink,
+    cancel: CancellationToken,
+    abort: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    if config.hooks.is_empty() || config.dry_run {
+        return run_targets(config, events, cancel, abort).await;
+    }
+    config.validate()?;
+    #[cfg(not(feature = "runtime-tokio"))]
+    return Err(AppError::InvalidConfig(
+        "hooks require the `runtime-tokio` feature".into(),
+    ));
+    #[cfg(feature = "runtime-tokio")]
+    {
+        use hooks::{run_hook, HookPoint};
+
+        let hooks = config.hooks.clone();
+        let notices = events.clone().with_observer(config.observer.clone());
+        let mut env = hooks::run_env(&config);
+        if let Some(pre) = &hooks.pre {
+            run_hook(pre, &env, hooks.timeout)
+                .await
+                .map_err(|reason| AppError::Hook {
+                    point: HookPoint::Pre,
+                    reason,
+                })?;
+        }
+        let result = run_targets(config, events, cancel, abort).await;
+        env.extend(hooks::result_env(&result));
+        let after = [
+            (
+                HookPoint::Failure,
+                hooks.failure.as_ref().filter(|_| result.is_err()),
+            ),
+            (HookPoint::Post, hooks.post.as_ref()),
+        ];
+        for (point, command) in after {
+            let Some(command) = command else { continue };
+            if let Err(reason) = run_hook(command, &env, hooks.timeout).await {
+                if hooks.strict && point == HookPoint::Post && result.is_ok() {
+                    return Err(AppError::Hook { point, reason });
+                }
+                notices.notice(None, format!("The {point}-hook failed: {reason}"));
+            }
+        }
+        result
+    }
+}
+
+/// Send the configured knocks to the host, or to each target of
+/// `config.srv` in turn until they open one.
+async fn run_targets(
+    config: KnockConfig,
+    events: EventSink,
+    cancel: CancellationToken,
+    abort: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    if config.srv.is_none() {
+        return run_rungs(config, events, cancel, abort).await;
+    }
+    let notices = events.clone().with_observer(config.observer.clone());
+    let run = |target| run_rungs(target, events.clone(), cancel.clone(), abort.clone());
+    srv::fall_back(config, &notices, run).await
+}
+
+/// Send the configured knocks, climbing the knock ladder when there is
+/// one.
+async fn run_rungs(
+    config: KnockConfig,
+    events: EventSink,
+    cancel: CancellationToken,
+    abort: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    if config.ladder.is_none() {
+        return run_in_netns(config, events, cancel, abort).await;
+    }
+    let notices = events.clone().with_observer(config.observer.clone());
+    let run = |rung| run_in_netns(rung, events.clone(), cancel.clone(), abort.clone());
+    ladder::climb(config, &notices, &cancel, run).await
+}
+
+/// Send the configured knocks from inside `config.netns` when it names a
+/// network namespace; only they run there.
+async fn run_in_netns(
+    config: KnockConfig,
+    events: EventSink,
+    cancel: CancellationToken,
+    abort: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    match config.netns.clone() {
+        #[cfg(target_os = "linux")]
+        Some(name) if !config.dry_run => {
+            let knocks = move || run_knocks(config, events, cancel, abort);
+            netns::run_in(&name, knocks).await
+        }
+        _ => run_knocks(config, events, cancel, abort).await,
+    }
+}
+
+/// Send the configured knocks, the run itself.
+async fn run_knocks(
+    mut config: KnockConfig,
+    events: EventSink,
+    cancel: CancellationToken,
+    abort: CancellationToken,
+) -> Result<KnockReport, AppError> {
+    config.validate()?;
+    let events = events.with_observer(config.observer.clone());
+    let started_at = SystemTime::now();
+    let started = Instant::now();
 
-    // Pre-resolve DNS once
-    let addrs = lookup_host((host.as_str(), 0)).await?.collect::<Vec<_>>();
-    if addrs.is_empty() {
-        return Err(AppError::NoDns);
+    // Wrap host in Arc so knocks can share it cheaply
+    let host: Arc<str> = Arc::from(config.host.as_str());
+
+    derive_sequence(&mut config, &events, &cancel).await?;
+    check_knocks(&mut config, &events)?;
+
+    let sign_key = match &config.sign_key {
+        Some(source) => Some(source.read("signing key").await.map_err(AppError::Sign)?),
+        None => None,
+    };
+    let spa = match &config.spa {
+        Some(settings) => {
+            let key = spa::load_key(settings.key.as_ref(), spa::KEY_ENV)
+                .await
+                .map_err(AppError::Spa)?;
+            Some(spa::SpaConfig {
+                key,
+                client_id: settings.client_id.clone(),
+            })
+        }
+        None => None,
+    };
+    #[cfg(feature = "crypto")]
+    let cipher = match &config.encrypt_key {
+        Some(source) => {
+            let key = crypto::load_key(source).await.map_err(AppError::Crypto)?;
+            Some(crypto::PayloadCipher::new(&key))
+        }
+        None => None,
+    };
+    #[cfg(feature = "fwknop")]
+    let mut fwknop = match &config.fwknop {
+        Some(settings) => Some(fwknop_config(settings).await?),
+        None => None,
+    };
+    let plugins = load_plugins(&config.sequence)?;
+
+    // Pre-resolve DNS once, or reuse the addresses of an earlier run as
+    // the resolution policy allows; with a proxy the name is resolved
+    // remotely
+    let (strategy, server) = (config.resolve, config.dns_server);
+    let resolve = || async {
+        if let Some(pinned) = dns::pinned(&config.resolve_pins, &host, strategy) {
+            return pinned;
+        }
+        if config.no_dns {
+            dns::require_literal("host", &host)?;
+        }
+        #[cfg(feature = "secure-dns")]
+        if let Some(secure) = &config.secure_dns {
+            return securedns::resolve(secure, &host, strategy).await;
+        }
+        match server {
+            #[cfg(feature = "custom-dns")]
+            Some(server) => dns::resolve_via(server, &host, strategy).await,
+            _ => dns::resolve_target(&host, strategy).await,
+        }
+    };
+    let looked_up = rt::Instant::now();
+    let addrs = match (&config.proxy_socks5, &config.jump) {
+        (Some(_), _) | (_, Some(_)) => Vec::new(),
+        (None, None) => {
+            let lookup = config
+                .dns_cache
+                .lookup(&host, config.resolution, resolve)
+                .await?;
+            if let Some(previous) = lookup.changed_from {
+                events.emit(KnockEvent::AddressesChanged {
+                    host: config.host.clone(),
+                    previous,
+                    addrs: lookup.addrs.clone(),
+                });
+            }
+            lookup.addrs
+        }
+    };
+    // A round trip to the name server, to calibrate the timeout with
+    let lookup_time = (!addrs.is_empty()).then(|| looked_up.elapsed());
+    events.emit(KnockEvent::Resolved {
+        host: config.host.clone(),
+        addrs: addrs.clone(),
+    });
+
+    // Behind NAT the host sees the knocks come from another address than
+    // ours; learn it before anything that names it is built
+    if let Some(check) = &config.public_ip {
+        if let Some(ip) = public_ip(check, &addrs, &events, &cancel).await? {
+            let text = ip.to_string();
+            let fill = |payload: &mut Option<Bytes>| {
+                if let Some(filled) = payload.as_deref().and_then(|p| fill_public_ip(p, &text)) {
+                    *payload = Some(filled.into());
+                }
+            };
+            fill(&mut config.payload);
+            fill(&mut config.tcp_payload);
+            for step in config.sequence.0.iter_mut() {
+                fill(&mut step.payload);
+            }
+            #[cfg(feature = "fwknop")]
+            if let (Some(fwknop), Some(settings)) = (&mut fwknop, &config.fwknop) {
+                if settings.allow_ip.is_none() {
+                    fwknop.allow_ip = ip;
+                }
+            }
+        }
+    }
+
+    // The kernel refuses a send to the limited broadcast address unless
+    // asked for it; say so before any knock goes out. A subnet's directed
+    // broadcast is only known to the kernel, and refused at the send
+    if !config.broadcast {
+        let udp_step = config
+            .sequence
+            .iter()
+            .find(|s| s.kind.is_none() && s.protocol.unwrap_or(config.protocol) == Protocol::Udp);
+        let limited = addrs.iter().find(|a| match a.ip() {
+            IpAddr::V4(ip) => ip.is_broadcast(),
+            IpAddr::V6(_) => false,
+        });
+        if let (Some(step), Some(addr)) = (udp_step, limited) {
+            return Err(AppError::Broadcast {
+                addr: SocketAddr::new(addr.ip(), step.port),
+            });
+        }
+    }
+    // Multicast knocks leave from an interface of the group's family
+    if let Some(interface) = config.multicast_if {
+        // An address no interface has would only fail at the first knock;
+        // when the interfaces cannot be listed, leave it to the socket
+        if let Ok(interfaces) = interfaces::list() {
+            let (addr, index) = match interface {
+                MulticastInterface::V4(addr) => (IpAddr::V4(addr), None),
+                MulticastInterface::V6 { addr, index } => (IpAddr::V6(addr), Some(index)),
+            };
+            interfaces::check_address(&interfaces, addr, index)
+                .map_err(|e| AppError::InvalidConfig(format!("--multicast-if {interface}: {e}")))?;
+        }
+        let group = addrs
+            .iter()
+            .find(|a| a.ip().is_multicast() && a.is_ipv6() != interface.is_ipv6());
+        if let Some(group) = group {
+            return Err(AppError::InvalidConfig(format!(
+                "multicast interface {interface} is not of the IP version of group {}",
+                group.ip()
+            )));
+        }
     }
-    let ips = Arc::new(addrs);
 
-    // Cloneable reference to optional UDP payload
-    let payload = cli.payload.clone();
+    // Optional UDP payload, shared by every knock
+    let payload = config.payload.clone();
+
+    // Optional pcap recorder shared by all knocks
+    let pcap = match &config.pcap {
+        Some(path) => Some(Arc::new(
+            PcapWriter::create(path)?.with_events(events.clone()),
+        )),
+        None => None,
+    };
+
+    // Optional directory the UDP replies are written to
+    let replies = match &config.save_replies {
+        Some(dir) => Some(ReplySaver::create(dir).map_err(AppError::Replies)?),
+        None => None,
+    };
 
     // Build a future-per-port knock
-    let knocks = cli.sequence.into_iter().map(|port| {
-        let host = Arc::clone(&host);
-        let ips = Arc::clone(&ips);
-        let payload = payload.clone();
-        let proto = cli.protocol;
-        let to_ms = cli.timeout;
-        let delay_ms = cli.delay;
-        let retries = cli.retries;
-        let backoff = cli.backoff;
+    let mut knock_opts = config.knock_opts();
+    // A knock waiting for its turn to send stops there once cancelled
+    knock_opts.rate = config
+        .rate_limiter()
+        .map(|rate| Arc::new(rate.closed_by(cancel.clone())));
+    #[cfg(feature = "raw")]
+    let (raw_flags, spoof) = (config.tcp_flags, config.spoof_source);
+    // Payload of one knock, built fresh for each so nonces and timestamps
+    // never repeat. A step's own payload wins over the run's, and an
+    // explicit payload over a generated DNS query; an SPA packet is signed
+    // for the step's port
+    let build_payload = |step: &KnockStep| {
+        let port = step.port;
+        let payload = match &spa {
+            Some(spa) => Some(Bytes::from(spa.packet(port))),
+            None => step
+                .payload
+                .clone()
+                .or_else(|| payload.clone())
+                .or_else(|| {
+                    config.payload_dns.as_deref().map(|name| {
+                        let id = rand::random::<u16>();
+                        Bytes::from(dns::build_query(id, name, dns::TYPE_A))
+                    })
+                }),
+        };
+        #[cfg(feature = "fwknop")]
+        let payload = fwknop
+            .as_ref()
+            .map(|fwknop| Bytes::from(fwknop.packet()))
+            .or(payload);
+        // Sign, then encrypt, whatever payload was chosen, an empty one included
+        let payload = match &sign_key {
+            Some(key) => Some(Bytes::from(signed::sign_knock_now(
+                key,
+                port,
+                payload.as_deref().unwrap_or_default(),
+            ))),
+            None => payload,
+        };
+        #[cfg(feature = "crypto")]
+        let payload = match &cipher {
+            Some(cipher) => Some(Bytes::from(
+                cipher.seal(payload.as_deref().unwrap_or_default()),
+            )),
+            None => payload,
+        };
+        payload
+    };
+    // Payloads keep their size from knock to knock, so one sample per step
+    // tells whether padding can fit them
+    if let Some(size) = config.pad_to {
+        for step in config.sequence.iter() {
+            let len = build_payload(step).map_or(0, |p| p.len());
+            if len > usize::from(size) {
+                return Err(AppError::Payload(format!(
+                    "payload of step {step} is {len} bytes, more than --pad-to {size}"
+                )));
+            }
+        }
+    }
+    let pad_to = config.pad_to;
+    // Pad last, after signing and encryption, so the on-wire length is the
+    // same for every knock
+    let knock_payload = |step: &KnockStep| match (build_payload(step), pad_to) {
+        (payload, Some(size)) => Some(Bytes::from(udp::pad_payload(
+            payload.as_deref().unwrap_or_default(),
+            usize::from(size),
+        ))),
+        (payload, None) => payload,
+    };
 
-        async move {
-            // Inter-knock delay + random jitter
-            if delay_ms > 0 {
-                use rand::{rngs::ThreadRng, RngCore};
-                use tokio::time::{sleep, Duration};
-                let mut rng: ThreadRng = ThreadRng::default();
-                let jitter = rng.next_u64() % (delay_ms + 1);
-                sleep(Duration::from_millis(delay_ms + jitter)).await;
-            }
-
-            // Dispatch to TCP or UDP knock
-            match proto {
-                cli::Protocol::Tcp => {
-                    knock_tcp(host.clone(), port, to_ms, retries, backoff).await;
-                }
-                cli::Protocol::Udp => {
-                    if let Err(e) = knock_udp(
-                        host.clone(),
-                        port,
-                        to_ms,
-                        retries,
-                        backoff,
-                        ips.clone(),
-                        payload.clone(),
+    // A datagram bigger than the path MTU is fragmented, or dropped when
+    // fragments are; warn about payloads too big for a common link
+    for step in config.sequence.iter() {
+        let proto = step.protocol.unwrap_or(config.protocol);
+        if proto != Protocol::Udp || step.kind.is_some() {
+            continue;
+        }
+        let len = match pad_to {
+            Some(size) => usize::from(size),
+            None => build_payload(step).map_or(0, |p| p.len()),
+        };
+        let over = [
+            (false, udp::MAX_UNFRAGMENTED_V4, "IPv4"),
+            (true, udp::MAX_UNFRAGMENTED_V6, "IPv6"),
+        ]
+        .into_iter()
+        .find(|&(v6, max, _)| len > max && addrs.iter().any(|a| a.is_ipv6() == v6));
+        if let Some((_, max, family)) = over {
+            let message = format!(
+                "payload of step {step} is {len} bytes, more than the {max} an unfragmented \
+                 {family} datagram carries; it may be dropped on the way"
+            );
+            if config.strict {
+                return Err(AppError::Payload(message));
+            }
+            events.notice(None, message);
+        }
+    }
+
+    // With strict timing every knock has a fixed slot from the start of
+    // the sequence. Otherwise each knock goes out the step's own delay, or
+    // the inter-knock delay + random jitter, after the previous knock, and
+    // the initial delay after the start
+    let schedule = config.strict_timing.then(|| config.schedule());
+    let knock_wait = |index: usize, step: &KnockStep| match &schedule {
+        Some(schedule) => schedule[index],
+        None => step.pre_delay.unwrap_or_else(|| {
+            use rand::{rngs::ThreadRng, RngCore};
+            if index == 0 {
+                return std::time::Duration::from_millis(config.initial_delay);
+            }
+            // The rest of a group goes out with its first knock
+            if step.with_previous {
+                return std::time::Duration::ZERO;
+            }
+            // Jitter as fine as the delay, so whole milliseconds stay whole
+            let delay = config.delay;
+            let unit = if pacing::is_fine(delay) { 1 } else { 1000 };
+            let units = delay.as_micros() as u64 / unit;
+            let jitter = ThreadRng::default().next_u64() % (units + 1) * unit;
+            delay + std::time::Duration::from_micros(jitter)
+        }),
+    };
+    // Warming up makes every knock ready now, into the plan a dry run
+    // shows and the run then sends as is
+    let warm = config.warmup.then(|| WarmPlan {
+        knocks: config
+            .sequence
+            .iter()
+            .enumerate()
+            .map(|(index, step)| WarmKnock {
+                step: step.clone(),
+                protocol: step.protocol.unwrap_or(config.protocol),
+                payload: knock_payload(step),
+                wait: knock_wait(index, step),
+            })
+            .collect(),
+        slots: schedule.is_some(),
+    });
+
+    // Show the plan and wait for an explicit go-ahead before any packet
+    if config.dry_run || config.confirm {
+        let mut text = confirm::describe_plan(&config, &addrs);
+        if let Some(warm) = &warm {
+            text.push_str(&warm.to_string());
+        }
+        events.emit(KnockEvent::Plan { text });
+    }
+    if config.dry_run {
+        return Ok(
+            RunRecorder::new(&events, &config.host, Vec::new(), started_at, started).finish(false),
+        );
+    }
+    // A port already open may close again when knocked, so leave it be
+    let open_probe = match config.skip_if_open {
+        Some(port) => Some(probe_open(&config, port, &addrs, &events).await),
+        None => None,
+    };
+    if let Some(addr) = open_probe.as_ref().and_then(|probe| probe.open_at) {
+        let mut recorder = RunRecorder::new(&events, &config.host, Vec::new(), started_at, started);
+        recorder.address = Some(addr.ip());
+        recorder.open_probe = open_probe;
+        return Ok(recorder.finish(false));
+    }
+    if let Some(mark) = config.fwmark {
+        fwmark::check(mark)?;
+    }
+    // No other locked run knocks the host until this one is done
+    let _lock = match config.lock {
+        true => {
+            let timeout = std::time::Duration::from_millis(config.lock_timeout);
+            let waiting = |pid: Option<u32>| {
+                let holder = pid.map(|pid| format!(" (PID {pid})")).unwrap_or_default();
+                events.notice(
+                    None,
+                    format!(
+                        "Another run{holder} is knocking {}; waiting up to {}ms for it",
+                        config.host, config.lock_timeout
+                    ),
+                );
+            };
+            let dir = config.lock_dir.clone().unwrap_or_else(lock::lock_dir);
+            match lock::acquire(&dir, &config.host, timeout, &cancel, waiting).await? {
+                Some(lock) => Some(lock),
+                None => {
+                    return Ok(RunRecorder::new(
+                        &events,
+                        &config.host,
+                        Vec::new(),
+                        started_at,
+                        started,
                     )
-                    .await
-                    {
-                        eprintln!("UDP knock error: {e}");
+                    .finish(true))
+                }
+            }
+        }
+        false => None,
+    };
+    if config.confirm {
+        confirm::confirm_plan(config.assume_yes).await?;
+    }
+    // A warmed-up run may hold its first knock for a key press or a time
+    let start = async {
+        if config.start_on_key {
+            warmup::wait_for_key().await?;
+        }
+        if let Some(at) = config.start_at {
+            let wait = at.duration_since(SystemTime::now()).map_err(|_| {
+                AppError::InvalidConfig("the --start-at time has already passed".into())
+            })?;
+            rt::sleep(wait).await;
+        }
+        Ok::<_, AppError>(())
+    };
+    tokio::select! {
+        ready = start => ready?,
+        _ = cancel.cancelled() => {
+            return Ok(
+                RunRecorder::new(&events, &config.host, Vec::new(), started_at, started)
+                    .finish(true),
+            );
+        }
+    }
+
+    // Calibrate the timeout right before the first knock, from connects to
+    // the calibration port, or else from the DNS lookup and the first knock
+    // answered. Steps with a timeout of their own keep it
+    let run_timeout = AtomicU64::new(config.timeout);
+    let calibration = std::sync::Mutex::new(None::<Vec<std::time::Duration>>);
+    if config.auto_timeout {
+        let probed = match (config.calibration_port, addrs.first()) {
+            (Some(port), Some(addr)) => {
+                let addr = SocketAddr::new(addr.ip(), port);
+                let limit = std::time::Duration::from_millis(config.timeout);
+                let rate = knock_opts.rate.as_deref();
+                let samples = calibrate::probe(addr, limit, rate, &cancel).await;
+                let what = format!("{} connects to port {port}", samples.len());
+                let probed = calibrated_timeout(&samples, &what, &events);
+                if probed.is_none() && !cancel.is_cancelled() {
+                    events.notice(
+                        None,
+                        format!("Port {port} did not answer to calibrate the timeout; timing the first knock instead"),
+                    );
+                }
+                probed
+            }
+            _ => None,
+        };
+        match probed {
+            Some(ms) => run_timeout.store(ms, Ordering::Relaxed),
+            None => *calibration.lock().unwrap() = Some(lookup_time.into_iter().collect()),
+        }
+    }
+
+    let clock = PassClock::new();
+    let pacer = pacing::Pacer::new();
+    let window = config.window.map(std::time::Duration::from_millis);
+
+    let knock = |index: usize, step: KnockStep, addrs: Arc<[SocketAddr]>| {
+        // What was warmed up, or made fresh for this knock
+        let (payload, wait) = match &warm {
+            Some(warm) => (warm.knocks[index].payload.clone(), warm.knocks[index].wait),
+            None => (knock_payload(&step), knock_wait(index, &step)),
+        };
+        let timing = match schedule {
+            Some(_) => {
+                let start = *clock.start.lock().unwrap();
+                Timing::Slot(start + wait, pacing::is_fine(wait))
+            }
+            None => Timing::Gap(wait),
+        };
+        let ctx = KnockContext {
+            host: Arc::clone(&host),
+            addrs,
+            payload,
+            timing,
+        };
+        let mut knock_opts = step_opts(&knock_opts, &step);
+        if config.auto_timeout {
+            let timeout = run_timeout.load(Ordering::Relaxed);
+            if step.timeout.is_none() {
+                knock_opts.timeout = timeout;
+            }
+            if config.recv_timeout.is_none() {
+                knock_opts.udp.recv_timeout = timeout;
+            }
+        }
+        // A lockstep reply is waited for as long as the step's own knock
+        if config.lockstep && config.recv_timeout.is_none() {
+            knock_opts.udp.recv_timeout = knock_opts.timeout;
+        }
+        let (events, pcap) = (&events, pcap.as_deref());
+        let proto = step.protocol.unwrap_or(config.protocol);
+        let deadline = std::time::Duration::from_millis(knock_opts.timeout);
+        let sni_default = &config.sni;
+        let plugins = &plugins;
+        let transports = &config.transports;
+        let step_transports = &config.step_transports;
+        #[cfg(feature = "raw")]
+        let icmp_reply = config.icmp_reply;
+        let all_ips = config.all_ips;
+        let cancel = &cancel;
+        let pacer = &pacer;
+        let replies = replies.as_ref();
+        let (first_sent, overran) = (&clock.first_sent, &clock.overran);
+
+        async move {
+            let (ctx, knock_opts) = (&ctx, &knock_opts);
+            let host = &*ctx.host;
+            // Wait for the knock's turn, however many are in flight; a slot
+            // the knocks before ran past is skipped rather than sent late.
+            // A knock cancelled before it is sent is not started at all
+            let now = rt::Instant::now();
+            match ctx.timing {
+                Timing::Slot(slot, _) if index > 0 && now > slot => {
+                    let target = KnockTarget::new(host, step.port, proto);
+                    let late = (now - slot).as_millis();
+                    let message = format!("missed its time slot by {late}ms, not sent");
+                    events.notice(Some(&target), &message);
+                    let outcome = KnockOutcome::failed(step.port, proto, message);
+                    return vec![finish_knock(events, target, Ok(outcome))];
+                }
+                Timing::Slot(slot, fine) => {
+                    if slot > now {
+                        tokio::select! {
+                            _ = pacing::sleep_until(slot, fine) => {}
+                            _ = cancel.cancelled() => return Vec::new(),
+                        }
+                    }
+                }
+                Timing::Gap(gap) => {
+                    if !pacer.wait(gap, cancel).await {
+                        return Vec::new();
+                    }
+                }
+            }
+
+            // A pass still sending past the window is given up before its
+            // next knock
+            if let Some(window) = window {
+                let mut first = first_sent.lock().unwrap();
+                match *first {
+                    Some(first) if first.elapsed() > window => {
+                        *overran.lock().unwrap() = Some((step.port, first.elapsed()));
+                        return Vec::new();
+                    }
+                    Some(_) => {}
+                    None => *first = Some(rt::Instant::now()),
+                }
+            }
+            // A spent retry budget may leave the knocks after it unsent
+            if let Some(budget) = knock_opts.budget.as_deref().filter(|b| b.skips_knock()) {
+                let target = KnockTarget::new(host, step.port, proto);
+                let total = budget.total();
+                let message = format!("retry budget of {total} attempts spent, not sent");
+                events.notice(Some(&target), &message);
+                let outcome = KnockOutcome::failed(step.port, proto, message);
+                return vec![finish_knock(events, target, Ok(outcome))];
+            }
+
+            let port = step.port;
+            // A transport set for the port beats the step's knock type
+            let custom = step_transports.get(&port).or_else(|| match step.kind {
+                None => transports.get(&proto),
+                Some(_) => None,
+            });
+            match &step.kind {
+                _ if custom.is_some() => {}
+                Some(StepKind::Http { path }) => {
+                    let target = KnockTarget {
+                        step: step.kind.clone(),
+                        ..KnockTarget::new(host, port, proto)
+                    };
+                    events.emit(KnockEvent::KnockStarted {
+                        target: target.clone(),
+                    });
+                    let outcome = match step_addr(host, &ctx.addrs, port).await {
+                        Ok(addr) => Ok(knock_http(host, addr, path, knock_opts, events).await),
+                        Err(e) => Err(e),
+                    };
+                    return vec![finish_knock(events, target, outcome)];
+                }
+                Some(StepKind::Tls { sni }) => {
+                    let sni = sni
+                        .clone()
+                        .or_else(|| sni_default.clone())
+                        .or_else(|| tls::default_sni(host));
+                    let target = KnockTarget {
+                        step: Some(StepKind::Tls { sni: sni.clone() }),
+                        ..KnockTarget::new(host, port, proto)
+                    };
+                    events.emit(KnockEvent::KnockStarted {
+                        target: target.clone(),
+                    });
+                    let outcome = match step_addr(host, &ctx.addrs, port).await {
+                        Ok(addr) => {
+                            let sni = sni.as_deref();
+                            Ok(tls::knock_tls(host, addr, sni, knock_opts, events).await)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    return vec![finish_knock(events, target, outcome)];
+                }
+                Some(StepKind::Quic) => {
+                    let target = KnockTarget {
+                        step: Some(StepKind::Quic),
+                        ..KnockTarget::new(host, port, Protocol::Udp)
+                    };
+                    events.emit(KnockEvent::KnockStarted {
+                        target: target.clone(),
+                    });
+                    let outcome = match step_addr(host, &ctx.addrs, port).await {
+                        Ok(addr) => knock_quic(host, addr, knock_opts, pcap, events).await,
+                        Err(e) => Err(e),
+                    };
+                    return vec![finish_knock(events, target, outcome)];
+                }
+                Some(StepKind::Plugin { path, arg }) => {
+                    let target = KnockTarget {
+                        step: step.kind.clone(),
+                        ..KnockTarget::new(host, port, Protocol::Udp)
+                    };
+                    events.emit(KnockEvent::KnockStarted {
+                        target: target.clone(),
+                    });
+                    let outcome = match step_addr(host, &ctx.addrs, port).await {
+                        Ok(addr) => {
+                            let call = PluginStep {
+                                plugins,
+                                path,
+                                arg: arg.as_deref(),
+                            };
+                            knock_plugin(host, addr, call, knock_opts, pcap, events).await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    return vec![finish_knock(events, target, outcome)];
+                }
+                None => {}
+            }
+
+            // One knock per chosen address, in resolution order; with a
+            // proxy there is none and the proxy resolves the name
+            let targets: Vec<Option<SocketAddr>> = if ctx.addrs.is_empty() {
+                vec![None]
+            } else {
+                ctx.addrs.iter().copied().map(Some).collect()
+            };
+            let mut outcomes = Vec::new();
+            for addr in targets {
+                // Name each address in the logs when knocking several
+                let label = match addr {
+                    Some(addr) if all_ips => Cow::Owned(addr_label(addr)),
+                    _ => Cow::Borrowed(host),
+                };
+                let target = KnockTarget::new(&label, port, proto);
+                events.emit(KnockEvent::KnockStarted {
+                    target: target.clone(),
+                });
+
+                // Dispatch through the transport for this step; with a
+                // proxy only plain TCP knocks get here
+                let result = match addr {
+                    Some(mut target) => {
+                        target.set_port(port);
+                        let builtin = Builtin {
+                            host: &label,
+                            protocol: proto,
+                            opts: knock_opts,
+                            payload: ctx.payload.as_deref(),
+                            pcap,
+                            events,
+                            #[cfg(feature = "raw")]
+                            raw_flags,
+                            #[cfg(feature = "raw")]
+                            spoof,
+                            #[cfg(feature = "raw")]
+                            icmp_reply,
+                        };
+                        let transport = custom.map_or(&builtin as &dyn KnockTransport, |t| &**t);
+                        // Custom transports leave the address to the run
+                        let result = transport.knock(target, &step, deadline).await;
+                        result.map(|outcome| KnockOutcome {
+                            addr: outcome.addr.or(Some(target)),
+                            ..outcome
+                        })
+                    }
+                    None => tcp::knock(&label, port, None, knock_opts, pcap, events).await,
+                };
+                let result = match (result, replies) {
+                    (Ok(outcome), Some(saver)) => {
+                        Ok(save_reply(saver, index, outcome, &target, events))
                     }
+                    (result, _) => result,
+                };
+                outcomes.push(finish_knock(events, target, result));
+            }
+            outcomes
+        }
+    };
+    // Until calibrated, the first knock answered times the round trip
+    let knock = |index: usize, step: KnockStep, addrs: Arc<[SocketAddr]>| {
+        let allowed = step
+            .attempts
+            .map_or(knock_opts.attempts, Attempts::Finite)
+            .finite();
+        let knocked = knock(index, step, addrs);
+        let (events, calibration, run_timeout) = (&events, &calibration, &run_timeout);
+        async move {
+            let mut outcomes = knocked.await;
+            for outcome in &mut outcomes {
+                outcome.attempts_allowed = allowed;
+            }
+            let latency = outcomes
+                .iter()
+                .filter(|o| o.acknowledged)
+                .find_map(|o| o.latency);
+            let pending = latency.and_then(|_| calibration.lock().unwrap().take());
+            if let (Some(latency), Some(mut samples)) = (latency, pending) {
+                samples.push(latency);
+                let what = "the DNS lookup and first knock";
+                if let Some(ms) = calibrated_timeout(&samples, what, events) {
+                    run_timeout.store(ms, Ordering::Relaxed);
                 }
             }
+            outcomes
         }
-    });
+    };
+
+    // From here on the run ends with a Finished event, even when cancelled
+    let ports = config.sequence.iter().map(|s| s.port).collect();
+    let mut recorder = RunRecorder::new(&events, &config.host, ports, started_at, started);
+    recorder.budget = knock_opts.budget.clone();
+    recorder.failure_tolerance = config.failure_tolerance;
+    recorder.attempts_overridden = config.sequence.iter().any(|s| s.attempts.is_some());
+    recorder.open_probe = open_probe;
+    if config.sequence.has_groups() {
+        recorder.group(&config.sequence);
+    }
+    let drain = std::time::Duration::from_millis(config.timeout.saturating_mul(2));
+    let sequence = send_sequence(
+        &config,
+        &addrs,
+        &knock,
+        &resolve,
+        &clock,
+        &run_timeout,
+        &mut recorder,
+        &events,
+        &cancel,
+    );
 
-    // Run knocks with bounded concurrency, abort on Ctrl-C
+    // Once cancelled, give the knocks in flight a little time to finish
+    // rather than cut them off halfway through; aborting does not wait
+    let draining = async {
+        cancel.cancelled().await;
+        events.notice(
+            None,
+            format!(
+                "Cancelled, finishing the knocks in flight (up to {}ms)",
+                drain.as_millis()
+            ),
+        );
+        rt::sleep(drain).await;
+    };
+    let mut result = Ok(());
+    let interrupted;
     tokio::select! {
-       _ = futures::stream::iter(knocks)
-          .buffered(cli.concurrency)
-          .for_each(|_| async {})
-       => {}
-       _ = signal::ctrl_c() => {
-          eprintln!("Received Ctrl-C, aborting port knocks");
+       biased;
+       _ = abort.cancelled() => {
+          events.notice(None, "Aborting the knocks in flight");
+          interrupted = true;
+       }
+       _ = draining => {
+          events.notice(None, "Knocks in flight did not finish in time, aborting them");
+          interrupted = true;
        }
+       res = sequence => {
+          result = res;
+          interrupted = cancel.is_cancelled();
+       }
+    }
+
+    // Finalize the capture even if the run was interrupted
+    if let Some(pcap) = &pcap {
+        if let Err(e) = pcap.finish() {
+            events.notice(None, format!("pcap flush ERR {e}"));
+        }
+    }
+
+    // Every knock was attempted, so report all that failed at once
+    let report = recorder.finish(interrupted);
+    result.and_then(|()| report.into_result())
+}
+
+/// Work out the sequence of this run, for the ways that make it afresh
+/// each time, and lay it out as it will be knocked: TTL walk, each knock
+/// over both transports split in two, and decoys among them.
+async fn derive_sequence(
+    config: &mut KnockConfig,
+    events: &EventSink,
+    cancel: &CancellationToken,
+) -> Result<(), AppError> {
+    // Only a clock the server agrees with derives the ports it expects
+    if let Some(check) = &config.check_clock {
+        check_clock(check, events, cancel).await?;
+    }
+
+    // Derive the sequence for the current time step from the shared secret
+    if let Some(totp) = &config.totp {
+        if u32::from(totp.port_base) + u32::from(totp.port_range) > 65536 {
+            return Err(AppError::Totp(format!(
+                "ports {}+{} run past 65535",
+                totp.port_base, totp.port_range
+            )));
+        }
+        let secret = totp
+            .secret
+            .read("TOTP secret")
+            .await
+            .map_err(AppError::Totp)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let step = totp::time_step(now, totp.step);
+        let knocks = usize::from(totp.knocks);
+        let ports = totp::derive_ports(
+            &secret,
+            totp.algorithm,
+            step,
+            knocks,
+            totp.port_base,
+            totp.port_range,
+        );
+        let sources = match &config.derive_source_ports {
+            Some(range) => {
+                let (base, len) = port_span(range);
+                totp::derive_source_ports(&secret, totp.algorithm, step, knocks, base, len)
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            }
+            None => vec![None; knocks],
+        };
+        config.sequence = with_source_ports(ports, sources);
+    }
+
+    // Or from a passphrase and the host, the same every run
+    if let Some(derived) = &config.passphrase_ports {
+        let passphrase = match &derived.source {
+            SecretSource::Prompt => passphrase::prompt(&config.host).await,
+            source => source.read("passphrase").await,
+        }
+        .map_err(AppError::Passphrase)?;
+        let knocks = usize::from(derived.knocks);
+        let ports = passphrase::derive_ports(
+            &passphrase,
+            &config.host,
+            knocks,
+            derived.port_base,
+            derived.port_range,
+        )
+        .map_err(AppError::Passphrase)?;
+        let sources = match &config.derive_source_ports {
+            Some(range) => {
+                let (base, len) = port_span(range);
+                passphrase::derive_source_ports(&passphrase, &config.host, knocks, base, len)
+                    .map_err(AppError::Passphrase)?
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            }
+            None => vec![None; knocks],
+        };
+        config.sequence = with_source_ports(ports, sources);
+    }
+
+    // Or from the TXT record it is published in, as it stands now
+    if let Some(txt) = &config.sequence_from_txt {
+        config.sequence = txt::fetch(txt, config.dns_server).await?;
+    }
+
+    // Or from the plan a script works out for the host now
+    if let Some(script) = &config.script {
+        let (sequence, printed) = script::plan(script, &config.host).await?;
+        for line in printed {
+            events.notice(None, format!("script: {line}"));
+        }
+        config.sequence = sequence;
+    }
+
+    // Or from the lengths of datagrams to one port, filled afresh every run
+    if let Some(lengths) = &config.length_sequence {
+        config.sequence = lengths.plan(&mut rand::rng());
+    }
+
+    // Or from a code told by the gaps between knocks, which only the
+    // strict timing schedule keeps to whatever the knocks before took
+    if let Some(code) = &config.timing_code {
+        config.sequence = code.plan();
+        config.strict_timing = true;
+    }
+
+    // Whichever way the sequence came, the TTL walk goes over it as it
+    // stands, before decoys join it
+    if let Some(walk) = config.ttl_walk {
+        config.sequence = std::mem::take(&mut config.sequence)
+            .with_ttl_walk(walk)
+            .map_err(AppError::InvalidConfig)?;
+    }
+
+    // Knocks over both transports are a TCP and a UDP step from here on,
+    // so the checks, the plan shown and the summary all see each of them
+    config.sequence = std::mem::take(&mut config.sequence).expand_both(config.protocol);
+    if config.protocol == Protocol::Both {
+        config.protocol = Protocol::Tcp;
+    }
+    // Decoys are drawn fresh every run, among the knocks already there
+    if config.decoys > 0 {
+        config.sequence = std::mem::take(&mut config.sequence)
+            .with_decoys(
+                config.decoys,
+                &config.decoy_exclude,
+                config.decoys_after,
+                &mut rand::rng(),
+            )
+            .map_err(AppError::InvalidConfig)?;
+    }
+    Ok(())
+}
+
+/// Check that the knocks can be sent as configured, dropping what the
+/// knocks cannot have, such as waiting for replies to a spoofed source.
+fn check_knocks(config: &mut KnockConfig, events: &EventSink) -> Result<(), AppError> {
+    // A lockstep knock is only done once the server has answered it
+    if config.lockstep {
+        config.expect_reply = true;
+    }
+
+    // Raw-socket modes are only compiled in with the `raw` feature
+    if config.tcp_flags.is_some() && !cfg!(feature = "raw") {
+        return Err(AppError::RawSocket(
+            "--tcp-mode syn and --tcp-flags require building with `--features raw`".into(),
+        ));
+    }
+    if config.spoof_source.is_some() && !cfg!(feature = "raw") {
+        return Err(AppError::RawSocket(
+            "--spoof-source requires building with `--features raw`".into(),
+        ));
+    }
+
+    let protocols: Vec<Protocol> = config
+        .sequence
+        .iter()
+        .map(|step| step.protocol.unwrap_or(config.protocol))
+        .collect();
+    if (config.protocol == Protocol::Icmp || protocols.contains(&Protocol::Icmp))
+        && !cfg!(feature = "raw")
+        && !config.transports.contains_key(&Protocol::Icmp)
+    {
+        return Err(AppError::RawSocket(
+            "--protocol icmp requires building with `--features raw`".into(),
+        ));
+    }
+
+    // Spoofed packets are crafted whole, which only the raw TCP and UDP
+    // knocks do, and replies go to the spoofed address instead
+    if let Some(spoof) = config.spoof_source {
+        let kinds = config.sequence.iter().any(|s| s.kind.is_some());
+        let protocol = std::iter::once(config.protocol)
+            .chain(protocols.iter().copied())
+            .find(|&p| !matches!(p, Protocol::Tcp | Protocol::Udp));
+        if let Some(protocol) = protocol {
+            return Err(AppError::InvalidConfig(format!(
+                "a spoofed source only works for tcp and udp knocks, not {protocol}"
+            )));
+        }
+        if kinds {
+            return Err(AppError::InvalidConfig(
+                "a spoofed source cannot be used with HTTP, TLS or QUIC steps".into(),
+            ));
+        }
+        // Only addresses of the spoofed source's family can be knocked
+        config.resolve = match (spoof, config.resolve) {
+            (IpAddr::V4(_), ResolveStrategy::OnlyV6) | (IpAddr::V6(_), ResolveStrategy::OnlyV4) => {
+                return Err(AppError::InvalidConfig(format!(
+                    "spoofed source {spoof} is of the IP version the resolve strategy excludes"
+                )));
+            }
+            (IpAddr::V4(_), _) => ResolveStrategy::OnlyV4,
+            (IpAddr::V6(_), _) => ResolveStrategy::OnlyV6,
+        };
+        if config.expect_reply {
+            config.expect_reply = false;
+            config.recv_timeout = None;
+            config.expect_pattern = None;
+            config.reply_port = None;
+            events.notice(
+                None,
+                format!("Replies would go to spoofed source {spoof}; not waiting for them"),
+            );
+        }
+    }
+
+    // A step's source port is bound by the plain TCP and UDP knocks alone
+    if let Some(step) = config.sequence.iter().find(|s| s.source_port.is_some()) {
+        let protocol = step.protocol.unwrap_or(config.protocol);
+        if step.kind.is_some() || !matches!(protocol, Protocol::Tcp | Protocol::Udp) {
+            return Err(AppError::InvalidConfig(format!(
+                "knock step '{step}' cannot pick its source port; only plain tcp and udp \
+                 steps can"
+            )));
+        }
+        if config.proxy_socks5.is_some()
+            || config.jump.is_some()
+            || config.tcp_flags.is_some()
+            || config.spoof_source.is_some()
+        {
+            return Err(AppError::InvalidConfig(format!(
+                "knock step '{step}' picks its source port, which a proxy, jump host or raw \
+                 knock does not keep"
+            )));
+        }
+    }
+
+    // So is a step's TTL, set on the knock's own socket
+    if let Some(step) = config.sequence.iter().find(|s| s.ttl.is_some()) {
+        let protocol = step.protocol.unwrap_or(config.protocol);
+        if step.kind.is_some() || !matches!(protocol, Protocol::Tcp | Protocol::Udp) {
+            return Err(AppError::InvalidConfig(format!(
+                "knock step '{step}' cannot set its TTL; only plain tcp and udp steps can"
+            )));
+        }
+        if config.proxy_socks5.is_some()
+            || config.jump.is_some()
+            || config.tcp_flags.is_some()
+            || config.spoof_source.is_some()
+        {
+            return Err(AppError::InvalidConfig(format!(
+                "knock step '{step}' sets its TTL, which a proxy, jump host or raw knock does \
+                 not keep"
+            )));
+        }
+    }
+    let low: Vec<String> = config
+        .sequence
+        .iter()
+        .filter(|s| s.ttl.is_some_and(|ttl| ttl < config.min_ttl))
+        .map(ToString::to_string)
+        .collect();
+    if !low.is_empty() {
+        events.notice(
+            None,
+            format!(
+                "Knock step(s) {} go out with a TTL below {}; they may expire before reaching {}",
+                low.join(", "),
+                config.min_ttl,
+                config.host
+            ),
+        );
+    }
+
+    // Broadcast reaches every host on an IPv4 segment with plain UDP
+    // datagrams; their replies say nothing about the knock
+    if config.broadcast {
+        let protocol = std::iter::once(config.protocol)
+            .chain(protocols.iter().copied())
+            .find(|&p| p != Protocol::Udp);
+        if let Some(protocol) = protocol {
+            return Err(AppError::InvalidConfig(format!(
+                "--broadcast only works for udp knocks, not {protocol}"
+            )));
+        }
+        if let Some(step) = config.sequence.iter().find(|s| s.kind.is_some()) {
+            return Err(AppError::InvalidConfig(format!(
+                "knock step '{step}' cannot be broadcast"
+            )));
+        }
+        config.resolve = match config.resolve {
+            ResolveStrategy::OnlyV6 => {
+                return Err(AppError::InvalidConfig(
+                    "--broadcast needs IPv4, which the resolve strategy excludes".into(),
+                ));
+            }
+            _ => ResolveStrategy::OnlyV4,
+        };
+        if config.expect_reply {
+            config.expect_reply = false;
+            config.recv_timeout = None;
+            config.expect_pattern = None;
+            config.reply_port = None;
+            events.notice(
+                None,
+                "Every host on the segment may reply to a broadcast; not waiting for replies",
+            );
+        }
+    }
+
+    if config
+        .sequence
+        .iter()
+        .any(|s| s.kind == Some(StepKind::Quic))
+        && !cfg!(feature = "quic")
+    {
+        return Err(AppError::InvalidConfig(
+            "quic knock steps require building with `--features quic`".into(),
+        ));
+    }
+    if config
+        .sequence
+        .iter()
+        .any(|s| matches!(s.kind, Some(StepKind::Plugin { .. })))
+        && !cfg!(feature = "wasm-plugins")
+    {
+        return Err(AppError::InvalidConfig(
+            "plugin knock steps require building with `--features wasm-plugins`".into(),
+        ));
+    }
+
+    if config.dont_fragment && !cfg!(target_os = "linux") {
+        return Err(AppError::InvalidConfig(
+            "--dont-fragment is only supported on Linux".into(),
+        ));
+    }
+    if config.fwmark.is_some() && !cfg!(target_os = "linux") {
+        return Err(AppError::InvalidConfig(
+            "--fwmark is only supported on Linux".into(),
+        ));
+    }
+    if config.netns.is_some() && !cfg!(target_os = "linux") {
+        return Err(AppError::InvalidConfig(
+            "--netns is only supported on Linux".into(),
+        ));
+    }
+
+    if config.dns_server.is_some() && !cfg!(feature = "custom-dns") {
+        return Err(AppError::InvalidConfig(
+            "--dns-server requires building with `--features custom-dns`".into(),
+        ));
+    }
+    if config.secure_dns.is_some() && !cfg!(feature = "secure-dns") {
+        return Err(AppError::InvalidConfig(
+            "--doh-url and --dot require building with `--features secure-dns`".into(),
+        ));
+    }
+
+    // Only plain TCP connects can be tunnelled through the proxy
+    if config.proxy_socks5.is_some() {
+        let protocol = std::iter::once(config.protocol)
+            .chain(protocols.iter().copied())
+            .find(|&p| p != Protocol::Tcp);
+        if let Some(protocol) = protocol {
+            return Err(AppError::Proxy(format!(
+                "{protocol} knocks cannot be sent through a SOCKS5 proxy"
+            )));
+        }
+        if let Some(step) = config.sequence.iter().find(|s| s.kind.is_some()) {
+            return Err(AppError::Proxy(format!(
+                "knock step '{step}' cannot be sent through a SOCKS5 proxy"
+            )));
+        }
+        if !config.transports.is_empty() || !config.step_transports.is_empty() {
+            return Err(AppError::Proxy(
+                "custom transports cannot be sent through a SOCKS5 proxy".into(),
+            ));
+        }
+    }
+
+    // A jump host forwards plain TCP connects and nothing else
+    if config.jump.is_some() {
+        if !cfg!(feature = "ssh") {
+            return Err(AppError::InvalidConfig(
+                "--jump requires building with `--features ssh`".into(),
+            ));
+        }
+        let protocol = std::iter::once(config.protocol)
+            .chain(protocols.iter().copied())
+            .find(|&p| p != Protocol::Tcp);
+        if let Some(protocol) = protocol {
+            return Err(AppError::Jump(format!(
+                "{protocol} knocks are not supported through an SSH jump host, which only \
+                 forwards TCP connections"
+            )));
+        }
+        if let Some(step) = config.sequence.iter().find(|s| s.kind.is_some()) {
+            return Err(AppError::Jump(format!(
+                "knock step '{step}' cannot be sent through an SSH jump host"
+            )));
+        }
+        if !config.transports.is_empty() || !config.step_transports.is_empty() {
+            return Err(AppError::Jump(
+                "custom transports cannot be sent through an SSH jump host".into(),
+            ));
+        }
     }
 
+    // SPA replaces the sequence with one signed datagram
+    if config.fwknop.is_some() && !cfg!(feature = "fwknop") {
+        return Err(AppError::Spa(
+            "--fwknop requires building with `--features fwknop`".into(),
+        ));
+    }
+    if config.pad_to.is_some() && config.protocol != Protocol::Udp {
+        return Err(AppError::Payload(
+            "--pad-to applies to UDP payloads; use --protocol udp".into(),
+        ));
+    }
+    if config.sign_key.is_some() && config.protocol != Protocol::Udp {
+        return Err(AppError::Sign(
+            "signed knocks are sent with --protocol udp".into(),
+        ));
+    }
+    if let Some(source) = &config.encrypt_key {
+        if !cfg!(feature = "crypto") {
+            return Err(AppError::Crypto(
+                "--encrypt-key requires building with `--features crypto`".into(),
+            ));
+        }
+        if config.protocol != Protocol::Udp {
+            return Err(AppError::Crypto(format!(
+                "only UDP payloads are encrypted; the key from {source} is not used with --protocol {}",
+                format!("{:?}", config.protocol).to_lowercase()
+            )));
+        }
+    }
+    if config.spa.is_some() || config.fwknop.is_some() {
+        if config.protocol != Protocol::Udp {
+            return Err(AppError::Spa(
+                "SPA packets are sent with --protocol udp".into(),
+            ));
+        }
+        if config.sequence.len() != 1 || config.sequence[0].kind.is_some() {
+            return Err(AppError::Spa(
+                "SPA sends a single knock; give exactly one port".into(),
+            ));
+        }
+    }
     Ok(())
 }
+
+/// Probe `port` of the host, at the addresses the knocks would go to
+/// first, saying whether the run goes on to knock.
+async fn probe_open(
+    config: &KnockConfig,
+    port: u16,
+    addrs: &[SocketAddr],
+    events: &EventSink,
+) -> OpenProbe {
+    // Only the family the knocks would go to first, as they do
+    let targets = match config.all_ips {
+        true => addrs.to_vec(),
+        false => preferred_family(addrs, config.resolve),
+    };
+    let probed = rt::Instant::now();
+    let open_at = verify::probe_open(&targets, port, config.open_probe_timeout).await;
+    let message = match &open_at {
+        Ok(addr) => format!(
+            "Port {port} of {} is already open at {addr}; not knocking",
+            config.host
+        ),
+        Err(e) => format!(
+            "Port {port} of {} is not open yet ({e}); knocking",
+            config.host
+        ),
+    };
+    events.notice(None, message);
+    OpenProbe {
+        port,
+        open_at: open_at.ok(),
+        probed: targets.iter().map(SocketAddr::ip).collect(),
+        elapsed: probed.elapsed(),
+    }
+}
+
+/// Send the knocks of the sequence to `addrs`, or to the one of them the
+/// first knock gets through to, recording them in `recorder`. A pass that
+/// runs past the window is started over, as often as allowed.
+#[allow(clippy::too_many_arguments)]
+async fn send_sequence<K, KnockFut, R, ResolveFut>(
+    config: &KnockConfig,
+    addrs: &[SocketAddr],
+    knock: &K,
+    resolve: &R,
+    clock: &PassClock,
+    run_timeout: &AtomicU64,
+    recorder: &mut RunRecorder,
+    events: &EventSink,
+    cancel: &CancellationToken,
+) -> Result<(), AppError>
+where
+    K: Fn(usize, KnockStep, Arc<[SocketAddr]>) -> KnockFut,
+    KnockFut: Future<Output = Vec<KnockOutcome>>,
+    R: Fn() -> ResolveFut,
+    ResolveFut: Future<Output = Result<Vec<SocketAddr>, AppError>>,
+{
+    let host = config.host.as_str();
+    let outcomes = &mut recorder.outcomes;
+    let (pulled, done, skipped) = (&recorder.pulled, &mut recorder.done, &mut recorder.skipped);
+    let all_ips = config.all_ips;
+    let fail_fast = config.fail_fast;
+    let lockstep = config.lockstep;
+    let recv_timeout = config.recv_timeout;
+    let reresolve = config.reresolve_on_failure;
+    let dns_cache = &config.dns_cache;
+    let grouped = config.sequence.has_groups();
+    if config.concurrency > 1 && !config.unordered && !grouped {
+        events.notice(
+            None,
+            "Knocking one port at a time to keep the sequence in order; \
+             --concurrency only applies with --unordered or groups",
+        );
+    }
+    let concurrency = config.knocks_in_flight();
+    // A group goes out all at once, or --concurrency at a time
+    let group_width = |len: usize| match config.concurrency {
+        1 => len,
+        concurrency => concurrency,
+    };
+    let (groups, group_of) = (&mut recorder.groups, &recorder.group_of);
+    let decoys = &mut recorder.decoys;
+    let circuit_open = &mut recorder.circuit_open;
+    let break_after = config.break_after;
+    // Steps in a row, decoys aside, that could not reach the host
+    let mut unreachable = 0;
+    let cooldown = config.window_cooldown.or(config.window).unwrap_or(0);
+    let restarts = config.window_restarts;
+    let (passes, overruns) = (&mut recorder.passes, &mut recorder.overruns);
+    let address = &mut recorder.address;
+    let plan = &config.sequence;
+    let mut steps = plan.iter().cloned().peekable();
+    // Stick to one address for the whole sequence so every knock lands
+    // on the same machine, unless asked to knock them all
+    let ips: Arc<[SocketAddr]> = match addrs {
+        [first, _, ..] if !all_ips => {
+            let addr = match steps.next_if(|s| s.kind.is_none() && !s.decoy) {
+                Some(step) => {
+                    pulled.store(1, Ordering::Relaxed);
+                    let sent = outcomes.len();
+                    let first = |step, addrs| knock(0, step, addrs);
+                    let addr = pick_address(&first, step, addrs, outcomes, events).await;
+                    *done = 1;
+                    if outcomes.len() == sent {
+                        skipped.push(0);
+                    }
+                    tally(groups, group_of, 0, &outcomes[sent..]);
+                    addr?
+                }
+                None => *first,
+            };
+            events.emit(KnockEvent::AddressChosen {
+                host: host.to_string(),
+                addr,
+            });
+            Arc::from([addr])
+        }
+        _ => Arc::from(addrs),
+    };
+    // One address for every knock, and for verifying them after
+    if let [addr] = *ips {
+        *address = Some(addr.ip());
+    }
+    // Knocks not yet started go wherever the host has moved to
+    let ips = std::sync::Mutex::new(ips);
+
+    let start = |step: KnockStep| {
+        let index = pulled.fetch_add(1, Ordering::Relaxed);
+        let ips = Arc::clone(&ips.lock().unwrap());
+        knock(index, step.clone(), ips).map(move |outcome| (index, step, outcome))
+    };
+    let start = &start;
+
+    loop {
+        // Run the remaining knocks one after another, or overlapping
+        // when unordered (outcomes still in sequence order), start no
+        // more once cancelled, and drop the ones in flight once one
+        // fails when failing fast. Each group is its own stream of
+        // knocks, and the next one starts once it has run dry
+        let remaining: Vec<KnockStep> = steps.collect();
+        let pass: Vec<(Vec<KnockStep>, usize)> = match grouped {
+            true => remaining
+                .chunk_by(|_, next| next.with_previous)
+                .map(|group| (group.to_vec(), group_width(group.len())))
+                .collect(),
+            false => vec![(remaining, concurrency)],
+        };
+        let pass = pass.into_iter().map(|(group, width)| {
+            futures::stream::iter(group.into_iter().map(start))
+                .take_until(cancel.cancelled())
+                .buffered(width)
+        });
+        let mut knocks = std::pin::pin!(futures::stream::iter(pass).flatten());
+        while let Some((index, step, mut outcome)) = knocks.next().await {
+            // Given up for the window: the knock was not sent
+            if outcome.is_empty() && clock.overran.lock().unwrap().is_some() {
+                skipped.push(*done);
+                *done += 1;
+                break;
+            }
+            let decoy = step.decoy;
+            // How long a lockstep reply was waited for
+            let waited = recv_timeout.unwrap_or(match step.timeout {
+                Some(timeout) => timeout.as_millis() as u64,
+                None => run_timeout.load(Ordering::Relaxed),
+            });
+            // A knock that could not reach the host may have gone to an
+            // address it left: follow it and send that knock once more
+            let current = ips.lock().unwrap().first().copied();
+            if let (true, Some(current)) = (reresolve, current) {
+                if !cancel.is_cancelled() && outcome.iter().any(KnockOutcome::unreachable) {
+                    let moved = follow_host(host, current, dns_cache, resolve, events).await;
+                    if let Some(addr) = moved {
+                        *address = Some(addr.ip());
+                        *ips.lock().unwrap() = Arc::from([addr]);
+                        outcome = knock(index, step, Arc::from([addr])).await;
+                    }
+                }
+            }
+            if outcome.is_empty() {
+                skipped.push(*done);
+            }
+            *done += 1;
+            // A decoy is only there to be seen; how it did is no matter
+            if decoy {
+                *decoys += outcome.len();
+                continue;
+            }
+            tally(groups, group_of, index, &outcome);
+            let failed = outcome.iter().find(|o| !o.succeeded).cloned();
+            match !outcome.is_empty() && outcome.iter().all(KnockOutcome::unreachable) {
+                true => unreachable += 1,
+                false => unreachable = 0,
+            }
+            outcomes.extend(outcome);
+            // A host that is down would cost every step left its full
+            // retries; take it for down and skip them
+            let left = plan.len() - *done;
+            if break_after.is_some_and(|n| unreachable >= n) && left > 0 {
+                events.notice(
+                    None,
+                    format!(
+                        "{unreachable} steps in a row got no answer from {host}; \
+                         circuit open, skipping the remaining {left}"
+                    ),
+                );
+                *circuit_open = Some(*done);
+                return Err(AppError::CircuitOpen {
+                    host: host.to_string(),
+                    failed: unreachable,
+                    skipped: left,
+                });
+            }
+            match failed {
+                // The server never answered, so the next knock would
+                // only confuse it
+                Some(failed)
+                    if lockstep
+                        && failed.protocol == Protocol::Udp
+                        && failed.errors.last().is_some_and(|e| e.timed_out) =>
+                {
+                    return Err(AppError::Lockstep {
+                        port: failed.port,
+                        waited,
+                    });
+                }
+                Some(failed) if fail_fast || lockstep => {
+                    failed.into_result()?;
+                }
+                _ => {}
+            }
+        }
+
+        // A pass that ran past the window is no use to the server; wait
+        // for it to forget the pass and start the sequence over
+        let Some((next_port, elapsed)) = clock.overran.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let overrun = WindowOverrun {
+            sent: *done - 1,
+            elapsed,
+            next_port,
+        };
+        events.notice(None, format!("Pass {passes} {overrun}"));
+        overruns.push(overrun);
+        if overruns.len() > restarts {
+            return Err(AppError::WindowOverrun {
+                window: config.window.unwrap_or_default(),
+                passes: *passes,
+            });
+        }
+        *passes += 1;
+        events.notice(
+            None,
+            format!("Starting the sequence over in {cooldown}ms (pass {passes})"),
+        );
+        tokio::select! {
+            _ = rt::sleep(std::time::Duration::from_millis(cooldown)) => {}
+            _ = cancel.cancelled() => return Ok(()),
+        }
+        outcomes.clear();
+        skipped.clear();
+        *decoys = 0;
+        unreachable = 0;
+        for group in groups.iter_mut() {
+            (group.sent, group.succeeded) = (0, 0);
+        }
+        *done = 0;
+        pulled.store(0, Ordering::Relaxed);
+        clock.restart();
+        steps = plan.iter().cloned().peekable();
+    }
+}
+
+/// Where a pass over the sequence stands, shared by its knocks and the
+/// loop sending them.
+struct PassClock {
+    /// Slots and gaps count from here, the start of the pass.
+    start: std::sync::Mutex<rt::Instant>,
+    /// When the pass sent its first packet.
+    first_sent: std::sync::Mutex<Option<rt::Instant>>,
+    /// The knock the pass was given up at for running past the window,
+    /// with how long after its first packet.
+    overran: std::sync::Mutex<Option<(u16, std::time::Duration)>>,
+}
+
+impl PassClock {
+    fn new() -> Self {
+        Self {
+            start: std::sync::Mutex::new(rt::Instant::now()),
+            first_sent: std::sync::Mutex::new(None),
+            overran: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Start a pass over from now.
+    fn restart(&self) {
+        *self.first_sent.lock().unwrap() = None;
+        *self.start.lock().unwrap() = rt::Instant::now();
+    }
+}
+
+/// Outcomes of a run in progress. Finishing it emits
+/// [`KnockEvent::Finished`]; a recorder dropped unfinished, because the
+/// run future was dropped, emits it as interrupted.
+struct RunRecorder {
+    events: EventSink,
+    host: String,
+    /// The address chosen for every knock, once there is one.
+    address: Option<IpAddr>,
+    /// Port of every step of the sequence.
+    ports: Vec<u16>,
+    started_at: SystemTime,
+    started: Instant,
+    outcomes: Vec<KnockOutcome>,
+    /// Steps handed to the knocks so far, and the ones of them that have
+    /// finished; steps finish in sequence order.
+    pulled: AtomicUsize,
+    done: usize,
+    /// Finished steps that were cancelled before their knock was sent.
+    skipped: Vec<usize>,
+    /// Passes started, and the ones given up for running past the window.
+    passes: usize,
+    overruns: Vec<WindowOverrun>,
+    /// Knocks of each group of a grouped sequence, and the group of each
+    /// step but the decoys.
+    groups: Vec<GroupReport>,
+    group_of: Vec<Option<usize>>,
+    /// Decoy knocks sent.
+    decoys: usize,
+    attempts_overridden: bool,
+    /// First step skipped once the circuit breaker opened.
+    circuit_open: Option<usize>,
+    budget: Option<Arc<budget::RetryBudget>>,
+    failure_tolerance: Option<FailureTolerance>,
+    open_probe: Option<OpenProbe>,
+    finished: bool,
+}
+
+impl RunRecorder {
+    fn new(
+        events: &EventSink,
+        host: &str,
+        ports: Vec<u16>,
+        started_at: SystemTime,
+        started: Instant,
+    ) -> Self {
+        Self {
+            events: events.clone(),
+            host: host.to_string(),
+            address: None,
+            ports,
+            started_at,
+            started,
+            outcomes: Vec::new(),
+            pulled: AtomicUsize::new(0),
+            done: 0,
+            skipped: Vec::new(),
+            passes: 1,
+            overruns: Vec::new(),
+            groups: Vec::new(),
+            group_of: Vec::new(),
+            decoys: 0,
+            attempts_overridden: false,
+            circuit_open: None,
+            budget: None,
+            failure_tolerance: None,
+            open_probe: None,
+            finished: false,
+        }
+    }
+
+    /// Tally the knocks of `plan` by group, leaving decoys out.
+    fn group(&mut self, plan: &KnockPlan) {
+        for steps in plan.groups() {
+            if steps.iter().all(|s| s.decoy) {
+                self.group_of.extend(std::iter::repeat_n(None, steps.len()));
+                continue;
+            }
+            let index = self.groups.len();
+            self.group_of
+                .extend(std::iter::repeat_n(Some(index), steps.len()));
+            self.groups.push(GroupReport {
+                ports: steps.iter().map(|s| s.port).collect(),
+                ..GroupReport::default()
+            });
+        }
+    }
+
+    fn finish(&mut self, interrupted: bool) -> KnockReport {
+        self.finished = true;
+        let pulled = self.pulled.load(Ordering::Relaxed).min(self.ports.len());
+        let done = self.done.min(pulled);
+        let not_started = self.skipped.iter().map(|&i| self.ports[i]);
+        let report = KnockReport {
+            host: self.host.clone(),
+            address: self.address,
+            started_at: self.started_at,
+            steps: std::mem::take(&mut self.outcomes),
+            duration: self.started.elapsed(),
+            interrupted,
+            aborted: self.ports[done..pulled].to_vec(),
+            not_started: not_started
+                .chain(self.ports[pulled..].iter().copied())
+                .collect(),
+            passes: self.passes,
+            overruns: std::mem::take(&mut self.overruns),
+            groups: std::mem::take(&mut self.groups),
+            decoys: self.decoys,
+            attempts_overridden: self.attempts_overridden,
+            circuit_open: self.circuit_open.map(|first| first + 1..=self.ports.len()),
+            retry_budget: self.budget.as_ref().map(|budget| budget.report()),
+            failure_tolerance: self.failure_tolerance,
+            ladder: None,
+            open_probe: self.open_probe.take(),
+        };
+        self.events.emit(KnockEvent::Finished {
+            report: Box::new(report.clone()),
+        });
+        report
+    }
+}
+
+impl Drop for RunRecorder {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish(true);
+        }
+    }
+}
+
+/// Count the knocks of step `index` toward its group, if the sequence has
+/// groups.
+fn tally(
+    groups: &mut [GroupReport],
+    group_of: &[Option<usize>],
+    index: usize,
+    outcome: &[KnockOutcome],
+) {
+    if let Some(group) = group_of
+        .get(index)
+        .copied()
+        .flatten()
+        .and_then(|g| groups.get_mut(g))
+    {
+        group.sent += outcome.len();
+        group.succeeded += outcome.iter().filter(|o| o.succeeded).count();
+    }
+}
+
+/// Hand a knock's outcome to the observer and announce it when it did not
+/// get through, turning a knock that could not even be attempted into a
+/// failed outcome.
+fn finish_knock(
+    events: &EventSink,
+    target: KnockTarget,
+    result: Result<KnockOutcome, AppError>,
+) -> KnockOutcome {
+    let outcome = result.unwrap_or_else(|e| {
+        let error = e.to_string();
+        events.attempt(AttemptInfo {
+            target: target.clone(),
+            attempt: 0,
+            result: AttemptResult::Failed {
+                error: error.clone(),
+            },
+        });
+        let mut outcome = KnockOutcome::failed(target.port, target.protocol, error);
+        outcome.errors[0].class = e.class();
+        outcome
+    });
+    events.result(target, &outcome);
+    outcome
+}
+
+/// Write the reply a knock got to a file, noting where in its outcome. A
+/// reply that cannot be written only costs a notice.
+fn save_reply(
+    saver: &ReplySaver,
+    index: usize,
+    mut outcome: KnockOutcome,
+    target: &KnockTarget,
+    events: &EventSink,
+) -> KnockOutcome {
+    let Some(reply) = &outcome.reply else {
+        return outcome;
+    };
+    match saver.save(index, target.port, reply) {
+        Ok(Some(saved)) => {
+            events.emit(KnockEvent::ReplySaved {
+                target: target.clone(),
+                path: saved.path.clone(),
+                bytes: saved.bytes,
+                truncated: saved.truncated,
+            });
+            outcome.reply_file = Some(saved.path);
+        }
+        Ok(None) => events.notice(
+            Some(target),
+            format!(
+                "reply not saved, {} bytes of replies already written",
+                replies::TOTAL_LIMIT
+            ),
+        ),
+        Err(e) => events.notice(Some(target), format!("cannot save reply: {e}")),
+    }
+    outcome
+}
+
+/// Send the first knock to each resolved address in turn until one gets
+/// through, and return that address for the rest of the sequence.
+///
+/// Only the successful knock is kept in `outcomes`; when every address
+/// fails the run ends with the last address's knock error.
+async fn pick_address<F, Fut>(
+    knock: &F,
+    step: KnockStep,
+    addrs: &[SocketAddr],
+    outcomes: &mut Vec<KnockOutcome>,
+    events: &EventSink,
+) -> Result<SocketAddr, AppError>
+where
+    F: Fn(KnockStep, Arc<[SocketAddr]>) -> Fut,
+    Fut: Future<Output = Vec<KnockOutcome>>,
+{
+    let mut error = AppError::NoDns;
+    for &addr in addrs {
+        let result = knock(step.clone(), Arc::from([addr])).await;
+        // Cancelled before the knock was sent: nothing more to try
+        if result.is_empty() || result.iter().any(|o| o.succeeded) {
+            outcomes.extend(result);
+            return Ok(addr);
+        }
+        if let Some(Err(e)) = result.into_iter().last().map(KnockOutcome::into_result) {
+            error = e;
+        }
+        events.notice(
+            None,
+            format!("Knock via {} failed, trying the next address", addr.ip()),
+        );
+    }
+    Err(error)
+}
+
+/// The addresses of the family `strategy` prefers, or all of `addrs` when
+/// it prefers none or the host has none of that family.
+fn preferred_family(addrs: &[SocketAddr], strategy: ResolveStrategy) -> Vec<SocketAddr> {
+    let v6 = match strategy {
+        ResolveStrategy::PreferV4 => false,
+        ResolveStrategy::PreferV6 => true,
+        _ => return addrs.to_vec(),
+    };
+    let preferred: Vec<SocketAddr> = addrs
+        .iter()
+        .filter(|a| a.is_ipv6() == v6)
+        .copied()
+        .collect();
+    match preferred.is_empty() {
+        true => addrs.to_vec(),
+        false => preferred,
+    }
+}
+
+/// Resolve `host` again after a knock could not reach it at `current`.
+///
+/// Returns the address to knock from now on when the host no longer
+/// resolves to `current`, and `None` to stay put. The run keeps the
+/// family of `current`, so a host that only has addresses of the other
+/// one now is not followed. The fresh addresses go into the cache for
+/// later runs either way.
+async fn follow_host<F, Fut>(
+    host: &str,
+    current: SocketAddr,
+    cache: &DnsCache,
+    resolve: F,
+    events: &EventSink,
+) -> Option<SocketAddr>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Vec<SocketAddr>, AppError>>,
+{
+    let lookup = match cache
+        .lookup(host, ResolutionPolicy::EveryRound, resolve)
+        .await
+    {
+        Ok(lookup) => lookup,
+        Err(e) => {
+            events.notice(None, format!("Resolving {host} again failed: {e}"));
+            return None;
+        }
+    };
+    if let Some(previous) = lookup.changed_from {
+        events.emit(KnockEvent::AddressesChanged {
+            host: host.to_string(),
+            previous,
+            addrs: lookup.addrs.clone(),
+        });
+    }
+    if lookup.addrs.iter().any(|a| a.ip() == current.ip()) {
+        events.notice(
+            None,
+            format!("{host} still resolves to {}, not retrying", current.ip()),
+        );
+        return None;
+    }
+    let Some(&addr) = lookup
+        .addrs
+        .iter()
+        .find(|a| a.is_ipv6() == current.is_ipv6())
+    else {
+        let family = if current.is_ipv6() { "IPv6" } else { "IPv4" };
+        events.notice(
+            None,
+            format!("{host} no longer has an {family} address, not following it"),
+        );
+        return None;
+    };
+    events.emit(KnockEvent::AddressChosen {
+        host: host.to_string(),
+        addr,
+    });
+    Some(addr)
+}
+
+/// Load the fwknop keys and fill in the access request defaults.
+#[cfg(feature = "fwknop")]
+async fn fwknop_config(
+    settings: &config::FwknopSettings,
+) -> Result<fwknop::FwknopConfig, AppError> {
+    let key = spa::load_key(settings.key.as_ref(), fwknop::KEY_ENV)
+        .await
+        .map_err(AppError::Spa)?;
+    // The HMAC key is optional, but a source that cannot be read is not
+    let hmac_key = match &settings.hmac_key {
+        Some(source) => Some(
+            spa::load_key(Some(source), fwknop::HMAC_KEY_ENV)
+                .await
+                .map_err(AppError::Spa)?,
+        ),
+        None => spa::load_key(None, fwknop::HMAC_KEY_ENV).await.ok(),
+    };
+    let user = settings
+        .user
+        .clone()
+        .or_else(|| std::env::var("USER").ok())
+        .ok_or_else(|| AppError::Spa("no username: pass --fwknop-user or set USER".into()))?;
+    Ok(fwknop::FwknopConfig {
+        key,
+        hmac_key,
+        user,
+        allow_ip: settings
+            .allow_ip
+            .unwrap_or(std::net::Ipv4Addr::UNSPECIFIED.into()),
+        access: settings.access.clone(),
+    })
+}
+
+/// What one knock works from, gathered once before it starts and
+/// borrowed by each of its attempts.
+struct KnockContext {
+    host: Arc<str>,
+    /// Addresses to knock; none with a proxy.
+    addrs: Arc<[SocketAddr]>,
+    payload: Option<Bytes>,
+    timing: Timing,
+}
+
+/// When a knock goes out.
+enum Timing {
+    /// At a fixed time, with strict timing; timed finely when the slot has
+    /// a sub-millisecond part.
+    Slot(rt::Instant, bool),
+    /// This long after the previous knock went out.
+    Gap(std::time::Duration),
+}
+
+/// The run's own knock for its protocol, with its log label, capture and
+/// payload; custom transports replace it per protocol or per port.
+struct Builtin<'a> {
+    host: &'a str,
+    protocol: Protocol,
+    opts: &'a KnockOpts,
+    payload: Option<&'a [u8]>,
+    pcap: Option<&'a PcapWriter>,
+    events: &'a EventSink,
+    #[cfg(feature = "raw")]
+    raw_flags: Option<packet::TcpFlags>,
+    #[cfg(feature = "raw")]
+    spoof: Option<IpAddr>,
+    #[cfg(feature = "raw")]
+    icmp_reply: bool,
+}
+
+impl KnockTransport for Builtin<'_> {
+    fn knock<'a>(
+        &'a self,
+        target: SocketAddr,
+        _step: &'a KnockStep,
+        _deadline: std::time::Duration,
+    ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+        let (host, opts, events, pcap) = (self.host, self.opts, self.events, self.pcap);
+        Box::pin(async move {
+            match self.protocol {
+                #[cfg(feature = "raw")]
+                Protocol::Tcp if self.raw_flags.is_some() || self.spoof.is_some() => {
+                    let flags = self.raw_flags.unwrap_or(packet::TcpFlags::SYN);
+                    raw::knock_tcp_raw(host, target, flags, self.spoof, pcap, events).await
+                }
+                Protocol::Tcp => {
+                    tcp::knock(host, target.port(), Some(target), opts, pcap, events).await
+                }
+                #[cfg(feature = "raw")]
+                Protocol::Udp if self.spoof.is_some() => {
+                    let spoof = self.spoof.unwrap_or(target.ip());
+                    raw::knock_udp_spoofed(host, target, self.payload, spoof, pcap, events).await
+                }
+                Protocol::Udp => udp::knock(host, target, self.payload, opts, pcap, events).await,
+                #[cfg(feature = "raw")]
+                Protocol::Icmp => {
+                    icmp::knock_icmp(
+                        host,
+                        target.ip(),
+                        target.port(),
+                        opts,
+                        self.icmp_reply,
+                        events,
+                    )
+                    .await
+                }
+                #[cfg(not(feature = "raw"))]
+                Protocol::Icmp => unreachable!("icmp is rejected up front without `raw`"),
+                #[cfg(target_os = "linux")]
+                Protocol::Sctp => Ok(sctp::knock_sctp(host, target, opts, events).await),
+                #[cfg(not(target_os = "linux"))]
+                Protocol::Sctp => unreachable!("sctp is rejected at parse time off Linux"),
+                Protocol::Both => unreachable!("both is expanded into tcp and udp steps"),
+            }
+        })
+    }
+}
+
+/// The run's knock options with a step's own timeout, attempts, source
+/// port, TTL and, for TCP, payload.
+fn step_opts(opts: &KnockOpts, step: &KnockStep) -> KnockOpts {
+    let mut opts = opts.clone();
+    if let Some(timeout) = step.timeout {
+        opts.timeout = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+    }
+    if let Some(attempts) = step.attempts {
+        opts.attempts = Attempts::Finite(attempts);
+    }
+    if let Some(payload) = &step.payload {
+        opts.tcp.payload = Some(payload.clone());
+    }
+    opts.tcp.bind_port = step.source_port;
+    opts.udp.bind_port = step.source_port;
+    opts.tcp.ttl = step.ttl;
+    opts.udp.ttl = step.ttl;
+    opts
+}
+
+/// Tell the timeout calibrated from `samples`, taken by `what`, and return
+/// it in milliseconds; `None` without a sample.
+fn calibrated_timeout(
+    samples: &[std::time::Duration],
+    what: &str,
+    events: &EventSink,
+) -> Option<u64> {
+    let ms = calibrate::timeout_for(samples)?.as_millis() as u64;
+    events.notice(None, format!("Timeout calibrated to {ms}ms from {what}"));
+    Some(ms)
+}
+
+/// Warn when the local clock is off the SNTP server's by more than the
+/// check allows, or fail with [`AppError::ClockSkew`] when it is strict.
+/// A server that cannot be asked only warns, and a cancelled run stops
+/// asking.
+async fn check_clock(
+    check: &config::ClockCheck,
+    events: &EventSink,
+    cancel: &CancellationToken,
+) -> Result<(), AppError> {
+    let skew = tokio::select! {
+        skew = sntp::clock_skew(&check.server, sntp::QUERY_TIMEOUT) => skew,
+        _ = cancel.cancelled() => return Ok(()),
+    };
+    let skew = match skew {
+        Ok(skew) => skew,
+        Err(e) => {
+            events.notice(
+                None,
+                format!(
+                    "Could not check the clock against {}: {e}; knocking anyway",
+                    check.server
+                ),
+            );
+            return Ok(());
+        }
+    };
+    if skew.unsigned_abs() <= check.max_skew * 1000 {
+        return Ok(());
+    }
+    if check.strict {
+        return Err(AppError::ClockSkew {
+            server: check.server.to_string(),
+            skew,
+            max: check.max_skew,
+        });
+    }
+    events.notice(
+        None,
+        format!(
+            "Local clock is {} {}, more than the {}s allowed; time-based knocks may be rejected",
+            sntp::describe_skew(skew),
+            check.server,
+            check.max_skew
+        ),
+    );
+    Ok(())
+}
+
+/// The address the host will see the knocks come from: the one the STUN
+/// server saw, or, when it cannot tell and that is allowed, the local
+/// address the knocks leave from, with a warning. A cancelled run stops
+/// asking.
+async fn public_ip(
+    check: &config::PublicIp,
+    addrs: &[SocketAddr],
+    events: &EventSink,
+    cancel: &CancellationToken,
+) -> Result<Option<IpAddr>, AppError> {
+    let v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let asked = tokio::select! {
+        addr = stun::public_addr(&check.server, v6, stun::QUERY_TIMEOUT) => addr,
+        _ = cancel.cancelled() => return Ok(None),
+    };
+    let e = match asked {
+        Ok(addr) => {
+            events.notice(
+                None,
+                format!("Public address, as {} sees it: {}", check.server, addr.ip()),
+            );
+            return Ok(Some(addr.ip()));
+        }
+        Err(e) => e,
+    };
+    let failed = |reason: String| AppError::PublicIp {
+        server: check.server.to_string(),
+        reason,
+    };
+    if check.required {
+        return Err(failed(e.to_string()));
+    }
+    // Connecting a UDP socket only picks the route, nothing is sent
+    let local = addrs.first().map(|&target| {
+        let unspecified: IpAddr = match target {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+        };
+        std::net::UdpSocket::bind((unspecified, 0))
+            .and_then(|socket| socket.connect(target).and_then(|()| socket.local_addr()))
+    });
+    match local {
+        Some(Ok(local)) => {
+            events.notice(
+                None,
+                format!(
+                    "Could not learn the public address from {}: {e}; using the local address {}",
+                    check.server,
+                    local.ip()
+                ),
+            );
+            Ok(Some(local.ip()))
+        }
+        Some(Err(local)) => Err(failed(format!("{e}, and no local address either: {local}"))),
+        None => Err(failed(e.to_string())),
+    }
+}
+
+/// `payload` with every `{public_ip}` in it replaced by `ip`; `None` when
+/// there is none.
+fn fill_public_ip(payload: &[u8], ip: &str) -> Option<Vec<u8>> {
+    const PLACEHOLDER: &[u8] = b"{public_ip}";
+    let mut filled = Vec::with_capacity(payload.len());
+    let mut rest = payload;
+    while let Some(at) = rest
+        .windows(PLACEHOLDER.len())
+        .position(|w| w == PLACEHOLDER)
+    {
+        filled.extend_from_slice(&rest[..at]);
+        filled.extend_from_slice(ip.as_bytes());
+        rest = &rest[at + PLACEHOLDER.len()..];
+    }
+    if rest.len() == payload.len() {
+        return None;
+    }
+    filled.extend_from_slice(rest);
+    Some(filled)
+}
+
+/// First port and length of a range of 1-65535.
+fn port_span(ports: &RangeInclusive<u16>) -> (u16, u16) {
+    (*ports.start(), ports.end() - ports.start() + 1)
+}
+
+/// Derived steps on `ports`, sent from `sources`.
+fn with_source_ports(ports: Vec<u16>, sources: Vec<Option<u16>>) -> KnockPlan {
+    ports
+        .into_iter()
+        .zip(sources)
+        .map(|(port, source_port)| KnockStep {
+            source_port,
+            ..KnockStep::new(port)
+        })
+        .collect()
+}
+
+/// Send a fresh QUIC Initial, with its own connection IDs, as one UDP
+/// knock; the handshake never goes on, so no reply is waited for.
+#[cfg(feature = "quic")]
+async fn knock_quic(
+    host: &str,
+    addr: SocketAddr,
+    opts: &KnockOpts,
+    pcap: Option<&PcapWriter>,
+    events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    let mut opts = opts.clone();
+    opts.udp.expect_reply = false;
+    let packet = quic::random_initial();
+    udp::knock(host, addr, Some(&packet), &opts, pcap, events).await
+}
+
+#[cfg(not(feature = "quic"))]
+async fn knock_quic(
+    _host: &str,
+    _addr: SocketAddr,
+    _opts: &KnockOpts,
+    _pcap: Option<&PcapWriter>,
+    _events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    unreachable!("quic steps are rejected up front without `quic`")
+}
+
+/// The compiled plugins of the `plugin` steps, by path; never any
+/// without the `wasm-plugins` feature.
+#[cfg(feature = "wasm-plugins")]
+type Plugins = std::collections::HashMap<std::path::PathBuf, plugin::Plugin>;
+#[cfg(not(feature = "wasm-plugins"))]
+type Plugins = std::collections::HashMap<std::path::PathBuf, std::convert::Infallible>;
+
+/// A `plugin` step to knock: its plugin among the run's, and its argument.
+#[cfg_attr(not(feature = "wasm-plugins"), allow(dead_code))]
+struct PluginStep<'a> {
+    plugins: &'a Plugins,
+    path: &'a Path,
+    arg: Option<&'a str>,
+}
+
+#[cfg(feature = "wasm-plugins")]
+fn load_plugins(plan: &KnockPlan) -> Result<Plugins, AppError> {
+    plugin::load_all(plan.iter().filter_map(|step| match &step.kind {
+        Some(StepKind::Plugin { path, .. }) => Some(path),
+        _ => None,
+    }))
+}
+
+/// Without the `wasm-plugins` feature plugin steps are rejected up front.
+#[cfg(not(feature = "wasm-plugins"))]
+fn load_plugins(_plan: &KnockPlan) -> Result<Plugins, AppError> {
+    Ok(Plugins::new())
+}
+
+/// Knock `addr` with the datagram the plugin of `step` builds, and have
+/// the plugin judge the reply it asks for. A plugin that fails fails this
+/// knock alone.
+#[cfg(feature = "wasm-plugins")]
+async fn knock_plugin(
+    host: &str,
+    addr: SocketAddr,
+    step: PluginStep<'_>,
+    opts: &KnockOpts,
+    pcap: Option<&PcapWriter>,
+    events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    let plugin = &step.plugins[step.path];
+    let request = plugin::PluginRequest {
+        host,
+        target: addr,
+        arg: step.arg,
+    };
+    let (mut call, knock) = match plugin.build(&request) {
+        Ok(built) => built,
+        Err(e) => return Ok(KnockOutcome::failed(addr.port(), Protocol::Udp, e)),
+    };
+    let mut opts = opts.clone();
+    opts.udp.expect_reply = knock.expect_reply;
+    let mut outcome = udp::knock(host, addr, Some(&knock.datagram), &opts, pcap, events).await?;
+    if let Some(reply) = outcome.reply.as_deref().filter(|_| outcome.succeeded) {
+        let rejected = match call.check(reply) {
+            Ok(true) => None,
+            Ok(false) => Some("the plugin rejected the reply".to_string()),
+            Err(e) => Some(e),
+        };
+        if let Some(message) = rejected {
+            outcome.succeeded = false;
+            outcome
+                .errors
+                .push(AttemptError::new(outcome.attempts, message));
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+async fn knock_plugin(
+    _host: &str,
+    _addr: SocketAddr,
+    _step: PluginStep<'_>,
+    _opts: &KnockOpts,
+    _pcap: Option<&PcapWriter>,
+    _events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    unreachable!("plugin steps are rejected up front without `wasm-plugins`")
+}
+
+/// The address an HTTP, TLS or QUIC step connects to: the first of the chosen
+/// addresses, or with a proxy (which these steps do not use) the host's
+/// first address.
+async fn step_addr(host: &str, ips: &[SocketAddr], port: u16) -> Result<SocketAddr, AppError> {
+    let mut addr = match ips.first() {
+        Some(&addr) => addr,
+        None => dns::resolve_target(host, ResolveStrategy::All).await?[0],
+    };
+    addr.set_port(port);
+    Ok(addr)
+}
+
+/// Log label for one of several resolved addresses, bracketing IPv6 so
+/// `label:port` stays readable.
+fn addr_label(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V4(a) => a.ip().to_string(),
+        SocketAddr::V6(a) => format!("[{}]", a.ip()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the bytes each thread allocates, so a test on the
+    /// current-thread runtime can tell what its run cost.
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // SAFETY: every call is passed on to the system allocator unchanged
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|n| n.set(n.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING: Counting = Counting;
+
+    /// Bytes the current thread allocated while `f` ran to completion.
+    async fn allocated_by<F: Future>(f: F) -> (F::Output, usize) {
+        let before = ALLOCATED.with(Cell::get);
+        let output = f.await;
+        (output, ALLOCATED.with(Cell::get) - before)
+    }
+
+    fn step() -> KnockStep {
+        KnockStep::new(7000)
+    }
+
+    /// Knock that only gets through to IPv4 addresses.
+    async fn v4_only(step: KnockStep, ips: Arc<[SocketAddr]>) -> Vec<KnockOutcome> {
+        let ok = ips[0].is_ipv4();
+        vec![KnockOutcome {
+            port: step.port,
+            protocol: Protocol::Tcp,
+            addr: Some(ips[0]),
+            source_port: None,
+            fwmark: None,
+            ttl: None,
+            payload_len: None,
+            attempts_allowed: None,
+            attempts: 1,
+            succeeded: ok,
+            acknowledged: ok,
+            latency: None,
+            reply: None,
+            reply_file: None,
+            stray_replies: 0,
+            errors: Vec::new(),
+            elapsed: std::time::Duration::ZERO,
+        }]
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_address() {
+        let addrs: Vec<SocketAddr> =
+            vec!["[::1]:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+        let mut outcomes = Vec::new();
+        let addr = pick_address(
+            &v4_only,
+            step(),
+            &addrs,
+            &mut outcomes,
+            &EventSink::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, addrs[1]);
+        assert_eq!(outcomes.len(), 1);
+        assert!(outcomes[0].succeeded);
+    }
+
+    #[tokio::test]
+    async fn step_payload_is_shared_not_copied() {
+        const SIZE: usize = 64 * 1024;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut buf = vec![0u8; SIZE];
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                use tokio::io::AsyncReadExt;
+                while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+            }
+        });
+        let knocks = |size: usize| {
+            let step = KnockStep {
+                payload: Some(Bytes::from(vec![0x5a; size])),
+                ..KnockStep::new(port)
+            };
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .plan(plan::KnockPlan(vec![step; 4]))
+                .build()
+                .unwrap()
+        };
+
+        // Four knocks writing a big payload cost no more than with a tiny
+        // one: none makes a copy of its own
+        let (report, small) = allocated_by(run(knocks(16))).await;
+        assert!(report.unwrap().succeeded());
+        let (report, big) = allocated_by(run(knocks(SIZE))).await;
+        assert!(report.unwrap().succeeded());
+        assert!(
+            big < small + SIZE,
+            "{big} bytes allocated, {small} with a tiny payload"
+        );
+    }
+
+    /// Transport for a host that moved: knocks on `stale` time out.
+    struct Moved {
+        stale: std::net::IpAddr,
+    }
+
+    impl KnockTransport for Moved {
+        fn knock<'a>(
+            &'a self,
+            target: SocketAddr,
+            step: &'a KnockStep,
+            _deadline: std::time::Duration,
+        ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+            let reached = target.ip() != self.stale;
+            Box::pin(async move {
+                Ok(KnockOutcome {
+                    succeeded: reached,
+                    attempts: 1,
+                    errors: match reached {
+                        true => Vec::new(),
+                        false => vec![AttemptError::timeout(1, "connect timed out")],
+                    },
+                    ..KnockOutcome::failed(step.port, Protocol::Tcp, "")
+                })
+            })
+        }
+    }
+
+    /// Transport for a host whose IPv6 routing is broken, noting where
+    /// each knock went.
+    #[derive(Default)]
+    struct BrokenV6 {
+        targets: std::sync::Mutex<Vec<SocketAddr>>,
+    }
+
+    impl KnockTransport for BrokenV6 {
+        fn knock<'a>(
+            &'a self,
+            target: SocketAddr,
+            step: &'a KnockStep,
+            _deadline: std::time::Duration,
+        ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+            self.targets.lock().unwrap().push(target);
+            let reached = target.is_ipv4();
+            Box::pin(async move {
+                Ok(KnockOutcome {
+                    succeeded: reached,
+                    attempts: 1,
+                    errors: match reached {
+                        true => Vec::new(),
+                        false => vec![AttemptError::new(1, "Network is unreachable")],
+                    },
+                    ..KnockOutcome::failed(step.port, step.protocol.unwrap_or(Protocol::Tcp), "")
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn every_knock_and_the_verify_use_one_family() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().port();
+        // The resolver answers with both families, IPv6 first
+        let config = |strategy| {
+            let transport = Arc::new(BrokenV6::default());
+            let config = KnockConfig::builder()
+                .host("dual.test")
+                .resolve_pin("dual.test:[::1]".parse().unwrap())
+                .resolve_pin("dual.test:127.0.0.1".parse().unwrap())
+                .resolve(strategy)
+                .plan(KnockPlan::parse("7000,8000/udp,9000").unwrap())
+                .transport(Protocol::Tcp, transport.clone())
+                .transport(Protocol::Udp, transport.clone())
+                .build()
+                .unwrap();
+            (config, transport)
+        };
+        let v4: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let at = |port| SocketAddr::new(v4.ip(), port);
+
+        // IPv6 fails the first knock, and the rest never try it
+        let (all, transport) = config(ResolveStrategy::All);
+        let report = run(all).await.unwrap();
+        assert_eq!(report.address, Some(v4.ip()));
+        let targets = transport.targets.lock().unwrap().clone();
+        assert_eq!(
+            targets,
+            ["[::1]:7000".parse().unwrap(), at(7000), at(8000), at(9000)]
+        );
+        assert!(report.steps.iter().all(|o| o.addr.unwrap().ip() == v4.ip()));
+        // The verifying connect goes there too, not to a fresh lookup
+        let verify = verify::Verify::new(open);
+        assert!(verify::check("dual.test", report.address, &verify)
+            .await
+            .is_ok());
+
+        // Preferring IPv4 never knocks on IPv6 at all
+        let (prefer, transport) = config(ResolveStrategy::PreferV4);
+        let report = run(prefer).await.unwrap();
+        assert_eq!(report.address, Some(v4.ip()));
+        let targets = transport.targets.lock().unwrap().clone();
+        assert_eq!(targets, [at(7000), at(8000), at(9000)]);
+    }
+
+    #[tokio::test]
+    async fn a_moved_host_is_only_followed_within_its_family() {
+        let current: SocketAddr = "[2001:db8::1]:0".parse().unwrap();
+        let moved = |addrs: &'static [&'static str]| {
+            let addrs: Vec<SocketAddr> = addrs.iter().map(|a| a.parse().unwrap()).collect();
+            move || async move { Ok(addrs) }
+        };
+        let (cache, events) = (DnsCache::new(), EventSink::default());
+        let follow = |resolve| follow_host("dual.test", current, &cache, resolve, &events);
+        assert_eq!(follow(moved(&["192.0.2.7:0"])).await, None);
+        assert_eq!(
+            follow(moved(&["192.0.2.7:0", "[2001:db8::7]:0"])).await,
+            Some("[2001:db8::7]:0".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_the_host_to_a_new_address() {
+        // An earlier run left an address the host has since moved away from
+        let stale: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([7000, 8000])
+            .transport(Protocol::Tcp, Arc::new(Moved { stale: stale.ip() }))
+            .build()
+            .unwrap();
+        let seed = || async { Ok(vec![stale]) };
+        let cache = &config.dns_cache;
+        cache
+            .lookup("127.0.0.1", ResolutionPolicy::Once, seed)
+            .await
+            .unwrap();
+        let report = run(config.clone()).await.unwrap_err();
+        assert!(matches!(report, AppError::Partial { succeeded: 0, .. }));
+
+        let config = KnockConfig {
+            reresolve_on_failure: true,
+            ..config
+        };
+        let cache = &config.dns_cache;
+        cache
+            .lookup("127.0.0.1", ResolutionPolicy::EveryRound, seed)
+            .await
+            .unwrap();
+        let (events, handle) = run_with_events(config, CancellationToken::new());
+        let events: Vec<KnockEvent> = events.collect().await;
+        let report = handle.await.unwrap().unwrap();
+        assert!(report.succeeded());
+        let addrs: Vec<_> = report.steps.iter().map(|o| o.addr.unwrap()).collect();
+        assert_eq!(
+            addrs,
+            [
+                "127.0.0.1:7000".parse().unwrap(),
+                "127.0.0.1:8000".parse().unwrap()
+            ]
+        );
+        assert!(events.iter().any(|e| matches!(
+            e,
+            KnockEvent::AddressesChanged { previous, .. } if previous == &[stale]
+        )));
+    }
+
+    #[tokio::test]
+    async fn dropped_run_still_finishes() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let ports = vec![0, 1, 2];
+        let mut recorder = RunRecorder::new(
+            &EventSink::new(tx),
+            "h",
+            ports,
+            SystemTime::now(),
+            Instant::now(),
+        );
+        recorder
+            .outcomes
+            .extend(v4_only(step(), Arc::from(["127.0.0.1:0".parse().unwrap()])).await);
+        recorder.done = 1;
+        recorder.pulled.store(2, Ordering::Relaxed);
+        drop(recorder);
+
+        let events: Vec<KnockEvent> = rx.collect().await;
+        match events.as_slice() {
+            [KnockEvent::Finished { report }] => {
+                assert!(report.interrupted);
+                assert_eq!(report.steps.len(), 1);
+                assert_eq!(
+                    (&report.aborted[..], &report.not_started[..]),
+                    (&[1][..], &[2][..])
+                );
+            }
+            other => panic!("unexpected events {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelling_interrupts_pending_knocks() {
+        // Nothing answers, so the knock would wait out its receive timeout
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([port])
+            .protocol(Protocol::Udp)
+            .expect_reply(true)
+            .recv_timeout(10_000)
+            .build()
+            .unwrap();
+        let cancel = CancellationToken::new();
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            rt::sleep(std::time::Duration::from_millis(50)).await;
+            trigger.cancel();
+        });
+
+        let report = run_with_cancel(config, cancel).await.unwrap();
+        assert!(report.interrupted);
+        assert!(report.steps.is_empty());
+        // Cut off once the drain timeout of twice the knock timeout ran out
+        assert_eq!(report.aborted, [port]);
+        assert!(report.duration < std::time::Duration::from_secs(5));
+    }
+
+    /// UDP port that answers each datagram after `delay`.
+    async fn slow_responder(delay: std::time::Duration) -> u16 {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((_, peer)) = socket.recv_from(&mut buf).await {
+                rt::sleep(delay).await;
+                let _ = socket.send_to(b"ok", peer).await;
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn cancelling_lets_knocks_in_flight_finish() {
+        use std::time::Duration;
+
+        let slow = slow_responder(Duration::from_millis(200)).await;
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan(format!("{slow},{slow}?delay=5000,7").parse().unwrap())
+            .protocol(Protocol::Udp)
+            .expect_reply(true)
+            .recv_timeout(2_000)
+            .build()
+            .unwrap();
+        let cancel_soon = |token: &CancellationToken| {
+            let token = token.clone();
+            tokio::spawn(async move {
+                rt::sleep(Duration::from_millis(50)).await;
+                token.cancel();
+            });
+        };
+
+        // The first knock gets its reply; the others are never sent
+        let cancel = CancellationToken::new();
+        cancel_soon(&cancel);
+        let report = run_with_cancel(config.clone(), cancel).await.unwrap();
+        assert!(report.interrupted);
+        assert_eq!(report.steps.len(), 1);
+        assert!(report.steps[0].acknowledged);
+        assert!(report.aborted.is_empty());
+        assert_eq!(report.not_started, [slow, 7]);
+
+        // Aborting does not wait for it
+        let (cancel, abort) = (CancellationToken::new(), CancellationToken::new());
+        cancel_soon(&cancel);
+        cancel_soon(&abort);
+        let report = run_with_abort(config, cancel, abort).await.unwrap();
+        assert!(report.steps.is_empty());
+        assert_eq!(report.aborted, [slow]);
+        assert_eq!(report.not_started, [slow, 7]);
+        assert!(report.duration < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn every_address_failing_ends_the_run() {
+        let addrs: Vec<SocketAddr> = vec!["[::1]:0".parse().unwrap(), "[::2]:0".parse().unwrap()];
+        let mut outcomes = Vec::new();
+        let err = pick_address(
+            &v4_only,
+            step(),
+            &addrs,
+            &mut outcomes,
+            &EventSink::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, AppError::KnockFailed { port: 7000, .. }),
+            "{err}"
+        );
+        assert!(outcomes.is_empty());
+    }
+
+    /// Records what the hooks saw, in order.
+    #[derive(Default)]
+    struct Recorder {
+        attempts: std::sync::Mutex<Vec<AttemptInfo>>,
+        results: std::sync::Mutex<Vec<KnockOutcome>>,
+        events: std::sync::Mutex<Vec<KnockEvent>>,
+    }
+
+    impl KnockObserver for Recorder {
+        fn on_attempt(&self, info: &AttemptInfo) {
+            self.attempts.lock().unwrap().push(info.clone());
+        }
+
+        fn on_result(&self, outcome: &KnockOutcome) {
+            self.results.lock().unwrap().push(outcome.clone());
+        }
+
+        fn on_event(&self, event: &KnockEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn observer_sees_every_attempt() {
+        // A closed port refuses every attempt
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let recorder = Arc::new(Recorder::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([port])
+            .refused_is_failure(true)
+            .attempts(2)
+            .backoff(0)
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        let err = run(config).await.unwrap_err();
+        assert!(
+            matches!(err, AppError::Partial { succeeded: 0, .. }),
+            "{err}"
+        );
+
+        let attempts = recorder.attempts.lock().unwrap();
+        let numbers: Vec<usize> = attempts.iter().map(|a| a.attempt).collect();
+        assert_eq!(numbers, [1, 2]);
+        assert!(attempts
+            .iter()
+            .all(|a| matches!(a.result, AttemptResult::Failed { .. }) && a.target.port == port));
+        let results = recorder.results.lock().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn dry_run_shows_passphrase_derived_ports() {
+        let path = std::env::temp_dir().join(format!("passphrase-{}", std::process::id()));
+        std::fs::write(&path, "correct horse battery staple\n").unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .passphrase_ports(config::PassphrasePorts {
+                source: SecretSource::File(path.clone()),
+                knocks: 3,
+                port_base: 20000,
+                port_range: 1000,
+            })
+            .dry_run(true)
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        run(config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected =
+            passphrase::derive_ports(b"correct horse battery staple", "127.0.0.1", 3, 20000, 1000)
+                .unwrap();
+        let events = recorder.events.lock().unwrap();
+        let plan = events
+            .iter()
+            .find_map(|e| match e {
+                KnockEvent::Plan { text } => Some(text),
+                _ => None,
+            })
+            .unwrap();
+        let ports: Vec<String> = expected.iter().map(u16::to_string).collect();
+        assert!(plan.contains(&ports.join(" -> ")), "{plan}");
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn dry_run_shows_the_script_plan() {
+        let dir = std::env::temp_dir().join(format!("script-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("secret"), "7\n").unwrap();
+        std::fs::write(
+            dir.join("knock.rhai"),
+            r#"fn plan(ctx) {
+                print(`for ${ctx.host}`);
+                let n = parse_int(ctx.secrets.n);
+                [7000 + n, #{ port: 8000 + n, protocol: "udp", payload: "hi" }]
+            }"#,
+        )
+        .unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .script(config::ScriptPlan {
+                path: dir.join("knock.rhai"),
+                secrets: vec![("n".into(), SecretSource::File(dir.join("secret")))],
+            })
+            .dry_run(true)
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        run(config).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let events = recorder.events.lock().unwrap();
+        let plan = events
+            .iter()
+            .find_map(|e| match e {
+                KnockEvent::Plan { text } => Some(text),
+                _ => None,
+            })
+            .unwrap();
+        assert!(plan.contains("7007 -> 8007/udp?payload=6869"), "{plan}");
+        assert!(events.iter().any(
+            |e| matches!(e, KnockEvent::Notice { message, .. } if message == "script: for 127.0.0.1")
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fwmark_shows_in_the_plan_and_the_report() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(1)
+            .udp_ports(1)
+            .bind()
+            .await
+            .unwrap();
+        let plan: KnockPlan = format!("{},{}/udp", server.tcp_ports()[0], server.udp_ports()[0])
+            .parse()
+            .unwrap();
+        let builder = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan(plan)
+            .fwmark(0x2a);
+        let recorder = Arc::new(Recorder::default());
+        let dry_run = builder.clone().dry_run(true).observer(recorder.clone());
+        run(dry_run.build().unwrap()).await.unwrap();
+        let plan = recorder
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|e| match e {
+                KnockEvent::Plan { text } => Some(text.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert!(plan.contains("Fwmark:    42 (0x2a)"), "{plan}");
+
+        match run(builder.build().unwrap()).await {
+            Ok(report) => assert!(report.steps.iter().all(|s| s.fwmark == Some(42))),
+            // Without CAP_NET_ADMIN the run stops before its first knock
+            Err(e) => {
+                assert!(e.to_string().contains("needs CAP_NET_ADMIN"), "{e}");
+                assert_eq!(e.exit_code(), 4);
+                assert!(server.received().is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn warmed_up_run_sends_the_plan_it_shows() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(2)
+            .bind()
+            .await
+            .unwrap();
+        let ports = server.udp_ports();
+        let plan: KnockPlan = format!("{},{}?delay=150", ports[0], ports[1])
+            .parse()
+            .unwrap();
+        let builder = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan(plan)
+            .protocol(Protocol::Udp)
+            .payload(b"knock".to_vec())
+            .pad_to(16)
+            .warmup(true);
+
+        let recorder = Arc::new(Recorder::default());
+        let dry_run = builder.clone().dry_run(true).observer(recorder.clone());
+        run(dry_run.build().unwrap()).await.unwrap();
+        let events = std::mem::take(&mut *recorder.events.lock().unwrap());
+        let Some(KnockEvent::Plan { text }) = events.get(1) else {
+            panic!("{events:?}");
+        };
+        let listed = format!(
+            "Warmed:    2 knocks\n  #1 {}/udp  +0ms  16 bytes\n  #2 {}/udp  +150ms  16 bytes\n",
+            ports[0], ports[1]
+        );
+        assert!(text.ends_with(&listed), "{text}");
+
+        // Held until the start time, then sent as warmed up
+        let start = SystemTime::now() + std::time::Duration::from_millis(200);
+        let begun = Instant::now();
+        let report = run(builder.clone().start_at(start).build().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(report.steps.len(), 2);
+        assert!(begun.elapsed() >= std::time::Duration::from_millis(350));
+        let received = server.wait_for(2, std::time::Duration::from_secs(2)).await;
+        assert!(received.iter().all(|k| k.payload.len() == 16));
+
+        // A start time already gone is a mistake
+        let late = builder.start_at(SystemTime::now()).build().unwrap();
+        assert!(matches!(
+            run(late).await,
+            Err(AppError::InvalidConfig(msg)) if msg.contains("passed")
+        ));
+    }
+
+    #[tokio::test]
+    async fn jump_host_only_forwards_tcp() {
+        let jumped = |protocol| {
+            KnockConfig::builder()
+                .host("10.0.0.5")
+                .sequence([7000])
+                .protocol(protocol)
+                .jump(jump::JumpHost::parse("bastion").unwrap())
+                .build()
+                .unwrap()
+        };
+        let err = run(jumped(Protocol::Udp)).await.unwrap_err();
+        match cfg!(feature = "ssh") {
+            true => assert!(
+                matches!(&err, AppError::Jump(m) if m.starts_with("udp knocks are not supported")),
+                "{err}"
+            ),
+            false => assert!(matches!(err, AppError::InvalidConfig(_)), "{err}"),
+        }
+        let rst = KnockConfig::builder()
+            .host("10.0.0.5")
+            .sequence([7000])
+            .tcp_close(TcpClose::Rst)
+            .jump(jump::JumpHost::parse("bastion").unwrap())
+            .build();
+        assert!(matches!(rst, Err(AppError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn pinned_host_is_never_resolved() {
+        let recorder = Arc::new(Recorder::default());
+        let config = KnockConfig::builder()
+            .host("knock.invalid")
+            .sequence([7000])
+            .resolve_pin("KNOCK.invalid:127.0.0.1".parse().unwrap())
+            .resolve_pin("other.invalid:::1".parse().unwrap())
+            .dry_run(true)
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        run(config).await.unwrap();
+        let events = recorder.events.lock().unwrap();
+        let plan = events
+            .iter()
+            .find_map(|e| match e {
+                KnockEvent::Plan { text } => Some(text),
+                _ => None,
+            })
+            .unwrap();
+        assert!(plan.contains("Addresses: 127.0.0.1 (pinned)\n"), "{plan}");
+    }
+
+    #[tokio::test]
+    async fn spoofed_source_turns_replies_off_and_refuses_other_knocks() {
+        let recorder = Arc::new(Recorder::default());
+        let spoofed = || {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([7000])
+                .spoof_source("192.0.2.1".parse().unwrap())
+                .dry_run(true)
+        };
+        let config = spoofed()
+            .expect_reply(true)
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        if !cfg!(feature = "raw") {
+            assert!(matches!(run(config).await, Err(AppError::RawSocket(_))));
+            return;
+        }
+        run(config).await.unwrap();
+        let noticed = recorder.events.lock().unwrap().iter().any(|e| {
+            matches!(
+                e,
+                KnockEvent::Notice { message, .. } if message.contains("not waiting for them")
+            )
+        });
+        assert!(noticed);
+
+        let icmp = spoofed().protocol(Protocol::Icmp).build().unwrap();
+        assert!(matches!(run(icmp).await, Err(AppError::InvalidConfig(_))));
+        let v6 = spoofed()
+            .spoof_source("2001:db8::1".parse().unwrap())
+            .resolve(ResolveStrategy::OnlyV4)
+            .build()
+            .unwrap();
+        assert!(matches!(run(v6).await, Err(AppError::InvalidConfig(_))));
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn quic_steps_send_an_initial_packet() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan(plan::KnockPlan::parse(&format!("{port}:quic")).unwrap())
+            .build()
+            .unwrap();
+        let report = run(config).await.unwrap();
+        assert_eq!(report.steps[0].protocol, Protocol::Udp);
+        let mut buf = [0; 2048];
+        let len = server.recv(&mut buf).await.unwrap();
+        assert_eq!(len, quic::MIN_INITIAL_LEN);
+        assert_eq!(buf[0] & 0xf0, 0xc0);
+        assert_eq!(buf[1..5], quic::VERSION_1.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn payloads_too_big_to_go_unfragmented_are_flagged() {
+        let recorder = Arc::new(Recorder::default());
+        let config = |host: &str, len: usize| {
+            KnockConfig::builder()
+                .host(host)
+                .sequence([7000])
+                .protocol(Protocol::Udp)
+                .payload(vec![0; len])
+                .dry_run(true)
+        };
+        // Fits IPv4 on Ethernet, but not the IPv6 minimum MTU
+        run(config("127.0.0.1", 1400).build().unwrap())
+            .await
+            .unwrap();
+        let config_v6 = config("::1", 1400).observer(recorder.clone());
+        run(config_v6.build().unwrap()).await.unwrap();
+        let warned = recorder.events.lock().unwrap().iter().any(|e| {
+            matches!(e, KnockEvent::Notice { message, .. } if message.contains("more than the 1232"))
+        });
+        assert!(warned);
+
+        let strict = config("127.0.0.1", 1473).strict(true).build().unwrap();
+        assert!(matches!(run(strict).await, Err(AppError::Payload(_))));
+    }
+
+    #[tokio::test]
+    async fn observer_receives_the_whole_output() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let builder = KnockConfig::builder().host("127.0.0.1").sequence([port]);
+
+        let recorder = Arc::new(Recorder::default());
+        let config = builder.clone().dry_run(true).observer(recorder.clone());
+        run(config.build().unwrap()).await.unwrap();
+        let events = std::mem::take(&mut *recorder.events.lock().unwrap());
+        assert!(matches!(
+            events.as_slice(),
+            [
+                KnockEvent::Resolved { .. },
+                KnockEvent::Plan { text },
+                KnockEvent::Finished { .. },
+            ] if text.contains(&port.to_string())
+        ));
+
+        let recorder = Arc::new(Recorder::default());
+        run(builder.observer(recorder.clone()).build().unwrap())
+            .await
+            .unwrap();
+        let events = recorder.events.lock().unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                KnockEvent::Resolved { .. },
+                KnockEvent::KnockStarted { .. },
+                KnockEvent::KnockSucceeded { attempt: 1, .. },
+                KnockEvent::Finished { report },
+            ] if report.steps.len() == 1
+        ));
+    }
+
+    /// A local port that refuses connections.
+    fn closed_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn a_step_gets_its_own_attempts() {
+        let open = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = open.local_addr().unwrap().port();
+        let closed = closed_port();
+        let plan = KnockPlan::parse(&format!("{open},{closed}*3")).unwrap();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan(plan)
+            .refused_is_failure(true)
+            .attempts(1)
+            .backoff(0)
+            .build()
+            .unwrap();
+
+        let (events, handle) = run_with_events(config, CancellationToken::new());
+        let events: Vec<KnockEvent> = events.collect().await;
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(AppError::Partial { .. })
+        ));
+        let Some(KnockEvent::Finished { report }) = events.last() else {
+            panic!("no report");
+        };
+        let attempts: Vec<_> = report
+            .steps
+            .iter()
+            .map(|o| (o.port, o.attempts, o.attempts_allowed))
+            .collect();
+        assert_eq!(attempts, [(open, 1, Some(1)), (closed, 3, Some(3))]);
+        assert!(report.attempts_overridden);
+    }
+
+    #[tokio::test]
+    async fn failures_within_the_tolerance_succeed_the_run() {
+        let open = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = open.local_addr().unwrap().port();
+        let closed = closed_port();
+        let builder = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([open, closed, open])
+            .refused_is_failure(true)
+            .backoff(0);
+
+        let report = run(builder.clone().max_failures(1).build().unwrap())
+            .await
+            .unwrap();
+        assert!(!report.succeeded());
+        let failed: Vec<u16> = report.failures().iter().map(|f| f.port).collect();
+        assert_eq!(failed, [closed]);
+
+        // One of three is more than 30%
+        let err = run(builder.clone().max_failure_percent(30.0).build().unwrap())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Partial { succeeded: 2, .. }));
+
+        // Nothing is left to tolerate once the first failure ends the run
+        assert!(builder.max_failures(1).fail_fast(true).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_together_in_order() {
+        let open = [
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let [open_a, open_b] = [0, 1].map(|i| open[i].local_addr().unwrap().port());
+        let [closed_a, closed_b] = [closed_port(), closed_port()];
+        let builder = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([open_a, closed_a, open_b, closed_b])
+            .refused_is_failure(true)
+            .attempts(2)
+            .backoff(0)
+            .concurrency(2)
+            .unordered(true);
+
+        let err = run(builder.clone().build().unwrap()).await.unwrap_err();
+        let AppError::Partial { failed, succeeded } = err else {
+            panic!("unexpected {err}");
+        };
+        assert_eq!(succeeded, 2);
+        let ports: Vec<u16> = failed.iter().map(|f| f.port).collect();
+        assert_eq!(ports, [closed_a, closed_b]);
+        assert!(failed
+            .iter()
+            .all(|f| f.attempts == 2 && f.errors.len() == 2));
+
+        // Failing fast stops at the first closed port
+        let err = run(builder.fail_fast(true).build().unwrap())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, AppError::KnockFailed { port, attempts: 2, .. } if port == closed_a),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn delay_spaces_knocks_without_postponing_the_first() {
+        use std::time::Duration;
+
+        let server = testing::MockKnockServer::builder()
+            .udp_ports(0)
+            .bind()
+            .await
+            .unwrap();
+        let ports = server.tcp_ports().to_vec();
+        let builder = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence(ports[..2].to_vec())
+            .delay(300);
+        let report = run(builder.clone().build().unwrap()).await.unwrap();
+        let received = server.wait_for(2, Duration::from_secs(2)).await;
+        assert!(received[0].at < Duration::from_millis(250), "{received:?}");
+        assert!(received[1].at - received[0].at >= Duration::from_millis(300));
+        assert!(report.duration >= Duration::from_millis(300));
+
+        // Asked for explicitly, the first knock waits
+        let report = run(builder
+            .sequence([ports[2]])
+            .initial_delay(100)
+            .build()
+            .unwrap())
+        .await
+        .unwrap();
+        assert!(report.duration >= Duration::from_millis(100));
+        assert!(report.duration < Duration::from_millis(300));
+    }
+
+    /// Knock three ports of a fresh mock server, the first one waiting
+    /// longest; returns the ports in sequence order, in arrival order and
+    /// as reported.
+    async fn knock_in_order(unordered: bool) -> (Vec<u16>, Vec<u16>, Vec<u16>) {
+        let server = testing::MockKnockServer::builder()
+            .udp_ports(0)
+            .bind()
+            .await
+            .unwrap();
+        let ports = server.tcp_ports().to_vec();
+        let plan = format!("{}?delay=150,{}?delay=50,{}", ports[0], ports[1], ports[2]);
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan(plan.parse().unwrap())
+            .concurrency(3)
+            .unordered(unordered)
+            .build()
+            .unwrap();
+        let report = run(config).await.unwrap();
+        let received = server.wait_for(3, std::time::Duration::from_secs(2)).await;
+        let arrived = received.iter().map(|k| k.port).collect();
+        let reported = report.steps.iter().map(|o| o.port).collect();
+        (ports, arrived, reported)
+    }
+
+    #[tokio::test]
+    async fn knocks_arrive_in_sequence_order_whatever_the_concurrency() {
+        let (ports, arrived, reported) = knock_in_order(false).await;
+        assert_eq!(arrived, ports);
+        assert_eq!(reported, ports);
+
+        // Unordered they may overlap, but each still waits its delay after
+        // the one before went out
+        let (ports, arrived, reported) = knock_in_order(true).await;
+        assert_eq!(arrived, ports);
+        assert_eq!(reported, ports);
+    }
+
+    #[tokio::test]
+    async fn delay_spaces_knocks_in_flight_together() {
+        use std::time::Duration;
+
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(4)
+            .udp_ports(0)
+            .bind()
+            .await
+            .unwrap();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence(server.tcp_ports().to_vec())
+            .delay(100)
+            .concurrency(4)
+            .unordered(true)
+            .build()
+            .unwrap();
+        run(config).await.unwrap();
+        let received = server.wait_for(4, Duration::from_secs(3)).await;
+        assert_eq!(received.len(), 4);
+        for pair in received.windows(2) {
+            assert!(
+                pair[1].at - pair[0].at >= Duration::from_millis(90),
+                "{received:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn steps_are_sent_from_their_source_ports() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(1)
+            .udp_ports(1)
+            .bind()
+            .await
+            .unwrap();
+        let free = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let source = free.local_addr().unwrap().port();
+        drop(free);
+        let (tcp_port, udp_port) = (server.tcp_ports()[0], server.udp_ports()[0]);
+        let plan: KnockPlan = format!("{tcp_port}<{source},{udp_port}<{source}/udp")
+            .parse()
+            .unwrap();
+        let config = KnockConfig::builder().host("127.0.0.1").plan(plan.clone());
+        let report = run(config.clone().build().unwrap()).await.unwrap();
+        let sources: Vec<_> = report.steps.iter().map(|o| o.source_port).collect();
+        assert_eq!(sources, [Some(source), Some(source)]);
+        let received = server.wait_for(2, std::time::Duration::from_secs(2)).await;
+        assert!(received.iter().all(|k| k.source.port() == source));
+
+        // A port in use fails the step instead of another being picked
+        let _taken = std::net::UdpSocket::bind(("0.0.0.0", source)).unwrap();
+        let udp_only = config.plan(KnockPlan(plan[1..].to_vec()));
+        match run(udp_only.build().unwrap()).await {
+            Err(AppError::Partial { failed, .. }) => {
+                assert!(
+                    failed[0].to_string().contains("could not bind"),
+                    "{failed:?}"
+                )
+            }
+            other => panic!("expected a failed step, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn steps_are_sent_with_their_ttls() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(2)
+            .udp_ports(1)
+            .bind()
+            .await
+            .unwrap();
+        let (tcp, udp) = (server.tcp_ports(), server.udp_ports()[0]);
+        let plan: KnockPlan = format!("{}~63,{},{udp}/udp", tcp[0], tcp[1])
+            .parse()
+            .unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan(plan)
+            .ttl_walk(plan::TtlWalk::parse("12,-4").unwrap())
+            .observer(recorder.clone());
+        let report = run(config.clone().build().unwrap()).await.unwrap();
+        let ttls: Vec<_> = report.steps.iter().map(|o| o.ttl).collect();
+        assert_eq!(ttls, [Some(63), Some(8), Some(4)]);
+        let details: Vec<String> = recorder
+            .attempts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|a| match &a.result {
+                AttemptResult::Delivered { detail, .. } => Some(detail.clone()),
+                AttemptResult::Failed { .. } => None,
+            })
+            .collect();
+        assert!(details[2].ends_with("(ttl 4)"), "{details:?}");
+        // Only the last one is below the minimum
+        let warned: Vec<String> = recorder
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|e| match e {
+                KnockEvent::Notice { message, .. } if message.contains("TTL below 8") => {
+                    Some(message.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(warned.len(), 1);
+        assert!(warned[0].contains(&format!("{udp}~4/udp")), "{warned:?}");
+        assert!(!warned[0].contains("~8"), "{warned:?}");
+
+        // An HTTP step has no socket of its own to set it on
+        let http = config.plan("80~60:http:/k".parse::<KnockPlan>().unwrap());
+        match run(http.build().unwrap()).await {
+            Err(AppError::InvalidConfig(msg)) => assert!(msg.contains("TTL"), "{msg}"),
+            other => panic!("expected a config error, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn length_sequence_sends_datagrams_of_those_lengths() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(1)
+            .bind()
+            .await
+            .unwrap();
+        let port = server.udp_ports()[0];
+        let recorder = Arc::new(Recorder::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .length_sequence(port, [100, 237, 61])
+            .observer(recorder.clone());
+        let report = run(config.clone().build().unwrap()).await.unwrap();
+        let lengths: Vec<_> = report.steps.iter().map(|o| o.payload_len).collect();
+        assert_eq!(lengths, [Some(100), Some(237), Some(61)]);
+        let received = server.wait_for(3, std::time::Duration::from_secs(2)).await;
+        let received: Vec<usize> = received.iter().map(|k| k.payload.len()).collect();
+        assert_eq!(received, [100, 237, 61]);
+
+        // A dry run shows the lengths rather than the bytes
+        config
+            .dry_run(true)
+            .build()
+            .map(run)
+            .unwrap()
+            .await
+            .unwrap();
+        let events = recorder.events.lock().unwrap();
+        let plan = events
+            .iter()
+            .find_map(|e| match e {
+                KnockEvent::Plan { text } => Some(text),
+                _ => None,
+            })
+            .unwrap();
+        assert!(
+            plan.contains(&format!("{port}/udp (100 bytes) -> {port}/udp (237 bytes)")),
+            "{plan}"
+        );
+    }
+
+    #[tokio::test]
+    async fn timing_code_is_heard_in_the_gaps() {
+        use std::time::Duration;
+
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(1)
+            .bind()
+            .await
+            .unwrap();
+        let unit = Duration::from_millis(100);
+        let code = config::TimingCode {
+            bits: config::TimingCode::parse_bits("10110").unwrap(),
+            unit,
+            port: server.udp_ports()[0],
+            protocol: Protocol::Udp,
+        };
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .timing_code(code.clone())
+            .build()
+            .unwrap();
+        run(config).await.unwrap();
+        let received = server.wait_for(6, Duration::from_secs(3)).await;
+        assert_eq!(received.len(), 6);
+        let tolerance = unit * 2 / 5;
+        for (bit, pair) in code.bits.iter().zip(received.windows(2)) {
+            let (gap, expected) = (pair[1].at - pair[0].at, code.gap(*bit));
+            assert!(
+                gap.abs_diff(expected) < tolerance,
+                "gap {gap:?} for bit {bit}, expected {expected:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn both_protocols_knock_each_port_twice() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(2)
+            .bind()
+            .await
+            .unwrap();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence(server.tcp_ports().to_vec())
+            .protocol(Protocol::Both)
+            .build()
+            .unwrap();
+        let report = run(config).await.unwrap();
+        let sent: Vec<_> = report.steps.iter().map(|o| (o.port, o.protocol)).collect();
+        let ports = server.tcp_ports();
+        assert_eq!(
+            sent,
+            [
+                (ports[0], Protocol::Tcp),
+                (ports[0], Protocol::Udp),
+                (ports[1], Protocol::Tcp),
+                (ports[1], Protocol::Udp),
+            ]
+        );
+        assert!(report.steps.iter().all(|o| o.succeeded));
+        let received = server.wait_for(2, std::time::Duration::from_secs(2)).await;
+        let ports: Vec<u16> = received.iter().map(|k| k.port).collect();
+        assert_eq!(ports, server.tcp_ports());
+    }
+
+    #[tokio::test]
+    async fn lockstep_stops_at_the_first_unanswered_knock() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(3)
+            .drop_first(1)
+            .reply(b"ack".to_vec())
+            .bind()
+            .await
+            .unwrap();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence(server.udp_ports().to_vec())
+            .protocol(Protocol::Udp)
+            .timeout(100)
+            .attempts(3)
+            .lockstep(true)
+            .build()
+            .unwrap();
+        // The lost first knock is not sent again, nor is any after it
+        match run(config.clone()).await {
+            Err(AppError::Lockstep { port, waited }) => {
+                assert_eq!((port, waited), (server.udp_ports()[0], 100));
+            }
+            other => panic!("expected a lockstep error, got {other:?}"),
+        }
+        rt::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(server.received().len(), 1);
+
+        // Answered knocks carry on through the whole sequence
+        let report = run(config).await.unwrap();
+        assert!(report.steps.iter().all(|o| o.acknowledged));
+        assert_eq!(report.steps.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn replies_are_saved_to_files() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(1)
+            .reply(b"welcome".to_vec())
+            .bind()
+            .await
+            .unwrap();
+        let port = server.udp_ports()[0];
+        let dir = std::env::temp_dir().join(format!("apk-save-replies-{}", std::process::id()));
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([port, port])
+            .protocol(Protocol::Udp)
+            .expect_reply(true)
+            .save_replies(&dir)
+            .build()
+            .unwrap();
+        let report = run(config).await.unwrap();
+        for (index, outcome) in report.steps.iter().enumerate() {
+            let path = outcome.reply_file.as_ref().expect("reply saved");
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            assert!(name.starts_with(&format!("{index:03}-{port}-")), "{name}");
+            assert_eq!(std::fs::read(path).unwrap(), b"welcome");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hexdump_shows_both_directions() {
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(1)
+            .reply(b"welcome".to_vec())
+            .bind()
+            .await
+            .unwrap();
+        let port = server.udp_ports()[0];
+        let recorder = Arc::new(Recorder::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([port])
+            .protocol(Protocol::Udp)
+            .payload(b"knock".to_vec())
+            .expect_reply(true)
+            .hexdump(4)
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        run(config).await.unwrap();
+        let dumps: Vec<_> = recorder
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                KnockEvent::Hexdump {
+                    direction,
+                    attempt,
+                    len,
+                    dump,
+                    ..
+                } => Some((*direction, *attempt, *len, dump.clone())),
+                _ => None,
+            })
+            .collect();
+        let line = |hex: &str, ascii: &str| format!("00000000  {hex:<48}  |{ascii}|\n");
+        assert_eq!(
+            dumps,
+            [
+                (
+                    hexdump::Direction::Sent,
+                    1,
+                    5,
+                    line("6b 6e 6f 63", "knoc") + "... 1 more bytes\n"
+                ),
+                (
+                    hexdump::Direction::Received,
+                    1,
+                    7,
+                    line("77 65 6c 63", "welc") + "... 3 more bytes\n"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_server_gets_each_udp_knock_once() {
+        for expect_reply in [false, true] {
+            let server = testing::MockKnockServer::builder()
+                .tcp_ports(0)
+                .bind()
+                .await
+                .unwrap();
+            let mut builder = KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence(server.udp_ports().to_vec())
+                .protocol(Protocol::Udp)
+                .attempts(3)
+                .backoff(0);
+            if expect_reply {
+                builder = builder.expect_reply(true).recv_timeout(50);
+            }
+            let result = run(builder.build().unwrap()).await;
+            assert_eq!(result.is_ok(), !expect_reply);
+
+            // Give a duplicate the chance to show up
+            let received = server
+                .wait_for(4, std::time::Duration::from_millis(200))
+                .await;
+            let ports: Vec<u16> = received.iter().map(|k| k.port).collect();
+            assert_eq!(ports, server.udp_ports(), "expect_reply: {expect_reply}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_spaces_knocks_and_stops_waiting_when_cancelled() {
+        use std::time::Duration;
+
+        let server = testing::MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(3)
+            .bind()
+            .await
+            .unwrap();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence(server.udp_ports().to_vec())
+            .protocol(Protocol::Udp)
+            .rate(20)
+            .build()
+            .unwrap();
+        let report = run(config.clone()).await.unwrap();
+        assert_eq!(report.steps.len(), 3);
+        assert!(report.duration >= Duration::from_millis(100));
+
+        // At one a second the second knock is still waiting for its turn
+        let config = KnockConfig {
+            rate: Some(1),
+            ..config
+        };
+        let cancel = CancellationToken::new();
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            rt::sleep(Duration::from_millis(100)).await;
+            trigger.cancel();
+        });
+        let report = run_with_cancel(config, cancel).await.unwrap();
+        assert!(report.interrupted);
+        assert!(report.steps[0].succeeded);
+        assert_eq!(report.steps[1].attempts, 0);
+        assert_eq!(report.not_started, server.udp_ports()[2..]);
+        assert!(report.duration < Duration::from_millis(500));
+    }
+
+    /// Transport that records the ports it was asked to knock.
+    #[derive(Default)]
+    struct Magic(std::sync::Mutex<Vec<u16>>);
+
+    impl KnockTransport for Magic {
+        fn knock<'a>(
+            &'a self,
+            target: SocketAddr,
+            step: &'a KnockStep,
+            _deadline: std::time::Duration,
+        ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+            self.0.lock().unwrap().push(target.port());
+            Box::pin(async move { Ok(v4_only(step.clone(), Arc::from([target])).await.remove(0)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_transports_replace_the_builtin_knocks() {
+        let by_protocol = Arc::new(Magic::default());
+        let by_port = Arc::new(Magic::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([7000, 7001])
+            .protocol(Protocol::Udp)
+            .transport(Protocol::Udp, by_protocol.clone())
+            .step_transport(7001, by_port.clone())
+            .build()
+            .unwrap();
+        let report = run_with_cancel(config, CancellationToken::new())
+            .await
+            .unwrap();
+        assert!(report.succeeded());
+        assert_eq!(*by_protocol.0.lock().unwrap(), [7000]);
+        assert_eq!(*by_port.0.lock().unwrap(), [7001]);
+    }
+
+    #[tokio::test]
+    async fn no_dns_refuses_a_name_before_any_socket() {
+        let magic = Arc::new(Magic::default());
+        let mut config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([7000])
+            .protocol(Protocol::Udp)
+            .transport(Protocol::Udp, magic.clone())
+            .no_dns(true)
+            .build()
+            .unwrap();
+        config.host = "knock.example".into();
+        let err = run(config.clone()).await.unwrap_err();
+        assert!(
+            matches!(&err, AppError::DnsForbidden { what: "host", name } if name == "knock.example"),
+            "{err}"
+        );
+        assert_eq!(err.exit_code(), 2);
+        assert!(magic.0.lock().unwrap().is_empty());
+
+        // A pinned name needs no lookup
+        config.resolve_pins = vec!["knock.example:127.0.0.1".parse().unwrap()];
+        assert!(run(config).await.unwrap().succeeded());
+        assert_eq!(*magic.0.lock().unwrap(), [7000]);
+
+        let stun = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([7000])
+            .public_ip(config::PublicIp {
+                server: stun::StunServer::parse("stun.example").unwrap(),
+                required: false,
+            })
+            .no_dns(true)
+            .build();
+        assert!(matches!(
+            stun,
+            Err(AppError::DnsForbidden {
+                what: "STUN server",
+                ..
+            })
+        ));
+    }
+
+    /// Transport that takes its time on the first knock of a port, noting
+    /// when each knock started.
+    struct Slow {
+        start: rt::Instant,
+        took: std::collections::HashMap<u16, u64>,
+        fired: std::sync::Mutex<Vec<(u16, u128)>>,
+    }
+
+    impl KnockTransport for Slow {
+        fn knock<'a>(
+            &'a self,
+            target: SocketAddr,
+            step: &'a KnockStep,
+            _deadline: std::time::Duration,
+        ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+            let at = self.start.elapsed().as_millis();
+            let mut fired = self.fired.lock().unwrap();
+            let again = fired.iter().any(|&(port, _)| port == target.port());
+            fired.push((target.port(), at));
+            let took = match again {
+                true => 0,
+                false => self.took.get(&target.port()).copied().unwrap_or(0),
+            };
+            Box::pin(async move {
+                rt::sleep(std::time::Duration::from_millis(took)).await;
+                Ok(v4_only(step.clone(), Arc::from([target])).await.remove(0))
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strict_timing_fires_knocks_at_their_slots() {
+        let slow = Arc::new(Slow {
+            start: rt::Instant::now(),
+            // The second knock runs past the third one's slot
+            took: [(2, 1500), (3, 300)].into(),
+            fired: Default::default(),
+        });
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan("1,2,3,4,5?delay=500".parse().unwrap())
+            .delay(1000)
+            .strict_timing(true)
+            .transport(Protocol::Tcp, slow.clone())
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.schedule(),
+            [0, 1000, 2000, 3000, 3500].map(std::time::Duration::from_millis)
+        );
+
+        let err = run_with_cancel(config, CancellationToken::new())
+            .await
+            .unwrap_err();
+        // Slots kept despite the slow knocks; the missed one is not sent
+        assert_eq!(
+            *slow.fired.lock().unwrap(),
+            [(1, 0), (2, 1000), (4, 3000), (5, 3500)]
+        );
+        let AppError::Partial { failed, succeeded } = err else {
+            panic!("{err}");
+        };
+        assert_eq!(succeeded, 4);
+        assert_eq!(failed[0].port, 3);
+        assert_eq!(
+            failed[0].errors[0].message,
+            "missed its time slot by 500ms, not sent"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn next_group_waits_for_the_whole_group() {
+        let slow = Arc::new(Slow {
+            start: rt::Instant::now(),
+            took: [(1, 200), (2, 500)].into(),
+            fired: Default::default(),
+        });
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .plan("(1,2),3".parse().unwrap())
+            .delay(100)
+            .transport(Protocol::Tcp, slow.clone())
+            .build()
+            .unwrap();
+        let report = run_with_cancel(config, CancellationToken::new())
+            .await
+            .unwrap();
+        // Both knocks of the group go out together, and port 3 only once
+        // the slower of them is done
+        assert_eq!(*slow.fired.lock().unwrap(), [(1, 0), (2, 0), (3, 500)]);
+        let groups: Vec<String> = report.groups.iter().map(ToString::to_string).collect();
+        assert_eq!(groups, ["(1, 2) 2/2", "3 1/1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn decoys_are_sent_but_kept_out_of_the_report() {
+        let slow = Arc::new(Slow {
+            start: rt::Instant::now(),
+            took: Default::default(),
+            fired: Default::default(),
+        });
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([1, 2, 3])
+            .delay(0)
+            .decoys(4)
+            .transport(Protocol::Tcp, slow.clone())
+            .build()
+            .unwrap();
+        let report = run_with_cancel(config, CancellationToken::new())
+            .await
+            .unwrap();
+        let fired: Vec<u16> = slow.fired.lock().unwrap().iter().map(|&(p, _)| p).collect();
+        assert_eq!(fired.len(), 7);
+        let real: Vec<u16> = fired.iter().copied().filter(|&p| p <= 3).collect();
+        assert_eq!(real, [1, 2, 3]);
+        // Nothing follows the last real knock
+        assert_eq!(fired.last(), Some(&3));
+        assert_eq!(report.steps.len(), 3);
+        assert_eq!(report.decoys, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn locked_runs_never_knock_the_same_host_at_once() {
+        let locks = tempfile::tempdir().unwrap();
+        let slow = Arc::new(Slow {
+            start: rt::Instant::now(),
+            took: [(1, 300)].into(),
+            fired: Default::default(),
+        });
+        let config = |port, timeout| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([port])
+                .lock(timeout)
+                .lock_dir(locks.path())
+                .transport(Protocol::Tcp, slow.clone())
+                .build()
+                .unwrap()
+        };
+        let later = |config| async move {
+            rt::sleep(std::time::Duration::from_millis(10)).await;
+            run_with_cancel(config, CancellationToken::new()).await
+        };
+        let (first, refused, waited) = tokio::join!(
+            run_with_cancel(config(1, 0), CancellationToken::new()),
+            later(config(2, 0)),
+            later(config(3, 1000)),
+        );
+        assert!(first.unwrap().succeeded());
+        match refused {
+            Err(AppError::Locked { host, pid, .. }) => {
+                assert_eq!(
+                    (host.as_str(), pid),
+                    ("127.0.0.1", Some(std::process::id()))
+                )
+            }
+            other => panic!("expected the lock to be held, got {other:?}"),
+        }
+        assert!(waited.unwrap().succeeded());
+        // The waiting run only knocked once the first was done
+        let fired = slow.fired.lock().unwrap().clone();
+        assert_eq!(fired.len(), 2);
+        assert_eq!(fired[1].0, 3);
+        assert!(fired[1].1 >= 300, "{fired:?}");
+    }
+
+    #[tokio::test]
+    async fn payloads_name_the_public_address() {
+        // A STUN server that sees every request come from 203.0.113.9
+        let stun = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stun_port = stun.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((_, from)) = stun.recv_from(&mut buf).await {
+                let mut response = buf[..20].to_vec();
+                response[..4].copy_from_slice(&[0x01, 0x01, 0, 12]);
+                response.extend_from_slice(&[0, 0x20, 0, 8, 0, 1, 0x21, 0x12]);
+                response.extend_from_slice(&[203 ^ 0x21, 0x12, 113 ^ 0xa4, 9 ^ 0x42]);
+                let _ = stun.send_to(&response, from).await;
+            }
+        });
+        let gone = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let knocked = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = |stun_port: u16, required| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([knocked.local_addr().unwrap().port()])
+                .protocol(Protocol::Udp)
+                .payload(b"open for {public_ip}".to_vec())
+                .public_ip(config::PublicIp {
+                    server: stun::StunServer::parse(&format!("127.0.0.1:{stun_port}")).unwrap(),
+                    required,
+                })
+                .build()
+                .unwrap()
+        };
+        let received = || async {
+            let mut buf = [0u8; 64];
+            let len = knocked.recv(&mut buf).await.unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+
+        run(config(stun_port, true)).await.unwrap();
+        assert_eq!(received().await, "open for 203.0.113.9");
+        // Without an answer the local address stands in, unless required
+        run(config(gone, false)).await.unwrap();
+        assert_eq!(received().await, "open for 127.0.0.1");
+        let err = run(config(gone, true)).await.unwrap_err();
+        assert!(matches!(err, AppError::PublicIp { .. }), "{err}");
+        assert_eq!(err.exit_code(), 1);
+
+        assert_eq!(fill_public_ip(b"no placeholder", "192.0.2.1"), None);
+        assert_eq!(
+            fill_public_ip(b"{public_ip},{public_ip}", "::1").as_deref(),
+            Some(&b"::1,::1"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn clock_check_fails_strict_runs_and_only_warns_otherwise() {
+        // A server whose clock reads the 2036 rollover, years ahead
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ahead = server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 48];
+            while let Ok((_, from)) = server.recv_from(&mut buf).await {
+                let mut reply = [0u8; 48];
+                reply[0] = 0x24;
+                reply[1] = 1;
+                reply[24..32].copy_from_slice(&buf[40..48]);
+                let _ = server.send_to(&reply, from).await;
+            }
+        });
+        // And one that is not there
+        let gone = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let slow = Arc::new(Slow {
+            start: rt::Instant::now(),
+            took: Default::default(),
+            fired: Default::default(),
+        });
+        let recorder = Arc::new(Recorder::default());
+        let config = |port: u16, strict| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([7000])
+                .check_clock(config::ClockCheck {
+                    server: sntp::NtpServer::parse(&format!("127.0.0.1:{port}")).unwrap(),
+                    max_skew: 5,
+                    strict,
+                })
+                .transport(Protocol::Tcp, slow.clone())
+                .observer(recorder.clone())
+                .build()
+                .unwrap()
+        };
+
+        let err = run(config(ahead, true)).await.unwrap_err();
+        assert!(
+            matches!(err, AppError::ClockSkew { skew, max: 5, .. } if skew < -86_400_000),
+            "{err}"
+        );
+        assert!(err.to_string().contains("behind 127.0.0.1:"), "{err}");
+        assert!(slow.fired.lock().unwrap().is_empty());
+
+        assert!(run(config(ahead, false)).await.unwrap().succeeded());
+        assert!(run(config(gone, true)).await.unwrap().succeeded());
+        let notices: Vec<String> = recorder
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|e| match e {
+                KnockEvent::Notice { message, .. } => Some(message.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notices.len(), 2, "{notices:?}");
+        assert!(notices[0].contains("time-based knocks may be rejected"));
+        assert!(notices[1].starts_with("Could not check the clock"));
+        assert_eq!(slow.fired.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sequence_starts_over_when_it_runs_past_the_window() {
+        let slow = || {
+            Arc::new(Slow {
+                start: rt::Instant::now(),
+                took: [(2, 1500)].into(),
+                fired: Default::default(),
+            })
+        };
+        let config = |slow: Arc<Slow>| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([1, 2, 3])
+                .delay(0)
+                .window(1000)
+                .transport(Protocol::Tcp, slow)
+        };
+
+        let first = slow();
+        let report = run_with_cancel(
+            config(first.clone()).build().unwrap(),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        // Port 3 would have gone out 1500ms in; the second pass waits out
+        // the window and then gets through
+        assert_eq!(
+            *first.fired.lock().unwrap(),
+            [(1, 0), (2, 0), (1, 2500), (2, 2500), (3, 2500)]
+        );
+        assert_eq!(report.passes, 2);
+        assert_eq!(
+            report.overruns,
+            [WindowOverrun {
+                sent: 2,
+                elapsed: std::time::Duration::from_millis(1500),
+                next_port: 3,
+            }]
+        );
+        assert_eq!(report.steps.len(), 3);
+        assert!(report.succeeded());
+
+        let config = config(slow()).window_restarts(0).build().unwrap();
+        match run_with_cancel(config, CancellationToken::new()).await {
+            Err(AppError::WindowOverrun { window, passes }) => {
+                assert_eq!((window, passes), (1000, 1))
+            }
+            other => panic!("expected a window overrun, got {other:?}"),
+        }
+    }
+
+    /// Answers every knock 60ms after it is sent, noting the timeout each
+    /// was given.
+    #[derive(Default)]
+    struct Timed {
+        deadlines: std::sync::Mutex<Vec<(u16, u128)>>,
+    }
+
+    impl KnockTransport for Timed {
+        fn knock<'a>(
+            &'a self,
+            target: SocketAddr,
+            step: &'a KnockStep,
+            deadline: std::time::Duration,
+        ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+            let port = target.port();
+            self.deadlines
+                .lock()
+                .unwrap()
+                .push((port, deadline.as_millis()));
+            Box::pin(async move {
+                let mut outcome = v4_only(step.clone(), Arc::from([target])).await.remove(0);
+                outcome.latency = Some(std::time::Duration::from_millis(60));
+                Ok(outcome)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_timeout_is_calibrated_before_the_steps_without_their_own() {
+        let recorder = Arc::new(Recorder::default());
+        let config = |calibration_port, transport: Arc<Timed>| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .plan("7000,7001,7002?timeout=900".parse().unwrap())
+                .auto_timeout(calibration_port)
+                .rate(50)
+                .transport(Protocol::Tcp, transport)
+                .observer(recorder.clone())
+                .build()
+                .unwrap()
+        };
+
+        // Without a calibration port, the first knock goes out with
+        // --timeout and times the round trip for the rest
+        let timed = Arc::new(Timed::default());
+        assert!(run(config(None, timed.clone())).await.unwrap().succeeded());
+        let deadlines = timed.deadlines.lock().unwrap().clone();
+        assert_eq!(deadlines, [(7000, 500), (7001, 180), (7002, 900)]);
+
+        // A local port answers the probes at once, for the least timeout,
+        // and the probes wait their turn at the rate
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let timed = Arc::new(Timed::default());
+        let started = rt::Instant::now();
+        assert!(run(config(Some(port), timed.clone()))
+            .await
+            .unwrap()
+            .succeeded());
+        assert!(started.elapsed() >= std::time::Duration::from_millis(20 * 4));
+        let deadlines = timed.deadlines.lock().unwrap().clone();
+        assert_eq!(deadlines, [(7000, 100), (7001, 100), (7002, 900)]);
+
+        let notices: Vec<String> = recorder
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|e| match e {
+                KnockEvent::Notice { message, .. } => Some(message.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notices,
+            [
+                "Timeout calibrated to 180ms from the DNS lookup and first knock".to_string(),
+                format!("Timeout calibrated to 100ms from 5 connects to port {port}"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_port_already_open_is_not_knocked() {
+        let knocked = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let knocked_port = knocked.local_addr().unwrap().port();
+        let open = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = open.local_addr().unwrap().port();
+        let config = |port| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([knocked_port])
+                .skip_if_open(port)
+                .open_probe_timeout(200)
+                .build()
+                .unwrap()
+        };
+
+        let report = run(config(open_port)).await.unwrap();
+        assert!(report.steps.is_empty());
+        let probe = report.open_probe.unwrap();
+        assert_eq!(probe.port, open_port);
+        assert_eq!(
+            probe.open_at,
+            Some(format!("127.0.0.1:{open_port}").parse().unwrap())
+        );
+
+        // A closed port is knocked open as usual
+        drop(open);
+        let report = run(config(open_port)).await.unwrap();
+        assert_eq!(report.steps.len(), 1);
+        assert!(report.succeeded());
+        assert_eq!(report.open_probe.unwrap().open_at, None);
+        assert!(knocked.accept().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn the_open_check_tries_the_preferred_family_only() {
+        let open = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = open.local_addr().unwrap().port();
+        let config = |strategy| {
+            KnockConfig::builder()
+                .host("dual.test")
+                .resolve_pin("dual.test:[::1]".parse().unwrap())
+                .resolve_pin("dual.test:127.0.0.1".parse().unwrap())
+                .resolve(strategy)
+                .sequence([port])
+                .skip_if_open(port)
+                .open_probe_timeout(200)
+                .build()
+                .unwrap()
+        };
+        let v4: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let report = run(config(ResolveStrategy::PreferV4)).await.unwrap();
+        assert!(report.steps.is_empty());
+        assert_eq!(report.address, Some(v4));
+        assert_eq!(report.open_probe.unwrap().probed, [v4]);
+
+        let addrs: Vec<SocketAddr> =
+            vec!["[::1]:1".parse().unwrap(), "127.0.0.1:1".parse().unwrap()];
+        let ips = |strategy| -> Vec<IpAddr> {
+            preferred_family(&addrs, strategy)
+                .iter()
+                .map(SocketAddr::ip)
+                .collect()
+        };
+        assert_eq!(ips(ResolveStrategy::PreferV4), [v4]);
+        assert_eq!(
+            ips(ResolveStrategy::PreferV6),
+            ["::1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(ips(ResolveStrategy::All).len(), 2);
+        // A host without the preferred family is tried on what it has
+        assert_eq!(
+            preferred_family(&addrs[1..], ResolveStrategy::PreferV6),
+            &addrs[1..]
+        );
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_after_steps_in_a_row_get_no_answer() {
+        // A blackhole: whatever reaches it is swallowed without a word
+        let blackhole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = blackhole.local_addr().unwrap().port();
+        let config = |break_after| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([port; 6])
+                .protocol(Protocol::Udp)
+                .expect_reply(true)
+                .recv_timeout(50)
+                .circuit_breaker(break_after)
+                .build()
+                .unwrap()
+        };
+
+        let (events, handle) = run_with_events(config(Some(3)), CancellationToken::new());
+        let events: Vec<KnockEvent> = events.collect().await;
+        let err = handle.await.unwrap().unwrap_err();
+        assert!(
+            matches!(
+                err,
+                AppError::CircuitOpen {
+                    failed: 3,
+                    skipped: 3,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(err.exit_code(), 6);
+        let Some(KnockEvent::Finished { report }) = events.last() else {
+            panic!("no report");
+        };
+        assert_eq!(report.steps.len(), 3);
+        assert_eq!(report.circuit_open, Some(4..=6));
+        assert_eq!(report.not_started, [port; 3]);
+
+        // Turned off, every step is sent
+        let err = run(config(None)).await.unwrap_err();
+        assert!(matches!(err, AppError::Partial { ref failed, .. } if failed.len() == 6));
+
+        // A refusal is an answer: the host is up
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([closed; 4])
+            .refused_is_failure(true)
+            .build()
+            .unwrap();
+        let err = run(config).await.unwrap_err();
+        assert!(matches!(err, AppError::Partial { ref failed, .. } if failed.len() == 4));
+        drop(blackhole);
+    }
+
+    #[tokio::test]
+    async fn retry_budget_is_shared_by_every_knock() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = |exhausted| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence([closed; 4])
+                .refused_is_failure(true)
+                .attempts(3)
+                .backoff(0)
+                .max_attempts_total(5, exhausted)
+                .circuit_breaker(None)
+                .build()
+                .unwrap()
+        };
+        let report = |config| async {
+            let (events, handle) = run_with_events(config, CancellationToken::new());
+            let events: Vec<KnockEvent> = events.collect().await;
+            assert!(matches!(
+                handle.await.unwrap(),
+                Err(AppError::Partial { .. })
+            ));
+            match events.last() {
+                Some(KnockEvent::Finished { report }) => report.clone(),
+                _ => panic!("no report"),
+            }
+        };
+
+        // Once the first two knocks spent it, the others get one attempt
+        let single = report(config(BudgetExhausted::Single)).await;
+        let attempts: Vec<usize> = single.steps.iter().map(|o| o.attempts).collect();
+        assert_eq!(attempts, [3, 2, 1, 1]);
+        let spent = single.retry_budget.unwrap();
+        assert_eq!(spent.to_string(), "5/5 attempts spent");
+
+        // Or none at all
+        let skip = report(config(BudgetExhausted::Skip)).await;
+        let attempts: Vec<usize> = skip.steps.iter().map(|o| o.attempts).collect();
+        assert_eq!(attempts, [3, 2, 0, 0]);
+        assert!(skip.steps[3].errors[0]
+            .message
+            .contains("budget of 5 attempts spent"));
+        let spent = skip.retry_budget.unwrap();
+        assert_eq!(spent.to_string(), "5/5 attempts spent, 2 knock(s) skipped");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hooks_run_around_the_run() {
+        let open = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let log = std::env::temp_dir().join(format!("apk-hook-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let append = |what: &str| format!("echo \"{what}\" >> '{}'", log.display());
+        let hooks = Hooks {
+            pre: Some(append("pre $KNOCK_SEQUENCE")),
+            post: Some(append("post $KNOCK_RESULT $KNOCK_EXIT_CODE")),
+            failure: Some(append("failure $KNOCK_FAILED_PORT")),
+            ..Hooks::default()
+        };
+        let config = |ports: Vec<u16>, hooks: Hooks| {
+            KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence(ports)
+                .refused_is_failure(true)
+                .attempts(1)
+                .delay(0)
+                .hooks(hooks)
+                .build()
+                .unwrap()
+        };
+        let port = open.local_addr().unwrap().port();
+        run(config(vec![port], hooks.clone())).await.unwrap();
+        let err = run(config(vec![port, closed], hooks.clone()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Partial { .. }));
+        let logged = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(
+            logged,
+            format!(
+                "pre {port}\npost success 0\npre {port},{closed}\nfailure {closed}\npost failure 7\n"
+            )
+        );
+
+        // A pre-hook that fails stops the run before any knock
+        let failing = Hooks {
+            pre: Some("exit 1".into()),
+            ..hooks.clone()
+        };
+        let err = run(config(vec![port], failing)).await.unwrap_err();
+        assert_eq!(err.to_string(), "pre-hook failed: exited with status 1");
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), logged);
+
+        // A post-hook failing only fails a run with strict hooks
+        let post = |strict| Hooks {
+            post: Some("exit 2".into()),
+            strict,
+            ..Hooks::default()
+        };
+        run(config(vec![port], post(false))).await.unwrap();
+        let err = run(config(vec![port], post(true))).await.unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::Hook {
+                point: hooks::HookPoint::Post,
+                ..
+            }
+        ));
+        assert_eq!(err.exit_code(), 1);
+        std::fs::remove_file(&log).unwrap();
+    }
+}
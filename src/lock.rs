@@ -0,0 +1,216 @@
+//! One run at a time per target (`--lock`).
+//!
+//! Two runs knocking the same host at once interleave their packets, and
+//! the server sees neither sequence. A locked run holds an advisory lock
+//! on a file named after the host, in the runtime directory
+//! (`$XDG_RUNTIME_DIR/async_port_knocker`, or the temporary directory)
+//! unless the config names another, for as long as it knocks, with its PID written in the file for anyone
+//! waiting on it. The operating system drops the lock with the process,
+//! however it ends; a lock still held for a PID that no longer runs, e.g.
+//! by a child that inherited it, is broken by replacing the file.
+
+use crate::AppError;
+use fs4::fs_std::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+/// How often a held lock is tried again while waiting for it.
+const POLL: Duration = Duration::from_millis(100);
+
+/// A lock file left this long without its PID is taken for a holder
+/// that is still writing it, not a stale one.
+const FRESH: Duration = Duration::from_secs(1);
+
+/// A held target lock, released when dropped.
+#[derive(Debug)]
+pub struct TargetLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl TargetLock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        // Closing the file releases it too; unlock first so a waiter need
+        // not wait for the close
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+/// Where the lock files go by default.
+pub fn lock_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .unwrap_or_else(std::env::temp_dir)
+        .join("async_port_knocker")
+}
+
+/// The lock file of `host` in `dir`, its name made safe for any file
+/// system.
+pub fn lock_path(dir: &Path, host: &str) -> PathBuf {
+    let name: String = host
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    dir.join(format!("{name}.lock"))
+}
+
+/// What trying the lock once found.
+enum Attempt {
+    Taken(TargetLock),
+    /// Held by another run, with its PID when the file names one.
+    Held(Option<u32>),
+    /// The file was replaced while it was being locked.
+    Replaced,
+}
+
+/// Take the lock of `host` in `dir`, waiting up to `timeout` for its holder to let
+/// go, after telling `waiting` who that is. `Ok(None)` when `cancel` fires
+/// first. A holder still there after the wait fails the run with
+/// [`AppError::Locked`].
+pub async fn acquire(
+    dir: &Path,
+    host: &str,
+    timeout: Duration,
+    cancel: &CancellationToken,
+    waiting: impl FnOnce(Option<u32>),
+) -> Result<Option<TargetLock>, AppError> {
+    let mut waiting = Some(waiting);
+    let path = lock_path(dir, host);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| lock_error(&path, e))?;
+    }
+    let started = crate::rt::Instant::now();
+    loop {
+        let pid = match try_lock(&path).map_err(|e| lock_error(&path, e))? {
+            Attempt::Taken(lock) => return Ok(Some(lock)),
+            Attempt::Replaced => continue,
+            Attempt::Held(pid) => pid,
+        };
+        if pid.is_some_and(|pid| !running(pid)) && !fresh(&path) {
+            // Whoever holds the file now goes on with one no one else
+            // opens; the next try makes a new one
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        let waited = started.elapsed();
+        if waited >= timeout {
+            return Err(AppError::Locked {
+                host: host.to_string(),
+                pid,
+                waited: waited.as_millis() as u64,
+            });
+        }
+        if let Some(waiting) = waiting.take() {
+            waiting(pid);
+        }
+        tokio::select! {
+            _ = crate::rt::sleep(POLL.min(timeout - waited)) => {}
+            _ = cancel.cancelled() => return Ok(None),
+        }
+    }
+}
+
+fn try_lock(path: &Path) -> io::Result<Attempt> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if !file.try_lock_exclusive()? {
+        let mut text = String::new();
+        let _ = file.read_to_string(&mut text);
+        return Ok(Attempt::Held(text.trim().parse().ok()));
+    }
+    if !same_file(&file, path) {
+        return Ok(Attempt::Replaced);
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Attempt::Taken(TargetLock {
+        file,
+        path: path.to_path_buf(),
+    }))
+}
+
+/// Whether `file` is still the one at `path`, and not one removed as
+/// stale after it was opened.
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+        _ => false,
+    }
+}
+
+/// Windows does not remove a file that is open, so it cannot change.
+#[cfg(not(unix))]
+fn same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+/// Whether the lock file was written just now, maybe without its PID yet.
+fn fresh(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|at| SystemTime::now().duration_since(at).unwrap_or_default() < FRESH)
+}
+
+/// Whether a process `pid` still runs; where that cannot be told, it is
+/// taken to.
+#[cfg(target_os = "linux")]
+fn running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn running(_pid: u32) -> bool {
+    true
+}
+
+fn lock_error(path: &Path, e: io::Error) -> AppError {
+    AppError::Runtime(format!("cannot lock {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn lock_of_a_dead_process_is_broken() {
+        let dir = tempfile::tempdir().unwrap();
+        let host = "stale.example";
+        let path = lock_path(dir.path(), host);
+        // Held, but for a PID no process can have, and written a while ago
+        let mut held = File::create(&path).unwrap();
+        assert!(held.try_lock_exclusive().unwrap());
+        writeln!(held, "{}", u32::MAX).unwrap();
+        held.set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        let cancel = CancellationToken::new();
+        let lock = acquire(dir.path(), host, Duration::ZERO, &cancel, |_| {}).await;
+        let lock = lock.unwrap().unwrap();
+        let pid = fs::read_to_string(lock.path()).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+        // A live holder is not
+        let again = acquire(dir.path(), host, Duration::ZERO, &cancel, |_| {}).await;
+        assert!(matches!(again, Err(AppError::Locked { pid: Some(_), .. })));
+    }
+}
@@ -1,15 +1,41 @@
-use async_port_knocker::{cli::Cli, run};
+use async_port_knocker::cli::{
+    self, GenSequenceCli, HistoryCli, InterfacesCli, ListenCli, SelfTestCli,
+};
 use clap::Parser;
 
 #[tokio::main]
 async fn main() {
-    // Parse command-line arguments using the definition from the library.
-    let cli = Cli::parse();
-
-    // Execute the main application logic from the library.
-    // If an error occurs, print it to stderr and exit with a non-zero code.
-    if let Err(e) = run(cli).await {
+    // `listen` runs the server end, `self-test` knocks a local one and
+    // `gen-sequence` draws a new sequence, `interfaces` lists the local
+    // addresses, `history` looks through the runs of --history-db; anything else describes knocks to send. Parse the command-line arguments using the definitions from
+    // the library, then run them through it
+    let result = match std::env::args().nth(1).as_deref() {
+        Some("listen") => cli::listen(ListenCli::parse_from(std::env::args().skip(1))).await,
+        Some("self-test") => {
+            cli::self_test(SelfTestCli::parse_from(std::env::args().skip(1))).await
+        }
+        Some("gen-sequence") => {
+            cli::gen_sequence(GenSequenceCli::parse_from(std::env::args().skip(1)))
+        }
+        Some("interfaces") => cli::interfaces(InterfacesCli::parse_from(std::env::args().skip(1))),
+        Some("history") => cli::history(HistoryCli::parse_from(std::env::args().skip(1))),
+        _ => match cli::parse_args(std::env::args_os()).unwrap_or_else(|e| e.exit()) {
+            cli if cli.print_config_schema => cli::print_config_schema(),
+            cli if cli.export_knockd => cli::export_knockd(cli),
+            cli if cli.soak.is_some() || cli.soak_duration.is_some() => cli::run_soak(cli).await,
+            cli if cli.schedule.is_some() => cli::run_scheduled(cli).await,
+            cli if cli.state_file.is_some() || cli.skip_if_recent.is_some() || cli.resume => {
+                cli::run_with_state(cli).await
+            }
+            cli if cli.plan.is_some() => cli::run_plan(cli).await,
+            cli if cli.hosts_file.is_some() => cli::run_hosts(cli).await,
+            cli => cli::run(cli).await.map(|_| ()),
+        },
+    };
+    // If an error occurs, print it to stderr and exit with the code for its
+    // kind of failure
+    if let Err(e) = result {
         eprintln!("Error: {e}");
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 }
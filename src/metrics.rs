@@ -0,0 +1,308 @@
+//! Prometheus metrics for long-running knocking.
+//!
+//! [`Metrics`] is a [`KnockObserver`] that counts the outcome of every
+//! knock it hears of, and [`serve`] answers `GET /metrics` with them in
+//! the Prometheus text format over a minimal HTTP/1.1 server, until
+//! cancelled:
+//!
+//! - `knocker_knocks_total{protocol, result}`: knocks that succeeded or
+//!   failed, by protocol; their sum is the knocks sent.
+//! - `knocker_attempts_total{protocol}`: attempts, retries included.
+//! - `knocker_last_success_timestamp_seconds`: Unix time of the last
+//!   knock that got through, 0 before the first.
+//! - `knocker_knock_latency_seconds`: histogram of the latency of the
+//!   attempts that got through.
+
+use crate::observer::KnockObserver;
+use crate::{AppError, KnockOutcome, Protocol};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Upper bounds of the latency histogram's buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Longest a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters fed from knock outcomes; see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// Per protocol, in the order first seen.
+    protocols: Vec<(Protocol, ProtocolCounters)>,
+    last_success: Option<SystemTime>,
+    /// Count per bucket of [`LATENCY_BUCKETS`], not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_count: u64,
+    latency_sum: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ProtocolCounters {
+    succeeded: u64,
+    failed: u64,
+    attempts: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one knock's outcome.
+    pub fn record(&self, outcome: &KnockOutcome) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let counters = match inner
+            .protocols
+            .iter()
+            .position(|(p, _)| *p == outcome.protocol)
+        {
+            Some(i) => &mut inner.protocols[i].1,
+            None => {
+                inner.protocols.push((outcome.protocol, Default::default()));
+                &mut inner.protocols.last_mut().expect("just pushed").1
+            }
+        };
+        counters.attempts += outcome.attempts as u64;
+        match outcome.succeeded {
+            true => counters.succeeded += 1,
+            false => counters.failed += 1,
+        }
+        if outcome.succeeded {
+            inner.last_success = Some(SystemTime::now());
+        }
+        if let Some(latency) = outcome.latency {
+            let seconds = latency.as_secs_f64();
+            if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+                inner.buckets[i] += 1;
+            }
+            inner.latency_count += 1;
+            inner.latency_sum += seconds;
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        out.push_str("# HELP knocker_knocks_total Knocks sent, by protocol and result.\n");
+        out.push_str("# TYPE knocker_knocks_total counter\n");
+        for (protocol, c) in &inner.protocols {
+            for (result, count) in [("succeeded", c.succeeded), ("failed", c.failed)] {
+                let _ = writeln!(
+                    out,
+                    "knocker_knocks_total{{protocol=\"{protocol}\",result=\"{result}\"}} {count}"
+                );
+            }
+        }
+        out.push_str("# HELP knocker_attempts_total Knock attempts, retries included.\n");
+        out.push_str("# TYPE knocker_attempts_total counter\n");
+        for (protocol, c) in &inner.protocols {
+            let _ = writeln!(
+                out,
+                "knocker_attempts_total{{protocol=\"{protocol}\"}} {}",
+                c.attempts
+            );
+        }
+        out.push_str(
+            "# HELP knocker_last_success_timestamp_seconds Unix time of the last knock \
+             that got through.\n",
+        );
+        out.push_str("# TYPE knocker_last_success_timestamp_seconds gauge\n");
+        let last = inner
+            .last_success
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0.0, |d| d.as_secs_f64());
+        let _ = writeln!(out, "knocker_last_success_timestamp_seconds {last:.3}");
+        out.push_str(
+            "# HELP knocker_knock_latency_seconds Latency of the attempts that got through.\n",
+        );
+        out.push_str("# TYPE knocker_knock_latency_seconds histogram\n");
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(inner.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "knocker_knock_latency_seconds_bucket{{le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "knocker_knock_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            inner.latency_count
+        );
+        let _ = writeln!(
+            out,
+            "knocker_knock_latency_seconds_sum {}",
+            inner.latency_sum
+        );
+        let _ = writeln!(
+            out,
+            "knocker_knock_latency_seconds_count {}",
+            inner.latency_count
+        );
+        out
+    }
+}
+
+impl KnockObserver for Metrics {
+    fn on_result(&self, outcome: &KnockOutcome) {
+        self.record(outcome);
+    }
+}
+
+/// Bind `addr` and answer scrapes of `metrics` until `cancel` is
+/// cancelled, when the task ends.
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: std::sync::Arc<Metrics>,
+    cancel: CancellationToken,
+) -> Result<JoinHandle<()>, AppError> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|source| AppError::Bind { addr, source })?;
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                _ = cancel.cancelled() => return,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(_) => continue,
+                },
+            };
+            let (metrics, cancel) = (metrics.clone(), cancel.clone());
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = cancel.cancelled() => {}
+                    _ = answer(stream, &metrics) => {}
+                }
+            });
+        }
+    }))
+}
+
+/// Read one request and answer it: the metrics for `GET /metrics`, 404
+/// for any other path and 405 for any other method.
+async fn answer(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let read = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            match stream.read(&mut buf).await? {
+                0 => break,
+                n => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    if tokio::time::timeout(REQUEST_TIMEOUT, read).await.is_err() {
+        return Ok(());
+    }
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next());
+    let path = path.map(|p| p.split('?').next().unwrap_or_default());
+    let (status, content_type, body) = match (method, path) {
+        ("GET", Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics.render(),
+        ),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".into()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".into(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn outcome(protocol: Protocol, succeeded: bool, attempts: usize, ms: u64) -> KnockOutcome {
+        KnockOutcome {
+            attempts,
+            succeeded,
+            latency: succeeded.then(|| Duration::from_millis(ms)),
+            ..KnockOutcome::failed(7000, protocol, "")
+        }
+    }
+
+    #[test]
+    fn outcomes_become_counters_and_a_histogram() {
+        let metrics = Metrics::new();
+        metrics.record(&outcome(Protocol::Tcp, true, 1, 3));
+        metrics.record(&outcome(Protocol::Tcp, true, 2, 40));
+        metrics.record(&outcome(Protocol::Udp, false, 3, 0));
+        let text = metrics.render();
+        for line in [
+            "knocker_knocks_total{protocol=\"tcp\",result=\"succeeded\"} 2",
+            "knocker_knocks_total{protocol=\"tcp\",result=\"failed\"} 0",
+            "knocker_knocks_total{protocol=\"udp\",result=\"failed\"} 1",
+            "knocker_attempts_total{protocol=\"tcp\"} 3",
+            "knocker_attempts_total{protocol=\"udp\"} 3",
+            "knocker_knock_latency_seconds_bucket{le=\"0.005\"} 1",
+            "knocker_knock_latency_seconds_bucket{le=\"0.025\"} 1",
+            "knocker_knock_latency_seconds_bucket{le=\"0.05\"} 2",
+            "knocker_knock_latency_seconds_bucket{le=\"+Inf\"} 2",
+            "knocker_knock_latency_seconds_count 2",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing:\n{text}");
+        }
+        assert!(!text.contains("knocker_last_success_timestamp_seconds 0.000"));
+        assert!(Metrics::new()
+            .render()
+            .contains("knocker_last_success_timestamp_seconds 0.000"));
+    }
+
+    #[tokio::test]
+    async fn serves_scrapes_until_cancelled() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.record(&outcome(Protocol::Tcp, true, 1, 3));
+        let cancel = CancellationToken::new();
+        let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = probe.local_addr().unwrap();
+        drop(probe);
+        let server = serve(addr, metrics, cancel.clone()).await.unwrap();
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("result=\"succeeded\"} 1"), "{response}");
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! `--netns`: knocks sent from inside a Linux network namespace, as
+//! `ip netns exec NAME` would, for embedders too.
+//!
+//! A namespace is entered per thread, and a runtime's worker threads
+//! could not all be moved in and out of one safely. So the knocks run on
+//! a thread of their own, on a runtime of their own, which enters the
+//! namespace before any socket is opened and goes back to the original
+//! one when they are done. The caller's threads never leave theirs, so
+//! hooks, `--verify` connects and everything else around the knocks run
+//! where they were configured. Name resolution inside the namespace uses
+//! the host's resolver configuration; `ip netns exec` would bind-mount
+//! `/etc/netns/NAME` over it.
+
+use crate::AppError;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Where `ip netns add` leaves the named namespaces.
+pub const NETNS_DIR: &str = "/var/run/netns";
+
+/// Check a namespace name: a file name under [`NETNS_DIR`].
+pub fn check_name(name: &str) -> Result<(), String> {
+    match name {
+        "" | "." | ".." => Err(format!("'{name}' is not a network namespace name")),
+        _ if name.contains('/') || name.contains('\0') => Err(format!(
+            "'{name}' is not a network namespace name; give the name `ip netns list` shows"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Run the future `knocks` makes inside namespace `name`, on a thread of
+/// its own, and hand back its result.
+pub(crate) async fn run_in<F, Fut, T>(name: &str, knocks: F) -> Result<T, AppError>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T, AppError>>,
+    T: Send + 'static,
+{
+    let path = Path::new(NETNS_DIR).join(name);
+    let target = File::open(&path).map_err(|e| {
+        AppError::InvalidConfig(format!(
+            "no network namespace '{name}': cannot open {}: {e}",
+            path.display()
+        ))
+    })?;
+    let (done, result) = futures::channel::oneshot::channel();
+    std::thread::Builder::new()
+        .name(format!("netns-{name}"))
+        .spawn(move || {
+            let _ = done.send(knock_inside(path, &target, knocks));
+        })
+        .map_err(|e| AppError::Runtime(format!("cannot start the --netns thread: {e}")))?;
+    result.await.map_err(|_| {
+        AppError::Runtime("the --netns thread stopped before the knocks ended".into())
+    })?
+}
+
+/// On the namespace's thread: enter it, drive the knocks on a runtime
+/// started there, then go back.
+fn knock_inside<F, Fut, T>(path: PathBuf, target: &File, knocks: F) -> Result<T, AppError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let original = File::open("/proc/thread-self/ns/net").map_err(AppError::local_failure)?;
+    enter(target).map_err(|source| setns_error(source, &path))?;
+    let result = crate::rt::block_on(knocks())
+        .map_err(AppError::local_failure)
+        .and_then(|ran| ran.expect("a fresh thread is outside any runtime"));
+    enter(&original).map_err(AppError::local_failure)?;
+    result
+}
+
+/// Move this thread into the network namespace `ns` is open on.
+fn enter(ns: &File) -> io::Result<()> {
+    // SAFETY: the descriptor is open for the duration of the call
+    match unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn setns_error(source: io::Error, path: &Path) -> AppError {
+    match source.kind() {
+        io::ErrorKind::PermissionDenied => AppError::LocalFailure {
+            source,
+            hint: "entering a --netns network namespace needs CAP_SYS_ADMIN; run as root or \
+                   `setcap cap_sys_admin+ep` on the binary",
+        },
+        _ => AppError::InvalidConfig(format!(
+            "cannot enter network namespace {}: {source}",
+            path.display()
+        )),
+    }
+}
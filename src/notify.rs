@@ -0,0 +1,221 @@
+//! Desktop notifications at the end of a run.
+//!
+//! [`DesktopNotifier`] shows a [`Notification`] through the desktop's
+//! notification service with notify-rust: freedesktop notifications over
+//! D-Bus on Linux and the BSDs, the notification center on macOS, toasts
+//! on Windows. Showing one blocks, so it runs on a blocking thread.
+//! Notifications are a courtesy: on a headless system, or one without the
+//! service, the first attempt sends one notice to the run's observer and
+//! every later one is skipped, and the run goes on as if nothing was
+//! asked.
+
+use crate::events::EventSink;
+use crate::KnockObserver;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Application name notifications are shown under.
+pub const APP_NAME: &str = "async_port_knocker";
+
+/// One notification: a title line and a body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    /// Whether it reports a failure, shown with critical urgency.
+    pub failed: bool,
+}
+
+impl Notification {
+    /// The knocks to `host` all got through, and `verified`, if any, is
+    /// the port a verifying connect found open.
+    pub fn succeeded(host: &str, verified: Option<u16>) -> Self {
+        let body = match verified {
+            Some(port) => format!("All knocks got through; port {port} is open"),
+            None => "All knocks got through".into(),
+        };
+        Self {
+            summary: format!("Knock on {host} succeeded"),
+            body,
+            failed: false,
+        }
+    }
+
+    /// The knocks to `host` failed with `error`.
+    pub fn failed(host: &str, error: &str) -> Self {
+        Self {
+            summary: format!("Knock on {host} failed"),
+            body: error.to_string(),
+            failed: true,
+        }
+    }
+}
+
+/// Shows notifications, warning once if it cannot; see the
+/// [module docs](self).
+#[derive(Default)]
+pub struct DesktopNotifier {
+    /// Set once showing failed; nothing is tried after that.
+    unavailable: AtomicBool,
+    /// Whether the last notification passed to
+    /// [`on_change`](Self::on_change) was a failure.
+    last_failed: Mutex<Option<bool>>,
+    events: EventSink,
+}
+
+impl DesktopNotifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send the notice that notifications are unavailable to `observer`.
+    pub fn with_observer(mut self, observer: Arc<dyn KnockObserver + Send + Sync>) -> Self {
+        self.events = EventSink::default().with_observer(Some(observer));
+        self
+    }
+
+    /// Show `notification`, or send one notice that notifications are
+    /// unavailable.
+    pub async fn show(&self, notification: &Notification) {
+        if self.unavailable.load(Ordering::Relaxed) {
+            return;
+        }
+        let notification = notification.clone();
+        let shown = crate::rt::spawn_blocking(move || display(&notification)).await;
+        if let Err(reason) = shown.map_err(|e| e.to_string()).and_then(|shown| shown) {
+            if !self.unavailable.swap(true, Ordering::Relaxed) {
+                self.events.notice(
+                    None,
+                    format!(
+                        "desktop notifications unavailable ({reason}); continuing without them"
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Show `notification` only if it is the first or turns success into
+    /// failure or back, for repeated runs that would otherwise notify on
+    /// every one.
+    pub async fn on_change(&self, notification: &Notification) {
+        if self.changed(notification.failed) {
+            self.show(notification).await;
+        }
+    }
+
+    fn changed(&self, failed: bool) -> bool {
+        let mut last = self.last_failed.lock().unwrap_or_else(|e| e.into_inner());
+        last.replace(failed) != Some(failed)
+    }
+}
+
+/// Hand `notification` to the desktop's notification service.
+fn display(notification: &Notification) -> Result<(), String> {
+    if let Some(reason) = headless(|name| std::env::var_os(name)) {
+        return Err(reason.into());
+    }
+    desktop(notification)
+        .show()
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+/// Why there is no desktop to notify, judged from the environment read
+/// through `var`: on Linux and the BSDs a session needs a display or a
+/// session bus.
+fn headless(var: impl Fn(&str) -> Option<OsString>) -> Option<&'static str> {
+    if cfg!(target_os = "macos") || cfg!(windows) {
+        return None;
+    }
+    let set = |name| var(name).is_some_and(|v| !v.is_empty());
+    match set("DISPLAY") || set("WAYLAND_DISPLAY") || set("DBUS_SESSION_BUS_ADDRESS") {
+        true => None,
+        false => Some("no display or session bus"),
+    }
+}
+
+/// `notification` as notify-rust shows it, critical when it reports a
+/// failure where urgency is supported.
+fn desktop(notification: &Notification) -> notify_rust::Notification {
+    let mut desktop = notify_rust::Notification::new();
+    desktop
+        .appname(APP_NAME)
+        .summary(&notification.summary)
+        .body(&notification.body);
+    #[cfg(all(unix, not(target_os = "macos")))]
+    desktop.urgency(match notification.failed {
+        true => notify_rust::Urgency::Critical,
+        false => notify_rust::Urgency::Normal,
+    });
+    desktop
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_name_the_host_and_verified_port() {
+        let ok = Notification::succeeded("gw.example", Some(22));
+        assert_eq!(ok.summary, "Knock on gw.example succeeded");
+        assert_eq!(ok.body, "All knocks got through; port 22 is open");
+        assert!(!ok.failed);
+        assert_eq!(
+            Notification::succeeded("gw.example", None).body,
+            "All knocks got through"
+        );
+        let failed = Notification::failed("gw.example", "knock to port 7000 failed");
+        assert_eq!(failed.summary, "Knock on gw.example failed");
+        assert!(failed.failed);
+    }
+
+    #[test]
+    fn repeated_runs_notify_on_transitions_only() {
+        let notifier = DesktopNotifier::new();
+        let shown: Vec<bool> = [false, false, true, true, true, false, true]
+            .into_iter()
+            .map(|failed| notifier.changed(failed))
+            .collect();
+        assert_eq!(shown, [true, false, true, false, false, true, true]);
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn headless_sessions_are_detected() {
+        assert_eq!(headless(|_| None), Some("no display or session bus"));
+        let empty = |_: &str| Some(OsString::new());
+        assert!(headless(empty).is_some());
+        let wayland = |name: &str| (name == "WAYLAND_DISPLAY").then(|| "wayland-0".into());
+        assert_eq!(headless(wayland), None);
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn failures_are_shown_critical() {
+        let shown = desktop(&Notification::failed("gw.example", "-x timed out"));
+        assert_eq!(shown.appname, APP_NAME);
+        assert_eq!(shown.summary, "Knock on gw.example failed");
+        assert_eq!(shown.body, "-x timed out");
+        let urgency = notify_rust::Hint::Urgency(notify_rust::Urgency::Critical);
+        assert!(shown.hints.contains(&urgency));
+        let ok = desktop(&Notification::succeeded("gw.example", None));
+        assert!(!ok.hints.contains(&urgency));
+    }
+
+    #[tokio::test]
+    async fn an_unavailable_desktop_is_tried_once() {
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let notifier = DesktopNotifier {
+            events: EventSink::new(tx),
+            ..DesktopNotifier::default()
+        };
+        notifier.unavailable.store(true, Ordering::Relaxed);
+        // Returns without showing anything or another notice
+        notifier
+            .show(&Notification::succeeded("gw.example", None))
+            .await;
+        drop(notifier);
+        assert!(rx.try_next().unwrap().is_none());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::events::{KnockEvent, KnockTarget};
+use crate::outcome::{KnockOutcome, KnockReport, LatencyStats};
+use chrono::{DateTime, Local, SecondsFormat};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Hooks called while a run is in progress, e.g. to feed metrics, to send
+/// the run's output somewhere other than stdout, or to check in tests
+/// exactly which attempts happened.
+///
+/// Hooks run on the knock tasks themselves, in the middle of the retry
+/// loop, so they should return quickly.
+pub trait KnockObserver {
+    /// Called once per attempt, when it got through or failed.
+    fn on_attempt(&self, _info: &AttemptInfo) {}
+    /// Called once per knock with its final outcome.
+    fn on_result(&self, _outcome: &KnockOutcome) {}
+    /// Called for every event of the run, in the order they happen; the
+    /// same events [`run_with_events`](crate::run_with_events) streams.
+    fn on_event(&self, _event: &KnockEvent) {}
+}
+
+/// One attempt of a knock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttemptInfo {
+    pub target: KnockTarget,
+    /// Attempt number (1-based); 0 for a failure before the first attempt.
+    pub attempt: usize,
+    pub result: AttemptResult,
+}
+
+/// How an attempt ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttemptResult {
+    /// The knock got through; `detail` says how, e.g. `OK` or `SENT 16 bytes`.
+    Delivered { latency: Duration, detail: String },
+    /// The attempt failed; another may follow.
+    Failed { error: String },
+}
+
+/// The binary's output: attempts and everything that went well on stdout,
+/// whatever failed or was skipped on stderr, each line stamped with its
+/// time when [`timestamped`](Self::timestamped).
+#[derive(Debug, Default)]
+pub struct StdoutObserver {
+    clock: Option<Clock>,
+}
+
+impl StdoutObserver {
+    /// Prefix every line with the time it was printed, in `format`. The
+    /// clock of [`Timestamps::Relative`] starts now, and again with the
+    /// first line after each run's summary.
+    pub fn timestamped(format: Timestamps) -> Self {
+        Self {
+            clock: Some(Clock {
+                format,
+                start: Mutex::new(Some(SystemTime::now())),
+            }),
+        }
+    }
+
+    /// `text` with each of its lines stamped with the time now.
+    fn stamped(&self, text: impl fmt::Display) -> String {
+        let text = text.to_string();
+        let Some(clock) = &self.clock else {
+            return text;
+        };
+        let stamp = clock.stamp(SystemTime::now());
+        text.split_inclusive('\n')
+            .map(|line| format!("{stamp} {line}"))
+            .collect()
+    }
+}
+
+impl KnockObserver for StdoutObserver {
+    fn on_attempt(&self, info: &AttemptInfo) {
+        let AttemptInfo {
+            target, attempt, ..
+        } = info;
+        match &info.result {
+            AttemptResult::Delivered { latency, detail } => println!(
+                "{}",
+                self.stamped(format_args!(
+                    "{target} {detail} in {}ms (attempt {attempt})",
+                    latency.as_millis()
+                ))
+            ),
+            AttemptResult::Failed { error } if *attempt == 0 => {
+                eprintln!("{}", self.stamped(format_args!("{target} ERR {error}")))
+            }
+            AttemptResult::Failed { error } => eprintln!(
+                "{}",
+                self.stamped(format_args!("{target} ERR {error} (attempt {attempt})"))
+            ),
+        }
+    }
+    /// Attempts are printed by [`on_attempt`](Self::on_attempt), so their
+    /// events are skipped here.
+    fn on_event(&self, event: &KnockEvent) {
+        match event {
+            KnockEvent::Resolved { .. }
+            | KnockEvent::KnockStarted { .. }
+            | KnockEvent::AttemptFailed { .. }
+            | KnockEvent::KnockSucceeded { .. } => {}
+            KnockEvent::Plan { text } => print!("{}", self.stamped(text)),
+            KnockEvent::AddressesChanged {
+                host,
+                previous,
+                addrs,
+            } => {
+                let ips = |addrs: &[SocketAddr]| {
+                    let ips: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+                    ips.join(", ")
+                };
+                println!(
+                    "{}",
+                    self.stamped(format_args!(
+                        "{host} now resolves to {} (was {})",
+                        ips(addrs),
+                        ips(previous)
+                    ))
+                );
+            }
+            KnockEvent::AddressChosen { host, addr } => {
+                println!(
+                    "{}",
+                    self.stamped(format_args!("Knocking {host} at {}", addr.ip()))
+                );
+            }
+            KnockEvent::ReplySaved {
+                target,
+                path,
+                bytes,
+                truncated,
+            } => {
+                let cut = if *truncated { ", truncated" } else { "" };
+                println!(
+                    "{}",
+                    self.stamped(format_args!(
+                        "{target} reply saved to {} ({bytes} bytes{cut})",
+                        path.display()
+                    ))
+                );
+            }
+            KnockEvent::Hexdump {
+                target,
+                direction,
+                attempt,
+                len,
+                dump,
+            } => eprint!(
+                "{}",
+                self.stamped(format_args!(
+                    "{target} {direction} {len} bytes (attempt {attempt}):\n{dump}"
+                ))
+            ),
+            KnockEvent::KnockFailed { target, attempts } => {
+                eprintln!(
+                    "{}",
+                    self.stamped(format_args!("{target} FAILED after {attempts} attempt(s)"))
+                );
+            }
+            KnockEvent::Notice {
+                target: Some(target),
+                message,
+            } => eprintln!("{}", self.stamped(format_args!("{target} {message}"))),
+            KnockEvent::Notice {
+                target: None,
+                message,
+            } => eprintln!("{}", self.stamped(message)),
+            KnockEvent::Finished { report } => {
+                print!("{}", self.stamped(summary(report, self.clock.as_ref())));
+                if let Some(clock) = &self.clock {
+                    *clock.start.lock().unwrap() = None;
+                }
+            }
+        }
+    }
+}
+
+/// How [`StdoutObserver`] stamps its lines (`--timestamps`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Timestamps {
+    /// Milliseconds since the run started, e.g. `+412ms`
+    Relative,
+    /// Local wall-clock time, e.g. `14:03:07.412`
+    Absolute,
+    /// RFC 3339 with the local offset, e.g. `2026-10-16T14:03:07.412+02:00`
+    Rfc3339,
+}
+
+/// The time a [`StdoutObserver`] stamps, and when its run started.
+#[derive(Debug)]
+struct Clock {
+    format: Timestamps,
+    /// Set by the first line of a run, cleared by its summary.
+    start: Mutex<Option<SystemTime>>,
+}
+
+impl Clock {
+    fn stamp(&self, at: SystemTime) -> String {
+        match self.format {
+            Timestamps::Relative => {
+                let start = *self.start.lock().unwrap().get_or_insert(at);
+                let since = at.duration_since(start).unwrap_or_default();
+                format!("+{}ms", since.as_millis())
+            }
+            Timestamps::Absolute => DateTime::<Local>::from(at)
+                .format("%H:%M:%S%.3f")
+                .to_string(),
+            Timestamps::Rfc3339 => {
+                DateTime::<Local>::from(at).to_rfc3339_opts(SecondsFormat::Millis, false)
+            }
+        }
+    }
+}
+
+/// The end-of-run summary: success count, latency spread, the knocks a
+/// cancelled run did not finish and, when stamping, when the run started
+/// and ended.
+fn summary(report: &KnockReport, clock: Option<&Clock>) -> String {
+    let mut lines = Vec::new();
+    if let Some(probe) = &report.open_probe {
+        let ms = probe.elapsed.as_millis();
+        lines.push(match probe.open_at {
+            Some(addr) => format!(
+                "Already open: port {} at {addr} ({ms}ms); nothing knocked",
+                probe.port
+            ),
+            None => {
+                let probed: Vec<String> = probe.probed.iter().map(|ip| ip.to_string()).collect();
+                format!(
+                    "Open check: port {} not open on {} ({ms}ms)",
+                    probe.port,
+                    probed.join(", ")
+                )
+            }
+        });
+    }
+    let outcomes = &report.steps;
+    let succeeded = outcomes.iter().filter(|o| o.succeeded).count();
+    let sent_only = outcomes
+        .iter()
+        .filter(|o| o.succeeded && !o.acknowledged)
+        .count();
+    if sent_only > 0 {
+        lines.push(format!(
+            "Summary: {succeeded}/{} knocks succeeded ({} acknowledged, {sent_only} sent without reply)",
+            outcomes.len(),
+            succeeded - sent_only
+        ));
+    } else {
+        lines.push(format!(
+            "Summary: {succeeded}/{} knocks succeeded",
+            outcomes.len()
+        ));
+    }
+    if let Some(clock) = clock {
+        let ended = report.started_at + report.duration;
+        lines.push(format!(
+            "Run: {} to {}",
+            clock.stamp(report.started_at),
+            clock.stamp(ended)
+        ));
+    }
+    if let Some(stats) = LatencyStats::from_outcomes(outcomes) {
+        lines.push(format!(
+            "Latency: min {}ms / avg {}ms / max {}ms",
+            stats.min.as_millis(),
+            stats.avg.as_millis(),
+            stats.max.as_millis()
+        ));
+    }
+    let ports = |ports: &[u16]| {
+        let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+        ports.join(", ")
+    };
+    // Used against allowed, once some step has attempts of its own
+    if report.attempts_overridden {
+        let attempts: Vec<String> = outcomes
+            .iter()
+            .map(|o| match o.attempts_allowed {
+                Some(allowed) => format!("{} {}/{allowed}", o.port, o.attempts),
+                None => format!("{} {}/unlimited", o.port, o.attempts),
+            })
+            .collect();
+        lines.push(format!("Attempts: {}", attempts.join(", ")));
+    }
+    if !report.aborted.is_empty() {
+        lines.push(format!("Aborted: {}", ports(&report.aborted)));
+    }
+    for (pass, overrun) in report.overruns.iter().enumerate() {
+        lines.push(format!("Pass {}: {overrun}", pass + 1));
+    }
+    if !report.overruns.is_empty() {
+        lines.push(format!("Passes: {}", report.passes));
+    }
+    if report.decoys > 0 {
+        lines.push(format!("Decoys: {} sent", report.decoys));
+    }
+    if !report.groups.is_empty() {
+        let groups: Vec<String> = report.groups.iter().map(ToString::to_string).collect();
+        lines.push(format!("Groups: {}", groups.join(" -> ")));
+    }
+    let failed: Vec<u16> = outcomes
+        .iter()
+        .filter(|o| !o.succeeded)
+        .map(|o| o.port)
+        .collect();
+    if let Some(tolerance) = report.failure_tolerance.filter(|_| !failed.is_empty()) {
+        let within = match report.tolerated() {
+            true => "tolerated",
+            false => "not tolerated",
+        };
+        lines.push(format!(
+            "Failures {within}: {} ({} of {}, at most {tolerance})",
+            ports(&failed),
+            failed.len(),
+            outcomes.len()
+        ));
+    }
+    if let Some(budget) = &report.retry_budget {
+        lines.push(format!("Retry budget: {budget}"));
+    }
+    if let Some(steps) = &report.circuit_open {
+        lines.push(match steps.start() == steps.end() {
+            true => format!("Step {} skipped: circuit open", steps.start()),
+            false => format!(
+                "Steps {}-{} skipped: circuit open",
+                steps.start(),
+                steps.end()
+            ),
+        });
+    }
+    if !report.not_started.is_empty() {
+        lines.push(format!("Not started: {}", ports(&report.not_started)));
+    }
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_in_each_format() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_792_137_600);
+        let clock = |format| Clock {
+            format,
+            start: Mutex::new(Some(start)),
+        };
+        let at = start + Duration::from_millis(412);
+        assert_eq!(clock(Timestamps::Relative).stamp(at), "+412ms");
+        assert_eq!(clock(Timestamps::Relative).stamp(start), "+0ms");
+        let absolute = clock(Timestamps::Absolute).stamp(at);
+        assert!(absolute.ends_with(":00.412"), "{absolute}");
+        let rfc3339 = clock(Timestamps::Rfc3339).stamp(at);
+        let parsed = DateTime::parse_from_rfc3339(&rfc3339).unwrap();
+        assert_eq!(SystemTime::from(parsed), at, "{rfc3339}");
+
+        // A run's first line starts a cleared clock
+        let cleared = Clock {
+            format: Timestamps::Relative,
+            start: Mutex::new(None),
+        };
+        assert_eq!(cleared.stamp(at), "+0ms");
+        assert_eq!(cleared.stamp(at + Duration::from_secs(2)), "+2000ms");
+    }
+
+    #[test]
+    fn summary_tells_attempts_of_their_own() {
+        let outcome = |port, attempts, attempts_allowed| KnockOutcome {
+            succeeded: true,
+            attempts,
+            attempts_allowed,
+            ..KnockOutcome::failed(port, crate::protocol::Protocol::Tcp, "")
+        };
+        let mut report = KnockReport {
+            attempts_overridden: true,
+            ..KnockReport::of_steps(
+                "host",
+                vec![outcome(7000, 1, Some(1)), outcome(9000, 3, Some(5))],
+            )
+        };
+        let text = summary(&report, None);
+        assert!(text.contains("Attempts: 7000 1/1, 9000 3/5\n"), "{text}");
+        // An override the same as the run's attempts is still shown
+        report.steps[1] = outcome(9000, 1, Some(1));
+        assert!(summary(&report, None).contains("Attempts: 7000 1/1, 9000 1/1\n"));
+        report.steps[1] = outcome(9000, 4, None);
+        assert!(summary(&report, None).contains("9000 4/unlimited"));
+
+        // Nothing to tell when every step has the run's attempts
+        report.attempts_overridden = false;
+        assert!(!summary(&report, None).contains("Attempts:"));
+    }
+
+    #[test]
+    fn summary_tells_whether_failures_are_tolerated() {
+        let outcome = |port, succeeded| KnockOutcome {
+            succeeded,
+            ..KnockOutcome::failed(port, crate::protocol::Protocol::Tcp, "")
+        };
+        let mut report = KnockReport {
+            failure_tolerance: Some(crate::FailureTolerance::Count(1)),
+            ..KnockReport::of_steps(
+                "host",
+                vec![
+                    outcome(7000, true),
+                    outcome(8000, false),
+                    outcome(9000, true),
+                ],
+            )
+        };
+        let text = summary(&report, None);
+        assert!(
+            text.contains("Failures tolerated: 8000 (1 of 3, at most 1)\n"),
+            "{text}"
+        );
+        report.failure_tolerance = Some(crate::FailureTolerance::Percent(20.0));
+        let text = summary(&report, None);
+        assert!(
+            text.contains("Failures not tolerated: 8000 (1 of 3, at most 20%)\n"),
+            "{text}"
+        );
+        report.steps[1].succeeded = true;
+        assert!(!summary(&report, None).contains("Failures"));
+    }
+
+    #[test]
+    fn every_line_is_stamped() {
+        assert_eq!(StdoutObserver::default().stamped("a\nb\n"), "a\nb\n");
+        let stamped = StdoutObserver::timestamped(Timestamps::Relative).stamped("a\nb\n");
+        let lines: Vec<&str> = stamped.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(
+            lines[0].starts_with('+') && lines[0].ends_with("ms a"),
+            "{stamped}"
+        );
+        assert!(lines[1].ends_with("ms b"), "{stamped}");
+    }
+}
@@ -0,0 +1,724 @@
+use crate::budget::BudgetReport;
+use crate::errors::{AppError, ErrorClass};
+use crate::events::{EventSink, KnockEvent, KnockTarget};
+use crate::hexdump::{hexdump, Direction};
+use crate::ladder::LadderReport;
+use crate::observer::{AttemptInfo, AttemptResult};
+use crate::protocol::Protocol;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Result of a single knock in the sequence.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KnockOutcome {
+    pub port: u16,
+    pub protocol: Protocol,
+    /// Address the knock was sent to; `None` through a SOCKS5 proxy or when
+    /// it never got that far.
+    pub addr: Option<SocketAddr>,
+    /// Local port the knock was sent from, where the protocol picks one
+    /// per knock (UDP), to match it up with the server's logs.
+    pub source_port: Option<u16>,
+    /// Firewall mark (SO_MARK) the knock's socket carried, with
+    /// `--fwmark`.
+    pub fwmark: Option<u32>,
+    /// IP TTL (IPv6 hop limit) the knock was sent with, when the step set
+    /// one.
+    pub ttl: Option<u8>,
+    /// Bytes of payload the datagram carried, for UDP knocks.
+    pub payload_len: Option<usize>,
+    /// Number of attempts made (1-based, including the successful one).
+    pub attempts: usize,
+    /// Number of attempts the step was allowed, its own or the run's;
+    /// `None` when unbounded.
+    pub attempts_allowed: Option<usize>,
+    pub succeeded: bool,
+    /// The target answered (handshake, refusal or reply) instead of the
+    /// knock only being sent.
+    pub acknowledged: bool,
+    /// Elapsed time of the attempt that succeeded, if any.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "latency_ms", with = "crate::serde_forms::millis_opt")
+    )]
+    pub latency: Option<Duration>,
+    /// The UDP reply that acknowledged the knock, when one was awaited.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::hex_opt"))]
+    pub reply: Option<Vec<u8>>,
+    /// File the reply was saved to with `--save-replies`.
+    pub reply_file: Option<PathBuf>,
+    /// Datagrams from some other source that were ignored while waiting
+    /// for that reply.
+    pub stray_replies: usize,
+    /// Why each failed attempt failed, in order.
+    pub errors: Vec<AttemptError>,
+    /// Wall time of the whole knock, retries and backoff included.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "elapsed_ms", with = "crate::serde_forms::millis")
+    )]
+    pub elapsed: Duration,
+}
+
+impl KnockOutcome {
+    /// A knock that could not be attempted at all, e.g. because its socket
+    /// could not be opened.
+    pub fn failed(port: u16, protocol: Protocol, message: impl Into<String>) -> Self {
+        Self {
+            port,
+            protocol,
+            addr: None,
+            source_port: None,
+            fwmark: None,
+            ttl: None,
+            payload_len: None,
+            attempts_allowed: None,
+            attempts: 0,
+            succeeded: false,
+            acknowledged: false,
+            latency: None,
+            reply: None,
+            reply_file: None,
+            stray_replies: 0,
+            errors: vec![AttemptError::new(0, message)],
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// The knock failed without reaching the host: every attempt timed out
+    /// or found no route to it, as with an address that went stale.
+    pub fn unreachable(&self) -> bool {
+        let no_route = |error: &AttemptError| {
+            let message = error.message.to_lowercase();
+            error.timed_out
+                || message.contains("unreachable")
+                || message.contains("no route to host")
+        };
+        !self.succeeded && self.attempts > 0 && self.errors.iter().all(no_route)
+    }
+
+    /// What went wrong, if the knock did not get through.
+    pub fn failure(&self) -> Option<KnockFailure> {
+        (!self.succeeded).then(|| KnockFailure {
+            port: self.port,
+            protocol: self.protocol,
+            attempts: self.attempts,
+            errors: self.errors.clone(),
+        })
+    }
+
+    /// The outcome if the knock got through, otherwise the error saying why
+    /// not: [`AppError::Timeout`] when every attempt timed out,
+    /// [`AppError::KnockFailed`] with the last error otherwise.
+    pub fn into_result(self) -> Result<Self, AppError> {
+        if self.succeeded {
+            return Ok(self);
+        }
+        if !self.errors.is_empty() && self.errors.iter().all(|e| e.timed_out) {
+            return Err(AppError::Timeout {
+                port: self.port,
+                attempts: self.attempts,
+            });
+        }
+        let last_error = match self.errors.last() {
+            Some(error) => error.message.clone(),
+            None => "no attempt got through".to_string(),
+        };
+        Err(AppError::KnockFailed {
+            port: self.port,
+            protocol: self.protocol,
+            attempts: self.attempts,
+            last_error,
+        })
+    }
+}
+
+/// A knock of a run that did not get through, as listed in
+/// [`AppError::Partial`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KnockFailure {
+    pub port: u16,
+    pub protocol: Protocol,
+    pub attempts: usize,
+    /// Why each attempt failed, in order.
+    pub errors: Vec<AttemptError>,
+}
+
+impl fmt::Display for KnockFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.port, self.protocol)?;
+        match self.errors.last() {
+            Some(error) => write!(f, " ({}, {} attempt(s))", error.message, self.attempts),
+            None => write!(f, " ({} attempt(s))", self.attempts),
+        }
+    }
+}
+
+/// One failed attempt of a knock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AttemptError {
+    /// Attempt number (1-based); 0 for a failure before the first attempt.
+    pub attempt: usize,
+    pub message: String,
+    /// The attempt ran out of time rather than failing outright.
+    pub timed_out: bool,
+    /// How the OS error that failed the attempt was classified, if one did.
+    pub class: Option<ErrorClass>,
+}
+
+impl AttemptError {
+    pub fn new(attempt: usize, message: impl Into<String>) -> Self {
+        Self {
+            attempt,
+            message: message.into(),
+            timed_out: false,
+            class: None,
+        }
+    }
+
+    /// A failure caused by an OS error of class `class`.
+    pub fn classified(attempt: usize, message: impl Into<String>, class: ErrorClass) -> Self {
+        Self {
+            class: Some(class),
+            ..Self::new(attempt, message)
+        }
+    }
+
+    pub fn timeout(attempt: usize, message: impl Into<String>) -> Self {
+        Self {
+            timed_out: true,
+            ..Self::new(attempt, message)
+        }
+    }
+}
+
+/// Failed attempts collected while a knock runs, shared by reference with
+/// the attempt futures and the timeout callback. Each failure is emitted as
+/// an event and to the run's observer as it happens, and so is the attempt
+/// that gets through.
+pub(crate) struct AttemptLog {
+    events: EventSink,
+    target: KnockTarget,
+    /// TTL the knock is sent with, told along with the attempt that gets
+    /// through.
+    ttl: Option<u8>,
+    errors: Mutex<Vec<AttemptError>>,
+}
+
+impl AttemptLog {
+    pub fn new(events: &EventSink, target: KnockTarget) -> Self {
+        Self {
+            events: events.clone(),
+            target,
+            ttl: None,
+            errors: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Tell `ttl` in the detail of the attempt that gets through.
+    pub fn with_ttl(mut self, ttl: Option<u8>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn push(&self, attempt: usize, message: impl Into<String>) {
+        self.record(AttemptError::new(attempt, message));
+    }
+
+    /// Record an attempt failed by an OS error of class `class`.
+    pub fn push_classified(&self, attempt: usize, message: impl Into<String>, class: ErrorClass) {
+        self.record(AttemptError::classified(attempt, message, class));
+    }
+
+    /// Record an attempt that ran out of time.
+    pub fn timed_out(&self, attempt: usize, message: impl Into<String>) {
+        self.record(AttemptError::timeout(attempt, message));
+    }
+
+    fn record(&self, error: AttemptError) {
+        let attempt = error.attempt;
+        self.events.attempt(AttemptInfo {
+            target: self.target.clone(),
+            attempt,
+            result: AttemptResult::Failed {
+                error: error.message.clone(),
+            },
+        });
+        self.errors.lock().unwrap().push(error);
+    }
+
+    pub fn succeeded(&self, attempt: usize, latency: Duration, detail: impl Into<String>) {
+        let mut detail = detail.into();
+        if let Some(ttl) = self.ttl {
+            detail.push_str(&format!(" (ttl {ttl})"));
+        }
+        self.events.attempt(AttemptInfo {
+            target: self.target.clone(),
+            attempt,
+            result: AttemptResult::Delivered { latency, detail },
+        });
+    }
+
+    /// Dump `bytes` when `limit` is set (`--hexdump`).
+    pub fn hexdump(
+        &self,
+        limit: Option<usize>,
+        direction: Direction,
+        attempt: usize,
+        bytes: &[u8],
+    ) {
+        if let Some(limit) = limit {
+            self.events.emit(KnockEvent::Hexdump {
+                target: self.target.clone(),
+                direction,
+                attempt,
+                len: bytes.len(),
+                dump: hexdump(bytes, limit),
+            });
+        }
+    }
+
+    pub fn notice(&self, message: impl Into<String>) {
+        self.events.notice(Some(&self.target), message);
+    }
+
+    pub fn into_errors(self) -> Vec<AttemptError> {
+        self.errors.into_inner().unwrap()
+    }
+}
+
+/// Everything a run did, returned by [`crate::run`]. With the `serde`
+/// feature it serializes as the control socket's `status` has it, started
+/// at in Unix seconds and durations in milliseconds.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KnockReport {
+    pub host: String,
+    /// The one address of the host every knock went to, chosen once after
+    /// resolution (`-4`/`-6` applied) by sending the first knock to each
+    /// address in turn until one got through, and the address verifying
+    /// connects go to; `None` with `--all-ips` and several addresses, or
+    /// through a proxy or jump host.
+    pub address: Option<IpAddr>,
+    /// Wall-clock time the run started.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_forms::unix_secs"))]
+    pub started_at: SystemTime,
+    /// One outcome per knock sent, in sequence order.
+    pub steps: Vec<KnockOutcome>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "duration_ms", with = "crate::serde_forms::millis")
+    )]
+    pub duration: Duration,
+    /// The run was cancelled, by a signal or its cancellation token.
+    pub interrupted: bool,
+    /// Ports of the knocks cut off halfway when the run was aborted, or
+    /// when they did not finish in time after it was cancelled.
+    pub aborted: Vec<u16>,
+    /// Ports of the knocks never sent, because the run was cancelled or
+    /// failed fast first.
+    pub not_started: Vec<u16>,
+    /// Passes of the sequence the run started, 1 unless it ran past the
+    /// window (`steps` are those of the last).
+    pub passes: usize,
+    /// Passes of the sequence given up for running past the window, in
+    /// order.
+    pub overruns: Vec<WindowOverrun>,
+    /// How each group of the last pass did, in order, when the sequence
+    /// has groups; empty otherwise.
+    pub groups: Vec<GroupReport>,
+    /// Decoy knocks of the last pass sent (`--decoys`), which are not in
+    /// `steps`.
+    pub decoys: usize,
+    /// Some step of the sequence has attempts of its own (`PORT*N`), so
+    /// the summary tells each step's attempts against those it was
+    /// allowed.
+    pub attempts_overridden: bool,
+    /// Steps of the last pass, numbered from 1, skipped because the ones
+    /// before could not reach the host and the circuit breaker opened.
+    pub circuit_open: Option<RangeInclusive<usize>>,
+    /// How the run used its retry budget (`--max-attempts-total`).
+    pub retry_budget: Option<BudgetReport>,
+    /// Failed knocks the run gets away with (`--max-failures`); within it,
+    /// [`into_result`](Self::into_result) is `Ok` with the failures still
+    /// in `steps`.
+    pub failure_tolerance: Option<FailureTolerance>,
+    /// Which rung of the knock ladder verified (`--verify`, `--ladder`).
+    pub ladder: Option<LadderReport>,
+    /// The check for a port already open before any knock
+    /// (`--skip-if-open`); when it found one open, nothing was knocked.
+    pub open_probe: Option<OpenProbe>,
+}
+
+/// How the `--skip-if-open` connect before the knocks went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OpenProbe {
+    pub port: u16,
+    /// The address that accepted the connect; `None` when none did and
+    /// the knocks went ahead.
+    pub open_at: Option<SocketAddr>,
+    /// The addresses tried: only the family the resolve strategy prefers,
+    /// unless every address is knocked.
+    pub probed: Vec<IpAddr>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "elapsed_ms", with = "crate::serde_forms::millis")
+    )]
+    pub elapsed: Duration,
+}
+
+/// A group of knocks sent together (`(7000,8000),9000`), or a step of a
+/// grouped sequence on its own.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GroupReport {
+    pub ports: Vec<u16>,
+    /// Knocks of the group sent, one per address with all addresses.
+    pub sent: usize,
+    pub succeeded: usize,
+}
+
+impl fmt::Display for GroupReport {
+    /// The ports, in parentheses for more than one, and the knocks that
+    /// succeeded, e.g. `(7000, 8000) 2/2`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ports: Vec<String> = self.ports.iter().map(u16::to_string).collect();
+        match ports.len() {
+            1 => write!(f, "{}", ports[0])?,
+            _ => write!(f, "({})", ports.join(", "))?,
+        }
+        write!(f, " {}/{}", self.succeeded, self.sent)
+    }
+}
+
+/// How many failures are tolerated before a run, or a fleet of them,
+/// counts as failed (`--max-failures`, `--max-failure-percent`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum FailureTolerance {
+    /// At most this many.
+    Count(usize),
+    /// At most this percentage of the total, 0-100.
+    Percent(f64),
+}
+
+impl FailureTolerance {
+    /// Whether `failed` out of `total` is within the tolerance.
+    pub fn allows(self, failed: usize, total: usize) -> bool {
+        match self {
+            FailureTolerance::Count(max) => failed <= max,
+            FailureTolerance::Percent(max) => failed as f64 * 100.0 <= max * total as f64,
+        }
+    }
+}
+
+impl fmt::Display for FailureTolerance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureTolerance::Count(max) => write!(f, "{max}"),
+            FailureTolerance::Percent(max) => write!(f, "{max}%"),
+        }
+    }
+}
+
+/// A pass of the sequence given up, and started over, because it ran past
+/// the server's window (`--window`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WindowOverrun {
+    /// Knocks of the pass sent before it was given up.
+    pub sent: usize,
+    /// Time from the pass's first packet to the knock that would have
+    /// gone out next.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "elapsed_ms", with = "crate::serde_forms::millis")
+    )]
+    pub elapsed: Duration,
+    /// Port of that knock, which was not sent.
+    pub next_port: u16,
+}
+
+impl fmt::Display for WindowOverrun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ran past the window {}ms after its first packet, with {} knock(s) sent and port {} \
+             next",
+            self.elapsed.as_millis(),
+            self.sent,
+            self.next_port
+        )
+    }
+}
+
+impl KnockReport {
+    /// A run of one pass that knocked `steps` on `host` and has nothing
+    /// else to report; tests set whatever else they look at.
+    #[cfg(test)]
+    pub(crate) fn of_steps(host: &str, steps: Vec<KnockOutcome>) -> Self {
+        KnockReport {
+            host: host.into(),
+            address: None,
+            started_at: std::time::UNIX_EPOCH,
+            steps,
+            duration: Duration::ZERO,
+            interrupted: false,
+            aborted: Vec::new(),
+            not_started: Vec::new(),
+            passes: 1,
+            overruns: Vec::new(),
+            groups: Vec::new(),
+            decoys: 0,
+            attempts_overridden: false,
+            circuit_open: None,
+            retry_budget: None,
+            failure_tolerance: None,
+            ladder: None,
+            open_probe: None,
+        }
+    }
+
+    /// Whether every knock got through and the run was not interrupted.
+    pub fn succeeded(&self) -> bool {
+        !self.interrupted && self.steps.iter().all(|o| o.succeeded)
+    }
+
+    /// The knocks that did not get through, in sequence order.
+    pub fn failures(&self) -> Vec<KnockFailure> {
+        self.steps
+            .iter()
+            .filter_map(KnockOutcome::failure)
+            .collect()
+    }
+
+    /// Whether the knocks that failed, if any, are within the
+    /// run's `failure_tolerance`.
+    pub fn tolerated(&self) -> bool {
+        let failed = self.steps.iter().filter(|o| !o.succeeded).count();
+        self.failure_tolerance
+            .is_some_and(|tolerance| tolerance.allows(failed, self.steps.len()))
+    }
+
+    /// The report if every knock sent got through, or the failures are
+    /// [`tolerated`](Self::tolerated), otherwise [`AppError::Partial`]
+    /// listing the ones that did not. An interrupted run is left to the
+    /// caller.
+    pub fn into_result(self) -> Result<Self, AppError> {
+        let failed = self.failures();
+        if failed.is_empty() || self.interrupted || self.tolerated() {
+            return Ok(self);
+        }
+        Err(AppError::Partial {
+            succeeded: self.steps.len() - failed.len(),
+            failed,
+        })
+    }
+}
+
+/// Min/avg/max latency over the successful knocks of a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    /// Compute stats from the outcomes that recorded a latency.
+    /// Returns `None` when no knock succeeded.
+    pub fn from_outcomes(outcomes: &[KnockOutcome]) -> Option<Self> {
+        let samples: Vec<Duration> = outcomes.iter().filter_map(|o| o.latency).collect();
+        let min = *samples.iter().min()?;
+        let max = *samples.iter().max()?;
+        let avg = samples.iter().sum::<Duration>() / samples.len() as u32;
+        Some(Self { min, avg, max })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(latency: Option<u64>) -> KnockOutcome {
+        KnockOutcome {
+            port: 7000,
+            protocol: Protocol::Tcp,
+            addr: None,
+            source_port: None,
+            fwmark: None,
+            ttl: None,
+            payload_len: None,
+            attempts_allowed: None,
+            attempts: 1,
+            succeeded: latency.is_some(),
+            acknowledged: latency.is_some(),
+            latency: latency.map(Duration::from_millis),
+            reply: None,
+            reply_file: None,
+            stray_replies: 0,
+            errors: Vec::new(),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn stats_over_successful_knocks() {
+        let outcomes = [outcome(Some(10)), outcome(None), outcome(Some(30))];
+        let stats = LatencyStats::from_outcomes(&outcomes).unwrap();
+        assert_eq!(stats.min, Duration::from_millis(10));
+        assert_eq!(stats.avg, Duration::from_millis(20));
+        assert_eq!(stats.max, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn no_stats_without_successes() {
+        assert!(LatencyStats::from_outcomes(&[outcome(None)]).is_none());
+    }
+
+    #[test]
+    fn unreachable_only_without_an_answer() {
+        let failed = |errors| KnockOutcome {
+            errors,
+            ..outcome(None)
+        };
+        let timed_out = AttemptError::timeout(1, "connect timed out after 500ms");
+        let no_route = AttemptError::new(2, "connect: No route to host (os error 113)");
+        assert!(failed(vec![timed_out.clone(), no_route]).unreachable());
+        let mismatch = AttemptError::new(2, "reply did not match the pattern");
+        assert!(!failed(vec![timed_out, mismatch]).unreachable());
+        assert!(!outcome(Some(10)).unreachable());
+        // Never attempted: there was no address to blame
+        assert!(!KnockOutcome::failed(7000, Protocol::Tcp, "no socket").unreachable());
+    }
+
+    #[test]
+    fn attempt_log_keeps_order() {
+        let log = AttemptLog::new(
+            &EventSink::default(),
+            KnockTarget::new("h", 7000, Protocol::Tcp),
+        );
+        log.push(1, "connection reset");
+        log.timed_out(2, "timed out");
+        assert_eq!(
+            log.into_errors(),
+            [
+                AttemptError::new(1, "connection reset"),
+                AttemptError::timeout(2, "timed out")
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_outcomes_become_errors() {
+        assert!(outcome(Some(10)).into_result().is_ok());
+
+        let mut timed_out = outcome(None);
+        timed_out.attempts = 2;
+        timed_out.errors = vec![
+            AttemptError::timeout(1, "timed out"),
+            AttemptError::timeout(2, "timed out"),
+        ];
+        assert!(matches!(
+            timed_out.clone().into_result(),
+            Err(AppError::Timeout {
+                port: 7000,
+                attempts: 2
+            })
+        ));
+
+        timed_out.errors[1] = AttemptError::new(2, "connection reset");
+        match timed_out.into_result() {
+            Err(AppError::KnockFailed { last_error, .. }) => {
+                assert_eq!(last_error, "connection reset")
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn report_success_needs_every_knock() {
+        let mut report = KnockReport {
+            duration: Duration::from_millis(30),
+            ..KnockReport::of_steps("h", vec![outcome(Some(10)), outcome(Some(20))])
+        };
+        assert!(report.succeeded());
+        report
+            .steps
+            .push(KnockOutcome::failed(7000, Protocol::Udp, "bind failed"));
+        assert!(!report.succeeded());
+    }
+
+    #[test]
+    fn tolerated_failures_still_succeed_the_run() {
+        let mut report = KnockReport {
+            duration: Duration::from_millis(30),
+            failure_tolerance: Some(FailureTolerance::Count(2)),
+            ..KnockReport::of_steps(
+                "h",
+                vec![
+                    outcome(Some(10)),
+                    outcome(None),
+                    outcome(Some(20)),
+                    outcome(None),
+                ],
+            )
+        };
+        assert!(report.tolerated() && !report.succeeded());
+        assert!(report.clone().into_result().is_ok());
+
+        report.failure_tolerance = Some(FailureTolerance::Count(1));
+        assert!(matches!(
+            report.clone().into_result(),
+            Err(AppError::Partial { succeeded: 2, .. })
+        ));
+        report.failure_tolerance = Some(FailureTolerance::Percent(50.0));
+        assert!(report.clone().into_result().is_ok());
+        report.failure_tolerance = Some(FailureTolerance::Percent(49.9));
+        assert!(report.into_result().is_err());
+        assert_eq!(FailureTolerance::Percent(12.5).to_string(), "12.5%");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn reports_round_trip_through_json() {
+        use std::time::UNIX_EPOCH;
+
+        let mut answered = outcome(Some(10));
+        answered.reply = Some(b"ok".to_vec());
+        let mut failed = outcome(None);
+        failed.errors = vec![AttemptError::timeout(1, "timed out")];
+        let report = KnockReport {
+            address: Some("203.0.113.7".parse().unwrap()),
+            started_at: UNIX_EPOCH + Duration::from_millis(1_792_137_600_250),
+            duration: Duration::from_millis(30),
+            not_started: vec![9000],
+            failure_tolerance: Some(FailureTolerance::Count(1)),
+            ..KnockReport::of_steps("h", vec![answered, failed])
+        };
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains(r#""started_at":1792137600.25"#), "{json}");
+        assert!(json.contains(r#""latency_ms":10"#), "{json}");
+        assert!(json.contains(r#""reply":"6f6b""#), "{json}");
+        assert!(json.contains(r#""duration_ms":30"#), "{json}");
+
+        let parsed: KnockReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        assert_eq!(parsed.started_at, report.started_at);
+        assert_eq!(parsed.steps[0].reply, report.steps[0].reply);
+        assert_eq!(parsed.steps[1].errors, report.steps[1].errors);
+        assert!(parsed.tolerated());
+    }
+}
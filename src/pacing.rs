@@ -0,0 +1,124 @@
+//! Spacing the knocks of a run apart on the wire.
+//!
+//! `--delay` is the gap between one knock going out and the next, however
+//! many knocks are in flight: each knock takes its turn at the pacer, which
+//! holds the next one back until the gap after the previous has passed.
+//!
+//! Tokio's timer ticks in whole milliseconds and may wake a sleeper most of
+//! a tick late. A gap or slot with a sub-millisecond part (`--delay 250us`)
+//! is therefore slept through up to its last [`SPIN`] only, then spun out
+//! yielding to the runtime. On an idle Linux machine that hands out turns
+//! a microsecond or two from what was asked, rarely more than 100µs, at
+//! the cost of a core busy for that last stretch; setting up each knock's
+//! socket comes on top, which `--warmup` cannot avoid. Whole milliseconds
+//! keep the plain timer, a millisecond or so late at most.
+
+use crate::rt::{self, Instant};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+/// Last stretch of a fine wait spun out rather than slept.
+const SPIN: Duration = Duration::from_millis(2);
+
+/// A wait with a sub-millisecond part, timed finer than the timer ticks.
+pub(crate) fn is_fine(wait: Duration) -> bool {
+    !wait.subsec_nanos().is_multiple_of(1_000_000)
+}
+
+/// Sleep until `deadline`, spinning through the last [`SPIN`] when
+/// `fine`.
+pub(crate) async fn sleep_until(deadline: Instant, fine: bool) {
+    if !fine {
+        return rt::sleep_until(deadline).await;
+    }
+    if let Some(coarse) = deadline.checked_sub(SPIN) {
+        rt::sleep_until(coarse).await;
+    }
+    while Instant::now() < deadline {
+        rt::yield_now().await;
+    }
+}
+
+/// When the last knock of a run went out, handed from knock to knock in
+/// the order they asked for it.
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    last: Mutex<Instant>,
+}
+
+impl Pacer {
+    /// Start counting from now, as if a knock had just gone out.
+    pub(crate) fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `gap` after the previous knock went out, or after the
+    /// pacer was made for the first, and mark the caller's knock as going
+    /// out now. Returns `false`, marking nothing, when `cancel` fires first.
+    pub(crate) async fn wait(&self, gap: Duration, cancel: &CancellationToken) -> bool {
+        // Knocks queue for their turn; later ones wait behind this one's gap
+        let mut last = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return false,
+            last = self.last.lock() => last,
+        };
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return false,
+            _ = sleep_until(*last + gap, is_fine(gap)) => {}
+        }
+        *last = Instant::now();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn knocks_waiting_together_go_out_a_gap_apart() {
+        let pacer = Pacer::new();
+        let cancel = CancellationToken::new();
+        let start = Instant::now();
+        let gap = Duration::from_millis(100);
+        let sent = |_| async {
+            assert!(pacer.wait(gap, &cancel).await);
+            start.elapsed()
+        };
+        let times = futures::future::join_all((0..3).map(sent)).await;
+        assert_eq!(times, [100, 200, 300].map(Duration::from_millis).to_vec());
+
+        // Cancelled in the queue, a knock marks nothing
+        cancel.cancel();
+        assert!(!pacer.wait(Duration::ZERO, &cancel).await);
+    }
+
+    /// Real clock, so a loaded machine can fail it: run with `--ignored`.
+    #[tokio::test]
+    #[ignore = "timing-sensitive"]
+    async fn sub_millisecond_gaps_are_kept_finely() {
+        let pacer = Pacer::new();
+        let cancel = CancellationToken::new();
+        for gap in [250, 750, 1250].map(Duration::from_micros) {
+            let mut last = Instant::now();
+            let mut errors = Vec::new();
+            for _ in 0..50 {
+                assert!(pacer.wait(gap, &cancel).await);
+                let now = Instant::now();
+                errors.push((now - last).abs_diff(gap));
+                last = now;
+            }
+            errors.sort();
+            let (median, worst) = (errors[errors.len() / 2], errors[errors.len() - 1]);
+            assert!(
+                median < Duration::from_micros(100),
+                "{gap:?}: median {median:?}"
+            );
+            assert!(worst < Duration::from_millis(1), "{gap:?}: worst {worst:?}");
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// IANA protocol numbers used in the IP headers we build.
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+/// Raw sockets of this protocol send whole IP packets, header included.
+pub const IPPROTO_RAW: u8 = 255;
+
+/// TCP header flag bits.
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+pub const TCP_ECE: u8 = 0x40;
+pub const TCP_CWR: u8 = 0x80;
+
+const FLAG_NAMES: [(u8, &str); 8] = [
+    (TCP_FIN, "fin"),
+    (TCP_SYN, "syn"),
+    (TCP_RST, "rst"),
+    (TCP_PSH, "psh"),
+    (TCP_ACK, "ack"),
+    (TCP_URG, "urg"),
+    (TCP_ECE, "ece"),
+    (TCP_CWR, "cwr"),
+];
+
+/// A combination of TCP header flags for crafted knock segments.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TcpFlags(pub u8);
+
+impl TcpFlags {
+    pub const SYN: TcpFlags = TcpFlags(TCP_SYN);
+
+    /// Parse `syn,ack`-style lists (`,`, `+` or `|` separated) and the
+    /// knockd-style presets `xmas` (FIN|PSH|URG) and `null` (no flags).
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "xmas" => return Ok(TcpFlags(TCP_FIN | TCP_PSH | TCP_URG)),
+            "null" | "none" => return Ok(TcpFlags(0)),
+            _ => {}
+        }
+        let mut bits = 0;
+        for name in s.split([',', '+', '|']).map(str::trim) {
+            let (bit, _) = FLAG_NAMES
+                .iter()
+                .find(|(_, n)| n.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("unknown TCP flag '{name}'"))?;
+            bits |= bit;
+        }
+        Ok(TcpFlags(bits))
+    }
+}
+
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(TcpFlags, TcpFlags::parse);
+
+impl std::fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("NULL");
+        }
+        let names: Vec<String> = FLAG_NAMES
+            .iter()
+            .filter(|(bit, _)| self.0 & bit != 0)
+            .map(|(_, n)| n.to_ascii_uppercase())
+            .collect();
+        f.write_str(&names.join("|"))
+    }
+}
+
+/// Fold a 32-bit one's complement accumulator over `data` (RFC 1071).
+fn sum_words(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for pair in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([pair[0], pair[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// Finish a one's complement sum into the 16-bit Internet checksum.
+fn finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum of a byte slice.
+pub fn checksum(data: &[u8]) -> u16 {
+    finish(sum_words(0, data))
+}
+
+/// Partial sum of the TCP/UDP pseudo-header for either IP family.
+fn pseudo_header_sum(src: IpAddr, dst: IpAddr, proto: u8, len: usize) -> u32 {
+    let sum = match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => sum_words(sum_words(0, &s.octets()), &d.octets()),
+        (IpAddr::V6(s), IpAddr::V6(d)) => sum_words(sum_words(0, &s.octets()), &d.octets()),
+        _ => 0,
+    };
+    sum + u32::from(proto) + len as u32
+}
+
+/// Wrap a transport segment into an IPv4 or IPv6 packet.
+/// Returns `None` when source and destination families differ.
+pub fn ip_packet(src: IpAddr, dst: IpAddr, proto: u8, transport: &[u8]) -> Option<Vec<u8>> {
+    match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let total = (20 + transport.len()) as u16;
+            let mut pkt = Vec::with_capacity(total as usize);
+            pkt.extend_from_slice(&[0x45, 0]);
+            pkt.extend_from_slice(&total.to_be_bytes());
+            pkt.extend_from_slice(&[0, 0, 0x40, 0]); // id 0, don't fragment
+            pkt.extend_from_slice(&[64, proto, 0, 0]);
+            pkt.extend_from_slice(&s.octets());
+            pkt.extend_from_slice(&d.octets());
+            let csum = checksum(&pkt);
+            pkt[10..12].copy_from_slice(&csum.to_be_bytes());
+            pkt.extend_from_slice(transport);
+            Some(pkt)
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            let mut pkt = Vec::with_capacity(40 + transport.len());
+            pkt.extend_from_slice(&[0x60, 0, 0, 0]);
+            pkt.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+            pkt.extend_from_slice(&[proto, 64]);
+            pkt.extend_from_slice(&s.octets());
+            pkt.extend_from_slice(&d.octets());
+            pkt.extend_from_slice(transport);
+            Some(pkt)
+        }
+        _ => None,
+    }
+}
+
+/// Build a UDP header + payload with a valid checksum.
+pub fn udp_segment(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let len = 8 + payload.len();
+    let mut seg = Vec::with_capacity(len);
+    seg.extend_from_slice(&src.port().to_be_bytes());
+    seg.extend_from_slice(&dst.port().to_be_bytes());
+    seg.extend_from_slice(&(len as u16).to_be_bytes());
+    seg.extend_from_slice(&[0, 0]);
+    seg.extend_from_slice(payload);
+    let sum = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_UDP, len);
+    let csum = match finish(sum_words(sum, &seg)) {
+        0 => 0xffff, // zero means "no checksum" in UDP
+        c => c,
+    };
+    seg[6..8].copy_from_slice(&csum.to_be_bytes());
+    seg
+}
+
+/// Build a 20-byte TCP header (no options) + payload with a valid checksum.
+pub fn tcp_segment(
+    src: SocketAddr,
+    dst: SocketAddr,
+    flags: u8,
+    seq: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let len = 20 + payload.len();
+    let mut seg = Vec::with_capacity(len);
+    seg.extend_from_slice(&src.port().to_be_bytes());
+    seg.extend_from_slice(&dst.port().to_be_bytes());
+    seg.extend_from_slice(&seq.to_be_bytes());
+    seg.extend_from_slice(&0u32.to_be_bytes()); // ack
+    seg.extend_from_slice(&[5 << 4, flags]);
+    seg.extend_from_slice(&64240u16.to_be_bytes()); // window
+    seg.extend_from_slice(&[0, 0, 0, 0]); // checksum, urgent pointer
+    seg.extend_from_slice(payload);
+    let sum = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_TCP, len);
+    let csum = finish(sum_words(sum, &seg));
+    seg[16..18].copy_from_slice(&csum.to_be_bytes());
+    seg
+}
+
+/// Build an ICMP (v4) or ICMPv6 echo request with a `size`-byte payload.
+///
+/// The ICMPv6 checksum covers a pseudo-header the kernel fills in, so it
+/// is left zero for v6 and computed here for v4.
+pub fn icmp_echo_request(v6: bool, ident: u16, seq: u16, size: usize) -> Vec<u8> {
+    let kind = if v6 { 128 } else { 8 };
+    let mut msg = Vec::with_capacity(8 + size);
+    msg.extend_from_slice(&[kind, 0, 0, 0]);
+    msg.extend_from_slice(&ident.to_be_bytes());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend((0..size).map(|i| i as u8));
+    if !v6 {
+        let csum = checksum(&msg);
+        msg[2..4].copy_from_slice(&csum.to_be_bytes());
+    }
+    msg
+}
+
+/// Whether `buf` holds an echo reply for `seq`. Raw IPv4 sockets deliver
+/// the IP header too, so it is skipped when present.
+pub fn is_echo_reply(buf: &[u8], v6: bool, seq: u16) -> bool {
+    let msg = match buf.first() {
+        Some(b) if !v6 && b >> 4 == 4 => buf.get(usize::from(b & 0x0f) * 4..).unwrap_or(&[]),
+        _ => buf,
+    };
+    let reply = if v6 { 129 } else { 0 };
+    msg.len() >= 8 && msg[0] == reply && msg[6..8] == seq.to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc1071_checksum() {
+        // Example from RFC 1071 section 3
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data), !0xddf2);
+    }
+
+    #[test]
+    fn udp_ipv4_packet_verifies() {
+        let src: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let seg = udp_segment(src, dst, b"knock");
+        let pkt = ip_packet(src.ip(), dst.ip(), IPPROTO_UDP, &seg).unwrap();
+
+        assert_eq!(pkt.len(), 20 + 8 + 5);
+        assert_eq!(checksum(&pkt[..20]), 0, "IPv4 header checksum");
+        let pseudo = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_UDP, seg.len());
+        assert_eq!(finish(sum_words(pseudo, &seg)), 0, "UDP checksum");
+        assert_eq!(&seg[2..4], &7000u16.to_be_bytes());
+    }
+
+    #[test]
+    fn tcp_syn_header_bytes() {
+        let src: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let dst: SocketAddr = "192.0.2.2:7000".parse().unwrap();
+        let seg = tcp_segment(src, dst, TCP_SYN, 0x01020304, &[]);
+
+        assert_eq!(seg.len(), 20);
+        assert_eq!(&seg[0..2], &40000u16.to_be_bytes());
+        assert_eq!(&seg[2..4], &7000u16.to_be_bytes());
+        assert_eq!(&seg[4..8], &[1, 2, 3, 4], "sequence number");
+        assert_eq!(&seg[8..12], &[0, 0, 0, 0], "ack number");
+        assert_eq!(seg[12], 0x50, "data offset of 5 words");
+        assert_eq!(seg[13], TCP_SYN);
+        let pseudo = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_TCP, seg.len());
+        assert_eq!(finish(sum_words(pseudo, &seg)), 0, "TCP checksum");
+    }
+
+    #[test]
+    fn parse_flag_lists_and_presets() {
+        assert_eq!(TcpFlags::parse("fin").unwrap(), TcpFlags(TCP_FIN));
+        assert_eq!(
+            TcpFlags::parse("syn,ack").unwrap(),
+            TcpFlags(TCP_SYN | TCP_ACK)
+        );
+        assert_eq!(
+            TcpFlags::parse("SYN+ACK").unwrap(),
+            TcpFlags(TCP_SYN | TCP_ACK)
+        );
+        assert_eq!(
+            TcpFlags::parse("xmas").unwrap(),
+            TcpFlags(TCP_FIN | TCP_PSH | TCP_URG)
+        );
+        assert_eq!(TcpFlags::parse("null").unwrap(), TcpFlags(0));
+        assert!(TcpFlags::parse("syn,bogus").is_err());
+        assert!(TcpFlags::parse("").is_err());
+        assert_eq!(
+            TcpFlags(TCP_FIN | TCP_PSH | TCP_URG).to_string(),
+            "FIN|PSH|URG"
+        );
+    }
+
+    #[test]
+    fn crafted_flags_land_in_header() {
+        let src: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let dst: SocketAddr = "192.0.2.2:7000".parse().unwrap();
+        for spec in ["fin", "ack", "syn,ack", "xmas", "null"] {
+            let flags = TcpFlags::parse(spec).unwrap();
+            let seg = tcp_segment(src, dst, flags.0, 1, &[]);
+            assert_eq!(seg[13], flags.0, "flags byte for {spec}");
+        }
+    }
+
+    #[test]
+    fn tcp_ipv6_checksum_verifies() {
+        let src: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:7000".parse().unwrap();
+        let seg = tcp_segment(src, dst, TCP_SYN, 7, &[]);
+        let pseudo = pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_TCP, seg.len());
+        assert_eq!(finish(sum_words(pseudo, &seg)), 0);
+    }
+
+    #[test]
+    fn icmp_echo_request_layout() {
+        let req = icmp_echo_request(false, 0x1234, 7, 100);
+        assert_eq!(req.len(), 108);
+        assert_eq!(req[0], 8, "echo request type");
+        assert_eq!(&req[4..6], &[0x12, 0x34]);
+        assert_eq!(&req[6..8], &[0, 7]);
+        assert_eq!(checksum(&req), 0, "ICMP checksum");
+
+        let req6 = icmp_echo_request(true, 1, 1, 0);
+        assert_eq!(req6.len(), 8);
+        assert_eq!(req6[0], 128);
+    }
+
+    #[test]
+    fn echo_reply_detection() {
+        let mut reply = icmp_echo_request(false, 1, 3, 4);
+        reply[0] = 0;
+        assert!(is_echo_reply(&reply, false, 3));
+        assert!(!is_echo_reply(&reply, false, 4));
+
+        // Same reply behind a 20-byte IPv4 header, as raw sockets deliver it
+        let mut framed = vec![0x45; 1];
+        framed.resize(20, 0);
+        framed.extend_from_slice(&reply);
+        assert!(is_echo_reply(&framed, false, 3));
+
+        let mut reply6 = icmp_echo_request(true, 1, 3, 0);
+        reply6[0] = 129;
+        assert!(is_echo_reply(&reply6, true, 3));
+    }
+}
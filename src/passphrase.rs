@@ -0,0 +1,244 @@
+//! Port sequences derived from a shared passphrase.
+//!
+//! Both ends agree on a passphrase instead of a port list, and each works
+//! the ports out for a host name with [`derive_ports`]:
+//!
+//! 1. `PRK = HMAC-SHA256(SALT, passphrase)`, the HKDF-Extract step of
+//!    RFC 5869 with [`SALT`] as the salt.
+//! 2. `OKM = HKDF-Expand(PRK, info, 8160)`, the longest output HKDF-SHA256
+//!    allows, where `info` is the host as [`derivation_host`] writes it:
+//!    lowercase ASCII (punycode) without a trailing dot, or the canonical
+//!    text of an IP address.
+//! 3. Read OKM as big-endian 32-bit words; word `w` gives the port
+//!    `base + w mod range`. A port already in the sequence is skipped, and
+//!    the next word tried, until there are `knocks` ports.
+//!
+//! The same passphrase gives unrelated sequences for different hosts. For
+//! example, `correct horse battery staple` gives 53690, 37596, 44253,
+//! 59742 for four knocks at `knock.example` in ports 10000-59999.
+//!
+//! The source ports of the knocks, when derived too, come out of the same
+//! steps with [`SOURCE_SALT`] as the salt ([`derive_source_ports`]): 45009,
+//! 53309, 59259, 47881 for the example above in ports 32768-60999.
+
+use crate::secret::Secret;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{self, IsTerminal};
+use std::net::IpAddr;
+
+/// HKDF salt of the derivation, naming it and its version.
+pub const SALT: &[u8] = b"async_port_knocker passphrase ports v1";
+
+/// HKDF salt of the source port derivation.
+pub const SOURCE_SALT: &[u8] = b"async_port_knocker passphrase source ports v1";
+
+/// Output length of SHA-256, and so of one HKDF-Expand block.
+const HASH_LEN: usize = 32;
+
+/// HKDF-SHA256 (RFC 5869) of `ikm`, `len` bytes long; `len` is at most
+/// 255 blocks of 32 bytes.
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    hkdf_expand(&hkdf_extract(salt, ikm), info, len)
+}
+
+/// The HKDF-Extract step: the pseudorandom key of `ikm` under `salt`.
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; HASH_LEN] {
+    hmac_sha256(salt, &[ikm])
+}
+
+/// The HKDF-Expand step: `len` bytes of output keyed by `prk`, at most
+/// 255 blocks of 32 bytes.
+pub fn hkdf_expand(prk: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    assert!(
+        len <= 255 * HASH_LEN,
+        "HKDF-SHA256 output is at most 8160 bytes"
+    );
+    let mut okm = Vec::with_capacity(len);
+    let mut block = Vec::new();
+    for counter in 1..=255u8 {
+        if okm.len() >= len {
+            break;
+        }
+        block = hmac_sha256(prk, &[&block, info, &[counter]]).to_vec();
+        okm.extend_from_slice(&block);
+    }
+    okm.truncate(len);
+    okm
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// The host as it goes into the derivation; see the [module docs](self).
+pub fn derivation_host(host: &str) -> Result<String, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+    let host = host.strip_suffix('.').unwrap_or(host);
+    Ok(crate::scope::ascii_host(host)?.to_ascii_lowercase())
+}
+
+/// `knocks` distinct ports in `base..base + range` for `host`, derived
+/// from `passphrase` as the [module docs](self) describe.
+pub fn derive_ports(
+    passphrase: &[u8],
+    host: &str,
+    knocks: usize,
+    base: u16,
+    range: u16,
+) -> Result<Vec<u16>, String> {
+    derive(SALT, passphrase, host, knocks, base, range)
+}
+
+/// `knocks` distinct local ports in `base..base + range` to send the
+/// knocks of [`derive_ports`] from, the same way under [`SOURCE_SALT`].
+pub fn derive_source_ports(
+    passphrase: &[u8],
+    host: &str,
+    knocks: usize,
+    base: u16,
+    range: u16,
+) -> Result<Vec<u16>, String> {
+    derive(SOURCE_SALT, passphrase, host, knocks, base, range)
+}
+
+fn derive(
+    salt: &[u8],
+    passphrase: &[u8],
+    host: &str,
+    knocks: usize,
+    base: u16,
+    range: u16,
+) -> Result<Vec<u16>, String> {
+    if range == 0 || u32::from(base) + u32::from(range) > 65536 {
+        return Err(format!("ports {base}+{range} are empty or run past 65535"));
+    }
+    if knocks > usize::from(range) {
+        return Err(format!("{knocks} knocks do not fit in {range} ports"));
+    }
+    if passphrase.is_empty() {
+        return Err("passphrase is empty".into());
+    }
+    let info = derivation_host(host)?;
+    let okm = hkdf_sha256(salt, passphrase, info.as_bytes(), 255 * HASH_LEN);
+    let mut ports = Vec::with_capacity(knocks);
+    for word in okm.chunks_exact(4) {
+        if ports.len() == knocks {
+            break;
+        }
+        let word = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        let port = base + (word % u32::from(range)) as u16;
+        if !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+    if ports.len() < knocks {
+        return Err(format!(
+            "only {} distinct ports derived for {knocks} knocks; widen the port range",
+            ports.len()
+        ));
+    }
+    Ok(ports)
+}
+
+/// Ask for the passphrase on the terminal without echoing it.
+pub async fn prompt(host: &str) -> Result<Secret, String> {
+    if !io::stdin().is_terminal() {
+        return Err(
+            "stdin is not a terminal; give the passphrase with file:, env: or keyring:".into(),
+        );
+    }
+    let question = format!("Passphrase for {host}: ");
+    let answer = crate::rt::spawn_blocking(move || rpassword::prompt_password(question))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("cannot read the passphrase: {e}"))?;
+    Ok(Secret::new(answer.into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc5869_test_case_1() {
+        let okm = hkdf_sha256(
+            &hex::decode("000102030405060708090a0b0c").unwrap(),
+            &[0x0b; 22],
+            &hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap(),
+            42,
+        );
+        assert_eq!(
+            hex::encode(okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+             34007208d5b887185865"
+        );
+    }
+
+    #[test]
+    fn rfc5869_test_case_3() {
+        // Empty salt and info
+        let okm = hkdf_sha256(&[], &[0x0b; 22], &[], 42);
+        assert_eq!(
+            hex::encode(okm),
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d\
+             9d201395faa4b61a96c8"
+        );
+    }
+
+    /// Vectors for server implementations to check theirs against.
+    #[test]
+    fn derivation_vectors() {
+        // Host names compare as lowercase ASCII without a trailing dot
+        for host in ["knock.example", "KNOCK.example."] {
+            assert_eq!(
+                derive_ports(b"correct horse battery staple", host, 4, 10000, 50000).unwrap(),
+                VECTOR_KNOCK_EXAMPLE
+            );
+        }
+        assert_eq!(
+            derive_ports(b"correct horse battery staple", "192.0.2.7", 3, 20000, 1000).unwrap(),
+            VECTOR_IP
+        );
+        assert_eq!(
+            derivation_host("bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(derivation_host("[2001:DB8::1]").unwrap(), "2001:db8::1");
+    }
+
+    #[test]
+    fn source_port_vectors() {
+        let sources = derive_source_ports(
+            b"correct horse battery staple",
+            "knock.example",
+            4,
+            32768,
+            28232,
+        )
+        .unwrap();
+        assert_eq!(sources, VECTOR_SOURCES);
+        assert!(sources.iter().all(|p| (32768..=60999).contains(p)));
+    }
+
+    const VECTOR_SOURCES: [u16; 4] = [45009, 53309, 59259, 47881];
+    const VECTOR_KNOCK_EXAMPLE: [u16; 4] = [53690, 37596, 44253, 59742];
+    const VECTOR_IP: [u16; 3] = [20758, 20259, 20375];
+
+    #[test]
+    fn every_port_of_a_small_range_is_reached() {
+        let mut ports = derive_ports(b"secret", "knock.example", 8, 7000, 8).unwrap();
+        ports.sort();
+        assert_eq!(ports, (7000..7008).collect::<Vec<u16>>());
+        assert!(derive_ports(b"secret", "knock.example", 9, 7000, 8).is_err());
+        assert!(derive_ports(b"secret", "knock.example", 1, 65000, 1000).is_err());
+        assert!(derive_ports(b"", "knock.example", 1, 7000, 8).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+
+/// Expected shape of a reply from the knock daemon.
+///
+/// Written as `hex:PREFIX` to match the leading bytes exactly, or as a
+/// glob over the raw reply bytes where `*` matches any run of bytes and
+/// `?` a single byte (`ACK*`, `OK ??`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplyPattern {
+    Prefix(Vec<u8>),
+    Glob(Vec<u8>),
+}
+
+impl ReplyPattern {
+    /// Parse a pattern from its command-line form.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.strip_prefix("hex:") {
+            Some(h) => {
+                let bytes = hex::decode(h).map_err(|e| format!("invalid hex pattern: {e}"))?;
+                if bytes.is_empty() {
+                    return Err("hex pattern must not be empty".into());
+                }
+                Ok(ReplyPattern::Prefix(bytes))
+            }
+            None if s.is_empty() => Err("reply pattern must not be empty".into()),
+            None => Ok(ReplyPattern::Glob(s.as_bytes().to_vec())),
+        }
+    }
+
+    /// Whether `reply` satisfies the pattern.
+    pub fn matches(&self, reply: &[u8]) -> bool {
+        match self {
+            ReplyPattern::Prefix(prefix) => reply.starts_with(prefix),
+            ReplyPattern::Glob(glob) => glob_match(glob, reply),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(ReplyPattern, ReplyPattern::parse);
+
+impl fmt::Display for ReplyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyPattern::Prefix(prefix) => write!(f, "hex:{}", hex::encode(prefix)),
+            ReplyPattern::Glob(glob) => write!(f, "{}", String::from_utf8_lossy(glob)),
+        }
+    }
+}
+
+/// Iterative wildcard match that backtracks only to the last `*`.
+fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    let (mut g, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some(b'*') => {
+                star = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` swallow one more byte and try again
+                Some((sg, st)) => {
+                    star = Some((sg, st + 1));
+                    g = sg + 1;
+                    t = st + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_prefix() {
+        let p = ReplyPattern::parse("hex:cafe").unwrap();
+        assert!(p.matches(&[0xca, 0xfe, 0x01]));
+        assert!(!p.matches(&[0xca]));
+        assert!(!p.matches(&[0x00, 0xca, 0xfe]));
+        assert_eq!(p.to_string(), "hex:cafe");
+        assert!(ReplyPattern::parse("hex:xyz").is_err());
+        assert!(ReplyPattern::parse("hex:").is_err());
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        let p = ReplyPattern::parse("ACK*").unwrap();
+        assert!(p.matches(b"ACK"));
+        assert!(p.matches(b"ACK 7000"));
+        assert!(!p.matches(b"NAK"));
+
+        let p = ReplyPattern::parse("OK ??").unwrap();
+        assert!(p.matches(b"OK 42"));
+        assert!(!p.matches(b"OK 4"));
+
+        let p = ReplyPattern::parse("*knock*done").unwrap();
+        assert!(p.matches(b"xx knock yy done"));
+        assert!(!p.matches(b"knock done!"));
+        assert!(ReplyPattern::parse("").is_err());
+    }
+}
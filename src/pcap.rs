@@ -0,0 +1,139 @@
+use crate::events::EventSink;
+use crate::packet::{self, IPPROTO_TCP, IPPROTO_UDP, TCP_SYN};
+use pcap_file::pcap::{PcapHeader, PcapPacket};
+use pcap_file::{DataLink, PcapError};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type FileWriter = pcap_file::pcap::PcapWriter<BufWriter<File>>;
+
+/// Records the traffic the knocker itself produces into a pcap file.
+///
+/// Packets are synthesized from the addresses and bytes already known to
+/// the tool, so no capture privileges are needed.
+pub struct PcapWriter {
+    /// Taken by [`finish`](Self::finish); packets after it are dropped.
+    out: Mutex<Option<FileWriter>>,
+    events: EventSink,
+}
+
+impl PcapWriter {
+    /// Create (truncate) the file at `path` and write the pcap global header.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        // LINKTYPE_RAW: records start directly with an IPv4/IPv6 header
+        let header = PcapHeader {
+            datalink: DataLink::RAW,
+            ..PcapHeader::default()
+        };
+        let out = FileWriter::with_header(BufWriter::new(File::create(path)?), header)
+            .map_err(into_io)?;
+        Ok(Self {
+            out: Mutex::new(Some(out)),
+            events: EventSink::default(),
+        })
+    }
+
+    /// Report write errors as notices of the run instead of dropping them.
+    pub(crate) fn with_events(mut self, events: EventSink) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Record a UDP datagram travelling from `src` to `dst` at time `ts`.
+    pub fn record_udp(&self, ts: SystemTime, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        let seg = packet::udp_segment(src, dst, payload);
+        self.record(ts, packet::ip_packet(src.ip(), dst.ip(), IPPROTO_UDP, &seg));
+    }
+
+    /// Record the SYN of a TCP connect from `src` to `dst` issued at `ts`.
+    pub fn record_tcp_syn(&self, ts: SystemTime, src: SocketAddr, dst: SocketAddr) {
+        self.record_tcp(ts, src, dst, TCP_SYN);
+    }
+
+    /// Record a bare TCP segment with the given `flags`.
+    pub fn record_tcp(&self, ts: SystemTime, src: SocketAddr, dst: SocketAddr, flags: u8) {
+        let seg = packet::tcp_segment(src, dst, flags, 0, &[]);
+        self.record(ts, packet::ip_packet(src.ip(), dst.ip(), IPPROTO_TCP, &seg));
+    }
+
+    fn record(&self, ts: SystemTime, pkt: Option<Vec<u8>>) {
+        let Some(pkt) = pkt else { return };
+        let Some(out) = &mut *self.out.lock().unwrap() else {
+            return;
+        };
+        let since = ts.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let written = out.write_packet(&PcapPacket::new(since, pkt.len() as u32, &pkt));
+        if let Err(e) = written.map_err(into_io) {
+            self.events.notice(None, format!("pcap write ERR {e}"));
+        }
+    }
+
+    /// Flush buffered records to disk. Called at the end of a run,
+    /// including when it was interrupted.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.out.lock().unwrap().take() {
+            Some(out) => out
+                .into_writer()
+                .into_inner()
+                .map(drop)
+                .map_err(|e| e.into_error()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The I/O error behind `e`, whose own message only says reading failed.
+fn into_io(e: PcapError) -> io::Error {
+    match e {
+        PcapError::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn captures_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("knock.pcap");
+        let src: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let pcap = PcapWriter::create(&path).unwrap();
+        let ts = UNIX_EPOCH + Duration::from_micros(1_500_000);
+        pcap.record_udp(ts, src, dst, b"knock");
+        pcap.record_tcp_syn(ts + Duration::from_millis(2), src, dst);
+        pcap.finish().unwrap();
+        // Nothing is written once the capture is finished
+        pcap.record_tcp_syn(ts, src, dst);
+
+        let mut reader = pcap_file::pcap::PcapReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.header().datalink, DataLink::RAW);
+        let mut packets = Vec::new();
+        while let Some(pkt) = reader.next_packet() {
+            let pkt = pkt.unwrap();
+            packets.push((pkt.timestamp, pkt.data.into_owned()));
+        }
+        let udp = packet::udp_segment(src, dst, b"knock");
+        let syn = packet::tcp_segment(src, dst, TCP_SYN, 0, &[]);
+        assert_eq!(
+            packets,
+            [
+                (
+                    Duration::from_micros(1_500_000),
+                    packet::ip_packet(src.ip(), dst.ip(), IPPROTO_UDP, &udp).unwrap()
+                ),
+                (
+                    Duration::from_micros(1_502_000),
+                    packet::ip_packet(src.ip(), dst.ip(), IPPROTO_TCP, &syn).unwrap()
+                ),
+            ]
+        );
+    }
+}
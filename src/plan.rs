@@ -0,0 +1,903 @@
+use crate::protocol::Protocol;
+use bytes::Bytes;
+use rand::seq::IndexedRandom;
+use rand::Rng;
+use std::fmt;
+use std::ops::{Deref, RangeInclusive};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Lowest port a decoy knock goes to.
+const DECOY_MIN_PORT: u16 = 1024;
+
+/// One entry of the knock sequence.
+///
+/// A bare port (`7000`) uses the run-wide settings; an annotated entry
+/// (`8080:http:/knock/abc`, `443:tls:sni.example`) selects a specific
+/// knock type for that step, `PORT/PROTO?key=value&...` overrides the
+/// protocol, timing or payload of that step alone, `PORT<SOURCE`
+/// sends the knock from that local port, `PORT~TTL` with that IP TTL and
+/// `PORT*N` gives it N attempts.
+///
+/// In a [`KnockPlan`], steps in parentheses, `(7000,8000),9000`, form a
+/// group sent together; the step after a group waits for all of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnockStep {
+    pub port: u16,
+    /// Local port the knock is sent from, for servers that check it too;
+    /// one in use fails the step rather than another being picked.
+    pub source_port: Option<u16>,
+    /// IP TTL (IPv6 hop limit) of the knock's packets instead of the
+    /// system's, for servers that read part of the secret from it.
+    pub ttl: Option<u8>,
+    pub kind: Option<StepKind>,
+    /// Protocol of this step instead of the run's.
+    pub protocol: Option<Protocol>,
+    /// Payload of this step instead of the run's: the datagram for UDP,
+    /// the bytes written on the connection for TCP.
+    pub payload: Option<Bytes>,
+    /// Per-attempt timeout of this step instead of the run's.
+    pub timeout: Option<Duration>,
+    /// Wait this long before the step, instead of the run's delay and jitter.
+    pub pre_delay: Option<Duration>,
+    /// Number of attempts of this step instead of the run's.
+    pub attempts: Option<usize>,
+    /// Sent along with the step before it, in the same group.
+    pub with_previous: bool,
+    /// A decoy knock mixed into the sequence (`--decoys`), not part of it.
+    pub decoy: bool,
+}
+
+/// The whole knock sequence, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnockPlan(pub Vec<KnockStep>);
+
+/// Per-step knock types that go beyond a bare connect or datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepKind {
+    /// Minimal HTTP/1.1 GET of `path`; any response counts as delivered.
+    Http { path: String },
+    /// TLS ClientHello with an optional per-step SNI override.
+    Tls { sni: Option<String> },
+    /// UDP datagram holding a QUIC v1 Initial packet (needs the `quic`
+    /// feature); nothing is waited for after it.
+    Quic,
+    /// UDP datagram built by the WebAssembly module at `path`, told `arg`
+    /// (needs the `wasm-plugins` feature; see `plugin`).
+    Plugin { path: PathBuf, arg: Option<String> },
+}
+
+impl KnockStep {
+    /// A step on `port` with the run-wide settings.
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            ..Self::default()
+        }
+    }
+
+    /// Parse a single sequence entry:
+    /// `PORT[<SOURCE][~TTL][*ATTEMPTS][/PROTO][?OPTION=VALUE&...][:KIND[:ARG]]`,
+    /// where the options are `timeout`, `delay` (milliseconds), `attempts`
+    /// (the same as `*ATTEMPTS`, which only one of may give) and `payload`
+    /// (hex); the deprecated `retries=N` means `attempts=N+1`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (head, rest) = match s.split_once(':') {
+            Some((head, rest)) => (head, Some(rest)),
+            None => (s, None),
+        };
+        let (head, options) = match head.split_once('?') {
+            Some((head, options)) => (head, Some(options)),
+            None => (head, None),
+        };
+        let (port, protocol) = match head.split_once('/') {
+            Some((port, protocol)) => (port, Some(protocol.parse::<Protocol>()?)),
+            None => (head, None),
+        };
+        let (port, attempts) = match port.split_once('*') {
+            Some((port, attempts)) => match attempts.trim().parse::<usize>() {
+                Ok(0) | Err(_) => {
+                    return Err(format!(
+                        "'*{attempts}' is not a positive number of attempts"
+                    ))
+                }
+                Ok(attempts) => (port, Some(attempts)),
+            },
+            None => (port, None),
+        };
+        let (port, ttl) = match port.split_once('~') {
+            Some((port, ttl)) => match ttl.trim().parse::<u8>() {
+                Ok(0) | Err(_) => return Err(format!("'{ttl}' is not a valid TTL (1-255)")),
+                Ok(ttl) => (port, Some(ttl)),
+            },
+            None => (port, None),
+        };
+        let (port, source_port) = match port.split_once('<') {
+            Some((port, source)) => match source.trim().parse::<u16>() {
+                Ok(0) | Err(_) => return Err(format!("'{source}' is not a valid source port")),
+                Ok(source) => (port, Some(source)),
+            },
+            None => (port, None),
+        };
+        let port = port
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| format!("'{port}' is not a valid port"))?;
+        let kind = match rest {
+            None => None,
+            Some(rest) => Some(StepKind::parse(rest)?),
+        };
+        if kind.is_some() && protocol.is_some() {
+            return Err(format!(
+                "'{s}' gives both a protocol and a knock type; use one"
+            ));
+        }
+        let mut step = Self {
+            port,
+            source_port,
+            ttl,
+            kind,
+            protocol,
+            attempts,
+            ..Self::default()
+        };
+        for option in options.into_iter().flat_map(|o| o.split('&')) {
+            if attempts.is_some()
+                && (option.starts_with("attempts=") || option.starts_with("retries="))
+            {
+                return Err(format!("'{s}' gives its attempts twice"));
+            }
+            step.set_option(option)?;
+        }
+        Ok(step)
+    }
+
+    fn set_option(&mut self, option: &str) -> Result<(), String> {
+        let (key, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("step option '{option}' is not KEY=VALUE"))?;
+        let millis = |value: &str| {
+            value
+                .parse::<u64>()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("{key} '{value}' is not a number of milliseconds"))
+        };
+        match key {
+            "timeout" => match millis(value)? {
+                Duration::ZERO => return Err("step timeout must not be 0".into()),
+                timeout => self.timeout = Some(timeout),
+            },
+            "delay" => self.pre_delay = Some(millis(value)?),
+            "attempts" => match value.parse::<usize>() {
+                Ok(0) | Err(_) => {
+                    return Err(format!("attempts '{value}' is not a positive number"))
+                }
+                Ok(attempts) => self.attempts = Some(attempts),
+            },
+            // Deprecated: retries after the first attempt
+            "retries" => match value.parse::<usize>() {
+                Ok(retries) => self.attempts = Some(retries.saturating_add(1)),
+                Err(_) => return Err(format!("retries '{value}' is not a number")),
+            },
+            "payload" => {
+                let payload =
+                    hex::decode(value).map_err(|e| format!("invalid hex payload: {e}"))?;
+                self.payload = Some(payload.into());
+            }
+            other => return Err(format!("unknown step option '{other}'")),
+        }
+        Ok(())
+    }
+}
+
+impl KnockPlan {
+    /// One step per port, all with the run-wide settings.
+    pub fn from_ports(ports: &[u16]) -> Self {
+        Self(ports.iter().copied().map(KnockStep::new).collect())
+    }
+
+    /// Parse a comma-separated sequence of [`KnockStep::parse`] entries,
+    /// with groups in parentheses: `(7000,8000),9000`. A `)` ending an
+    /// entry inside a group closes it, so an HTTP path ending in `)` is
+    /// written outside one.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut steps = Vec::new();
+        let mut group = false;
+        for entry in s.split(',') {
+            let trimmed = entry.trim();
+            let (entry, opens) = match trimmed.strip_prefix('(') {
+                Some(_) if group => {
+                    return Err(format!("'{s}' opens a group inside another"));
+                }
+                Some(rest) => (rest, true),
+                None => (trimmed, false),
+            };
+            let (entry, closes) = match entry.strip_suffix(')') {
+                Some(rest) if opens || group => (rest, true),
+                _ => (entry, false),
+            };
+            let mut step = KnockStep::parse(entry)?;
+            step.with_previous = group;
+            group = (group || opens) && !closes;
+            steps.push(step);
+        }
+        if group {
+            return Err(format!("'{s}' has a group that is never closed"));
+        }
+        Ok(Self(steps))
+    }
+
+    /// [`parse`](Self::parse) a sequence from outside the program: a
+    /// `knock://` URI, a DNS TXT record or ssh_config. A `plugin` step
+    /// loads a local file, so only `--sequence`, `--plan` and `--script`
+    /// may give one.
+    pub fn parse_untrusted(s: &str) -> Result<Self, String> {
+        let plan = Self::parse(s)?;
+        if let Some(step) = plan
+            .iter()
+            .find(|step| matches!(step.kind, Some(StepKind::Plugin { .. })))
+        {
+            return Err(format!(
+                "'{}' is a plugin step, which only --sequence, --plan or --script may give",
+                step.port
+            ));
+        }
+        Ok(plan)
+    }
+
+    /// Whether any steps are grouped.
+    pub fn has_groups(&self) -> bool {
+        self.iter().any(|s| s.with_previous)
+    }
+
+    /// The groups of the plan in order, a step outside any group being a
+    /// group of its own.
+    pub fn groups(&self) -> impl Iterator<Item = &[KnockStep]> {
+        self.0.chunk_by(|_, next| next.with_previous)
+    }
+
+    /// The plan with `count` decoy knocks put in at random before or
+    /// between its groups, and after the last one too when `after`. Each
+    /// goes to a different random port from 1024 up that is neither in the
+    /// plan nor `excluded`, over the protocol of the step it goes before
+    /// (the last step's, at the end); the run's timing and payload apply
+    /// to it as to any other step.
+    pub fn with_decoys(
+        self,
+        count: usize,
+        excluded: &[RangeInclusive<u16>],
+        after: bool,
+        rng: &mut impl Rng,
+    ) -> Result<Self, String> {
+        if count == 0 || self.0.is_empty() {
+            return Ok(self);
+        }
+        let free: Vec<u16> = (DECOY_MIN_PORT..=u16::MAX)
+            .filter(|port| !self.iter().any(|s| s.port == *port))
+            .filter(|port| !excluded.iter().any(|range| range.contains(port)))
+            .collect();
+        if free.len() < count {
+            return Err(format!(
+                "only {} ports are left for {count} decoy knocks",
+                free.len()
+            ));
+        }
+        let ports: Vec<u16> = free.choose_multiple(rng, count).copied().collect();
+        // A decoy never splits a group
+        let mut slots: Vec<usize> = (0..self.0.len())
+            .filter(|&i| !self.0[i].with_previous)
+            .collect();
+        if after {
+            slots.push(self.0.len());
+        }
+        let mut at: Vec<usize> = (0..count)
+            .map(|_| slots[rng.random_range(0..slots.len())])
+            .collect();
+        at.sort_unstable();
+
+        let decoy = |port, next: &KnockStep| KnockStep {
+            protocol: next.protocol.filter(|&p| p != Protocol::Icmp),
+            decoy: true,
+            ..KnockStep::new(port)
+        };
+        let last = self.0[self.0.len() - 1].clone();
+        let mut decoys = ports.into_iter().zip(at).peekable();
+        let mut steps = Vec::with_capacity(self.0.len() + count);
+        for (index, step) in self.0.into_iter().enumerate() {
+            while let Some((port, _)) = decoys.next_if(|&(_, at)| at == index) {
+                steps.push(decoy(port, &step));
+            }
+            steps.push(step);
+        }
+        steps.extend(decoys.map(|(port, _)| decoy(port, &last)));
+        Ok(Self(steps))
+    }
+
+    /// The plan with the TTLs of `walk` given to its steps in order, the
+    /// `n`th step (from 0) getting `base + n * step`; steps with a TTL of
+    /// their own keep it but still count. A TTL the walk takes below 1 or
+    /// above 255 is an error.
+    pub fn with_ttl_walk(self, walk: TtlWalk) -> Result<Self, String> {
+        self.0
+            .into_iter()
+            .enumerate()
+            .map(|(index, step)| {
+                if step.ttl.is_some() {
+                    return Ok(step);
+                }
+                let ttl = walk.ttl(index).ok_or_else(|| {
+                    format!(
+                        "--ttl-walk {walk} takes knock {} past the TTLs 1-255",
+                        index + 1
+                    )
+                })?;
+                Ok(KnockStep {
+                    ttl: Some(ttl),
+                    ..step
+                })
+            })
+            .collect()
+    }
+
+    /// Expand every plain step over [`Protocol::Both`], its own or the
+    /// run's `protocol`, into a TCP step and a UDP step right after it.
+    /// Both keep the step's payload, timeout and attempts; the UDP one
+    /// follows the TCP one without a delay.
+    /// A step in a group stays in it, along with its UDP twin.
+    pub fn expand_both(self, protocol: Protocol) -> Self {
+        let grouped: Vec<bool> = (0..self.0.len())
+            .map(|i| self.0[i].with_previous || self.0.get(i + 1).is_some_and(|s| s.with_previous))
+            .collect();
+        let mut steps = Vec::with_capacity(self.0.len());
+        for (step, grouped) in self.0.into_iter().zip(grouped) {
+            let both = step.kind.is_none() && step.protocol.unwrap_or(protocol) == Protocol::Both;
+            if !both {
+                steps.push(step);
+                continue;
+            }
+            let udp = KnockStep {
+                protocol: Some(Protocol::Udp),
+                pre_delay: Some(Duration::ZERO),
+                with_previous: grouped,
+                ..step.clone()
+            };
+            steps.push(KnockStep {
+                protocol: Some(Protocol::Tcp),
+                ..step
+            });
+            steps.push(udp);
+        }
+        Self(steps)
+    }
+}
+
+/// TTLs that change by a fixed step from one knock to the next
+/// (`--ttl-walk BASE,STEP`), e.g. `64,-4` for 64, 60, 56 and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlWalk {
+    pub base: u8,
+    pub step: i16,
+}
+
+impl TtlWalk {
+    /// Parse `BASE,STEP`, the step signed.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (base, step) = s
+            .split_once(',')
+            .ok_or_else(|| format!("'{s}' is not BASE,STEP"))?;
+        let base = match base.trim().parse::<u8>() {
+            Ok(0) | Err(_) => return Err(format!("'{base}' is not a valid TTL (1-255)")),
+            Ok(base) => base,
+        };
+        let step = step
+            .trim()
+            .parse::<i16>()
+            .map_err(|_| format!("'{step}' is not a TTL step"))?;
+        Ok(Self { base, step })
+    }
+
+    /// TTL of knock `index` (from 0), `None` outside 1-255.
+    pub fn ttl(&self, index: usize) -> Option<u8> {
+        let index = i64::try_from(index).ok()?;
+        let ttl = i64::from(self.base) + index.checked_mul(i64::from(self.step))?;
+        u8::try_from(ttl).ok().filter(|&ttl| ttl > 0)
+    }
+}
+
+impl fmt::Display for TtlWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.base, self.step)
+    }
+}
+
+impl Deref for KnockPlan {
+    type Target = [KnockStep];
+
+    fn deref(&self) -> &[KnockStep] {
+        &self.0
+    }
+}
+
+impl FromIterator<KnockStep> for KnockPlan {
+    fn from_iter<I: IntoIterator<Item = KnockStep>>(steps: I) -> Self {
+        Self(steps.into_iter().collect())
+    }
+}
+
+impl IntoIterator for KnockPlan {
+    type Item = KnockStep;
+    type IntoIter = std::vec::IntoIter<KnockStep>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl StepKind {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+        match kind.to_ascii_lowercase().as_str() {
+            "http" => {
+                let path = arg.unwrap_or("/");
+                validate_http_path(path)?;
+                Ok(StepKind::Http {
+                    path: path.to_string(),
+                })
+            }
+            "tls" => {
+                if let Some(sni) = arg {
+                    crate::tls::validate_sni(sni)?;
+                }
+                Ok(StepKind::Tls {
+                    sni: arg.map(str::to_string),
+                })
+            }
+            "quic" => match arg {
+                None => Ok(StepKind::Quic),
+                Some(arg) => Err(format!(
+                    "the quic knock type takes no argument, got '{arg}'"
+                )),
+            },
+            "plugin" => {
+                let (path, arg) = match arg.map(|a| a.split_once(':')) {
+                    Some(Some((path, arg))) => (path, Some(arg.to_string())),
+                    Some(None) => (arg.unwrap_or_default(), None),
+                    None => ("", None),
+                };
+                if path.is_empty() {
+                    return Err("the plugin knock type needs the module's path, e.g. \
+                         7000:plugin:variant.wasm"
+                        .into());
+                }
+                Ok(StepKind::Plugin {
+                    path: path.into(),
+                    arg,
+                })
+            }
+            other => Err(format!("unknown knock type '{other}'")),
+        }
+    }
+}
+
+/// The entry as it would be written in a sequence.
+impl fmt::Display for KnockStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.port)?;
+        if let Some(source) = self.source_port {
+            write!(f, "<{source}")?;
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, "~{ttl}")?;
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, "/{protocol}")?;
+        }
+        let options = [
+            self.timeout.map(|t| format!("timeout={}", t.as_millis())),
+            self.pre_delay.map(|d| format!("delay={}", d.as_millis())),
+            self.attempts.map(|a| format!("attempts={a}")),
+            self.payload
+                .as_ref()
+                .map(|p| format!("payload={}", hex::encode(p))),
+        ];
+        let options: Vec<String> = options.into_iter().flatten().collect();
+        if !options.is_empty() {
+            write!(f, "?{}", options.join("&"))?;
+        }
+        match &self.kind {
+            None => Ok(()),
+            Some(StepKind::Http { path }) => write!(f, ":http:{path}"),
+            Some(StepKind::Tls { sni: None }) => write!(f, ":tls"),
+            Some(StepKind::Tls { sni: Some(sni) }) => write!(f, ":tls:{sni}"),
+            Some(StepKind::Quic) => write!(f, ":quic"),
+            Some(StepKind::Plugin { path, arg }) => {
+                write!(f, ":plugin:{}", path.display())?;
+                match arg {
+                    Some(arg) => write!(f, ":{arg}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// The sequence as it would be written on the command line, steps
+/// separated by commas and groups in parentheses.
+impl fmt::Display for KnockPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups: Vec<String> = self
+            .groups()
+            .map(|group| {
+                let steps: Vec<String> = group.iter().map(ToString::to_string).collect();
+                match group.len() {
+                    1 => steps.join(","),
+                    _ => format!("({})", steps.join(",")),
+                }
+            })
+            .collect();
+        f.write_str(&groups.join(","))
+    }
+}
+
+// A step is written as its sequence entry, e.g. `"8000/udp?timeout=250"`:
+// milliseconds, hex payloads and lower-case protocols. Alone it is in no
+// group; a plan keeps its groups
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(KnockStep, KnockStep::parse);
+
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(TtlWalk, TtlWalk::parse);
+
+/// Written as the sequence string, `"(7000,8000),9000/udp"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for KnockPlan {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Read from plan files as a sequence string, `"7000,8000/udp"`, or a
+/// list of entries, `["7000", "8000/udp"]`.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for KnockPlan {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Entries {
+            Sequence(String),
+            List(Vec<String>),
+        }
+        let plan = match Entries::deserialize(deserializer)? {
+            Entries::Sequence(s) => s.parse(),
+            Entries::List(entries) => entries.iter().map(|s| KnockStep::parse(s)).collect(),
+        };
+        plan.map_err(serde::de::Error::custom)
+    }
+}
+
+/// Either form [`Deserialize`](serde::Deserialize) takes; what makes a
+/// valid entry is left to [`KnockStep::parse`].
+#[cfg(feature = "plan-file")]
+impl schemars::JsonSchema for KnockPlan {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "KnockPlan".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        let entry = "PORT[<SOURCE][~TTL][*ATTEMPTS][/PROTO][?OPTION=VALUE&...][:KIND[:ARG]], e.g. \
+                     \"8000/udp?payload=cafe\" or \"443:tls:sni.example\"";
+        schemars::json_schema!({
+            "description": "The knock sequence: comma-separated entries, steps in \
+                            parentheses sent together, or a list of entries.",
+            "anyOf": [
+                { "type": "string", "minLength": 1 },
+                {
+                    "type": "array",
+                    "items": { "type": "string", "minLength": 1, "description": entry },
+                    "minItems": 1,
+                },
+            ],
+        })
+    }
+}
+
+/// Parse a comma-separated sequence; HTTP paths and SNIs never contain
+/// commas, so every comma separates steps.
+impl std::str::FromStr for KnockPlan {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Self::parse(s)
+    }
+}
+
+/// Only allow RFC 3986 path/query characters so a step can never inject
+/// extra request lines or headers.
+pub fn validate_http_path(path: &str) -> Result<(), String> {
+    if !path.starts_with('/') {
+        return Err(format!("HTTP path '{path}' must start with '/'"));
+    }
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-._~!$&'()*+;=:@/?%".contains(c);
+    match path.chars().find(|c| !allowed(*c)) {
+        Some(c) => Err(format!("HTTP path contains invalid character {c:?}")),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_round_trips_through_its_text_form() {
+        let text = "7000/udp?timeout=250&payload=6b6e6f636b,8080:http:/knock/abc?x=1,443:tls:front.example";
+        let plan: KnockPlan = text.parse().unwrap();
+        assert_eq!(plan.len(), 3);
+        assert_eq!(plan[0].timeout, Some(Duration::from_millis(250)));
+        assert_eq!(plan[0].payload.as_deref(), Some(&b"knock"[..]));
+        assert_eq!(plan.to_string(), text);
+        assert_eq!(plan.to_string().parse::<KnockPlan>().unwrap(), plan);
+        assert!("7000,,8000".parse::<KnockPlan>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn plans_and_steps_serialize_as_their_text() {
+        let plan: KnockPlan = "(7000,8000/udp?timeout=250),9000:tls:front.example"
+            .parse()
+            .unwrap();
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(json, format!("\"{plan}\""));
+        assert_eq!(serde_json::from_str::<KnockPlan>(&json).unwrap(), plan);
+
+        let step = KnockStep::parse("7000/udp?payload=6b6e6f636b").unwrap();
+        let json = serde_json::to_string(&step).unwrap();
+        assert_eq!(serde_json::from_str::<KnockStep>(&json).unwrap(), step);
+        assert!(serde_json::from_str::<KnockStep>("\"70000\"").is_err());
+    }
+
+    #[test]
+    fn bare_port_step() {
+        let step = KnockStep::parse("7000").unwrap();
+        assert_eq!(step.port, 7000);
+        assert_eq!(step.kind, None);
+    }
+
+    #[test]
+    fn http_step_with_path() {
+        let step = KnockStep::parse("8080:http:/knock/abc123?x=1").unwrap();
+        assert_eq!(step.port, 8080);
+        assert_eq!(
+            step.kind,
+            Some(StepKind::Http {
+                path: "/knock/abc123?x=1".into()
+            })
+        );
+        assert_eq!(step.to_string(), "8080:http:/knock/abc123?x=1");
+    }
+
+    #[test]
+    fn tls_step_with_optional_sni() {
+        let step = KnockStep::parse("443:tls").unwrap();
+        assert_eq!(step.kind, Some(StepKind::Tls { sni: None }));
+        let step = KnockStep::parse("443:tls:front.example").unwrap();
+        assert_eq!(
+            step.kind,
+            Some(StepKind::Tls {
+                sni: Some("front.example".into())
+            })
+        );
+        assert!(KnockStep::parse("443:tls:bad name").is_err());
+    }
+
+    #[test]
+    fn quic_step() {
+        let step = KnockStep::parse("443:quic").unwrap();
+        assert_eq!(step.kind, Some(StepKind::Quic));
+        assert_eq!(step.to_string(), "443:quic");
+        assert!(KnockStep::parse("443:quic:x").is_err());
+        assert!(KnockStep::parse("443/udp:quic").is_err());
+    }
+
+    #[test]
+    fn plugin_step() {
+        let step = KnockStep::parse("7000:plugin:variant.wasm:k=v:w").unwrap();
+        assert_eq!(
+            step.kind,
+            Some(StepKind::Plugin {
+                path: "variant.wasm".into(),
+                arg: Some("k=v:w".into())
+            })
+        );
+        assert_eq!(step.to_string(), "7000:plugin:variant.wasm:k=v:w");
+        let step = KnockStep::parse("7000:plugin:variant.wasm").unwrap();
+        assert_eq!(step.to_string(), "7000:plugin:variant.wasm");
+        assert!(KnockStep::parse("7000:plugin").is_err());
+        assert!(KnockStep::parse("7000:plugin::x").is_err());
+    }
+
+    #[test]
+    fn per_step_overrides() {
+        let step =
+            KnockStep::parse("7000/udp?timeout=200&delay=50&attempts=3&payload=beef").unwrap();
+        assert_eq!(step.protocol, Some(Protocol::Udp));
+        assert_eq!(step.timeout, Some(Duration::from_millis(200)));
+        assert_eq!(step.pre_delay, Some(Duration::from_millis(50)));
+        assert_eq!(step.attempts, Some(3));
+        assert_eq!(step.payload.as_deref(), Some(&[0xbe, 0xef][..]));
+        assert_eq!(
+            step.to_string(),
+            "7000/udp?timeout=200&delay=50&attempts=3&payload=beef"
+        );
+
+        let step = KnockStep::parse("8080?attempts=2:http:/k?x=1").unwrap();
+        assert_eq!(step.attempts, Some(2));
+        assert_eq!(step.to_string(), "8080?attempts=2:http:/k?x=1");
+
+        // The old spelling counts retries after the first attempt
+        let step = KnockStep::parse("8080?retries=0").unwrap();
+        assert_eq!(step.attempts, Some(1));
+        assert!(KnockStep::parse("8080?attempts=0").is_err());
+
+        assert!(KnockStep::parse("7000/quic").is_err());
+        assert!(KnockStep::parse("7000?timeout=0").is_err());
+        assert!(KnockStep::parse("7000?ttl=3").is_err());
+        assert!(KnockStep::parse("443/udp:tls").is_err());
+    }
+
+    #[test]
+    fn untrusted_sequences_load_no_plugins() {
+        assert!(KnockPlan::parse_untrusted("7000,8000/udp,443:tls:sni.example").is_ok());
+        let error = KnockPlan::parse_untrusted("7000,8000:plugin:/etc/passwd").unwrap_err();
+        assert!(error.contains("plugin step"), "{error}");
+        assert!(!error.contains("/etc/passwd"), "{error}");
+    }
+
+    #[test]
+    fn attempts_annotation() {
+        let plan = KnockPlan::parse("7000,8000,9000*5").unwrap();
+        let attempts: Vec<_> = plan.iter().map(|s| s.attempts).collect();
+        assert_eq!(attempts, [None, None, Some(5)]);
+        let step = KnockStep::parse("7000<40001~63*2/udp?delay=50").unwrap();
+        assert_eq!(
+            (step.source_port, step.ttl, step.attempts),
+            (Some(40001), Some(63), Some(2))
+        );
+        assert_eq!(step.to_string(), "7000<40001~63/udp?delay=50&attempts=2");
+
+        assert!(KnockStep::parse("9000*0").is_err());
+        assert!(KnockStep::parse("9000*x").is_err());
+        assert!(KnockStep::parse("9000*").is_err());
+        assert!(KnockStep::parse("9000*2?attempts=3").is_err());
+        assert!(KnockStep::parse("9000*2?retries=1").is_err());
+    }
+
+    #[test]
+    fn source_port_annotation() {
+        let plan = KnockPlan::parse("7000<40001,8000<40002/udp?timeout=200").unwrap();
+        assert_eq!(plan[0].port, 7000);
+        assert_eq!(plan[0].source_port, Some(40001));
+        assert_eq!(plan[1].source_port, Some(40002));
+        assert_eq!(plan[1].protocol, Some(Protocol::Udp));
+        assert_eq!(plan.to_string(), "7000<40001,8000<40002/udp?timeout=200");
+        assert_eq!(KnockStep::parse("7000").unwrap().source_port, None);
+        assert!(KnockStep::parse("7000<0").is_err());
+        assert!(KnockStep::parse("7000<").is_err());
+        assert!(KnockStep::parse("7000<70000").is_err());
+    }
+
+    #[test]
+    fn ttl_annotation_and_walk() {
+        let plan = KnockPlan::parse("7000~63,8000<40002~51/udp,9000").unwrap();
+        assert_eq!(plan[0].ttl, Some(63));
+        assert_eq!(plan[1].ttl, Some(51));
+        assert_eq!(plan[1].source_port, Some(40002));
+        assert_eq!(plan[2].ttl, None);
+        assert_eq!(plan.to_string(), "7000~63,8000<40002~51/udp,9000");
+        assert!(KnockStep::parse("7000~0").is_err());
+        assert!(KnockStep::parse("7000~256").is_err());
+        assert!(KnockStep::parse("7000~").is_err());
+
+        // The walk counts every step but leaves their own TTLs alone
+        let walk = TtlWalk::parse("64,-4").unwrap();
+        let walked = plan.clone().with_ttl_walk(walk).unwrap();
+        let ttls: Vec<_> = walked.iter().map(|s| s.ttl).collect();
+        assert_eq!(ttls, [Some(63), Some(51), Some(56)]);
+        assert_eq!(walk.to_string(), "64,-4");
+        assert!(plan.with_ttl_walk(TtlWalk::parse("5,-3").unwrap()).is_err());
+        assert_eq!(TtlWalk::parse("250,3").unwrap().ttl(2), None);
+        assert!(TtlWalk::parse("0,1").is_err());
+        assert!(TtlWalk::parse("64").is_err());
+    }
+
+    #[test]
+    fn plan_from_ports_and_text() {
+        let plan = KnockPlan::parse("7000,8000/udp").unwrap();
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0], KnockStep::new(7000));
+        assert_eq!(plan[1].protocol, Some(Protocol::Udp));
+        assert_eq!(KnockPlan::from_ports(&[7000]).0, [KnockStep::new(7000)]);
+        assert!(KnockPlan::parse("7000,").is_err());
+    }
+
+    #[test]
+    fn both_expands_into_a_tcp_and_a_udp_step() {
+        let plan = KnockPlan::parse("7000?payload=beef,8000/udp,9000/both,443:tls").unwrap();
+        let expanded = plan.clone().expand_both(Protocol::Both);
+        assert_eq!(
+            expanded.to_string(),
+            "7000/tcp?payload=beef,7000/udp?delay=0&payload=beef,8000/udp,\
+             9000/tcp,9000/udp?delay=0,443:tls"
+        );
+        // Only the steps asking for both when the run does not
+        let expanded = plan.expand_both(Protocol::Tcp);
+        assert_eq!(
+            expanded.to_string(),
+            "7000?payload=beef,8000/udp,9000/tcp,9000/udp?delay=0,443:tls"
+        );
+    }
+
+    #[test]
+    fn groups_parse_in_parentheses() {
+        let plan = KnockPlan::parse("(7000,8000/udp),9000,(10000, 11000,12000)").unwrap();
+        let grouped: Vec<bool> = plan.iter().map(|s| s.with_previous).collect();
+        assert_eq!(grouped, [false, true, false, false, true, true]);
+        let sizes: Vec<usize> = plan.groups().map(<[_]>::len).collect();
+        assert_eq!(sizes, [2, 1, 3]);
+        assert_eq!(plan.to_string(), "(7000,8000/udp),9000,(10000,11000,12000)");
+        assert_eq!(plan.to_string().parse::<KnockPlan>().unwrap(), plan);
+        // A group of one is just a step, and a path may still end in ')'
+        assert!(!KnockPlan::parse("(7000),9000").unwrap().has_groups());
+        assert!(KnockPlan::parse("80:http:/a)").is_ok());
+        assert!(KnockPlan::parse("(7000,8000").is_err());
+        assert!(KnockPlan::parse("(7000,(8000)),9000").is_err());
+        assert!(KnockPlan::parse("(),9000").is_err());
+        // Both protocols of a grouped step stay in its group
+        let expanded = KnockPlan::parse("(7000,8000)")
+            .unwrap()
+            .expand_both(Protocol::Both);
+        assert_eq!(expanded.groups().count(), 1);
+    }
+
+    #[test]
+    fn decoys_go_before_and_between_groups() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let plan = KnockPlan::parse("(7000,8000),9000/udp").unwrap();
+        let excluded = [1024..=40000];
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let decoyed = plan
+                .clone()
+                .with_decoys(3, &excluded, false, &mut rng)
+                .unwrap();
+            let real: Vec<&KnockStep> = decoyed.iter().filter(|s| !s.decoy).collect();
+            assert_eq!(real, plan.iter().collect::<Vec<_>>());
+            assert!(!decoyed.last().unwrap().decoy, "{decoyed}");
+            // The group stays whole
+            let sizes: Vec<usize> = decoyed.groups().map(<[_]>::len).collect();
+            assert_eq!(sizes.iter().filter(|&&n| n == 2).count(), 1, "{decoyed}");
+            let mut ports: Vec<u16> = decoyed.iter().filter(|s| s.decoy).map(|s| s.port).collect();
+            assert!(ports.iter().all(|&p| p > 40000), "{decoyed}");
+            ports.sort_unstable();
+            ports.dedup();
+            assert_eq!(ports.len(), 3);
+        }
+        // Not enough ports left
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(plan
+            .with_decoys(2, &[1024..=65534], true, &mut rng)
+            .is_err());
+    }
+
+    #[test]
+    fn http_path_injection_rejected() {
+        assert!(KnockStep::parse("80:http:/a\r\nX-Evil: 1").is_err());
+        assert!(KnockStep::parse("80:http:/a b").is_err());
+        assert!(KnockStep::parse("80:http:no-slash").is_err());
+        assert!(KnockStep::parse("80:gopher:/").is_err());
+    }
+}
@@ -0,0 +1,383 @@
+//! `plugin` knock steps (`7000:plugin:variant.wasm[:ARG]`): the datagram
+//! of the step is built by a WebAssembly module, for knock variants that
+//! cannot ship with the knocker.
+//!
+//! The module only computes bytes; the knocker does all the I/O, sending
+//! what the module returns as a UDP datagram to the step's port and, when
+//! asked to, handing it the reply to judge. A module is a core WebAssembly
+//! module (a `.wasm` file, or its `.wat` text) exporting:
+//!
+//! - `memory`;
+//! - `knock_alloc(len: i32) -> i32`: room for `len` bytes the knocker
+//!   writes into;
+//! - `knock_build(ptr: i32, len: i32) -> i64`: given the request at `ptr`,
+//!   the response's address in the high 32 bits and its length in the
+//!   low ones;
+//! - optionally `knock_check(ptr: i32, len: i32) -> i32`: given the reply,
+//!   nonzero to accept it. Without it any reply is accepted.
+//!
+//! The request is UTF-8 text, a `key=value` line each of `host` (as
+//! configured), `target` (the address knocked, `IP:PORT`), `port` and
+//! `arg` (the step's argument, possibly empty), in that order and `arg`
+//! last, so it runs to the end of the request. The response is a flags
+//! byte, bit 0 set to wait for a reply, followed by the datagram.
+//!
+//! Every knock gets a fresh instance, which `knock_check` shares with
+//! its `knock_build`. A module has no files, network or clock: a module
+//! built for WASI (`wasm32-wasip1`) links, but calling any of its imports
+//! traps. A call may run [`FUEL`] instructions and grow the memory to
+//! [`MAX_MEMORY`] bytes; a trap, running out of fuel or a malformed
+//! response fails that step alone. A module that does not compile or
+//! lacks the exports fails the run before anything is sent.
+//!
+//! `examples/plugins/tagged.wat` is a plugin to start from.
+
+use crate::AppError;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use wasmtime::{
+    Config, Engine, ExternType, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+    Trap, ValType,
+};
+
+/// Instructions a call into a plugin may run, standing in for a timeout.
+pub const FUEL: u64 = 50_000_000;
+
+/// Most bytes of linear memory a plugin may have.
+pub const MAX_MEMORY: usize = 16 << 20;
+
+/// Most bytes a datagram a plugin builds may hold.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+/// The functions a plugin exports: name, count of `i32` parameters,
+/// whether the result is an `i64` rather than an `i32`, and whether the
+/// function is required.
+const FUNCTIONS: &[(&str, usize, bool, bool)] = &[
+    ("knock_alloc", 1, false, true),
+    ("knock_build", 2, true, true),
+    ("knock_check", 2, false, false),
+];
+
+/// What a plugin is told of the step.
+#[derive(Debug, Clone, Copy)]
+pub struct PluginRequest<'a> {
+    pub host: &'a str,
+    pub target: SocketAddr,
+    pub arg: Option<&'a str>,
+}
+
+impl PluginRequest<'_> {
+    /// The request as the plugin reads it.
+    pub fn encode(&self) -> String {
+        format!(
+            "host={}\ntarget={}\nport={}\narg={}",
+            self.host,
+            self.target,
+            self.target.port(),
+            self.arg.unwrap_or_default()
+        )
+    }
+}
+
+/// What a plugin made of a step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginKnock {
+    pub datagram: Vec<u8>,
+    pub expect_reply: bool,
+}
+
+/// A compiled plugin.
+#[derive(Clone)]
+pub struct Plugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    checks: bool,
+}
+
+impl Plugin {
+    /// Compile the module at `path` and check its exports.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let name = path.display().to_string();
+        let fail = |why: String| format!("plugin {name}: {why}");
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| fail(e.to_string()))?;
+        let module = Module::from_file(&engine, path).map_err(|e| fail(unquoted(&e)))?;
+        if !matches!(module.get_export("memory"), Some(ExternType::Memory(_))) {
+            return Err(fail("exports no memory".into()));
+        }
+        let mut checks = false;
+        for &(function, params, wide, required) in FUNCTIONS {
+            let ty = match module.get_export(function) {
+                Some(ExternType::Func(ty)) => ty,
+                None if !required => continue,
+                _ => return Err(fail(format!("exports no function {function}"))),
+            };
+            let results: Vec<ValType> = ty.results().collect();
+            let fits = ty.params().len() == params
+                && ty.params().all(|p| matches!(p, ValType::I32))
+                && match &results[..] {
+                    [ValType::I64] => wide,
+                    [ValType::I32] => !wide,
+                    _ => false,
+                };
+            if !fits {
+                return Err(fail(format!("{function} has the wrong signature")));
+            }
+            checks |= function == "knock_check";
+        }
+        Ok(Self {
+            name,
+            engine,
+            module,
+            checks,
+        })
+    }
+
+    /// Have a fresh instance build the knock for `request`; the call is
+    /// kept to check the reply with.
+    pub fn build(&self, request: &PluginRequest) -> Result<(PluginCall, PluginKnock), String> {
+        let mut call = self.instantiate()?;
+        let response = call.call("knock_build", request.encode().as_bytes())?;
+        let knock = call.response(response)?;
+        Ok((call, knock))
+    }
+
+    fn instantiate(&self) -> Result<PluginCall, String> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        let fail = |e: wasmtime::Error| format!("plugin {}: {}", self.name, describe(e));
+        store.set_fuel(FUEL).map_err(fail)?;
+        let mut linker = Linker::new(&self.engine);
+        linker
+            .define_unknown_imports_as_traps(&self.module)
+            .map_err(fail)?;
+        let instance = linker.instantiate(&mut store, &self.module).map_err(fail)?;
+        Ok(PluginCall {
+            name: self.name.clone(),
+            checks: self.checks,
+            store,
+            instance,
+        })
+    }
+}
+
+/// The instance of one knock, to check the reply with.
+pub struct PluginCall {
+    name: String,
+    checks: bool,
+    store: Store<StoreLimits>,
+    instance: Instance,
+}
+
+impl PluginCall {
+    /// Whether the plugin accepts `reply`; any reply, without a
+    /// `knock_check`.
+    pub fn check(&mut self, reply: &[u8]) -> Result<bool, String> {
+        if !self.checks {
+            return Ok(true);
+        }
+        Ok(self.call("knock_check", reply)? != 0)
+    }
+
+    /// Write `input` into the plugin's memory and call `function` on it,
+    /// with a full tank of fuel.
+    fn call(&mut self, function: &str, input: &[u8]) -> Result<i64, String> {
+        let name = &self.name;
+        let fail = |e: wasmtime::Error| format!("plugin {name}: {function}: {}", describe(e));
+        self.store.set_fuel(FUEL).map_err(fail)?;
+        let len =
+            i32::try_from(input.len()).map_err(|_| format!("plugin {name}: input too long"))?;
+        let alloc = self
+            .instance
+            .get_typed_func::<i32, i32>(&mut self.store, "knock_alloc")
+            .map_err(fail)?;
+        let ptr = alloc.call(&mut self.store, len).map_err(fail)?;
+        let memory = memory(&self.instance, &mut self.store, name)?;
+        memory
+            .write(&mut self.store, ptr as u32 as usize, input)
+            .map_err(|_| format!("plugin {name}: knock_alloc gave room outside its memory"))?;
+        match function {
+            "knock_check" => {
+                let check = self
+                    .instance
+                    .get_typed_func::<(i32, i32), i32>(&mut self.store, function)
+                    .map_err(fail)?;
+                check
+                    .call(&mut self.store, (ptr, len))
+                    .map(i64::from)
+                    .map_err(fail)
+            }
+            _ => {
+                let build = self
+                    .instance
+                    .get_typed_func::<(i32, i32), i64>(&mut self.store, function)
+                    .map_err(fail)?;
+                build.call(&mut self.store, (ptr, len)).map_err(fail)
+            }
+        }
+    }
+
+    /// The knock in the response `knock_build` pointed at.
+    fn response(&mut self, response: i64) -> Result<PluginKnock, String> {
+        let name = &self.name;
+        let (ptr, len) = ((response >> 32) as u32 as usize, response as u32 as usize);
+        let memory = memory(&self.instance, &mut self.store, name)?;
+        let bytes = memory
+            .data(&self.store)
+            .get(ptr..ptr.saturating_add(len))
+            .ok_or_else(|| format!("plugin {name}: knock_build pointed outside its memory"))?;
+        let Some((&flags, datagram)) = bytes.split_first() else {
+            return Err(format!("plugin {name}: knock_build returned nothing"));
+        };
+        if datagram.len() > MAX_DATAGRAM {
+            return Err(format!(
+                "plugin {name}: a {}-byte datagram is more than {MAX_DATAGRAM}",
+                datagram.len()
+            ));
+        }
+        Ok(PluginKnock {
+            datagram: datagram.to_vec(),
+            expect_reply: flags & 1 != 0,
+        })
+    }
+}
+
+/// The memory `instance` of plugin `name` exports.
+fn memory(
+    instance: &Instance,
+    store: &mut Store<StoreLimits>,
+    name: &str,
+) -> Result<wasmtime::Memory, String> {
+    instance
+        .get_memory(store, "memory")
+        .ok_or_else(|| format!("plugin {name}: exports no memory"))
+}
+
+/// What went wrong in a plugin, without the backtrace.
+fn describe(e: wasmtime::Error) -> String {
+    match e.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => {
+            format!("ran out of fuel after {FUEL} instructions")
+        }
+        Some(trap) => format!("trapped: {trap}"),
+        None => e.root_cause().to_string(),
+    }
+}
+
+/// A compile error without the excerpt of the file it quotes: the path
+/// names a module, but could be any local file.
+fn unquoted(error: &wasmtime::Error) -> String {
+    let text = format!("{error:#}");
+    let lines: Vec<&str> = text
+        .lines()
+        .take_while(|line| !line.trim_start().starts_with('|'))
+        .map(str::trim)
+        .collect();
+    lines.join(" ")
+}
+
+/// The plugins of the `plugin` steps of `steps`, each compiled once.
+pub fn load_all<'a>(
+    paths: impl IntoIterator<Item = &'a PathBuf>,
+) -> Result<HashMap<PathBuf, Plugin>, AppError> {
+    let mut plugins = HashMap::new();
+    for path in paths {
+        if !plugins.contains_key(path) {
+            let plugin = Plugin::load(path).map_err(AppError::Plugin)?;
+            plugins.insert(path.clone(), plugin);
+        }
+    }
+    Ok(plugins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(wat: &str) -> Result<Plugin, String> {
+        let dir = std::env::temp_dir().join(format!("pk-plugin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{:x}.wat", rand::random::<u64>()));
+        std::fs::write(&path, wat).unwrap();
+        let plugin = Plugin::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        plugin
+    }
+
+    const REQUEST: PluginRequest = PluginRequest {
+        host: "example.com",
+        target: SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 7000),
+        arg: Some("x=1"),
+    };
+
+    #[test]
+    fn load_errors_do_not_quote_the_file() {
+        let error = plugin("root:x:0:0:secret-line\n").err().unwrap();
+        assert!(error.contains("expected"), "{error}");
+        assert!(!error.contains("secret-line"), "{error}");
+    }
+
+    #[test]
+    fn requests_end_with_the_argument() {
+        assert_eq!(
+            REQUEST.encode(),
+            "host=example.com\ntarget=127.0.0.1:7000\nport=7000\narg=x=1"
+        );
+    }
+
+    #[test]
+    fn the_example_plugin_tags_its_knocks() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/plugins/tagged.wat");
+        let plugin = Plugin::load(&path).unwrap();
+        let (mut call, knock) = plugin.build(&REQUEST).unwrap();
+        assert_eq!(knock.datagram, b"knock:x=1");
+        assert!(knock.expect_reply);
+        assert!(call.check(b"ok, open").unwrap());
+        assert!(!call.check(b"no").unwrap());
+    }
+
+    #[test]
+    fn misbehaving_plugins_fail_their_call() {
+        let echo = |body: &str| {
+            format!(
+                r#"(module (memory (export "memory") 1)
+                   (func (export "knock_alloc") (param i32) (result i32) i32.const 64)
+                   (func (export "knock_build") (param i32 i32) (result i64) {body}))"#
+            )
+        };
+        let error = |body: &str| plugin(&echo(body)).unwrap().build(&REQUEST).err().unwrap();
+        assert!(
+            error("unreachable").contains("trapped"),
+            "{}",
+            error("unreachable")
+        );
+        assert!(error("(loop br 0) i64.const 0").contains("ran out of fuel"));
+        assert!(error("i64.const 0").contains("returned nothing"));
+        assert!(error("i64.const 0x10000000000001").contains("outside its memory"));
+        // A WASI import links, and traps once called
+        let wasi = r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "knock_alloc") (param i32) (result i32) i32.const 64)
+            (func (export "knock_build") (param i32 i32) (result i64)
+              i32.const 1 call $exit i64.const 0))"#;
+        let err = plugin(wasi).unwrap().build(&REQUEST).err().unwrap();
+        assert!(err.contains("unknown import"), "{err}");
+
+        let memoryless =
+            r#"(module (func (export "knock_build") (param i32 i32) (result i64) i64.const 0))"#;
+        assert!(plugin(memoryless)
+            .err()
+            .unwrap()
+            .ends_with("exports no memory"));
+        let unsigned = echo("i64.const 0").replace("(param i32 i32) (result i64)", "(result i64)");
+        assert!(plugin(&unsigned).err().unwrap().contains("wrong signature"));
+    }
+}
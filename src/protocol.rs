@@ -0,0 +1,260 @@
+// Knock settings shared by the library and the command line; clap only
+// derives its parsing for them with the `cli` feature.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Supported knock protocols
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// ICMP echo requests; sequence numbers are payload sizes
+    Icmp,
+    /// SCTP association setup (INIT chunk), Linux only
+    Sctp,
+    /// TCP then UDP on each port, for servers that may expect either;
+    /// never sent as such, each step becomes a TCP and a UDP step when
+    /// the plan is built
+    Both,
+}
+
+impl Protocol {
+    /// Lower-case name, as on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+            Protocol::Sctp => "sctp",
+            Protocol::Both => "both",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Written by name, e.g. `"udp"`, and read back as `FromStr` takes it
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(Protocol);
+
+/// The names plan files take, lower case as `name` gives them.
+#[cfg(feature = "plan-file")]
+impl schemars::JsonSchema for Protocol {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "Protocol".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        let names = [
+            Protocol::Tcp,
+            Protocol::Udp,
+            Protocol::Icmp,
+            Protocol::Sctp,
+            Protocol::Both,
+        ]
+        .map(Protocol::name);
+        schemars::json_schema!({
+            "description": "Knock protocol; `both` knocks each port over TCP and then UDP.",
+            "type": "string",
+            "enum": names,
+        })
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let protocol = match s.to_ascii_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            "icmp" => Protocol::Icmp,
+            "sctp" => Protocol::Sctp,
+            "both" => Protocol::Both,
+            _ => {
+                return Err(format!(
+                    "'{s}' is not a supported protocol (expected tcp, udp, icmp, sctp or both)"
+                ))
+            }
+        };
+        if protocol == Protocol::Sctp && !cfg!(target_os = "linux") {
+            return Err("sctp knocks are only supported on Linux".into());
+        }
+        Ok(protocol)
+    }
+}
+
+/// How a connected TCP knock is torn down
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TcpClose {
+    /// Orderly FIN/ACK shutdown
+    #[default]
+    Fin,
+    /// Abortive close (SO_LINGER 0): a single RST, no FIN exchange
+    Rst,
+}
+
+/// How the wait between retries grows
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum BackoffStrategy {
+    /// The same backoff after every attempt
+    #[default]
+    Constant,
+    /// Doubled after every attempt, up to the maximum
+    Exponential,
+    /// Exponential, with each wait drawn at random below it
+    Jitter,
+}
+
+/// What the knocks of a run get once its retry budget is spent
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum BudgetExhausted {
+    /// One attempt each, without retries
+    #[default]
+    Single,
+    /// None: they are not sent
+    Skip,
+}
+
+/// Which of a host name's addresses are knocked, and in which order
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ResolveStrategy {
+    /// Every address, in the resolver's order
+    #[default]
+    All,
+    /// IPv4 addresses first, then IPv6
+    PreferV4,
+    /// IPv6 addresses first, then IPv4
+    PreferV6,
+    /// IPv4 addresses only
+    OnlyV4,
+    /// IPv6 addresses only
+    OnlyV6,
+}
+
+/// HMAC used for TOTP-derived ports (RFC 6238)
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TotpAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+/// Implement `name()`, `Display` and `FromStr` with the lower-case names
+/// the command line uses, so every setting has one stable spelling, and
+/// serialize it as that.
+macro_rules! named {
+    ($type:ident, $what:literal, { $($variant:ident => $name:literal),+ $(,)? }) => {
+        impl $type {
+            /// Lower-case name, as on the command line.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($type::$variant => $name),+
+                }
+            }
+        }
+
+        impl fmt::Display for $type {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        impl FromStr for $type {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, String> {
+                match s.to_ascii_lowercase().as_str() {
+                    $($name => Ok($type::$variant),)+
+                    _ => Err(format!(
+                        concat!("'{}' is not a valid ", $what, " (expected {})"),
+                        s,
+                        [$($name),+].join(", ")
+                    )),
+                }
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        crate::serde_forms::as_text!($type);
+    };
+}
+
+named!(TcpClose, "TCP close mode", { Fin => "fin", Rst => "rst" });
+named!(BackoffStrategy, "backoff strategy", {
+    Constant => "constant",
+    Exponential => "exponential",
+    Jitter => "jitter",
+});
+named!(BudgetExhausted, "budget mode", { Single => "single", Skip => "skip" });
+named!(ResolveStrategy, "resolve strategy", {
+    All => "all",
+    PreferV4 => "prefer-v4",
+    PreferV6 => "prefer-v6",
+    OnlyV4 => "only-v4",
+    OnlyV6 => "only-v6",
+});
+named!(TotpAlgorithm, "TOTP algorithm", { Sha1 => "sha1", Sha256 => "sha256" });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T>(values: &[T])
+    where
+        T: Copy + fmt::Display + FromStr<Err = String> + PartialEq + fmt::Debug,
+    {
+        for &value in values {
+            assert_eq!(value.to_string().parse::<T>(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        round_trip(&[Protocol::Tcp, Protocol::Udp, Protocol::Icmp]);
+        round_trip(&[TcpClose::Fin, TcpClose::Rst]);
+        round_trip(&[BackoffStrategy::Constant, BackoffStrategy::Jitter]);
+        round_trip(&[BudgetExhausted::Single, BudgetExhausted::Skip]);
+        round_trip(&[
+            ResolveStrategy::All,
+            ResolveStrategy::PreferV6,
+            ResolveStrategy::OnlyV4,
+        ]);
+        round_trip(&[TotpAlgorithm::Sha1, TotpAlgorithm::Sha256]);
+        assert_eq!("SHA256".parse(), Ok(TotpAlgorithm::Sha256));
+        assert_eq!(
+            "v4".parse::<ResolveStrategy>(),
+            Err("'v4' is not a valid resolve strategy (expected all, prefer-v4, prefer-v6, only-v4, only-v6)".into())
+        );
+    }
+
+    /// The names are the ones clap accepts for the flags.
+    #[cfg(feature = "cli")]
+    #[test]
+    fn names_match_the_command_line() {
+        use clap::ValueEnum;
+
+        fn check<T: ValueEnum + Copy + fmt::Display>() {
+            for value in T::value_variants() {
+                let possible = value.to_possible_value().unwrap();
+                assert_eq!(possible.get_name(), value.to_string());
+            }
+        }
+        check::<Protocol>();
+        check::<TcpClose>();
+        check::<BackoffStrategy>();
+        check::<ResolveStrategy>();
+        check::<TotpAlgorithm>();
+    }
+}
@@ -0,0 +1,337 @@
+//! Python bindings (pyo3): the `async_port_knocker` extension module,
+//! built with maturin from `python/` (see its `pyproject.toml`).
+//!
+//! `PortKnocker.knock` runs on a thread of its own through
+//! [`blocking::run_with_cancel`](crate::blocking::run_with_cancel) while the
+//! calling thread waits with the GIL released. It checks for signals every
+//! 50ms, so Ctrl-C cancels the run and raises `KeyboardInterrupt` once the
+//! run has stopped.
+//!
+//! A run whose only failure is knocks that did not get through
+//! ([`AppError::Partial`]) returns its report, those knocks marked failed;
+//! other failures raise `KnockError` with the report, if the run got that
+//! far, as `report`.
+
+use crate::{
+    blocking, protocol::Protocol, AppError, CancellationToken, KnockConfig, KnockEvent,
+    KnockObserver,
+};
+use pyo3::exceptions::{PyException, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pyo3::create_exception!(
+    async_port_knocker,
+    KnockError,
+    PyException,
+    "A run that failed; `code` is the binary's exit code for the error and `report` the run's KnockReport, or None when it failed before knocking."
+);
+
+/// How often a waiting `knock` checks for Ctrl-C.
+const SIGNAL_CHECK: Duration = Duration::from_millis(50);
+
+/// How one knock went.
+#[pyclass(
+    name = "KnockResult",
+    module = "async_port_knocker",
+    get_all,
+    frozen,
+    skip_from_py_object
+)]
+#[derive(Debug, Clone)]
+pub struct PyKnockResult {
+    port: u16,
+    protocol: String,
+    succeeded: bool,
+    /// The target answered, rather than the knock only being sent.
+    acknowledged: bool,
+    attempts: usize,
+    /// Latency of the attempt that got through, if one did.
+    latency_ms: Option<u64>,
+    /// Time spent on the knock, retries included.
+    elapsed_ms: u64,
+    /// Why the last attempt of a failed knock failed.
+    error: Option<String>,
+}
+
+#[pymethods]
+impl PyKnockResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "KnockResult(port={}, protocol='{}', succeeded={}, attempts={})",
+            self.port,
+            self.protocol,
+            if self.succeeded { "True" } else { "False" },
+            self.attempts
+        )
+    }
+}
+
+/// Every knock of a run, in sequence order.
+#[pyclass(
+    name = "KnockReport",
+    module = "async_port_knocker",
+    get_all,
+    frozen,
+    skip_from_py_object
+)]
+#[derive(Debug, Clone)]
+pub struct PyKnockReport {
+    knocks: Vec<PyKnockResult>,
+    duration_ms: u64,
+    /// The run was cancelled before every knock was sent.
+    interrupted: bool,
+}
+
+#[pymethods]
+impl PyKnockReport {
+    /// Every knock got through and the run was not cancelled.
+    #[getter]
+    fn succeeded(&self) -> bool {
+        !self.interrupted && self.knocks.iter().all(|k| k.succeeded)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "KnockReport(knocks={}, duration_ms={}, interrupted={})",
+            self.knocks.len(),
+            self.duration_ms,
+            if self.interrupted { "True" } else { "False" }
+        )
+    }
+}
+
+impl From<&crate::KnockReport> for PyKnockReport {
+    fn from(run: &crate::KnockReport) -> Self {
+        let knocks = run
+            .steps
+            .iter()
+            .map(|o| PyKnockResult {
+                port: o.port,
+                protocol: o.protocol.to_string(),
+                succeeded: o.succeeded,
+                acknowledged: o.acknowledged,
+                attempts: o.attempts,
+                latency_ms: o.latency.map(|l| l.as_millis() as u64),
+                elapsed_ms: o.elapsed.as_millis() as u64,
+                error: o
+                    .errors
+                    .last()
+                    .filter(|_| !o.succeeded)
+                    .map(|e| e.message.clone()),
+            })
+            .collect();
+        PyKnockReport {
+            knocks,
+            duration_ms: run.duration.as_millis() as u64,
+            interrupted: run.interrupted,
+        }
+    }
+}
+
+/// Sends knock sequences through the library.
+#[pyclass(name = "PortKnocker", module = "async_port_knocker", frozen)]
+#[derive(Debug, Default)]
+pub struct PortKnocker;
+
+#[pymethods]
+impl PortKnocker {
+    #[new]
+    fn new() -> Self {
+        PortKnocker
+    }
+
+    /// Knock `sequence` on `host` and return the report, in which knocks
+    /// that did not get through are marked failed. Raises KnockError when
+    /// the run fails otherwise.
+    #[pyo3(signature = (host, sequence, protocol="tcp", timeout_ms=500, attempts=1, delay_ms=0, payload=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn knock(
+        &self,
+        py: Python<'_>,
+        host: &str,
+        sequence: Vec<u16>,
+        protocol: &str,
+        timeout_ms: u64,
+        attempts: usize,
+        delay_ms: u64,
+        payload: Option<Vec<u8>>,
+    ) -> PyResult<PyKnockReport> {
+        let protocol = protocol
+            .parse::<Protocol>()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let mut builder = KnockConfig::builder()
+            .host(host)
+            .sequence(sequence)
+            .protocol(protocol)
+            .timeout(timeout_ms)
+            .attempts(attempts)
+            .delay(delay_ms);
+        if let Some(payload) = payload {
+            builder = builder.payload(payload);
+        }
+        let last = Arc::new(LastReport::default());
+        let config = builder
+            .observer(last.clone())
+            .build()
+            .map_err(|e| knock_error(py, e, None))?;
+        let result = run_interruptibly(py, config)?;
+        match with_failed_knocks(result, &last) {
+            Ok(run) => Ok(PyKnockReport::from(&run)),
+            Err((e, run)) => Err(knock_error(py, e, run.as_deref())),
+        }
+    }
+}
+
+/// Keeps the report of the run, which [`AppError`]s do not carry.
+#[derive(Debug, Default)]
+struct LastReport(Mutex<Option<crate::KnockReport>>);
+
+impl KnockObserver for LastReport {
+    fn on_event(&self, event: &KnockEvent) {
+        if let KnockEvent::Finished { report } = event {
+            *self.0.lock().unwrap() = Some((**report).clone());
+        }
+    }
+}
+
+/// The report of a run that knocked, failed knocks included; other errors
+/// come with the report when there is one.
+fn with_failed_knocks(
+    result: Result<crate::KnockReport, AppError>,
+    last: &LastReport,
+) -> Result<crate::KnockReport, (AppError, Option<Box<crate::KnockReport>>)> {
+    let report = last.0.lock().unwrap().take();
+    match (result, report) {
+        (Ok(run), _) => Ok(run),
+        (Err(AppError::Partial { .. }), Some(run)) => Ok(run),
+        (Err(e), run) => Err((e, run.map(Box::new))),
+    }
+}
+
+/// Run `config` on a worker thread, waiting without the GIL; a pending
+/// signal's exception (KeyboardInterrupt for Ctrl-C) cancels the run and is
+/// raised once it has stopped.
+fn run_interruptibly(
+    py: Python<'_>,
+    config: KnockConfig,
+) -> PyResult<Result<crate::KnockReport, AppError>> {
+    let cancel = CancellationToken::new();
+    let waiting = thread::current();
+    let worker = {
+        let cancel = cancel.clone();
+        thread::Builder::new()
+            .name("port-knock".into())
+            .spawn(move || {
+                let result = blocking::run_with_cancel(config, cancel);
+                waiting.unpark();
+                result
+            })
+            .map_err(|e| PyRuntimeError::new_err(format!("cannot start the run: {e}")))?
+    };
+    while !worker.is_finished() {
+        py.detach(|| thread::park_timeout(SIGNAL_CHECK));
+        if let Err(interrupt) = py.check_signals() {
+            cancel.cancel();
+            let _ = py.detach(|| worker.join());
+            return Err(interrupt);
+        }
+    }
+    match worker.join() {
+        Ok(result) => Ok(result),
+        Err(_) => Err(PyRuntimeError::new_err("the run panicked")),
+    }
+}
+
+fn knock_error(py: Python<'_>, e: AppError, run: Option<&crate::KnockReport>) -> PyErr {
+    let err = KnockError::new_err(e.to_string());
+    let value = err.value(py);
+    let attached = value
+        .setattr("code", e.exit_code())
+        .and_then(|()| value.setattr("report", run.map(PyKnockReport::from)));
+    match attached {
+        Ok(()) => err,
+        Err(set) => set,
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "_native")]
+fn native(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("KnockError", m.py().get_type::<KnockError>())?;
+    m.add_class::<PortKnocker>()?;
+    m.add_class::<PyKnockReport>()?;
+    m.add_class::<PyKnockResult>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_carry_every_knock() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([port, port])
+            .build()
+            .unwrap();
+        let report = PyKnockReport::from(&blocking::run(config).unwrap());
+        assert!(report.succeeded());
+        let ports: Vec<_> = report
+            .knocks
+            .iter()
+            .map(|k| (k.port, &*k.protocol))
+            .collect();
+        assert_eq!(ports, [(port, "tcp"), (port, "tcp")]);
+        assert!(report.knocks.iter().all(|k| k.latency_ms.is_some()));
+    }
+
+    #[test]
+    fn knocks_that_fail_are_reported_not_raised() {
+        let open = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // A listener whose backlog is full: connects to it time out
+        let full =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        full.bind(
+            &"127.0.0.1:0"
+                .parse::<std::net::SocketAddr>()
+                .unwrap()
+                .into(),
+        )
+        .unwrap();
+        full.listen(0).unwrap();
+        let full_addr = full.local_addr().unwrap().as_socket().unwrap();
+        let _queued = std::net::TcpStream::connect(full_addr).unwrap();
+
+        let last = Arc::new(LastReport::default());
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([open.local_addr().unwrap().port(), full_addr.port()])
+            .timeout(200)
+            .observer(last.clone())
+            .build()
+            .unwrap();
+        let result = blocking::run(config);
+        assert!(matches!(result, Err(AppError::Partial { .. })));
+        let report = PyKnockReport::from(&with_failed_knocks(result, &last).unwrap());
+        assert!(!report.succeeded());
+        let marked: Vec<_> = report
+            .knocks
+            .iter()
+            .map(|k| (k.succeeded, k.error.is_some()))
+            .collect();
+        assert_eq!(marked, [(true, false), (false, true)]);
+
+        // Other failures keep their error
+        let result = Err(AppError::InvalidConfig("no host".into()));
+        assert!(matches!(
+            with_failed_knocks(result, &LastReport::default()),
+            Err((AppError::InvalidConfig(_), None))
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! QUIC v1 Initial packets, as the payload of `quic` knock steps.
+//!
+//! [`random_initial`] builds a client Initial (RFC 9000 section 17.2.2)
+//! with fresh random connection IDs: a long header, a PING frame padded
+//! to the 1200 bytes a client's first datagram must fill, AEAD payload
+//! protection and header protection with the keys every QUIC v1 endpoint
+//! derives from the Destination Connection ID (RFC 9001 section 5). A
+//! server can decrypt it, but it carries no ClientHello, so no handshake
+//! follows; to anything watching it is one more client trying port 443.
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt};
+use aes::Aes128;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Nonce};
+use rand::{rngs::ThreadRng, RngCore};
+
+/// QUIC version 1 (RFC 9000).
+pub const VERSION_1: u32 = 1;
+
+/// Salt for the Initial secrets of QUIC v1 (RFC 9001 section 5.2).
+pub const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Smallest UDP payload allowed for a datagram carrying a client Initial.
+pub const MIN_INITIAL_LEN: usize = 1200;
+
+/// Length of the random connection IDs.
+pub const CID_LEN: usize = 8;
+
+/// Bytes of the packet number; always 4, so the header-protection sample
+/// starts right after it.
+const PN_LEN: usize = 4;
+
+/// Length of the AES-128-GCM tag after the payload.
+const TAG_LEN: usize = 16;
+
+/// Frame types used in the payload.
+const FRAME_PADDING: u8 = 0x00;
+const FRAME_PING: u8 = 0x01;
+
+/// The client's Initial packet protection keys for a Destination
+/// Connection ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitialKeys {
+    pub key: [u8; 16],
+    pub iv: [u8; 12],
+    pub hp: [u8; 16],
+}
+
+impl InitialKeys {
+    /// Derive the client keys from `dcid` (RFC 9001 section 5.2).
+    pub fn client(dcid: &[u8]) -> Self {
+        let initial = crate::passphrase::hkdf_extract(&INITIAL_SALT, dcid);
+        let secret = expand_label(&initial, "client in", 32);
+        let mut keys = Self {
+            key: [0; 16],
+            iv: [0; 12],
+            hp: [0; 16],
+        };
+        keys.key
+            .copy_from_slice(&expand_label(&secret, "quic key", 16));
+        keys.iv
+            .copy_from_slice(&expand_label(&secret, "quic iv", 12));
+        keys.hp
+            .copy_from_slice(&expand_label(&secret, "quic hp", 16));
+        keys
+    }
+
+    /// The header-protection mask for a 16-byte `sample` of the
+    /// ciphertext (RFC 9001 section 5.4.3).
+    pub fn mask(&self, sample: &[u8; 16]) -> [u8; 5] {
+        let cipher = Aes128::new(GenericArray::from_slice(&self.hp));
+        let mut block = GenericArray::clone_from_slice(sample);
+        cipher.encrypt_block(&mut block);
+        let mut mask = [0; 5];
+        mask.copy_from_slice(&block[..5]);
+        mask
+    }
+
+    /// The AEAD nonce of packet number `pn`: the IV with `pn` XORed into
+    /// its last bytes.
+    fn nonce(&self, pn: u32) -> [u8; 12] {
+        let mut nonce = self.iv;
+        for (n, p) in nonce[8..].iter_mut().zip(pn.to_be_bytes()) {
+            *n ^= p;
+        }
+        nonce
+    }
+}
+
+/// HKDF-Expand-Label of TLS 1.3 (RFC 8446 section 7.1) with an empty
+/// context.
+fn expand_label(secret: &[u8], label: &str, len: u16) -> Vec<u8> {
+    let label = format!("tls13 {label}");
+    let mut info = Vec::with_capacity(4 + label.len());
+    info.extend_from_slice(&len.to_be_bytes());
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+    crate::passphrase::hkdf_expand(secret, &info, usize::from(len))
+}
+
+/// A protected client Initial with packet number `pn`, padded so the
+/// datagram is [`MIN_INITIAL_LEN`] bytes.
+pub fn initial_packet(dcid: &[u8], scid: &[u8], pn: u32) -> Vec<u8> {
+    assert!(
+        dcid.len() <= 20 && scid.len() <= 20,
+        "QUIC v1 connection IDs are at most 20 bytes"
+    );
+    // First byte: long header, fixed bit, type Initial (0), packet
+    // number length - 1 in the low bits
+    let mut packet = vec![0xc0 | (PN_LEN as u8 - 1)];
+    packet.extend_from_slice(&VERSION_1.to_be_bytes());
+    packet.push(dcid.len() as u8);
+    packet.extend_from_slice(dcid);
+    packet.push(scid.len() as u8);
+    packet.extend_from_slice(scid);
+    // No token
+    packet.push(0);
+    // Length of packet number and protected payload, as a 2-byte varint
+    let length = MIN_INITIAL_LEN - packet.len() - 2;
+    packet.extend_from_slice(&(0x4000 | length as u16).to_be_bytes());
+    let pn_offset = packet.len();
+    packet.extend_from_slice(&pn.to_be_bytes());
+
+    let mut frames = vec![FRAME_PADDING; length - PN_LEN - TAG_LEN];
+    frames[0] = FRAME_PING;
+    let keys = InitialKeys::client(dcid);
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(&keys.key));
+    let sealed = cipher
+        .encrypt(
+            Nonce::from_slice(&keys.nonce(pn)),
+            Payload {
+                msg: &frames,
+                aad: &packet,
+            },
+        )
+        .expect("AES-GCM seals any payload this size");
+    packet.extend_from_slice(&sealed);
+
+    let mut sample = [0; 16];
+    sample.copy_from_slice(&packet[pn_offset + PN_LEN..pn_offset + PN_LEN + 16]);
+    let mask = keys.mask(&sample);
+    packet[0] ^= mask[0] & 0x0f;
+    for (byte, m) in packet[pn_offset..pn_offset + PN_LEN]
+        .iter_mut()
+        .zip(&mask[1..])
+    {
+        *byte ^= m;
+    }
+    packet
+}
+
+/// An Initial with fresh random connection IDs, for one knock.
+pub fn random_initial() -> Vec<u8> {
+    let mut rng = ThreadRng::default();
+    let (mut dcid, mut scid) = ([0; CID_LEN], [0; CID_LEN]);
+    rng.fill_bytes(&mut dcid);
+    rng.fill_bytes(&mut scid);
+    initial_packet(&dcid, &scid, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Destination Connection ID of the RFC 9001 appendix A examples.
+    const RFC_DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+    #[test]
+    fn rfc9001_client_initial_keys() {
+        let keys = InitialKeys::client(&RFC_DCID);
+        assert_eq!(hex::encode(keys.key), "1f369613dd76d5467730efcbe3b1a22d");
+        assert_eq!(hex::encode(keys.iv), "fa044b2f42a3fd3b46fb255c");
+        assert_eq!(hex::encode(keys.hp), "9f50449e04a0e810283a1e9933adedd2");
+        let sample = hex::decode("d1b1c98dd7689fb8ec11d242b123dc9b").unwrap();
+        let mask = keys.mask(&sample.try_into().unwrap());
+        assert_eq!(hex::encode(mask), "437b9aec36");
+    }
+
+    #[test]
+    fn long_header_fields_follow_rfc9000() {
+        let scid = [0x5c; 4];
+        let packet = initial_packet(&RFC_DCID, &scid, 2);
+        assert_eq!(packet.len(), MIN_INITIAL_LEN);
+        // Header form and fixed bit set, type Initial; header protection
+        // only touches the low 4 bits
+        assert_eq!(packet[0] & 0xf0, 0xc0);
+        assert_eq!(packet[1..5], VERSION_1.to_be_bytes());
+        assert_eq!(packet[5], 8);
+        assert_eq!(packet[6..14], RFC_DCID);
+        assert_eq!(packet[14], 4);
+        assert_eq!(packet[15..19], scid);
+        // Empty token, then the length of everything after the field
+        assert_eq!(packet[19], 0);
+        let length = u16::from_be_bytes([packet[20], packet[21]]);
+        assert_eq!(length & 0xc000, 0x4000, "2-byte varint");
+        assert_eq!(usize::from(length & 0x3fff), packet.len() - 22);
+    }
+
+    #[test]
+    fn receiver_can_unprotect_and_decrypt() {
+        let packet = initial_packet(&RFC_DCID, &[], 7);
+        let keys = InitialKeys::client(&RFC_DCID);
+        let pn_offset = 1 + 4 + 1 + 8 + 1 + 1 + 2;
+        let sample: [u8; 16] = packet[pn_offset + 4..pn_offset + 20].try_into().unwrap();
+        let mask = keys.mask(&sample);
+        let mut header = packet[..pn_offset + 4].to_vec();
+        header[0] ^= mask[0] & 0x0f;
+        assert_eq!(header[0] & 0x03, 3, "4-byte packet number");
+        for (byte, m) in header[pn_offset..].iter_mut().zip(&mask[1..]) {
+            *byte ^= m;
+        }
+        let pn = u32::from_be_bytes(header[pn_offset..].try_into().unwrap());
+        assert_eq!(pn, 7);
+
+        let cipher = Aes128Gcm::new(GenericArray::from_slice(&keys.key));
+        let frames = cipher
+            .decrypt(
+                Nonce::from_slice(&keys.nonce(pn)),
+                Payload {
+                    msg: &packet[pn_offset + 4..],
+                    aad: &header,
+                },
+            )
+            .unwrap();
+        assert_eq!(frames[0], FRAME_PING);
+        assert!(frames[1..].iter().all(|&b| b == FRAME_PADDING));
+    }
+
+    #[test]
+    fn connection_ids_differ_per_knock() {
+        let (a, b) = (random_initial(), random_initial());
+        assert_eq!(a[5], CID_LEN as u8);
+        assert_ne!(a[6..6 + CID_LEN], b[6..6 + CID_LEN]);
+    }
+}
@@ -0,0 +1,166 @@
+//! Capping how fast a run sends, across all of its knocks.
+//!
+//! Every send or connect attempt, retries included, takes a token from
+//! one bucket shared by the whole run, so overlapping knocks and retry
+//! storms stay under `--rate` per second together.
+
+use crate::rt::{sleep, Instant};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Token bucket refilled at a steady rate.
+///
+/// Waiting for a token holds none: dropping an [`acquire`](Self::acquire)
+/// future part way leaves the bucket as if it had never been called, so a
+/// cancelled knock costs the others nothing.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Time it takes to refill one token.
+    interval: Duration,
+    burst: u32,
+    state: Mutex<Bucket>,
+    closed: CancellationToken,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Whole tokens left at `at`.
+    tokens: u32,
+    /// When the bucket last held exactly `tokens`.
+    at: Instant,
+}
+
+impl RateLimiter {
+    /// Allow `per_second` tokens a second, one at a time. Zero is taken as
+    /// one, since an unlimited run has no limiter at all.
+    pub fn new(per_second: u32) -> Self {
+        let per_second = per_second.max(1);
+        Self {
+            interval: Duration::from_secs(1) / per_second,
+            burst: 1,
+            state: Mutex::new(Bucket {
+                tokens: 1,
+                at: Instant::now(),
+            }),
+            closed: CancellationToken::new(),
+        }
+    }
+
+    /// Let up to `burst` tokens be taken at once after a quiet spell,
+    /// starting with a full bucket.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self.state
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .tokens = self.burst;
+        self
+    }
+
+    /// Close the limiter once `cancel` is cancelled.
+    pub fn closed_by(mut self, cancel: CancellationToken) -> Self {
+        self.closed = cancel;
+        self
+    }
+
+    /// Stop handing out tokens: waits in progress and later ones return
+    /// `false` at once.
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    /// Wait for a token and take it. Returns `false`, without a token,
+    /// when the limiter is closed first.
+    pub async fn acquire(&self) -> bool {
+        loop {
+            if self.closed.is_cancelled() {
+                return false;
+            }
+            let wait = match self.try_take() {
+                Ok(()) => return true,
+                Err(wait) => wait,
+            };
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = self.closed.cancelled() => return false,
+            }
+        }
+    }
+
+    /// Take a token if there is one, or tell how long until the next.
+    fn try_take(&self) -> Result<(), Duration> {
+        let mut bucket = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(bucket.at);
+        let refilled = elapsed.as_nanos() / self.interval.as_nanos().max(1);
+        let refilled = u32::try_from(refilled).unwrap_or(u32::MAX);
+        if bucket.tokens.saturating_add(refilled) >= self.burst {
+            bucket.tokens = self.burst;
+            bucket.at = now;
+        } else if refilled > 0 {
+            bucket.tokens += refilled;
+            bucket.at += self.interval * refilled;
+        }
+        if bucket.tokens == 0 {
+            return Err(self.interval - now.saturating_duration_since(bucket.at));
+        }
+        bucket.tokens -= 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_tokens_evenly() {
+        let limiter = RateLimiter::new(10);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire().await);
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_taken_at_once() {
+        let limiter = RateLimiter::new(10).with_burst(3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire().await);
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(limiter.acquire().await);
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_wait_keeps_no_token() {
+        let limiter = RateLimiter::new(10);
+        let start = Instant::now();
+        assert!(limiter.acquire().await);
+        // Give up half way through the wait for the second token
+        let gave_up = tokio::time::timeout(Duration::from_millis(50), limiter.acquire()).await;
+        assert!(gave_up.is_err());
+        // The token it waited for is still there when due
+        assert!(limiter.acquire().await);
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closing_ends_the_wait() {
+        let cancel = CancellationToken::new();
+        let limiter = RateLimiter::new(1).closed_by(cancel.clone());
+        assert!(limiter.acquire().await);
+        let start = Instant::now();
+        let (acquired, ()) = tokio::join!(limiter.acquire(), async {
+            sleep(Duration::from_millis(300)).await;
+            cancel.cancel();
+        });
+        assert!(!acquired);
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+        assert!(!limiter.acquire().await);
+    }
+}
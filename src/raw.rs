@@ -0,0 +1,223 @@
+use crate::rt::Instant;
+use crate::{
+    events::{EventSink, KnockTarget},
+    observer::{AttemptInfo, AttemptResult},
+    outcome::KnockOutcome,
+    packet::{self, TcpFlags, IPPROTO_RAW, IPPROTO_TCP, IPPROTO_UDP},
+    pcap::PcapWriter,
+    protocol::Protocol,
+    AppError,
+};
+use rand::{rngs::ThreadRng, RngCore};
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::SystemTime;
+
+/// Send a single crafted TCP segment (e.g. a bare SYN or FIN) to `target`
+/// without completing a handshake, from `spoof` if given. Needs
+/// CAP_NET_RAW.
+pub(crate) async fn knock_tcp_raw(
+    host: &str,
+    target: SocketAddr,
+    flags: TcpFlags,
+    spoof: Option<IpAddr>,
+    pcap: Option<&PcapWriter>,
+    events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    let port = target.port();
+    let start = Instant::now();
+
+    let src_ip = match spoof {
+        Some(ip) => ip,
+        None => source_ip_for(target)?,
+    };
+    let mut rng = ThreadRng::default();
+    let src = SocketAddr::new(src_ip, ephemeral_port(&mut rng));
+    let seg = packet::tcp_segment(src, target, flags.0, rng.next_u32(), &[]);
+
+    let sent_at = SystemTime::now();
+    match spoof {
+        Some(_) => send_spoofed(src, target, IPPROTO_TCP, &seg)?,
+        None => send_raw(target, &seg).map_err(raw_error)?,
+    }
+    let elapsed = start.elapsed();
+    if let Some(pcap) = pcap {
+        pcap.record_tcp(sent_at, src, target, flags.0);
+    }
+    events.attempt(AttemptInfo {
+        target: KnockTarget::new(host, port, Protocol::Tcp),
+        attempt: 1,
+        result: AttemptResult::Delivered {
+            latency: elapsed,
+            detail: format!("{flags} sent"),
+        },
+    });
+
+    Ok(KnockOutcome {
+        port,
+        protocol: Protocol::Tcp,
+        addr: Some(target),
+        source_port: None,
+        fwmark: None,
+        ttl: None,
+        payload_len: None,
+        attempts_allowed: None,
+        attempts: 1,
+        succeeded: true,
+        acknowledged: false,
+        latency: Some(elapsed),
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: Vec::new(),
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Send one UDP datagram to `target` from the spoofed address `spoof`,
+/// with the IP header crafted here. Nothing can come back to it, so the
+/// knock counts as delivered once it is sent. Needs CAP_NET_RAW.
+pub(crate) async fn knock_udp_spoofed(
+    host: &str,
+    target: SocketAddr,
+    payload: Option<&[u8]>,
+    spoof: IpAddr,
+    pcap: Option<&PcapWriter>,
+    events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    let port = target.port();
+    let start = Instant::now();
+    let payload = payload.unwrap_or_default();
+    let src = SocketAddr::new(spoof, ephemeral_port(&mut ThreadRng::default()));
+    let seg = packet::udp_segment(src, target, payload);
+
+    let sent_at = SystemTime::now();
+    send_spoofed(src, target, IPPROTO_UDP, &seg)?;
+    let elapsed = start.elapsed();
+    if let Some(pcap) = pcap {
+        pcap.record_udp(sent_at, src, target, payload);
+    }
+    events.attempt(AttemptInfo {
+        target: KnockTarget::new(host, port, Protocol::Udp),
+        attempt: 1,
+        result: AttemptResult::Delivered {
+            latency: elapsed,
+            detail: format!("sent from {spoof}"),
+        },
+    });
+
+    Ok(KnockOutcome {
+        port,
+        protocol: Protocol::Udp,
+        addr: Some(target),
+        source_port: None,
+        fwmark: None,
+        ttl: None,
+        payload_len: Some(payload.len()),
+        attempts_allowed: None,
+        attempts: 1,
+        succeeded: true,
+        acknowledged: false,
+        latency: Some(elapsed),
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: Vec::new(),
+        elapsed: start.elapsed(),
+    })
+}
+
+/// A source port from the usual ephemeral range.
+fn ephemeral_port(rng: &mut ThreadRng) -> u16 {
+    32768 + (rng.next_u32() % (61000 - 32768)) as u16
+}
+
+/// Ask the kernel which local address routes to `target` (no packet is sent).
+fn source_ip_for(target: SocketAddr) -> io::Result<IpAddr> {
+    let bind = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let probe = UdpSocket::bind(bind)?;
+    probe.connect(target)?;
+    Ok(probe.local_addr()?.ip())
+}
+
+/// Send a pre-built TCP segment on a raw IPPROTO_TCP socket; the kernel
+/// supplies the IP header.
+fn send_raw(target: SocketAddr, segment: &[u8]) -> io::Result<()> {
+    let domain = match target {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::RAW, Some(i32::from(IPPROTO_TCP).into()))?;
+    // Raw sockets take the port from the segment, not the address
+    let dst = SocketAddr::new(target.ip(), 0);
+    socket.send_to(segment, &dst.into())?;
+    Ok(())
+}
+
+/// Send a transport segment from `src` inside an IP header built here, on
+/// an IPPROTO_RAW socket (which implies IP_HDRINCL) so the kernel keeps the
+/// spoofed source.
+fn send_spoofed(
+    src: SocketAddr,
+    target: SocketAddr,
+    proto: u8,
+    segment: &[u8],
+) -> Result<(), AppError> {
+    let packet = packet::ip_packet(src.ip(), target.ip(), proto, segment).ok_or_else(|| {
+        AppError::RawSocket(format!(
+            "spoofed source {} and target {} are not the same IP version",
+            src.ip(),
+            target.ip()
+        ))
+    })?;
+    let domain = match target {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let send = || -> io::Result<()> {
+        let socket = Socket::new(domain, Type::RAW, Some(i32::from(IPPROTO_RAW).into()))?;
+        socket.send_to(&packet, &SocketAddr::new(target.ip(), 0).into())?;
+        Ok(())
+    };
+    send().map_err(|e| spoof_error(e, src.ip()))
+}
+
+/// Explain why a spoofed packet could not be sent instead of passing on
+/// the bare errno.
+fn spoof_error(e: io::Error, src: IpAddr) -> AppError {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => AppError::RawSocket(format!(
+            "cannot send from spoofed source {src}: crafting IP headers needs CAP_NET_RAW \
+             (run as root or `setcap cap_net_raw+ep` on the binary), and a firewall \
+             OUTPUT rule dropping the packet fails the same way ({e})"
+        )),
+        io::ErrorKind::AddrNotAvailable | io::ErrorKind::InvalidInput => {
+            AppError::RawSocket(format!(
+                "the kernel refused spoofed source {src} ({e}); IPv6 raw sockets may not \
+                 allow a crafted header on this system, and a source that is multicast, \
+                 broadcast or unspecified is never accepted"
+            ))
+        }
+        _ => AppError::RawSocket(format!(
+            "cannot send from spoofed source {src}: {e}; note that even a sent packet may \
+             be dropped by reverse-path filtering (rp_filter) on routers or the target, \
+             or by egress filtering of your network, when {src} is not routed through it"
+        )),
+    }
+}
+
+/// Turn a permission failure into actionable guidance.
+fn raw_error(e: io::Error) -> AppError {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        AppError::RawSocket(
+            "raw sockets need CAP_NET_RAW; run as root or `setcap cap_net_raw+ep` on the binary"
+                .into(),
+        )
+    } else {
+        AppError::Io(e)
+    }
+}
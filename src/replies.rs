@@ -0,0 +1,116 @@
+//! UDP replies written to files for offline analysis (`--save-replies`).
+//!
+//! Each reply that acknowledged a knock goes to its own file in the
+//! directory, named `KNOCK-PORT-UNIXMILLIS.bin` after the knock's index in
+//! the sequence (from 0), the knocked port and when it was saved, e.g.
+//! `002-9000-1760600000123.bin`. A reply longer than [`FILE_LIMIT`] is cut
+//! short, and once [`TOTAL_LIMIT`] bytes were written in a run no more
+//! replies are saved.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most bytes kept of one reply.
+pub const FILE_LIMIT: usize = 64 * 1024;
+
+/// Most bytes written for all the replies of a run.
+pub const TOTAL_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Writes the replies of a run into one directory.
+#[derive(Debug)]
+pub struct ReplySaver {
+    dir: PathBuf,
+    /// Bytes written so far.
+    written: Mutex<u64>,
+}
+
+/// A reply written to a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedReply {
+    pub path: PathBuf,
+    /// Bytes written, at most [`FILE_LIMIT`].
+    pub bytes: usize,
+    /// The reply was longer than what was written.
+    pub truncated: bool,
+}
+
+impl ReplySaver {
+    /// Use `dir`, creating it when missing, after checking that files can
+    /// be written there.
+    pub fn create(dir: &Path) -> Result<Self, String> {
+        let cannot = |e: io::Error| format!("cannot write replies to {}: {e}", dir.display());
+        fs::create_dir_all(dir).map_err(cannot)?;
+        let probe = dir.join(format!(".probe-{}", std::process::id()));
+        File::create(&probe).map_err(cannot)?;
+        let _ = fs::remove_file(&probe);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            written: Mutex::new(0),
+        })
+    }
+
+    /// Write the reply to knock `index` on `port`; `None` once the run's
+    /// total is used up.
+    pub fn save(&self, index: usize, port: u16, reply: &[u8]) -> io::Result<Option<SavedReply>> {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        let left = usize::try_from(TOTAL_LIMIT.saturating_sub(*written)).unwrap_or(usize::MAX);
+        if left == 0 {
+            return Ok(None);
+        }
+        let kept = &reply[..reply.len().min(FILE_LIMIT).min(left)];
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let path = self.dir.join(format!("{index:03}-{port}-{millis}.bin"));
+        File::create(&path)?.write_all(kept)?;
+        *written += kept.len() as u64;
+        Ok(Some(SavedReply {
+            path,
+            bytes: kept.len(),
+            truncated: kept.len() < reply.len(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_are_named_and_capped() {
+        let dir = std::env::temp_dir().join(format!("apk-replies-{}", std::process::id()));
+        let saver = ReplySaver::create(&dir.join("nested")).unwrap();
+        let saved = saver.save(2, 9000, b"pong").unwrap().unwrap();
+        let name = saved
+            .path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert!(
+            name.starts_with("002-9000-") && name.ends_with(".bin"),
+            "{name}"
+        );
+        assert_eq!(fs::read(&saved.path).unwrap(), b"pong");
+        assert!(!saved.truncated);
+
+        let big = vec![0xab; FILE_LIMIT + 1];
+        let saved = saver.save(3, 9000, &big).unwrap().unwrap();
+        assert_eq!((saved.bytes, saved.truncated), (FILE_LIMIT, true));
+
+        // Nothing more once the run's total is reached
+        *saver.written.lock().unwrap() = TOTAL_LIMIT;
+        assert_eq!(saver.save(4, 9000, b"late").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unwritable_directory_is_named() {
+        let err = ReplySaver::create(Path::new("/proc/apk-replies")).unwrap_err();
+        assert!(err.contains("/proc/apk-replies"), "{err}");
+    }
+}
@@ -1,37 +1,571 @@
+use crate::budget::RetryBudget;
+use crate::ratelimit::RateLimiter;
+use crate::rt::{sleep, timeout, Instant};
+use rand::Rng;
+use std::fmt;
 use std::future::Future;
-use tokio::time::{sleep, timeout, Duration};
+use std::time::Duration;
+
+/// How long [`retry_with_backoff`] waits after a failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackoffPolicy {
+    /// The same wait after every attempt.
+    Constant(Duration),
+    /// `base`, then `factor` times longer after each attempt, up to `max`.
+    Exponential {
+        base: Duration,
+        factor: u32,
+        max: Duration,
+    },
+    /// [`BackoffPolicy::Exponential`] with each wait drawn uniformly from
+    /// zero up to the exponential one, so clients retrying together spread
+    /// out.
+    ExponentialWithJitter {
+        base: Duration,
+        factor: u32,
+        max: Duration,
+    },
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy::Constant(Duration::from_millis(100))
+    }
+}
+
+impl BackoffPolicy {
+    /// The wait after failed attempt `attempt` (1-based).
+    pub fn delay_for(&self, attempt: usize) -> Duration {
+        let ceiling = self.max_delay_for(attempt);
+        match self {
+            BackoffPolicy::ExponentialWithJitter { .. } => {
+                let nanos = u64::try_from(ceiling.as_nanos()).unwrap_or(u64::MAX);
+                Duration::from_nanos(rand::rng().random_range(0..=nanos))
+            }
+            _ => ceiling,
+        }
+    }
+
+    /// The longest wait [`BackoffPolicy::delay_for`] can return for
+    /// `attempt`, which is the wait itself without jitter.
+    pub fn max_delay_for(&self, attempt: usize) -> Duration {
+        match *self {
+            BackoffPolicy::Constant(delay) => delay,
+            BackoffPolicy::Exponential { base, factor, max }
+            | BackoffPolicy::ExponentialWithJitter { base, factor, max } => {
+                let exponent = u32::try_from(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+                let growth = factor.checked_pow(exponent).unwrap_or(u32::MAX);
+                base.checked_mul(growth).unwrap_or(max).min(max)
+            }
+        }
+    }
+}
+
+/// How many attempts [`retry_with_backoff`] may make, the first included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attempts {
+    /// At most this many; `Finite(0)` makes none.
+    Finite(usize),
+    /// Keep going until an attempt is done, the deadline passes or the
+    /// rate limiter is closed.
+    Unlimited,
+}
+
+impl Default for Attempts {
+    fn default() -> Self {
+        Attempts::Finite(1)
+    }
+}
+
+impl Attempts {
+    /// Whether attempt `attempt` (1-based) may be made.
+    pub fn allows(self, attempt: usize) -> bool {
+        match self {
+            Attempts::Finite(n) => attempt <= n,
+            Attempts::Unlimited => true,
+        }
+    }
+
+    /// The attempt count when there is one.
+    pub fn finite(self) -> Option<usize> {
+        match self {
+            Attempts::Finite(n) => Some(n),
+            Attempts::Unlimited => None,
+        }
+    }
+}
+
+impl fmt::Display for Attempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attempts::Finite(n) => write!(f, "{n}"),
+            Attempts::Unlimited => f.write_str("unlimited"),
+        }
+    }
+}
+
+/// Serialized as the count, or `"unlimited"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Attempts {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Attempts::Finite(n) => serializer.serialize_u64(*n as u64),
+            Attempts::Unlimited => serializer.serialize_str("unlimited"),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Attempts {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Count {
+            Finite(usize),
+            Named(String),
+        }
+        match Count::deserialize(deserializer)? {
+            Count::Finite(n) => Ok(Attempts::Finite(n)),
+            Count::Named(name) if name == "unlimited" => Ok(Attempts::Unlimited),
+            Count::Named(name) => Err(serde::de::Error::custom(format!(
+                "'{name}' is not a number of attempts or unlimited"
+            ))),
+        }
+    }
+}
+
+/// What one attempt of a [`retry_with_backoff`] loop decided.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetryDecision<T, E> {
+    /// The attempt got through; stop and hand back its value.
+    Done(T),
+    /// The attempt failed but another may work; back off and try again.
+    Retry,
+    /// The attempt failed in a way no retry can fix; stop at once with
+    /// this error.
+    Fatal(E),
+}
+
+/// How a [`retry_with_backoff`] loop ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryOutcome<T> {
+    /// The value of the attempt that was done; `None` when every attempt
+    /// failed or timed out.
+    pub value: Option<T>,
+    /// Attempts made, including the successful one.
+    pub attempts: usize,
+    /// Wall time of all attempts and backoff sleeps.
+    pub total_elapsed: Duration,
+    /// Attempts were left but the overall deadline stopped them.
+    pub deadline_limited: bool,
+    /// Attempts were left but the rate limiter was closed while the next
+    /// one waited for its turn.
+    pub rate_closed: bool,
+    /// Attempts were left but the run's retry budget was spent.
+    pub budget_spent: bool,
+}
+
+impl<T> RetryOutcome<T> {
+    /// Whether an attempt was done.
+    pub fn succeeded(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+/// Adapt a synchronous timeout callback for [`retry_with_backoff`].
+pub fn sync_on_timeout<F>(mut f: F) -> impl FnMut(usize, Duration) -> std::future::Ready<()>
+where
+    F: FnMut(usize, Duration),
+{
+    move |attempt, elapsed| {
+        f(attempt, elapsed);
+        std::future::ready(())
+    }
+}
 
 /// generic async retry helper with timeout and backoff.
-pub async fn retry_with_backoff<F, Fut, E, TCB>(
-    retries: usize,
+///
+/// Each attempt of `operation` returns a [`RetryDecision`]; a fatal one
+/// aborts the loop without backing off and its error is returned as is.
+/// Running out of `attempts` is not an error but an outcome without a
+/// value; with [`Attempts::Unlimited`] only a success, `max_elapsed` or a
+/// closed `rate` limiter ends the loop, or dropping its future. With
+/// `max_elapsed`, no attempt is started and no backoff slept
+/// that would begin or end past it; an attempt already running keeps its
+/// own timeout.
+///
+/// With `rate`, each attempt first waits for a token, outside its timeout;
+/// once the limiter is closed no further attempt is made.
+///
+/// With `budget`, each attempt takes one from it; once it is spent the
+/// first attempt is still made, but no retry.
+///
+/// `on_timeout` is awaited with the attempt number and how long the attempt
+/// ran whenever one times out; wrap a plain closure in [`sync_on_timeout`].
+#[allow(clippy::too_many_arguments)]
+pub async fn retry_with_backoff<F, Fut, T, E, TCB, TFut>(
+    attempts: Attempts,
     timeout_ms: u64,
-    backoff_ms: u64,
+    backoff: &BackoffPolicy,
+    max_elapsed: Option<Duration>,
+    rate: Option<&RateLimiter>,
+    budget: Option<&RetryBudget>,
     mut operation: F,
     mut on_timeout: TCB,
-) -> Result<(), E>
+) -> Result<RetryOutcome<T>, E>
 where
     F: FnMut(usize) -> Fut,
-    Fut: Future<Output = Result<bool, E>>,
-    TCB: FnMut(usize),
+    Fut: Future<Output = RetryDecision<T, E>>,
+    TCB: FnMut(usize, Duration) -> TFut,
+    TFut: Future<Output = ()>,
 {
-    for attempt in 1..=retries {
-        match timeout(Duration::from_millis(timeout_ms), operation(attempt)).await {
-            Ok(Ok(done)) => {
-                if done {
-                    return Ok(());
-                }
+    let started = Instant::now();
+    let outcome = |value, attempts, deadline_limited| RetryOutcome {
+        value,
+        attempts,
+        total_elapsed: started.elapsed(),
+        deadline_limited,
+        rate_closed: false,
+        budget_spent: false,
+    };
+    let past_deadline =
+        |wait: Duration| max_elapsed.is_some_and(|max| started.elapsed() + wait >= max);
+    let mut attempt = 0;
+    while attempts.allows(attempt + 1) {
+        attempt += 1;
+        if past_deadline(Duration::ZERO) {
+            return Ok(outcome(None, attempt - 1, true));
+        }
+        if budget.is_some_and(|budget| !budget.take()) && attempt > 1 {
+            return Ok(RetryOutcome {
+                budget_spent: true,
+                ..outcome(None, attempt - 1, false)
+            });
+        }
+        if let Some(rate) = rate {
+            if !rate.acquire().await {
+                return Ok(RetryOutcome {
+                    rate_closed: true,
+                    ..outcome(None, attempt - 1, false)
+                });
             }
-            Ok(Err(e)) => return Err(e),
+        }
+        let attempt_started = Instant::now();
+        match timeout(Duration::from_millis(timeout_ms), operation(attempt)).await {
+            Ok(RetryDecision::Done(value)) => return Ok(outcome(Some(value), attempt, false)),
+            Ok(RetryDecision::Retry) => {}
+            Ok(RetryDecision::Fatal(e)) => return Err(e),
             Err(_) => {
                 // Timed out before operation completed
-                on_timeout(attempt);
+                on_timeout(attempt, attempt_started.elapsed()).await;
+            }
+        }
+
+        // If we're going to retry, wait the backoff interval, unless the
+        // next attempt would start past the deadline anyway
+        if attempts.allows(attempt + 1) {
+            // No retry is left to back off for
+            if budget.is_some_and(RetryBudget::is_spent) {
+                return Ok(RetryOutcome {
+                    budget_spent: true,
+                    ..outcome(None, attempt, false)
+                });
+            }
+            let wait = backoff.delay_for(attempt);
+            if past_deadline(wait) {
+                return Ok(outcome(None, attempt, true));
             }
+            sleep(wait).await;
+        }
+    }
+    Ok(outcome(None, attempt, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn succeeds_on_the_last_attempt() {
+        let mut calls = 0;
+        let Ok(outcome) = retry_with_backoff(
+            Attempts::Finite(3),
+            100,
+            &BackoffPolicy::Constant(Duration::from_millis(10)),
+            None,
+            None,
+            None,
+            |attempt| {
+                calls += 1;
+                let done = calls == 3;
+                async move {
+                    if done {
+                        RetryDecision::<_, Infallible>::Done(format!("reply {attempt}"))
+                    } else {
+                        RetryDecision::Retry
+                    }
+                }
+            },
+            sync_on_timeout(|_, _| {}),
+        )
+        .await;
+        assert_eq!(calls, 3);
+        assert_eq!(outcome.value.as_deref(), Some("reply 3"));
+        assert_eq!(outcome.attempts, 3);
+        // Two backoff sleeps before the last attempt
+        assert!(outcome.total_elapsed >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn never_succeeding_is_exhaustion() {
+        let mut timeouts = Vec::new();
+        let Ok(outcome) = retry_with_backoff(
+            Attempts::Finite(2),
+            20,
+            &BackoffPolicy::Constant(Duration::ZERO),
+            None,
+            None,
+            None,
+            |attempt| async move {
+                if attempt == 1 {
+                    sleep(Duration::from_millis(100)).await;
+                }
+                RetryDecision::<(), Infallible>::Retry
+            },
+            sync_on_timeout(|attempt, _| timeouts.push(attempt)),
+        )
+        .await;
+        assert!(!outcome.succeeded());
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(timeouts, [1]);
+    }
+
+    #[tokio::test]
+    async fn fatal_stops_without_backing_off() {
+        let mut attempts = 0;
+        let started = Instant::now();
+        let err = retry_with_backoff(
+            Attempts::Finite(3),
+            100,
+            &BackoffPolicy::Constant(Duration::from_secs(1)),
+            None,
+            None,
+            None,
+            |attempt| {
+                attempts = attempt;
+                async move {
+                    match attempt {
+                        1 => RetryDecision::Fatal("permission denied"),
+                        _ => RetryDecision::Done(()),
+                    }
+                }
+            },
+            sync_on_timeout(|_, _| {}),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err, "permission denied");
+        assert_eq!(attempts, 1);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn exponential_backoff_grows_up_to_the_cap() {
+        let policy = BackoffPolicy::Exponential {
+            base: Duration::from_millis(100),
+            factor: 2,
+            max: Duration::from_millis(1000),
+        };
+        let delays: Vec<u128> = (1..=6).map(|a| policy.delay_for(a).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        // No overflow far past the cap
+        assert_eq!(policy.delay_for(usize::MAX), Duration::from_millis(1000));
+
+        let constant = BackoffPolicy::Constant(Duration::from_millis(150));
+        assert_eq!(constant.delay_for(1), constant.delay_for(5));
+    }
+
+    #[test]
+    fn jitter_stays_under_the_exponential_delay() {
+        let policy = BackoffPolicy::ExponentialWithJitter {
+            base: Duration::from_millis(100),
+            factor: 3,
+            max: Duration::from_millis(500),
+        };
+        assert_eq!(policy.max_delay_for(2), Duration::from_millis(300));
+        for attempt in 1..=5 {
+            let ceiling = policy.max_delay_for(attempt);
+            assert!((0..50).all(|_| policy.delay_for(attempt) <= ceiling));
         }
+        // Draws actually vary
+        let draws: std::collections::HashSet<Duration> =
+            (0..50).map(|_| policy.delay_for(3)).collect();
+        assert!(draws.len() > 1);
+    }
+
+    /// Attempt that always times out after `timeout_ms`, counting calls.
+    async fn deadline_run(
+        attempts: Attempts,
+        timeout_ms: u64,
+        backoff_ms: u64,
+        max_elapsed: u64,
+    ) -> (RetryOutcome<()>, Duration) {
+        let started = Instant::now();
+        let Ok(outcome) = retry_with_backoff(
+            attempts,
+            timeout_ms,
+            &BackoffPolicy::Constant(Duration::from_millis(backoff_ms)),
+            Some(Duration::from_millis(max_elapsed)),
+            None,
+            None,
+            |_| async { std::future::pending::<RetryDecision<(), Infallible>>().await },
+            sync_on_timeout(|_, _| {}),
+        )
+        .await;
+        (outcome, started.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_stops_further_attempts() {
+        // 1000ms attempts with 500ms backoff: attempt 2 starts at 1500ms
+        // and attempt 3 would start at 3000ms, past the deadline
+        let (outcome, elapsed) = deadline_run(Attempts::Finite(10), 1000, 500, 2800).await;
+        assert!(!outcome.succeeded() && outcome.deadline_limited);
+        assert_eq!(outcome.attempts, 2);
+        // The last backoff is not slept
+        assert_eq!(elapsed, Duration::from_millis(2500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_checked_before_each_attempt() {
+        // The first attempt already runs past the deadline
+        let (outcome, elapsed) = deadline_run(Attempts::Finite(3), 1000, 0, 500).await;
+        assert!(outcome.deadline_limited);
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(elapsed, Duration::from_millis(1000));
+
+        // A deadline that is never reached changes nothing
+        let (outcome, elapsed) = deadline_run(Attempts::Finite(3), 100, 50, 10_000).await;
+        assert!(!outcome.deadline_limited);
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(elapsed, Duration::from_millis(400));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_attempts_make_none() {
+        let (outcome, elapsed) = deadline_run(Attempts::Finite(0), 100, 50, 10_000).await;
+        assert_eq!(outcome.attempts, 0);
+        assert!(!outcome.succeeded() && !outcome.deadline_limited);
+        assert_eq!(elapsed, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_attempts_run_to_the_deadline() {
+        // An attempt every 150ms; the seventh ends at 1000ms, the deadline
+        let (outcome, elapsed) = deadline_run(Attempts::Unlimited, 100, 50, 1000).await;
+        assert!(!outcome.succeeded() && outcome.deadline_limited);
+        assert_eq!(outcome.attempts, 7);
+        assert_eq!(elapsed, Duration::from_millis(1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_attempts_stop_on_success_or_close() {
+        let Ok(outcome) = retry_with_backoff(
+            Attempts::Unlimited,
+            100,
+            &BackoffPolicy::Constant(Duration::from_millis(10)),
+            None,
+            None,
+            None,
+            |attempt| async move {
+                match attempt {
+                    50 => RetryDecision::<_, Infallible>::Done(attempt),
+                    _ => RetryDecision::Retry,
+                }
+            },
+            sync_on_timeout(|_, _| {}),
+        )
+        .await;
+        assert_eq!(outcome.value, Some(50));
+
+        // With no deadline, closing the limiter is what ends the loop
+        let rate = RateLimiter::new(10);
+        let (outcome, ()) = tokio::join!(
+            retry_with_backoff(
+                Attempts::Unlimited,
+                100,
+                &BackoffPolicy::Constant(Duration::ZERO),
+                None,
+                Some(&rate),
+                None,
+                |_| async { RetryDecision::<(), Infallible>::Retry },
+                sync_on_timeout(|_, _| {}),
+            ),
+            async {
+                sleep(Duration::from_millis(250)).await;
+                rate.close();
+            }
+        );
+        let Ok(outcome) = outcome;
+        assert!(outcome.rate_closed && !outcome.deadline_limited);
+        // Tokens at 0, 100 and 200ms
+        assert_eq!(outcome.attempts, 3);
+    }
 
-        // If we're going to retry, wait the backoff interval
-        if attempt < retries {
-            sleep(Duration::from_millis(backoff_ms)).await;
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_are_reported_asynchronously() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let Ok(outcome) = retry_with_backoff(
+            Attempts::Finite(2),
+            300,
+            &BackoffPolicy::Constant(Duration::ZERO),
+            None,
+            None,
+            None,
+            |_| async { std::future::pending::<RetryDecision<(), Infallible>>().await },
+            |attempt, elapsed| {
+                let tx = tx.clone();
+                async move { tx.send((attempt, elapsed)).await.unwrap() }
+            },
+        )
+        .await;
+        assert_eq!(outcome.attempts, 2);
+        drop(tx);
+        let mut reports = Vec::new();
+        while let Some(report) = rx.recv().await {
+            reports.push(report);
         }
+        assert_eq!(
+            reports,
+            [
+                (1, Duration::from_millis(300)),
+                (2, Duration::from_millis(300))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn spent_budget_leaves_one_attempt_per_knock() {
+        let budget = RetryBudget::new(3, crate::protocol::BudgetExhausted::Single);
+        let knock = || {
+            retry_with_backoff(
+                Attempts::Finite(5),
+                100,
+                &BackoffPolicy::Constant(Duration::ZERO),
+                None,
+                None,
+                Some(&budget),
+                |_| async { RetryDecision::<(), Infallible>::Retry },
+                sync_on_timeout(|_, _| {}),
+            )
+        };
+        // The first knock spends the budget in three of its five attempts
+        let Ok(first) = knock().await;
+        assert_eq!((first.attempts, first.budget_spent), (3, true));
+        // Later ones still get their first
+        let Ok(second) = knock().await;
+        assert_eq!((second.attempts, second.budget_spent), (1, true));
+        assert_eq!(budget.report().spent, 3);
     }
-    Ok(())
 }
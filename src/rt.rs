@@ -0,0 +1,453 @@
+//! The async runtime the knocks run on.
+//!
+//! Timers, sockets, name lookups and blocking calls all go through here, so
+//! the rest of the crate does not care what drives it: Tokio with the
+//! `runtime-tokio` feature (the default), or async-io, the reactor smol and
+//! async-std run on, with `runtime-smol`. With both, Tokio is used.
+//! Tokio's channels, locks and `select!` need no runtime and are used with
+//! either.
+//!
+//! Without Tokio the library runs on any executor and brings no runtime of
+//! its own, but what only makes sense in a process Tokio drives is left
+//! out: the binary, the listen mode, signal handling (cancel the run's
+//! token instead), `run_with_events` and the features built on those.
+
+use std::io;
+use std::net::SocketAddr;
+
+#[cfg(not(any(feature = "runtime-tokio", feature = "runtime-smol")))]
+compile_error!("enable one of the `runtime-tokio` and `runtime-smol` features");
+
+#[cfg(feature = "runtime-tokio")]
+pub use self::tokio_rt::*;
+
+#[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+pub use self::smol_rt::*;
+
+/// Look `host` up for connecting to `port` on it.
+pub(crate) async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    lookup(host, port).await
+}
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio_rt {
+    use std::io;
+    use std::net::SocketAddr;
+
+    pub use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+    pub(crate) use tokio::task::yield_now;
+    pub use tokio::time::error::Elapsed;
+    pub use tokio::time::Instant;
+    pub(crate) use tokio::time::{sleep, sleep_until, timeout};
+
+    pub(super) async fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+
+    /// Run `f` on a thread that may block, e.g. to read from the terminal.
+    pub(crate) async fn spawn_blocking<T: Send + 'static>(
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> io::Result<T> {
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    /// Receive a datagram, also waking on a pending socket error such as
+    /// an ICMP port-unreachable, which a plain `recv` only reports on the
+    /// next send.
+    pub(crate) async fn recv_or_error(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        tokio::select! {
+            res = socket.recv_from(buf) => res,
+            ready = socket.ready(tokio::io::Interest::ERROR) => {
+                ready?;
+                match socket.take_error()? {
+                    Some(e) => Err(e),
+                    None => socket.recv_from(buf).await,
+                }
+            }
+        }
+    }
+
+    /// Wait for the non-blocking connect of `socket` to finish, then
+    /// report how it went.
+    #[cfg(unix)]
+    pub(crate) async fn connected(socket: socket2::Socket) -> io::Result<socket2::Socket> {
+        let fd = tokio::io::unix::AsyncFd::new(socket)?;
+        let _ = fd.writable().await?;
+        let socket = fd.into_inner();
+        match socket.take_error()? {
+            Some(e) => Err(e),
+            None => Ok(socket),
+        }
+    }
+
+    /// Drive `future` to completion on a fresh runtime. Blocking a runtime
+    /// thread would stall its other tasks (and Tokio panics on nested
+    /// runtimes), so a call from inside one is refused with `None`.
+    #[cfg(any(feature = "blocking", target_os = "linux"))]
+    pub(crate) fn block_on<F: std::future::Future>(future: F) -> io::Result<Option<F::Output>> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Ok(None);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Some(runtime.block_on(future)))
+    }
+}
+
+#[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+mod smol_rt {
+    use async_io::{Async, Timer};
+    use futures::channel::oneshot;
+    use futures::future::{self, Either};
+    use futures::io::{AsyncRead as _, AsyncWrite as _};
+    use socket2::{Domain, SockAddr, Socket, Type};
+    use std::fmt;
+    use std::future::Future;
+    use std::io;
+    use std::net::{self, SocketAddr, ToSocketAddrs};
+    use std::pin::{pin, Pin};
+    use std::task::{ready, Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    pub use std::time::Instant;
+
+    /// A [`timeout`] that ran out.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Elapsed(());
+
+    impl fmt::Display for Elapsed {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("deadline has elapsed")
+        }
+    }
+
+    impl std::error::Error for Elapsed {}
+
+    pub(crate) async fn sleep(duration: Duration) {
+        Timer::after(duration).await;
+    }
+
+    pub(crate) async fn sleep_until(deadline: Instant) {
+        Timer::at(deadline).await;
+    }
+
+    pub(crate) async fn timeout<F: Future>(
+        duration: Duration,
+        future: F,
+    ) -> Result<F::Output, Elapsed> {
+        match future::select(pin!(future), Timer::after(duration)).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Elapsed(())),
+        }
+    }
+
+    /// Let the executor run other tasks before going on.
+    pub(crate) async fn yield_now() {
+        let mut yielded = false;
+        future::poll_fn(|cx| match std::mem::replace(&mut yielded, true) {
+            true => Poll::Ready(()),
+            false => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    pub(super) async fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let host = host.to_string();
+        spawn_blocking(move || {
+            (host.as_str(), port)
+                .to_socket_addrs()
+                .map(Iterator::collect)
+        })
+        .await?
+    }
+
+    /// Run `f` on a thread of its own, which may block, e.g. to read from
+    /// the terminal.
+    pub(crate) async fn spawn_blocking<T: Send + 'static>(
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> io::Result<T> {
+        let (tx, rx) = oneshot::channel();
+        std::thread::Builder::new()
+            .name("async_port_knocker-blocking".into())
+            .spawn(move || {
+                let _ = tx.send(f());
+            })?;
+        rx.await
+            .map_err(|_| io::Error::other("blocking task panicked"))
+    }
+
+    /// A datagram is the first thing a socket with a pending error, such
+    /// as an ICMP port-unreachable, wakes up for, and receiving reports it.
+    pub(crate) async fn recv_or_error(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        socket.recv_from(buf).await
+    }
+
+    /// Wait for the non-blocking connect of `socket` to finish, then
+    /// report how it went.
+    pub(crate) async fn connected(socket: Socket) -> io::Result<Socket> {
+        let socket = Async::new(socket)?;
+        socket.writable().await?;
+        let socket = socket.into_inner()?;
+        match socket.take_error()? {
+            Some(e) => Err(e),
+            None => Ok(socket),
+        }
+    }
+
+    /// Drive `future` to completion on this thread.
+    #[cfg(any(feature = "blocking", target_os = "linux"))]
+    pub(crate) fn block_on<F: Future>(future: F) -> io::Result<Option<F::Output>> {
+        Ok(Some(async_io::block_on(future)))
+    }
+
+    /// Start a non-blocking connect of `socket` to `target`.
+    fn start_connect(socket: &Socket, target: SocketAddr) -> io::Result<()> {
+        match socket.connect(&SockAddr::from(target)) {
+            Ok(()) => Ok(()),
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => Ok(()),
+            // Windows says it would block instead
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Where [`TcpStream::connect`] dials: an address, or `HOST:PORT`,
+    /// looked up first.
+    pub trait ConnectTo {
+        fn into_name(self) -> Result<SocketAddr, String>;
+    }
+
+    impl ConnectTo for SocketAddr {
+        fn into_name(self) -> Result<SocketAddr, String> {
+            Ok(self)
+        }
+    }
+
+    impl ConnectTo for &str {
+        fn into_name(self) -> Result<SocketAddr, String> {
+            self.parse().map_err(|_| self.to_string())
+        }
+    }
+
+    /// A TCP connection.
+    #[derive(Debug)]
+    pub struct TcpStream(Async<net::TcpStream>);
+
+    impl TcpStream {
+        /// Connect to `addr`, trying each address a name has in turn.
+        pub async fn connect(addr: impl ConnectTo) -> io::Result<Self> {
+            let addrs = match addr.into_name() {
+                Ok(addr) => vec![addr],
+                Err(name) => {
+                    spawn_blocking(move || name.to_socket_addrs().map(Iterator::collect)).await??
+                }
+            };
+            let mut last = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+            for addr in addrs {
+                match Async::<net::TcpStream>::connect(addr).await {
+                    Ok(stream) => return Ok(Self(stream)),
+                    Err(e) => last = e,
+                }
+            }
+            Err(last)
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.get_ref().local_addr()
+        }
+
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.0.get_ref().peer_addr()
+        }
+
+        pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.0.get_ref().set_nodelay(nodelay)
+        }
+    }
+
+    impl AsyncRead for TcpStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let n = ready!(Pin::new(&mut self.0).poll_read(cx, buf.initialize_unfilled()))?;
+            buf.advance(n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for TcpStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_close(cx)
+        }
+    }
+
+    /// A TCP socket set up before it connects, e.g. bound to a port.
+    #[derive(Debug)]
+    pub struct TcpSocket(Socket);
+
+    impl TcpSocket {
+        pub fn new_v4() -> io::Result<Self> {
+            Socket::new(Domain::IPV4, Type::STREAM, None).map(Self)
+        }
+
+        pub fn new_v6() -> io::Result<Self> {
+            Socket::new(Domain::IPV6, Type::STREAM, None).map(Self)
+        }
+
+        pub fn set_reuseaddr(&self, reuse: bool) -> io::Result<()> {
+            self.0.set_reuse_address(reuse)
+        }
+
+        pub fn bind(&self, addr: SocketAddr) -> io::Result<()> {
+            self.0.bind(&SockAddr::from(addr))
+        }
+
+        pub async fn connect(self, addr: SocketAddr) -> io::Result<TcpStream> {
+            self.0.set_nonblocking(true)?;
+            start_connect(&self.0, addr)?;
+            let socket = connected(self.0).await?;
+            Ok(TcpStream(Async::new(net::TcpStream::from(socket))?))
+        }
+    }
+
+    /// A UDP socket.
+    #[derive(Debug)]
+    pub struct UdpSocket(Async<net::UdpSocket>);
+
+    impl UdpSocket {
+        pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+            let mut last = io::Error::new(io::ErrorKind::InvalidInput, "no address to bind");
+            for addr in addr.to_socket_addrs()? {
+                match Async::<net::UdpSocket>::bind(addr) {
+                    Ok(socket) => return Ok(Self(socket)),
+                    Err(e) => last = e,
+                }
+            }
+            Err(last)
+        }
+
+        pub fn from_std(socket: net::UdpSocket) -> io::Result<Self> {
+            Async::new(socket).map(Self)
+        }
+
+        pub async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+            self.0.get_ref().connect(addr)
+        }
+
+        pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf).await
+        }
+
+        pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+            self.0.send_to(buf, target).await
+        }
+
+        pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf).await
+        }
+
+        pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf).await
+        }
+
+        pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.get_ref().recv_from(buf)
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.get_ref().local_addr()
+        }
+
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.0.get_ref().peer_addr()
+        }
+
+        pub fn set_broadcast(&self, on: bool) -> io::Result<()> {
+            self.0.get_ref().set_broadcast(on)
+        }
+
+        pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+            self.0.get_ref().take_error()
+        }
+    }
+
+    #[cfg(unix)]
+    mod fd {
+        use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+        impl AsFd for super::TcpStream {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                self.0.get_ref().as_fd()
+            }
+        }
+
+        impl AsFd for super::TcpSocket {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                self.0.as_fd()
+            }
+        }
+
+        impl AsRawFd for super::UdpSocket {
+            fn as_raw_fd(&self) -> RawFd {
+                self.0.get_ref().as_raw_fd()
+            }
+        }
+
+        impl AsFd for super::UdpSocket {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                self.0.get_ref().as_fd()
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    mod socket {
+        use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
+
+        impl AsSocket for super::TcpStream {
+            fn as_socket(&self) -> BorrowedSocket<'_> {
+                self.0.get_ref().as_socket()
+            }
+        }
+
+        impl AsRawSocket for super::UdpSocket {
+            fn as_raw_socket(&self) -> RawSocket {
+                self.0.get_ref().as_raw_socket()
+            }
+        }
+
+        impl AsSocket for super::UdpSocket {
+            fn as_socket(&self) -> BorrowedSocket<'_> {
+                self.0.get_ref().as_socket()
+            }
+        }
+    }
+}
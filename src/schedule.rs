@@ -0,0 +1,283 @@
+//! Knocking on a cron schedule.
+//!
+//! [`CronSchedule`] reads the usual five cron fields (minute, hour, day
+//! of month, month, day of week, with Sunday as 0 or 7) and
+//! [`run_on_schedule`] sleeps until each fire time in local time, runs the
+//! whole sequence, and starts over until cancelled. A run that overruns
+//! the next fire times skips them rather than knocking twice in a row.
+//! [`run_on_schedule_controlled`] also takes [`ScheduleCommand`]s between
+//! runs: a knock out of schedule, or a new configuration.
+
+use crate::{AppError, KnockConfig, KnockReport};
+use chrono::{DateTime, Local, TimeZone};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::sync::CancellationToken;
+
+/// A parsed cron expression.
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    expr: String,
+    schedule: cron::Schedule,
+}
+
+/// What [`run_on_schedule`] reports as it goes.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum ScheduleEvent {
+    /// Sleeping until the next fire time.
+    Waiting { next: DateTime<Local> },
+    /// Fire times that passed while the previous run was still going.
+    Missed { count: usize },
+    /// The sequence ran at its fire time.
+    Ran {
+        at: DateTime<Local>,
+        result: Result<KnockReport, AppError>,
+    },
+    /// A [`ScheduleCommand::Reload`] replaced the configuration.
+    Reloaded,
+}
+
+/// What a [`run_on_schedule_controlled`] can be told while it waits.
+pub enum ScheduleCommand {
+    /// Run the sequence now, without waiting for the next fire time; the
+    /// schedule goes on as before.
+    KnockNow,
+    /// Knock with this configuration from the next run on.
+    Reload(Box<KnockConfig>),
+}
+
+impl CronSchedule {
+    /// Parse five cron fields, or one of the `@hourly`, `@daily`,
+    /// `@weekly`, `@monthly` and `@yearly` shorthands.
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let expr = expr.trim();
+        let source = match expr.starts_with('@') {
+            true => expr.to_string(),
+            false => {
+                let fields: Vec<&str> = expr.split_whitespace().collect();
+                let [minute, hour, day, month, weekday] = fields[..] else {
+                    return Err(format!(
+                        "'{expr}' is not a cron expression: expected 5 fields \
+                         (minute hour day-of-month month day-of-week)"
+                    ));
+                };
+                // The parser counts seconds first and Sunday as day 1
+                let weekday = weekdays(weekday)?;
+                format!("0 {minute} {hour} {day} {month} {weekday}")
+            }
+        };
+        let schedule = cron::Schedule::from_str(&source)
+            .map_err(|e| format!("'{expr}' is not a cron expression: {e}"))?;
+        Ok(Self {
+            expr: expr.to_string(),
+            schedule,
+        })
+    }
+
+    /// The first fire time strictly after `after`.
+    pub fn next_after<Z: TimeZone>(&self, after: &DateTime<Z>) -> Option<DateTime<Z>> {
+        self.schedule.after(after).next()
+    }
+
+    /// Fire times after `last` up to and including `now`.
+    pub fn missed<Z: TimeZone>(&self, last: &DateTime<Z>, now: &DateTime<Z>) -> usize {
+        self.schedule.after(last).take_while(|t| t <= now).count()
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expr)
+    }
+}
+
+/// Rewrite a numeric day-of-week field (0-7, Sunday both 0 and 7) as the
+/// list of days the parser expects, Sunday being 1 there. Named days and
+/// `*` are the same in both and pass through.
+fn weekdays(field: &str) -> Result<String, String> {
+    if !field.bytes().any(|b| b.is_ascii_digit()) {
+        return Ok(field.to_string());
+    }
+    let invalid = || format!("'{field}' is not a day-of-week field of numbers 0-7");
+    let day = |s: &str| match s.parse::<u32>() {
+        Ok(day) if day <= 7 => Ok(day),
+        _ => Err(invalid()),
+    };
+    let mut days = BTreeSet::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(invalid()),
+            },
+            None => (item, 1),
+        };
+        let (first, last) = match range {
+            "*" => (0, 6),
+            range => match range.split_once('-') {
+                Some((first, last)) => (day(first)?, day(last)?),
+                None if step > 1 => (day(range)?, 6),
+                None => (day(range)?, day(range)?),
+            },
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        days.extend((first..=last).step_by(step as usize).map(|d| d % 7 + 1));
+    }
+    let days: Vec<String> = days.iter().map(u32::to_string).collect();
+    Ok(days.join(","))
+}
+
+/// Run `config` at every fire time of `schedule` until `cancel` is
+/// cancelled, which also stops a run in flight the way
+/// [`crate::run_with_cancel`] does. `on_event` hears of each wait, run and
+/// skipped fire time.
+pub async fn run_on_schedule(
+    config: KnockConfig,
+    schedule: &CronSchedule,
+    cancel: CancellationToken,
+    on_event: impl FnMut(ScheduleEvent),
+) -> Result<(), AppError> {
+    let (_commands, mut received) = tokio::sync::mpsc::unbounded_channel();
+    run_on_schedule_controlled(config, schedule, cancel, &mut received, on_event).await
+}
+
+/// [`run_on_schedule`], also doing what `commands` says while it waits for
+/// a fire time. A command sent during a run is carried out after it.
+pub async fn run_on_schedule_controlled(
+    mut config: KnockConfig,
+    schedule: &CronSchedule,
+    cancel: CancellationToken,
+    commands: &mut UnboundedReceiver<ScheduleCommand>,
+    mut on_event: impl FnMut(ScheduleEvent),
+) -> Result<(), AppError> {
+    config.validate()?;
+    let mut now = Local::now();
+    while let Some(next) = schedule.next_after(&now) {
+        on_event(ScheduleEvent::Waiting { next });
+        let wait = (next - Local::now()).to_std().unwrap_or_default();
+        // The last fire time this run stands in for
+        let last = tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(wait) => next,
+            Some(command) = commands.recv() => match command {
+                ScheduleCommand::KnockNow => Local::now(),
+                ScheduleCommand::Reload(reloaded) => {
+                    reloaded.validate()?;
+                    config = *reloaded;
+                    on_event(ScheduleEvent::Reloaded);
+                    continue;
+                }
+            },
+        };
+        let result = crate::run_with_cancel(config.clone(), cancel.clone()).await;
+        on_event(ScheduleEvent::Ran { at: last, result });
+        if cancel.is_cancelled() {
+            return Ok(());
+        }
+        now = Local::now();
+        let count = schedule.missed(&last, &now);
+        if count > 0 {
+            on_event(ScheduleEvent::Missed { count });
+        }
+    }
+    Err(AppError::InvalidConfig(format!(
+        "schedule '{schedule}' never fires again"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, Utc};
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // October 2026: the 16th is a Friday
+        NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn weekdays_skip_the_weekend() {
+        let schedule = CronSchedule::parse("55 8 * * 1-5").unwrap();
+        assert_eq!(schedule.next_after(&at(15, 9, 0)), Some(at(16, 8, 55)));
+        assert_eq!(schedule.next_after(&at(16, 9, 0)), Some(at(19, 8, 55)));
+        assert_eq!(schedule.to_string(), "55 8 * * 1-5");
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        for expr in ["0 12 * * 0", "0 12 * * 7", "0 12 * * SUN"] {
+            let schedule = CronSchedule::parse(expr).unwrap();
+            assert_eq!(
+                schedule.next_after(&at(16, 0, 0)),
+                Some(at(18, 12, 0)),
+                "{expr}"
+            );
+        }
+        assert_eq!(weekdays("5-7").unwrap(), "1,6,7");
+        assert_eq!(weekdays("*/2").unwrap(), "1,3,5,7");
+        assert_eq!(weekdays("1,3-4").unwrap(), "2,4,5");
+    }
+
+    #[test]
+    fn counts_missed_fire_times() {
+        let schedule = CronSchedule::parse("*/10 * * * *").unwrap();
+        assert_eq!(schedule.missed(&at(16, 8, 0), &at(16, 8, 5)), 0);
+        assert_eq!(schedule.missed(&at(16, 8, 0), &at(16, 8, 30)), 3);
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for expr in [
+            "55 8 * *",
+            "0 0 0 * * * *",
+            "61 * * * *",
+            "0 0 * * 8",
+            "0 0 * * 5-1",
+        ] {
+            assert!(CronSchedule::parse(expr).is_err(), "{expr}");
+        }
+        assert!(CronSchedule::parse("@daily").is_ok());
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_the_wait() {
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([7000])
+            .build()
+            .unwrap();
+        // At most once a year, so only cancelling ends the wait
+        let schedule = CronSchedule::parse("0 0 1 1 *").unwrap();
+        let cancel = CancellationToken::new();
+        let mut events = Vec::new();
+        let run = run_on_schedule(config, &schedule, cancel.clone(), |e| events.push(e));
+        let stop = async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            cancel.cancel();
+        };
+        let (result, ()) = tokio::time::timeout(std::time::Duration::from_secs(2), async {
+            tokio::join!(run, stop)
+        })
+        .await
+        .unwrap();
+        result.unwrap();
+        assert!(matches!(events[..], [ScheduleEvent::Waiting { .. }]));
+    }
+}
@@ -0,0 +1,235 @@
+use idna::AsciiDenyList;
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv6Addr, SocketAddrV6};
+
+/// Parse an IPv6 literal with a zone suffix (`fe80::1%eth0`, `fe80::1%2`)
+/// into an address whose scope ID is the interface index. The port is left
+/// at 0. Hosts without a `%` return `Ok(None)`.
+pub fn parse_scoped(host: &str) -> Result<Option<SocketAddrV6>, String> {
+    let Some((addr, zone)) = host.split_once('%') else {
+        return Ok(None);
+    };
+    let ip: Ipv6Addr = addr
+        .parse()
+        .map_err(|_| format!("'{addr}' is not an IPv6 address; only IPv6 takes a %interface"))?;
+    let scope_id = interface_index(zone)?;
+    Ok(Some(SocketAddrV6::new(ip, 0, 0, scope_id)))
+}
+
+/// Check a `--host` value: a zone must name a known interface, and a bare
+/// link-local literal needs one so the kernel can route it.
+pub fn validate_host(host: &str) -> Result<(), String> {
+    if parse_scoped(host)?.is_none() {
+        if let Ok(ip) = host.parse::<Ipv6Addr>() {
+            if is_link_local(&ip) {
+                return Err(format!(
+                    "link-local address '{host}' needs an interface, e.g. '{host}%eth0'"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check a `--host` value and return the host alone, as the resolver
+/// takes it: an IPv6 literal loses its brackets, and a `:port` pasted from
+/// a URL is refused, since knock ports belong in the sequence.
+///
+/// An IPv6 literal is read whole, so `::1:7000` is an address; a port after
+/// IPv6 needs brackets (`[::1]:7000`) and is refused like any other.
+pub fn parse_host(s: &str) -> Result<String, String> {
+    let no_port = |host: &str, port: &str| match port.parse::<u16>() {
+        Ok(_) => Err(format!(
+            "'{s}' has a port; give --host just '{host}' and put knock ports in the sequence"
+        )),
+        Err(_) if port.is_empty() => Err(format!("'{s}' ends in ':' without a port")),
+        Err(_) => Err(format!("'{port}' after '{host}' in '{s}' is not a port")),
+    };
+    let host = if let Some(rest) = s.strip_prefix('[') {
+        let Some((inner, after)) = rest.split_once(']') else {
+            return Err(format!("'{s}' is missing the closing ']'"));
+        };
+        if parse_scoped(inner)?.is_none() && inner.parse::<Ipv6Addr>().is_err() {
+            return Err(format!("'{inner}' in '{s}' is not an IPv6 address"));
+        }
+        match after {
+            "" => inner,
+            _ => match after.strip_prefix(':') {
+                Some(port) => return no_port(inner, port),
+                None => return Err(format!("unexpected '{after}' after ']' in '{s}'")),
+            },
+        }
+    } else if s.contains(']') {
+        return Err(format!("'{s}' has a ']' without its '['"));
+    } else if s.contains('%') || s.parse::<IpAddr>().is_ok() {
+        s
+    } else {
+        match s.split_once(':') {
+            None => s,
+            Some((host, port)) if !port.contains(':') => return no_port(host, port),
+            Some(_) => {
+                return Err(format!(
+                    "'{s}' is not an IPv6 address; a port after IPv6 needs brackets, as in '[::1]:7000'"
+                ))
+            }
+        }
+    };
+    if host.is_empty() {
+        return Err("no host given".into());
+    }
+    validate_host(host)?;
+    ascii_host(host)?;
+    Ok(host.to_string())
+}
+
+/// The name resolvers and servers are given for `host`: an IP literal as
+/// is, a host name without a single trailing dot and with its Unicode
+/// labels in punycode (`bücher.example.` is `xn--bcher-kva.example`). The
+/// host as given is what logs and reports show.
+pub fn ascii_host(host: &str) -> Result<Cow<'_, str>, String> {
+    if host.contains('%') || host.parse::<IpAddr>().is_ok() {
+        return Ok(Cow::Borrowed(host));
+    }
+    let name = host.strip_suffix('.').unwrap_or(host);
+    let mut labels = Vec::new();
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(format!("'{host}' has an empty label"));
+        }
+        let ascii = idna::domain_to_ascii_cow(label.as_bytes(), AsciiDenyList::URL)
+            .map_err(|_| format!("label '{label}' of '{host}' is not a valid host name"))?;
+        if ascii.len() > 63 {
+            return Err(format!(
+                "label '{label}' of '{host}' is longer than 63 characters"
+            ));
+        }
+        labels.push(ascii);
+    }
+    // No top-level domain is all digits, so this was meant as an address
+    if labels
+        .last()
+        .is_some_and(|l| l.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Err(format!("'{host}' is not a valid IPv4 address"));
+    }
+    let ascii = labels.join(".");
+    if ascii.len() > 253 {
+        return Err(format!("'{host}' is longer than 253 characters"));
+    }
+    Ok(match ascii == host {
+        true => Cow::Borrowed(host),
+        false => Cow::Owned(ascii),
+    })
+}
+
+/// Whether `ip` is in `fe80::/10`.
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Interface index for a zone given as a number or an interface name.
+fn interface_index(zone: &str) -> Result<u32, String> {
+    match zone.parse::<u32>() {
+        Ok(0) => Err("interface index must not be 0".into()),
+        Ok(index) => Ok(index),
+        Err(_) if zone.is_empty() => Err("missing interface after '%'".into()),
+        Err(_) => name_to_index(zone).ok_or_else(|| {
+            format!(
+                "unknown interface '{zone}'{}",
+                crate::interfaces::did_you_mean(zone)
+            )
+        }),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn name_to_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `name` is a valid NUL-terminated string for the whole call
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+/// Interface names are only resolved on Linux; elsewhere use the index.
+#[cfg(not(target_os = "linux"))]
+fn name_to_index(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn zone_becomes_scope_id() {
+        let addr = parse_scoped("fe80::1%3").unwrap().unwrap();
+        assert_eq!(addr.ip(), &"fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(addr.scope_id(), 3);
+        assert_eq!(parse_scoped("fe80::1").unwrap(), None);
+        assert_eq!(parse_scoped("knock.example").unwrap(), None);
+
+        assert!(parse_scoped("10.0.0.1%3").is_err());
+        assert!(parse_scoped("fe80::1%").is_err());
+        assert!(parse_scoped("fe80::1%0").is_err());
+        assert!(parse_scoped("fe80::1%no-such-if0").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn interface_name_is_resolved() {
+        let addr = parse_scoped("fe80::1%lo").unwrap().unwrap();
+        assert_ne!(addr.scope_id(), 0);
+    }
+
+    #[test]
+    fn scope_survives_setting_the_port() {
+        let mut target = SocketAddr::V6(parse_scoped("fe80::1%7").unwrap().unwrap());
+        target.set_port(7000);
+        match target {
+            SocketAddr::V6(v6) => {
+                assert_eq!(v6.port(), 7000);
+                assert_eq!(v6.scope_id(), 7);
+            }
+            SocketAddr::V4(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn host_names_become_ascii() {
+        let ascii = |host| ascii_host(host).map(Cow::into_owned);
+        assert_eq!(ascii("bücher.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(
+            ascii("xn--bcher-kva.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        // One trailing dot is dropped, and names are compared lowercase
+        assert_eq!(ascii("Example.COM.").unwrap(), "example.com");
+        assert!(matches!(ascii_host("knock.example"), Ok(Cow::Borrowed(_))));
+        assert_eq!(ascii("fe80::1%3").unwrap(), "fe80::1%3");
+
+        // All-numeric labels are fine, except as the last one
+        assert_eq!(ascii("123.example").unwrap(), "123.example");
+        assert_eq!(
+            ascii("256.1.1.1").unwrap_err(),
+            "'256.1.1.1' is not a valid IPv4 address"
+        );
+
+        assert_eq!(
+            ascii("knock.ex ample.com").unwrap_err(),
+            "label 'ex ample' of 'knock.ex ample.com' is not a valid host name"
+        );
+        assert!(ascii("xn--zz.example").unwrap_err().contains("'xn--zz'"));
+        assert!(ascii("a..example").is_err());
+        assert!(ascii("example.com..").is_err());
+        assert!(ascii(&format!("{}.example", "a".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn bare_link_local_needs_interface() {
+        assert!(validate_host("fe80::1").is_err());
+        assert!(validate_host("fe80::1%2").is_ok());
+        assert!(validate_host("2001:db8::1").is_ok());
+        assert!(validate_host("knock.example").is_ok());
+    }
+}
@@ -0,0 +1,372 @@
+//! `--script`: the knock sequence worked out by a [rhai] script when the
+//! run starts, for schemes that compute ports and payloads with more logic
+//! than a derivation flag offers.
+//!
+//! The script defines `plan(ctx)`, where `ctx` is a map of:
+//!
+//! - `host`: the host as configured;
+//! - `time`: the Unix time in seconds;
+//! - `date`: the UTC date, `YYYY-MM-DD`;
+//! - `secrets`: the `--script-secret` secrets by name, as strings.
+//!
+//! It returns an array of steps, each a port number, an entry as
+//! `--sequence` takes it (`"7000/udp?delay=50"`), or a map of `port` and
+//! optionally `protocol`, `payload` (a string or a blob), `delay` and
+//! `timeout` (milliseconds) and `attempts`. Every step goes through the
+//! same checks as a `--sequence` entry, and the plan is then knocked like
+//! one. Besides rhai's standard library the script has `sha256(data)` and
+//! `hmac_sha256(key, data)`, each of a string or a blob and returning a
+//! blob; `print` and `debug` lines become notices of the run.
+//!
+//! A script has no files, modules or network, runs for at most
+//! [`TIME_LIMIT`] and [`MAX_OPERATIONS`], and its strings, arrays and maps
+//! are capped in size. Errors in it name the line they happened on.
+//! `examples/scripts/daily.rhai` is one to start from.
+//!
+//! [rhai]: https://rhai.rs
+
+use crate::config::ScriptPlan;
+use crate::plan::KnockPlan;
+use crate::secret::Secret;
+use crate::AppError;
+use std::path::Path;
+use std::time::Duration;
+
+/// Longest a script may run.
+pub const TIME_LIMIT: Duration = Duration::from_secs(1);
+
+/// Most rhai operations a script may run.
+pub const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// Longest string a script may build, in bytes.
+pub const MAX_STRING: usize = 64 << 10;
+
+/// Most items of an array or bytes of a blob a script may build.
+pub const MAX_ARRAY: usize = 64 << 10;
+
+/// What the script's `plan(ctx)` is told.
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+struct Context<'a> {
+    host: &'a str,
+    now: chrono::DateTime<chrono::Utc>,
+    secrets: Vec<(&'a str, Secret)>,
+}
+
+/// The sequence `script.path` works out for `host` now, and the lines it
+/// printed.
+pub async fn plan(script: &ScriptPlan, host: &str) -> Result<(KnockPlan, Vec<String>), AppError> {
+    let mut secrets = Vec::with_capacity(script.secrets.len());
+    for (name, source) in &script.secrets {
+        let secret = source
+            .read(&format!("script secret {name}"))
+            .await
+            .map_err(AppError::Script)?;
+        secrets.push((name.as_str(), secret));
+    }
+    let path = &script.path;
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| AppError::Script(format!("cannot read {}: {e}", path.display())))?;
+    let context = Context {
+        host,
+        now: chrono::Utc::now(),
+        secrets,
+    };
+    evaluate(path, &source, &context).map_err(AppError::Script)
+}
+
+/// Run the `plan(ctx)` of `source`, read from `path`.
+#[cfg(feature = "scripting")]
+fn evaluate(
+    path: &Path,
+    source: &str,
+    context: &Context,
+) -> Result<(KnockPlan, Vec<String>), String> {
+    use rhai::{Array, Dynamic, EvalAltResult, Map, Scope};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let name = path.display();
+    // rhai's positions, when it has them, are lines of the script
+    let fail = |line: Option<usize>, why: String| match line {
+        Some(line) => format!("{name}: line {line}: {why}"),
+        None => format!("{name}: {why}"),
+    };
+
+    let printed = Rc::new(RefCell::new(Vec::new()));
+    let engine = engine(Rc::clone(&printed));
+    let ast = engine
+        .compile(source)
+        .map_err(|e| fail(e.1.line(), e.0.to_string()))?;
+    if !ast
+        .iter_functions()
+        .any(|f| f.name == "plan" && f.params.len() == 1)
+    {
+        return Err(fail(None, "defines no plan(ctx) function".into()));
+    }
+
+    let mut secrets = Map::new();
+    for (secret_name, secret) in &context.secrets {
+        let text = std::str::from_utf8(secret)
+            .map_err(|_| format!("script secret {secret_name} is not UTF-8 text"))?;
+        secrets.insert((*secret_name).into(), text.into());
+    }
+    let mut ctx = Map::new();
+    ctx.insert("host".into(), context.host.into());
+    ctx.insert("time".into(), Dynamic::from_int(context.now.timestamp()));
+    ctx.insert(
+        "date".into(),
+        context.now.format("%Y-%m-%d").to_string().into(),
+    );
+    ctx.insert("secrets".into(), secrets.into());
+
+    let steps = engine
+        .call_fn::<Dynamic>(&mut Scope::new(), &ast, "plan", (ctx,))
+        .map_err(|e| {
+            // The error inside plan(ctx), not the calls leading to it
+            let mut e = *e;
+            while let EvalAltResult::ErrorInFunctionCall(_, _, inner, _) = e {
+                e = *inner;
+            }
+            let line = e.take_position().line();
+            match e {
+                EvalAltResult::ErrorTooManyOperations(_) => {
+                    fail(line, format!("ran more than {MAX_OPERATIONS} operations"))
+                }
+                EvalAltResult::ErrorTerminated(..) => {
+                    fail(line, format!("ran longer than {TIME_LIMIT:?}"))
+                }
+                e => fail(line, e.to_string()),
+            }
+        })?;
+    let type_name = steps.type_name();
+    let steps = steps.try_cast::<Array>().ok_or_else(|| {
+        fail(
+            None,
+            format!("plan(ctx) returned a {type_name}, not an array of steps"),
+        )
+    })?;
+    if steps.is_empty() {
+        return Err(fail(None, "plan(ctx) returned no steps".into()));
+    }
+    let steps = steps
+        .into_iter()
+        .enumerate()
+        .map(|(i, step)| {
+            entry(step)
+                .and_then(|entry| crate::plan::KnockStep::parse(&entry))
+                .map_err(|e| fail(None, format!("step {} of the plan: {e}", i + 1)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let printed = printed.take();
+    Ok((KnockPlan(steps), printed))
+}
+
+/// An engine with the limits and functions of the [module docs](self),
+/// `print` and `debug` collected into `printed`.
+#[cfg(feature = "scripting")]
+fn engine(printed: std::rc::Rc<std::cell::RefCell<Vec<String>>>) -> rhai::Engine {
+    use rhai::module_resolvers::DummyModuleResolver;
+    use rhai::{Blob, Dynamic, Engine};
+
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(64)
+        .set_max_expr_depths(64, 64)
+        .set_max_string_size(MAX_STRING)
+        .set_max_array_size(MAX_ARRAY)
+        .set_max_map_size(MAX_ARRAY);
+    let started = std::time::Instant::now();
+    engine.on_progress(move |_| (started.elapsed() > TIME_LIMIT).then_some(Dynamic::UNIT));
+    let print = std::rc::Rc::clone(&printed);
+    engine.on_print(move |line| print.borrow_mut().push(line.to_string()));
+    engine.on_debug(move |line, _, _| printed.borrow_mut().push(line.to_string()));
+
+    let sha256 = |data: &[u8]| -> Blob {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(data).to_vec()
+    };
+    let hmac = |key: &[u8], data: &[u8]| -> Blob {
+        use hmac::{Hmac, Mac};
+        let mut mac =
+            Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    };
+    engine
+        .register_fn("sha256", move |data: &str| sha256(data.as_bytes()))
+        .register_fn("sha256", move |data: Blob| sha256(&data))
+        .register_fn("hmac_sha256", move |key: &str, data: &str| {
+            hmac(key.as_bytes(), data.as_bytes())
+        })
+        .register_fn("hmac_sha256", move |key: &str, data: Blob| {
+            hmac(key.as_bytes(), &data)
+        })
+        .register_fn("hmac_sha256", move |key: Blob, data: &str| {
+            hmac(&key, data.as_bytes())
+        })
+        .register_fn("hmac_sha256", move |key: Blob, data: Blob| {
+            hmac(&key, &data)
+        });
+    engine
+}
+
+/// A step the script returned, as a `--sequence` entry.
+#[cfg(feature = "scripting")]
+fn entry(step: rhai::Dynamic) -> Result<String, String> {
+    use rhai::{Blob, Map};
+
+    if step.is_int() {
+        return Ok(step.as_int().unwrap_or_default().to_string());
+    }
+    if step.is_string() {
+        return Ok(step.into_string().unwrap_or_default());
+    }
+    let type_name = step.type_name();
+    let Some(map) = step.try_cast::<Map>() else {
+        return Err(format!("a {type_name} is not a port, an entry or a map"));
+    };
+    let mut port = None;
+    let mut protocol = None;
+    let mut options = Vec::new();
+    for (key, value) in map {
+        let int = |value: &rhai::Dynamic| {
+            value
+                .as_int()
+                .map_err(|_| format!("{key} is a {}, not a number", value.type_name()))
+        };
+        match key.as_str() {
+            "port" => port = Some(int(&value)?),
+            "protocol" => {
+                let type_name = value.type_name();
+                let text = value
+                    .into_string()
+                    .map_err(|_| format!("protocol is a {type_name}, not a string"))?;
+                protocol = Some(text);
+            }
+            "delay" | "timeout" | "attempts" => {
+                options.push(format!("{key}={}", int(&value)?));
+            }
+            "payload" => {
+                let bytes = if value.is_string() {
+                    value.into_string().unwrap_or_default().into_bytes()
+                } else if value.is_blob() {
+                    value.cast::<Blob>()
+                } else {
+                    return Err(format!(
+                        "payload is a {}, not a string or a blob",
+                        value.type_name()
+                    ));
+                };
+                options.push(format!("payload={}", hex::encode(bytes)));
+            }
+            other => return Err(format!("'{other}' is not a step key")),
+        }
+    }
+    let port = port.ok_or("a step map needs a port")?;
+    let mut entry = port.to_string();
+    if let Some(protocol) = protocol {
+        entry = format!("{entry}/{protocol}");
+    }
+    if !options.is_empty() {
+        entry = format!("{entry}?{}", options.join("&"));
+    }
+    Ok(entry)
+}
+
+/// Without the `scripting` feature there is no engine to run the script.
+#[cfg(not(feature = "scripting"))]
+fn evaluate(
+    _path: &Path,
+    _source: &str,
+    _context: &Context,
+) -> Result<(KnockPlan, Vec<String>), String> {
+    Err("--script requires building with `--features scripting`".into())
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use crate::plan::KnockStep;
+    use crate::protocol::Protocol;
+
+    fn run(source: &str) -> Result<(KnockPlan, Vec<String>), String> {
+        let context = Context {
+            host: "knock.example",
+            now: "2026-10-16T08:30:00Z".parse().unwrap(),
+            secrets: vec![("phrase", Secret::from(&b"open sesame"[..]))],
+        };
+        evaluate(Path::new("knock.rhai"), source, &context)
+    }
+
+    #[test]
+    fn steps_come_as_ports_entries_and_maps() {
+        let (plan, printed) = run(r#"
+            fn plan(ctx) {
+                print(`knocking ${ctx.host} on ${ctx.date}`);
+                let tag = hmac_sha256(ctx.secrets.phrase, ctx.date);
+                [
+                    7000,
+                    "8000/udp?delay=50",
+                    #{ port: 9000, protocol: "udp", payload: tag.extract(0, 2), attempts: 2 },
+                ]
+            }
+        "#)
+        .unwrap();
+        assert_eq!(printed, ["knocking knock.example on 2026-10-16"]);
+        assert_eq!(plan.len(), 3);
+        assert_eq!(plan[0], KnockStep::new(7000));
+        assert_eq!(plan[1].to_string(), "8000/udp?delay=50");
+        let mut mac =
+            <hmac::Hmac<sha2::Sha256> as hmac::Mac>::new_from_slice(b"open sesame").unwrap();
+        hmac::Mac::update(&mut mac, b"2026-10-16");
+        let tag = hmac::Mac::finalize(mac).into_bytes();
+        assert_eq!(plan[2].protocol, Some(Protocol::Udp));
+        assert_eq!(plan[2].payload.as_deref(), Some(&tag[..2]));
+        assert_eq!(plan[2].attempts, Some(2));
+    }
+
+    #[test]
+    fn the_example_script_knocks_three_ports_of_the_day() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/scripts/daily.rhai");
+        let (plan, _) = run(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(plan.len(), 3);
+        assert!(plan.iter().all(|step| (10000..60000).contains(&step.port)));
+    }
+
+    #[test]
+    fn errors_name_their_line() {
+        let err = run("fn plan(ctx) {\n  let x = 1;\n  x +\n}").unwrap_err();
+        assert!(err.starts_with("knock.rhai: line 4: "), "{err}");
+        let err = run("fn plan(ctx) {\n  let x = [];\n  x[3]\n}").unwrap_err();
+        assert!(err.starts_with("knock.rhai: line 3: "), "{err}");
+        let err = run("fn plan(ctx) {\n  ctx.secrets.missing.len()\n}").unwrap_err();
+        assert!(err.starts_with("knock.rhai: line 2: "), "{err}");
+
+        let err = run("fn knock(ctx) { [7000] }").unwrap_err();
+        assert!(err.ends_with("defines no plan(ctx) function"), "{err}");
+        let err = run("fn plan(ctx) { 7000 }").unwrap_err();
+        assert!(err.ends_with("not an array of steps"), "{err}");
+        let err = run("fn plan(ctx) { [7000, 70000] }").unwrap_err();
+        assert!(err.contains("step 2 of the plan"), "{err}");
+        let err = run("fn plan(ctx) { [#{ port: 7000, ttl: 3 }] }").unwrap_err();
+        assert!(err.contains("'ttl' is not a step key"), "{err}");
+        let err = run("fn plan(ctx) { [] }").unwrap_err();
+        assert!(err.ends_with("returned no steps"), "{err}");
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        let err = run("fn plan(ctx) { loop {} }").unwrap_err();
+        assert!(err.contains(": ran "), "{err}");
+        let err = run(r#"fn plan(ctx) { let s = "x"; loop { s += s; } }"#).unwrap_err();
+        assert!(err.contains("too large"), "{err}");
+        // Nothing to import, so nothing read from disk
+        let err = run(r#"fn plan(ctx) { import "knock" as k; [7000] }"#).unwrap_err();
+        assert!(
+            err.starts_with("knock.rhai: line 1: Module not found"),
+            "{err}"
+        );
+    }
+}
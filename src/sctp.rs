@@ -0,0 +1,100 @@
+use crate::rt::{self, Instant};
+use crate::{
+    config::KnockOpts,
+    events::{EventSink, KnockTarget},
+    outcome::{AttemptLog, KnockOutcome},
+    protocol::Protocol,
+    retry::{retry_with_backoff, sync_on_timeout, RetryDecision},
+};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+
+/// Perform an SCTP knock: each attempt sends an INIT by starting a
+/// one-to-one association, with the same retry/timeout/backoff as TCP.
+pub(crate) async fn knock_sctp(
+    host: &str,
+    target: SocketAddr,
+    opts: &KnockOpts,
+    events: &EventSink,
+) -> KnockOutcome {
+    let port = target.port();
+    let started = Instant::now();
+    let log = AttemptLog::new(events, KnockTarget::new(host, port, Protocol::Sctp));
+    let Ok(retry) = retry_with_backoff(
+        opts.attempts,
+        opts.timeout,
+        &opts.backoff,
+        opts.deadline,
+        opts.rate.as_deref(),
+        opts.budget.as_deref(),
+        |attempt| {
+            let log = &log;
+            async move {
+                let start = Instant::now();
+                match connect(target).await {
+                    Ok(_socket) => {
+                        let elapsed = start.elapsed();
+                        log.succeeded(attempt, elapsed, "OK");
+                        RetryDecision::<_, Infallible>::Done(elapsed) // stop retrying
+                    }
+                    Err(e) => {
+                        log.push(attempt, e.to_string());
+                        RetryDecision::<_, Infallible>::Retry // retry
+                    }
+                }
+            }
+        },
+        sync_on_timeout(|attempt, _| {
+            log.timed_out(attempt, "timed out");
+        }),
+    )
+    .await;
+
+    let latency = retry.value;
+    KnockOutcome {
+        port,
+        protocol: Protocol::Sctp,
+        addr: Some(target),
+        source_port: None,
+        fwmark: None,
+        ttl: None,
+        payload_len: None,
+        attempts_allowed: None,
+        attempts: retry.attempts,
+        succeeded: retry.succeeded(),
+        acknowledged: latency.is_some(),
+        latency,
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: log.into_errors(),
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Non-blocking SCTP connect driven by the runtime's readiness events.
+async fn connect(target: SocketAddr) -> io::Result<Socket> {
+    let domain = match target {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket =
+        Socket::new(domain, Type::STREAM, Some(libc::IPPROTO_SCTP.into())).map_err(|e| {
+            if e.raw_os_error() == Some(libc::EPROTONOSUPPORT) {
+                io::Error::new(e.kind(), "kernel has no SCTP support (try `modprobe sctp`)")
+            } else {
+                e
+            }
+        })?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&SockAddr::from(target)) {
+        Ok(()) => return Ok(socket),
+        Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(e) => return Err(e),
+    }
+
+    // Wait until the association is up (or failed)
+    rt::connected(socket).await
+}
@@ -0,0 +1,124 @@
+//! Telling systemd how a `--schedule` service is doing (`sd_notify`).
+//!
+//! A unit with `Type=notify` waits for `READY=1` before it counts as
+//! started, and one with `WatchdogSec=` restarts the service when the
+//! `WATCHDOG=1` pings stop. systemd passes the datagram socket to send
+//! them to in `$NOTIFY_SOCKET`; without it, as outside such a unit,
+//! [`SdNotify::from_env`] gives `None` and nothing is sent. Each message is
+//! a few `KEY=value` lines (see sd_notify(3)), sent the way libsystemd
+//! does, to a path or, starting with `@`, to an abstract socket.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+/// The environment variable systemd names the socket in.
+pub const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// A connection to the service manager's notification socket.
+#[derive(Debug)]
+pub struct SdNotify {
+    socket: UnixDatagram,
+}
+
+impl SdNotify {
+    /// The socket `$NOTIFY_SOCKET` names, `None` when it is unset or empty.
+    pub fn from_env() -> Option<io::Result<Self>> {
+        let path = std::env::var(NOTIFY_SOCKET).ok()?;
+        (!path.is_empty()).then(|| Self::connect(&path))
+    }
+
+    /// Send to the socket at `path`, or the abstract one `@name` names.
+    pub fn connect(path: &str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        match path.strip_prefix('@') {
+            Some(name) => connect_abstract(&socket, name)?,
+            None => socket.connect(PathBuf::from(path))?,
+        }
+        Ok(Self { socket })
+    }
+
+    /// Send `state`, one or more `KEY=value` lines.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send(state.as_bytes()).map(|_| ())
+    }
+
+    /// `READY=1`: started, with `status` as the status line.
+    pub fn ready(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("READY=1\nSTATUS={}", one_line(status)))
+    }
+
+    /// `WATCHDOG=1`: still alive, with `status` as the status line.
+    pub fn watchdog(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("WATCHDOG=1\nSTATUS={}", one_line(status)))
+    }
+
+    /// The status line `systemctl status` shows.
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("STATUS={}", one_line(status)))
+    }
+
+    /// `STOPPING=1`: shutting down.
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+}
+
+/// A status on several lines would end the `STATUS=` value at the first.
+fn one_line(status: &str) -> String {
+    status.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn connect_abstract(socket: &UnixDatagram, name: &str) -> io::Result<()> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.connect_addr(&addr)
+}
+
+/// Only Linux has abstract sockets.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn connect_abstract(_socket: &UnixDatagram, name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("abstract socket '@{name}' is only available on Linux"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_reach_the_socket_as_sent() {
+        let path = std::env::temp_dir().join(format!("apk-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        let notify = SdNotify::connect(path.to_str().unwrap()).unwrap();
+        let receive = || {
+            let mut buf = [0; 512];
+            let n = manager.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+
+        notify
+            .ready("Knock at 08:55: all 3\nknocks got through")
+            .unwrap();
+        assert_eq!(
+            receive(),
+            "READY=1\nSTATUS=Knock at 08:55: all 3 knocks got through"
+        );
+        notify.watchdog("ok").unwrap();
+        assert_eq!(receive(), "WATCHDOG=1\nSTATUS=ok");
+        notify.stopping().unwrap();
+        assert_eq!(receive(), "STOPPING=1");
+        std::fs::remove_file(&path).unwrap();
+
+        // Nobody listening is an error for the caller to shrug off
+        assert!(SdNotify::connect(path.to_str().unwrap()).is_err());
+    }
+}
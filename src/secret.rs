@@ -0,0 +1,242 @@
+//! Where keys, secrets and passphrases come from: never the command line.
+//!
+//! Anything on the command line shows up in `ps` and shell history, so
+//! every option taking a secret takes a [`SecretSource`] instead:
+//!
+//! - `file:PATH`: the file's contents
+//! - `env:VAR`: the environment variable's value
+//! - `keyring:SERVICE/USER`: the OS keyring entry (Keychain, Credential
+//!   Manager, the Linux kernel keyring); needs the `keyring` feature
+//! - `prompt:`: asked for on the terminal without echo
+//!
+//! A bare value, once the path to a key file, is rejected with a hint to
+//! use the `file:` form; the value itself is not repeated, as it may be
+//! the secret. What is read is held as a [`Secret`],
+//! wiped from memory when dropped and never shown by `Debug`.
+
+use std::fmt;
+use std::io::IsTerminal;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Where a secret is read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    File(PathBuf),
+    Env(String),
+    Keyring { service: String, user: String },
+    Prompt,
+}
+
+impl FromStr for SecretSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some((kind, rest)) = s
+            .split_once(':')
+            .filter(|(kind, _)| matches!(*kind, "file" | "env" | "keyring" | "prompt"))
+        else {
+            // The value is likely the secret itself, so it is not repeated
+            return Err(
+                "a secret source needs a file:PATH, env:VAR, keyring:SERVICE/USER \
+                 or prompt: prefix (secrets are never taken from the command line)"
+                    .to_string(),
+            );
+        };
+        match kind {
+            "file" if !rest.is_empty() => Ok(SecretSource::File(PathBuf::from(rest))),
+            "env" if !rest.is_empty() && !rest.contains('=') => {
+                Ok(SecretSource::Env(rest.to_string()))
+            }
+            "keyring" => match rest.split_once('/') {
+                Some((service, user)) if !service.is_empty() && !user.is_empty() => {
+                    Ok(SecretSource::Keyring {
+                        service: service.to_string(),
+                        user: user.to_string(),
+                    })
+                }
+                _ => Err("keyring: takes SERVICE/USER".to_string()),
+            },
+            "prompt" if rest.is_empty() => Ok(SecretSource::Prompt),
+            "prompt" => Err("prompt: takes nothing after it".to_string()),
+            "env" => Err("env: takes a variable name, not VAR=VALUE".to_string()),
+            _ => Err("file: takes a path".to_string()),
+        }
+    }
+}
+
+// Serialized as the source, never the secret read from it
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(SecretSource);
+
+/// The source as [`FromStr`] takes it.
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::File(path) => write!(f, "file:{}", path.display()),
+            SecretSource::Env(var) => write!(f, "env:{var}"),
+            SecretSource::Keyring { service, user } => write!(f, "keyring:{service}/{user}"),
+            SecretSource::Prompt => f.write_str("prompt:"),
+        }
+    }
+}
+
+impl SecretSource {
+    /// Read the secret, a trailing newline dropped; an empty one is an
+    /// error. `what` names it in the prompt and the errors, e.g. "SPA key".
+    pub async fn read(&self, what: &str) -> Result<Secret, String> {
+        let mut secret = self.read_raw(what).await?;
+        while secret.0.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+            secret.0.pop();
+        }
+        if secret.is_empty() {
+            return Err(format!("{what} from {self} is empty"));
+        }
+        Ok(secret)
+    }
+
+    /// Read the secret exactly as stored, for keys whose bytes may end in
+    /// a newline.
+    pub async fn read_raw(&self, what: &str) -> Result<Secret, String> {
+        match self {
+            SecretSource::File(path) => std::fs::read(path)
+                .map(Secret::new)
+                .map_err(|e| format!("cannot read {what} file {}: {e}", path.display())),
+            SecretSource::Env(var) => match std::env::var(var) {
+                Ok(value) => Ok(Secret::new(value.into_bytes())),
+                Err(_) => Err(format!("no {what}: ${var} is not set")),
+            },
+            SecretSource::Keyring { service, user } => keyring(service, user)
+                .map_err(|e| format!("cannot read {what} from the keyring ({self}): {e}")),
+            SecretSource::Prompt => prompt(what).await,
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring(service: &str, user: &str) -> Result<Secret, String> {
+    let entry = keyring::Entry::new(service, user).map_err(|e| e.to_string())?;
+    entry
+        .get_secret()
+        .map(Secret::new)
+        .map_err(|e| e.to_string())
+}
+
+/// Without the `keyring` feature a keyring source is an error.
+#[cfg(not(feature = "keyring"))]
+fn keyring(_service: &str, _user: &str) -> Result<Secret, String> {
+    Err("requires building with `--features keyring`".into())
+}
+
+/// Ask for the secret on the terminal without echoing it.
+async fn prompt(what: &str) -> Result<Secret, String> {
+    if !std::io::stdin().is_terminal() {
+        return Err(format!(
+            "stdin is not a terminal to ask for the {what}; give it with file:, env: or keyring:"
+        ));
+    }
+    let question = format!("{}{}: ", what[..1].to_uppercase(), &what[1..]);
+    let answer = crate::rt::spawn_blocking(move || rpassword::prompt_password(question))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("cannot read the {what}: {e}"))?;
+    Ok(Secret::new(answer.into_bytes()))
+}
+
+/// Secret bytes, wiped when dropped. `Debug` shows only how many there
+/// are.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<Vec<u8>>);
+
+impl Secret {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for Secret {
+    fn from(bytes: &[u8]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret([{} bytes redacted])", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_parse_and_bare_values_are_refused() {
+        let parse = |s: &str| s.parse::<SecretSource>();
+        assert_eq!(
+            parse("file:/etc/knock.key"),
+            Ok(SecretSource::File("/etc/knock.key".into()))
+        );
+        assert_eq!(
+            parse("env:KNOCK_KEY"),
+            Ok(SecretSource::Env("KNOCK_KEY".into()))
+        );
+        let keyring = parse("keyring:knocker/alice@example").unwrap();
+        assert_eq!(keyring.to_string(), "keyring:knocker/alice@example");
+        assert_eq!(parse("prompt:"), Ok(SecretSource::Prompt));
+
+        // The old key file paths, and secrets given outright
+        let err = parse("/etc/knock.key").unwrap_err();
+        assert!(err.contains("file:PATH"), "{err}");
+        // Refusals never repeat what may be the secret
+        for secret in [
+            "hunter2",
+            "env:KEY=hunter2",
+            "prompt:hunter2",
+            "keyring:hunter2",
+        ] {
+            let err = parse(secret).unwrap_err();
+            assert!(!err.contains("hunter2"), "{err}");
+        }
+        assert!(parse("c:\\keys\\knock.key").is_err());
+        for bad in [
+            "file:",
+            "env:",
+            "keyring:knocker",
+            "keyring:/alice",
+            "prompt:x",
+        ] {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn secrets_are_read_and_never_shown() {
+        let path = std::env::temp_dir().join(format!("apk-secret-{}", std::process::id()));
+        std::fs::write(&path, b"s3cret\n").unwrap();
+        let file = SecretSource::File(path.clone());
+        let secret = file.read("key").await.unwrap();
+        assert_eq!(&*secret, b"s3cret");
+        assert_eq!(&*file.read_raw("key").await.unwrap(), b"s3cret\n");
+        assert_eq!(format!("{secret:?}"), "Secret([6 bytes redacted])");
+
+        std::fs::write(&path, b"\n").unwrap();
+        let err = file.read("key").await.unwrap_err();
+        assert!(err.ends_with("is empty"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+
+        let unset = SecretSource::Env("APK_SURELY_UNSET_VARIABLE".into());
+        let err = unset.read("SPA key").await.unwrap_err();
+        assert_eq!(err, "no SPA key: $APK_SURELY_UNSET_VARIABLE is not set");
+    }
+}
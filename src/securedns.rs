@@ -0,0 +1,262 @@
+//! Resolving the knock target over an encrypted transport, so its name
+//! does not cross the network in cleartext right before the knocks
+//! (`--doh-url`, `--dot`).
+//!
+//! DNS-over-HTTPS and DNS-over-TLS queries go through hickory-resolver
+//! with the Mozilla root certificates, and need the `secure-dns` feature.
+//! A server given by name is itself looked up with the system resolver
+//! first; give its IP address to avoid that. Like `--dns-server`, a
+//! secure resolver replaces the system one for the knock target only:
+//! `--resolve` pins are used without asking it, and the address-family
+//! strategy filters what it answers.
+
+use std::fmt;
+#[cfg(feature = "secure-dns")]
+use {
+    crate::{dns, protocol::ResolveStrategy, scope, AppError},
+    std::net::{IpAddr, SocketAddr},
+};
+
+/// Default DNS-over-HTTPS port.
+const HTTPS_PORT: u16 = 443;
+/// Default DNS-over-TLS port.
+const TLS_PORT: u16 = 853;
+/// The only path hickory-resolver sends DNS-over-HTTPS queries to.
+const DOH_PATH: &str = "/dns-query";
+
+/// An encrypted DNS server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum SecureDns {
+    /// DNS-over-HTTPS at `https://HOST:PORT/dns-query`.
+    Https { host: String, port: u16 },
+    /// DNS-over-TLS at `HOST:PORT`.
+    Tls { host: String, port: u16 },
+}
+
+impl SecureDns {
+    /// Parse a `--doh-url`: `https://HOST[:PORT]/dns-query`, where HOST is
+    /// a name or an IP address, bracketed for IPv6.
+    pub fn parse_doh_url(s: &str) -> Result<Self, String> {
+        let rest = s
+            .strip_prefix("https://")
+            .ok_or_else(|| format!("'{s}' is not an https:// URL"))?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        if !matches!(path, "" | "/" | DOH_PATH) {
+            return Err(format!(
+                "'{s}' has the path {path}; only {DOH_PATH} is supported"
+            ));
+        }
+        let (host, port) = host_port(authority, HTTPS_PORT).map_err(|e| format!("'{s}': {e}"))?;
+        Ok(SecureDns::Https { host, port })
+    }
+
+    /// Parse a `--dot` server: `HOST[:PORT]`, where HOST is a name or an
+    /// IP address, bracketed for IPv6 with a port.
+    pub fn parse_dot(s: &str) -> Result<Self, String> {
+        let (host, port) = host_port(s, TLS_PORT).map_err(|e| format!("'{s}': {e}"))?;
+        Ok(SecureDns::Tls { host, port })
+    }
+
+    /// The server's name or address, also the name its certificate is
+    /// checked against.
+    pub fn host(&self) -> &str {
+        match self {
+            SecureDns::Https { host, .. } | SecureDns::Tls { host, .. } => host,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            SecureDns::Https { port, .. } | SecureDns::Tls { port, .. } => *port,
+        }
+    }
+}
+
+/// Names the transport and the server, e.g. `DNS-over-TLS server
+/// 1.1.1.1:853`.
+impl fmt::Display for SecureDns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = self.host();
+        let host = match host.contains(':') {
+            true => format!("[{host}]"),
+            false => host.to_string(),
+        };
+        match self {
+            SecureDns::Https { port, .. } => {
+                write!(f, "DNS-over-HTTPS server https://{host}:{port}{DOH_PATH}")
+            }
+            SecureDns::Tls { port, .. } => write!(f, "DNS-over-TLS server {host}:{port}"),
+        }
+    }
+}
+
+/// Split `HOST[:PORT]`, with IPv6 addresses bracketed when a port follows.
+pub(crate) fn host_port(s: &str, default_port: u16) -> Result<(String, u16), String> {
+    let (host, port) = match s.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest.split_once(']').ok_or("unclosed '['")?;
+            match after {
+                "" => (host, None),
+                _ => (
+                    host,
+                    Some(after.strip_prefix(':').ok_or("expected ':' after ']'")?),
+                ),
+            }
+        }
+        // A bare IPv6 address has no port
+        None if s.matches(':').count() > 1 => (s, None),
+        None => match s.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        },
+    };
+    if host.is_empty() {
+        return Err("no server host".into());
+    }
+    let port = match port {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return Err(format!("'{port}' is not a valid port")),
+        },
+        None => default_port,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// [`dns::resolve_target`] asking `server` over its encrypted transport
+/// instead of the system resolver, for the records `strategy` needs.
+///
+/// Every failure, of the transport or the lookup, is an
+/// [`AppError::SecureResolve`] naming the server and its transport.
+#[cfg(feature = "secure-dns")]
+pub async fn resolve(
+    server: &SecureDns,
+    host: &str,
+    strategy: ResolveStrategy,
+) -> Result<Vec<SocketAddr>, AppError> {
+    use hickory_resolver::config::{
+        LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+    };
+    use hickory_resolver::error::ResolveErrorKind;
+    use hickory_resolver::TokioAsyncResolver;
+
+    if let Some(addr) = dns::literal(host) {
+        let mut addrs = vec![addr];
+        dns::apply_strategy(&mut addrs, strategy);
+        return match addrs.is_empty() {
+            true => Err(AppError::NoDns),
+            false => Ok(addrs),
+        };
+    }
+    let fail = |reason: String| AppError::SecureResolve {
+        host: host.to_string(),
+        server: server.clone(),
+        reason,
+    };
+    let name = scope::ascii_host(host).map_err(&fail)?;
+
+    // Where the server is; its name, if it has one, is what its
+    // certificate must be for
+    let ips: Vec<IpAddr> = match server.host().parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((server.host(), server.port()))
+            .await
+            .map_err(|e| fail(format!("cannot look the server up: {e}")))?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+    let tls_name = server.host().to_string();
+    let servers = match server {
+        SecureDns::Https { port, .. } => {
+            NameServerConfigGroup::from_ips_https(&ips, *port, tls_name, true)
+        }
+        SecureDns::Tls { port, .. } => {
+            NameServerConfigGroup::from_ips_tls(&ips, *port, tls_name, true)
+        }
+    };
+    let mut opts = ResolverOpts::default();
+    opts.ip_strategy = match strategy {
+        ResolveStrategy::OnlyV4 => LookupIpStrategy::Ipv4Only,
+        ResolveStrategy::OnlyV6 => LookupIpStrategy::Ipv6Only,
+        _ => LookupIpStrategy::Ipv4AndIpv6,
+    };
+    // Nothing but the server answers, as with the system resolver skipped
+    opts.use_hosts_file = false;
+    opts.attempts = 2;
+    let resolver =
+        TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, Vec::new(), servers), opts);
+
+    // Fully qualified, so no search domain is tried first
+    let mut addrs: Vec<SocketAddr> = match resolver.lookup_ip(format!("{name}.")).await {
+        Ok(lookup) => lookup.iter().map(|ip| SocketAddr::new(ip, 0)).collect(),
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Vec::new(),
+        Err(e) => return Err(fail(e.to_string())),
+    };
+    dns::apply_strategy(&mut addrs, strategy);
+    if addrs.is_empty() {
+        return Err(AppError::NoDns);
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn servers_parse_with_default_ports() {
+        let https = |host: &str, port| SecureDns::Https {
+            host: host.into(),
+            port,
+        };
+        assert_eq!(
+            SecureDns::parse_doh_url("https://1.1.1.1/dns-query"),
+            Ok(https("1.1.1.1", 443))
+        );
+        assert_eq!(
+            SecureDns::parse_doh_url("https://dns.example:8443"),
+            Ok(https("dns.example", 8443))
+        );
+        assert_eq!(
+            SecureDns::parse_doh_url("https://[2606:4700::1111]/dns-query"),
+            Ok(https("2606:4700::1111", 443))
+        );
+        assert!(SecureDns::parse_doh_url("http://1.1.1.1/dns-query").is_err());
+        assert!(SecureDns::parse_doh_url("https://1.1.1.1/resolve").is_err());
+        assert!(SecureDns::parse_doh_url("https:///dns-query").is_err());
+
+        let tls = SecureDns::parse_dot("1.1.1.1").unwrap();
+        assert_eq!(tls.to_string(), "DNS-over-TLS server 1.1.1.1:853");
+        let tls = SecureDns::parse_dot("[2606:4700::1111]:8853").unwrap();
+        assert_eq!(
+            tls.to_string(),
+            "DNS-over-TLS server [2606:4700::1111]:8853"
+        );
+        assert_eq!(SecureDns::parse_dot("2606:4700::1111").unwrap().port(), 853);
+        assert!(SecureDns::parse_dot("dns.example:0").is_err());
+    }
+
+    #[cfg(feature = "secure-dns")]
+    #[tokio::test]
+    async fn failed_transport_is_named() {
+        // Nothing listens there, so the TLS connection is refused
+        let server = SecureDns::parse_dot("127.0.0.1:1").unwrap();
+        let err = resolve(&server, "example.com", ResolveStrategy::All)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::SecureResolve { .. }), "{err}");
+        assert!(
+            err.to_string()
+                .starts_with("could not resolve example.com over DNS-over-TLS server 127.0.0.1:1"),
+            "{err}"
+        );
+        // IP literals need no lookup at all
+        let addrs = resolve(&server, "192.0.2.7", ResolveStrategy::All).await;
+        assert_eq!(addrs.unwrap(), [SocketAddr::from(([192, 0, 2, 7], 0))]);
+    }
+}
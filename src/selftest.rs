@@ -0,0 +1,187 @@
+//! A smoke test of the whole client against listeners on loopback.
+//!
+//! [`self_test`] binds a [`MockKnockServer`] on ephemeral ports, knocks a
+//! generated TCP/UDP sequence with a payload through [`crate::run`], the
+//! same path the binary takes, and checks what the server saw. A second
+//! server drops the first datagram it gets: a lost UDP knock is never sent
+//! again, as that would be a second knock, so the knocker has to notice
+//! the reply is missing and report it.
+
+use crate::protocol::Protocol;
+use crate::testing::{MockKnockServer, ObservedKnock};
+use crate::{AppError, KnockConfig, KnockReport};
+use std::fmt;
+use std::time::Duration;
+
+/// Answer to UDP knocks, so the knocker can tell one was lost.
+const REPLY: &[u8] = b"self-test ok";
+
+/// One thing the self-test checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    /// What was seen, and on failure what was expected.
+    pub detail: String,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.passed { "PASS" } else { "FAIL" };
+        write!(f, "{verdict} {}: {}", self.name, self.detail)
+    }
+}
+
+/// Everything the self-test checked, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+}
+
+/// Knock a loopback listener and check it saw the sequence; see the
+/// [module docs](self). Fails only when the listener cannot be set up.
+pub async fn self_test() -> Result<SelfTestReport, AppError> {
+    let server = MockKnockServer::builder()
+        .tcp_ports(2)
+        .udp_ports(1)
+        .bind()
+        .await?;
+    let (tcp, udp) = (server.tcp_ports(), server.udp_ports());
+    let steps = [
+        (Protocol::Tcp, tcp[0]),
+        (Protocol::Udp, udp[0]),
+        (Protocol::Tcp, tcp[1]),
+    ];
+    let payload = format!("self-test {:016x}", rand::random::<u64>()).into_bytes();
+    let sequence = steps
+        .iter()
+        .map(|&(protocol, port)| crate::plan::KnockStep {
+            protocol: Some(protocol),
+            ..crate::plan::KnockStep::new(port)
+        });
+    let config = KnockConfig::builder()
+        .host(server.ip().to_string())
+        .steps(sequence)
+        .payload(payload.clone())
+        .tcp_payload(payload.clone())
+        .build()?;
+    let result = crate::run(config).await;
+    let received = server.wait_for(steps.len(), Duration::from_secs(2)).await;
+    let mut checks = vec![run_check(&result)];
+    checks.push(order_check(&steps, &received));
+    checks.push(payload_check(&payload, &received));
+    checks.push(loss_check().await?);
+    Ok(SelfTestReport { checks })
+}
+
+fn run_check(result: &Result<KnockReport, AppError>) -> Check {
+    Check {
+        name: "run",
+        passed: result.is_ok(),
+        detail: match result {
+            Ok(report) => format!("{} knocks got through", report.steps.len()),
+            Err(e) => e.to_string(),
+        },
+    }
+}
+
+fn order_check(steps: &[(Protocol, u16)], received: &[ObservedKnock]) -> Check {
+    let seen: Vec<(Protocol, u16)> = received
+        .iter()
+        .filter(|k| !k.dropped)
+        .map(|k| (k.protocol, k.port))
+        .collect();
+    let show = |knocks: &[(Protocol, u16)]| {
+        let knocks: Vec<String> = knocks
+            .iter()
+            .map(|(p, port)| format!("{port}/{p}"))
+            .collect();
+        knocks.join(",")
+    };
+    Check {
+        name: "order",
+        passed: seen == steps,
+        detail: match seen == steps {
+            true => format!("listener saw {}", show(&seen)),
+            false => format!("listener saw {}, expected {}", show(&seen), show(steps)),
+        },
+    }
+}
+
+fn payload_check(payload: &[u8], received: &[ObservedKnock]) -> Check {
+    let wrong: Vec<String> = received
+        .iter()
+        .filter(|k| k.payload != payload)
+        .map(|k| {
+            format!(
+                "{}/{} got {:?}",
+                k.port,
+                k.protocol,
+                String::from_utf8_lossy(&k.payload)
+            )
+        })
+        .collect();
+    Check {
+        name: "payload",
+        passed: wrong.is_empty() && !received.is_empty(),
+        detail: match wrong.is_empty() {
+            true => format!("{} knocks carried {} bytes", received.len(), payload.len()),
+            false => wrong.join("; "),
+        },
+    }
+}
+
+/// Knock a listener that drops the first datagram, waiting for a reply.
+async fn loss_check() -> Result<Check, AppError> {
+    let server = MockKnockServer::builder()
+        .tcp_ports(0)
+        .udp_ports(1)
+        .drop_first(1)
+        .reply(REPLY)
+        .bind()
+        .await?;
+    let port = server.udp_ports()[0];
+    let config = KnockConfig::builder()
+        .host(server.ip().to_string())
+        .sequence([port])
+        .protocol(Protocol::Udp)
+        .expect_reply(true)
+        .recv_timeout(200)
+        .attempts(2)
+        .build()?;
+    let result = crate::run(config).await;
+    let received = server.wait_for(2, Duration::from_millis(200)).await;
+    let sent = received.len();
+    let reported = match &result {
+        Err(AppError::Partial { failed, .. }) => failed.iter().any(|f| f.port == port),
+        _ => false,
+    };
+    Ok(Check {
+        name: "loss",
+        passed: reported && sent == 1,
+        detail: match (reported, sent) {
+            (true, 1) => "dropped datagram reported as unanswered, not sent again".into(),
+            (false, _) => "dropped datagram not reported".into(),
+            (true, n) => format!("dropped datagram reported, but sent {n} times"),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn self_test_passes() {
+        let report = self_test().await.unwrap();
+        let names: Vec<&str> = report.checks.iter().map(|c| c.name).collect();
+        assert_eq!(names, ["run", "order", "payload", "loss"]);
+        assert!(report.passed(), "{:#?}", report.checks);
+    }
+}
@@ -0,0 +1,161 @@
+//! How values with no serde form of their own are written with the
+//! `serde` feature: durations as milliseconds, bytes as hex, times as Unix
+//! seconds, and settings given on the command line as the text given
+//! there ([`as_text!`]). Fields pick one with `#[serde(with = "...")]`.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Implement `Serialize` and `Deserialize` through the type's text form:
+/// `Display` out, and `FromStr`, or the parser given, back in.
+macro_rules! as_text {
+    ($type:ty) => {
+        $crate::serde_forms::as_text!($type, |s: &str| s.parse::<$type>());
+    };
+    ($type:ty, $parse:expr) => {
+        impl serde::Serialize for $type {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $type {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+                ($parse)(s.as_str()).map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+pub(crate) use as_text;
+
+/// A duration serialized as [`millis`], for where no field attribute
+/// reaches it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Millis(#[serde(with = "millis")] pub Duration);
+
+/// A duration as milliseconds, fractional when it is finer (`--delay`).
+pub mod millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        match duration.subsec_nanos() % 1_000_000 {
+            0 => serializer.serialize_u64(duration.as_millis() as u64),
+            _ => serializer.serialize_f64(duration.as_secs_f64() * 1000.0),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let ms = f64::deserialize(deserializer)?;
+        if !ms.is_finite() || ms < 0.0 || ms >= u64::MAX as f64 {
+            return Err(de::Error::custom(format!(
+                "{ms} is not a number of milliseconds"
+            )));
+        }
+        Ok(match ms.fract() {
+            0.0 => Duration::from_millis(ms as u64),
+            _ => Duration::from_nanos((ms * 1e6).round() as u64),
+        })
+    }
+}
+
+/// [`millis`] for an optional duration.
+pub mod millis_opt {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration.map(Millis).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<Millis>::deserialize(deserializer)?.map(|m| m.0))
+    }
+}
+
+/// Bytes as a hex string, as `payload=` takes them.
+pub mod hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        bytes: &impl AsRef<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&::hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: From<Vec<u8>>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let bytes =
+            ::hex::decode(&s).map_err(|e| de::Error::custom(format!("invalid hex: {e}")))?;
+        Ok(bytes.into())
+    }
+}
+
+/// [`hex`] for optional bytes.
+pub mod hex_opt {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Hex(#[serde(with = "hex")] Vec<u8>);
+
+    pub fn serialize<S: Serializer, T: AsRef<[u8]>>(
+        bytes: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&::hex::encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: From<Vec<u8>>>(
+        deserializer: D,
+    ) -> Result<Option<T>, D::Error> {
+        Ok(Option::<Hex>::deserialize(deserializer)?.map(|h| h.0.into()))
+    }
+}
+
+/// A time as fractional seconds since the Unix epoch.
+pub mod unix_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        serializer.serialize_f64(since.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+        let since = Duration::try_from_secs_f64(secs)
+            .map_err(|_| de::Error::custom(format!("{secs} is not a time after 1970")))?;
+        Ok(UNIX_EPOCH + since)
+    }
+}
+
+/// [`unix_secs`] for an optional time.
+pub mod unix_secs_opt {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct UnixSecs(#[serde(with = "unix_secs")] SystemTime);
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        time.map(UnixSecs).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        Ok(Option::<UnixSecs>::deserialize(deserializer)?.map(|t| t.0))
+    }
+}
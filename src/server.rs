@@ -0,0 +1,1390 @@
+//! The other end of the knock: listening for a sequence and opening up.
+//!
+//! [`KnockServer`] binds the ports of a [`ServerConfig`], UDP sockets and
+//! TCP listeners, and follows each source IP through the sequence on its
+//! own. A knock on the next port moves that source on; a knock on any
+//! other port of the sequence starts it over (from the first step, if that
+//! is the port knocked), and the whole sequence has to arrive within the
+//! sequence timeout. When a source completes it, the configured command runs with
+//! `%IP%` replaced by its address, as knockd does, the way the client's
+//! [hooks](crate::hooks) run: through the shell, and killed once its
+//! timeout runs out.
+//!
+//! With a signing key only UDP knocks carrying a valid signed knock (see
+//! [`crate::signed`]) for their port count: recent enough, and not seen
+//! before. What the client signed along with it, its identifier, is handed
+//! to the command as `KNOCK_CLIENT_ID`.
+//!
+//! With a [one-time file](OneTimeFile) each sequence in it is accepted
+//! once: the first unused line is the one listened for, and a source that
+//! completes it crosses it off so a replayed knock does nothing.
+
+#[cfg(feature = "cli")]
+use crate::cli::ListenCli;
+use crate::errors::AppError;
+use crate::hooks;
+use crate::protocol::Protocol;
+use crate::secret::{Secret, SecretSource};
+use crate::signed::{self, NONCE_LEN};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Placeholder in [`ServerConfig::command`] for the knocking address.
+pub const IP_PLACEHOLDER: &str = "%IP%";
+/// Environment variable giving the command the signed client identifier.
+pub const CLIENT_ID_ENV: &str = "KNOCK_CLIENT_ID";
+/// Nonces of signed knocks remembered unless told otherwise.
+pub const DEFAULT_REPLAY_CACHE: usize = 1024;
+/// Time a source has to complete the sequence unless told otherwise.
+pub const DEFAULT_SEQ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One port of the sequence the server listens for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenStep {
+    pub port: u16,
+    /// Protocol of this port instead of the server's.
+    pub protocol: Option<Protocol>,
+}
+
+impl ListenStep {
+    /// Parse `PORT[/PROTO]`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (port, protocol) = match s.split_once('/') {
+            Some((port, protocol)) => (port, Some(protocol.parse::<Protocol>()?)),
+            None => (s, None),
+        };
+        let port = port
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| format!("'{port}' is not a valid port"))?;
+        Ok(Self { port, protocol })
+    }
+}
+
+impl fmt::Display for ListenStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol {
+            Some(protocol) => write!(f, "{}/{protocol}", self.port),
+            None => write!(f, "{}", self.port),
+        }
+    }
+}
+
+/// What a [`KnockServer`] listens for and does.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Address to listen on.
+    pub bind: IpAddr,
+    /// Ports to be knocked, in order. Port 0 binds a free port, one per
+    /// step; see [`KnockServer::ports`].
+    pub sequence: Vec<ListenStep>,
+    /// Take the sequences from this file instead, each accepted once; see
+    /// [`OneTimeFile`].
+    pub one_time_file: Option<PathBuf>,
+    /// Protocol of the steps that do not name one: TCP or UDP.
+    pub protocol: Protocol,
+    /// Time from a source's first knock by which it has to complete the
+    /// sequence.
+    pub seq_timeout: Duration,
+    /// Sources followed through the sequence at once.
+    pub max_sources: usize,
+    /// Ignore sources that knock out of order too often.
+    pub ban: Option<BanPolicy>,
+    /// Only count UDP knocks signed with this key.
+    pub sign_key: Option<SecretSource>,
+    /// How far a signed knock's timestamp may be from the local clock.
+    pub max_skew: Duration,
+    /// Nonces of signed knocks remembered to reject replays.
+    pub replay_cache: usize,
+    /// Shell command run for each completed sequence, with
+    /// [`IP_PLACEHOLDER`] replaced by the source address.
+    pub command: Option<String>,
+    /// How long the command may run before it is killed.
+    pub command_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: Ipv4Addr::UNSPECIFIED.into(),
+            sequence: Vec::new(),
+            one_time_file: None,
+            protocol: Protocol::Tcp,
+            seq_timeout: DEFAULT_SEQ_TIMEOUT,
+            max_sources: DEFAULT_MAX_SOURCES,
+            ban: None,
+            sign_key: None,
+            max_skew: Duration::from_secs(30),
+            replay_cache: DEFAULT_REPLAY_CACHE,
+            command: None,
+            command_timeout: hooks::DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    /// Check that the sequence can be listened for.
+    pub fn validate(&self) -> Result<(), AppError> {
+        match (&self.one_time_file, self.sequence.is_empty()) {
+            (None, true) => {
+                return Err(AppError::InvalidConfig(
+                    "listening needs a sequence of at least one port".into(),
+                ))
+            }
+            (Some(_), false) => {
+                return Err(AppError::InvalidConfig(
+                    "give either a sequence or a one-time file, not both".into(),
+                ))
+            }
+            _ => {}
+        }
+        if let Some(step) = self
+            .sequence
+            .iter()
+            .find(|s| !matches!(self.protocol_of(s), Protocol::Tcp | Protocol::Udp))
+        {
+            return Err(AppError::InvalidConfig(format!(
+                "cannot listen for {} knocks (step '{step}'); use tcp or udp",
+                self.protocol_of(step)
+            )));
+        }
+        if self.seq_timeout.is_zero() {
+            return Err(AppError::InvalidConfig(
+                "the sequence timeout must be longer than zero".into(),
+            ));
+        }
+        if self.max_sources == 0 {
+            return Err(AppError::InvalidConfig(
+                "at least one source has to be followed".into(),
+            ));
+        }
+        if self.sign_key.is_some() {
+            if let Some(step) = self
+                .sequence
+                .iter()
+                .find(|s| self.protocol_of(s) != Protocol::Udp)
+            {
+                return Err(AppError::Sign(format!(
+                    "signed knocks are UDP datagrams; step '{step}' is not"
+                )));
+            }
+            if self.replay_cache == 0 {
+                return Err(AppError::InvalidConfig(
+                    "signed knocks need a replay cache of at least one nonce".into(),
+                ));
+            }
+        }
+        if self.ban.is_some_and(|ban| ban.after == 0) {
+            return Err(AppError::InvalidConfig(
+                "a ban needs at least one out-of-order knock".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn protocol_of(&self, step: &ListenStep) -> Protocol {
+        step.protocol.unwrap_or(self.protocol)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    pub fn bind(mut self, ip: IpAddr) -> Self {
+        self.config.bind = ip;
+        self
+    }
+
+    /// Ports with the server's protocol.
+    pub fn sequence(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.config.sequence = ports
+            .into_iter()
+            .map(|port| ListenStep {
+                port,
+                protocol: None,
+            })
+            .collect();
+        self
+    }
+
+    /// Ports, each possibly with its own protocol.
+    pub fn steps(mut self, steps: impl IntoIterator<Item = ListenStep>) -> Self {
+        self.config.sequence = steps.into_iter().collect();
+        self
+    }
+
+    /// Accept each sequence of this file once instead of a fixed one.
+    pub fn one_time_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.one_time_file = Some(path.into());
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = protocol;
+        self
+    }
+
+    pub fn seq_timeout(mut self, timeout: Duration) -> Self {
+        self.config.seq_timeout = timeout;
+        self
+    }
+
+    pub fn max_sources(mut self, max: usize) -> Self {
+        self.config.max_sources = max;
+        self
+    }
+
+    /// Ignore a source for `duration` after `after` out-of-order knocks.
+    pub fn ban(mut self, after: u32, duration: Duration) -> Self {
+        self.config.ban = Some(BanPolicy { after, duration });
+        self
+    }
+
+    pub fn command(mut self, command: impl Into<String>) -> Self {
+        self.config.command = Some(command.into());
+        self
+    }
+
+    /// Kill the command when it runs longer than `timeout`.
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.config.command_timeout = timeout;
+        self
+    }
+
+    /// Only count UDP knocks signed with the key `source` holds.
+    pub fn sign_key(mut self, source: SecretSource) -> Self {
+        self.config.sign_key = Some(source);
+        self
+    }
+
+    pub fn max_skew(mut self, skew: Duration) -> Self {
+        self.config.max_skew = skew;
+        self
+    }
+
+    pub fn replay_cache(mut self, nonces: usize) -> Self {
+        self.config.replay_cache = nonces;
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, AppError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(feature = "cli")]
+impl From<ListenCli> for ServerConfig {
+    fn from(cli: ListenCli) -> Self {
+        Self {
+            bind: cli.bind,
+            sequence: cli.sequence,
+            one_time_file: cli.one_time_file,
+            protocol: cli.protocol,
+            seq_timeout: Duration::from_millis(cli.seq_timeout),
+            max_sources: cli.max_sources,
+            ban: cli.ban_after.map(|after| BanPolicy {
+                after,
+                duration: Duration::from_secs(cli.ban_time),
+            }),
+            sign_key: cli.sign_key,
+            max_skew: Duration::from_secs(cli.max_skew),
+            replay_cache: cli.replay_cache,
+            command: cli.command,
+            command_timeout: cli.command_timeout,
+        }
+    }
+}
+
+/// A source that knocked the whole sequence.
+#[derive(Debug)]
+pub struct SequenceCompleted {
+    pub source: IpAddr,
+    /// From its first knock of the sequence to its last.
+    pub elapsed: Duration,
+    /// Whether the command, if one is configured, succeeded; why not when
+    /// it could not be started, failed or was killed for running too long.
+    /// Not run when a one-time sequence could not be crossed off.
+    pub command: Option<Result<(), String>>,
+    /// The one-time sequence crossed off, or why it could not be.
+    pub one_time: Option<Result<OneTimeUse, String>>,
+    /// What the client signed along with its last knock, as text, or hex
+    /// when it is not UTF-8; with a signing key only.
+    pub client: Option<String>,
+}
+
+/// A one-time sequence that was used up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OneTimeUse {
+    /// Its line in the file, counting from 1.
+    pub line: usize,
+    /// Unused sequences left; none means no knock opens the host any more.
+    pub remaining: usize,
+}
+
+/// A knock that reached one of the server's ports.
+struct Hit {
+    source: IpAddr,
+    protocol: Protocol,
+    port: u16,
+    /// Identifier of a signed knock.
+    client: Option<String>,
+}
+
+/// Listens for the sequence; see the [module docs](self). The ports close
+/// when it is dropped.
+pub struct KnockServer {
+    ports: Vec<u16>,
+    completed: mpsc::UnboundedReceiver<SequenceCompleted>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl KnockServer {
+    /// Bind every port of the sequence, or of every unused one-time
+    /// sequence, and start listening.
+    pub async fn bind(config: ServerConfig) -> Result<Self, AppError> {
+        config.validate()?;
+        let (hits_tx, hits) = mpsc::unbounded_channel();
+        let signed = match &config.sign_key {
+            Some(source) => Some(Arc::new(SignedCheck {
+                key: source.read("signing key").await.map_err(AppError::Sign)?,
+                max_skew: config.max_skew,
+                seen: Mutex::new(ReplayCache::new(config.replay_cache)),
+            })),
+            None => None,
+        };
+        let mut listener = Listener {
+            ip: config.bind,
+            signed,
+            hits: hits_tx,
+            bound: HashMap::new(),
+            tasks: Vec::new(),
+        };
+        let (sequence, one_time) = match &config.one_time_file {
+            Some(path) => {
+                let file = OneTimeFile::new(path);
+                let unused = file
+                    .unused()
+                    .map_err(|e| AppError::InvalidConfig(format!("{}: {e}", path.display())))?;
+                let Some((line, steps)) = unused.first() else {
+                    return Err(AppError::InvalidConfig(format!(
+                        "{} has no unused sequences",
+                        path.display()
+                    )));
+                };
+                // Later sequences knock ports of their own; bind them all now
+                for (_, steps) in &unused[1..] {
+                    for step in steps {
+                        listener.bind(config.protocol_of(step), step.port).await?;
+                    }
+                }
+                let mut sequence = Vec::with_capacity(steps.len());
+                for step in steps {
+                    sequence.push(listener.bind(config.protocol_of(step), step.port).await?);
+                }
+                (sequence, Some((file, *line)))
+            }
+            None => {
+                let mut sequence = Vec::with_capacity(config.sequence.len());
+                for step in &config.sequence {
+                    sequence.push(listener.bind(config.protocol_of(step), step.port).await?);
+                }
+                (sequence, None)
+            }
+        };
+
+        let (completed_tx, completed) = mpsc::unbounded_channel();
+        let ports = sequence.iter().map(|&(_, port)| port).collect();
+        let mut tasks = listener.tasks;
+        let tracker = SequenceTracker::new(sequence, config.seq_timeout)
+            .max_sources(config.max_sources)
+            .ban(config.ban);
+        let one_time = one_time.map(|(file, line)| OneTimeState {
+            file,
+            line,
+            protocol: config.protocol,
+        });
+        tasks.push(tokio::spawn(track(
+            tracker,
+            hits,
+            config.command.map(|c| (c, config.command_timeout)),
+            one_time,
+            completed_tx,
+        )));
+        Ok(Self {
+            ports,
+            completed,
+            tasks,
+        })
+    }
+
+    /// The port bound for each step, in sequence order: the configured
+    /// one, or the one picked for port 0. With a one-time file, those of
+    /// its first unused sequence.
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    /// Wait for the next source to complete the sequence, after its
+    /// command, if any, has finished.
+    pub async fn next_completed(&mut self) -> Option<SequenceCompleted> {
+        self.completed.recv().await
+    }
+}
+
+impl Drop for KnockServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// The ports bound so far and the tasks listening on them.
+struct Listener {
+    ip: IpAddr,
+    signed: Option<Arc<SignedCheck>>,
+    hits: mpsc::UnboundedSender<Hit>,
+    bound: HashMap<(Protocol, u16), u16>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Listener {
+    /// Listen on `port`, a free one for 0, unless already listening there;
+    /// returns the step as knocked.
+    async fn bind(&mut self, protocol: Protocol, port: u16) -> Result<(Protocol, u16), AppError> {
+        if let Some(&bound) = self.bound.get(&(protocol, port)).filter(|_| port != 0) {
+            return Ok((protocol, bound));
+        }
+        if self.signed.is_some() && protocol != Protocol::Udp {
+            return Err(AppError::Sign(format!(
+                "signed knocks are UDP datagrams; port {port} is {protocol}"
+            )));
+        }
+        let addr = SocketAddr::new(self.ip, port);
+        let bind_error = |source| AppError::Bind { addr, source };
+        let hits = self.hits.clone();
+        let bound = match protocol {
+            Protocol::Udp => {
+                let socket = UdpSocket::bind(addr).await.map_err(bind_error)?;
+                let bound = socket.local_addr().map_err(bind_error)?.port();
+                let signed = self.signed.clone();
+                self.tasks
+                    .push(tokio::spawn(receive(socket, bound, signed, hits)));
+                bound
+            }
+            _ => {
+                let listener = TcpListener::bind(addr).await.map_err(bind_error)?;
+                let bound = listener.local_addr().map_err(bind_error)?.port();
+                self.tasks.push(tokio::spawn(accept(listener, bound, hits)));
+                bound
+            }
+        };
+        self.bound.insert((protocol, port), bound);
+        Ok((protocol, bound))
+    }
+}
+
+/// Report each accepted connection and close it.
+async fn accept(listener: TcpListener, port: u16, hits: mpsc::UnboundedSender<Hit>) {
+    while let Ok((_stream, source)) = listener.accept().await {
+        let hit = Hit {
+            source: source.ip().to_canonical(),
+            protocol: Protocol::Tcp,
+            port,
+            client: None,
+        };
+        if hits.send(hit).is_err() {
+            return;
+        }
+    }
+}
+
+/// Report each datagram, or with a signing key each one carrying a valid
+/// signed knock for this port.
+async fn receive(
+    socket: UdpSocket,
+    port: u16,
+    signed: Option<Arc<SignedCheck>>,
+    hits: mpsc::UnboundedSender<Hit>,
+) {
+    let mut buf = vec![0u8; 65536];
+    while let Ok((n, source)) = socket.recv_from(&mut buf).await {
+        let client = match &signed {
+            Some(signed) => match signed.check(&buf[..n], port) {
+                Some(client) => Some(client),
+                None => continue,
+            },
+            None => None,
+        };
+        let hit = Hit {
+            source: source.ip().to_canonical(),
+            protocol: Protocol::Udp,
+            port,
+            client,
+        };
+        if hits.send(hit).is_err() {
+            return;
+        }
+    }
+}
+
+/// The one-time sequence being listened for.
+struct OneTimeState {
+    file: OneTimeFile,
+    line: usize,
+    /// Protocol of the steps that do not name one.
+    protocol: Protocol,
+}
+
+impl OneTimeState {
+    /// Cross off the current sequence and move the tracker on to the next,
+    /// or to none when they ran out.
+    fn consume(&mut self, tracker: &mut SequenceTracker) -> Result<OneTimeUse, String> {
+        let used = self.line;
+        let unused = self
+            .file
+            .consume(used)
+            .map_err(|e| format!("{}: {e}", self.file.path().display()))?;
+        let next = match unused.first() {
+            Some((line, steps)) => {
+                self.line = *line;
+                steps
+                    .iter()
+                    .map(|s| (s.protocol.unwrap_or(self.protocol), s.port))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        tracker.restart(next);
+        Ok(OneTimeUse {
+            line: used,
+            remaining: unused.len(),
+        })
+    }
+}
+
+/// Follow the sources through the sequence and run the command for each
+/// that completes it, once a one-time sequence is crossed off.
+async fn track(
+    mut tracker: SequenceTracker,
+    mut hits: mpsc::UnboundedReceiver<Hit>,
+    command: Option<(String, Duration)>,
+    mut one_time: Option<OneTimeState>,
+    completed: mpsc::UnboundedSender<SequenceCompleted>,
+) {
+    while let Some(hit) = hits.recv().await {
+        let TrackedKnock::Completed { elapsed } =
+            tracker.knock(hit.source, hit.protocol, hit.port, Instant::now())
+        else {
+            continue;
+        };
+        let used = one_time.as_mut().map(|state| state.consume(&mut tracker));
+        let status = match &command {
+            Some(_) if matches!(used, Some(Err(_))) => None,
+            Some((command, timeout)) => {
+                Some(run_command(command, *timeout, hit.source, hit.client.as_deref()).await)
+            }
+            None => None,
+        };
+        let done = SequenceCompleted {
+            source: hit.source,
+            elapsed,
+            command: status,
+            one_time: used,
+            client: hit.client,
+        };
+        if completed.send(done).is_err() {
+            return;
+        }
+    }
+}
+
+/// Run `command` as a hook for `source`, with the identifier it signed,
+/// if any, in the environment.
+async fn run_command(
+    command: &str,
+    timeout: Duration,
+    source: IpAddr,
+    client: Option<&str>,
+) -> Result<(), String> {
+    let command = command.replace(IP_PLACEHOLDER, &source.to_string());
+    let env: Vec<_> = client
+        .map(|client| (CLIENT_ID_ENV, client.to_string()))
+        .into_iter()
+        .collect();
+    hooks::run_hook(&command, &env, timeout).await
+}
+
+/// Sources a [`SequenceTracker`] follows at once unless told otherwise.
+pub const DEFAULT_MAX_SOURCES: usize = 4096;
+
+/// Ignore a source for a while after it knocks out of order too often.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanPolicy {
+    /// Out-of-order knocks, since the source last completed the sequence,
+    /// that get it banned.
+    pub after: u32,
+    pub duration: Duration,
+}
+
+/// How a [`SequenceTracker`] took a knock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackedKnock {
+    /// The step the source was due; `done` steps of the sequence are in.
+    Advanced { done: usize },
+    /// The last step, this long after the source's first.
+    Completed { elapsed: Duration },
+    /// Not the step the source was due. Its progress starts over, from the
+    /// first step if that is the port knocked.
+    OutOfOrder,
+    /// Out of order once too often: the source is ignored until `until`.
+    Banned { until: Instant },
+    /// From a banned source, and ignored.
+    Ignored,
+}
+
+/// What the tracker knows of one source.
+#[derive(Debug)]
+struct Source {
+    /// Steps knocked so far, and when the first of them was.
+    done: usize,
+    started: Instant,
+    last_seen: Instant,
+    /// Out-of-order knocks since it last completed the sequence.
+    wrong: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Source {
+    fn banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Follows each source IP through the sequence on its own, without any
+/// sockets: feed it the knocks and it tells what they did.
+///
+/// A source's partial progress expires `seq_timeout` after its first step.
+/// At most `max_sources` sources are followed; a new one beyond that
+/// pushes out the one heard from least recently, banned sources last.
+#[derive(Debug)]
+pub struct SequenceTracker {
+    sequence: Vec<(Protocol, u16)>,
+    seq_timeout: Duration,
+    max_sources: usize,
+    ban: Option<BanPolicy>,
+    sources: HashMap<IpAddr, Source>,
+}
+
+impl SequenceTracker {
+    /// Follow sources through `sequence`, each step a protocol and port.
+    pub fn new(sequence: Vec<(Protocol, u16)>, seq_timeout: Duration) -> Self {
+        Self {
+            sequence,
+            seq_timeout,
+            max_sources: DEFAULT_MAX_SOURCES,
+            ban: None,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Follow at most this many sources at once (at least one).
+    pub fn max_sources(mut self, max: usize) -> Self {
+        self.max_sources = max.max(1);
+        self
+    }
+
+    pub fn ban(mut self, ban: Option<BanPolicy>) -> Self {
+        self.ban = ban;
+        self
+    }
+
+    /// Listen for `sequence` from now on, every source starting over; bans
+    /// stay. An empty sequence is never completed.
+    pub fn restart(&mut self, sequence: Vec<(Protocol, u16)>) {
+        self.sequence = sequence;
+        for source in self.sources.values_mut() {
+            source.done = 0;
+        }
+    }
+
+    /// Sources followed right now.
+    pub fn tracked(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Take a knock on `port` over `protocol` from `source` at `now`.
+    pub fn knock(
+        &mut self,
+        source: IpAddr,
+        protocol: Protocol,
+        port: u16,
+        now: Instant,
+    ) -> TrackedKnock {
+        if !self.sources.contains_key(&source) {
+            self.make_room(now);
+        }
+        let seq_timeout = self.seq_timeout;
+        let state = self.sources.entry(source).or_insert(Source {
+            done: 0,
+            started: now,
+            last_seen: now,
+            wrong: 0,
+            banned_until: None,
+        });
+        if state.banned(now) {
+            return TrackedKnock::Ignored;
+        }
+        state.last_seen = now;
+        // Progress that ran out of time starts over
+        if state.done > 0 && now.duration_since(state.started) > seq_timeout {
+            state.done = 0;
+        }
+
+        let knock = (protocol, port);
+        if self.sequence.get(state.done) == Some(&knock) {
+            if state.done == 0 {
+                state.started = now;
+            }
+            state.done += 1;
+            if state.done < self.sequence.len() {
+                return TrackedKnock::Advanced { done: state.done };
+            }
+            let elapsed = now.duration_since(state.started);
+            self.sources.remove(&source);
+            return TrackedKnock::Completed { elapsed };
+        }
+
+        // Out of order: start over, counting this knock if it is the first
+        // step of the sequence
+        let first = self.sequence.first() == Some(&knock);
+        state.done = usize::from(first);
+        state.started = now;
+        state.wrong += 1;
+        match self.ban {
+            Some(ban) if state.wrong >= ban.after => {
+                let until = now + ban.duration;
+                state.banned_until = Some(until);
+                state.done = 0;
+                state.wrong = 0;
+                TrackedKnock::Banned { until }
+            }
+            _ => TrackedKnock::OutOfOrder,
+        }
+    }
+
+    /// Forget sources gone quiet for longer than a sequence may take and
+    /// whose ban is over, then the least recently heard from until a new
+    /// one fits.
+    fn make_room(&mut self, now: Instant) {
+        if self.sources.len() < self.max_sources {
+            return;
+        }
+        let seq_timeout = self.seq_timeout;
+        self.sources
+            .retain(|_, s| s.banned(now) || now.duration_since(s.last_seen) <= seq_timeout);
+        while self.sources.len() >= self.max_sources {
+            let oldest = self
+                .sources
+                .iter()
+                .min_by_key(|(_, s)| (s.banned(now), s.last_seen))
+                .map(|(&ip, _)| ip);
+            match oldest {
+                Some(ip) => self.sources.remove(&ip),
+                None => break,
+            };
+        }
+    }
+}
+
+/// Verifies the signed knocks a server is given.
+///
+/// Deliberately not `Debug`, so the key cannot end up in a log line.
+struct SignedCheck {
+    key: Secret,
+    max_skew: Duration,
+    seen: Mutex<ReplayCache>,
+}
+
+impl SignedCheck {
+    /// The client identifier of `datagram` when it is a signed knock for
+    /// `port`, recent enough and not seen before.
+    fn check(&self, datagram: &[u8], port: u16) -> Option<String> {
+        let knock = signed::verify_signed_knock(&self.key, datagram, self.max_skew).ok()?;
+        if knock.port != port || !self.seen.lock().unwrap().insert(knock.nonce) {
+            return None;
+        }
+        Some(match String::from_utf8(knock.extra) {
+            Ok(text) => text,
+            Err(e) => hex::encode(e.into_bytes()),
+        })
+    }
+}
+
+/// Nonces of the signed knocks accepted lately, the oldest forgotten first.
+/// Only knocks whose signature checks out get in, so the cache cannot be
+/// flushed without the key.
+#[derive(Debug)]
+struct ReplayCache {
+    capacity: usize,
+    order: VecDeque<[u8; NONCE_LEN]>,
+    seen: HashSet<[u8; NONCE_LEN]>,
+}
+
+impl ReplayCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Remember `nonce`; `false` if it was already.
+    fn insert(&mut self, nonce: [u8; NONCE_LEN]) -> bool {
+        if !self.seen.insert(nonce) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(nonce);
+        true
+    }
+}
+
+/// A file of knock sequences each accepted once, like knockd's
+/// `one_time_sequences`.
+///
+/// Each line holds a sequence in `--sequence` form (`7000,8000/udp,9000`);
+/// blank lines and lines starting with `#` are skipped. A used sequence is
+/// commented out in place, under a lock and by replacing the whole file,
+/// so the line numbers of the others stay put and a crash leaves either
+/// the old file or the new one.
+#[derive(Debug, Clone)]
+pub struct OneTimeFile {
+    path: PathBuf,
+}
+
+impl OneTimeFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The sequences not used yet, in file order, with their line numbers
+    /// counting from 1.
+    pub fn unused(&self) -> io::Result<Vec<(usize, Vec<ListenStep>)>> {
+        parse_one_time(&fs::read_to_string(&self.path)?)
+    }
+
+    /// Cross off the sequence on `line`, which must not be used yet, and
+    /// return the sequences still unused.
+    pub fn consume(&self, line: usize) -> io::Result<Vec<(usize, Vec<ListenStep>)>> {
+        // Held until the new file is in place, so two servers (or an edit
+        // through this type) cannot both rewrite it from the same copy
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling(".lock"))?;
+        lock.lock()?;
+
+        let text = fs::read_to_string(&self.path)?;
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let entry = line.checked_sub(1).and_then(|i| lines.get_mut(i));
+        let entry = match entry {
+            Some(entry) if !is_skipped(entry) => entry,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {line} holds no unused sequence"),
+                ))
+            }
+        };
+        *entry = format!("# used: {}", entry.trim());
+        let mut text = lines.join("\n");
+        text.push('\n');
+
+        let tmp = self.sibling(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        parse_one_time(&text)
+    }
+
+    /// `path` with `suffix` appended to its file name.
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(suffix);
+        PathBuf::from(name)
+    }
+}
+
+fn is_skipped(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// The unused sequences of a one-time file.
+fn parse_one_time(text: &str) -> io::Result<Vec<(usize, Vec<ListenStep>)>> {
+    let invalid = |line: usize, e: String| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("line {line}: {e}"))
+    };
+    let mut sequences = Vec::new();
+    for (i, entry) in text.lines().enumerate() {
+        let line = i + 1;
+        if is_skipped(entry) {
+            continue;
+        }
+        let steps = entry
+            .split(',')
+            .map(ListenStep::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(line, e))?;
+        if steps.iter().any(|s| s.port == 0) {
+            return Err(invalid(line, "port 0 cannot be knocked".into()));
+        }
+        sequences.push((line, steps));
+    }
+    Ok(sequences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run, KnockConfig};
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    const C: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+
+    fn tracker() -> SequenceTracker {
+        let sequence = vec![
+            (Protocol::Tcp, 7000),
+            (Protocol::Udp, 8000),
+            (Protocol::Tcp, 9000),
+        ];
+        SequenceTracker::new(sequence, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn steps_parse_with_and_without_protocol() {
+        let step = ListenStep::parse("7000/udp").unwrap();
+        assert_eq!((step.port, step.protocol), (7000, Some(Protocol::Udp)));
+        assert_eq!(ListenStep::parse("7000").unwrap().protocol, None);
+        assert!(ListenStep::parse("7000/quic").is_err());
+        assert!(ListenStep::parse("seven").is_err());
+    }
+
+    #[test]
+    fn only_tcp_and_udp_can_be_listened_for() {
+        let config = ServerConfig::builder().sequence([7000]);
+        assert!(config.protocol(Protocol::Icmp).build().is_err());
+        assert!(ServerConfig::builder().build().is_err());
+    }
+
+    #[test]
+    fn interleaved_sources_progress_separately() {
+        use TrackedKnock::*;
+        let mut tracker = tracker();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(
+            tracker.knock(A, Protocol::Tcp, 7000, at(0)),
+            Advanced { done: 1 }
+        );
+        assert_eq!(
+            tracker.knock(B, Protocol::Tcp, 7000, at(10)),
+            Advanced { done: 1 }
+        );
+        assert_eq!(
+            tracker.knock(A, Protocol::Udp, 8000, at(20)),
+            Advanced { done: 2 }
+        );
+        assert_eq!(
+            tracker.knock(B, Protocol::Udp, 8000, at(30)),
+            Advanced { done: 2 }
+        );
+        let elapsed = Duration::from_millis(40);
+        assert_eq!(
+            tracker.knock(A, Protocol::Tcp, 9000, at(40)),
+            Completed { elapsed }
+        );
+        // Completing forgets the source, so it starts over
+        assert_eq!(tracker.tracked(), 1);
+        assert_eq!(tracker.knock(A, Protocol::Tcp, 9000, at(50)), OutOfOrder);
+        let elapsed = Duration::from_millis(50);
+        assert_eq!(
+            tracker.knock(B, Protocol::Tcp, 9000, at(60)),
+            Completed { elapsed }
+        );
+    }
+
+    #[test]
+    fn out_of_order_knocks_reset_progress() {
+        use TrackedKnock::*;
+        let mut tracker = tracker();
+        let now = Instant::now();
+        tracker.knock(A, Protocol::Tcp, 7000, now);
+        // The right port over the wrong protocol is a wrong knock
+        assert_eq!(tracker.knock(A, Protocol::Tcp, 8000, now), OutOfOrder);
+        assert_eq!(tracker.knock(A, Protocol::Udp, 8000, now), OutOfOrder);
+        assert_eq!(tracker.knock(A, Protocol::Tcp, 9000, now), OutOfOrder);
+
+        // The first port again starts the sequence over from there
+        tracker.knock(A, Protocol::Tcp, 7000, now);
+        assert_eq!(tracker.knock(A, Protocol::Tcp, 7000, now), OutOfOrder);
+        assert_eq!(
+            tracker.knock(A, Protocol::Udp, 8000, now),
+            Advanced { done: 2 }
+        );
+        assert!(matches!(
+            tracker.knock(A, Protocol::Tcp, 9000, now),
+            Completed { .. }
+        ));
+    }
+
+    #[test]
+    fn progress_expires_after_the_sequence_timeout() {
+        use TrackedKnock::*;
+        let mut tracker = tracker();
+        let start = Instant::now();
+        tracker.knock(A, Protocol::Tcp, 7000, start);
+        tracker.knock(A, Protocol::Udp, 8000, start + Duration::from_secs(3));
+        let late = start + Duration::from_secs(6);
+        assert_eq!(tracker.knock(A, Protocol::Tcp, 9000, late), OutOfOrder);
+
+        // Timed from the first step, which a late one starts afresh
+        assert_eq!(
+            tracker.knock(A, Protocol::Tcp, 7000, late),
+            Advanced { done: 1 }
+        );
+        let on_time = late + Duration::from_secs(5);
+        tracker.knock(A, Protocol::Udp, 8000, on_time);
+        assert!(matches!(
+            tracker.knock(A, Protocol::Tcp, 9000, on_time),
+            Completed { .. }
+        ));
+    }
+
+    #[test]
+    fn sources_knocking_out_of_order_get_banned() {
+        use TrackedKnock::*;
+        let ban = BanPolicy {
+            after: 2,
+            duration: Duration::from_secs(60),
+        };
+        let mut tracker = tracker().ban(Some(ban));
+        let start = Instant::now();
+        assert_eq!(tracker.knock(A, Protocol::Udp, 8000, start), OutOfOrder);
+        let until = start + Duration::from_secs(60);
+        assert_eq!(
+            tracker.knock(A, Protocol::Tcp, 9000, start),
+            Banned { until }
+        );
+
+        // Even the right sequence is ignored while banned
+        for (protocol, port) in [(Protocol::Tcp, 7000), (Protocol::Udp, 8000)] {
+            assert_eq!(tracker.knock(A, protocol, port, start), Ignored);
+        }
+        assert_eq!(
+            tracker.knock(B, Protocol::Tcp, 7000, start),
+            Advanced { done: 1 }
+        );
+        assert_eq!(
+            tracker.knock(A, Protocol::Tcp, 7000, until),
+            Advanced { done: 1 }
+        );
+    }
+
+    #[test]
+    fn sources_beyond_the_cap_push_out_the_quietest() {
+        use TrackedKnock::*;
+        let mut tracker = tracker().max_sources(2);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        tracker.knock(A, Protocol::Tcp, 7000, at(0));
+        tracker.knock(B, Protocol::Tcp, 7000, at(10));
+        tracker.knock(A, Protocol::Udp, 8000, at(20));
+        tracker.knock(C, Protocol::Tcp, 7000, at(30));
+        assert_eq!(tracker.tracked(), 2);
+
+        // B was pushed out, A kept its progress
+        assert!(matches!(
+            tracker.knock(A, Protocol::Tcp, 9000, at(40)),
+            Completed { .. }
+        ));
+        assert_eq!(tracker.knock(B, Protocol::Udp, 8000, at(50)), OutOfOrder);
+    }
+
+    #[tokio::test]
+    async fn client_knocks_open_the_server() {
+        let config = ServerConfig::builder()
+            .bind(Ipv4Addr::LOCALHOST.into())
+            .steps([
+                ListenStep::parse("0/udp").unwrap(),
+                ListenStep::parse("0/tcp").unwrap(),
+                ListenStep::parse("0/udp").unwrap(),
+            ])
+            .command(format!("test {IP_PLACEHOLDER} = 127.0.0.1"))
+            .build()
+            .unwrap();
+        let mut server = KnockServer::bind(config).await.unwrap();
+        let ports = server.ports().to_vec();
+
+        // Starting with a later port does not count
+        let steps = [ports[2], ports[0], ports[1], ports[2]]
+            .iter()
+            .zip(["udp", "udp", "tcp", "udp"])
+            .map(|(port, proto)| crate::plan::KnockStep::parse(&format!("{port}/{proto}")))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let client = KnockConfig::builder()
+            .host("127.0.0.1")
+            .steps(steps)
+            .build()
+            .unwrap();
+        run(client).await.unwrap();
+
+        let done = tokio::time::timeout(Duration::from_secs(2), server.next_completed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(done.source, IpAddr::from(Ipv4Addr::LOCALHOST));
+        #[cfg(unix)]
+        assert_eq!(done.command, Some(Ok(())));
+    }
+
+    #[tokio::test]
+    async fn wrong_order_from_the_client_does_not_open() {
+        let config = ServerConfig::builder()
+            .bind(Ipv4Addr::LOCALHOST.into())
+            .sequence([0, 0])
+            .build()
+            .unwrap();
+        let mut server = KnockServer::bind(config).await.unwrap();
+        let ports = server.ports().to_vec();
+        let client = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([ports[1], ports[0]])
+            .build()
+            .unwrap();
+        run(client).await.unwrap();
+
+        let done = tokio::time::timeout(Duration::from_millis(300), server.next_completed()).await;
+        assert!(done.is_err(), "{done:?}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn commands_running_too_long_are_killed() {
+        let config = ServerConfig::builder()
+            .bind(Ipv4Addr::LOCALHOST.into())
+            .sequence([0])
+            .command("sleep 10")
+            .command_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let mut server = KnockServer::bind(config).await.unwrap();
+        let client = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence(server.ports().to_vec())
+            .build()
+            .unwrap();
+        run(client).await.unwrap();
+
+        let done = tokio::time::timeout(Duration::from_secs(5), server.next_completed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(done.command, Some(Err("killed after running 0.2s".into())));
+    }
+
+    /// A file of one-time sequences that lasts for the test.
+    fn one_time_file(name: &str, text: &str) -> OneTimeFile {
+        let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        fs::write(&path, text).unwrap();
+        OneTimeFile::new(path)
+    }
+
+    #[test]
+    fn one_time_lines_are_crossed_off_in_place() {
+        let file = one_time_file("one-time-cross", "# spares\n7000,8000/udp\n\n9000\n");
+        let unused = file.unused().unwrap();
+        assert_eq!(unused.iter().map(|(l, _)| *l).collect::<Vec<_>>(), [2, 4]);
+
+        let left = file.consume(2).unwrap();
+        assert_eq!(left.iter().map(|(l, _)| *l).collect::<Vec<_>>(), [4]);
+        let text = fs::read_to_string(file.path()).unwrap();
+        assert_eq!(text, "# spares\n# used: 7000,8000/udp\n\n9000\n");
+        // A used line cannot be used again
+        assert!(file.consume(2).is_err());
+        assert!(file.consume(3).is_err());
+
+        assert!(file.consume(4).unwrap().is_empty());
+        fs::remove_file(file.path()).unwrap();
+        let _ = fs::remove_file(file.sibling(".lock"));
+    }
+
+    #[test]
+    fn bad_one_time_lines_are_named() {
+        let file = one_time_file("one-time-bad", "7000\n7000,eighty\n");
+        let error = file.unused().unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{error}");
+        fs::remove_file(file.path()).unwrap();
+    }
+
+    /// The next completed sequence, unless none is within half a second.
+    async fn next_completed(server: &mut KnockServer) -> Option<SequenceCompleted> {
+        let wait = Duration::from_millis(500);
+        tokio::time::timeout(wait, server.next_completed())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn one_time_sequences_open_once_each() {
+        // Ports free right now, for sequences that have to name them
+        let mut free = Vec::new();
+        for _ in 0..4 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            free.push(listener.local_addr().unwrap().port());
+        }
+        let text = format!("{},{}\n{},{}\n", free[0], free[1], free[2], free[3]);
+        let file = one_time_file("one-time-server", &text);
+        let config = ServerConfig::builder()
+            .bind(Ipv4Addr::LOCALHOST.into())
+            .one_time_file(file.path())
+            .build()
+            .unwrap();
+        let mut server = KnockServer::bind(config).await.unwrap();
+        assert_eq!(server.ports(), &free[..2]);
+
+        let knock = |ports: &[u16]| {
+            let client = KnockConfig::builder()
+                .host("127.0.0.1")
+                .sequence(ports.to_vec())
+                .build()
+                .unwrap();
+            run(client)
+        };
+        knock(&free[..2]).await.unwrap();
+        let done = next_completed(&mut server).await.unwrap();
+        let used = OneTimeUse {
+            line: 1,
+            remaining: 1,
+        };
+        assert_eq!(done.one_time, Some(Ok(used)));
+
+        // Replaying the used sequence opens nothing
+        knock(&free[..2]).await.unwrap();
+        assert!(next_completed(&mut server).await.is_none());
+
+        knock(&free[2..]).await.unwrap();
+        let done = next_completed(&mut server).await.unwrap();
+        assert_eq!(done.one_time.unwrap().unwrap().remaining, 0);
+        fs::remove_file(file.path()).unwrap();
+        let _ = fs::remove_file(file.sibling(".lock"));
+    }
+
+    #[test]
+    fn replay_cache_forgets_the_oldest_nonce() {
+        let mut cache = ReplayCache::new(2);
+        assert!(cache.insert([1; NONCE_LEN]));
+        assert!(!cache.insert([1; NONCE_LEN]));
+        assert!(cache.insert([2; NONCE_LEN]));
+        assert!(cache.insert([3; NONCE_LEN]));
+        assert_eq!(cache.seen.len(), 2);
+        assert!(cache.insert([1; NONCE_LEN]));
+        assert!(!cache.insert([3; NONCE_LEN]));
+    }
+
+    #[test]
+    fn signed_knocks_are_udp_only() {
+        let config = ServerConfig::builder()
+            .sequence([7000])
+            .sign_key(SecretSource::File("key".into()))
+            .build();
+        assert!(matches!(config, Err(AppError::Sign(_))), "{config:?}");
+    }
+
+    #[tokio::test]
+    async fn only_fresh_signed_knocks_open() {
+        let key = std::env::temp_dir().join(format!("listen-sign-key-{}", std::process::id()));
+        fs::write(&key, "listen-test-key\n").unwrap();
+        let config = ServerConfig::builder()
+            .bind(Ipv4Addr::LOCALHOST.into())
+            .protocol(Protocol::Udp)
+            .sequence([0, 0])
+            .sign_key(SecretSource::File(key.clone()))
+            .command(format!("test \"${CLIENT_ID_ENV}\" = alice"))
+            .build()
+            .unwrap();
+        let mut server = KnockServer::bind(config).await.unwrap();
+        let ports = server.ports().to_vec();
+
+        // Unsigned knocks do not count
+        let client = KnockConfig::builder()
+            .host("127.0.0.1")
+            .protocol(Protocol::Udp)
+            .sequence(ports.clone());
+        run(client.clone().build().unwrap()).await.unwrap();
+        assert!(next_completed(&mut server).await.is_none());
+
+        // Signed ones do, the signed payload naming the client
+        let signed = client
+            .sign_key(SecretSource::File(key.clone()))
+            .payload(&b"alice"[..]);
+        run(signed.build().unwrap()).await.unwrap();
+        let done = next_completed(&mut server).await.unwrap();
+        assert_eq!(done.client.as_deref(), Some("alice"));
+        #[cfg(unix)]
+        assert_eq!(done.command, Some(Ok(())));
+
+        // A knock replayed, or signed for another port, is ignored
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let first = signed::sign_knock_now(b"listen-test-key", ports[0], b"bob");
+        let wrong_port = signed::sign_knock_now(b"listen-test-key", ports[0], b"bob");
+        let second = signed::sign_knock_now(b"listen-test-key", ports[1], b"bob");
+        socket.send_to(&first, ("127.0.0.1", ports[0])).unwrap();
+        socket
+            .send_to(&wrong_port, ("127.0.0.1", ports[1]))
+            .unwrap();
+        socket.send_to(&first, ("127.0.0.1", ports[0])).unwrap();
+        socket.send_to(&second, ("127.0.0.1", ports[1])).unwrap();
+        let done = next_completed(&mut server).await.unwrap();
+        assert_eq!(done.client.as_deref(), Some("bob"));
+        socket.send_to(&first, ("127.0.0.1", ports[0])).unwrap();
+        socket.send_to(&second, ("127.0.0.1", ports[1])).unwrap();
+        assert!(next_completed(&mut server).await.is_none());
+        fs::remove_file(key).unwrap();
+    }
+}
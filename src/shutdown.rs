@@ -0,0 +1,221 @@
+//! Stopping a run when the process is asked to exit.
+//!
+//! Besides Ctrl-C, service managers and containers stop processes with
+//! SIGTERM (and terminals with SIGHUP); on Windows closing the console or
+//! Ctrl-Break does the same. All of them cancel the run the same way.
+//! Listening for them needs the `runtime-tokio` feature.
+
+use std::fmt;
+#[cfg(feature = "runtime-tokio")]
+use {
+    futures::stream::{self, BoxStream, StreamExt},
+    std::io,
+    std::sync::{Arc, OnceLock},
+    tokio::task::JoinHandle,
+    tokio_util::sync::CancellationToken,
+};
+
+/// What asked the process to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownSignal {
+    /// Ctrl-C: SIGINT, or CTRL_C_EVENT on Windows.
+    Interrupt,
+    /// SIGTERM, e.g. from `systemctl stop` or `docker stop`.
+    Terminate,
+    /// SIGHUP: the controlling terminal went away.
+    Hangup,
+    /// CTRL_BREAK_EVENT (Windows).
+    CtrlBreak,
+    /// CTRL_CLOSE_EVENT: the console window was closed (Windows).
+    CtrlClose,
+    /// CTRL_SHUTDOWN_EVENT: the system is shutting down (Windows).
+    CtrlShutdown,
+}
+
+impl ShutdownSignal {
+    pub fn name(self) -> &'static str {
+        match self {
+            ShutdownSignal::Interrupt if cfg!(windows) => "Ctrl-C",
+            ShutdownSignal::Interrupt => "SIGINT",
+            ShutdownSignal::Terminate => "SIGTERM",
+            ShutdownSignal::Hangup => "SIGHUP",
+            ShutdownSignal::CtrlBreak => "Ctrl-Break",
+            ShutdownSignal::CtrlClose => "console close",
+            ShutdownSignal::CtrlShutdown => "system shutdown",
+        }
+    }
+
+    /// Exit code as shells report a process killed by the signal: 128
+    /// plus its number. Windows console events count as SIGBREAK (21) or
+    /// SIGTERM.
+    pub fn exit_code(self) -> i32 {
+        128 + match self {
+            ShutdownSignal::Interrupt => 2,
+            ShutdownSignal::Hangup => 1,
+            ShutdownSignal::CtrlBreak => 21,
+            ShutdownSignal::Terminate
+            | ShutdownSignal::CtrlClose
+            | ShutdownSignal::CtrlShutdown => 15,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Wait for the first shutdown signal.
+#[cfg(feature = "runtime-tokio")]
+pub async fn shutdown_signal() -> io::Result<ShutdownSignal> {
+    match listen()?.next().await {
+        Some(signal) => Ok(signal),
+        None => std::future::pending().await,
+    }
+}
+
+/// Cancel `cancel` on the first shutdown signal and `abort` on the second,
+/// for a run that finishes its knocks in flight when cancelled unless told
+/// again. Dropping the listener stops it cancelling anything.
+///
+/// The signals are caught from the moment this returns, and stay caught
+/// for the rest of the process: Tokio never puts back a handler it
+/// installed, so once the listener is gone SIGINT, SIGTERM and SIGHUP are
+/// ignored instead of exiting. Install your own handling if the process
+/// goes on after the run.
+#[cfg(feature = "runtime-tokio")]
+pub fn cancel_on_shutdown(
+    cancel: CancellationToken,
+    abort: CancellationToken,
+) -> io::Result<ShutdownListener> {
+    Ok(ShutdownListener::spawn(listen()?, cancel, abort))
+}
+
+/// Watches for shutdown signals; see [`cancel_on_shutdown`].
+#[cfg(feature = "runtime-tokio")]
+pub struct ShutdownListener {
+    task: JoinHandle<()>,
+    signal: Arc<OnceLock<ShutdownSignal>>,
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl ShutdownListener {
+    fn spawn(
+        mut signals: BoxStream<'static, ShutdownSignal>,
+        cancel: CancellationToken,
+        abort: CancellationToken,
+    ) -> Self {
+        let signal = Arc::new(OnceLock::new());
+        let first = Arc::clone(&signal);
+        let task = tokio::spawn(async move {
+            let Some(signal) = signals.next().await else {
+                return;
+            };
+            let _ = first.set(signal);
+            cancel.cancel();
+            if signals.next().await.is_some() {
+                abort.cancel();
+            }
+        });
+        Self { task, signal }
+    }
+
+    /// The first signal received, if any.
+    pub fn signal(&self) -> Option<ShutdownSignal> {
+        self.signal.get().copied()
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl Drop for ShutdownListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Register the handlers now and receive the signals later.
+#[cfg(all(unix, feature = "runtime-tokio"))]
+fn listen() -> io::Result<BoxStream<'static, ShutdownSignal>> {
+    use tokio::signal::unix::{signal, Signal, SignalKind};
+
+    let stream = |kind: SignalKind, which: ShutdownSignal| {
+        let signal: Signal = signal(kind)?;
+        Ok::<_, io::Error>(
+            stream::unfold(signal, move |mut signal| async move {
+                signal.recv().await.map(|()| (which, signal))
+            })
+            .boxed(),
+        )
+    };
+    Ok(stream::select_all([
+        stream(SignalKind::interrupt(), ShutdownSignal::Interrupt)?,
+        stream(SignalKind::terminate(), ShutdownSignal::Terminate)?,
+        stream(SignalKind::hangup(), ShutdownSignal::Hangup)?,
+    ])
+    .boxed())
+}
+
+#[cfg(all(windows, feature = "runtime-tokio"))]
+fn listen() -> io::Result<BoxStream<'static, ShutdownSignal>> {
+    use tokio::signal::windows;
+
+    // Each console event has its own listener type
+    macro_rules! stream {
+        ($listen:path, $which:expr) => {
+            stream::unfold($listen()?, |mut signal| async move {
+                signal.recv().await.map(|()| ($which, signal))
+            })
+            .boxed()
+        };
+    }
+    Ok(stream::select_all([
+        stream!(windows::ctrl_c, ShutdownSignal::Interrupt),
+        stream!(windows::ctrl_break, ShutdownSignal::CtrlBreak),
+        stream!(windows::ctrl_close, ShutdownSignal::CtrlClose),
+        stream!(windows::ctrl_shutdown, ShutdownSignal::CtrlShutdown),
+    ])
+    .boxed())
+}
+
+#[cfg(all(not(any(unix, windows)), feature = "runtime-tokio"))]
+fn listen() -> io::Result<BoxStream<'static, ShutdownSignal>> {
+    Ok(stream::unfold((), |()| async {
+        tokio::signal::ctrl_c()
+            .await
+            .ok()
+            .map(|()| (ShutdownSignal::Interrupt, ()))
+    })
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_follow_the_shell_convention() {
+        assert_eq!(ShutdownSignal::Interrupt.exit_code(), 130);
+        assert_eq!(ShutdownSignal::Terminate.exit_code(), 143);
+        assert_eq!(ShutdownSignal::Hangup.exit_code(), 129);
+        assert_eq!(ShutdownSignal::CtrlClose.exit_code(), 143);
+    }
+
+    #[tokio::test]
+    async fn second_signal_aborts() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let (cancel, abort) = (CancellationToken::new(), CancellationToken::new());
+        let listener = ShutdownListener::spawn(rx.boxed(), cancel.clone(), abort.clone());
+        assert_eq!(listener.signal(), None);
+
+        tx.unbounded_send(ShutdownSignal::Terminate).unwrap();
+        cancel.cancelled().await;
+        assert_eq!(listener.signal(), Some(ShutdownSignal::Terminate));
+        assert!(!abort.is_cancelled());
+
+        tx.unbounded_send(ShutdownSignal::Interrupt).unwrap();
+        abort.cancelled().await;
+        // The first signal is the one that stopped the run
+        assert_eq!(listener.signal(), Some(ShutdownSignal::Terminate));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::spa::MAC_LEN;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Length of the random nonce in a signed knock.
+pub const NONCE_LEN: usize = 16;
+/// Length of the fixed fields before the extra data.
+pub const HEADER_LEN: usize = 2 + 8 + NONCE_LEN;
+
+/// A signed knock that passed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedKnock {
+    /// Port the knock was signed for.
+    pub port: u16,
+    /// Sender clock at signing time, in Unix milliseconds.
+    pub unix_millis: u64,
+    /// Per-knock nonce; remember recent ones to reject replays within the
+    /// accepted window.
+    pub nonce: [u8; NONCE_LEN],
+    /// Payload carried along with the signed fields.
+    pub extra: Vec<u8>,
+}
+
+/// Why a signed knock was rejected.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error("signed knock is {0} bytes, shorter than the fixed fields")]
+    TooShort(usize),
+
+    #[error("signed knock HMAC does not match")]
+    BadMac,
+
+    #[error("signed knock timestamp is {0}ms away from the local clock")]
+    Skewed(u64),
+}
+
+/// Build a signed knock:
+///
+/// ```text
+/// port (2) | unix_millis (8) | nonce (16) | extra (n) | tag (32)
+/// ```
+///
+/// All integers are big-endian. `tag` is HMAC-SHA256 over every byte
+/// before it, so the extra data is covered as well.
+pub fn sign_knock(
+    key: &[u8],
+    port: u16,
+    unix_millis: u64,
+    nonce: [u8; NONCE_LEN],
+    extra: &[u8],
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + extra.len() + MAC_LEN);
+    out.extend_from_slice(&port.to_be_bytes());
+    out.extend_from_slice(&unix_millis.to_be_bytes());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(extra);
+    let tag = crate::spa::hmac_sha256(key, &[&out]);
+    out.extend_from_slice(&tag);
+    out
+}
+
+/// Sign a knock for `port` with the current time and a fresh nonce.
+pub fn sign_knock_now(key: &[u8], port: u16, extra: &[u8]) -> Vec<u8> {
+    sign_knock(
+        key,
+        port,
+        unix_millis(SystemTime::now()),
+        rand::random(),
+        extra,
+    )
+}
+
+/// Check the HMAC of a signed knock and that its timestamp is within
+/// `max_skew` of the local clock, in either direction.
+///
+/// Replays inside the window still verify; servers should also reject
+/// nonces they have already seen.
+pub fn verify_signed_knock(
+    key: &[u8],
+    bytes: &[u8],
+    max_skew: Duration,
+) -> Result<SignedKnock, VerifyError> {
+    verify_at(key, bytes, max_skew, unix_millis(SystemTime::now()))
+}
+
+/// [`verify_signed_knock`] against a given local time.
+fn verify_at(
+    key: &[u8],
+    bytes: &[u8],
+    max_skew: Duration,
+    now_millis: u64,
+) -> Result<SignedKnock, VerifyError> {
+    if bytes.len() < HEADER_LEN + MAC_LEN {
+        return Err(VerifyError::TooShort(bytes.len()));
+    }
+    let (signed, tag) = bytes.split_at(bytes.len() - MAC_LEN);
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(signed);
+    mac.verify_slice(tag).map_err(|_| VerifyError::BadMac)?;
+
+    let port = u16::from_be_bytes([signed[0], signed[1]]);
+    let mut millis = [0u8; 8];
+    millis.copy_from_slice(&signed[2..10]);
+    let unix_millis = u64::from_be_bytes(millis);
+    let skew = now_millis.abs_diff(unix_millis);
+    if u128::from(skew) > max_skew.as_millis() {
+        return Err(VerifyError::Skewed(skew));
+    }
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&signed[10..HEADER_LEN]);
+    Ok(SignedKnock {
+        port,
+        unix_millis,
+        nonce,
+        extra: signed[HEADER_LEN..].to_vec(),
+    })
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"sign-test-key";
+    const NOW: u64 = 1_700_000_000_000;
+    const WINDOW: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn fixed_width_layout() {
+        let bytes = sign_knock(KEY, 7000, NOW, [0xaa; NONCE_LEN], b"hi");
+        assert_eq!(bytes.len(), HEADER_LEN + 2 + MAC_LEN);
+        assert_eq!(&bytes[..2], &7000u16.to_be_bytes());
+        assert_eq!(&bytes[2..10], &NOW.to_be_bytes());
+        assert_eq!(&bytes[10..HEADER_LEN], &[0xaa; NONCE_LEN]);
+        assert_eq!(&bytes[HEADER_LEN..HEADER_LEN + 2], b"hi");
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = sign_knock(KEY, 7000, NOW, [1; NONCE_LEN], b"extra");
+        let knock = verify_at(KEY, &bytes, WINDOW, NOW + 1_000).unwrap();
+        assert_eq!(
+            knock,
+            SignedKnock {
+                port: 7000,
+                unix_millis: NOW,
+                nonce: [1; NONCE_LEN],
+                extra: b"extra".to_vec(),
+            }
+        );
+        let now_signed = sign_knock_now(KEY, 7000, b"");
+        assert!(verify_signed_knock(KEY, &now_signed, WINDOW).is_ok());
+    }
+
+    #[test]
+    fn skewed_clocks() {
+        let bytes = sign_knock(KEY, 7000, NOW, [1; NONCE_LEN], b"");
+        // Sender behind and ahead of the receiver, at and past the window
+        assert!(verify_at(KEY, &bytes, WINDOW, NOW + 30_000).is_ok());
+        assert!(verify_at(KEY, &bytes, WINDOW, NOW - 30_000).is_ok());
+        assert_eq!(
+            verify_at(KEY, &bytes, WINDOW, NOW + 30_001),
+            Err(VerifyError::Skewed(30_001))
+        );
+        assert_eq!(
+            verify_at(KEY, &bytes, WINDOW, NOW - 45_000),
+            Err(VerifyError::Skewed(45_000))
+        );
+    }
+
+    #[test]
+    fn tampered_bytes() {
+        let bytes = sign_knock(KEY, 7000, NOW, [1; NONCE_LEN], b"extra");
+        for i in 0..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(
+                verify_at(KEY, &tampered, WINDOW, NOW),
+                Err(VerifyError::BadMac),
+                "flipped byte {i}"
+            );
+        }
+        assert_eq!(
+            verify_at(b"other-key", &bytes, WINDOW, NOW),
+            Err(VerifyError::BadMac)
+        );
+        assert_eq!(
+            verify_at(KEY, &bytes[..10], WINDOW, NOW),
+            Err(VerifyError::TooShort(10))
+        );
+    }
+}
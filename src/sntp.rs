@@ -0,0 +1,240 @@
+//! Checking the local clock against an SNTP server before time-based
+//! knocks (`--check-clock`).
+//!
+//! TOTP-derived ports and signed or SPA payloads carry the time, and a
+//! server whose clock disagrees with ours drops them without a word. One
+//! SNTPv4 request (RFC 4330) measures how far off the local clock is. The
+//! request carries a random transmit timestamp instead of the local time,
+//! which the server echoes back, so the reply can be matched to it and
+//! nothing about this host's clock is sent.
+
+use crate::rt::{self, UdpSocket};
+use crate::securedns::host_port;
+use rand::RngCore;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Port SNTP servers listen on.
+pub const NTP_PORT: u16 = 123;
+/// Server asked without `--ntp-server`.
+pub const DEFAULT_SERVER: &str = "pool.ntp.org";
+/// How long the server has to answer, lookup included.
+pub const QUERY_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Seconds from the NTP epoch (1900) to the Unix one.
+const UNIX_OFFSET: i128 = 2_208_988_800;
+const PACKET_LEN: usize = 48;
+
+/// An SNTP server, `HOST[:PORT]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NtpServer {
+    pub host: String,
+    pub port: u16,
+}
+
+impl NtpServer {
+    /// Parse `HOST[:PORT]`, with IPv6 addresses bracketed when a port
+    /// follows; the port defaults to 123.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (host, port) = host_port(s, NTP_PORT).map_err(|e| format!("'{s}': {e}"))?;
+        Ok(Self { host, port })
+    }
+}
+
+impl Default for NtpServer {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_SERVER.to_string(),
+            port: NTP_PORT,
+        }
+    }
+}
+
+/// The host, with the port when it is not 123.
+impl fmt::Display for NtpServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.port, self.host.contains(':')) {
+            (NTP_PORT, _) => f.write_str(&self.host),
+            (port, true) => write!(f, "[{}]:{port}", self.host),
+            (port, false) => write!(f, "{}:{port}", self.host),
+        }
+    }
+}
+
+/// How far the local clock is ahead of `server`'s, in milliseconds;
+/// negative when it is behind. Fails when the server cannot be reached
+/// within `timeout` or its answer is no use.
+pub async fn clock_skew(server: &NtpServer, timeout: Duration) -> io::Result<i64> {
+    match rt::timeout(timeout, query(server)).await {
+        Ok(skew) => skew,
+        Err(_) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("no answer within {}ms", timeout.as_millis()),
+        )),
+    }
+}
+
+async fn query(server: &NtpServer) -> io::Result<i64> {
+    let addr = rt::lookup_host(&server.host, server.port)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no addresses"))?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    let mut nonce = [0u8; 8];
+    rand::rng().fill_bytes(&mut nonce);
+    let sent = SystemTime::now();
+    socket.send(&request(nonce)).await?;
+    let mut buf = [0u8; 512];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let received = SystemTime::now();
+        match parse_reply(&buf[..len], nonce) {
+            Ok(Some(reply)) => return Ok(reply.skew(sent, received)),
+            // Not an answer to this request: a late one to another
+            Ok(None) => continue,
+            Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// A client request: version 4, mode 3, with `nonce` as the transmit
+/// timestamp.
+fn request(nonce: [u8; 8]) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    packet[0] = (4 << 3) | 3;
+    packet[40..48].copy_from_slice(&nonce);
+    packet
+}
+
+/// The server's receive and transmit times from a reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reply {
+    /// Nanoseconds since the Unix epoch.
+    receive: i128,
+    transmit: i128,
+}
+
+impl Reply {
+    /// The local clock's lead over the server's, from when the request
+    /// left and the reply came, in milliseconds: the NTP offset, negated.
+    fn skew(&self, sent: SystemTime, received: SystemTime) -> i64 {
+        let (sent, received) = (unix_nanos(sent), unix_nanos(received));
+        let offset = ((self.receive - sent) + (self.transmit - received)) / 2;
+        (-offset / 1_000_000) as i64
+    }
+}
+
+/// Read a reply to the request sent with `nonce`; `None` when it answers
+/// another one.
+fn parse_reply(reply: &[u8], nonce: [u8; 8]) -> Result<Option<Reply>, String> {
+    if reply.len() < PACKET_LEN {
+        return Err(format!("{}-byte reply is too short", reply.len()));
+    }
+    if reply[24..32] != nonce {
+        return Ok(None);
+    }
+    let (leap, mode, stratum) = (reply[0] >> 6, reply[0] & 7, reply[1]);
+    if mode != 4 {
+        return Err(format!("reply in mode {mode}, not from a server"));
+    }
+    if stratum == 0 {
+        let code = String::from_utf8_lossy(&reply[12..16]).into_owned();
+        return Err(format!(
+            "server refused the request ({})",
+            code.trim_end_matches('\0')
+        ));
+    }
+    if leap == 3 {
+        return Err("server clock is not synchronized".into());
+    }
+    let timestamp = |at: usize| u64::from_be_bytes(reply[at..at + 8].try_into().unwrap());
+    Ok(Some(Reply {
+        receive: ntp_nanos(timestamp(32)),
+        transmit: ntp_nanos(timestamp(40)),
+    }))
+}
+
+/// An NTP timestamp as nanoseconds since the Unix epoch. Seconds with the
+/// top bit clear are taken to be past the 2036 rollover.
+fn ntp_nanos(timestamp: u64) -> i128 {
+    let mut secs = i128::from(timestamp >> 32);
+    if secs < 1 << 31 {
+        secs += 1 << 32;
+    }
+    let fraction = (i128::from(timestamp as u32) * 1_000_000_000) >> 32;
+    (secs - UNIX_OFFSET) * 1_000_000_000 + fraction
+}
+
+fn unix_nanos(at: SystemTime) -> i128 {
+    match at.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i128,
+        Err(e) => -(e.duration().as_nanos() as i128),
+    }
+}
+
+/// How far `skew_ms` puts the local clock from the server's, e.g. "2.5s
+/// ahead of".
+pub fn describe_skew(skew_ms: i64) -> String {
+    let side = if skew_ms < 0 { "behind" } else { "ahead of" };
+    let secs = skew_ms.unsigned_abs() as f64 / 1000.0;
+    format!("{secs:.1}s {side}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An NTP timestamp `lead` ahead of now.
+    fn ntp_time(lead: Duration) -> [u8; 8] {
+        let unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + lead;
+        let secs = (unix.as_secs() as i128 + UNIX_OFFSET) as u64 & 0xffff_ffff;
+        let fraction = (u64::from(unix.subsec_nanos()) << 32) / 1_000_000_000;
+        ((secs << 32) | fraction).to_be_bytes()
+    }
+
+    #[tokio::test]
+    async fn skew_is_measured_against_a_server_ahead() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!((len, buf[0]), (PACKET_LEN, 0x23));
+            // A stray reply to some other request is skipped first
+            let mut reply = [0u8; PACKET_LEN];
+            reply[0] = 0x24;
+            reply[1] = 2;
+            server.send_to(&reply, from).await.unwrap();
+            // The server's clock runs 90s ahead of ours
+            reply[24..32].copy_from_slice(&buf[40..48]);
+            reply[32..40].copy_from_slice(&ntp_time(Duration::from_secs(90)));
+            reply[40..48].copy_from_slice(&ntp_time(Duration::from_secs(90)));
+            server.send_to(&reply, from).await.unwrap();
+        });
+
+        let server = NtpServer::parse(&format!("127.0.0.1:{port}")).unwrap();
+        let skew = clock_skew(&server, QUERY_TIMEOUT).await.unwrap();
+        assert!((-90_100..=-89_900).contains(&skew), "{skew}");
+        assert_eq!(describe_skew(skew), "90.0s behind");
+        assert_eq!(describe_skew(2_450), "2.5s ahead of");
+
+        // A kiss-o'-death is an error, not a time
+        let mut kiss = [0u8; PACKET_LEN];
+        kiss[0] = 0x24;
+        kiss[12..16].copy_from_slice(b"RATE");
+        kiss[24..32].copy_from_slice(&[7; 8]);
+        let err = parse_reply(&kiss, [7; 8]).unwrap_err();
+        assert_eq!(err, "server refused the request (RATE)");
+        assert_eq!(NtpServer::default().to_string(), "pool.ntp.org");
+    }
+}
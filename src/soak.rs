@@ -0,0 +1,599 @@
+//! `--soak`: the same knocks run over and over, to measure how reliable a
+//! knock path is before trusting it with unattended automation.
+//!
+//! [`run_soak`] runs the whole sequence, with its verifying connect when
+//! there is one, for a number of iterations or for a time, spaced by an
+//! interval. Each iteration records when it started, how long it took and,
+//! when it failed, the step it failed at and the [`FailureKind`] of the
+//! error. The [`SoakReport`] sums them up as a success rate, failures by
+//! step and by kind, and latency percentiles of the iterations that
+//! succeeded, and writes the iterations out as JSON or CSV for graphing.
+//!
+//! Cancelling ends the soak after the iteration in flight, which is left
+//! out of the statistics rather than counted as a failure.
+
+use crate::events::KnockEvent;
+use crate::observer::{AttemptInfo, KnockObserver};
+use crate::{AppError, KnockConfig, KnockOutcome};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+/// Percentiles of the latency the summary shows.
+pub const PERCENTILES: [u32; 3] = [50, 90, 99];
+
+/// How long a soak goes on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoakLimit {
+    /// This many iterations.
+    Iterations(usize),
+    /// Iterations started until this long has passed.
+    Duration(Duration),
+}
+
+/// A soak test of a configuration's knocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Soak {
+    pub limit: SoakLimit,
+    /// Wait between the end of one iteration and the start of the next.
+    pub interval: Duration,
+}
+
+/// What went wrong in a failed iteration, by the exit code class of its
+/// error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FailureKind {
+    /// Bad configuration or key material.
+    Config,
+    /// The host could not be resolved.
+    Resolve,
+    /// A local socket could not be opened or used.
+    Local,
+    /// A knock did not get through.
+    Knock,
+    /// The knocks got through, but the verifying connect failed.
+    Verify,
+    /// A knock timed out.
+    Timeout,
+    /// Some of the knocks failed.
+    Partial,
+    Other,
+}
+
+impl FailureKind {
+    pub fn of(error: &AppError) -> Self {
+        match (error, error.exit_code()) {
+            (AppError::Ladder { .. }, _) => FailureKind::Verify,
+            (_, 2) => FailureKind::Config,
+            (_, 3) => FailureKind::Resolve,
+            (_, 4) => FailureKind::Local,
+            (_, 5) => FailureKind::Knock,
+            (_, 6) => FailureKind::Timeout,
+            (_, 7) => FailureKind::Partial,
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::Config => "config",
+            FailureKind::Resolve => "resolve",
+            FailureKind::Local => "local",
+            FailureKind::Knock => "knock",
+            FailureKind::Verify => "verify",
+            FailureKind::Timeout => "timeout",
+            FailureKind::Partial => "partial",
+            FailureKind::Other => "other",
+        })
+    }
+}
+
+/// How a failed iteration failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoakFailure {
+    /// The first step of the sequence that failed, numbered from 1, and
+    /// its port; `None` when the knocks got through or none was sent.
+    pub step: Option<(usize, u16)>,
+    pub kind: FailureKind,
+    pub error: String,
+}
+
+impl SoakFailure {
+    /// Where the iteration failed, as the summary groups failures: a step,
+    /// the verifying connect, or before any knock.
+    pub fn place(&self) -> String {
+        match (self.step, self.kind) {
+            (Some((step, port)), _) => format!("step {step} (port {port})"),
+            (None, FailureKind::Verify) => "verify".to_string(),
+            (None, _) => "no step".to_string(),
+        }
+    }
+}
+
+/// One run of the sequence in a soak.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoakIteration {
+    /// Numbered from 1.
+    pub number: usize,
+    pub started_at: SystemTime,
+    /// Wall time of the whole run, verifying connect included.
+    pub latency: Duration,
+    /// `None` when the iteration succeeded.
+    pub failure: Option<SoakFailure>,
+}
+
+impl SoakIteration {
+    pub fn succeeded(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Every iteration of a soak, and the statistics of them.
+#[derive(Debug, Clone, Default)]
+pub struct SoakReport {
+    pub iterations: Vec<SoakIteration>,
+    /// The soak was cancelled before its limit.
+    pub interrupted: bool,
+    pub duration: Duration,
+}
+
+impl SoakReport {
+    pub fn succeeded(&self) -> usize {
+        self.iterations.iter().filter(|i| i.succeeded()).count()
+    }
+
+    /// Share of the iterations that succeeded, from 0 to 1; 0 without any.
+    pub fn success_rate(&self) -> f64 {
+        match self.iterations.len() {
+            0 => 0.0,
+            n => self.succeeded() as f64 / n as f64,
+        }
+    }
+
+    /// Failed iterations by where they failed, most first.
+    pub fn failures_by_step(&self) -> Vec<(String, usize)> {
+        tally(self.failures().map(SoakFailure::place))
+    }
+
+    /// Failed iterations by the kind of their error, most first.
+    pub fn failures_by_kind(&self) -> Vec<(FailureKind, usize)> {
+        tally(self.failures().map(|f| f.kind))
+    }
+
+    /// The `percentile`th percentile (nearest rank) of the latency of the
+    /// iterations that succeeded; `None` without any.
+    pub fn latency_percentile(&self, percentile: u32) -> Option<Duration> {
+        let mut latencies: Vec<Duration> = self
+            .iterations
+            .iter()
+            .filter(|i| i.succeeded())
+            .map(|i| i.latency)
+            .collect();
+        latencies.sort_unstable();
+        let rank = (latencies.len() * percentile as usize).div_ceil(100);
+        latencies.get(rank.max(1) - 1).copied()
+    }
+
+    fn failures(&self) -> impl Iterator<Item = &SoakFailure> {
+        self.iterations.iter().filter_map(|i| i.failure.as_ref())
+    }
+
+    /// The statistics as the binary prints them at the end of a soak.
+    pub fn summary(&self) -> String {
+        let total = self.iterations.len();
+        let mut out = format!(
+            "Soak{}: {} of {total} iteration(s) succeeded ({:.1}%) in {:.1}s\n",
+            if self.interrupted {
+                " (interrupted)"
+            } else {
+                ""
+            },
+            self.succeeded(),
+            self.success_rate() * 100.0,
+            self.duration.as_secs_f64()
+        );
+        let list = |counts: Vec<(String, usize)>| {
+            let counts: Vec<String> = counts
+                .into_iter()
+                .map(|(what, count)| format!("{what}: {count}"))
+                .collect();
+            counts.join(", ")
+        };
+        if self.succeeded() < total {
+            out.push_str(&format!(
+                "Failures by step: {}\n",
+                list(self.failures_by_step())
+            ));
+            let kinds = self.failures_by_kind();
+            out.push_str(&format!(
+                "Failures by kind: {}\n",
+                list(kinds.into_iter().map(|(k, n)| (k.to_string(), n)).collect())
+            ));
+        }
+        if let Some(max) = self.latency_percentile(100) {
+            let percentiles: Vec<String> = PERCENTILES
+                .iter()
+                .filter_map(|&p| {
+                    let latency = self.latency_percentile(p)?;
+                    Some(format!("p{p} {}ms", latency.as_millis()))
+                })
+                .collect();
+            out.push_str(&format!(
+                "Latency of successful iterations: {}, max {}ms\n",
+                percentiles.join(", "),
+                max.as_millis()
+            ));
+        }
+        out
+    }
+
+    /// The statistics and every iteration as a JSON object, as the report
+    /// serializes.
+    #[cfg(feature = "serde")]
+    pub fn json(&self) -> String {
+        serde_json::to_string(self).expect("a soak report serializes")
+    }
+
+    /// Every iteration as a CSV row, after a header row.
+    pub fn csv(&self) -> String {
+        let mut out =
+            String::from("iteration,started_at,succeeded,latency_ms,step,port,kind,error\n");
+        for i in &self.iterations {
+            let failure = i.failure.as_ref();
+            let step = failure.and_then(|f| f.step);
+            out.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                i.number,
+                rfc3339(i.started_at),
+                i.succeeded(),
+                i.latency.as_secs_f64() * 1000.0,
+                step.map_or(String::new(), |(s, _)| s.to_string()),
+                step.map_or(String::new(), |(_, p)| p.to_string()),
+                failure.map_or(String::new(), |f| f.kind.to_string()),
+                failure.map_or(String::new(), |f| csv_field(&f.error)),
+            ));
+        }
+        out
+    }
+}
+
+/// Counts of `items`, most first and then in order.
+fn tally<T: Ord + Clone>(items: impl Iterator<Item = T>) -> Vec<(T, usize)> {
+    let mut counts = std::collections::BTreeMap::new();
+    for item in items {
+        *counts.entry(item).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(T, usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    counts
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// The statistics, then every iteration under `runs`; latencies in
+/// milliseconds, fractional when finer.
+#[cfg(feature = "serde")]
+impl serde::Serialize for SoakReport {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use crate::serde_forms::Millis;
+        use serde::ser::SerializeStruct;
+        /// Counts keyed in the order given, most first.
+        struct Counts(Vec<(String, usize)>);
+        impl serde::Serialize for Counts {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_map(self.0.iter().map(|(what, count)| (what, count)))
+            }
+        }
+        struct Percentiles<'a>(&'a SoakReport);
+        impl serde::Serialize for Percentiles<'_> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_map(PERCENTILES.iter().chain(&[100]).map(|&p| {
+                    let name = match p {
+                        100 => "max".to_string(),
+                        p => format!("p{p}"),
+                    };
+                    (name, self.0.latency_percentile(p).map(Millis))
+                }))
+            }
+        }
+        let kinds = self.failures_by_kind().into_iter();
+        let mut report = serializer.serialize_struct("SoakReport", 9)?;
+        report.serialize_field("iterations", &self.iterations.len())?;
+        report.serialize_field("succeeded", &self.succeeded())?;
+        report.serialize_field("success_rate", &self.success_rate())?;
+        report.serialize_field("interrupted", &self.interrupted)?;
+        report.serialize_field("duration_ms", &Millis(self.duration))?;
+        report.serialize_field("failures_by_step", &Counts(self.failures_by_step()))?;
+        report.serialize_field(
+            "failures_by_kind",
+            &Counts(kinds.map(|(k, n)| (k.to_string(), n)).collect()),
+        )?;
+        report.serialize_field("latency_ms", &Percentiles(self))?;
+        report.serialize_field("runs", &self.iterations)?;
+        report.end()
+    }
+}
+
+/// An iteration as a run of the report, its failure spread over `step`,
+/// `port`, `kind` and `error`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for SoakIteration {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let failure = self.failure.as_ref();
+        let step = failure.and_then(|f| f.step);
+        let mut iteration = serializer.serialize_struct("SoakIteration", 8)?;
+        iteration.serialize_field("iteration", &self.number)?;
+        iteration.serialize_field("started_at", &rfc3339(self.started_at))?;
+        iteration.serialize_field("succeeded", &self.succeeded())?;
+        iteration.serialize_field("latency_ms", &crate::serde_forms::Millis(self.latency))?;
+        iteration.serialize_field("step", &step.map(|(step, _)| step))?;
+        iteration.serialize_field("port", &step.map(|(_, port)| port))?;
+        iteration.serialize_field("kind", &failure.map(|f| f.kind.to_string()))?;
+        iteration.serialize_field("error", &failure.map(|f| &f.error))?;
+        iteration.end()
+    }
+}
+
+/// A CSV field, quoted when it holds a comma, quote or line break.
+fn csv_field(s: &str) -> String {
+    match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    }
+}
+
+/// Run `config` over and over as `soak` says, until its limit or until
+/// `cancel` is cancelled. `on_iteration` hears of each iteration as it
+/// ends.
+pub async fn run_soak(
+    mut config: KnockConfig,
+    soak: &Soak,
+    cancel: CancellationToken,
+    mut on_iteration: impl FnMut(&SoakIteration),
+) -> Result<SoakReport, AppError> {
+    config.validate()?;
+    if soak.limit == SoakLimit::Iterations(0) || soak.limit == SoakLimit::Duration(Duration::ZERO) {
+        return Err(AppError::InvalidConfig(
+            "a soak needs at least 1 iteration".into(),
+        ));
+    }
+    let steps = Arc::new(FirstFailedStep {
+        inner: config.observer.take(),
+        step: Mutex::new(None),
+    });
+    config.observer = Some(steps.clone());
+    let started = Instant::now();
+    let mut report = SoakReport::default();
+    loop {
+        let number = report.iterations.len() + 1;
+        let done = match soak.limit {
+            SoakLimit::Iterations(n) => number > n,
+            SoakLimit::Duration(limit) => started.elapsed() >= limit,
+        };
+        if done {
+            break;
+        }
+        if number > 1 {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = crate::rt::sleep(soak.interval) => {}
+            }
+        }
+        if cancel.is_cancelled() {
+            report.interrupted = true;
+            break;
+        }
+        *steps.step.lock().unwrap() = None;
+        let started_at = SystemTime::now();
+        let run_started = Instant::now();
+        let result = crate::run_with_cancel(config.clone(), cancel.clone()).await;
+        let latency = run_started.elapsed();
+        // A run cut short says nothing of the knock path
+        if cancel.is_cancelled() {
+            report.interrupted = true;
+            break;
+        }
+        let failure = match result {
+            Ok(_) => None,
+            Err(e) => Some(SoakFailure {
+                step: steps.step.lock().unwrap().take(),
+                kind: FailureKind::of(&e),
+                error: e.to_string(),
+            }),
+        };
+        let iteration = SoakIteration {
+            number,
+            started_at,
+            latency,
+            failure,
+        };
+        on_iteration(&iteration);
+        report.iterations.push(iteration);
+    }
+    report.duration = started.elapsed();
+    Ok(report)
+}
+
+/// Passes everything on to `inner`, noting the first failed step of the
+/// last run that finished.
+struct FirstFailedStep {
+    inner: Option<Arc<dyn KnockObserver + Send + Sync>>,
+    step: Mutex<Option<(usize, u16)>>,
+}
+
+impl KnockObserver for FirstFailedStep {
+    fn on_attempt(&self, info: &AttemptInfo) {
+        if let Some(inner) = &self.inner {
+            inner.on_attempt(info);
+        }
+    }
+
+    fn on_result(&self, outcome: &KnockOutcome) {
+        if let Some(inner) = &self.inner {
+            inner.on_result(outcome);
+        }
+    }
+
+    fn on_event(&self, event: &KnockEvent) {
+        if let KnockEvent::Finished { report } = event {
+            let failed = report.steps.iter().position(|step| !step.succeeded);
+            *self.step.lock().unwrap() = failed.map(|i| (i + 1, report.steps[i].port));
+        }
+        if let Some(inner) = &self.inner {
+            inner.on_event(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iteration(number: usize, latency: u64, failure: Option<SoakFailure>) -> SoakIteration {
+        SoakIteration {
+            number,
+            started_at: SystemTime::UNIX_EPOCH + Duration::from_secs(number as u64),
+            latency: Duration::from_millis(latency),
+            failure,
+        }
+    }
+
+    fn failure(step: Option<(usize, u16)>, kind: FailureKind) -> Option<SoakFailure> {
+        Some(SoakFailure {
+            step,
+            kind,
+            error: "knock on port 8000 timed out, after 3 attempt(s)".into(),
+        })
+    }
+
+    #[test]
+    fn statistics_of_the_iterations() {
+        let mut iterations: Vec<SoakIteration> =
+            (1..=97).map(|n| iteration(n, n as u64, None)).collect();
+        iterations.push(iteration(
+            98,
+            5000,
+            failure(Some((2, 8000)), FailureKind::Timeout),
+        ));
+        iterations.push(iteration(
+            99,
+            5000,
+            failure(Some((2, 8000)), FailureKind::Timeout),
+        ));
+        iterations.push(iteration(100, 300, failure(None, FailureKind::Verify)));
+        let report = SoakReport {
+            iterations,
+            interrupted: false,
+            duration: Duration::from_secs(120),
+        };
+        assert_eq!(report.succeeded(), 97);
+        assert_eq!(
+            report.failures_by_step(),
+            [
+                ("step 2 (port 8000)".to_string(), 2),
+                ("verify".to_string(), 1)
+            ]
+        );
+        assert_eq!(
+            report.failures_by_kind(),
+            [(FailureKind::Timeout, 2), (FailureKind::Verify, 1)]
+        );
+        // Failed iterations' latencies are left out
+        assert_eq!(
+            report.latency_percentile(50),
+            Some(Duration::from_millis(49))
+        );
+        assert_eq!(
+            report.latency_percentile(99),
+            Some(Duration::from_millis(97))
+        );
+        assert_eq!(
+            report.summary(),
+            "Soak: 97 of 100 iteration(s) succeeded (97.0%) in 120.0s\n\
+             Failures by step: step 2 (port 8000): 2, verify: 1\n\
+             Failures by kind: timeout: 2, verify: 1\n\
+             Latency of successful iterations: p50 49ms, p90 88ms, p99 97ms, max 97ms\n"
+        );
+        assert_eq!(SoakReport::default().latency_percentile(50), None);
+    }
+
+    #[test]
+    fn iterations_as_csv_and_json() {
+        let report = SoakReport {
+            iterations: vec![
+                iteration(1, 12, None),
+                iteration(2, 3000, failure(Some((2, 8000)), FailureKind::Timeout)),
+            ],
+            interrupted: true,
+            duration: Duration::from_secs(5),
+        };
+        assert_eq!(
+            report.csv(),
+            "iteration,started_at,succeeded,latency_ms,step,port,kind,error\n\
+             1,1970-01-01T00:00:01.000Z,true,12,,,,\n\
+             2,1970-01-01T00:00:02.000Z,false,3000,2,8000,timeout,\
+             \"knock on port 8000 timed out, after 3 attempt(s)\"\n"
+        );
+        #[cfg(feature = "serde")]
+        {
+            let json = report.json();
+            assert!(json.starts_with(
+                "{\"iterations\":2,\"succeeded\":1,\"success_rate\":0.5,\"interrupted\":true,"
+            ));
+            assert!(json.contains("\"latency_ms\":{\"p50\":12,\"p90\":12,\"p99\":12,\"max\":12}"));
+            assert!(json.contains(
+                "{\"iteration\":2,\"started_at\":\"1970-01-01T00:00:02.000Z\",\"succeeded\":false,\
+                 \"latency_ms\":3000,\"step\":2,\"port\":8000,\"kind\":\"timeout\","
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn soaks_run_their_iterations_and_stop_when_cancelled() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let closed = {
+            let probe = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap().port()
+        };
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([open, closed])
+            .refused_is_failure(true)
+            .attempts(1)
+            .delay(0)
+            .build()
+            .unwrap();
+        let soak = Soak {
+            limit: SoakLimit::Iterations(3),
+            interval: Duration::from_millis(10),
+        };
+        let mut seen = 0;
+        let report = run_soak(config.clone(), &soak, CancellationToken::new(), |_| {
+            seen += 1
+        })
+        .await
+        .unwrap();
+        assert_eq!(seen, 3);
+        assert_eq!(report.iterations.len(), 3);
+        assert!(!report.interrupted);
+        let failure = report.iterations[2].failure.as_ref().unwrap();
+        assert_eq!(failure.step, Some((2, closed)));
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let forever = Soak {
+            limit: SoakLimit::Duration(Duration::from_secs(3600)),
+            ..soak
+        };
+        let report = run_soak(config, &forever, cancel, |_| {}).await.unwrap();
+        assert!(report.interrupted);
+        assert!(report.iterations.is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+use crate::rt::TcpStream;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A SOCKS5 proxy TCP knocks are tunnelled through.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// Proxy address as `host:port`.
+    pub addr: String,
+    /// Optional username/password (RFC 1929).
+    pub auth: Option<(String, String)>,
+}
+
+/// Why a connection through the proxy failed.
+#[derive(Debug)]
+pub enum SocksError {
+    /// The proxy itself could not be reached or refused the handshake.
+    Proxy(String),
+    /// The proxy reported that connecting to the target failed.
+    Target(io::Error),
+}
+
+impl Socks5Proxy {
+    /// Parse `[user:pass@]host:port`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (auth, addr) = match s.rsplit_once('@') {
+            Some((creds, addr)) => {
+                let (user, pass) = creds
+                    .split_once(':')
+                    .ok_or_else(|| "proxy credentials must be user:pass".to_string())?;
+                if user.len() > 255 || pass.len() > 255 {
+                    return Err("proxy username and password are limited to 255 bytes".into());
+                }
+                (Some((user.to_string(), pass.to_string())), addr)
+            }
+            None => (None, s),
+        };
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(Self {
+                addr: addr.to_string(),
+                auth,
+            }),
+            _ => Err(format!("proxy address '{addr}' must be host:port")),
+        }
+    }
+
+    /// Open a tunnelled connection to `host:port`. Host names are sent to
+    /// the proxy as-is (ATYP domain) so no local DNS lookup happens.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, SocksError> {
+        if host.len() > 255 {
+            return Err(SocksError::Proxy(format!(
+                "host name '{host}' is too long for SOCKS5"
+            )));
+        }
+        let mut stream = TcpStream::connect(self.addr.as_str())
+            .await
+            .map_err(|e| SocksError::Proxy(format!("connect to {}: {e}", self.addr)))?;
+        self.handshake(&mut stream, host, port)
+            .await
+            .map_err(|e| match e {
+                SocksError::Proxy(msg) => SocksError::Proxy(format!("{}: {msg}", self.addr)),
+                target => target,
+            })?;
+        Ok(stream)
+    }
+
+    async fn handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), SocksError> {
+        let proto = |e: io::Error| SocksError::Proxy(e.to_string());
+
+        // Method negotiation
+        let greeting: &[u8] = match self.auth {
+            Some(_) => &[0x05, 0x02, 0x00, 0x02],
+            None => &[0x05, 0x01, 0x00],
+        };
+        stream.write_all(greeting).await.map_err(proto)?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await.map_err(proto)?;
+        match (reply, &self.auth) {
+            ([0x05, 0x00], _) => {}
+            ([0x05, 0x02], Some((user, pass))) => {
+                let mut req = vec![0x01, user.len() as u8];
+                req.extend_from_slice(user.as_bytes());
+                req.push(pass.len() as u8);
+                req.extend_from_slice(pass.as_bytes());
+                stream.write_all(&req).await.map_err(proto)?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await.map_err(proto)?;
+                if status[1] != 0x00 {
+                    return Err(SocksError::Proxy("authentication rejected".into()));
+                }
+            }
+            ([0x05, 0xff], _) => return Err(SocksError::Proxy("no acceptable auth method".into())),
+            _ => return Err(SocksError::Proxy("not a SOCKS5 proxy".into())),
+        }
+
+        // CONNECT request
+        stream
+            .write_all(&connect_request(host, port))
+            .await
+            .map_err(proto)?;
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await.map_err(proto)?;
+        if head[0] != 0x05 {
+            return Err(SocksError::Proxy("malformed CONNECT reply".into()));
+        }
+        reply_status(head[1])?;
+
+        // Discard the bound address
+        let skip = match head[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => usize::from(stream.read_u8().await.map_err(proto)?),
+            _ => return Err(SocksError::Proxy("malformed CONNECT reply".into())),
+        };
+        let mut bound = vec![0u8; skip + 2];
+        stream.read_exact(&mut bound).await.map_err(proto)?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Socks5Proxy {
+    // Keep the password out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5Proxy")
+            .field("addr", &self.addr)
+            .field("auth", &self.auth.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+/// Serialized as `host:port` alone, the password kept out of it like out
+/// of logs; read back as [`Socks5Proxy::parse`] takes it.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Socks5Proxy {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.addr)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Socks5Proxy {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        Self::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Encode a CONNECT request, using a literal address type when possible.
+fn connect_request(host: &str, port: u16) -> Vec<u8> {
+    let mut req = vec![0x05, 0x01, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(0x01);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(0x04);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name = crate::scope::ascii_host(host).unwrap_or(host.into());
+            req.push(0x03);
+            req.push(name.len() as u8);
+            req.extend_from_slice(name.as_bytes());
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    req
+}
+
+/// Map a CONNECT reply code: target-side failures become the io errors a
+/// direct connect would have produced, policy failures stay proxy errors.
+fn reply_status(code: u8) -> Result<(), SocksError> {
+    use io::ErrorKind::*;
+    let kind = match code {
+        0x00 => return Ok(()),
+        0x02 => {
+            return Err(SocksError::Proxy(
+                "connection not allowed by ruleset".into(),
+            ))
+        }
+        0x07 => return Err(SocksError::Proxy("CONNECT not supported".into())),
+        0x08 => return Err(SocksError::Proxy("address type not supported".into())),
+        0x03 => NetworkUnreachable,
+        0x04 => HostUnreachable,
+        0x05 => ConnectionRefused,
+        0x06 => TimedOut,
+        _ => Other,
+    };
+    Err(SocksError::Target(io::Error::new(
+        kind,
+        format!("proxy reported {kind} (reply {code:#04x})"),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parse_proxy_spec() {
+        let p = Socks5Proxy::parse("alice:s3cret@127.0.0.1:1080").unwrap();
+        assert_eq!(p.addr, "127.0.0.1:1080");
+        assert_eq!(p.auth, Some(("alice".into(), "s3cret".into())));
+        assert!(!format!("{p:?}").contains("s3cret"));
+        assert!(Socks5Proxy::parse("proxy.local").is_err());
+        assert!(Socks5Proxy::parse("nopass@proxy:1080").is_err());
+    }
+
+    #[test]
+    fn domain_request_encoding() {
+        let req = connect_request("bastion.example", 7000);
+        assert_eq!(&req[..5], &[0x05, 0x01, 0x00, 0x03, 15]);
+        assert_eq!(&req[5..20], b"bastion.example");
+        assert_eq!(&req[20..], &7000u16.to_be_bytes());
+    }
+
+    /// Minimal SOCKS5 server answering CONNECT with `rep`; returns the
+    /// request it received.
+    async fn fake_proxy(rep: u8) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            sock.read_exact(&mut greeting).await.unwrap();
+            sock.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut req = vec![0u8; 5];
+            sock.read_exact(&mut req).await.unwrap();
+            let mut rest = vec![0u8; usize::from(req[4]) + 2];
+            sock.read_exact(&mut rest).await.unwrap();
+            req.extend(rest);
+            sock.write_all(&[0x05, rep, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            req
+        });
+        (addr, server)
+    }
+
+    #[tokio::test]
+    async fn connect_through_proxy_uses_remote_dns() {
+        let (addr, server) = fake_proxy(0x00).await;
+        let proxy = Socks5Proxy::parse(&addr).unwrap();
+        proxy.connect("knock.example", 7000).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            connect_request("knock.example", 7000)
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_target_maps_to_io_error() {
+        let (addr, _server) = fake_proxy(0x05).await;
+        let proxy = Socks5Proxy::parse(&addr).unwrap();
+        match proxy.connect("knock.example", 7000).await {
+            Err(SocksError::Target(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::secret::{Secret, SecretSource};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version byte leading every SPA packet; bump it when the layout changes.
+pub const VERSION: u8 = 1;
+/// Environment variable holding the key when no source is given.
+pub const KEY_ENV: &str = "KNOCK_SPA_KEY";
+/// Length of the HMAC-SHA256 tag closing the packet.
+pub const MAC_LEN: usize = 32;
+
+/// Key and identity used to sign Single Packet Authorization knocks.
+///
+/// Deliberately not `Debug`, so the key cannot end up in a log line.
+#[derive(Clone)]
+pub struct SpaConfig {
+    pub key: Secret,
+    pub client_id: String,
+}
+
+impl SpaConfig {
+    /// Build a packet for `port`, stamped with the current time.
+    pub fn packet(&self, port: u16) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        build_packet(&self.key, &self.client_id, now, port)
+    }
+}
+
+/// Build a version 1 SPA packet:
+///
+/// ```text
+/// version (1) | timestamp (8, BE unix seconds) | id_len (1) | client_id | tag (32)
+/// ```
+///
+/// `tag` is HMAC-SHA256 over everything before it followed by the target
+/// port (2, BE), so a packet only authorizes the port it was built for.
+pub fn build_packet(key: &[u8], client_id: &str, timestamp: u64, port: u16) -> Vec<u8> {
+    let id = client_id.as_bytes();
+    let mut out = Vec::with_capacity(10 + id.len() + MAC_LEN);
+    out.push(VERSION);
+    out.extend_from_slice(&timestamp.to_be_bytes());
+    out.push(id.len() as u8);
+    out.extend_from_slice(id);
+    let tag = hmac_sha256(key, &[&out, &port.to_be_bytes()]);
+    out.extend_from_slice(&tag);
+    out
+}
+
+/// Check that a client ID fits the one-byte length field.
+pub fn validate_client_id(id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > 255 {
+        return Err(format!("SPA client ID '{id}' must be 1-255 bytes"));
+    }
+    Ok(())
+}
+
+/// Check an fwknop access request: comma-separated `tcp/PORT` or
+/// `udp/PORT` items.
+pub fn validate_fwknop_access(access: &str) -> Result<(), String> {
+    for item in access.split(',') {
+        let (proto, port) = item
+            .split_once('/')
+            .ok_or_else(|| format!("'{item}' is not PROTO/PORT, e.g. tcp/22"))?;
+        if proto != "tcp" && proto != "udp" {
+            return Err(format!("'{proto}' is not tcp or udp"));
+        }
+        match port.parse::<u16>() {
+            Ok(0) => return Err("access port must not be 0".into()),
+            Ok(_) => {}
+            Err(_) => return Err(format!("'{port}' is not a valid port")),
+        }
+    }
+    Ok(())
+}
+
+/// Read a key from `source`, or from the `env` variable when none is
+/// given. A trailing newline is not part of the key.
+pub async fn load_key(source: Option<&SecretSource>, env: &str) -> Result<Secret, String> {
+    match source {
+        Some(source) => source.read("key").await,
+        None => SecretSource::Env(env.to_string()).read("key").await,
+    }
+}
+
+/// HMAC-SHA256 over the concatenation of `parts`.
+pub(crate) fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; MAC_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_matches_rfc4231() {
+        // RFC 4231 test case 2
+        let tag = hmac_sha256(b"Jefe", &[b"what do ya ", b"want for nothing?"]);
+        assert_eq!(
+            hex::encode(tag),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn packet_layout_v1() {
+        let packet = build_packet(b"spa-test-key", "laptop", 1_700_000_000, 7000);
+        assert_eq!(
+            hex::encode(&packet),
+            "01000000006553f100066c6170746f70\
+             c6dffb4be79ccee131a209640ea9adac322ae7fc9ecdd969e5af16dcfe761948"
+        );
+        assert_eq!(packet.len(), 1 + 8 + 1 + 6 + MAC_LEN);
+    }
+
+    #[test]
+    fn tag_binds_the_port() {
+        let a = build_packet(b"k", "id", 1, 7000);
+        let b = build_packet(b"k", "id", 1, 7001);
+        assert_eq!(a[..a.len() - MAC_LEN], b[..b.len() - MAC_LEN]);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn client_id_length() {
+        assert!(validate_client_id("laptop").is_ok());
+        assert!(validate_client_id("").is_err());
+        assert!(validate_client_id(&"x".repeat(256)).is_err());
+    }
+
+    #[tokio::test]
+    async fn key_file_drops_trailing_newline() {
+        let path = std::env::temp_dir().join(format!("spa-key-{}", std::process::id()));
+        let source = SecretSource::File(path.clone());
+        std::fs::write(&path, "secret\n").unwrap();
+        assert_eq!(&*load_key(Some(&source), KEY_ENV).await.unwrap(), b"secret");
+        std::fs::write(&path, "\n").unwrap();
+        assert!(load_key(Some(&source), KEY_ENV).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
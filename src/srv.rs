@@ -0,0 +1,307 @@
+//! `--srv`: knock targets found through DNS SRV records (RFC 2782), e.g.
+//! `_knock._udp.example.com`, instead of one fixed host.
+//!
+//! The records are asked of `--dns-server`, or else the first nameserver
+//! in `/etc/resolv.conf`, and put in the order RFC 2782 has clients try
+//! them: lowest priority first, and within a priority a weighted random
+//! draw, so a target with twice the weight comes first twice as often.
+//! The configured sequence is knocked at the first target; when that run
+//! fails the next target is tried, until one succeeds or none is left.
+//!
+//! The port of a `_tcp` record is where its service listens, so unless a
+//! `--verify` or ladder is configured a target only counts as open once
+//! that port connects. The port of a `_udp` record, where the knocks of
+//! a knock daemon go, says nothing to verify and is not used.
+
+use crate::events::EventSink;
+use crate::ladder::Ladder;
+use crate::verify::Verify;
+use crate::{AppError, KnockConfig, KnockReport};
+use rand::Rng;
+use std::future::Future;
+use std::net::SocketAddr;
+
+/// One SRV record: a host offering the service, and how to pick it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower is tried first.
+    pub priority: u16,
+    /// Relative share of first picks among records of one priority.
+    pub weight: u16,
+    pub port: u16,
+    /// Host name, without a trailing dot; `.` when the service is
+    /// decidedly not offered.
+    pub target: String,
+}
+
+/// `records` in the order to try them, as RFC 2782 orders them: by
+/// priority, and within a priority drawn one at a time with chances in
+/// proportion to their weights. Records of weight 0 are drawn first only
+/// rarely, when the draw lands on 0.
+pub fn order(mut records: Vec<SrvRecord>, rng: &mut impl Rng) -> Vec<SrvRecord> {
+    records.sort_by_key(|r| r.priority);
+    let mut ordered = Vec::with_capacity(records.len());
+    for group in records.chunk_by(|a, b| a.priority == b.priority) {
+        let mut left: Vec<&SrvRecord> = group.iter().collect();
+        left.sort_by_key(|r| r.weight != 0);
+        while !left.is_empty() {
+            let total: u32 = left.iter().map(|r| u32::from(r.weight)).sum();
+            let draw = rng.random_range(0..=total);
+            let mut sum = 0;
+            let picked = left
+                .iter()
+                .position(|r| {
+                    sum += u32::from(r.weight);
+                    sum >= draw
+                })
+                .expect("the running sum reaches the total");
+            ordered.push(left.remove(picked).clone());
+        }
+    }
+    ordered
+}
+
+/// The records of `name` in the order to try them. A `.` target means
+/// the service is not offered there.
+pub async fn resolve(name: &str, server: Option<SocketAddr>) -> Result<Vec<SrvRecord>, AppError> {
+    let server = match server {
+        Some(server) => server,
+        None => crate::dns::system_nameserver("SRV records")?,
+    };
+    let records = lookup(server, name).await?;
+    if records.iter().all(|r| r.target == ".") {
+        return Err(AppError::Resolve {
+            host: name.to_string(),
+            server: Some(server),
+            source: std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "the SRV records say the service is not offered",
+            ),
+        });
+    }
+    let records = records.into_iter().filter(|r| r.target != ".").collect();
+    Ok(order(records, &mut rand::rng()))
+}
+
+#[cfg(feature = "custom-dns")]
+async fn lookup(server: SocketAddr, name: &str) -> Result<Vec<SrvRecord>, AppError> {
+    crate::dns::lookup_srv(server, name).await
+}
+
+/// Without the `custom-dns` feature there is no DNS client to ask.
+#[cfg(not(feature = "custom-dns"))]
+async fn lookup(_server: SocketAddr, _name: &str) -> Result<Vec<SrvRecord>, AppError> {
+    Err(AppError::InvalidConfig(
+        "--srv requires building with `--features custom-dns`".into(),
+    ))
+}
+
+/// Whether the records of `name` are for a TCP service, so their ports
+/// are worth a verifying connect.
+fn is_tcp(name: &str) -> bool {
+    name.split('.')
+        .nth(1)
+        .is_some_and(|proto| proto.eq_ignore_ascii_case("_tcp"))
+}
+
+/// Run `config` through `run` at each target the SRV records of
+/// `config.srv` name, in turn, until one run succeeds. A dry run shows
+/// the plan for the first target only.
+pub(crate) async fn fall_back<F, Fut>(
+    mut config: KnockConfig,
+    events: &EventSink,
+    mut run: F,
+) -> Result<KnockReport, AppError>
+where
+    F: FnMut(KnockConfig) -> Fut,
+    Fut: Future<Output = Result<KnockReport, AppError>>,
+{
+    let Some(name) = config.srv.take() else {
+        return run(config).await;
+    };
+    let records = resolve(&name, config.dns_server).await?;
+    let listed: Vec<String> = records
+        .iter()
+        .map(|r| format!("{}:{}", r.target, r.port))
+        .collect();
+    events.notice(None, format!("SRV {name}: {}", listed.join(", ")));
+    let count = records.len();
+    for (index, record) in records.into_iter().enumerate() {
+        let mut target = config.clone();
+        target.host = record.target.clone();
+        if target.ladder.is_none() && record.port != 0 && is_tcp(&name) {
+            target.ladder = Some(Ladder::new(Verify::new(record.port)));
+        }
+        if config.dry_run {
+            return run(target).await;
+        }
+        match run(target).await {
+            Err(e @ (AppError::Interrupted(_) | AppError::InvalidConfig(_))) => return Err(e),
+            Err(e) if index + 1 < count => events.notice(
+                None,
+                format!(
+                    "SRV target {}/{count}, {}:{}, failed: {e}; trying the next",
+                    index + 1,
+                    record.target,
+                    record.port
+                ),
+            ),
+            result => return result,
+        }
+    }
+    unreachable!("the last target returns")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 62201,
+            target: target.into(),
+        }
+    }
+
+    fn targets(records: &[SrvRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.target.as_str()).collect()
+    }
+
+    #[test]
+    fn priorities_come_in_order() {
+        let records = vec![
+            record(20, 0, "backup"),
+            record(10, 5, "a"),
+            record(30, 100, "last"),
+            record(10, 5, "b"),
+        ];
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..50 {
+            let ordered = order(records.clone(), &mut rng);
+            assert_eq!(ordered.len(), 4);
+            let mut first_two = targets(&ordered[..2]);
+            first_two.sort();
+            assert_eq!(first_two, ["a", "b"]);
+            assert_eq!(targets(&ordered[2..]), ["backup", "last"]);
+        }
+    }
+
+    #[test]
+    fn weights_share_out_the_first_picks() {
+        let records = vec![
+            record(0, 60, "big"),
+            record(0, 30, "medium"),
+            record(0, 10, "small"),
+            record(0, 0, "spare"),
+        ];
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut firsts = std::collections::HashMap::new();
+        let rounds = 10_000;
+        for _ in 0..rounds {
+            let ordered = order(records.clone(), &mut rng);
+            assert_eq!(ordered.len(), 4);
+            *firsts.entry(ordered[0].target.clone()).or_insert(0) += 1;
+        }
+        let share = |t: &str| f64::from(*firsts.get(t).unwrap_or(&0)) / f64::from(rounds);
+        assert!((share("big") - 0.6).abs() < 0.03, "{firsts:?}");
+        assert!((share("medium") - 0.3).abs() < 0.03, "{firsts:?}");
+        assert!((share("small") - 0.1).abs() < 0.03, "{firsts:?}");
+        // Weight 0 only when the draw lands on 0, 1 in 101
+        assert!(share("spare") < 0.03, "{firsts:?}");
+    }
+
+    #[test]
+    fn unweighted_records_are_all_kept() {
+        let records = vec![record(1, 0, "x"), record(1, 0, "y"), record(1, 0, "z")];
+        let ordered = order(records, &mut StdRng::seed_from_u64(3));
+        let mut seen = targets(&ordered);
+        seen.sort();
+        assert_eq!(seen, ["x", "y", "z"]);
+        assert!(order(Vec::new(), &mut StdRng::seed_from_u64(3)).is_empty());
+    }
+
+    /// A DNS server on localhost answering every query with `records`,
+    /// their targets IP literals spelled as names.
+    #[cfg(feature = "custom-dns")]
+    async fn srv_server(records: Vec<SrvRecord>) -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                let mut pkt = buf[..n].to_vec();
+                pkt[2] |= 0x80;
+                pkt[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+                for r in &records {
+                    let mut data = Vec::new();
+                    for field in [r.priority, r.weight, r.port] {
+                        data.extend_from_slice(&field.to_be_bytes());
+                    }
+                    // The root, ".", is no labels at all
+                    for label in r.target.split('.').filter(|l| !l.is_empty()) {
+                        data.push(label.len() as u8);
+                        data.extend_from_slice(label.as_bytes());
+                    }
+                    data.push(0);
+                    pkt.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0]);
+                    pkt.push(data.len() as u8);
+                    pkt.extend_from_slice(&data);
+                }
+                let _ = socket.send_to(&pkt, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "custom-dns")]
+    #[tokio::test]
+    async fn a_failed_target_falls_back_to_the_next() {
+        use tokio::net::TcpListener;
+
+        let knocked = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let open_port = open.local_addr().unwrap().port();
+        tokio::spawn(async move { while open.accept().await.is_ok() {} });
+        // The preferred target's service does not come up, the backup's does
+        let server = srv_server(vec![
+            SrvRecord {
+                port: open_port,
+                ..record(20, 0, "127.0.0.1")
+            },
+            SrvRecord {
+                port: closed_port,
+                ..record(10, 0, "127.0.0.1")
+            },
+        ])
+        .await;
+        let config = KnockConfig::builder()
+            .srv("_ssh._tcp.example.com")
+            .dns_server(server)
+            .sequence([knocked.local_addr().unwrap().port()])
+            .build()
+            .unwrap();
+        let report = crate::run(config).await.unwrap();
+        assert_eq!(report.host, "127.0.0.1");
+        assert_eq!(report.ladder.unwrap().port, open_port);
+
+        let server = srv_server(vec![record(0, 0, ".")]).await;
+        let err = resolve("_knock._udp.example.com", Some(server))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().ends_with("not offered"), "{err}");
+    }
+
+    #[test]
+    fn only_tcp_ports_are_verified() {
+        assert!(is_tcp("_ssh._tcp.example.com"));
+        assert!(is_tcp("_ssh._TCP.example.com."));
+        assert!(!is_tcp("_knock._udp.example.com"));
+        assert!(!is_tcp("example.com"));
+    }
+}
@@ -0,0 +1,499 @@
+//! `--ssh-host`: the knock for a host taken from its entry in ssh_config
+//! (`~/.ssh/config`, or `--ssh-config`), so hosts are described once.
+//!
+//! The file is read the way `ssh` reads it: `Host` and `Match` blocks
+//! apply when they match the name, the first value found for a setting
+//! wins, and `Include` pulls in other files (relative paths taken from the
+//! directory of the config file, wildcards allowed). Of the entry:
+//!
+//! - `HostName` is the host to knock (`%h` standing for the name), or the
+//!   name itself without one;
+//! - `Port`, 22 without one, is the port to `--verify`;
+//! - comments `#knock-NAME VALUE` and `SetEnv KNOCK_NAME=VALUE` give the
+//!   long option NAME: `#knock-sequence 7000,8000,9000`,
+//!   `SetEnv KNOCK_PROTOCOL=udp`. Apart from `sequence` the options are
+//!   those a `knock://` URI may give ([`crate::uri::PARAMS`]), with values
+//!   as the URI takes them.
+//!
+//! Other directives are left to `ssh`. Of `Match`, only `all`, `host`,
+//! `originalhost` and `final` are judged; a block with any other
+//! criterion, such as `exec`, is taken as not matching. On the command
+//! line, options passed as flags win over the entry's.
+
+use crate::plan::KnockPlan;
+use std::path::{Path, PathBuf};
+
+/// Most levels of `Include` followed, as `ssh` allows.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// The ssh port, when the entry names none.
+const DEFAULT_PORT: u16 = 22;
+
+/// A host's entry in ssh_config, as the knock takes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshHost {
+    /// The host to knock: `HostName`, or the name looked up.
+    pub host: String,
+    /// `Port`, or 22.
+    pub port: u16,
+    /// The `knock-sequence` of the entry.
+    pub sequence: Option<KnockPlan>,
+    /// The other knock options in order: each the long option and the
+    /// value to give it, `None` for a flag that is set.
+    pub options: Vec<(&'static str, Option<String>)>,
+}
+
+impl SshHost {
+    /// Look `name` up in the ssh_config at `path`. A name no `Host` or
+    /// `Match` block matches is an error, as is a file that cannot be read.
+    pub fn lookup(path: &Path, name: &str) -> Result<Self, String> {
+        let base = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let name = name.to_lowercase();
+        let mut reader = Reader {
+            name: name.clone(),
+            base,
+            matched: false,
+            settings: Settings::default(),
+        };
+        reader.read(path, true, 0)?;
+        if !reader.matched {
+            return Err(format!(
+                "no Host or Match block of {} matches '{name}'",
+                path.display()
+            ));
+        }
+        let settings = reader.settings;
+        let host = match settings.host_name {
+            Some(host_name) => expand_host_name(&host_name, &name),
+            None => name.clone(),
+        };
+        Ok(Self {
+            host: crate::scope::parse_host(&host)
+                .map_err(|e| format!("HostName of '{name}': {e}"))?,
+            port: settings.port.unwrap_or(DEFAULT_PORT),
+            sequence: settings.sequence,
+            options: settings.options,
+        })
+    }
+
+    /// The command-line arguments the entry stands for: `--host`, the
+    /// sequence if it has one, one per option, and `--verify` with the
+    /// port unless an option gives it.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![format!("--host={}", self.host)];
+        if let Some(sequence) = &self.sequence {
+            args.push(format!("--sequence={sequence}"));
+        }
+        for (option, value) in &self.options {
+            args.push(match value {
+                Some(value) => format!("--{option}={value}"),
+                None => format!("--{option}"),
+            });
+        }
+        if !self.options.iter().any(|(option, _)| *option == "verify") {
+            args.push(format!("--verify={}", self.port));
+        }
+        args
+    }
+}
+
+/// `~/.ssh/config`, where `ssh` reads the user's settings.
+pub fn user_config() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh/config"))
+}
+
+/// What the blocks matching the name set so far; the first value wins.
+#[derive(Default)]
+struct Settings {
+    host_name: Option<String>,
+    port: Option<u16>,
+    sequence: Option<KnockPlan>,
+    options: Vec<(&'static str, Option<String>)>,
+    /// Every knock option seen, flags set to false among them.
+    seen: Vec<String>,
+}
+
+struct Reader {
+    /// The name looked up, lowercased as `ssh` matches it.
+    name: String,
+    /// Where relative `Include` paths are taken from.
+    base: PathBuf,
+    /// Whether any `Host` or `Match` block matched.
+    matched: bool,
+    settings: Settings,
+}
+
+impl Reader {
+    /// Read the file at `path`, its lines applying while `active`.
+    fn read(&mut self, path: &Path, mut active: bool, depth: usize) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        for (number, line) in text.lines().enumerate() {
+            let at = || format!("{} line {}", path.display(), number + 1);
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                let Some(option) = comment.trim_start().strip_prefix("knock-") else {
+                    continue;
+                };
+                if active {
+                    let (name, value) = split_keyword(option);
+                    let value = Some(unquote(value)).filter(|v| !v.is_empty());
+                    self.knock_option(name, value)
+                        .map_err(|e| format!("{}: {e}", at()))?;
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let (keyword, rest) = split_keyword(line);
+            let args = split_args(rest).map_err(|e| format!("{}: {e}", at()))?;
+            match keyword.to_ascii_lowercase().as_str() {
+                "host" => {
+                    active = matches_list(&self.name, args.iter().map(String::as_str));
+                    self.matched |= active;
+                }
+                "match" => {
+                    active = self.matches(&args).map_err(|e| format!("{}: {e}", at()))?;
+                    self.matched |= active;
+                }
+                _ if !active => {}
+                "hostname" => {
+                    let value = first_arg(&args, keyword).map_err(|e| format!("{}: {e}", at()))?;
+                    self.settings.host_name.get_or_insert(value);
+                }
+                "port" => {
+                    let value = first_arg(&args, keyword).map_err(|e| format!("{}: {e}", at()))?;
+                    let port = value
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|&port| port != 0)
+                        .ok_or_else(|| format!("{}: Port '{value}' is not a port", at()))?;
+                    self.settings.port.get_or_insert(port);
+                }
+                "setenv" => {
+                    for arg in &args {
+                        let (variable, value) = arg.split_once('=').unwrap_or((arg, ""));
+                        let Some(option) = variable.strip_prefix("KNOCK_") else {
+                            continue;
+                        };
+                        let name = option.to_ascii_lowercase().replace('_', "-");
+                        let value = Some(value.to_string()).filter(|v| !v.is_empty());
+                        self.knock_option(&name, value)
+                            .map_err(|e| format!("{}: {e}", at()))?;
+                    }
+                }
+                "include" => {
+                    if depth + 1 >= MAX_INCLUDE_DEPTH {
+                        return Err(format!(
+                            "{}: Include nested more than {MAX_INCLUDE_DEPTH} deep",
+                            at()
+                        ));
+                    }
+                    for pattern in &args {
+                        for included in self.include_paths(pattern) {
+                            self.read(&included, true, depth + 1)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the knock option `name`, unless an earlier block gave it.
+    fn knock_option(&mut self, name: &str, value: Option<String>) -> Result<(), String> {
+        let settings = &mut self.settings;
+        if settings.seen.iter().any(|seen| seen == name) && name != "resolve" {
+            return Ok(());
+        }
+        settings.seen.push(name.to_string());
+        if name == "sequence" {
+            let value = value.ok_or("'sequence' needs a value")?;
+            let sequence =
+                KnockPlan::parse_untrusted(&value).map_err(|e| format!("knock-sequence: {e}"))?;
+            settings.sequence = Some(sequence);
+            return Ok(());
+        }
+        if let Some(option) = crate::uri::param(name, value, "knock option of ssh_config")? {
+            settings.options.push(option);
+        }
+        Ok(())
+    }
+
+    /// Whether the criteria of a `Match` line hold for the name.
+    fn matches(&self, args: &[String]) -> Result<bool, String> {
+        let host = match &self.settings.host_name {
+            Some(host_name) => expand_host_name(host_name, &self.name).to_lowercase(),
+            None => self.name.clone(),
+        };
+        let mut args = args.iter();
+        let mut all = true;
+        while let Some(arg) = args.next() {
+            let (negated, criterion) = match arg.strip_prefix('!') {
+                Some(criterion) => (true, criterion),
+                None => (false, arg.as_str()),
+            };
+            let criterion = criterion.to_ascii_lowercase();
+            let holds = match criterion.as_str() {
+                "all" | "final" => true,
+                "canonical" => false,
+                _ => {
+                    let list = args
+                        .next()
+                        .ok_or_else(|| format!("Match {criterion} needs an argument"))?;
+                    match criterion.as_str() {
+                        "host" => matches_list(&host, list.split(',')),
+                        "originalhost" => matches_list(&self.name, list.split(',')),
+                        // exec, user, localuser, localnetwork, tagged, ...
+                        _ => return Ok(false),
+                    }
+                }
+            };
+            all &= holds != negated;
+        }
+        Ok(all)
+    }
+
+    /// The files an `Include` of `pattern` reads, in name order: a
+    /// wildcard may stand in the file name, and a missing file is none.
+    fn include_paths(&self, pattern: &str) -> Vec<PathBuf> {
+        let path = match pattern.strip_prefix("~/") {
+            Some(rest) => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(rest),
+                None => return Vec::new(),
+            },
+            None => self.base.join(pattern),
+        };
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            return Vec::new();
+        };
+        if !file_name.contains(['*', '?']) {
+            return match path.is_file() {
+                true => vec![path],
+                false => Vec::new(),
+            };
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| !name.starts_with('.') && wildcard_match(file_name, name))
+            })
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        paths
+    }
+}
+
+/// The keyword of a line and the rest: `Keyword value`, `Keyword=value`
+/// or `Keyword = value`.
+fn split_keyword(line: &str) -> (&str, &str) {
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    let (keyword, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim_start();
+    (keyword, rest)
+}
+
+/// The arguments of a directive, split at whitespace outside quotes.
+fn split_args(rest: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    for c in rest.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated quote in '{rest}'"));
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// `value` without the double quotes around it, if it has them.
+fn unquote(value: &str) -> String {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// The one argument `keyword` takes.
+fn first_arg(args: &[String], keyword: &str) -> Result<String, String> {
+    args.first()
+        .cloned()
+        .ok_or_else(|| format!("{keyword} needs an argument"))
+}
+
+/// `HostName` with `%h` the name and `%%` a percent sign.
+fn expand_host_name(host_name: &str, name: &str) -> String {
+    host_name
+        .replace("%%", "\0")
+        .replace("%h", name)
+        .replace('\0', "%")
+}
+
+/// Whether `name` matches a pattern list: one of its patterns, and none
+/// of its `!` negated ones.
+fn matches_list<'a>(name: &str, patterns: impl Iterator<Item = &'a str>) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        let pattern = pattern.to_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated, name) => return false,
+            Some(_) => {}
+            None => matched |= wildcard_match(&pattern, name),
+        }
+    }
+    matched
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for one.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ssh/config")
+    }
+
+    fn lookup(name: &str) -> Result<SshHost, String> {
+        SshHost::lookup(&fixture(), name)
+    }
+
+    #[test]
+    fn entries_give_the_host_port_and_knock() {
+        let bastion = lookup("bastion").unwrap();
+        assert_eq!(bastion.host, "203.0.113.7");
+        assert_eq!(bastion.port, 2222);
+        assert_eq!(bastion.sequence.unwrap().to_string(), "7000,8000,9000");
+        assert_eq!(
+            bastion.options,
+            [
+                ("protocol", Some("udp".into())),
+                ("timeout", Some("2000".into())),
+                ("dry-run", None),
+            ]
+        );
+
+        // From SetEnv, the first value winning; no Port is 22
+        let web = lookup("WEB.example.com").unwrap();
+        assert_eq!(web.host, "web.example.com.internal");
+        assert_eq!(web.port, 22);
+        assert_eq!(web.sequence.as_ref().unwrap().to_string(), "1111,2222/udp");
+        assert_eq!(
+            web.args()[2..],
+            ["--payload=open sesame", "--fail-fast", "--verify=22"]
+        );
+    }
+
+    #[test]
+    fn includes_and_match_blocks_apply() {
+        // From config.d/*.conf, Match host judged on the HostName
+        let db = lookup("db").unwrap();
+        assert_eq!(db.host, "10.0.0.5");
+        assert_eq!(db.port, 5022);
+        assert_eq!(db.sequence.as_ref().unwrap().to_string(), "4000,5000");
+        assert_eq!(db.args().last().unwrap(), "--verify=5432");
+
+        // Match exec is never judged to hold
+        let other = lookup("other").unwrap();
+        assert_eq!(other.host, "other");
+        assert!(other.sequence.is_none());
+        assert!(other.options.is_empty());
+    }
+
+    #[test]
+    fn bad_entries_are_errors() {
+        let error = |name: &str| lookup(name).unwrap_err();
+        assert!(error("broken").contains("'hooks' is not a knock option"));
+        assert!(error("badport").contains("Port 'ssh' is not a port"));
+        let missing = SshHost::lookup(Path::new("/nonexistent/ssh_config"), "h").unwrap_err();
+        assert!(missing.starts_with("cannot read /nonexistent/ssh_config"));
+    }
+
+    #[test]
+    fn patterns_match_like_ssh() {
+        assert!(wildcard_match("*.example.com", "web.example.com"));
+        assert!(wildcard_match("db?", "db1"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("a*b*c", "aXXbYYc"));
+        assert!(!wildcard_match("db?", "db"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(matches_list("web1", ["web*", "!web2"].into_iter()));
+        assert!(!matches_list("web2", ["web*", "!web2"].into_iter()));
+        assert!(!matches_list("web1", ["!web2"].into_iter()));
+        assert_eq!(
+            split_keyword("HostName=example.com"),
+            ("HostName", "example.com")
+        );
+        assert_eq!(split_keyword("Port = 22"), ("Port", "22"));
+        assert_eq!(
+            split_args(r#"KNOCK_A=1 "KNOCK_B=a b"  c"#).unwrap(),
+            ["KNOCK_A=1", "KNOCK_B=a b", "c"]
+        );
+        assert!(split_args("\"open").is_err());
+    }
+}
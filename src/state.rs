@@ -0,0 +1,282 @@
+//! Remembering successful runs, so one that knocked recently can be
+//! skipped (`--state-file`, `--skip-if-recent`), and how far a run got, so
+//! one cut short can be resumed (`--resume`).
+//!
+//! The state file holds one line per host and sequence last knocked
+//! successfully: `HOST<TAB>SEQUENCE-HASH<TAB>UNIX-SECONDS`, and one per
+//! host whose last run is still going or was cut short, with the index of
+//! the last knock delivered after the time: `...<TAB>UNIX-SECONDS<TAB>INDEX`.
+//! Only a hash of the sequence is kept, never the ports themselves. Lines
+//! that do not parse are skipped, and dropped the next time the file is
+//! written; a missing file has no records. By default the file is
+//! `$XDG_STATE_HOME/async_port_knocker/state`, or under
+//! `~/.local/state` without `XDG_STATE_HOME`.
+
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What a record is kept for: a host and the sequence knocked on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateKey {
+    pub host: String,
+    /// Hex of the first 16 bytes of the SHA-256 of the sequence's text.
+    pub sequence: String,
+}
+
+impl StateKey {
+    /// The key of knocking `sequence`, as text, on `host`.
+    pub fn new(host: &str, sequence: &str) -> Self {
+        let hash = Sha256::digest(sequence.as_bytes());
+        Self {
+            host: host.to_string(),
+            sequence: hex::encode(&hash[..16]),
+        }
+    }
+}
+
+/// When a host and sequence were last knocked successfully, or, with
+/// `step`, when their knock of that index was delivered in a run that has
+/// not succeeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    key: StateKey,
+    at: SystemTime,
+    step: Option<usize>,
+}
+
+/// How far the last run on a host got: the sequence it knocked and the
+/// last of its knocks delivered, with every one before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub key: StateKey,
+    /// Index of that knock in the sequence, from 0.
+    pub last_delivered: usize,
+    pub at: SystemTime,
+}
+
+/// The records of a state file, read into memory.
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    records: Vec<Record>,
+}
+
+/// Where the state file goes without `--state-file`: under
+/// `$XDG_STATE_HOME`, or `~/.local/state`; `None` without either.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+        })?;
+    Some(dir.join("async_port_knocker").join("state"))
+}
+
+impl StateFile {
+    /// Read the state file at `path`; a missing one has no records.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            records: text.lines().filter_map(parse_record).collect(),
+        })
+    }
+
+    /// The state file at `path` with no records, to start over from one
+    /// that cannot be read.
+    pub fn empty(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            records: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How long ago `key` was last knocked successfully. A record from the
+    /// future, after the clock was set back, does not count.
+    pub fn age(&self, key: &StateKey) -> Option<Duration> {
+        let record = self
+            .records
+            .iter()
+            .find(|r| r.key == *key && r.step.is_none())?;
+        SystemTime::now().duration_since(record.at).ok()
+    }
+
+    /// Note that `key` was knocked successfully at `at`; a run in
+    /// progress on its host is over.
+    pub fn record(&mut self, key: &StateKey, at: SystemTime) {
+        self.forget(key);
+        self.records
+            .retain(|r| r.key.host != key.host || r.step.is_none());
+        self.records.push(Record {
+            key: key.clone(),
+            at,
+            step: None,
+        });
+    }
+
+    /// Drop the record of `key`, so the next run knocks again. How far a
+    /// run got is kept, for `--resume`.
+    pub fn forget(&mut self, key: &StateKey) {
+        self.records.retain(|r| r.key != *key || r.step.is_some());
+    }
+
+    /// How far the last run on `host` that has not succeeded got, if it
+    /// delivered any knock.
+    pub fn progress(&self, host: &str) -> Option<Progress> {
+        self.records.iter().find_map(|r| {
+            let last_delivered = r.step.filter(|_| r.key.host == host)?;
+            Some(Progress {
+                key: r.key.clone(),
+                last_delivered,
+                at: r.at,
+            })
+        })
+    }
+
+    /// Note that the run knocking `key` delivered its knock `step`, and
+    /// every one before it, at `at`; it replaces how far any earlier run
+    /// on the host got.
+    pub fn record_progress(&mut self, key: &StateKey, step: usize, at: SystemTime) {
+        self.records
+            .retain(|r| r.key.host != key.host || r.step.is_none());
+        self.records.push(Record {
+            key: key.clone(),
+            at,
+            step: Some(step),
+        });
+    }
+
+    /// Write the records back, creating the directory when missing. The
+    /// file is replaced whole, so a run cut short never leaves half of it.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = String::new();
+        for record in &self.records {
+            let secs = record.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            text.push_str(&format!(
+                "{}\t{}\t{}",
+                record.key.host,
+                record.key.sequence,
+                secs.as_secs()
+            ));
+            if let Some(step) = record.step {
+                text.push_str(&format!("\t{step}"));
+            }
+            text.push('\n');
+        }
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(format!(".{}.tmp", std::process::id()));
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+/// One line of a state file, `None` when it is not a record.
+fn parse_record(line: &str) -> Option<Record> {
+    let mut fields = line.split('\t');
+    let (host, sequence, secs) = (fields.next()?, fields.next()?, fields.next()?);
+    let step = match fields.next() {
+        Some(step) => Some(step.parse().ok()?),
+        None => None,
+    };
+    let valid_hash = sequence.len() == 32 && sequence.bytes().all(|b| b.is_ascii_hexdigit());
+    if fields.next().is_some() || host.is_empty() || !valid_hash {
+        return None;
+    }
+    Some(Record {
+        key: StateKey {
+            host: host.to_string(),
+            sequence: sequence.to_string(),
+        },
+        at: UNIX_EPOCH.checked_add(Duration::from_secs(secs.parse().ok()?))?,
+        step,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_survive_a_round_trip_and_junk_is_skipped() {
+        let path = std::env::temp_dir()
+            .join(format!("apk-state-{}", std::process::id()))
+            .join("state");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        let key = StateKey::new("gw.example", "7000,8000,9000");
+        let other = StateKey::new("gw.example", "7000,8000");
+        assert_ne!(key, other);
+
+        // Missing: no records
+        let mut state = StateFile::load(&path).unwrap();
+        assert_eq!(state.age(&key), None);
+        state.record(&key, SystemTime::now() - Duration::from_secs(90));
+        state.record(&other, SystemTime::now());
+        state.save().unwrap();
+
+        // Garbage around the records is dropped on the next write
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("not a record\n{text}\t\t\nhost\tzz\t1\n")).unwrap();
+        let mut state = StateFile::load(&path).unwrap();
+        let age = state.age(&key).unwrap();
+        assert!((90..92).contains(&age.as_secs()), "{age:?}");
+        state.forget(&other);
+        state.save().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(StateFile::load(&path).unwrap().age(&other), None);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn progress_is_kept_until_the_run_succeeds() {
+        let path = std::env::temp_dir()
+            .join(format!("apk-progress-{}", std::process::id()))
+            .join("state");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        let key = StateKey::new("gw.example", "7000,8000,9000");
+        let at = UNIX_EPOCH + Duration::from_secs(1_792_137_600);
+
+        let mut state = StateFile::load(&path).unwrap();
+        state.record(&key, at);
+        state.record_progress(&key, 0, at);
+        state.record_progress(&key, 1, at);
+        state.save().unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2, "{text}");
+        assert!(text.ends_with("\t1792137600\t1\n"), "{text}");
+
+        // A failed run forgets the success but not how far it got
+        let mut state = StateFile::load(&path).unwrap();
+        state.forget(&key);
+        let progress = state.progress("gw.example").unwrap();
+        assert_eq!((progress.key, progress.last_delivered), (key.clone(), 1));
+        assert_eq!(state.progress("other.example"), None);
+
+        // Another sequence's run on the host replaces it
+        let other = StateKey::new("gw.example", "7000,8000");
+        state.record_progress(&other, 0, at);
+        assert_eq!(state.progress("gw.example").unwrap().key, other);
+
+        // And success ends it
+        state.record(&other, at);
+        assert_eq!(state.progress("gw.example"), None);
+        assert!(state.age(&other).is_some());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,326 @@
+//! Learning the public address this host knocks from (`--stun-server`),
+//! for SPA packets and payloads that must name it from behind NAT.
+//!
+//! One STUN Binding request (RFC 5389) goes to the server over UDP, and
+//! the address the server saw it come from is read from the response's
+//! XOR-MAPPED-ADDRESS, or the MAPPED-ADDRESS of a server from before RFC
+//! 5389. Nothing else of STUN is spoken: no authentication, no
+//! FINGERPRINT check, no retransmission but the one timeout.
+
+use crate::rt::{self, UdpSocket};
+use crate::securedns::host_port;
+use rand::RngCore;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// Port STUN servers listen on.
+pub const STUN_PORT: u16 = 3478;
+/// How long the server has to answer, lookup included.
+pub const QUERY_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Fixed second word of every RFC 5389 message.
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const ERROR_CODE: u16 = 0x0009;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// A STUN server, `HOST[:PORT]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StunServer {
+    pub host: String,
+    pub port: u16,
+}
+
+impl StunServer {
+    /// Parse `HOST[:PORT]`, with IPv6 addresses bracketed when a port
+    /// follows; the port defaults to 3478.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (host, port) = host_port(s, STUN_PORT).map_err(|e| format!("'{s}': {e}"))?;
+        Ok(Self { host, port })
+    }
+}
+
+/// The host, with the port when it is not 3478.
+impl fmt::Display for StunServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.port, self.host.contains(':')) {
+            (STUN_PORT, _) => f.write_str(&self.host),
+            (port, true) => write!(f, "[{}]:{port}", self.host),
+            (port, false) => write!(f, "{}:{port}", self.host),
+        }
+    }
+}
+
+/// The address `server` sees this host's UDP packets come from, asked
+/// over IPv6 when `v6` and the server has an IPv6 address, else over
+/// IPv4. Fails when the server cannot be reached within `timeout` or its
+/// answer is no use.
+pub async fn public_addr(
+    server: &StunServer,
+    v6: bool,
+    timeout: Duration,
+) -> io::Result<SocketAddr> {
+    match rt::timeout(timeout, query(server, v6)).await {
+        Ok(addr) => addr,
+        Err(_) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("no answer within {}ms", timeout.as_millis()),
+        )),
+    }
+}
+
+async fn query(server: &StunServer, v6: bool) -> io::Result<SocketAddr> {
+    let addrs = rt::lookup_host(&server.host, server.port).await?;
+    let addr = addrs
+        .iter()
+        .find(|a| a.is_ipv6() == v6)
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no addresses"))?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    let mut id = [0u8; 12];
+    rand::rng().fill_bytes(&mut id);
+    socket.send(&request(id)).await?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        match parse_response(&buf[..len], id) {
+            Ok(Some(addr)) => return Ok(addr),
+            // Not an answer to this request: a late one to another
+            Ok(None) => continue,
+            Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// A Binding request with transaction ID `id` and no attributes.
+fn request(id: [u8; 12]) -> [u8; HEADER_LEN] {
+    let mut message = [0u8; HEADER_LEN];
+    message[..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    message[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message[8..].copy_from_slice(&id);
+    message
+}
+
+/// The mapped address in a response to the request sent with `id`;
+/// `None` when it answers another one.
+fn parse_response(message: &[u8], id: [u8; 12]) -> Result<Option<SocketAddr>, String> {
+    if message.len() < HEADER_LEN {
+        return Err(format!("{}-byte response is too short", message.len()));
+    }
+    let word = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]);
+    if message[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return Err("response is not STUN (no magic cookie)".into());
+    }
+    if message[8..HEADER_LEN] != id {
+        return Ok(None);
+    }
+    let kind = word(0);
+    let len = usize::from(word(2));
+    if message.len() < HEADER_LEN + len {
+        return Err(format!(
+            "response says {len} bytes of attributes, has {}",
+            message.len() - HEADER_LEN
+        ));
+    }
+    let (mut xor_mapped, mut mapped, mut error) = (None, None, None);
+    let mut rest = &message[HEADER_LEN..HEADER_LEN + len];
+    while rest.len() >= 4 {
+        let kind = u16::from_be_bytes([rest[0], rest[1]]);
+        let len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+        let Some(value) = rest.get(4..4 + len) else {
+            return Err(format!("attribute 0x{kind:04x} runs past the response"));
+        };
+        match kind {
+            XOR_MAPPED_ADDRESS => xor_mapped = Some(address(value, Some(&message[4..HEADER_LEN]))?),
+            MAPPED_ADDRESS => mapped = Some(address(value, None)?),
+            ERROR_CODE if value.len() >= 4 => {
+                let code = u16::from(value[2] & 7) * 100 + u16::from(value[3]);
+                let reason = String::from_utf8_lossy(&value[4..]).into_owned();
+                error = Some(format!("{code} {reason}"));
+            }
+            _ => {}
+        }
+        // Values are padded to a multiple of 4 bytes
+        rest = rest.get(4 + len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    match kind {
+        BINDING_SUCCESS => xor_mapped
+            .or(mapped)
+            .map(Some)
+            .ok_or_else(|| "response has no mapped address".into()),
+        BINDING_ERROR => Err(format!(
+            "server refused the request ({})",
+            error.as_deref().unwrap_or("no error code")
+        )),
+        kind => Err(format!(
+            "message of type 0x{kind:04x} is not a Binding response"
+        )),
+    }
+}
+
+/// A (XOR-)MAPPED-ADDRESS value; `mask` is the cookie and transaction ID
+/// to undo the XOR with.
+fn address(value: &[u8], mask: Option<&[u8]>) -> Result<SocketAddr, String> {
+    let unmask = |bytes: &[u8]| -> Vec<u8> {
+        match mask {
+            Some(mask) => bytes.iter().zip(mask).map(|(b, m)| b ^ m).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+    let (family, len) = match value.get(1) {
+        Some(1) => (4, 8),
+        Some(2) => (6, 20),
+        Some(family) => return Err(format!("mapped address of unknown family {family}")),
+        None => return Err("mapped address is empty".into()),
+    };
+    if value.len() < len {
+        return Err(format!(
+            "IPv{family} mapped address is {} bytes",
+            value.len()
+        ));
+    }
+    let port = unmask(&value[2..4]);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let ip = unmask(&value[4..len]);
+    let ip = match family {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+        _ => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transaction ID of the RFC 5769 sample responses.
+    const ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    /// RFC 5769 2.2: a Binding response from "test vector" with
+    /// XOR-MAPPED-ADDRESS 192.0.2.1:32853, MESSAGE-INTEGRITY and FINGERPRINT.
+    const RESPONSE_V4: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    /// RFC 5769 2.3: the same with XOR-MAPPED-ADDRESS
+    /// [2001:db8:1234:5678:11:2233:4455:6677]:32853.
+    const RESPONSE_V6: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01,
+        0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9,
+        0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb,
+        0x0b, 0x4c,
+    ];
+
+    #[test]
+    fn requests_are_bare_binding_requests() {
+        assert_eq!(
+            request(ID),
+            [
+                0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34,
+                0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+            ]
+        );
+    }
+
+    #[test]
+    fn mapped_addresses_are_read_from_responses() {
+        assert_eq!(
+            parse_response(&RESPONSE_V4, ID),
+            Ok(Some("192.0.2.1:32853".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_response(&RESPONSE_V6, ID),
+            Ok(Some(
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                    .parse()
+                    .unwrap()
+            ))
+        );
+        // An answer to another request is skipped
+        assert_eq!(parse_response(&RESPONSE_V4, [0; 12]), Ok(None));
+
+        // A server from before RFC 5389 sends the address as is
+        let mut old = RESPONSE_V4[..20].to_vec();
+        old[3] = 12;
+        old.extend_from_slice(&[0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x80, 0x55, 192, 0, 2, 9]);
+        assert_eq!(
+            parse_response(&old, ID),
+            Ok(Some("192.0.2.9:32853".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn unusable_responses_are_errors() {
+        let error = |message: &[u8]| parse_response(message, ID).unwrap_err();
+        assert_eq!(error(&RESPONSE_V4[..12]), "12-byte response is too short");
+        assert!(error(&RESPONSE_V4[..40]).contains("says 60 bytes of attributes"));
+        let mut not_stun = RESPONSE_V4;
+        not_stun[4] = 0;
+        assert!(error(&not_stun).contains("no magic cookie"));
+
+        // 420 Unknown Attribute
+        let mut refused = RESPONSE_V4[..20].to_vec();
+        refused[..4].copy_from_slice(&[0x01, 0x11, 0x00, 0x1c]);
+        refused.extend_from_slice(&[0x00, 0x09, 0x00, 0x15, 0, 0, 4, 20]);
+        refused.extend_from_slice(b"Unknown Attribute\0\0\0");
+        assert_eq!(
+            error(&refused),
+            "server refused the request (420 Unknown Attribute)"
+        );
+
+        let mut bare = RESPONSE_V4[..20].to_vec();
+        bare[3] = 0;
+        assert_eq!(error(&bare), "response has no mapped address");
+    }
+
+    #[tokio::test]
+    async fn the_server_names_the_address_it_saw() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!((len, &buf[..4]), (HEADER_LEN, &[0, 1, 0, 0][..]));
+            let mut response = RESPONSE_V4;
+            response[8..20].copy_from_slice(&buf[8..20]);
+            // XOR the source port in as the server would
+            let xport = from.port() ^ (MAGIC_COOKIE >> 16) as u16;
+            response[42..44].copy_from_slice(&xport.to_be_bytes());
+            server.send_to(&response, from).await.unwrap();
+        });
+
+        let server = StunServer::parse(&format!("127.0.0.1:{port}")).unwrap();
+        let addr = public_addr(&server, false, QUERY_TIMEOUT).await.unwrap();
+        assert_eq!(addr.ip(), "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_ne!(addr.port(), 32853);
+        assert_eq!(server.to_string(), format!("127.0.0.1:{port}"));
+        assert_eq!(
+            StunServer::parse("stun.example.net").unwrap().port,
+            STUN_PORT
+        );
+    }
+}
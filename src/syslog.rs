@@ -0,0 +1,504 @@
+//! Knock events sent to the local syslog daemon.
+//!
+//! [`SyslogObserver`] writes one datagram per [`KnockEvent`] to a Unix
+//! datagram socket, `/dev/log` by default, in the RFC 3164 format the C
+//! library's `syslog()` uses or in RFC 5424 with the knock in structured
+//! data. Each event's level comes from [`severity`]. Syslog is only a copy
+//! of the output: a daemon that is down or a full socket loses the
+//! message, never the knock.
+
+use crate::events::{KnockEvent, KnockTarget};
+use crate::observer::KnockObserver;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fmt::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Where the local syslog daemon listens.
+pub const DEFAULT_SOCKET: &str = "/dev/log";
+
+/// Name the messages are tagged with.
+pub const APP_NAME: &str = "async_port_knocker";
+
+/// Private enterprise number of the structured data ID; 32473 is the one
+/// RFC 5612 reserves for documentation and examples.
+const SD_ID: &str = "knock@32473";
+
+/// Syslog severity levels (RFC 5424 section 6.2.1) that knock events use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+/// Syslog facilities a knocker may log as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+    User,
+    Daemon,
+    Auth,
+    Local(u8),
+}
+
+/// How messages are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// `<PRI>Mmm dd hh:mm:ss TAG[PID]: MSG`, as `syslog()` sends it.
+    #[default]
+    Rfc3164,
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP PROCID MSGID [SD] MSG`.
+    Rfc5424,
+}
+
+impl Facility {
+    /// Facility number (RFC 5424 section 6.2.1).
+    pub fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Auth => 4,
+            Facility::Local(n) => 16 + n,
+        }
+    }
+}
+
+/// `user`, `daemon`, `auth` or `local0` to `local7`.
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "user" => Ok(Facility::User),
+            "daemon" => Ok(Facility::Daemon),
+            "auth" => Ok(Facility::Auth),
+            local => match local.strip_prefix("local").map(str::parse::<u8>) {
+                Some(Ok(n)) if n <= 7 => Ok(Facility::Local(n)),
+                _ => Err(format!(
+                    "unknown syslog facility '{s}'; use user, daemon, auth or local0-local7"
+                )),
+            },
+        }
+    }
+}
+
+/// `rfc3164` or `rfc5424`.
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "rfc3164" | "3164" => Ok(Format::Rfc3164),
+            "rfc5424" | "5424" => Ok(Format::Rfc5424),
+            _ => Err(format!(
+                "unknown syslog format '{s}'; use rfc3164 or rfc5424"
+            )),
+        }
+    }
+}
+
+/// The level an event is logged at: failures as errors, failed attempts
+/// that may still be retried as warnings, changes and notices as notices,
+/// what went as planned as info, and the chatter before each knock as
+/// debug.
+pub fn severity(event: &KnockEvent) -> Severity {
+    match event {
+        KnockEvent::KnockFailed { .. } => Severity::Error,
+        KnockEvent::Finished { report } if report.steps.iter().any(|o| !o.succeeded) => {
+            Severity::Error
+        }
+        KnockEvent::AttemptFailed { .. } => Severity::Warning,
+        KnockEvent::AddressesChanged { .. } | KnockEvent::Notice { .. } => Severity::Notice,
+        KnockEvent::AddressChosen { .. }
+        | KnockEvent::Plan { .. }
+        | KnockEvent::KnockSucceeded { .. }
+        | KnockEvent::ReplySaved { .. }
+        | KnockEvent::Finished { .. } => Severity::Info,
+        KnockEvent::Resolved { .. }
+        | KnockEvent::KnockStarted { .. }
+        | KnockEvent::Hexdump { .. } => Severity::Debug,
+    }
+}
+
+/// Sends every event of a run to syslog; see the [module docs](self).
+#[derive(Debug)]
+pub struct SyslogObserver {
+    path: PathBuf,
+    facility: Facility,
+    format: Format,
+    hostname: String,
+    /// Connected lazily, and again after a send fails.
+    socket: Mutex<Option<UnixDatagram>>,
+}
+
+impl SyslogObserver {
+    /// Log to the daemon at `path`; nothing is sent until the first event.
+    pub fn new(path: impl Into<PathBuf>, facility: Facility, format: Format) -> Self {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty() && h.is_ascii() && !h.contains(' '))
+            .unwrap_or_else(|| "-".into());
+        Self {
+            path: path.into(),
+            facility,
+            format,
+            hostname,
+            socket: Mutex::new(None),
+        }
+    }
+
+    /// The datagram for `event`, stamped `now`.
+    pub fn message(&self, event: &KnockEvent, now: SystemTime) -> String {
+        let pri = u16::from(self.facility.code()) * 8 + severity(event) as u16;
+        let pid = std::process::id();
+        let text = describe(event);
+        match self.format {
+            Format::Rfc3164 => format!("<{pri}>{} {APP_NAME}[{pid}]: {text}", rfc3164_time(now)),
+            Format::Rfc5424 => format!(
+                "<{pri}>1 {} {} {APP_NAME} {pid} {} {} {text}",
+                rfc3339_time(now),
+                self.hostname,
+                msg_id(event),
+                structured_data(event)
+            ),
+        }
+    }
+
+    /// Send one datagram, reconnecting once if the daemon was restarted;
+    /// a daemon that is not there loses the message.
+    fn send(&self, message: &str) {
+        let mut socket = self.socket.lock().unwrap_or_else(|e| e.into_inner());
+        for _ in 0..2 {
+            if socket.is_none() {
+                *socket = connect(&self.path).ok();
+            }
+            match socket.as_ref().map(|s| s.send(message.as_bytes())) {
+                Some(Ok(_)) | None => return,
+                Some(Err(_)) => *socket = None,
+            }
+        }
+    }
+}
+
+impl KnockObserver for SyslogObserver {
+    fn on_event(&self, event: &KnockEvent) {
+        self.send(&self.message(event, SystemTime::now()));
+    }
+}
+
+/// A non-blocking socket to the daemon, so a stalled daemon drops
+/// messages instead of holding up the knocks.
+fn connect(path: &Path) -> std::io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// The event as one line of `key=value` fields after its name.
+fn describe(event: &KnockEvent) -> String {
+    let target = |t: &KnockTarget| format!("target=\"{t}\"");
+    let mut out = msg_id(event).to_string();
+    let _ = match event {
+        KnockEvent::Resolved { host, addrs } | KnockEvent::AddressesChanged { host, addrs, .. } => {
+            let addrs: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+            write!(out, " host={host} addrs={}", addrs.join(","))
+        }
+        KnockEvent::AddressChosen { host, addr } => write!(out, " host={host} addr={}", addr.ip()),
+        KnockEvent::Plan { text } => write!(out, " plan=\"{}\"", text.trim().replace('\n', "; ")),
+        KnockEvent::KnockStarted { target: t } => write!(out, " {}", target(t)),
+        KnockEvent::AttemptFailed {
+            target: t,
+            attempt,
+            error,
+        } => write!(out, " {} attempt={attempt} error=\"{error}\"", target(t)),
+        KnockEvent::KnockSucceeded {
+            target: t,
+            attempt,
+            latency,
+            detail,
+        } => write!(
+            out,
+            " {} attempt={attempt} latency_ms={} detail=\"{detail}\"",
+            target(t),
+            latency.as_millis()
+        ),
+        KnockEvent::ReplySaved {
+            target: t,
+            path,
+            bytes,
+            truncated,
+        } => write!(
+            out,
+            " {} path=\"{}\" bytes={bytes} truncated={truncated}",
+            target(t),
+            path.display()
+        ),
+        // The dump itself spans lines, which a syslog message does not
+        KnockEvent::Hexdump {
+            target: t,
+            direction,
+            attempt,
+            len,
+            ..
+        } => write!(
+            out,
+            " {} direction={direction} bytes={len} attempt={attempt}",
+            target(t)
+        ),
+        KnockEvent::KnockFailed {
+            target: t,
+            attempts,
+        } => write!(out, " {} attempts={attempts}", target(t)),
+        KnockEvent::Notice { target: t, message } => match t {
+            Some(t) => write!(out, " {} message=\"{message}\"", target(t)),
+            None => write!(out, " message=\"{message}\""),
+        },
+        KnockEvent::Finished { report } => write!(
+            out,
+            " host={} succeeded={} knocks={} duration_ms={} interrupted={}",
+            report.host,
+            report.steps.iter().filter(|o| o.succeeded).count(),
+            report.steps.len(),
+            report.duration.as_millis(),
+            report.interrupted
+        ),
+    };
+    out
+}
+
+/// The event's name, the RFC 5424 MSGID.
+fn msg_id(event: &KnockEvent) -> &'static str {
+    match event {
+        KnockEvent::Resolved { .. } => "resolved",
+        KnockEvent::AddressesChanged { .. } => "addresses_changed",
+        KnockEvent::AddressChosen { .. } => "address_chosen",
+        KnockEvent::Plan { .. } => "plan",
+        KnockEvent::KnockStarted { .. } => "knock_started",
+        KnockEvent::AttemptFailed { .. } => "attempt_failed",
+        KnockEvent::KnockSucceeded { .. } => "knock_succeeded",
+        KnockEvent::ReplySaved { .. } => "reply_saved",
+        KnockEvent::Hexdump { .. } => "hexdump",
+        KnockEvent::KnockFailed { .. } => "knock_failed",
+        KnockEvent::Notice { .. } => "notice",
+        KnockEvent::Finished { .. } => "finished",
+    }
+}
+
+/// The knock an event is about as an RFC 5424 SD-ELEMENT, or the nil
+/// value for events about no knock.
+fn structured_data(event: &KnockEvent) -> String {
+    let target = match event {
+        KnockEvent::KnockStarted { target }
+        | KnockEvent::AttemptFailed { target, .. }
+        | KnockEvent::KnockSucceeded { target, .. }
+        | KnockEvent::ReplySaved { target, .. }
+        | KnockEvent::Hexdump { target, .. }
+        | KnockEvent::KnockFailed { target, .. }
+        | KnockEvent::Notice {
+            target: Some(target),
+            ..
+        } => target,
+        _ => return "-".into(),
+    };
+    // Values escape '"', '\' and ']' (RFC 5424 section 6.3.3)
+    let escape = |s: &str| {
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace(']', "\\]")
+    };
+    format!(
+        "[{SD_ID} host=\"{}\" port=\"{}\" protocol=\"{}\"]",
+        escape(&target.host),
+        target.port,
+        target.protocol
+    )
+}
+
+/// `Oct 16 08:55:00` in UTC, the day padded with a space.
+fn rfc3164_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%b %e %H:%M:%S")
+        .to_string()
+}
+
+/// `2026-10-16T08:55:00.000Z`.
+fn rfc3339_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KnockOutcome, KnockReport, Protocol};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn target() -> KnockTarget {
+        KnockTarget::new("knock.example", 7000, Protocol::Udp)
+    }
+
+    fn report(succeeded: bool) -> KnockReport {
+        KnockReport {
+            duration: Duration::from_millis(12),
+            ..KnockReport::of_steps(
+                "knock.example",
+                vec![KnockOutcome {
+                    succeeded,
+                    ..KnockOutcome::failed(7000, Protocol::Tcp, "")
+                }],
+            )
+        }
+    }
+
+    /// A socket standing in for `/dev/log`, and an observer logging to it.
+    fn fixture(format: Format) -> (UnixDatagram, SyslogObserver, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "knock-syslog-{}-{format:?}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let observer = SyslogObserver::new(&path, Facility::Local(3), format);
+        (daemon, observer, path)
+    }
+
+    fn receive(daemon: &UnixDatagram) -> String {
+        let mut buf = [0; 2048];
+        let n = daemon.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn events_map_to_syslog_levels() {
+        let (daemon, observer, path) = fixture(Format::Rfc3164);
+        // local3 is facility 19, so PRI = 152 + severity
+        for (event, pri) in [
+            (
+                KnockEvent::KnockFailed {
+                    target: target(),
+                    attempts: 3,
+                },
+                155,
+            ),
+            (
+                KnockEvent::Finished {
+                    report: Box::new(report(false)),
+                },
+                155,
+            ),
+            (
+                KnockEvent::AttemptFailed {
+                    target: target(),
+                    attempt: 1,
+                    error: "timed out".into(),
+                },
+                156,
+            ),
+            (
+                KnockEvent::Notice {
+                    target: None,
+                    message: "deadline reached".into(),
+                },
+                157,
+            ),
+            (
+                KnockEvent::KnockSucceeded {
+                    target: target(),
+                    attempt: 1,
+                    latency: Duration::from_millis(3),
+                    detail: "SENT 16 bytes".into(),
+                },
+                158,
+            ),
+            (
+                KnockEvent::Finished {
+                    report: Box::new(report(true)),
+                },
+                158,
+            ),
+            (KnockEvent::KnockStarted { target: target() }, 159),
+        ] {
+            observer.on_event(&event);
+            let message = receive(&daemon);
+            assert!(
+                message.starts_with(&format!("<{pri}>")),
+                "{event:?}: {message}"
+            );
+            assert!(
+                message.contains(&format!(" {APP_NAME}[{}]: ", std::process::id())),
+                "{message}"
+            );
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rfc5424_carries_the_knock_as_structured_data() {
+        let (daemon, observer, path) = fixture(Format::Rfc5424);
+        observer.on_event(&KnockEvent::KnockFailed {
+            target: target(),
+            attempts: 2,
+        });
+        let message = receive(&daemon);
+        let fields: Vec<&str> = message.splitn(8, ' ').collect();
+        assert_eq!(fields[0], "<155>1");
+        assert!(
+            fields[1].ends_with('Z') && fields[1].len() == 24,
+            "{message}"
+        );
+        assert_eq!(fields[3], APP_NAME);
+        assert_eq!(fields[5], "knock_failed");
+        assert!(
+            message.contains(
+                "[knock@32473 host=\"knock.example\" port=\"7000\" protocol=\"udp\"] \
+                 knock_failed target=\"UDP knock.example:7000\" attempts=2"
+            ),
+            "{message}"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn timestamps_are_utc_calendar_dates() {
+        // 2026-10-16 08:55:00.250 UTC
+        let time = UNIX_EPOCH + Duration::from_millis(1_792_140_900_250);
+        assert_eq!(rfc3339_time(time), "2026-10-16T08:55:00.250Z");
+        assert_eq!(rfc3164_time(time), "Oct 16 08:55:00");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(rfc3339_time(leap), "2000-02-29T00:00:00.000Z");
+        // A one-digit day is padded with a space
+        let early = UNIX_EPOCH + Duration::from_secs(1_791_190_800);
+        assert_eq!(rfc3164_time(early), "Oct  5 09:00:00");
+    }
+
+    #[test]
+    fn a_missing_daemon_loses_only_the_message() {
+        let observer = SyslogObserver::new(
+            "/nonexistent/knock-syslog.sock",
+            Facility::User,
+            Format::Rfc3164,
+        );
+        observer.on_event(&KnockEvent::KnockStarted { target: target() });
+        assert!(observer.socket.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn facility_and_format_names() {
+        assert_eq!("daemon".parse(), Ok(Facility::Daemon));
+        assert_eq!("LOCAL7".parse(), Ok(Facility::Local(7)));
+        assert_eq!(Facility::Local(7).code(), 23);
+        assert!("local8".parse::<Facility>().is_err());
+        assert!("kern".parse::<Facility>().is_err());
+        assert_eq!("rfc5424".parse(), Ok(Format::Rfc5424));
+        assert!("json".parse::<Format>().is_err());
+    }
+}
@@ -1,40 +1,606 @@
-use crate::retry::retry_with_backoff;
-use std::sync::Arc;
-use tokio::net::TcpStream;
+use crate::rt::{Instant, TcpSocket, TcpStream};
+use crate::{
+    config::KnockOpts,
+    errors::{AppError, ErrorClass},
+    events::{EventSink, KnockTarget},
+    hexdump::Direction,
+    jump::JumpHost,
+    outcome::{AttemptLog, KnockOutcome},
+    pcap::PcapWriter,
+    protocol::{Protocol, TcpClose},
+    retry::{retry_with_backoff, sync_on_timeout, RetryDecision},
+    socks::{Socks5Proxy, SocksError},
+};
+use bytes::Bytes;
+use socket2::SockRef;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-/// Perform a TCP knock with per-attempt logging, retries, timeouts and backoff.
-pub(crate) async fn knock_tcp(
-    host: Arc<String>,
+/// Per-run TCP knock behavior beyond timing and retries.
+#[derive(Clone, Default)]
+pub struct TcpOpts {
+    /// Count a refused connection as a failed knock instead of a delivered one.
+    pub refused_is_failure: bool,
+    /// Bytes to write on the connection once it is established.
+    pub payload: Option<Bytes>,
+    /// Number of response bytes to wait for before closing.
+    pub expect: usize,
+    /// How the knock connection is torn down.
+    pub close: TcpClose,
+    /// Tunnel each knock connection through this SOCKS5 proxy.
+    pub proxy: Option<Socks5Proxy>,
+    /// Forward each knock connection through this SSH bastion.
+    pub jump: Option<JumpHost>,
+    /// Connect from exactly this local port, as a step asks for; one in
+    /// use is an [`AppError::Bind`]. Address reuse is on, so the port's
+    /// earlier connections waiting out TIME_WAIT do not block it.
+    pub bind_port: Option<u16>,
+    /// Firewall mark (SO_MARK) of the knock connection (Linux only).
+    pub fwmark: Option<u32>,
+    /// IP TTL (IPv6 hop limit) of the knock connection's packets, as a
+    /// step asks for.
+    pub ttl: Option<u8>,
+}
+
+/// Perform a single TCP knock on `target` with retries, timeouts and
+/// backoff, and report how it went.
+///
+/// A refused connection proves the SYN reached the host, so it counts as a
+/// delivered knock unless `opts.tcp.refused_is_failure` is set. When a
+/// proxy or jump host is configured, a failed proxy handshake or a jump
+/// host that cannot be used aborts the knock with [`AppError::Proxy`] or
+/// [`AppError::Jump`] instead of being retried, and so does a connect the
+/// local host does not permit or has no address for, as [`AppError::Io`]. A knock that does not get
+/// through is an [`AppError::Timeout`] or [`AppError::KnockFailed`].
+pub async fn knock_tcp(target: SocketAddr, opts: &KnockOpts) -> Result<KnockOutcome, AppError> {
+    knock_addr(target, opts).await?.into_result()
+}
+
+/// [`knock_tcp`] returning the outcome of a failed knock too.
+pub(crate) async fn knock_addr(
+    target: SocketAddr,
+    opts: &KnockOpts,
+) -> Result<KnockOutcome, AppError> {
+    knock(
+        &target.ip().to_string(),
+        target.port(),
+        Some(target),
+        opts,
+        None,
+        &EventSink::default(),
+    )
+    .await
+}
+
+/// [`knock_tcp`] with per-attempt events and capture, as used by a run.
+///
+/// `addr` is the resolved address to dial, so every knock of a sequence
+/// lands on the same machine; it is `None` when the proxy or jump host
+/// resolves `host`.
+pub(crate) async fn knock(
+    host: &str,
     port: u16,
-    to_ms: u64,
-    retries: usize,
-    backoff: u64,
-) {
-    let host_for_timeout = host.clone();
-    let _ = retry_with_backoff(
-        retries,
-        to_ms,
-        backoff,
+    addr: Option<SocketAddr>,
+    opts: &KnockOpts,
+    pcap: Option<&PcapWriter>,
+    events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    let target = addr.map(|mut a| {
+        a.set_port(port);
+        a
+    });
+    let started = Instant::now();
+    let tcp = &opts.tcp;
+    let ttl = tcp
+        .ttl
+        .filter(|_| tcp.proxy.is_none() && tcp.jump.is_none());
+    let log = AttemptLog::new(events, KnockTarget::new(host, port, Protocol::Tcp)).with_ttl(ttl);
+    let retry = retry_with_backoff(
+        opts.attempts,
+        opts.timeout,
+        &opts.backoff,
+        opts.deadline,
+        opts.rate.as_deref(),
+        opts.budget.as_deref(),
         |attempt| {
-            let host = host.clone();
+            let log = &log;
             async move {
-                match TcpStream::connect((host.as_str(), port)).await {
+                let start = Instant::now();
+                let sent_at = SystemTime::now();
+                let connected = match connect(host, port, target, tcp).await {
+                    Ok(connected) => connected,
+                    // A failed proxy handshake will not go better next time,
+                    // nor a knock without an address
+                    Err(e) => return RetryDecision::Fatal(e),
+                };
+                match connected {
                     // Connected successfully
-                    Ok(_stream) => {
-                        println!("TCP {host}:{port} OK");
-                        Ok::<bool, ()>(true) // stop retrying
-                    }
-                    // Got an immediate I/O error
-                    Err(e) => {
-                        eprintln!("TCP {host}:{port} ERR {e} (attempt {attempt})");
-                        Ok::<bool, ()>(false) // retry
+                    Ok(mut stream) => {
+                        if let (Some(pcap), Connection::Direct(direct)) = (pcap, &stream) {
+                            if let (Ok(local), Ok(peer)) = (direct.local_addr(), direct.peer_addr())
+                            {
+                                pcap.record_tcp_syn(sent_at, local, peer);
+                            }
+                        }
+                        // Optional payload exchange before closing
+                        let exchanged = match &mut stream {
+                            Connection::Direct(stream) => exchange(stream, tcp).await,
+                            #[cfg(feature = "ssh")]
+                            Connection::Jumped(stream) => exchange(stream, tcp).await,
+                        };
+                        let received = match exchanged {
+                            Ok(received) => received,
+                            Err(msg) => {
+                                log.push(attempt, msg);
+                                return RetryDecision::Retry; // retry
+                            }
+                        };
+                        if let Some(payload) = &tcp.payload {
+                            log.hexdump(opts.hexdump, Direction::Sent, attempt, payload);
+                        }
+                        if tcp.expect > 0 {
+                            log.hexdump(opts.hexdump, Direction::Received, attempt, &received);
+                        }
+                        if let Err(e) = close(stream, tcp.close) {
+                            log.notice(format!("close ERR {e} (attempt {attempt})"));
+                        }
+                        let elapsed = start.elapsed();
+                        log.succeeded(attempt, elapsed, "OK");
+                        RetryDecision::Done(elapsed) // stop retrying
                     }
+                    Err(e) => match ErrorClass::of(&e) {
+                        // Refused: the SYN got through, the knock was delivered
+                        ErrorClass::Delivered if !tcp.refused_is_failure => {
+                            if let Some(pcap) = pcap {
+                                record_failed_syn(pcap, sent_at, target);
+                            }
+                            let elapsed = start.elapsed();
+                            log.succeeded(attempt, elapsed, "REFUSED (knock delivered)");
+                            RetryDecision::Done(elapsed) // stop retrying
+                        }
+                        // Not allowed to connect at all (e.g. EACCES from a
+                        // local firewall rule) or no local address to
+                        // connect from: no retry will change that
+                        ErrorClass::Fatal if tcp.jump.is_some() => {
+                            RetryDecision::Fatal(AppError::LocalFailure {
+                                source: e,
+                                hint: crate::jump::PROHIBITED_HINT,
+                            })
+                        }
+                        ErrorClass::Fatal => RetryDecision::Fatal(AppError::local_failure(e)),
+                        // Unreachable or other I/O error: worth another attempt
+                        class => {
+                            if let Some(pcap) = pcap {
+                                record_failed_syn(pcap, sent_at, target);
+                            }
+                            log.push_classified(attempt, e.to_string(), class);
+                            RetryDecision::Retry // retry
+                        }
+                    },
                 }
             }
         },
-        |attempt| {
-            eprintln!("TCP {host_for_timeout}:{port} TIMEOUT (attempt {attempt})");
+        sync_on_timeout(|attempt, elapsed| {
+            log.timed_out(
+                attempt,
+                format!("timed out after {}ms", elapsed.as_millis()),
+            );
+        }),
+    )
+    .await?;
+    if retry.deadline_limited {
+        log.notice(format!(
+            "knock deadline reached after {} attempt(s)",
+            retry.attempts
+        ));
+    }
+    if retry.rate_closed {
+        log.notice(format!(
+            "cancelled waiting for --rate after {} attempt(s)",
+            retry.attempts
+        ));
+    }
+    if retry.budget_spent {
+        log.notice(format!(
+            "retry budget spent after {} attempt(s)",
+            retry.attempts
+        ));
+    }
+
+    let latency = retry.value;
+    Ok(KnockOutcome {
+        port,
+        protocol: Protocol::Tcp,
+        addr: target,
+        source_port: tcp.bind_port,
+        fwmark: tcp
+            .fwmark
+            .filter(|_| tcp.proxy.is_none() && tcp.jump.is_none()),
+        ttl,
+        payload_len: None,
+        attempts_allowed: None,
+        attempts: retry.attempts,
+        succeeded: retry.succeeded(),
+        acknowledged: latency.is_some(),
+        latency,
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: log.into_errors(),
+        elapsed: started.elapsed(),
+    })
+}
+
+/// A knock connection, dialled or forwarded by a jump host.
+#[derive(Debug)]
+enum Connection {
+    Direct(TcpStream),
+    #[cfg(feature = "ssh")]
+    Jumped(crate::jump::JumpStream),
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Self {
+        Connection::Direct(stream)
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl From<crate::jump::JumpStream> for Connection {
+    fn from(stream: crate::jump::JumpStream) -> Self {
+        Connection::Jumped(stream)
+    }
+}
+
+/// Connect directly to `target`, through the proxy or through the jump
+/// host. The outer error is a proxy or jump host failure, or no address to
+/// dial without either, since a knock never resolves `host` by itself; the
+/// inner result is the target connect as a direct dial would have
+/// reported it.
+async fn connect(
+    host: &str,
+    port: u16,
+    target: Option<SocketAddr>,
+    opts: &TcpOpts,
+) -> Result<io::Result<Connection>, AppError> {
+    if let Some(jump) = &opts.jump {
+        return Ok(jump.connect(host, port).await?.map(Connection::from));
+    }
+    match &opts.proxy {
+        None => {
+            let target = target.ok_or(AppError::NoDns)?;
+            let socket = match (opts.bind_port, opts.fwmark, opts.ttl) {
+                (None, None, None) => {
+                    return Ok(TcpStream::connect(target).await.map(Connection::from))
+                }
+                (port, fwmark, ttl) => open_socket(target, port, fwmark, ttl)?,
+            };
+            Ok(socket.connect(target).await.map(Connection::from))
+        }
+        Some(proxy) => match proxy.connect(host, port).await {
+            Ok(stream) => Ok(Ok(stream.into())),
+            Err(SocksError::Target(e)) => Ok(Err(e)),
+            Err(SocksError::Proxy(msg)) => Err(AppError::Proxy(msg)),
         },
+    }
+}
+
+/// Connect to `target` for a step of another kind (HTTP, TLS), from a
+/// socket carrying firewall mark `fwmark` when given.
+pub(crate) async fn dial(target: SocketAddr, fwmark: Option<u32>) -> io::Result<TcpStream> {
+    match fwmark {
+        None => TcpStream::connect(target).await,
+        Some(_) => {
+            let socket = open_socket(target, None, fwmark, None).map_err(io::Error::other)?;
+            socket.connect(target).await
+        }
+    }
+}
+
+/// A socket for connecting to `target`, carrying firewall mark `fwmark`,
+/// sending with IP TTL `ttl` and bound to local port `port` when given.
+fn open_socket(
+    target: SocketAddr,
+    port: Option<u16>,
+    fwmark: Option<u32>,
+    ttl: Option<u8>,
+) -> Result<TcpSocket, AppError> {
+    let socket = match target {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .map_err(AppError::local_failure)?;
+    if let Some(mark) = fwmark {
+        crate::fwmark::set(&socket, mark)?;
+    }
+    if let Some(ttl) = ttl {
+        crate::udp::set_ttl(socket2::SockRef::from(&socket), target.is_ipv6(), ttl)
+            .map_err(AppError::local_failure)?;
+    }
+    if let Some(port) = port {
+        let addr = crate::udp::bind_addr(target, port);
+        let bind = || {
+            socket.set_reuseaddr(true)?;
+            socket.bind(addr)
+        };
+        bind().map_err(|source| AppError::Bind { addr, source })?;
+    }
+    Ok(socket)
+}
+
+/// Write the configured payload and wait for the expected reply bytes,
+/// which are returned. An error is a human-readable description of what
+/// went wrong.
+async fn exchange<S>(stream: &mut S, opts: &TcpOpts) -> Result<Vec<u8>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(payload) = &opts.payload {
+        let total = payload.len();
+        let mut written = 0;
+        while written < total {
+            match stream.write(&payload[written..]).await {
+                Ok(0) => return Err(format!("PARTIAL write {written}/{total} bytes")),
+                Ok(n) => written += n,
+                Err(e) if is_reset(&e) => {
+                    return Err(format!("RESET mid-write after {written}/{total} bytes"))
+                }
+                Err(e) => return Err(format!("write ERR {e} after {written}/{total} bytes")),
+            }
+        }
+    }
+
+    let mut buf = vec![0u8; opts.expect];
+    if opts.expect > 0 {
+        let mut got = 0;
+        while got < opts.expect {
+            match stream.read(&mut buf[got..]).await {
+                Ok(0) => return Err(format!("closed after {got}/{} expected bytes", opts.expect)),
+                Ok(n) => got += n,
+                Err(e) if is_reset(&e) => {
+                    return Err(format!("RESET after {got}/{} expected bytes", opts.expect))
+                }
+                Err(e) => return Err(format!("read ERR {e}")),
+            }
+        }
+    }
+    Ok(buf)
+}
+
+/// Drop the knock connection, aborting it with a RST instead of the
+/// FIN exchange when requested (SO_LINGER with a zero timeout).
+/// Tear the connection down; a forwarded one is closed by the jump host,
+/// which cannot reset it.
+fn close(stream: Connection, mode: TcpClose) -> io::Result<()> {
+    match stream {
+        Connection::Direct(stream) => {
+            if mode == TcpClose::Rst {
+                SockRef::from(&stream).set_linger(Some(Duration::ZERO))?;
+            }
+            drop(stream);
+            Ok(())
+        }
+        #[cfg(feature = "ssh")]
+        Connection::Jumped(_) => Ok(()),
+    }
+}
+
+/// Peer tore the connection down under us.
+fn is_reset(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
     )
-    .await;
+}
+
+/// Record the SYN of a connect that failed. The local address is unknown
+/// at this point, so the source is left unspecified.
+fn record_failed_syn(pcap: &PcapWriter, sent_at: SystemTime, target: Option<SocketAddr>) {
+    let Some(target) = target else {
+        return;
+    };
+    let src = match target.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    pcap.record_tcp_syn(sent_at, SocketAddr::new(src, 0), target);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::{Attempts, BackoffPolicy};
+    use std::io::ErrorKind;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn direct_connect_never_resolves_the_name() {
+        let result = connect("localhost", 80, None, &TcpOpts::default()).await;
+        assert!(matches!(result, Err(AppError::NoDns)));
+    }
+
+    #[tokio::test]
+    async fn payload_written_and_reply_awaited() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            sock.read_exact(&mut buf).await.unwrap();
+            sock.write_all(b"ok").await.unwrap();
+            buf
+        });
+
+        let opts = TcpOpts {
+            payload: Some(Bytes::from_static(b"magic")),
+            expect: 2,
+            ..TcpOpts::default()
+        };
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        exchange(&mut stream, &opts).await.unwrap();
+        assert_eq!(&server.await.unwrap(), b"magic");
+    }
+
+    #[tokio::test]
+    async fn early_close_reports_missing_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            drop(sock);
+        });
+
+        let opts = TcpOpts {
+            expect: 4,
+            ..TcpOpts::default()
+        };
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let err = exchange(&mut stream, &opts).await.unwrap_err();
+        assert!(err.contains("0/4"), "{err}");
+    }
+
+    /// Accept one connection, then report how the client closed it.
+    async fn observe_close(listener: TcpListener) -> io::Result<usize> {
+        let (mut sock, _) = listener.accept().await?;
+        let mut buf = [0u8; 16];
+        sock.read(&mut buf).await
+    }
+
+    #[tokio::test]
+    async fn rst_close_resets_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(observe_close(listener));
+
+        let opts = KnockOpts {
+            tcp: TcpOpts {
+                close: TcpClose::Rst,
+                ..TcpOpts::default()
+            },
+            ..KnockOpts::default()
+        };
+        let outcome = knock_tcp(addr, &opts).await.unwrap();
+        assert!(outcome.succeeded);
+
+        let err = server.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn default_close_is_graceful() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(observe_close(listener));
+
+        // The pre-resolved address is dialed; the name is never looked up
+        let outcome = knock(
+            "knock.invalid",
+            addr.port(),
+            Some(addr),
+            &KnockOpts::default(),
+            None,
+            &EventSink::default(),
+        )
+        .await
+        .unwrap();
+        assert!(outcome.succeeded);
+
+        // FIN shows up as a clean end-of-stream
+        assert_eq!(server.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn dead_proxy_is_a_proxy_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let opts = KnockOpts {
+            attempts: Attempts::Finite(3),
+            backoff: BackoffPolicy::Constant(Duration::ZERO),
+            tcp: TcpOpts {
+                proxy: Some(Socks5Proxy::parse(&proxy_addr.to_string()).unwrap()),
+                ..TcpOpts::default()
+            },
+            ..KnockOpts::default()
+        };
+        let err = knock(
+            "bastion.example",
+            7000,
+            None,
+            &opts,
+            None,
+            &EventSink::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, AppError::Proxy(_)), "{err}");
+    }
+
+    /// Knock through a stand-in for `ssh` whose channel open fails with
+    /// `failure`.
+    #[cfg(all(feature = "ssh", unix))]
+    async fn knock_through_failing_jump(
+        name: &str,
+        failure: &str,
+        refused_is_failure: bool,
+    ) -> Result<KnockOutcome, AppError> {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("jump-{}-{name}", std::process::id()));
+        std::fs::write(
+            &path,
+            format!("#!/bin/sh\necho '{failure}' >&2\nexit 255\n"),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let opts = KnockOpts {
+            attempts: Attempts::Finite(2),
+            backoff: BackoffPolicy::Constant(Duration::ZERO),
+            tcp: TcpOpts {
+                refused_is_failure,
+                jump: Some(JumpHost {
+                    program: path.to_string_lossy().into_owned(),
+                    ..JumpHost::parse("bastion").unwrap()
+                }),
+                ..TcpOpts::default()
+            },
+            ..KnockOpts::default()
+        };
+        let events = EventSink::default();
+        let result = knock("10.0.0.5", 7000, None, &opts, None, &events).await;
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[cfg(all(feature = "ssh", unix))]
+    #[tokio::test]
+    async fn jump_channel_failures_count_like_direct_connects() {
+        let refused = "channel 0: open failed: connect failed: Connection refused";
+        let outcome = knock_through_failing_jump("refused", refused, false)
+            .await
+            .unwrap();
+        assert!(outcome.succeeded && outcome.attempts == 1);
+        let outcome = knock_through_failing_jump("refused-strict", refused, true)
+            .await
+            .unwrap();
+        assert!(!outcome.succeeded && outcome.attempts == 2);
+
+        let prohibited = "channel 0: open failed: administratively prohibited: open failed";
+        let err = knock_through_failing_jump("prohibited", prohibited, false)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, AppError::LocalFailure { hint, .. } if hint.contains("PermitOpen")),
+            "{err}"
+        );
+        let err = knock_through_failing_jump("login", "ops@bastion: Permission denied", false)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SSH jump host error: bastion: ops@bastion: Permission denied"
+        );
+    }
 }
@@ -0,0 +1,357 @@
+//! An in-process server to knock against in tests.
+//!
+//! [`MockKnockServer`] listens on ephemeral TCP and UDP ports of
+//! `127.0.0.1` and records every knock that reaches it, so a test can run
+//! the knocker (or code embedding it) and then check what arrived, in
+//! which order and with which payload.
+
+use crate::protocol::Protocol;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// How long a TCP knock may keep its connection open before what it sent
+/// so far is recorded.
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// One knock the server received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObservedKnock {
+    pub protocol: Protocol,
+    /// The server port that was knocked.
+    pub port: u16,
+    pub source: SocketAddr,
+    /// The datagram, or everything written on the connection before it
+    /// closed.
+    pub payload: Vec<u8>,
+    /// When it arrived (for TCP: when the connection was accepted),
+    /// counted from when the server was bound.
+    pub at: Duration,
+    /// The datagram was one of the first ones the server was told to drop.
+    pub dropped: bool,
+}
+
+/// Configures a [`MockKnockServer`] before it binds its ports.
+#[derive(Debug, Clone)]
+pub struct MockKnockServerBuilder {
+    tcp_ports: usize,
+    udp_ports: usize,
+    drop_first: usize,
+    reply: Option<Vec<u8>>,
+    refuse_connections: bool,
+}
+
+impl Default for MockKnockServerBuilder {
+    fn default() -> Self {
+        Self {
+            tcp_ports: 3,
+            udp_ports: 3,
+            drop_first: 0,
+            reply: None,
+            refuse_connections: false,
+        }
+    }
+}
+
+impl MockKnockServerBuilder {
+    /// Number of TCP ports to listen on (3 by default).
+    pub fn tcp_ports(mut self, count: usize) -> Self {
+        self.tcp_ports = count;
+        self
+    }
+
+    /// Number of UDP ports to listen on (3 by default).
+    pub fn udp_ports(mut self, count: usize) -> Self {
+        self.udp_ports = count;
+        self
+    }
+
+    /// Ignore the first `count` datagrams, over all UDP ports, as if they
+    /// were lost on the way. They are still recorded, marked `dropped`.
+    pub fn drop_first(mut self, count: usize) -> Self {
+        self.drop_first = count;
+        self
+    }
+
+    /// Answer every datagram that is not dropped, and every accepted
+    /// connection, with `reply`.
+    pub fn reply(mut self, reply: impl Into<Vec<u8>>) -> Self {
+        self.reply = Some(reply.into());
+        self
+    }
+
+    /// Keep the TCP ports bound but not listening, so connects to them are
+    /// refused. Refused knocks are not recorded.
+    pub fn refuse_connections(mut self, refuse: bool) -> Self {
+        self.refuse_connections = refuse;
+        self
+    }
+
+    /// Bind the ports and start recording.
+    pub async fn bind(self) -> io::Result<MockKnockServer> {
+        let started = Instant::now();
+        let shared = Arc::new(Shared {
+            started,
+            received: Mutex::new(Vec::new()),
+            to_drop: Mutex::new(self.drop_first),
+            reply: self.reply,
+        });
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let mut server = MockKnockServer {
+            tcp_ports: Vec::new(),
+            udp_ports: Vec::new(),
+            shared,
+            tasks: Vec::new(),
+            _refusing: Vec::new(),
+        };
+        for _ in 0..self.tcp_ports {
+            if self.refuse_connections {
+                let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+                socket.bind(&localhost.into())?;
+                let port = socket.local_addr()?.as_socket().map_or(0, |a| a.port());
+                server.tcp_ports.push(port);
+                server._refusing.push(socket);
+                continue;
+            }
+            let listener = TcpListener::bind(localhost).await?;
+            server.tcp_ports.push(listener.local_addr()?.port());
+            let shared = server.shared.clone();
+            server.tasks.push(tokio::spawn(accept(listener, shared)));
+        }
+        for _ in 0..self.udp_ports {
+            let socket = UdpSocket::bind(localhost).await?;
+            server.udp_ports.push(socket.local_addr()?.port());
+            let shared = server.shared.clone();
+            server.tasks.push(tokio::spawn(receive(socket, shared)));
+        }
+        Ok(server)
+    }
+}
+
+/// A knock target running inside the test; see the [module docs](self).
+/// The ports close when it is dropped.
+pub struct MockKnockServer {
+    tcp_ports: Vec<u16>,
+    udp_ports: Vec<u16>,
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+    /// Bound, never listening TCP sockets for `refuse_connections`.
+    _refusing: Vec<Socket>,
+}
+
+struct Shared {
+    started: Instant,
+    received: Mutex<Vec<ObservedKnock>>,
+    to_drop: Mutex<usize>,
+    reply: Option<Vec<u8>>,
+}
+
+impl MockKnockServer {
+    pub fn builder() -> MockKnockServerBuilder {
+        MockKnockServerBuilder::default()
+    }
+
+    /// Listen on three TCP and three UDP ports that accept everything.
+    pub async fn bind() -> io::Result<Self> {
+        Self::builder().bind().await
+    }
+
+    /// The address to knock: `127.0.0.1`.
+    pub fn ip(&self) -> IpAddr {
+        Ipv4Addr::LOCALHOST.into()
+    }
+
+    pub fn tcp_ports(&self) -> &[u16] {
+        &self.tcp_ports
+    }
+
+    pub fn udp_ports(&self) -> &[u16] {
+        &self.udp_ports
+    }
+
+    /// Everything received so far, in order of arrival.
+    pub fn received(&self) -> Vec<ObservedKnock> {
+        let mut received = self.shared.received.lock().unwrap().clone();
+        received.sort_by_key(|k| k.at);
+        received
+    }
+
+    /// Wait until `count` knocks were received, or `timeout` passed;
+    /// returns what was received either way. TCP knocks are recorded once
+    /// their connection closes, a little after the knocker is done.
+    pub async fn wait_for(&self, count: usize, timeout: Duration) -> Vec<ObservedKnock> {
+        let deadline = Instant::now() + timeout;
+        while self.shared.received.lock().unwrap().len() < count && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        self.received()
+    }
+}
+
+impl Drop for MockKnockServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Shared {
+    fn record(&self, knock: ObservedKnock) {
+        self.received.lock().unwrap().push(knock);
+    }
+}
+
+/// Record each connection with what the client wrote before closing it.
+async fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    let port = listener.local_addr().map_or(0, |a| a.port());
+    while let Ok((stream, source)) = listener.accept().await {
+        let at = shared.started.elapsed();
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let payload = read_knock(stream, shared.reply.as_deref()).await;
+            shared.record(ObservedKnock {
+                protocol: Protocol::Tcp,
+                port,
+                source,
+                payload,
+                at,
+                dropped: false,
+            });
+        });
+    }
+}
+
+/// Send the reply, if any, then read until the client closes (or resets)
+/// the connection or goes quiet.
+async fn read_knock(mut stream: TcpStream, reply: Option<&[u8]>) -> Vec<u8> {
+    if let Some(reply) = reply {
+        let _ = stream.write_all(reply).await;
+    }
+    let mut payload = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        match tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => payload.extend_from_slice(&buf[..n]),
+            _ => return payload,
+        }
+    }
+}
+
+/// Record each datagram, dropping the first ones as configured and
+/// answering the others.
+async fn receive(socket: UdpSocket, shared: Arc<Shared>) {
+    let port = socket.local_addr().map_or(0, |a| a.port());
+    let mut buf = vec![0u8; 65536];
+    while let Ok((n, source)) = socket.recv_from(&mut buf).await {
+        let dropped = {
+            let mut to_drop = shared.to_drop.lock().unwrap();
+            let dropped = *to_drop > 0;
+            *to_drop = to_drop.saturating_sub(1);
+            dropped
+        };
+        shared.record(ObservedKnock {
+            protocol: Protocol::Udp,
+            port,
+            source,
+            payload: buf[..n].to_vec(),
+            at: shared.started.elapsed(),
+            dropped,
+        });
+        if let (false, Some(reply)) = (dropped, &shared.reply) {
+            let _ = socket.send_to(reply, source).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run, AppError, KnockConfig};
+
+    #[tokio::test]
+    async fn sequence_arrives_in_order() {
+        let server = MockKnockServer::builder()
+            .udp_ports(0)
+            .bind()
+            .await
+            .unwrap();
+        let ports = server.tcp_ports().to_vec();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([ports[2], ports[0], ports[1]])
+            .tcp_payload(b"open sesame".to_vec())
+            .build()
+            .unwrap();
+        run(config).await.unwrap();
+
+        let received = server.wait_for(3, Duration::from_secs(2)).await;
+        let order: Vec<u16> = received.iter().map(|k| k.port).collect();
+        assert_eq!(order, [ports[2], ports[0], ports[1]]);
+        assert!(received
+            .iter()
+            .all(|k| k.payload == b"open sesame" && k.source.ip() == server.ip()));
+    }
+
+    #[tokio::test]
+    async fn dropped_datagram_goes_unanswered() {
+        let server = MockKnockServer::builder()
+            .tcp_ports(0)
+            .udp_ports(1)
+            .drop_first(1)
+            .reply(b"welcome".to_vec())
+            .bind()
+            .await
+            .unwrap();
+        let port = server.udp_ports()[0];
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([port, port])
+            .protocol(crate::Protocol::Udp)
+            .payload(b"knock".to_vec())
+            .expect_reply(true)
+            .recv_timeout(100)
+            .build()
+            .unwrap();
+        let err = run(config).await.unwrap_err();
+        let AppError::Partial { failed, succeeded } = err else {
+            panic!("{err}");
+        };
+        assert_eq!((failed.len(), succeeded), (1, 1));
+
+        let received = server.received();
+        let dropped: Vec<bool> = received.iter().map(|k| k.dropped).collect();
+        assert_eq!(dropped, [true, false]);
+        assert!(received.iter().all(|k| k.payload == b"knock"));
+    }
+
+    #[tokio::test]
+    async fn refused_connections_fail_strict_knocks() {
+        let server = MockKnockServer::builder()
+            .tcp_ports(1)
+            .udp_ports(0)
+            .refuse_connections(true)
+            .bind()
+            .await
+            .unwrap();
+        let config = KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([server.tcp_ports()[0]])
+            .refused_is_failure(true)
+            .backoff(0)
+            .build()
+            .unwrap();
+        let err = run(config).await.unwrap_err();
+        assert!(
+            matches!(err, AppError::Partial { succeeded: 0, .. }),
+            "{err}"
+        );
+        assert!(server.received().is_empty());
+    }
+}
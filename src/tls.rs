@@ -0,0 +1,249 @@
+use crate::rt::Instant;
+use crate::{
+    config::KnockOpts,
+    events::{EventSink, KnockTarget},
+    outcome::{AttemptLog, KnockOutcome},
+    plan::StepKind,
+    protocol::Protocol,
+    retry::{retry_with_backoff, sync_on_timeout, RetryDecision},
+};
+use rand::{rngs::ThreadRng, RngCore};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::io::AsyncWriteExt;
+
+/// Perform a TLS knock: connect to `addr` and send a ClientHello carrying
+/// `sni`.
+/// The handshake is never completed; once the hello is written the knock
+/// counts as delivered, whatever the server does next.
+pub(crate) async fn knock_tls(
+    host: &str,
+    addr: SocketAddr,
+    sni: Option<&str>,
+    opts: &KnockOpts,
+    events: &EventSink,
+) -> KnockOutcome {
+    let port = addr.port();
+    let label = sni.unwrap_or("-");
+    let started = Instant::now();
+    let log = AttemptLog::new(
+        events,
+        KnockTarget {
+            step: Some(StepKind::Tls {
+                sni: sni.map(str::to_string),
+            }),
+            ..KnockTarget::new(host, port, Protocol::Tcp)
+        },
+    );
+    let Ok(retry) = retry_with_backoff(
+        opts.attempts,
+        opts.timeout,
+        &opts.backoff,
+        opts.deadline,
+        opts.rate.as_deref(),
+        opts.budget.as_deref(),
+        |attempt| {
+            let log = &log;
+            async move {
+                let start = Instant::now();
+                let hello = client_hello(sni);
+                let sent = async {
+                    let mut stream = crate::tcp::dial(addr, opts.tcp.fwmark).await?;
+                    stream.write_all(&hello).await
+                };
+                match sent.await {
+                    Ok(()) => {
+                        let elapsed = start.elapsed();
+                        log.succeeded(attempt, elapsed, format!("ClientHello (sni {label}) sent"));
+                        RetryDecision::<_, Infallible>::Done(elapsed) // stop retrying
+                    }
+                    Err(e) => {
+                        log.push(attempt, e.to_string());
+                        RetryDecision::<_, Infallible>::Retry // retry
+                    }
+                }
+            }
+        },
+        sync_on_timeout(|attempt, _| {
+            log.timed_out(attempt, "timed out");
+        }),
+    )
+    .await;
+
+    let latency = retry.value;
+    KnockOutcome {
+        port,
+        protocol: Protocol::Tcp,
+        addr: Some(addr),
+        source_port: None,
+        fwmark: None,
+        ttl: None,
+        payload_len: None,
+        attempts_allowed: None,
+        attempts: retry.attempts,
+        succeeded: retry.succeeded(),
+        acknowledged: latency.is_some(),
+        latency,
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: log.into_errors(),
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Check that `name` is usable as an SNI host name (LDH labels, no IPs).
+pub fn validate_sni(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 253 {
+        return Err(format!("SNI '{name}' must be 1-253 characters"));
+    }
+    if name.parse::<std::net::IpAddr>().is_ok() {
+        return Err(format!(
+            "SNI '{name}' must be a host name, not an IP address"
+        ));
+    }
+    for label in name.trim_end_matches('.').split('.') {
+        let ok = !label.is_empty()
+            && label.len() <= 63
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !ok {
+            return Err(format!("SNI '{name}' has an invalid label '{label}'"));
+        }
+    }
+    Ok(())
+}
+
+/// The SNI to use for `host` when none is configured: the host itself,
+/// unless it is an IP literal (which RFC 6066 does not allow as SNI).
+pub fn default_sni(host: &str) -> Option<String> {
+    let name = crate::scope::ascii_host(host).ok()?;
+    validate_sni(&name).ok().map(|_| name.into_owned())
+}
+
+/// Append a u16-length-prefixed block built by `body`.
+fn with_len16(out: &mut Vec<u8>, body: impl FnOnce(&mut Vec<u8>)) {
+    let at = out.len();
+    out.extend_from_slice(&[0, 0]);
+    body(out);
+    let len = (out.len() - at - 2) as u16;
+    out[at..at + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Build a TLS record holding a ClientHello that offers TLS 1.3 and 1.2
+/// and, when given, a server_name extension for `sni`.
+pub fn client_hello(sni: Option<&str>) -> Vec<u8> {
+    let mut rng = ThreadRng::default();
+    let mut random = [0u8; 32];
+    let mut session_id = [0u8; 32];
+    let mut key_share = [0u8; 32];
+    rng.fill_bytes(&mut random);
+    rng.fill_bytes(&mut session_id);
+    rng.fill_bytes(&mut key_share);
+
+    let mut body = Vec::with_capacity(512);
+    body.extend_from_slice(&[0x03, 0x03]); // legacy_version TLS 1.2
+    body.extend_from_slice(&random);
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(&session_id);
+    with_len16(&mut body, |b| {
+        for suite in [0x1301u16, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030] {
+            b.extend_from_slice(&suite.to_be_bytes());
+        }
+    });
+    body.extend_from_slice(&[0x01, 0x00]); // null compression only
+    with_len16(&mut body, |ext| {
+        if let Some(name) = sni {
+            ext.extend_from_slice(&0x0000u16.to_be_bytes()); // server_name
+            with_len16(ext, |e| {
+                with_len16(e, |list| {
+                    list.push(0); // host_name
+                    with_len16(list, |n| n.extend_from_slice(name.as_bytes()));
+                })
+            });
+        }
+        ext.extend_from_slice(&0x000au16.to_be_bytes()); // supported_groups
+        with_len16(ext, |e| {
+            with_len16(e, |g| g.extend_from_slice(&[0x00, 0x1d, 0x00, 0x17]))
+        });
+        ext.extend_from_slice(&0x000du16.to_be_bytes()); // signature_algorithms
+        with_len16(ext, |e| {
+            with_len16(e, |a| {
+                a.extend_from_slice(&[0x04, 0x03, 0x08, 0x04, 0x04, 0x01])
+            })
+        });
+        ext.extend_from_slice(&0x002bu16.to_be_bytes()); // supported_versions
+        with_len16(ext, |e| {
+            e.extend_from_slice(&[0x04, 0x03, 0x04, 0x03, 0x03])
+        });
+        ext.extend_from_slice(&0x0033u16.to_be_bytes()); // key_share (x25519)
+        with_len16(ext, |e| {
+            with_len16(e, |k| {
+                k.extend_from_slice(&[0x00, 0x1d]);
+                with_len16(k, |v| v.extend_from_slice(&key_share));
+            })
+        });
+    });
+
+    let mut record = Vec::with_capacity(body.len() + 9);
+    record.extend_from_slice(&[0x16, 0x03, 0x01]); // handshake record, TLS 1.0 compat
+    record.extend_from_slice(&((body.len() + 4) as u16).to_be_bytes());
+    record.push(0x01); // ClientHello
+    record.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    record.extend_from_slice(&body);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(b: &[u8], i: usize) -> usize {
+        usize::from(u16::from_be_bytes([b[i], b[i + 1]]))
+    }
+
+    /// Walk the generated hello and return the SNI host name, if any.
+    fn parse_sni(rec: &[u8]) -> Option<String> {
+        assert_eq!(rec[0], 0x16, "handshake record");
+        assert_eq!(u16_at(rec, 3), rec.len() - 5, "record length");
+        let hs = &rec[5..];
+        assert_eq!(hs[0], 0x01, "ClientHello");
+        let mut i = 4 + 2 + 32; // header, version, random
+        i += 1 + usize::from(hs[i]); // session id
+        i += 2 + u16_at(hs, i); // cipher suites
+        i += 1 + usize::from(hs[i]); // compression
+        let end = i + 2 + u16_at(hs, i);
+        assert_eq!(end, hs.len(), "extensions fill the hello");
+        i += 2;
+        while i < end {
+            let (kind, len) = (u16_at(hs, i), u16_at(hs, i + 2));
+            let data = &hs[i + 4..i + 4 + len];
+            if kind == 0 {
+                assert_eq!(data[2], 0, "host_name entry");
+                let n = u16_at(data, 3);
+                return Some(String::from_utf8(data[5..5 + n].to_vec()).unwrap());
+            }
+            i += 4 + len;
+        }
+        None
+    }
+
+    #[test]
+    fn hello_carries_sni() {
+        let hello = client_hello(Some("knock.example.com"));
+        assert_eq!(parse_sni(&hello).as_deref(), Some("knock.example.com"));
+        assert_eq!(parse_sni(&client_hello(None)), None);
+    }
+
+    #[test]
+    fn sni_validation() {
+        assert!(validate_sni("example.com").is_ok());
+        assert!(validate_sni("203.0.113.7").is_err());
+        assert!(validate_sni("bad_name.com").is_err());
+        assert_eq!(default_sni("::1"), None);
+        assert_eq!(default_sni("host.example").as_deref(), Some("host.example"));
+        assert_eq!(
+            default_sni("bücher.example.").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+    }
+}
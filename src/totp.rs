@@ -0,0 +1,136 @@
+use crate::protocol::TotpAlgorithm;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha256;
+
+/// RFC 4226 HOTP value before the decimal reduction: the 31-bit integer
+/// dynamically truncated from HMAC(secret, counter).
+pub fn hotp(secret: &[u8], counter: u64, algorithm: TotpAlgorithm) -> u32 {
+    let digest = match algorithm {
+        TotpAlgorithm::Sha1 => hmac_digest::<Hmac<Sha1>>(secret, counter),
+        TotpAlgorithm::Sha256 => hmac_digest::<Hmac<Sha256>>(secret, counter),
+    };
+    let offset = usize::from(digest[digest.len() - 1] & 0x0f);
+    let code = [
+        digest[offset],
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ];
+    u32::from_be_bytes(code) & 0x7fff_ffff
+}
+
+fn hmac_digest<M: Mac + hmac::digest::KeyInit>(secret: &[u8], counter: u64) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// RFC 6238 time step counter for a Unix time.
+pub fn time_step(unix_secs: u64, step_secs: u64) -> u64 {
+    unix_secs / step_secs
+}
+
+/// Ports for one time step: knock `i` of `knocks` uses HOTP counter
+/// `step * knocks + i`, so every knock of every step gets its own counter,
+/// and lands on `base + (hotp mod range)`.
+pub fn derive_ports(
+    secret: &[u8],
+    algorithm: TotpAlgorithm,
+    step: u64,
+    knocks: usize,
+    base: u16,
+    range: u16,
+) -> Vec<u16> {
+    let knocks_u64 = knocks as u64;
+    (0..knocks_u64)
+        .map(|i| {
+            let counter = step.wrapping_mul(knocks_u64).wrapping_add(i);
+            base + (hotp(secret, counter, algorithm) % u32::from(range)) as u16
+        })
+        .collect()
+}
+
+/// Local ports to send the knocks of [`derive_ports`] from: knock `i`
+/// uses HOTP counter `step * knocks + i` with the top bit set, so no
+/// counter is shared with a destination port.
+pub fn derive_source_ports(
+    secret: &[u8],
+    algorithm: TotpAlgorithm,
+    step: u64,
+    knocks: usize,
+    base: u16,
+    range: u16,
+) -> Vec<u16> {
+    let knocks_u64 = knocks as u64;
+    (0..knocks_u64)
+        .map(|i| {
+            let counter = step.wrapping_mul(knocks_u64).wrapping_add(i) | 1 << 63;
+            base + (hotp(secret, counter, algorithm) % u32::from(range)) as u16
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 appendix B, 8 digits, 30 s steps.
+    const VECTORS: [(u64, u32, u32); 6] = [
+        (59, 94287082, 46119246),
+        (1111111109, 7081804, 68084774),
+        (1111111111, 14050471, 67062674),
+        (1234567890, 89005924, 91819424),
+        (2000000000, 69279037, 90698825),
+        (20000000000, 65353130, 77737706),
+    ];
+
+    #[test]
+    fn rfc6238_sha1() {
+        for (time, sha1, _) in VECTORS {
+            let code = hotp(
+                b"12345678901234567890",
+                time_step(time, 30),
+                TotpAlgorithm::Sha1,
+            );
+            assert_eq!(code % 100_000_000, sha1, "T={time}");
+        }
+    }
+
+    #[test]
+    fn rfc6238_sha256() {
+        let secret = b"12345678901234567890123456789012";
+        for (time, _, sha256) in VECTORS {
+            let code = hotp(secret, time_step(time, 30), TotpAlgorithm::Sha256);
+            assert_eq!(code % 100_000_000, sha256, "T={time}");
+        }
+    }
+
+    #[test]
+    fn ports_stay_in_range_and_follow_the_clock() {
+        let secret = b"knock-secret";
+        let ports = derive_ports(secret, TotpAlgorithm::Sha1, 1000, 4, 20000, 100);
+        assert_eq!(ports.len(), 4);
+        assert!(ports.iter().all(|p| (20000..20100).contains(p)));
+        // Same step, same ports; the next step moves on
+        assert_eq!(
+            ports,
+            derive_ports(secret, TotpAlgorithm::Sha1, 1000, 4, 20000, 100)
+        );
+        assert_ne!(
+            ports,
+            derive_ports(secret, TotpAlgorithm::Sha1, 1001, 4, 20000, 100)
+        );
+    }
+
+    #[test]
+    fn source_ports_use_counters_of_their_own() {
+        let secret = b"knock-secret";
+        let sources = derive_source_ports(secret, TotpAlgorithm::Sha1, 1000, 4, 20000, 100);
+        assert!(sources.iter().all(|p| (20000..20100).contains(p)));
+        assert_ne!(
+            sources,
+            derive_ports(secret, TotpAlgorithm::Sha1, 1000, 4, 20000, 100)
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{
+    config::KnockOpts, errors::AppError, outcome::KnockOutcome, plan::KnockStep, tcp, udp,
+};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A way of sending one knock, so new knock types can be added without
+/// touching the run. Set one per protocol or per port on the
+/// [`KnockConfig`](crate::KnockConfig); the run emits the usual start and
+/// failure events around it.
+pub trait KnockTransport {
+    /// Knock `target`, whose port is the step's port. `deadline` is how
+    /// long one attempt may take (the run's `timeout`); retries, if any,
+    /// are up to the transport.
+    fn knock<'a>(
+        &'a self,
+        target: SocketAddr,
+        step: &'a KnockStep,
+        deadline: Duration,
+    ) -> BoxFuture<'a, Result<KnockOutcome, AppError>>;
+}
+
+/// A TCP connect knock, as sent by [`knock_tcp`](crate::knock_tcp).
+#[derive(Clone, Default)]
+pub struct TcpTransport {
+    pub opts: KnockOpts,
+}
+
+impl KnockTransport for TcpTransport {
+    fn knock<'a>(
+        &'a self,
+        target: SocketAddr,
+        _step: &'a KnockStep,
+        deadline: Duration,
+    ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+        Box::pin(async move { tcp::knock_addr(target, &with_deadline(&self.opts, deadline)).await })
+    }
+}
+
+/// A UDP datagram knock, as sent by [`knock_udp`](crate::knock_udp), with the same payload
+/// for every step.
+#[derive(Clone, Default)]
+pub struct UdpTransport {
+    pub opts: KnockOpts,
+    pub payload: Option<Bytes>,
+}
+
+impl KnockTransport for UdpTransport {
+    fn knock<'a>(
+        &'a self,
+        target: SocketAddr,
+        _step: &'a KnockStep,
+        deadline: Duration,
+    ) -> BoxFuture<'a, Result<KnockOutcome, AppError>> {
+        Box::pin(async move {
+            let opts = with_deadline(&self.opts, deadline);
+            udp::knock_addr(target, self.payload.as_deref(), &opts).await
+        })
+    }
+}
+
+fn with_deadline(opts: &KnockOpts, deadline: Duration) -> KnockOpts {
+    KnockOpts {
+        timeout: u64::try_from(deadline.as_millis()).unwrap_or(u64::MAX),
+        ..opts.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn tcp_transport_knocks_the_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let step = KnockStep::new(target.port());
+        let transport: Box<dyn KnockTransport> = Box::new(TcpTransport::default());
+        let outcome = transport
+            .knock(target, &step, Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert!(outcome.succeeded);
+        assert_eq!(outcome.port, target.port());
+    }
+}
@@ -0,0 +1,201 @@
+//! `--sequence-from-txt`: the knock sequence published in a DNS TXT
+//! record, so it can be rotated without touching the clients.
+//!
+//! The record holds the sequence as `--sequence` takes it, e.g.
+//! `7000,8000,9000`; a record of several strings is read as their
+//! concatenation. With a key (`--txt-key`) it instead holds, in base64,
+//! the sequence sealed with AES-256-GCM as `--encrypt-key` seals payloads
+//! (nonce, ciphertext, tag), and a record that does not authenticate
+//! under the key is rejected. A name with several TXT records, a record
+//! over [`MAX_RECORD_LEN`] bytes and anything but a valid sequence are
+//! errors; none of them is ever knocked.
+//!
+//! The record is asked of `--dns-server`, or else the first nameserver in
+//! `/etc/resolv.conf`, when the run starts.
+
+use crate::config::TxtSequence;
+use crate::plan::KnockPlan;
+use crate::AppError;
+use std::net::SocketAddr;
+
+/// Most bytes a sequence record may hold, its strings joined.
+pub const MAX_RECORD_LEN: usize = 2048;
+
+/// Fetch the TXT record of `txt.name` and read the sequence it holds.
+pub async fn fetch(txt: &TxtSequence, server: Option<SocketAddr>) -> Result<KnockPlan, AppError> {
+    let name = &txt.name;
+    let server = match server {
+        Some(server) => server,
+        None => crate::dns::system_nameserver("the TXT record")?,
+    };
+    let records = lookup(server, name).await?;
+    let [record] = &records[..] else {
+        return Err(AppError::TxtSequence(format!(
+            "{name} has {} TXT records; publish the sequence in exactly one",
+            records.len()
+        )));
+    };
+    match &txt.key {
+        #[cfg(feature = "crypto")]
+        Some(source) => {
+            let key = crate::crypto::load_key(source)
+                .await
+                .map_err(AppError::Crypto)?;
+            parse(name, &unseal(name, &key, record)?)
+        }
+        #[cfg(not(feature = "crypto"))]
+        Some(_) => Err(AppError::InvalidConfig(
+            "--txt-key requires building with `--features crypto`".into(),
+        )),
+        None => parse(name, record),
+    }
+}
+
+#[cfg(feature = "custom-dns")]
+async fn lookup(server: SocketAddr, name: &str) -> Result<Vec<Vec<u8>>, AppError> {
+    crate::dns::lookup_txt(server, name).await
+}
+
+/// Without the `custom-dns` feature there is no DNS client to ask.
+#[cfg(not(feature = "custom-dns"))]
+async fn lookup(_server: SocketAddr, _name: &str) -> Result<Vec<Vec<u8>>, AppError> {
+    Err(AppError::InvalidConfig(
+        "--sequence-from-txt requires building with `--features custom-dns`".into(),
+    ))
+}
+
+/// The sequence in the plain `record` of `name`.
+pub fn parse(name: &str, record: &[u8]) -> Result<KnockPlan, AppError> {
+    let fail = |why: String| AppError::TxtSequence(format!("the TXT record of {name} {why}"));
+    if record.len() > MAX_RECORD_LEN {
+        return Err(fail(format!(
+            "is {} bytes; a sequence record may be at most {MAX_RECORD_LEN}",
+            record.len()
+        )));
+    }
+    let text = std::str::from_utf8(record)
+        .map_err(|e| fail(format!("is not UTF-8 text: {e}")))?
+        .trim();
+    if text.is_empty() {
+        return Err(fail("is empty".into()));
+    }
+    KnockPlan::parse_untrusted(text).map_err(|e| fail(format!("holds no knock sequence: {e}")))
+}
+
+/// The plaintext of the sealed `record` of `name`.
+#[cfg(feature = "crypto")]
+pub fn unseal(
+    name: &str,
+    key: &[u8; crate::crypto::KEY_LEN],
+    record: &[u8],
+) -> Result<Vec<u8>, AppError> {
+    use crate::crypto::{NONCE_LEN, TAG_LEN};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let fail = |why: String| AppError::TxtSequence(format!("the TXT record of {name} {why}"));
+    if record.len() > MAX_RECORD_LEN {
+        return Err(fail(format!(
+            "is {} bytes; a sequence record may be at most {MAX_RECORD_LEN}",
+            record.len()
+        )));
+    }
+    let sealed = STANDARD
+        .decode(record.trim_ascii())
+        .map_err(|e| fail(format!("is not base64, as a sealed record must be: {e}")))?;
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(fail(format!(
+            "holds {} sealed bytes, fewer than a nonce and tag ({})",
+            sealed.len(),
+            NONCE_LEN + TAG_LEN
+        )));
+    }
+    crate::crypto::open(key, &sealed).ok_or_else(|| {
+        fail(
+            "does not authenticate under --txt-key; it was sealed with another key or altered"
+                .into(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "_seq.example.com";
+
+    #[test]
+    fn plain_records_hold_a_sequence() {
+        let plan = parse(NAME, b" 7000,(8000,8001),9000\n").unwrap();
+        assert_eq!(plan, KnockPlan::parse("7000,(8000,8001),9000").unwrap());
+
+        let error = |record: &[u8]| parse(NAME, record).err().unwrap().to_string();
+        assert_eq!(
+            error(&[b'7'; MAX_RECORD_LEN + 1]),
+            format!(
+                "TXT sequence error: the TXT record of {NAME} is 2049 bytes; a sequence \
+                 record may be at most 2048"
+            )
+        );
+        assert!(error(b"  ").ends_with("is empty"));
+        assert!(error(b"7000,\xff").contains("is not UTF-8 text"));
+        assert!(error(b"v=spf1 -all").contains("holds no knock sequence"));
+        // A record must not make the client read a local file
+        assert!(error(b"7000:plugin:/etc/passwd").contains("is a plugin step"));
+        assert_eq!(parse(NAME, b"").err().unwrap().exit_code(), 2);
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn sealed_records_must_authenticate() {
+        use crate::crypto::PayloadCipher;
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let key = [7; crate::crypto::KEY_LEN];
+        let record = STANDARD.encode(PayloadCipher::new(&key).seal(b"7000,8000"));
+        let plaintext = unseal(NAME, &key, record.as_bytes()).unwrap();
+        assert_eq!(parse(NAME, &plaintext).unwrap().to_string(), "7000,8000");
+
+        let error = |key, record: &[u8]| unseal(NAME, key, record).err().unwrap().to_string();
+        assert!(error(&[8; 32], record.as_bytes()).contains("does not authenticate"));
+        assert!(error(&key, b"7000,8000").contains("is not base64"));
+        let short = STANDARD.encode([0; 20]);
+        assert!(error(&key, short.as_bytes()).contains("holds 20 sealed bytes"));
+    }
+
+    /// A DNS server on localhost answering every query with one TXT
+    /// record of `strings`.
+    #[cfg(feature = "custom-dns")]
+    async fn txt_server(strings: &'static [&'static str]) -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                let mut pkt = buf[..n].to_vec();
+                pkt[2] |= 0x80;
+                pkt[7] = 1;
+                let data: Vec<u8> = strings
+                    .iter()
+                    .flat_map(|s| std::iter::once(s.len() as u8).chain(s.bytes()))
+                    .collect();
+                pkt.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0]);
+                pkt.push(data.len() as u8);
+                pkt.extend_from_slice(&data);
+                let _ = socket.send_to(&pkt, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "custom-dns")]
+    #[tokio::test]
+    async fn the_strings_of_a_record_are_joined() {
+        let server = txt_server(&["7000,80", "00,9000"]).await;
+        let txt = TxtSequence {
+            name: NAME.into(),
+            key: None,
+        };
+        let plan = fetch(&txt, Some(server)).await.unwrap();
+        assert_eq!(plan.to_string(), "7000,8000,9000");
+    }
+}
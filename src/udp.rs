@@ -1,89 +1,1157 @@
-use crate::{retry::retry_with_backoff, AppError};
-use rand::{rngs::ThreadRng, RngCore};
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-
-/// Perform a UDP knock with retries, random source port, and optional reply.
-pub(crate) async fn knock_udp(
-    host: Arc<String>,
-    port: u16,
-    to_ms: u64,
-    retries: usize,
-    backoff: u64,
-    ips: Arc<Vec<SocketAddr>>,
-    payload: Option<Arc<Vec<u8>>>,
-) -> Result<(), AppError> {
-    // Copy first resolved address (SocketAddr is Copy), set port
-    let mut target = match ips.first().copied() {
-        Some(addr) => addr,
-        None => return Err(AppError::NoDns),
-    };
-    target.set_port(port);
-
-    // Pick a random local ephemeral port
-    let mut rng: ThreadRng = ThreadRng::default();
-    let range = (61000 - 32768) as u32;
-    let offset = rng.next_u32() % range;
-    let local_port = 32768 + offset as u16;
-
-    // Bind UDP socket on that port
-    let bind_addr = if target.is_ipv6() {
-        format!("[::]:{local_port}")
-    } else {
-        format!("0.0.0.0:{local_port}")
-    };
-    let socket = match UdpSocket::bind(&bind_addr).await {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("UDP {host}:{port} bind ERR {e}");
-            return Ok(()); // keep same behavior for bind errors
+use crate::rt::{recv_or_error, timeout, Instant, UdpSocket};
+use crate::{
+    config::KnockOpts,
+    errors::is_msgsize,
+    events::{EventSink, KnockTarget},
+    hexdump::Direction,
+    outcome::{AttemptLog, KnockOutcome},
+    pattern::ReplyPattern,
+    pcap::PcapWriter,
+    protocol::Protocol,
+    retry::{retry_with_backoff, sync_on_timeout, RetryDecision},
+    AppError, ErrorClass,
+};
+use rand::{Rng, RngCore};
+use socket2::SockRef;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::ops::RangeInclusive;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Source ports [`SourcePortPolicy::Random`] picks from by default.
+pub const EPHEMERAL_PORTS: RangeInclusive<u16> = 32768..=60999;
+
+/// How a UDP knock picks its local port.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SourcePortPolicy {
+    /// Bind port 0 and let the kernel pick from its own ephemeral range.
+    #[default]
+    Os,
+    /// A port drawn uniformly from the range, drawn again when in use.
+    Random(RangeInclusive<u16>),
+    /// The first free port of the range, counting up from its start, so
+    /// knocks come from a predictable port.
+    Range(RangeInclusive<u16>),
+}
+
+impl SourcePortPolicy {
+    /// Parse `os`, `random`, `random:FIRST-LAST` or `range:FIRST-LAST`
+    /// (a single port pins it).
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "os" => Ok(SourcePortPolicy::Os),
+            None if s == "random" => Ok(SourcePortPolicy::Random(EPHEMERAL_PORTS)),
+            Some(("random", ports)) => parse_port_range(ports).map(SourcePortPolicy::Random),
+            Some(("range", ports)) => parse_port_range(ports).map(SourcePortPolicy::Range),
+            _ => Err(format!(
+                "'{s}' is not os, random, random:FIRST-LAST or range:FIRST-LAST"
+            )),
+        }
+    }
+
+    /// The ports the policy picks from; `None` when the kernel picks.
+    pub fn ports(&self) -> Option<&RangeInclusive<u16>> {
+        match self {
+            SourcePortPolicy::Os => None,
+            SourcePortPolicy::Random(ports) | SourcePortPolicy::Range(ports) => Some(ports),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(SourcePortPolicy, SourcePortPolicy::parse);
+
+impl fmt::Display for SourcePortPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourcePortPolicy::Os => f.write_str("os"),
+            SourcePortPolicy::Random(ports) => {
+                write!(f, "random:{}-{}", ports.start(), ports.end())
+            }
+            SourcePortPolicy::Range(ports) => write!(f, "range:{}-{}", ports.start(), ports.end()),
+        }
+    }
+}
+
+/// The interface multicast knocks leave from (`--multicast-if`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MulticastInterface {
+    /// The IPv4 interface with this address (IP_MULTICAST_IF).
+    V4(Ipv4Addr),
+    /// An IPv6 address and the index of the interface it is on, which is
+    /// what IPV6_MULTICAST_IF takes.
+    V6 { addr: Ipv6Addr, index: u32 },
+}
+
+impl MulticastInterface {
+    /// Parse an IPv4 address, or an IPv6 one with its `%interface` zone
+    /// (`fe80::1%eth0`, `2001:db8::5%2`).
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Ok(ip) = s.parse::<Ipv4Addr>() {
+            return Ok(MulticastInterface::V4(ip));
         }
+        match crate::scope::parse_scoped(s)? {
+            Some(scoped) => Ok(MulticastInterface::V6 {
+                addr: *scoped.ip(),
+                index: scoped.scope_id(),
+            }),
+            None if s.parse::<Ipv6Addr>().is_ok() => Err(format!(
+                "IPv6 multicast picks an interface by index; add its zone, e.g. '{s}%eth0'"
+            )),
+            None => Err(format!("'{s}' is not an IP address")),
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, MulticastInterface::V6 { .. })
+    }
+}
+
+#[cfg(feature = "serde")]
+crate::serde_forms::as_text!(MulticastInterface, MulticastInterface::parse);
+
+impl fmt::Display for MulticastInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MulticastInterface::V4(ip) => write!(f, "{ip}"),
+            MulticastInterface::V6 { addr, index } => write!(f, "{addr}%{index}"),
+        }
+    }
+}
+
+/// Largest UDP payload at all: 65535 bytes of IPv4 datagram less 20 of IP
+/// and 8 of UDP header.
+pub const MAX_PAYLOAD_LEN: usize = 65507;
+
+/// Largest `--pad-to` size: a datagram this long fits the IPv6 minimum
+/// MTU of 1280 bytes after IP and UDP headers, so it is never fragmented.
+pub const MAX_PADDED_LEN: usize = 1232;
+
+/// Largest UDP payload of an unfragmented IPv4 datagram on a 1500-byte
+/// Ethernet MTU, after 20 bytes of IP and 8 of UDP header.
+pub const MAX_UNFRAGMENTED_V4: usize = 1472;
+
+/// Largest UDP payload of an unfragmented IPv6 datagram on the minimum
+/// IPv6 MTU.
+pub const MAX_UNFRAGMENTED_V6: usize = MAX_PADDED_LEN;
+
+/// Per-run UDP knock behavior beyond timing and retries.
+#[derive(Clone, Default)]
+pub struct UdpOpts {
+    /// Wait for a reply datagram instead of treating a successful send as
+    /// a delivered knock.
+    pub expect_reply: bool,
+    /// How long to wait for a reply after the send, in milliseconds.
+    pub recv_timeout: u64,
+    /// Only replies matching this pattern count; others are skipped.
+    pub pattern: Option<ReplyPattern>,
+    /// Count an ICMP port-unreachable as a failed knock instead of a
+    /// delivered one.
+    pub strict: bool,
+    /// Only a reply datagram answers the knock: a port-unreachable while
+    /// waiting for it is ignored instead of counting as delivered.
+    pub reply_only: bool,
+    /// How to pick the local port to send from.
+    pub source_port: SourcePortPolicy,
+    /// Send from exactly this local port instead, as a step asks for; one
+    /// in use is an [`AppError::Bind`], not a reason to pick another.
+    pub bind_port: Option<u16>,
+    /// Port the server replies from, when not the knocked one.
+    pub reply_port: Option<u16>,
+    /// Set the don't-fragment bit, so a datagram too big for the path
+    /// fails to send instead of being dropped on the way (Linux only).
+    pub dont_fragment: bool,
+    /// Allow a broadcast target (SO_BROADCAST); no reply is waited for,
+    /// whatever `expect_reply` says.
+    pub broadcast: bool,
+    /// Interface a knock to a multicast group is sent from; the routing
+    /// table picks when unset.
+    pub multicast_if: Option<MulticastInterface>,
+    /// Hop limit of a knock to a multicast group; the OS default of 1
+    /// when unset keeps it on the local segment.
+    pub multicast_ttl: Option<u8>,
+    /// Firewall mark (SO_MARK) of the knock socket (Linux only).
+    pub fwmark: Option<u32>,
+    /// IP TTL (IPv6 hop limit) of the knock datagram, as a step asks for;
+    /// a knock to a multicast group goes by `multicast_ttl` instead.
+    pub ttl: Option<u8>,
+}
+
+/// Perform a single UDP knock on `target` from a source port picked by
+/// `opts.udp.source_port`, with retries, and report how it went.
+///
+/// Knock daemons normally stay silent, so a successful send is a delivered
+/// knock; with `opts.udp.expect_reply` the knock only succeeds once a reply
+/// arrives within `opts.udp.recv_timeout`. Retries cover the send alone, so
+/// a missing reply never causes the datagram to be sent twice. An ICMP
+/// port-unreachable, reported as a refused send or recv on the connected
+/// socket, proves the datagram reached the host and also counts as
+/// delivered unless `opts.udp.strict` is set. Only a reply from the target
+/// address counts, and from the knocked port unless `opts.udp.reply_port`
+/// names another; datagrams from anywhere else are skipped and counted in
+/// [`KnockOutcome::stray_replies`]. A knock sent to a multicast group takes
+/// a reply from any member, from `opts.udp.reply_port` when given. A knock
+/// that does not get through is an [`AppError::Timeout`] or
+/// [`AppError::KnockFailed`], and a source port that cannot be bound an
+/// [`AppError::Bind`], after trying a couple of other random ports when it
+/// was in use.
+pub async fn knock_udp(
+    target: SocketAddr,
+    payload: Option<&[u8]>,
+    opts: &KnockOpts,
+) -> Result<KnockOutcome, AppError> {
+    knock_addr(target, payload, opts).await?.into_result()
+}
+
+/// [`knock_udp`] returning the outcome of a failed knock too.
+pub(crate) async fn knock_addr(
+    target: SocketAddr,
+    payload: Option<&[u8]>,
+    opts: &KnockOpts,
+) -> Result<KnockOutcome, AppError> {
+    knock(
+        &target.ip().to_string(),
+        target,
+        payload,
+        opts,
+        None,
+        &EventSink::default(),
+    )
+    .await
+}
+
+/// [`knock_udp`] with per-attempt events and capture, as used by a run;
+/// `host` names the target in the events.
+pub(crate) async fn knock(
+    host: &str,
+    target: SocketAddr,
+    payload: Option<&[u8]>,
+    opts: &KnockOpts,
+    pcap: Option<&PcapWriter>,
+    events: &EventSink,
+) -> Result<KnockOutcome, AppError> {
+    let port = target.port();
+    let udp = &opts.udp;
+    let started = Instant::now();
+    let mut outcome = KnockOutcome {
+        port,
+        protocol: Protocol::Udp,
+        addr: Some(target),
+        source_port: None,
+        fwmark: None,
+        ttl: None,
+        payload_len: None,
+        attempts_allowed: None,
+        attempts: 0,
+        succeeded: false,
+        acknowledged: false,
+        latency: None,
+        reply: None,
+        reply_file: None,
+        stray_replies: 0,
+        errors: Vec::new(),
+        elapsed: Duration::ZERO,
     };
+    let multicast = target.ip().is_multicast();
+    let log = AttemptLog::new(events, KnockTarget::new(host, port, Protocol::Udp))
+        .with_ttl(udp.ttl.filter(|_| !multicast));
+    if let Some(interface) = udp.multicast_if.filter(|_| multicast) {
+        if interface.is_ipv6() != target.is_ipv6() {
+            return Err(AppError::InvalidConfig(format!(
+                "multicast interface {interface} is not of the IP version of group {}",
+                target.ip()
+            )));
+        }
+    }
 
-    // Convert Option<Arc<Vec<u8>>> into a byte slice
-    let data: &[u8] = match &payload {
-        Some(buf) => buf.as_slice(),
-        None => &[],
+    // Bind the UDP socket on the step's own local port or the one the
+    // policy picks
+    let socket = match (udp.bind_port, udp.source_port.ports()) {
+        (Some(port), _) => {
+            let bind = bind_addr(target, port);
+            UdpSocket::bind(bind)
+                .await
+                .map_err(|source| AppError::Bind { addr: bind, source })?
+        }
+        (None, Some(ports)) => {
+            let candidates = candidate_ports(&udp.source_port);
+            bind_source_port(target, ports, &candidates).await?
+        }
+        (None, None) => {
+            let bind = bind_addr(target, 0);
+            UdpSocket::bind(bind)
+                .await
+                .map_err(|source| AppError::Bind { addr: bind, source })?
+        }
     };
-    let buf = vec![0u8; 1500];
+    outcome.source_port = socket.local_addr().ok().map(|local| local.port());
+    if let Some(mark) = udp.fwmark {
+        crate::fwmark::set(&socket, mark)?;
+        outcome.fwmark = Some(mark);
+    }
+    if let Some(ttl) = udp.ttl.filter(|_| !multicast) {
+        set_ttl(SockRef::from(&socket), target.is_ipv6(), ttl).map_err(AppError::local_failure)?;
+        outcome.ttl = Some(ttl);
+    }
+    #[cfg(target_os = "linux")]
+    if udp.dont_fragment {
+        set_dont_fragment(&socket, target.is_ipv6()).map_err(AppError::local_failure)?;
+    }
+    if udp.broadcast {
+        socket
+            .set_broadcast(true)
+            .map_err(AppError::local_failure)?;
+    }
+    if multicast {
+        set_multicast(&socket, udp).map_err(AppError::local_failure)?;
+    }
+    // Replies from every host on the segment would not tell one knock
+    // from another
+    let expect_reply = udp.expect_reply && !udp.broadcast;
+    // Connect to the chosen address so the kernel drops datagrams from
+    // other sources and reports ICMP errors on send/recv. That would drop a
+    // reply from another port too, so then the socket stays unconnected,
+    // and so does one sending to a group, whose members reply from their
+    // own unicast addresses
+    let reply_from = SocketAddr::new(target.ip(), udp.reply_port.unwrap_or(port));
+    let connected = udp.reply_port.is_none() && !multicast;
+    if connected {
+        if let Err(e) = socket.connect(target).await {
+            let class = ErrorClass::of(&e);
+            if class == ErrorClass::Fatal {
+                return Err(local_failure(e, target, udp.broadcast));
+            }
+            log.push_classified(0, format!("connect: {e}"), class);
+            outcome.errors = log.into_errors();
+            return Ok(outcome);
+        }
+    }
+
+    let data = payload.unwrap_or_default();
+    outcome.payload_len = Some(data.len());
+    let local = socket.local_addr()?;
 
-    retry_with_backoff(
-        retries,
-        to_ms,
-        backoff,
+    // Only the send is retried: once a datagram is out, sending it again
+    // would hand the daemon a duplicate knock
+    let retry = retry_with_backoff(
+        opts.attempts,
+        opts.timeout,
+        &opts.backoff,
+        opts.deadline,
+        opts.rate.as_deref(),
+        opts.budget.as_deref(),
         |attempt| {
             let socket = &socket;
-            let mut buf = buf.clone();
-            let host = host.clone();
+            let log = &log;
             async move {
+                let start = Instant::now();
+                let sent_at = SystemTime::now();
                 // Send datagram
-                match socket.send_to(data, target).await {
+                let sent = match connected {
+                    true => socket.send(data).await,
+                    false => socket.send_to(data, target).await,
+                };
+                match sent {
                     Ok(_) => {
-                        // Try to catch any ICMP or UDP reply
-                        match socket.recv_from(&mut buf).await {
-                            Ok((nrecv, src)) => {
-                                println!("UDP {host}:{port} received {nrecv} bytes from {src}");
-                                Ok::<bool, AppError>(true) // stop retrying
-                            }
-                            Err(e) => {
-                                eprintln!("UDP {host}:{port} recv ERR {e} (attempt {attempt})");
-                                Ok::<bool, AppError>(false) // retry
-                            }
+                        if let Some(pcap) = pcap {
+                            pcap.record_udp(sent_at, local, target, data);
                         }
+                        log.hexdump(opts.hexdump, Direction::Sent, attempt, data);
+                        let elapsed = start.elapsed();
+                        let detail = format!("SENT {} bytes", data.len());
+                        // With a reply expected the knock is not done yet
+                        if expect_reply {
+                            log.notice(format!(
+                                "{detail} in {}ms (attempt {attempt})",
+                                elapsed.as_millis()
+                            ));
+                        } else {
+                            log.succeeded(attempt, elapsed, detail);
+                        }
+                        RetryDecision::Done(Sent {
+                            start,
+                            latency: elapsed,
+                            refused: false,
+                        })
                     }
-                    Err(e) => {
-                        eprintln!("UDP {host}:{port} send ERR {e} (attempt {attempt})");
-                        Ok::<bool, AppError>(false) // retry
-                    }
+                    Err(e) => match ErrorClass::of(&e) {
+                        // Refusal left over from an earlier attempt's datagram
+                        ErrorClass::Delivered if !udp.strict => {
+                            let elapsed = start.elapsed();
+                            log.succeeded(attempt, elapsed, "REFUSED (knock delivered)");
+                            RetryDecision::Done(Sent {
+                                start,
+                                latency: elapsed,
+                                refused: true,
+                            })
+                        }
+                        // Too big for the path, or blocked locally
+                        ErrorClass::Fatal if is_msgsize(&e) => {
+                            RetryDecision::Fatal(AppError::Oversized {
+                                size: data.len(),
+                                mtu: path_mtu(socket, target.is_ipv6()),
+                            })
+                        }
+                        ErrorClass::Fatal => {
+                            RetryDecision::Fatal(local_failure(e, target, udp.broadcast))
+                        }
+                        // Network/host unreachable or other I/O error: retry
+                        class => {
+                            log.push_classified(attempt, format!("send: {e}"), class);
+                            RetryDecision::Retry // retry
+                        }
+                    },
                 }
             }
         },
-        |attempt| {
-            eprintln!("UDP {host}:{port} no response (recv timeout) (attempt {attempt})");
-        },
+        sync_on_timeout(|attempt, elapsed| {
+            log.timed_out(
+                attempt,
+                format!("send timed out after {}ms", elapsed.as_millis()),
+            );
+        }),
     )
     .await?;
+    if retry.deadline_limited {
+        log.notice(format!(
+            "knock deadline reached after {} attempt(s)",
+            retry.attempts
+        ));
+    }
+    if retry.rate_closed {
+        log.notice(format!(
+            "cancelled waiting for --rate after {} attempt(s)",
+            retry.attempts
+        ));
+    }
+    if retry.budget_spent {
+        log.notice(format!(
+            "retry budget spent after {} attempt(s)",
+            retry.attempts
+        ));
+    }
+
+    outcome.attempts = retry.attempts;
+    outcome.latency = retry.value.as_ref().map(|sent| sent.latency);
+    let mut refused = retry.value.as_ref().is_some_and(|sent| sent.refused);
+    if let (true, Some(sent)) = (expect_reply, &retry.value) {
+        // Catch any ICMP or UDP reply; the receive timeout bounds the wait
+        // for a matching one
+        let start = sent.start;
+        let attempt = outcome.attempts;
+        let mut buf = vec![0u8; 1500];
+        let mut stray = 0;
+        let reply = timeout(Duration::from_millis(udp.recv_timeout), async {
+            loop {
+                match recv_or_error(&socket, &mut buf).await {
+                    Ok((nrecv, src)) => {
+                        let bytes = &buf[..nrecv];
+                        if let Some(pcap) = pcap {
+                            pcap.record_udp(SystemTime::now(), src, local, bytes);
+                        }
+                        log.hexdump(opts.hexdump, Direction::Received, attempt, bytes);
+                        let from_target = match multicast {
+                            true => udp.reply_port.is_none_or(|p| p == src.port()),
+                            false => (src.ip(), src.port()) == (reply_from.ip(), reply_from.port()),
+                        };
+                        if !from_target {
+                            stray += 1;
+                            log.notice(format!(
+                                "ignored {nrecv} bytes from {src}, not {reply_from} (attempt {attempt})"
+                            ));
+                            continue;
+                        }
+                        if let Some(pattern) = &udp.pattern {
+                            if !pattern.matches(bytes) {
+                                log.notice(format!(
+                                    "ignored {nrecv} bytes not matching {pattern} (attempt {attempt})"
+                                ));
+                                continue;
+                            }
+                        }
+                        log.succeeded(
+                            attempt,
+                            start.elapsed(),
+                            format!("received {nrecv} bytes from {src}"),
+                        );
+                        return Some(Some(bytes.to_vec()));
+                    }
+                    Err(e) => match ErrorClass::of(&e) {
+                        ErrorClass::Delivered if udp.reply_only => {
+                            log.notice(format!(
+                                "ignored port unreachable, waiting for a reply (attempt {attempt})"
+                            ));
+                            continue;
+                        }
+                        // Port unreachable: the datagram got through
+                        ErrorClass::Delivered if !udp.strict => {
+                            log.succeeded(attempt, start.elapsed(), "REFUSED (knock delivered)");
+                            return Some(None);
+                        }
+                        class => {
+                            log.push_classified(attempt, format!("recv: {e}"), class);
+                            return None;
+                        }
+                    },
+                }
+            }
+        })
+        .await;
+        outcome.stray_replies = stray;
+        outcome.latency = match reply {
+            Ok(Some(bytes)) => {
+                refused = bytes.is_none();
+                outcome.reply = bytes;
+                Some(start.elapsed())
+            }
+            Ok(None) => None,
+            Err(_) => {
+                log.timed_out(attempt, "no reply within the receive timeout");
+                None
+            }
+        };
+    }
 
+    // A sent datagram still needs its reply when one is expected
+    outcome.succeeded = retry.succeeded() && outcome.latency.is_some();
+    outcome.acknowledged = outcome.succeeded && (expect_reply || refused);
+    outcome.errors = log.into_errors();
+    outcome.elapsed = started.elapsed();
+    Ok(outcome)
+}
+
+/// The send that went through.
+struct Sent {
+    start: Instant,
+    latency: Duration,
+    /// It reported a port-unreachable for an earlier datagram.
+    refused: bool,
+}
+
+/// Send from the interface and with the hop limit `udp` asks for
+/// multicast knocks.
+fn set_multicast(socket: &UdpSocket, udp: &UdpOpts) -> io::Result<()> {
+    let sock = SockRef::from(socket);
+    match udp.multicast_if {
+        Some(MulticastInterface::V4(addr)) => sock.set_multicast_if_v4(&addr)?,
+        Some(MulticastInterface::V6 { index, .. }) => sock.set_multicast_if_v6(index)?,
+        None => {}
+    }
+    if let Some(ttl) = udp.multicast_ttl {
+        match socket.local_addr()?.is_ipv6() {
+            true => sock.set_multicast_hops_v6(u32::from(ttl))?,
+            false => sock.set_multicast_ttl_v4(u32::from(ttl))?,
+        }
+    }
     Ok(())
 }
+
+/// Send unicast packets from `socket` with IP TTL, or IPv6 hop limit,
+/// `ttl`; set on each knock's own socket, so every step can have its own.
+pub(crate) fn set_ttl(sock: SockRef<'_>, ipv6: bool, ttl: u8) -> io::Result<()> {
+    match ipv6 {
+        true => sock.set_unicast_hops_v6(u32::from(ttl)),
+        false => sock.set_ttl(u32::from(ttl)),
+    }
+}
+
+/// A fatal connect or send error; the kernel refuses a broadcast target
+/// with EACCES unless SO_BROADCAST is set, which `--broadcast` does.
+fn local_failure(e: io::Error, target: SocketAddr, broadcast: bool) -> AppError {
+    let eacces = if cfg!(windows) { 10013 } else { 13 };
+    if !broadcast && target.is_ipv4() && e.raw_os_error() == Some(eacces) {
+        return AppError::Broadcast { addr: target };
+    }
+    AppError::local_failure(e)
+}
+
+/// Append random bytes to `payload` until it is `size` bytes long; a
+/// payload already that long is returned unchanged.
+pub(crate) fn pad_payload(payload: &[u8], size: usize) -> Vec<u8> {
+    let mut out = payload.to_vec();
+    if out.len() < size {
+        let mut padding = vec![0u8; size - out.len()];
+        rand::rng().fill_bytes(&mut padding);
+        out.extend_from_slice(&padding);
+    }
+    out
+}
+
+/// How many source ports to try before giving up on ones in use.
+const BIND_ATTEMPTS: usize = 10;
+
+/// Parse a source port range, `FIRST-LAST` or a single port.
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let port = |p: &str| {
+        p.trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|&p| p > 0)
+            .ok_or_else(|| format!("'{p}' is not a port (1-65535)"))
+    };
+    let (first, last) = match s.split_once('-') {
+        Some((first, last)) => (port(first)?, port(last)?),
+        None => (port(s)?, port(s)?),
+    };
+    if first > last {
+        return Err(format!("port range {first}-{last} is empty"));
+    }
+    Ok(first..=last)
+}
+
+/// Ports to try binding under `policy`, in order: the first ones of a
+/// [`SourcePortPolicy::Range`]; for [`SourcePortPolicy::Random`] ones drawn
+/// uniformly or, for a range no bigger than the attempts, each of its ports
+/// once starting at a random one. None when the kernel picks.
+fn candidate_ports(policy: &SourcePortPolicy) -> Vec<u16> {
+    let mut rng = rand::rng();
+    match policy {
+        SourcePortPolicy::Os => Vec::new(),
+        SourcePortPolicy::Range(ports) => ports.clone().take(BIND_ATTEMPTS).collect(),
+        SourcePortPolicy::Random(ports) if ports.len() <= BIND_ATTEMPTS => {
+            let mut ports: Vec<u16> = ports.clone().collect();
+            let start = rng.random_range(0..ports.len());
+            ports.rotate_left(start);
+            ports
+        }
+        SourcePortPolicy::Random(ports) => (0..BIND_ATTEMPTS)
+            .map(|_| rng.random_range(ports.clone()))
+            .collect(),
+    }
+}
+
+/// Bind a socket for knocking `target` on the first of `candidates` that
+/// is not in use; [`AppError::SourcePorts`] names `ports` when all were.
+async fn bind_source_port(
+    target: SocketAddr,
+    ports: &RangeInclusive<u16>,
+    candidates: &[u16],
+) -> Result<UdpSocket, AppError> {
+    for &port in candidates {
+        let bind = bind_addr(target, port);
+        match UdpSocket::bind(bind).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(source) => return Err(AppError::Bind { addr: bind, source }),
+        }
+    }
+    Err(AppError::SourcePorts {
+        first: *ports.start(),
+        last: *ports.end(),
+        attempts: candidates.len(),
+    })
+}
+
+/// Wildcard local address in the target's family, carrying the target's
+/// scope ID so link-local knocks leave through the right interface.
+pub(crate) fn bind_addr(target: SocketAddr, local_port: u16) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port),
+        SocketAddr::V6(v6) => {
+            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, local_port, 0, v6.scope_id()).into()
+        }
+    }
+}
+
+/// Have the kernel refuse, with EMSGSIZE, datagrams that would need
+/// fragmenting, rather than fragment them or let a router drop them.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, v6: bool) -> io::Result<()> {
+    let (level, name, value) = match v6 {
+        false => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        true => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+    };
+    // SAFETY: the descriptor is open for the socket's lifetime and the
+    // option value is a c_int of the size given
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match rc {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The path MTU the kernel knows for a connected socket, if it says.
+#[cfg(target_os = "linux")]
+fn path_mtu(socket: &UdpSocket, v6: bool) -> Option<u32> {
+    let (level, name) = match v6 {
+        false => (libc::IPPROTO_IP, libc::IP_MTU),
+        true => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: as in `set_dont_fragment`, with `len` the size of `mtu`
+    let rc = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&mut mtu as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    (rc == 0).then(|| u32::try_from(mtu).ok()).flatten()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn path_mtu(_socket: &UdpSocket, _v6: bool) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::KnockEvent;
+    use crate::retry::{Attempts, BackoffPolicy};
+    use futures::StreamExt;
+
+    /// Knock options with no backoff around the given UDP behavior.
+    fn knock_opts(timeout: u64, attempts: usize, udp: &UdpOpts) -> KnockOpts {
+        KnockOpts {
+            timeout,
+            attempts: Attempts::Finite(attempts),
+            backoff: BackoffPolicy::Constant(Duration::ZERO),
+            udp: udp.clone(),
+            ..KnockOpts::default()
+        }
+    }
+
+    #[test]
+    fn padding_reaches_the_target_size() {
+        let padded = pad_payload(b"knock", 64);
+        assert_eq!(padded.len(), 64);
+        assert_eq!(&padded[..5], b"knock");
+        assert_eq!(pad_payload(b"", 16).len(), 16);
+        assert_eq!(pad_payload(b"knock", 5), b"knock");
+    }
+
+    #[tokio::test]
+    async fn port_in_use_is_picked_again() {
+        let occupied = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let taken = occupied.local_addr().unwrap().port();
+        let free = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let free_port = free.local_addr().unwrap().port();
+        drop(free);
+        let target: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+
+        let socket = bind_source_port(target, &EPHEMERAL_PORTS, &[taken, free_port])
+            .await
+            .unwrap();
+        assert_eq!(socket.local_addr().unwrap().port(), free_port);
+
+        // A range with every port in use fails naming it, without looping
+        let range = taken..=taken;
+        let candidates = candidate_ports(&SourcePortPolicy::Random(range.clone()));
+        let err = bind_source_port(target, &range, &candidates)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("no free source port in {taken}-{taken} after 1 attempt(s)")
+        );
+    }
+
+    #[test]
+    fn candidates_cover_small_ranges_once() {
+        let mut ports = candidate_ports(&SourcePortPolicy::Random(40000..=40003));
+        ports.sort_unstable();
+        assert_eq!(ports, [40000, 40001, 40002, 40003]);
+
+        let ports = candidate_ports(&SourcePortPolicy::Random(EPHEMERAL_PORTS));
+        assert_eq!(ports.len(), BIND_ATTEMPTS);
+        assert!(ports.iter().all(|p| EPHEMERAL_PORTS.contains(p)));
+
+        // Both ends of a range can be drawn
+        let ends: std::collections::HashSet<u16> = (0..200)
+            .flat_map(|_| candidate_ports(&SourcePortPolicy::Random(40000..=40011)))
+            .collect();
+        assert!(ends.contains(&40000) && ends.contains(&40011));
+
+        // A pinned range is walked from its start; the kernel needs none
+        let ports = candidate_ports(&SourcePortPolicy::Range(40000..=40100));
+        assert_eq!(ports, (40000..40010).collect::<Vec<u16>>());
+        assert!(candidate_ports(&SourcePortPolicy::Os).is_empty());
+    }
+
+    #[test]
+    fn source_port_policies_parse() {
+        let parse = SourcePortPolicy::parse;
+        assert_eq!(parse("os"), Ok(SourcePortPolicy::Os));
+        assert_eq!(
+            parse("random"),
+            Ok(SourcePortPolicy::Random(EPHEMERAL_PORTS))
+        );
+        assert_eq!(
+            parse("random:40000-40100"),
+            Ok(SourcePortPolicy::Random(40000..=40100))
+        );
+        assert_eq!(
+            parse("range:5000"),
+            Ok(SourcePortPolicy::Range(5000..=5000))
+        );
+        assert!(parse("range").is_err());
+        assert!(parse("kernel").is_err());
+        assert_eq!(
+            parse("range:5000-5010").unwrap().to_string(),
+            "range:5000-5010"
+        );
+    }
+
+    #[test]
+    fn port_ranges_parse() {
+        assert_eq!(parse_port_range("40000-40100"), Ok(40000..=40100));
+        assert_eq!(parse_port_range("40000"), Ok(40000..=40000));
+        assert!(parse_port_range("40100-40000").is_err());
+        assert!(parse_port_range("0-10").is_err());
+        assert!(parse_port_range("1-70000").is_err());
+    }
+
+    #[test]
+    fn bind_addr_keeps_family_and_scope() {
+        let v4: SocketAddr = "192.0.2.1:7000".parse().unwrap();
+        assert_eq!(bind_addr(v4, 40000), "0.0.0.0:40000".parse().unwrap());
+
+        let v6 = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 7000, 0, 4));
+        match bind_addr(v6, 40000) {
+            SocketAddr::V6(local) => {
+                assert!(local.ip().is_unspecified());
+                assert_eq!(local.port(), 40000);
+                assert_eq!(local.scope_id(), 4);
+            }
+            SocketAddr::V4(_) => panic!("bound an IPv4 socket for an IPv6 target"),
+        }
+    }
+
+    #[tokio::test]
+    async fn datagrams_from_other_sources_are_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        // Answer every knock from a different socket, never from the target
+        tokio::spawn(async move {
+            let stray = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut buf = [0u8; 64];
+            while let Ok((_, src)) = server.recv_from(&mut buf).await {
+                let _ = stray.send_to(b"noise", src).await;
+            }
+        });
+
+        let opts = UdpOpts {
+            expect_reply: true,
+            recv_timeout: 200,
+            ..UdpOpts::default()
+        };
+        let err = knock_udp(target, None, &knock_opts(200, 1, &opts))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, AppError::Timeout { attempts: 1, .. }),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_port_accepts_only_that_port() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        let replier = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reply_port = replier.local_addr().unwrap().port();
+        // A stranger gets in first, then the real reply comes from its port
+        tokio::spawn(async move {
+            let spoof = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut buf = [0u8; 64];
+            while let Ok((_, src)) = server.recv_from(&mut buf).await {
+                let _ = spoof.send_to(b"spoof", src).await;
+                let _ = replier.send_to(b"ok", src).await;
+            }
+        });
+
+        let opts = UdpOpts {
+            expect_reply: true,
+            recv_timeout: 500,
+            reply_port: Some(reply_port),
+            ..UdpOpts::default()
+        };
+        let outcome = knock_udp(target, None, &knock_opts(500, 1, &opts))
+            .await
+            .unwrap();
+        assert!(outcome.succeeded && outcome.acknowledged);
+        assert_eq!(outcome.reply.as_deref(), Some(&b"ok"[..]));
+        assert_eq!(outcome.stray_replies, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn oversized_datagrams_fail_with_their_size() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        let opts = UdpOpts {
+            dont_fragment: true,
+            ..UdpOpts::default()
+        };
+        let outcome = knock_udp(target, Some(&[0; 2000]), &knock_opts(200, 1, &opts)).await;
+        assert!(outcome.unwrap().succeeded, "loopback carries 2000 bytes");
+
+        // More than any IPv4 datagram holds
+        let payload = vec![0; 65508];
+        let err = knock_udp(target, Some(&payload), &knock_opts(200, 1, &opts))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                AppError::Oversized {
+                    size: 65508,
+                    mtu: Some(_)
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains("over a path MTU of"), "{err}");
+        assert_eq!(err.exit_code(), 4);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn broadcast_targets_need_the_flag() {
+        // The broadcast address of 127.0.0.0/8 on the loopback interface
+        let target: SocketAddr = "127.255.255.255:7000".parse().unwrap();
+        let opts = UdpOpts::default();
+        let err = knock_udp(target, None, &knock_opts(200, 1, &opts))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Broadcast { addr } if addr == target));
+        assert!(err.to_string().contains("--broadcast"), "{err}");
+
+        // Sent without waiting for the replies it asks for
+        let opts = UdpOpts {
+            broadcast: true,
+            expect_reply: true,
+            recv_timeout: 5000,
+            ..UdpOpts::default()
+        };
+        let outcome = knock_udp(target, None, &knock_opts(200, 1, &opts))
+            .await
+            .unwrap();
+        assert!(outcome.succeeded && !outcome.acknowledged);
+        assert!(outcome.elapsed < Duration::from_secs(5));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn multicast_replies_come_from_any_member() {
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let member = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        member
+            .join_multicast_v4(group, Ipv4Addr::LOCALHOST)
+            .unwrap();
+        let target = SocketAddr::new(group.into(), member.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (_, src) = member.recv_from(&mut buf).await.unwrap();
+            member.send_to(b"ok", src).await.unwrap();
+        });
+
+        let opts = UdpOpts {
+            expect_reply: true,
+            recv_timeout: 1000,
+            multicast_if: Some(MulticastInterface::V4(Ipv4Addr::LOCALHOST)),
+            multicast_ttl: Some(1),
+            ..UdpOpts::default()
+        };
+        let outcome = knock_udp(target, None, &knock_opts(500, 1, &opts))
+            .await
+            .unwrap();
+        assert_eq!(outcome.reply.as_deref(), Some(&b"ok"[..]));
+
+        let opts = UdpOpts {
+            multicast_if: Some(MulticastInterface::parse("::1%1").unwrap()),
+            ..UdpOpts::default()
+        };
+        let err = knock_udp(target, None, &knock_opts(500, 1, &opts)).await;
+        assert!(matches!(err, Err(AppError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn multicast_interfaces_parse() {
+        assert_eq!(
+            MulticastInterface::parse("192.0.2.1"),
+            Ok(MulticastInterface::V4(Ipv4Addr::new(192, 0, 2, 1)))
+        );
+        assert_eq!(
+            MulticastInterface::parse("fe80::1%3"),
+            Ok(MulticastInterface::V6 {
+                addr: "fe80::1".parse().unwrap(),
+                index: 3
+            })
+        );
+        assert!(MulticastInterface::parse("fe80::1").is_err());
+        assert!(MulticastInterface::parse("eth0").is_err());
+    }
+
+    #[tokio::test]
+    async fn source_port_is_reported() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        let free = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let pinned = free.local_addr().unwrap().port();
+        drop(free);
+
+        for policy in [
+            SourcePortPolicy::Os,
+            SourcePortPolicy::Range(pinned..=pinned),
+        ] {
+            let opts = UdpOpts {
+                source_port: policy.clone(),
+                ..UdpOpts::default()
+            };
+            let outcome = knock_udp(target, None, &knock_opts(200, 1, &opts))
+                .await
+                .unwrap();
+            let mut buf = [0u8; 16];
+            let (_, src) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(outcome.source_port, Some(src.port()), "{policy}");
+            if policy != SourcePortPolicy::Os {
+                assert_eq!(src.port(), pinned);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_alone_delivers_by_default() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let outcome = knock(
+            "127.0.0.1",
+            target,
+            None,
+            &knock_opts(200, 3, &UdpOpts::default()),
+            None,
+            &EventSink::new(tx),
+        )
+        .await
+        .unwrap();
+        assert!(outcome.succeeded);
+        assert!(!outcome.acknowledged);
+        assert_eq!(outcome.attempts, 1);
+
+        // The send is the knock getting through, with no failures before it
+        let events: Vec<KnockEvent> = rx.collect().await;
+        assert!(matches!(
+            events.as_slice(),
+            [KnockEvent::KnockSucceeded { attempt: 1, detail, .. }] if detail == "SENT 0 bytes"
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_matching_reply_succeeds() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((_, src)) = server.recv_from(&mut buf).await {
+                let _ = server.send_to(b"noise", src).await;
+                let _ = server.send_to(b"ACK 7000", src).await;
+            }
+        });
+
+        let mut opts = UdpOpts {
+            expect_reply: true,
+            recv_timeout: 200,
+            pattern: Some(ReplyPattern::parse("ACK*").unwrap()),
+            ..UdpOpts::default()
+        };
+        let outcome = knock_udp(target, None, &knock_opts(500, 1, &opts))
+            .await
+            .unwrap();
+        assert!(outcome.succeeded && outcome.acknowledged);
+        // The noise before it was skipped
+        assert_eq!(outcome.reply.as_deref(), Some(&b"ACK 7000"[..]));
+
+        opts.pattern = Some(ReplyPattern::parse("hex:ff").unwrap());
+        let err = knock_udp(target, None, &knock_opts(200, 1, &opts))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Timeout { .. }), "{err}");
+    }
+
+    #[tokio::test]
+    async fn port_unreachable_counts_as_delivered() {
+        // Bind and drop to get a local port nobody listens on
+        let target = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut opts = UdpOpts {
+            expect_reply: true,
+            recv_timeout: 200,
+            ..UdpOpts::default()
+        };
+        let outcome = knock_udp(target, None, &knock_opts(200, 3, &opts))
+            .await
+            .unwrap();
+        assert!(outcome.succeeded && outcome.acknowledged);
+        assert_eq!(outcome.attempts, 1);
+
+        opts.strict = true;
+        let err = knock_udp(target, None, &knock_opts(200, 2, &opts))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::KnockFailed { .. }), "{err}");
+    }
+
+    #[tokio::test]
+    async fn missing_reply_does_not_resend() {
+        // Accept the knock but never answer
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+
+        let opts = UdpOpts {
+            expect_reply: true,
+            recv_timeout: 100,
+            ..UdpOpts::default()
+        };
+        let outcome = knock_addr(target, None, &knock_opts(200, 3, &opts))
+            .await
+            .unwrap();
+        assert!(!outcome.succeeded);
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(
+            outcome.errors,
+            [crate::outcome::AttemptError::timeout(
+                1,
+                "no reply within the receive timeout"
+            )]
+        );
+        assert!(outcome.elapsed >= Duration::from_millis(100));
+
+        let mut buf = [0u8; 64];
+        let mut copies = 0;
+        while server.try_recv_from(&mut buf).is_ok() {
+            copies += 1;
+        }
+        assert_eq!(copies, 1);
+    }
+}
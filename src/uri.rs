@@ -0,0 +1,452 @@
+//! `knock://` URIs: a whole knock in one string, for tools that launch
+//! the knocker (`--uri`), e.g.
+//!
+//! ```text
+//! knock://example.com/7000,8000:udp,9000?timeout=500ms&verify=22&protocol=tcp
+//! ```
+//!
+//! - The host is the URI's host; an IPv6 literal is bracketed, its zone
+//!   written `%25` as RFC 6874 has it, e.g. `knock://[fe80::1%25eth0]/7000`.
+//!   No user or port may come with it.
+//! - The path is the sequence as `--sequence` takes it, where `PORT:PROTO`
+//!   may stand for `PORT/PROTO`, less `plugin` steps, which load a file.
+//! - Each query parameter is the long option of the same name:
+//!   `verify=22` is `--verify 22`. A flag is given bare or as `=true` or
+//!   `=false`, and options taking milliseconds take a
+//!   [duration](crate::duration) with a unit: `500ms`, `2s`, `1m30s`. Only the options in [`PARAMS`] can be given; those that read
+//!   files, run commands or need privileges are only taken as flags.
+//!
+//! Everything is percent-decoded, so a step option in the path is written
+//! `7000%3Ftimeout=900` and a space `%20`; `+` is taken as it is. On the
+//! command line, options passed as flags win over the URI's.
+
+use crate::plan::KnockPlan;
+use crate::protocol::Protocol;
+use std::fmt;
+use std::str::FromStr;
+
+/// How a query parameter's value becomes its option's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    /// Taken as it is.
+    Value,
+    /// A duration with a unit, handed on in milliseconds.
+    Millis,
+    /// A flag: bare, `true` or `false`.
+    Flag,
+}
+
+/// The query parameters a URI may give: the long options they stand for.
+pub const PARAMS: &[(&str, ParamKind)] = &[
+    ("all-ips", ParamKind::Flag),
+    ("attempts", ParamKind::Value),
+    ("auto-timeout", ParamKind::Flag),
+    ("backoff", ParamKind::Millis),
+    ("backoff-max", ParamKind::Millis),
+    ("backoff-strategy", ParamKind::Value),
+    ("concurrency", ParamKind::Value),
+    ("decoys", ParamKind::Value),
+    ("delay", ParamKind::Value),
+    ("dns-server", ParamKind::Value),
+    ("dry-run", ParamKind::Flag),
+    ("expect-reply", ParamKind::Flag),
+    ("fail-fast", ParamKind::Flag),
+    ("initial-delay", ParamKind::Millis),
+    ("knock-deadline", ParamKind::Millis),
+    ("lockstep", ParamKind::Flag),
+    ("payload", ParamKind::Value),
+    ("protocol", ParamKind::Value),
+    ("rate", ParamKind::Value),
+    ("recv-timeout", ParamKind::Millis),
+    ("resolve", ParamKind::Value),
+    ("sni", ParamKind::Value),
+    ("strict-udp", ParamKind::Flag),
+    ("tcp-close", ParamKind::Value),
+    ("timeout", ParamKind::Millis),
+    ("unordered", ParamKind::Flag),
+    ("verify", ParamKind::Value),
+    ("verify-banner", ParamKind::Value),
+];
+
+/// Parameters that may be given more than once, as their options may.
+const REPEATABLE: &[&str] = &["resolve"];
+
+/// A parsed `knock://` URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnockUri {
+    /// The host, without brackets, as `--host` takes it.
+    pub host: String,
+    pub sequence: KnockPlan,
+    /// The query parameters in order: each the long option it stands for
+    /// and the value to give it, `None` for a flag that is set. A flag set
+    /// to `false` is left out.
+    pub options: Vec<(&'static str, Option<String>)>,
+}
+
+impl KnockUri {
+    /// Parse a `knock://HOST/SEQUENCE[?NAME=VALUE&...]` URI.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let rest = s
+            .split_once("://")
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("knock"))
+            .map(|(_, rest)| rest)
+            .ok_or_else(|| format!("'{s}' is not a knock:// URI"))?;
+        if rest.contains('#') {
+            return Err("a knock:// URI takes no #fragment".into());
+        }
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let host = parse_authority(authority)?;
+        let sequence = parse_path(path)?;
+        let mut options: Vec<(&'static str, Option<String>)> = Vec::new();
+        let mut seen: Vec<String> = Vec::new();
+        for pair in query.into_iter().flat_map(|q| q.split('&')) {
+            if pair.is_empty() {
+                continue;
+            }
+            let (name, value) = match pair.split_once('=') {
+                Some((name, value)) => (decode(name)?, Some(decode(value)?)),
+                None => (decode(pair)?, None),
+            };
+            if seen.contains(&name) && !REPEATABLE.contains(&name.as_str()) {
+                return Err(format!("parameter '{name}' is given twice"));
+            }
+            seen.push(name.clone());
+            let Some((option, value)) = param(&name, value, "knock:// parameter")? else {
+                continue;
+            };
+            options.push((option, value));
+        }
+        Ok(Self {
+            host,
+            sequence,
+            options,
+        })
+    }
+
+    /// The command-line arguments the URI stands for: `--host`,
+    /// `--sequence` and one per option, in order.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!("--host={}", self.host),
+            format!("--sequence={}", self.sequence),
+        ];
+        for (option, value) in &self.options {
+            args.push(match value {
+                Some(value) => format!("--{option}={value}"),
+                None => format!("--{option}"),
+            });
+        }
+        args
+    }
+}
+
+impl FromStr for KnockUri {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Self::parse(s)
+    }
+}
+
+/// The URI, percent-encoded as [`KnockUri::parse`] reads it back;
+/// durations are given in milliseconds.
+impl fmt::Display for KnockUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", encode(&self.host, ":")),
+            false => encode(&self.host, ""),
+        };
+        write!(
+            f,
+            "knock://{host}/{}",
+            encode(&self.sequence.to_string(), ",()<:/=&")
+        )?;
+        for (i, (option, value)) in self.options.iter().enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            let millis = PARAMS.contains(&(*option, ParamKind::Millis));
+            match value {
+                Some(value) if millis => write!(f, "{separator}{option}={value}ms")?,
+                Some(value) => write!(f, "{separator}{option}={}", encode(value, ",:/"))?,
+                None => write!(f, "{separator}{option}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The host of the authority part: no user, no port.
+fn parse_authority(authority: &str) -> Result<String, String> {
+    if authority.contains('@') {
+        return Err("a knock:// URI takes no user@ before the host".into());
+    }
+    let after_host = match authority.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or("", |(_, after)| after),
+        None => authority,
+    };
+    if after_host.contains(':') {
+        return Err(
+            "a knock:// URI takes no :port after the host; the ports to knock are its path".into(),
+        );
+    }
+    let host = decode(authority)?;
+    if host.is_empty() {
+        return Err("a knock:// URI needs a host".into());
+    }
+    crate::scope::parse_host(&host)
+}
+
+/// The sequence in the path, `PORT:PROTO` read as `PORT/PROTO`.
+fn parse_path(path: &str) -> Result<KnockPlan, String> {
+    let path = decode(path)?;
+    if path.is_empty() {
+        return Err(
+            "a knock:// URI needs the sequence as its path, e.g. knock://HOST/7000,8000,9000"
+                .into(),
+        );
+    }
+    let steps: Vec<String> = path
+        .split(',')
+        .map(|step| match step.rsplit_once(':') {
+            Some((port, protocol))
+                if Protocol::from_str(protocol.trim_end_matches(')')).is_ok() =>
+            {
+                format!("{port}/{protocol}")
+            }
+            _ => step.to_string(),
+        })
+        .collect();
+    KnockPlan::parse_untrusted(&steps.join(","))
+        .map_err(|e| format!("the sequence of the URI: {e}"))
+}
+
+/// The long option the parameter `name` stands for and the value to give
+/// it, as a URI's query gives them; `what` names such a parameter in
+/// errors. `None` for a flag set to `false`.
+pub(crate) fn param(
+    name: &str,
+    value: Option<String>,
+    what: &str,
+) -> Result<Option<(&'static str, Option<String>)>, String> {
+    let Some(&(option, kind)) = PARAMS.iter().find(|(option, _)| *option == name) else {
+        return Err(format!(
+            "'{name}' is not a {what} (options that read files, run commands or need \
+             privileges are only taken as flags)"
+        ));
+    };
+    let value = match (kind, value) {
+        (ParamKind::Flag, None) => None,
+        (ParamKind::Flag, Some(value)) => match value.as_str() {
+            "true" => None,
+            "false" => return Ok(None),
+            _ => {
+                return Err(format!(
+                    "flag '{option}' takes true or false, not '{value}'"
+                ))
+            }
+        },
+        (_, None) => return Err(format!("'{option}' needs a value")),
+        (ParamKind::Millis, Some(value)) => Some(millis(option, &value)?.to_string()),
+        (ParamKind::Value, Some(value)) => Some(value),
+    };
+    Ok(Some((option, value)))
+}
+
+/// A [`duration`](crate::duration) with a unit, in whole milliseconds.
+fn millis(option: &str, value: &str) -> Result<u64, String> {
+    let duration =
+        crate::duration::parse(value, None).map_err(|e| format!("{e} for '{option}'"))?;
+    match duration.subsec_nanos() % 1_000_000 {
+        0 => u64::try_from(duration.as_millis())
+            .map_err(|_| format!("'{value}' for '{option}' is too long")),
+        _ => Err(format!(
+            "'{value}' for '{option}' is not a whole number of milliseconds"
+        )),
+    }
+}
+
+/// Percent-decode `s` into UTF-8 text.
+fn decode(s: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let [first, tail @ ..] = rest {
+        if *first != b'%' {
+            bytes.push(*first);
+            rest = tail;
+            continue;
+        }
+        let byte = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| {
+                let shown: String = String::from_utf8_lossy(&rest[..rest.len().min(3)]).into();
+                format!("'{shown}' in '{s}' is not a percent-encoded byte")
+            })?;
+        bytes.push(byte);
+        rest = &tail[2..];
+    }
+    String::from_utf8(bytes).map_err(|_| format!("'{s}' does not decode to UTF-8 text"))
+}
+
+/// Percent-encode all of `s` but unreserved characters and `keep`.
+fn encode(s: &str, keep: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() || "-._~".contains(c) || keep.contains(c) {
+            out.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_uris() {
+        for (uri, host, sequence, args) in [
+            (
+                "knock://example.com/7000,8000,9000",
+                "example.com",
+                "7000,8000,9000",
+                &[][..],
+            ),
+            ("KNOCK://example.com/7000", "example.com", "7000", &[]),
+            (
+                "knock://example.com/7000,8000:udp,9000?timeout=500ms&verify=22&protocol=tcp",
+                "example.com",
+                "7000,8000/udp,9000",
+                &["--timeout=500", "--verify=22", "--protocol=tcp"],
+            ),
+            ("knock://203.0.113.7/7000", "203.0.113.7", "7000", &[]),
+            ("knock://[::1]/7000/udp", "::1", "7000/udp", &[]),
+            ("knock://[fe80::1%252]/7000", "fe80::1%2", "7000", &[]),
+            (
+                "knock://b%C3%BCcher.example/7000",
+                "bücher.example",
+                "7000",
+                &[],
+            ),
+            (
+                "knock://h/(7000:udp,8000),9000",
+                "h",
+                "(7000/udp,8000),9000",
+                &[],
+            ),
+            ("knock://h/7000%3Ftimeout=900", "h", "7000?timeout=900", &[]),
+            (
+                "knock://h/7000?dry-run&fail-fast=true&lockstep=false",
+                "h",
+                "7000",
+                &["--dry-run", "--fail-fast"],
+            ),
+            (
+                "knock://h/7000?backoff=2s&knock-deadline=1m&initial-delay=0ms",
+                "h",
+                "7000",
+                &[
+                    "--backoff=2000",
+                    "--knock-deadline=60000",
+                    "--initial-delay=0",
+                ],
+            ),
+            (
+                "knock://h/7000?resolve=h:203.0.113.7&resolve=only-v4&&payload=a%20b%26c",
+                "h",
+                "7000",
+                &[
+                    "--resolve=h:203.0.113.7",
+                    "--resolve=only-v4",
+                    "--payload=a b&c",
+                ],
+            ),
+        ] {
+            let parsed = KnockUri::parse(uri).unwrap_or_else(|e| panic!("{uri}: {e}"));
+            assert_eq!(parsed.host, host, "{uri}");
+            assert_eq!(parsed.sequence.to_string(), sequence, "{uri}");
+            assert_eq!(parsed.args()[2..], *args, "{uri}");
+        }
+    }
+
+    #[test]
+    fn invalid_uris() {
+        for (uri, error) in [
+            ("http://example.com/7000", "is not a knock:// URI"),
+            ("example.com/7000", "is not a knock:// URI"),
+            ("knock://example.com/7000#x", "no #fragment"),
+            ("knock://me@example.com/7000", "no user@"),
+            ("knock://example.com:22/7000", "no :port"),
+            ("knock://[::1]:22/7000", "no :port"),
+            ("knock:///7000", "needs a host"),
+            ("knock://example.com", "needs the sequence"),
+            ("knock://example.com/", "needs the sequence"),
+            ("knock://example.com/seven", "the sequence of the URI"),
+            ("knock://example.com/70000", "the sequence of the URI"),
+            ("knock://exa%2mple.com/7000", "not a percent-encoded byte"),
+            ("knock://h/7000%", "not a percent-encoded byte"),
+            ("knock://h/7000%FF", "does not decode to UTF-8"),
+            (
+                "knock://h/7000?hooks=x",
+                "'hooks' is not a knock:// parameter",
+            ),
+            ("knock://h/7000?pre-knock=rm", "is not a knock:// parameter"),
+            (
+                "knock://h/7000?verify=22&verify=80",
+                "'verify' is given twice",
+            ),
+            ("knock://h/7000?timeout", "'timeout' needs a value"),
+            ("knock://h/7000?timeout=500", "is not a duration"),
+            ("knock://h/7000?timeout=fast", "is not a duration"),
+            ("knock://h/7000?dry-run=yes", "takes true or false"),
+            ("knock://h/7000:plugin:/etc/passwd", "is a plugin step"),
+            ("knock://h/7000,8000:plugin:x.wasm:k=v", "is a plugin step"),
+        ] {
+            let err = KnockUri::parse(uri)
+                .err()
+                .unwrap_or_else(|| panic!("{uri} parsed"));
+            assert!(err.contains(error), "{uri}: {err}");
+        }
+    }
+
+    #[test]
+    fn uris_display_as_they_parse() {
+        for uri in [
+            "knock://example.com/7000,8000/udp,9000?timeout=500ms&verify=22",
+            "knock://[fe80::1%252]/(7000,8000),9000?dry-run",
+            "knock://h/7000%3Ftimeout=900?payload=a%20b%26c&resolve=h:203.0.113.7",
+        ] {
+            let parsed: KnockUri = uri.parse().unwrap();
+            assert_eq!(parsed.to_string(), uri);
+            assert_eq!(KnockUri::parse(&parsed.to_string()), Ok(parsed));
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn every_parameter_is_a_long_option() {
+        use clap::CommandFactory;
+
+        let command = crate::Cli::command();
+        for (param, kind) in PARAMS {
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(param))
+                .unwrap_or_else(|| panic!("no --{param}"));
+            assert_eq!(
+                *kind == ParamKind::Flag,
+                !arg.get_action().takes_values(),
+                "{param}"
+            );
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Checking that a knock opened what it should have: a TCP connect to a
+//! port of the host, as a `--plan` stage's `verify` and `--verify` do.
+//!
+//! An open port may still be a tarpit, so a check can also read what the
+//! service says first: with a banner such as `SSH-2.0`, up to
+//! `banner_bytes` bytes must arrive within `banner_timeout` milliseconds
+//! and start with it (or contain it, with `banner_contains`). A service
+//! that waits for the client to speak first sends nothing; that fails as
+//! "connected but no banner" unless `banner_optional` is set.
+
+use crate::rt::{self, TcpStream};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+
+/// How long a verifying connect may take when no timeout is given.
+pub const DEFAULT_VERIFY_TIMEOUT: u64 = 2000;
+
+/// How long the `--skip-if-open` connect may take when no timeout is
+/// given; a port the knocks have yet to open mostly drops it unanswered.
+pub const DEFAULT_OPEN_PROBE_TIMEOUT: u64 = 300;
+
+/// Most bytes of a banner read when no limit is given.
+pub const DEFAULT_BANNER_BYTES: usize = 256;
+
+/// How long to wait for a banner when no timeout is given.
+pub const DEFAULT_BANNER_TIMEOUT: u64 = 2000;
+
+/// A TCP connect that shows the knocks opened what they should have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(deny_unknown_fields)
+)]
+#[cfg_attr(feature = "plan-file", derive(schemars::JsonSchema))]
+pub struct Verify {
+    /// TCP port of the host that the knocks should have opened.
+    pub port: u16,
+    /// Milliseconds.
+    #[cfg_attr(feature = "serde", serde(default = "default_verify_timeout"))]
+    pub timeout: u64,
+    /// What the service must send first once connected.
+    pub banner: Option<String>,
+    /// Most bytes read looking for the banner.
+    #[cfg_attr(feature = "serde", serde(default = "default_banner_bytes"))]
+    pub banner_bytes: usize,
+    /// Milliseconds to wait for the banner after connecting.
+    #[cfg_attr(feature = "serde", serde(default = "default_banner_timeout"))]
+    pub banner_timeout: u64,
+    /// The banner may appear anywhere in what is read, not only first.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub banner_contains: bool,
+    /// A service that sends nothing still verifies.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub banner_optional: bool,
+}
+
+impl Verify {
+    /// A connect to `port` with the default timeout and no banner.
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            timeout: DEFAULT_VERIFY_TIMEOUT,
+            banner: None,
+            banner_bytes: DEFAULT_BANNER_BYTES,
+            banner_timeout: DEFAULT_BANNER_TIMEOUT,
+            banner_contains: false,
+            banner_optional: false,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_verify_timeout() -> u64 {
+    DEFAULT_VERIFY_TIMEOUT
+}
+
+#[cfg(feature = "serde")]
+fn default_banner_bytes() -> usize {
+    DEFAULT_BANNER_BYTES
+}
+
+#[cfg(feature = "serde")]
+fn default_banner_timeout() -> u64 {
+    DEFAULT_BANNER_TIMEOUT
+}
+
+/// What a verifying connect found beyond the open port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum BannerCheck {
+    /// No banner was asked for.
+    NotChecked,
+    /// The service sent this, which matched.
+    Matched(String),
+    /// Connected, but the service sent nothing; only with
+    /// `banner_optional`.
+    Silent,
+}
+
+/// Connect to `verify.port` of `host` and check its banner; an error
+/// reads "port P of H is not open: ..." or names what the banner lacked.
+/// The connect goes to `address`, the one the run knocked
+/// ([`KnockReport::address`](crate::KnockReport::address)), when there is
+/// one, so it does not end up on an address of the other family.
+pub async fn check(
+    host: &str,
+    address: Option<IpAddr>,
+    verify: &Verify,
+) -> Result<BannerCheck, String> {
+    let port = verify.port;
+    let stream = verify_open(host, address, verify)
+        .await
+        .map_err(|e| format!("port {port} of {host} is not open: {e}"))?;
+    check_banner(stream, verify)
+        .await
+        .map_err(|e| format!("port {port} of {host} {e}"))
+}
+
+/// Connect to `port` on each of `addrs` in turn, all within `timeout`
+/// milliseconds, and say which one accepted; an error says why none did.
+pub async fn probe_open(
+    addrs: &[SocketAddr],
+    port: u16,
+    timeout: u64,
+) -> Result<SocketAddr, String> {
+    let attempt = async {
+        let mut last = "no addresses".to_string();
+        for addr in addrs {
+            let addr = SocketAddr::new(addr.ip(), port);
+            match TcpStream::connect(addr).await {
+                Ok(_) => return Ok(addr),
+                Err(e) => last = e.to_string(),
+            }
+        }
+        Err(last)
+    };
+    rt::timeout(Duration::from_millis(timeout), attempt)
+        .await
+        .unwrap_or_else(|_| Err(format!("no connection within {timeout}ms")))
+}
+
+/// Connect to `verify.port` of `address`, or of `host` on any of its
+/// addresses.
+async fn verify_open(
+    host: &str,
+    address: Option<IpAddr>,
+    verify: &Verify,
+) -> Result<TcpStream, String> {
+    let attempt = async {
+        let addrs = match address {
+            Some(ip) => vec![SocketAddr::new(ip, verify.port)],
+            None => {
+                let name = crate::scope::ascii_host(host)?;
+                rt::lookup_host(&name, verify.port)
+                    .await
+                    .map_err(|e| e.to_string())?
+            }
+        };
+        let mut last = "no addresses".to_string();
+        for addr in addrs {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last = e.to_string(),
+            }
+        }
+        Err(last)
+    };
+    rt::timeout(Duration::from_millis(verify.timeout), attempt)
+        .await
+        .unwrap_or_else(|_| Err(format!("no connection within {}ms", verify.timeout)))
+}
+
+/// Read what the service sends first, until the banner matches, the
+/// byte limit or end of stream is reached, or the banner timeout runs
+/// out, and say how the check went; an error reads after "port P of H".
+async fn check_banner(mut stream: TcpStream, verify: &Verify) -> Result<BannerCheck, String> {
+    let Some(expected) = &verify.banner else {
+        return Ok(BannerCheck::NotChecked);
+    };
+    let matches = |read: &[u8]| match verify.banner_contains {
+        true => read
+            .windows(expected.len().max(1))
+            .any(|w| w == expected.as_bytes()),
+        false => read.starts_with(expected.as_bytes()),
+    };
+    let mut read = Vec::with_capacity(verify.banner_bytes);
+    let reading = async {
+        let mut buf = [0; 512];
+        while read.len() < verify.banner_bytes && !matches(&read) {
+            let want = buf.len().min(verify.banner_bytes - read.len());
+            match stream.read(&mut buf[..want]).await {
+                Ok(0) => break,
+                Ok(n) => read.extend_from_slice(&buf[..n]),
+                Err(e) if read.is_empty() => return Err(e.to_string()),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    };
+    let timeout = Duration::from_millis(verify.banner_timeout);
+    if let Ok(Err(e)) = rt::timeout(timeout, reading).await {
+        return Err(format!("connected but the banner read failed: {e}"));
+    }
+    let text = String::from_utf8_lossy(&read)
+        .trim_end()
+        .escape_debug()
+        .to_string();
+    match (read.is_empty(), matches(&read)) {
+        (true, _) if verify.banner_optional => Ok(BannerCheck::Silent),
+        (true, _) => Err(format!(
+            "connected but no banner within {}ms",
+            verify.banner_timeout
+        )),
+        (false, true) => Ok(BannerCheck::Matched(text)),
+        (false, false) => Err(format!(
+            "sent \"{text}\", which does not {} \"{expected}\"",
+            match verify.banner_contains {
+                true => "contain",
+                false => "start with",
+            }
+        )),
+    }
+}
+
+#[cfg(all(test, feature = "runtime-tokio"))]
+mod tests {
+    use super::*;
+
+    /// A listener that sends `greeting` (if any) to each client and then
+    /// keeps the connection open without a word more.
+    async fn greeter(greeting: Option<&'static [u8]>) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if let Some(greeting) = greeting {
+                        let _ = tokio::io::AsyncWriteExt::write_all(&mut stream, greeting).await;
+                    }
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                });
+            }
+        });
+        port
+    }
+
+    async fn banner(
+        port: u16,
+        banner: &str,
+        contains: bool,
+        optional: bool,
+    ) -> Result<BannerCheck, String> {
+        let verify = Verify {
+            timeout: 500,
+            banner: Some(banner.into()),
+            banner_timeout: 300,
+            banner_contains: contains,
+            banner_optional: optional,
+            ..Verify::new(port)
+        };
+        let stream = verify_open("127.0.0.1", None, &verify).await?;
+        check_banner(stream, &verify).await
+    }
+
+    #[tokio::test]
+    async fn banners_tell_services_from_tarpits() {
+        let ssh = greeter(Some(b"SSH-2.0-OpenSSH_9.6\r\n")).await;
+        assert_eq!(
+            banner(ssh, "SSH-2.0", false, false).await,
+            Ok(BannerCheck::Matched("SSH-2.0-OpenSSH_9.6".into()))
+        );
+        assert!(banner(ssh, "OpenSSH", true, false).await.is_ok());
+        let error = banner(ssh, "OpenSSH", false, false).await.unwrap_err();
+        assert!(error.contains("does not start with"), "{error}");
+
+        // A tarpit, or a service waiting for the client to speak first
+        let silent = greeter(None).await;
+        let error = banner(silent, "SSH-2.0", false, false).await.unwrap_err();
+        assert_eq!(error, "connected but no banner within 300ms");
+        assert_eq!(
+            banner(silent, "SSH-2.0", false, true).await,
+            Ok(BannerCheck::Silent)
+        );
+
+        let plain = check("127.0.0.1", None, &Verify::new(silent)).await;
+        assert_eq!(plain, Ok(BannerCheck::NotChecked));
+    }
+}
@@ -0,0 +1,167 @@
+//! Getting everything ready before the first packet (`--warmup`).
+//!
+//! A run normally builds each payload, signs and encrypts it and draws its
+//! jitter just before the knock goes out, so the first knock also pays for
+//! resolution and setup, which skews the spacing a server with a tight
+//! window sees. A warmed-up run works all of that out first into a
+//! [`WarmPlan`], then sends exactly what is in it, optionally at a given
+//! wall-clock time (`--start-at`) or once Enter is pressed
+//! (`--start-on-key`). A dry run with `--warmup` shows the same plan.
+//!
+//! Signed and SPA payloads carry the time they were built, so a start long
+//! after the warm-up can make them too old for the server.
+
+use crate::{plan::KnockStep, protocol::Protocol, AppError};
+use bytes::Bytes;
+use std::fmt::{self, Write};
+use std::io::{self, BufRead, IsTerminal, Write as _};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Every knock of a run, ready to send.
+#[derive(Debug, Clone, Default)]
+pub struct WarmPlan {
+    pub knocks: Vec<WarmKnock>,
+    /// Each knock's `wait` counts from the start of the sequence instead
+    /// of from the knock before (`--strict-timing`).
+    pub slots: bool,
+}
+
+/// One knock of a [`WarmPlan`].
+#[derive(Debug, Clone)]
+pub struct WarmKnock {
+    pub step: KnockStep,
+    pub protocol: Protocol,
+    /// The bytes sent, padded, signed and encrypted as asked.
+    pub payload: Option<Bytes>,
+    /// How long after the previous knock went out this one does, jitter
+    /// included, or its slot with `slots`.
+    pub wait: Duration,
+}
+
+impl fmt::Display for WarmPlan {
+    /// One line per knock, e.g. `  #2 8000/udp  +250ms  32 bytes`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = if self.slots { "at" } else { "+" };
+        writeln!(f, "Warmed:    {} knocks", self.knocks.len())?;
+        for (index, knock) in self.knocks.iter().enumerate() {
+            let protocol = format!("{:?}", knock.protocol).to_lowercase();
+            let wait = match crate::pacing::is_fine(knock.wait) {
+                true => format!("{}us", knock.wait.as_micros()),
+                false => format!("{}ms", knock.wait.as_millis()),
+            };
+            let mut line = format!(
+                "  #{} {}/{protocol}  {at}{wait}",
+                index + 1,
+                knock.step.port
+            );
+            if let Some(payload) = &knock.payload {
+                let _ = write!(line, "  {} bytes", payload.len());
+            }
+            if knock.step.decoy {
+                line.push_str("  decoy");
+            }
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a `--start-at` time: Unix seconds, fractions allowed
+/// (`1760600000.5`), or a UTC RFC 3339 time (`2026-10-16T12:00:00Z`,
+/// `2026-10-16T12:00:00.250Z`).
+pub fn parse_start_at(s: &str) -> Result<SystemTime, String> {
+    let invalid = || {
+        format!(
+            "'{s}' is not a start time: expected Unix seconds or a UTC time \
+             like 2026-10-16T12:00:00Z"
+        )
+    };
+    if let Ok(secs) = s.parse::<f64>() {
+        return match secs.is_finite() && secs >= 0.0 {
+            true => Ok(UNIX_EPOCH + Duration::from_secs_f64(secs)),
+            false => Err(invalid()),
+        };
+    }
+    // A zero offset, whether written Z or +00:00, is UTC
+    let time = chrono::DateTime::parse_from_rfc3339(s).map_err(|_| invalid())?;
+    if time.offset().local_minus_utc() != 0 {
+        return Err(invalid());
+    }
+    let secs = u64::try_from(time.timestamp()).map_err(|_| invalid())?;
+    Ok(UNIX_EPOCH + Duration::new(secs, time.timestamp_subsec_nanos()))
+}
+
+/// Wait for Enter on stdin before the first knock. A non-interactive
+/// stdin is an error rather than a silent hang.
+pub async fn wait_for_key() -> Result<(), AppError> {
+    if !io::stdin().is_terminal() {
+        return Err(AppError::Confirm(
+            "stdin is not a terminal; use --start-at to start unattended".into(),
+        ));
+    }
+    crate::rt::spawn_blocking(|| {
+        print!("Warmed up; press Enter to start knocking ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line)? {
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            _ => Ok(()),
+        }
+    })
+    .await?
+    .map_err(|e| AppError::Confirm(format!("aborted, no knocks sent: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_times_parse_as_unix_or_utc() {
+        let at = |secs, nanos| UNIX_EPOCH + Duration::new(secs, nanos);
+        assert_eq!(parse_start_at("1760600000"), Ok(at(1760600000, 0)));
+        assert_eq!(
+            parse_start_at("1760600000.5"),
+            Ok(at(1760600000, 500_000_000))
+        );
+        assert_eq!(parse_start_at("1970-01-01T00:00:00Z"), Ok(at(0, 0)));
+        assert_eq!(
+            parse_start_at("2026-10-16T12:00:00.25Z"),
+            Ok(at(1792152000, 250_000_000))
+        );
+        // Leap day of a leap year
+        assert_eq!(
+            parse_start_at("2024-02-29T00:00:00Z"),
+            Ok(at(1709164800, 0))
+        );
+        for bad in [
+            "",
+            "-1",
+            "2026-10-16 12:00:00",
+            "2026-10-16T12:00:00",
+            "2026-13-01T00:00:00Z",
+            "1969-12-31T23:59:59Z",
+            "2026-10-16T12:00:00+02:00",
+        ] {
+            assert!(parse_start_at(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn impossible_dates_are_not_start_times() {
+        for bad in [
+            "2026-02-31T00:00:00Z",
+            "2026-04-31T00:00:00Z",
+            "2025-02-29T00:00:00Z",
+            "2100-02-29T00:00:00Z",
+            "2026-10-16T24:00:00Z",
+        ] {
+            assert!(parse_start_at(bad).is_err(), "{bad}");
+        }
+        // The same instant, spelled with a zero offset
+        assert_eq!(
+            parse_start_at("2026-10-16T12:00:00+00:00"),
+            parse_start_at("2026-10-16T12:00:00Z")
+        );
+    }
+}
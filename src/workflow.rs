@@ -0,0 +1,551 @@
+//! Multi-stage knock workflows read from plan files.
+//!
+//! A plan file lists stages in order, each knocking its own host, e.g. a
+//! perimeter box and then an inner one behind it:
+//!
+//! ```toml
+//! continue_on_failure = false
+//!
+//! [[stage]]
+//! name = "perimeter"
+//! host = "gw.example"
+//! sequence = "7000,8000/udp,9000"
+//! verify = { port = 22, timeout = 2000, banner = "SSH-2.0" }
+//!
+//! [[stage]]
+//! name = "inner"
+//! host = "10.0.0.5"
+//! sequence = ["6001", "6002/udp?payload=cafe"]
+//! protocol = "tcp"
+//! delay = 200
+//! ```
+//!
+//! Every stage takes the settings of a base [`KnockConfig`] and overrides
+//! its host and sequence, or instead the `length_sequence` of UDP datagram
+//! lengths sent to `length_port` (see [`crate::config::LengthSequence`]),
+//! and any of `protocol`, `payload` (hex),
+//! `payload_text`, `tcp_payload`, `tcp_payload_text`, `timeout`, `delay`,
+//! `initial_delay` (milliseconds) and `attempts`. A stage with `verify`
+//! then needs a TCP connection to that port of its host to succeed within
+//! `timeout` milliseconds before the next stage starts. With `ladders`, a
+//! list of sequences like `sequence`, a stage whose sequence does not
+//! verify knocks each of them in turn until one does, waiting
+//! `ladder_cooldown` milliseconds between them and giving up after
+//! `ladder_max_time` (see [`crate::ladder`]).
+//!
+//! An open port may still be a tarpit, so `verify` can also read what the
+//! service says first: with `banner = "SSH-2.0"` up to `banner_bytes`
+//! bytes must arrive within `banner_timeout` milliseconds and start with
+//! it (or contain it, with `banner_contains = true`). A service that waits
+//! for the client to speak first sends nothing; that fails as "connected
+//! but no banner" unless `banner_optional = true`.
+
+use crate::config::LengthSequence;
+use crate::ladder::{self, Ladder};
+use crate::plan::KnockPlan;
+use crate::protocol::Protocol;
+use crate::retry::Attempts;
+use crate::{AppError, KnockConfig};
+use bytes::Bytes;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+
+pub use crate::verify::{
+    BannerCheck, Verify, DEFAULT_BANNER_BYTES, DEFAULT_BANNER_TIMEOUT, DEFAULT_VERIFY_TIMEOUT,
+};
+
+/// A whole plan file.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Workflow {
+    /// Go on with the next stage when one fails.
+    #[serde(default)]
+    pub continue_on_failure: bool,
+    /// The stages, knocked in order.
+    #[serde(rename = "stage")]
+    pub stages: Vec<Stage>,
+}
+
+/// One host to knock, and how.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(extend("oneOf" = [
+    { "required": ["sequence"] },
+    { "required": ["length_sequence", "length_port"] },
+]))]
+pub struct Stage {
+    /// Shown in progress and errors instead of the stage's number.
+    pub name: Option<String>,
+    pub host: String,
+    #[serde(default)]
+    pub sequence: KnockPlan,
+    /// Payload lengths of the UDP datagrams to `length_port` that tell the
+    /// sequence instead of `sequence`, each filled with random bytes.
+    #[schemars(length(min = 1))]
+    pub length_sequence: Option<Vec<usize>>,
+    /// Port every `length_sequence` datagram goes to.
+    #[schemars(range(min = 1))]
+    pub length_port: Option<u16>,
+    pub protocol: Option<Protocol>,
+    /// UDP payload, in hex.
+    #[serde(default, with = "crate::serde_forms::hex_opt")]
+    #[schemars(with = "Option<String>")]
+    pub payload: Option<Bytes>,
+    /// UDP payload, as text.
+    pub payload_text: Option<String>,
+    /// Bytes written on each TCP knock's connection, in hex.
+    #[serde(default, with = "crate::serde_forms::hex_opt")]
+    #[schemars(with = "Option<String>")]
+    pub tcp_payload: Option<Bytes>,
+    /// Bytes written on each TCP knock's connection, as text.
+    pub tcp_payload_text: Option<String>,
+    /// Milliseconds each knock attempt may take.
+    pub timeout: Option<u64>,
+    /// Milliseconds between knocks.
+    #[serde(default, with = "crate::serde_forms::millis_opt")]
+    #[schemars(with = "Option<f64>", range(min = 0))]
+    pub delay: Option<Duration>,
+    /// Milliseconds before the first knock.
+    pub initial_delay: Option<u64>,
+    /// Tries per knock, the first included.
+    #[schemars(range(min = 1))]
+    pub attempts: Option<usize>,
+    /// A TCP connect that must succeed before the next stage.
+    pub verify: Option<Verify>,
+    /// Sequences knocked in turn when the one before does not verify; see
+    /// [`crate::ladder`].
+    #[serde(default)]
+    pub ladders: Vec<KnockPlan>,
+    /// Milliseconds between ladder rungs.
+    #[serde(default, with = "crate::serde_forms::millis_opt")]
+    #[schemars(with = "Option<u64>")]
+    pub ladder_cooldown: Option<Duration>,
+    /// Most milliseconds all ladder rungs may take together.
+    #[serde(default, with = "crate::serde_forms::millis_opt")]
+    #[schemars(with = "Option<u64>")]
+    pub ladder_max_time: Option<Duration>,
+}
+
+/// What [`run_workflow`] reports as it goes.
+#[derive(Debug)]
+pub enum WorkflowEvent<'a> {
+    /// Stage `index` (from 0) of `count` is about to knock.
+    Started {
+        index: usize,
+        count: usize,
+        stage: &'a Stage,
+    },
+    /// The stage's verifying connect succeeded.
+    Verified {
+        index: usize,
+        port: u16,
+        banner: BannerCheck,
+    },
+    /// The stage failed; the run stops unless it continues on failure.
+    Failed { index: usize, error: &'a AppError },
+}
+
+impl Workflow {
+    /// Parse a plan file's text.
+    pub fn from_toml(text: &str) -> Result<Self, AppError> {
+        let workflow: Self = toml::from_str(text)
+            .map_err(|e| AppError::InvalidConfig(format!("invalid plan file: {e}")))?;
+        if workflow.stages.is_empty() {
+            return Err(AppError::InvalidConfig(
+                "the plan file has no stages".into(),
+            ));
+        }
+        // A stage tells its knocks by ports or by lengths, one of the two
+        let unclear = workflow
+            .stages
+            .iter()
+            .enumerate()
+            .find(|(_, s)| s.sequence.is_empty() == s.length_sequence.is_none());
+        if let Some((index, stage)) = unclear {
+            return Err(AppError::InvalidConfig(format!(
+                "invalid plan file: {} needs either a sequence or a length_sequence",
+                stage.label(index)
+            )));
+        }
+        Ok(workflow)
+    }
+
+    /// Read and parse a plan file.
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            AppError::InvalidConfig(format!("cannot read plan file {}: {e}", path.display()))
+        })?;
+        Self::from_toml(&text)
+    }
+
+    /// The JSON Schema plan files follow, for editors and validators
+    /// (`--print-config-schema`), its descriptions taken from the docs of
+    /// the types here.
+    pub fn json_schema() -> String {
+        let schema = schemars::schema_for!(Workflow);
+        serde_json::to_string_pretty(&schema).expect("a schema serializes")
+    }
+}
+
+impl Stage {
+    /// The stage's name, or its place in the plan.
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("stage {}", index + 1),
+        }
+    }
+
+    /// `base` with this stage's host, sequence and overrides.
+    pub fn config(&self, base: &KnockConfig) -> Result<KnockConfig, AppError> {
+        let invalid = |e: String| AppError::InvalidConfig(format!("stage {}: {e}", self.host));
+        let text = |s: &Option<String>| s.as_ref().map(|s| Bytes::copy_from_slice(s.as_bytes()));
+
+        let mut config = base.clone();
+        config.host = crate::scope::parse_host(&self.host).map_err(invalid)?;
+        config.sequence = self.sequence.clone();
+        config.length_sequence = match (&self.length_sequence, self.length_port) {
+            (Some(lengths), Some(port)) => Some(LengthSequence {
+                port,
+                lengths: lengths.clone(),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(invalid(
+                    "length_sequence and length_port go together".into(),
+                ))
+            }
+        };
+        config.totp = None;
+        config.passphrase_ports = None;
+        config.ladder = match (&self.verify, self.ladders.is_empty()) {
+            (_, true) => None,
+            (None, false) => return Err(invalid("ladders need a verify".into())),
+            (Some(verify), false) => Some(Ladder {
+                rungs: self.ladders.clone(),
+                verify: verify.clone(),
+                cooldown: self.ladder_cooldown.unwrap_or(ladder::DEFAULT_COOLDOWN),
+                max_time: self.ladder_max_time.unwrap_or(ladder::DEFAULT_MAX_TIME),
+            }),
+        };
+        if let Some(protocol) = self.protocol {
+            config.protocol = protocol;
+        }
+        if let Some(payload) = self.payload.clone().or_else(|| text(&self.payload_text)) {
+            config.payload = Some(payload);
+        }
+        if let Some(payload) = (self.tcp_payload.clone()).or_else(|| text(&self.tcp_payload_text)) {
+            config.tcp_payload = Some(payload);
+        }
+        config.timeout = self.timeout.unwrap_or(config.timeout);
+        config.delay = self.delay.unwrap_or(config.delay);
+        config.initial_delay = self.initial_delay.unwrap_or(config.initial_delay);
+        if let Some(attempts) = self.attempts {
+            config.attempts = Attempts::Finite(attempts);
+        }
+        if let Some(Verify {
+            banner: Some(banner),
+            banner_bytes,
+            ..
+        }) = &self.verify
+        {
+            if banner.is_empty() || *banner_bytes < banner.len() {
+                return Err(invalid(format!(
+                    "banner \"{banner}\" must be non-empty and fit in banner_bytes ({banner_bytes})"
+                )));
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Run the stages of `workflow` in order over the settings of `base`,
+/// each through [`crate::run`]. With `base.dry_run` every stage only shows
+/// its plan and nothing is verified.
+///
+/// The first failing stage ends the run with its error, unless the
+/// workflow or `continue_on_failure` says to go on, when the rest still
+/// run and the first error is returned at the end. A signal always stops
+/// the run.
+pub async fn run_workflow(
+    workflow: &Workflow,
+    base: &KnockConfig,
+    continue_on_failure: bool,
+    mut on_event: impl FnMut(WorkflowEvent<'_>),
+) -> Result<(), AppError> {
+    let continue_on_failure = continue_on_failure || workflow.continue_on_failure;
+    // Every stage is checked before the first one knocks
+    let configs = workflow
+        .stages
+        .iter()
+        .map(|stage| stage.config(base))
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = configs.len();
+    let mut first_error = None;
+    for (index, (stage, config)) in workflow.stages.iter().zip(configs).enumerate() {
+        on_event(WorkflowEvent::Started {
+            index,
+            count,
+            stage,
+        });
+        let dry_run = config.dry_run;
+        let host = match config.no_dns {
+            true => crate::dns::without_dns(&config.host, &config.resolve_pins)?,
+            false => config.host.clone(),
+        };
+        // A stage with ladders is verified by its run, rung by rung
+        let laddered = config.ladder.is_some();
+        let mut result = crate::run(config).await.map(|report| {
+            if let Some(ladder) = report.ladder {
+                on_event(WorkflowEvent::Verified {
+                    index,
+                    port: ladder.port,
+                    banner: ladder.banner,
+                });
+            }
+            report.address
+        });
+        if let (Ok(address), Some(verify), false, false) =
+            (&result, &stage.verify, dry_run, laddered)
+        {
+            let address = *address;
+            result = match crate::verify::check(&host, address, verify).await {
+                Ok(banner) => {
+                    on_event(WorkflowEvent::Verified {
+                        index,
+                        port: verify.port,
+                        banner,
+                    });
+                    Ok(address)
+                }
+                Err(e) => Err(AppError::Runtime(format!("{}: {e}", stage.label(index)))),
+            };
+        }
+        if let Err(error) = result {
+            on_event(WorkflowEvent::Failed {
+                index,
+                error: &error,
+            });
+            if !continue_on_failure || matches!(error, AppError::Interrupted(_)) {
+                return Err(error);
+            }
+            first_error.get_or_insert(error);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockKnockServer;
+
+    const EXAMPLE: &str = include_str!("../examples/two-stage-plan.toml");
+
+    fn base() -> KnockConfig {
+        KnockConfig::builder()
+            .host("127.0.0.1")
+            .sequence([1])
+            .timeout(300)
+            .build()
+            .unwrap()
+    }
+
+    /// Point each stage of the example at a mock server, port for port
+    /// and protocol for protocol, and its verify at a listening port.
+    fn retarget(workflow: &mut Workflow, servers: &[MockKnockServer]) {
+        for (stage, server) in workflow.stages.iter_mut().zip(servers) {
+            stage.host = server.ip().to_string();
+            let (mut tcp, mut udp) = (server.tcp_ports().iter(), server.udp_ports().iter());
+            for step in stage.sequence.0.iter_mut() {
+                let protocol = step.protocol.or(stage.protocol).unwrap_or(Protocol::Tcp);
+                step.port = match protocol {
+                    Protocol::Udp => *udp.next().unwrap(),
+                    _ => *tcp.next().unwrap(),
+                };
+            }
+            if let Some(verify) = &mut stage.verify {
+                verify.port = *tcp.next().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn example_parses() {
+        let workflow = Workflow::from_toml(EXAMPLE).unwrap();
+        let labels: Vec<String> = workflow
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s.label(i))
+            .collect();
+        assert_eq!(labels, ["perimeter", "inner"]);
+        assert!(!workflow.continue_on_failure);
+        let perimeter = workflow.stages[0].config(&base()).unwrap();
+        assert_eq!(perimeter.host, "gw.example");
+        assert_eq!(perimeter.sequence.to_string(), "7000,8000/udp,9000");
+        assert!(workflow.stages[0].verify.is_some());
+        let inner = workflow.stages[1].config(&base()).unwrap();
+        assert_eq!(inner.delay, Duration::from_millis(200));
+        assert_eq!(inner.payload.as_deref(), Some(&b"inner"[..]));
+    }
+
+    #[test]
+    fn bad_plans_are_refused() {
+        for text in [
+            "",
+            "[[stage]]\nhost = \"h.example\"",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"70000\"",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\nretries = 2",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\nprotocol = \"smtp\"",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\npayload = \"xyz\"",
+        ] {
+            assert!(Workflow::from_toml(text).is_err(), "{text}");
+        }
+
+        // Ladders fall back on other sequences and need a verify to know
+        // which one worked
+        let laddered = "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\n\
+                        ladders = [\"7100,7200\", \"7300\"]\nladder_cooldown = 500\n";
+        let workflow = Workflow::from_toml(laddered).unwrap();
+        let err = workflow.stages[0].config(&base()).err().unwrap();
+        assert!(err.to_string().contains("ladders need a verify"), "{err}");
+        let workflow = Workflow::from_toml(&format!("{laddered}verify = {{ port = 22 }}")).unwrap();
+        let config = workflow.stages[0].config(&base()).unwrap();
+        let ladder = config.ladder.unwrap();
+        assert_eq!(ladder.rungs.len(), 2);
+        assert_eq!(ladder.rungs[0].to_string(), "7100,7200");
+        assert_eq!(ladder.cooldown, Duration::from_millis(500));
+        assert_eq!(ladder.verify.port, 22);
+
+        // Lengths tell a stage's knocks instead of ports, never as well
+        let lengths = "[[stage]]\nhost = \"h.example\"\nlength_sequence = [100, 237]\n";
+        let workflow = Workflow::from_toml(&format!("{lengths}length_port = 40000")).unwrap();
+        let config = workflow.stages[0].config(&base()).unwrap();
+        assert_eq!(config.length_sequence.unwrap().lengths, [100, 237]);
+        let workflow = Workflow::from_toml(lengths).unwrap();
+        assert!(workflow.stages[0].config(&base()).is_err());
+        assert!(Workflow::from_toml(&format!("{lengths}sequence = \"7000\"")).is_err());
+    }
+
+    #[test]
+    fn examples_follow_the_schema() {
+        let schema: serde_json::Value = serde_json::from_str(&Workflow::json_schema()).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        let examples = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(examples).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "toml") {
+                let text = std::fs::read_to_string(&path).unwrap();
+                let plan: serde_json::Value = toml::from_str(&text).unwrap();
+                let errors: Vec<String> = validator
+                    .iter_errors(&plan)
+                    .map(|e| e.to_string())
+                    .collect();
+                assert!(errors.is_empty(), "{}: {errors:?}", path.display());
+                checked += 1;
+            }
+        }
+        assert!(checked > 0);
+
+        // What the types refuse, the schema refuses too
+        for text in [
+            "[[stage]]\nhost = \"h.example\"",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\nretries = 2",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\nprotocol = \"smtp\"",
+            "[[stage]]\nhost = \"h.example\"\nsequence = []",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\nverify = { timeout = 5 }",
+            "[[stage]]\nhost = \"h.example\"\nlength_sequence = [100]",
+            "[[stage]]\nhost = \"h.example\"\nsequence = \"7000\"\nlength_sequence = [100]\n\
+             length_port = 1",
+        ] {
+            let plan: serde_json::Value = toml::from_str(text).unwrap();
+            assert!(!validator.is_valid(&plan), "{text}");
+        }
+        let lengths = "[[stage]]\nhost = \"h.example\"\nlength_sequence = [100]\nlength_port = 1";
+        assert!(validator.is_valid(&toml::from_str(lengths).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn example_runs_against_two_listeners() {
+        let mut servers = Vec::new();
+        for _ in 0..2 {
+            servers.push(
+                MockKnockServer::builder()
+                    .tcp_ports(3)
+                    .udp_ports(1)
+                    .bind()
+                    .await
+                    .unwrap(),
+            );
+        }
+        let mut workflow = Workflow::from_toml(EXAMPLE).unwrap();
+        retarget(&mut workflow, &servers);
+
+        let mut events = Vec::new();
+        run_workflow(&workflow, &base(), false, |e| {
+            events.push(match e {
+                WorkflowEvent::Started { index, .. } => format!("start {index}"),
+                WorkflowEvent::Verified { index, .. } => format!("verified {index}"),
+                WorkflowEvent::Failed { index, error } => format!("failed {index}: {error}"),
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(events, ["start 0", "verified 0", "start 1"]);
+
+        for (stage, server) in workflow.stages.iter().zip(&servers) {
+            let ports: Vec<u16> = stage.sequence.iter().map(|s| s.port).collect();
+            let seen: Vec<u16> = server
+                .wait_for(ports.len(), Duration::from_secs(2))
+                .await
+                .iter()
+                .map(|k| k.port)
+                .collect();
+            // The verifying connect of the first stage comes last
+            assert_eq!(seen[..ports.len()], ports[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_stage_stops_the_run_unless_told_to_go_on() {
+        let server = MockKnockServer::builder()
+            .tcp_ports(2)
+            .udp_ports(0)
+            .bind()
+            .await
+            .unwrap();
+        let [open, knock] = [server.tcp_ports()[0], server.tcp_ports()[1]];
+        // Nothing listens on the verified port once its listener is gone
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let text = format!(
+            "[[stage]]\nhost = \"127.0.0.1\"\nsequence = \"{knock}\"\n\
+             verify = {{ port = {closed}, timeout = 500 }}\n\
+             [[stage]]\nhost = \"127.0.0.1\"\nsequence = \"{open}\"\n"
+        );
+        let workflow = Workflow::from_toml(&text).unwrap();
+
+        let mut started = 0;
+        let result = run_workflow(&workflow, &base(), false, |e| {
+            started += matches!(e, WorkflowEvent::Started { .. }) as usize;
+        })
+        .await;
+        assert!(matches!(result, Err(AppError::Runtime(_))), "{result:?}");
+        assert_eq!(started, 1);
+
+        let mut started = 0;
+        let result = run_workflow(&workflow, &base(), true, |e| {
+            started += matches!(e, WorkflowEvent::Started { .. }) as usize;
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(started, 2);
+    }
+}
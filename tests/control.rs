@@ -0,0 +1,186 @@
+//! The `--control-socket` protocol, spoken over a raw Unix socket the way
+//! `socat` or `nc -U` would.
+
+#![cfg(all(unix, feature = "runtime-tokio", feature = "serde"))]
+
+use async_port_knocker::control::{self, ControlCommand};
+use async_port_knocker::CancellationToken;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A socket path of its own for each test.
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("apk-{}-{name}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Send each of `lines` on one connection and read the answers.
+async fn ask(path: &Path, lines: &[&str]) -> Vec<String> {
+    let (path, lines) = (
+        path.to_path_buf(),
+        lines.iter().map(|l| l.to_string()).collect::<Vec<_>>(),
+    );
+    tokio::task::spawn_blocking(move || {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        lines
+            .iter()
+            .map(|line| {
+                writeln!(stream, "{line}").unwrap();
+                let mut answer = String::new();
+                reader.read_line(&mut answer).unwrap();
+                answer.trim_end().to_string()
+            })
+            .collect()
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn commands_are_answered_line_by_line() {
+    let path = socket_path("commands");
+    let cancel = CancellationToken::new();
+    let stop = cancel.clone();
+    let respond = move |command| match command {
+        ControlCommand::Stop => {
+            stop.cancel();
+            "ok: stopping".to_string()
+        }
+        command => format!("ok: {command}"),
+    };
+    let server = control::serve(&path, respond, cancel.clone())
+        .await
+        .unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // A blank line gets no answer
+    let answers = ask(&path, &["status", "KNOCK", "\nreboot", "reload"]).await;
+    assert_eq!(answers[..2], ["ok: status", "ok: knock"]);
+    assert!(answers[2].starts_with("error: unknown command 'reboot'"));
+    assert_eq!(answers[3], "ok: reload");
+
+    // A second instance on the same path is turned away
+    let err = control::serve(&path, |_| String::new(), CancellationToken::new())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("another process"), "{err}");
+
+    assert_eq!(ask(&path, &["stop"]).await, ["ok: stopping"]);
+    tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(cancel.is_cancelled());
+    assert!(!path.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stale_socket_is_replaced_but_other_files_are_not() {
+    // A socket nobody listens on any more, as a crash leaves behind
+    let path = socket_path("stale");
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let cancel = CancellationToken::new();
+    let server = control::serve(&path, |_| "ok".to_string(), cancel.clone())
+        .await
+        .unwrap();
+    assert_eq!(ask(&path, &["status"]).await, ["ok"]);
+    cancel.cancel();
+    server.await.unwrap();
+
+    let path = socket_path("regular");
+    std::fs::write(&path, "not a socket").unwrap();
+    let err = control::serve(&path, |_| String::new(), CancellationToken::new())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("not a socket"), "{err}");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "schedule")]
+#[tokio::test(flavor = "multi_thread")]
+async fn schedule_knocks_and_reports_on_command() {
+    use async_port_knocker::control::LastReport;
+    use async_port_knocker::schedule::{self, CronSchedule, ScheduleCommand, ScheduleEvent};
+    use async_port_knocker::KnockConfig;
+    use std::sync::{Arc, Mutex};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let last = Arc::new(LastReport::default());
+    let mut config = KnockConfig::builder()
+        .host("127.0.0.1")
+        .sequence([port])
+        .build()
+        .unwrap();
+    config.observer = Some(last.clone());
+
+    let path = socket_path("schedule");
+    let cancel = CancellationToken::new();
+    let (commands, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let (status, stop) = (last.clone(), cancel.clone());
+    let commands = Mutex::new(commands);
+    let respond = move |command| match command {
+        ControlCommand::Status => status.json(),
+        ControlCommand::Knock => {
+            commands
+                .lock()
+                .unwrap()
+                .send(ScheduleCommand::KnockNow)
+                .unwrap();
+            "ok: knocking now".to_string()
+        }
+        ControlCommand::Reload => "error: not here".to_string(),
+        ControlCommand::Stop => {
+            stop.cancel();
+            "ok: stopping".to_string()
+        }
+    };
+    let server = control::serve(&path, respond, cancel.clone())
+        .await
+        .unwrap();
+    // At most once a year, so only the socket makes it knock
+    let yearly = CronSchedule::parse("0 0 1 1 *").unwrap();
+    let ran = Arc::new(Mutex::new(0));
+    let counted = ran.clone();
+    let run = tokio::spawn(async move {
+        schedule::run_on_schedule_controlled(config, &yearly, cancel, &mut received, |e| {
+            if let ScheduleEvent::Ran { result, .. } = e {
+                assert!(result.is_ok());
+                *counted.lock().unwrap() += 1;
+            }
+        })
+        .await
+    });
+
+    assert_eq!(
+        ask(&path, &["status", "knock"]).await,
+        ["null", "ok: knocking now"]
+    );
+    for _ in 0..50 {
+        if *ran.lock().unwrap() == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let status = &ask(&path, &["status"]).await[0];
+    assert!(status.starts_with("{\"host\":\"127.0.0.1\""), "{status}");
+    assert!(status.contains(&format!("\"port\":{port}")), "{status}");
+    assert!(status.contains("\"succeeded\":true"), "{status}");
+
+    assert_eq!(ask(&path, &["stop"]).await, ["ok: stopping"]);
+    run.await.unwrap().unwrap();
+    server.await.unwrap();
+    assert_eq!(*ran.lock().unwrap(), 1);
+    assert!(!path.exists());
+}
@@ -0,0 +1,123 @@
+//! Knocks sent from inside a network namespace (`--netns`). Creating one
+//! needs root and the `ip` tool, so these only build with the
+//! `privileged-tests` feature:
+//!
+//! ```text
+//! sudo cargo test --features privileged-tests --test netns
+//! ```
+
+#![cfg(all(target_os = "linux", feature = "privileged-tests"))]
+
+use async_port_knocker::plan::KnockPlan;
+use async_port_knocker::{run, AppError, KnockConfig};
+use std::fs::File;
+use std::net::{TcpListener, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::process::Command;
+use std::time::Duration;
+
+/// A namespace of its own with loopback up, deleted when dropped.
+struct Netns(String);
+
+impl Netns {
+    fn add(name: &str) -> Self {
+        let name = format!("{name}-{}", std::process::id());
+        ip(&["netns", "add", &name]);
+        let netns = Netns(name);
+        ip(&["-n", &netns.0, "link", "set", "lo", "up"]);
+        netns
+    }
+
+    /// Run `f` on a thread moved into the namespace; sockets it opens
+    /// stay there.
+    fn inside<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> T {
+        let ns = File::open(format!("/var/run/netns/{}", self.0)).unwrap();
+        std::thread::spawn(move || {
+            // SAFETY: the descriptor is open for the duration of the call
+            assert_eq!(
+                unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) },
+                0
+            );
+            f()
+        })
+        .join()
+        .unwrap()
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        let _ = Command::new("ip").args(["netns", "del", &self.0]).status();
+    }
+}
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip").args(args).status().unwrap();
+    assert!(status.success(), "ip {args:?}");
+}
+
+fn this_namespace() -> std::path::PathBuf {
+    std::fs::read_link("/proc/thread-self/ns/net").unwrap()
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn knocks_are_sent_from_inside_the_namespace() {
+    let netns = Netns::add("apk-knock");
+    let (tcp, udp) = netns.inside(|| {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        (tcp, udp)
+    });
+    udp.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let plan: KnockPlan = format!(
+        "{},{}/udp",
+        tcp.local_addr().unwrap().port(),
+        udp.local_addr().unwrap().port()
+    )
+    .parse()
+    .unwrap();
+    let builder = KnockConfig::builder()
+        .host("127.0.0.1")
+        .plan(plan)
+        .refused_is_failure(true)
+        .payload(b"inside".to_vec());
+
+    let before = this_namespace();
+    let report = run(builder.clone().netns(&netns.0).build().unwrap())
+        .await
+        .unwrap();
+    assert!(report.steps[0].acknowledged);
+    tcp.set_nonblocking(true).unwrap();
+    assert!(tcp.accept().is_ok());
+    let mut buf = [0; 16];
+    let n = udp.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"inside");
+    // The caller never left its own namespace
+    assert_eq!(this_namespace(), before);
+
+    // Outside it nothing listens on the port
+    let err = run(builder.build().unwrap()).await.unwrap_err();
+    assert!(
+        matches!(err, AppError::KnockFailed { .. } | AppError::Partial { .. }),
+        "{err}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn a_missing_namespace_is_a_config_error() {
+    let config = KnockConfig::builder()
+        .host("127.0.0.1")
+        .sequence([7000])
+        .netns("apk-surely-no-such-namespace")
+        .build()
+        .unwrap();
+    let err = run(config).await.unwrap_err();
+    assert_eq!(err.exit_code(), 2, "{err}");
+    assert!(err.to_string().contains("no network namespace"), "{err}");
+
+    let bad = KnockConfig::builder()
+        .host("127.0.0.1")
+        .sequence([7000])
+        .netns("../etc");
+    assert!(bad.build().is_err());
+}
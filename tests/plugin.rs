@@ -0,0 +1,101 @@
+//! `plugin` steps end to end: the example plugin's datagrams reach a UDP
+//! server, its check judges the reply, and a broken plugin fails only its
+//! own step.
+
+#![cfg(all(feature = "wasm-plugins", feature = "runtime-tokio"))]
+
+use async_port_knocker::{
+    plan::KnockPlan, run_with_cancel, AppError, CancellationToken, KnockConfig,
+};
+use std::net::UdpSocket;
+use std::path::Path;
+
+fn run(config: KnockConfig) -> Result<async_port_knocker::KnockReport, AppError> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run_with_cancel(config, CancellationToken::new()))
+}
+
+/// A UDP server answering `ok` to `knock:s3cret` and `no` to anything else.
+fn server() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = socket.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            let reply: &[u8] = if &buf[..len] == b"knock:s3cret" {
+                b"ok"
+            } else {
+                b"no"
+            };
+            let _ = socket.send_to(reply, from);
+        }
+    });
+    port
+}
+
+fn config(sequence: &str) -> KnockConfig {
+    KnockConfig::builder()
+        .host("127.0.0.1")
+        .plan(KnockPlan::parse(sequence).unwrap())
+        .attempts(1)
+        .delay(0)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn the_example_plugin_knocks_and_checks_the_reply() {
+    let tagged = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/plugins/tagged.wat");
+    let port = server();
+
+    let report = run(config(&format!(
+        "{port}:plugin:{}:s3cret",
+        tagged.display()
+    )))
+    .unwrap();
+    assert!(report.succeeded());
+
+    // The server answers a wrong argument, and the plugin rejects that
+    let err = run(config(&format!("{port}:plugin:{}:guess", tagged.display()))).unwrap_err();
+    assert!(
+        matches!(err, AppError::Partial { succeeded: 0, .. }),
+        "expected the rejected reply to fail the step, got {err:?}"
+    );
+}
+
+#[test]
+fn a_trapping_plugin_fails_its_step_alone() {
+    let dir = std::env::temp_dir().join(format!("pk-plugin-it-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let broken = dir.join("broken.wat");
+    std::fs::write(
+        &broken,
+        r#"(module (memory (export "memory") 1)
+             (func (export "knock_alloc") (param i32) (result i32) i32.const 0)
+             (func (export "knock_build") (param i32 i32) (result i64) unreachable))"#,
+    )
+    .unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = udp.local_addr().unwrap().port();
+
+    let err = run(config(&format!(
+        "{port}:plugin:{},{port}/udp",
+        broken.display()
+    )))
+    .unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    match err {
+        AppError::Partial { failed, succeeded } => {
+            assert_eq!(succeeded, 1);
+            assert_eq!(failed.len(), 1);
+        }
+        other => panic!("expected only the plugin step to fail, got {other:?}"),
+    }
+
+    // A plugin that does not compile fails the run before any knock
+    let err = run(config("7000:plugin:/nonexistent/knock.wasm")).unwrap_err();
+    assert!(matches!(err, AppError::Plugin(_)), "{err:?}");
+}
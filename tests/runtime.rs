@@ -0,0 +1,108 @@
+//! Knocks through the public API on the runtime the library is built for:
+//! Tokio by default, async-io with `--no-default-features --features
+//! runtime-smol`. Both CI runtimes run these.
+
+use async_port_knocker::{run_with_cancel, AppError, CancellationToken, KnockConfig, Protocol};
+use std::future::Future;
+use std::net::{TcpListener, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Drive `future` the way an application on that runtime would.
+fn block_on<F: Future>(future: F) -> F::Output {
+    #[cfg(feature = "runtime-tokio")]
+    return tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future);
+    #[cfg(not(feature = "runtime-tokio"))]
+    async_io::block_on(future)
+}
+
+fn run(config: KnockConfig) -> Result<async_port_knocker::KnockReport, AppError> {
+    block_on(run_with_cancel(config, CancellationToken::new()))
+}
+
+#[test]
+fn tcp_sequence_is_paced_by_the_delay() {
+    let listeners: Vec<_> = (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let ports = listeners.iter().map(|l| l.local_addr().unwrap().port());
+    let config = KnockConfig::builder()
+        .host("127.0.0.1")
+        .sequence(ports)
+        .delay(100)
+        .build()
+        .unwrap();
+
+    let started = Instant::now();
+    let report = run(config).unwrap();
+    assert!(report.succeeded());
+    assert_eq!(report.steps.len(), 3);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn udp_replies_are_awaited_and_missing_ones_time_out() {
+    let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let ports = [
+        echo.local_addr().unwrap().port(),
+        silent.local_addr().unwrap().port(),
+    ];
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while let Ok((len, from)) = echo.recv_from(&mut buf) {
+            let _ = echo.send_to(&buf[..len], from);
+        }
+    });
+    let config = KnockConfig::builder()
+        .host("127.0.0.1")
+        .sequence(ports)
+        .protocol(Protocol::Udp)
+        .payload(&b"knock"[..])
+        .expect_reply(true)
+        .recv_timeout(200)
+        .attempts(1)
+        .delay(0)
+        .build()
+        .unwrap();
+
+    let started = Instant::now();
+    let err = run(config).unwrap_err();
+    match err {
+        AppError::Partial { failed, succeeded } => {
+            assert_eq!(succeeded, 1);
+            assert_eq!(failed.len(), 1);
+            assert_eq!(failed[0].port, ports[1]);
+        }
+        other => panic!("expected the silent port to fail, got {other:?}"),
+    }
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    drop(silent);
+}
+
+#[test]
+fn cancelled_run_stops_between_knocks() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let config = KnockConfig::builder()
+        .host("127.0.0.1")
+        .sequence([port; 5])
+        .delay(1000)
+        .build()
+        .unwrap();
+    let cancel = CancellationToken::new();
+    let later = cancel.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        later.cancel();
+    });
+
+    let started = Instant::now();
+    let report = block_on(run_with_cancel(config, cancel)).unwrap();
+    assert!(report.interrupted);
+    assert!(!report.not_started.is_empty());
+    assert!(started.elapsed() < Duration::from_secs(2));
+}